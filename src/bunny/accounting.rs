@@ -0,0 +1,141 @@
+use bytes::Bytes;
+use futures::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+tokio::task_local! {
+    static USAGE: Arc<UpstreamUsage>;
+}
+
+/// Bunny API usage attributed to one S3 request. [`BunnyClient`] records into
+/// the usage of the request whose task it runs on, so handlers need not pass
+/// a context through every call.
+///
+/// [`BunnyClient`]: super::BunnyClient
+#[derive(Debug, Default)]
+pub struct UpstreamUsage {
+    calls: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl UpstreamUsage {
+    pub fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn add_call(&self) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn add_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn add_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Runs `f` with `usage` as the current request's accounting.
+pub async fn scope<F: Future>(usage: Arc<UpstreamUsage>, f: F) -> F::Output {
+    USAGE.scope(usage, f).await
+}
+
+/// Carries the current request's accounting into a future that will run on
+/// another task, such as one passed to `tokio::spawn`.
+pub fn propagate<F: Future>(f: F) -> impl Future<Output = F::Output> {
+    let usage = current();
+    async move {
+        match usage {
+            Some(usage) => USAGE.scope(usage, f).await,
+            None => f.await,
+        }
+    }
+}
+
+/// The accounting of the request running on this task, if any.
+pub(super) fn current() -> Option<Arc<UpstreamUsage>> {
+    USAGE.try_with(Arc::clone).ok()
+}
+
+pub(super) fn record_call() {
+    let _ = USAGE.try_with(|usage| usage.add_call());
+}
+
+pub(super) fn record_sent(bytes: usize) {
+    let _ = USAGE.try_with(|usage| usage.add_sent(bytes));
+}
+
+pub(super) fn record_received(bytes: usize) {
+    let _ = USAGE.try_with(|usage| usage.add_received(bytes));
+}
+
+/// An upload body that counts the bytes sent and polls its source inside the
+/// request's scope, since the HTTP client drives it from its own task.
+pub(super) struct MeteredStream<S> {
+    inner: Pin<Box<S>>,
+    usage: Option<Arc<UpstreamUsage>>,
+}
+
+impl<S> MeteredStream<S> {
+    pub(super) fn new(inner: S) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            usage: current(),
+        }
+    }
+}
+
+impl<S> Stream for MeteredStream<S>
+where
+    S: Stream<Item = std::result::Result<Bytes, std::io::Error>>,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Some(usage) = self.usage.clone() else {
+            return self.inner.as_mut().poll_next(cx);
+        };
+        let poll = USAGE.sync_scope(Arc::clone(&usage), || self.inner.as_mut().poll_next(cx));
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            usage.add_sent(chunk.len());
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_usage_follows_spawned_tasks() {
+        let usage = Arc::new(UpstreamUsage::default());
+        scope(Arc::clone(&usage), async {
+            record_call();
+            tokio::spawn(propagate(async { record_sent(10) }))
+                .await
+                .unwrap();
+            tokio::spawn(async { record_received(99) }).await.unwrap();
+        })
+        .await;
+        record_call();
+
+        assert_eq!(usage.calls(), 1);
+        assert_eq!(usage.bytes_sent(), 10);
+        assert_eq!(usage.bytes_received(), 0);
+    }
+}
@@ -0,0 +1,536 @@
+//! Where objects are stored. [`StorageBackend`] is the part of the Bunny
+//! storage API the proxy relies on; [`BunnyClient`] implements it over HTTP
+//! and [`LocalFsBackend`] with files below `--localfs-root`, so the proxy can
+//! run without a storage zone. [`Backend`] is the one `--backend` selects,
+//! wrapped with `--listing-index` so writes keep the zone's
+//! [`ListingIndex`] current.
+
+use bytes::{Bytes, BytesMut};
+use futures::future::Either;
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt, TryStreamExt};
+use reqwest::header::HeaderMap;
+use reqwest::{Response, StatusCode};
+use std::sync::Arc;
+
+use crate::config::{BackendKind, Config, StorageZoneConfig};
+use crate::error::{ProxyError, Result};
+
+use super::accounting::{self, UpstreamUsage};
+use super::client::{BunnyClient, UpstreamStats};
+use super::listing_index::ListingIndex;
+use super::localfs::LocalFsBackend;
+use super::types::{StorageObject, UploadOptions, ZoneStatistics};
+
+#[allow(async_fn_in_trait)]
+pub trait StorageBackend: Clone + Send + Sync {
+    /// The storage zone this backend holds.
+    fn zone(&self) -> &str;
+
+    /// The folder this backend's paths are relative to, empty for the zone root.
+    fn root(&self) -> &str;
+
+    /// A backend whose paths are relative to the folder `prefix` under this
+    /// backend's root. Listings it returns are relative to that folder too.
+    fn scoped(&self, prefix: &str) -> Self;
+
+    /// The objects and folders directly inside the folder `path`; a missing
+    /// folder lists as empty.
+    async fn list(&self, path: &str) -> Result<Vec<StorageObject>>;
+
+    /// Describes a file or folder; a missing file is `NotFound`.
+    async fn describe(&self, path: &str) -> Result<StorageObject>;
+
+    async fn download_range(&self, path: &str, range: Option<&str>) -> Result<DownloadResponse>;
+
+    /// Stores `body` at `path`, creating its parent folders. A path ending
+    /// in `/` creates that folder instead.
+    async fn upload(&self, path: &str, body: Bytes, options: UploadOptions) -> Result<()>;
+
+    async fn upload_stream(
+        &self,
+        path: &str,
+        stream: impl Stream<Item = std::result::Result<Bytes, std::io::Error>> + Send + 'static,
+        content_length: Option<u64>,
+        content_type: Option<&str>,
+    ) -> Result<()>;
+
+    /// Deletes a file, or a folder with everything below it. Deleting
+    /// something missing succeeds.
+    async fn delete(&self, path: &str) -> Result<()>;
+
+    async fn download(&self, path: &str) -> Result<DownloadResponse> {
+        self.download_range(path, None).await
+    }
+
+    /// Creates the folder `path`.
+    async fn create_directory(&self, path: &str) -> Result<()> {
+        let path = format!("{}/", path.trim_end_matches('/'));
+        self.upload(&path, Bytes::new(), UploadOptions::default())
+            .await
+    }
+
+    async fn list_recursive(
+        &self,
+        prefix: &str,
+        max_keys: Option<usize>,
+    ) -> Result<Vec<StorageObject>> {
+        let mut all_objects = Vec::new();
+        let mut dirs_to_process = vec![prefix.to_string()];
+
+        while let Some(dir) = dirs_to_process.pop() {
+            if let Some(max) = max_keys
+                && all_objects.len() >= max
+            {
+                break;
+            }
+
+            let objects = self.list(&dir).await?;
+            for obj in objects {
+                if obj.is_directory {
+                    dirs_to_process.push(format!("{}/", obj.s3_key()));
+                } else {
+                    all_objects.push(obj);
+                    if let Some(max) = max_keys
+                        && all_objects.len() >= max
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(all_objects)
+    }
+
+    /// Up to `limit` objects under the folder `prefix` after `start_after`,
+    /// in key order and without those `skip` rejects. Folders are walked in
+    /// key order too, and those whose keys all sort before `start_after` are
+    /// never listed, so each page of a listing resumes where the last ended.
+    /// A folder `skip` rejects, as `folder/`, is not descended into.
+    async fn list_recursive_after(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+        skip: &(dyn Fn(&str) -> bool + Sync),
+    ) -> Result<Vec<StorageObject>> {
+        enum Walk {
+            Folder(String),
+            Object(Box<StorageObject>),
+        }
+
+        let after = |key: &str| start_after.is_none_or(|s| key > s);
+        let mut objects = Vec::new();
+        let mut pending = vec![Walk::Folder(prefix.to_string())];
+        while objects.len() < limit
+            && let Some(next) = pending.pop()
+        {
+            let dir = match next {
+                Walk::Object(obj) => {
+                    objects.push(*obj);
+                    continue;
+                }
+                Walk::Folder(dir) => dir,
+            };
+            let mut entries: Vec<(String, Walk)> = Vec::new();
+            for obj in self.list(&dir).await? {
+                let key = obj.s3_key();
+                if obj.is_directory {
+                    // Everything in the folder sorts between `folder/` and
+                    // the keys that extend it.
+                    let folder = format!("{}/", key);
+                    if !skip(&folder)
+                        && start_after.is_none_or(|s| s < folder.as_str() || s.starts_with(&folder))
+                    {
+                        entries.push((folder.clone(), Walk::Folder(folder)));
+                    }
+                } else if after(&key) && !skip(&key) {
+                    entries.push((key, Walk::Object(Box::new(obj))));
+                }
+            }
+            entries.sort_by(|a, b| b.0.cmp(&a.0));
+            pending.extend(entries.into_iter().map(|(_, walk)| walk));
+        }
+
+        Ok(objects)
+    }
+
+    /// Lists every object under `prefix`, listing up to `concurrency`
+    /// directories at once.
+    async fn list_recursive_concurrent(
+        &self,
+        prefix: &str,
+        concurrency: usize,
+    ) -> Result<Vec<StorageObject>> {
+        let mut all_objects = Vec::new();
+        let mut pending = vec![prefix.to_string()];
+
+        while !pending.is_empty() {
+            let mut listings = futures::stream::iter(std::mem::take(&mut pending))
+                .map(|dir| {
+                    let client = self.clone();
+                    async move { client.list(&dir).await }
+                })
+                .buffer_unordered(concurrency.max(1));
+            while let Some(objects) = listings.next().await {
+                for obj in objects? {
+                    if obj.is_directory {
+                        pending.push(format!("{}/", obj.s3_key()));
+                    } else {
+                        all_objects.push(obj);
+                    }
+                }
+            }
+        }
+
+        Ok(all_objects)
+    }
+
+    /// Copies `source`, a path of `from`, to `dest` under this backend. The
+    /// download is streamed into the upload, so `from` may be another zone
+    /// and the object any size.
+    async fn copy_from(&self, from: &impl StorageBackend, source: &str, dest: &str) -> Result<()> {
+        let download = from.download(source).await?;
+        let content_length = download.content_length();
+        let content_type = download.content_type().map(str::to_string);
+        self.upload_stream(
+            dest,
+            download.bytes_stream(),
+            content_length,
+            content_type.as_deref(),
+        )
+        .await
+    }
+}
+
+/// The storage backend of a zone, as `--backend` selects it.
+#[derive(Clone)]
+pub enum Backend {
+    Bunny(BunnyClient),
+    LocalFs(LocalFsBackend),
+    /// A backend whose writes are recorded in the index of its zone.
+    Indexed(Box<Backend>, Arc<ListingIndex>),
+}
+
+impl Backend {
+    pub fn new(config: &Config, zone: StorageZoneConfig) -> Self {
+        match config.backend {
+            BackendKind::Bunny => Self::Bunny(BunnyClient::new(zone)),
+            BackendKind::Localfs => {
+                Self::LocalFs(LocalFsBackend::new(&config.localfs_root, &zone.name))
+            }
+        }
+    }
+
+    pub fn fresh(&self) -> Self {
+        match self {
+            Self::Bunny(client) => Self::Bunny(client.fresh()),
+            Self::LocalFs(fs) => Self::LocalFs(fs.clone()),
+            Self::Indexed(inner, index) => Self::Indexed(Box::new(inner.fresh()), index.clone()),
+        }
+    }
+
+    /// Records this backend's writes in `index`, which must index its zone.
+    pub fn indexed(self, index: Arc<ListingIndex>) -> Self {
+        Self::Indexed(Box::new(self), index)
+    }
+
+    /// Up to `limit` objects under `prefix` after `start_after`, in key
+    /// order and without those `skip` rejects, from the listing index; `None`
+    /// without an index or while it is not fresh.
+    pub fn indexed_listing(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+        skip: &dyn Fn(&str) -> bool,
+    ) -> Option<Vec<StorageObject>> {
+        match self {
+            Self::Indexed(inner, index) => index.list(inner, prefix, start_after, limit, skip),
+            _ => None,
+        }
+    }
+
+    /// Points a Bunny backend at a stand-in for the Bunny storage API.
+    #[cfg(test)]
+    pub fn with_base_url(self, base_url: &str) -> Self {
+        match self {
+            Self::Bunny(client) => Self::Bunny(client.with_base_url(base_url)),
+            Self::Indexed(inner, index) => {
+                Self::Indexed(Box::new(inner.with_base_url(base_url)), index)
+            }
+            local => local,
+        }
+    }
+
+    pub fn stats(&self) -> &UpstreamStats {
+        match self {
+            Self::Bunny(client) => client.stats(),
+            Self::LocalFs(fs) => fs.stats(),
+            Self::Indexed(inner, _) => inner.stats(),
+        }
+    }
+
+    /// Totals for this storage zone from the Bunny account API, or `None`
+    /// where there is no such API to ask.
+    pub async fn zone_statistics(&self) -> Result<Option<ZoneStatistics>> {
+        match self {
+            Self::Bunny(client) => client.zone_statistics().await,
+            Self::LocalFs(_) => Ok(None),
+            Self::Indexed(inner, _) => Box::pin(inner.zone_statistics()).await,
+        }
+    }
+}
+
+impl StorageBackend for Backend {
+    fn zone(&self) -> &str {
+        match self {
+            Self::Bunny(client) => client.zone(),
+            Self::LocalFs(fs) => fs.zone(),
+            Self::Indexed(inner, _) => inner.zone(),
+        }
+    }
+
+    fn root(&self) -> &str {
+        match self {
+            Self::Bunny(client) => client.root(),
+            Self::LocalFs(fs) => fs.root(),
+            Self::Indexed(inner, _) => inner.root(),
+        }
+    }
+
+    fn scoped(&self, prefix: &str) -> Self {
+        match self {
+            Self::Bunny(client) => Self::Bunny(client.scoped(prefix)),
+            Self::LocalFs(fs) => Self::LocalFs(fs.scoped(prefix)),
+            Self::Indexed(inner, index) => {
+                Self::Indexed(Box::new(inner.scoped(prefix)), index.clone())
+            }
+        }
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<StorageObject>> {
+        match self {
+            Self::Bunny(client) => client.list(path).await,
+            Self::LocalFs(fs) => fs.list(path).await,
+            Self::Indexed(inner, _) => Box::pin(inner.list(path)).await,
+        }
+    }
+
+    async fn describe(&self, path: &str) -> Result<StorageObject> {
+        match self {
+            Self::Bunny(client) => client.describe(path).await,
+            Self::LocalFs(fs) => fs.describe(path).await,
+            Self::Indexed(inner, _) => Box::pin(inner.describe(path)).await,
+        }
+    }
+
+    async fn download_range(&self, path: &str, range: Option<&str>) -> Result<DownloadResponse> {
+        match self {
+            Self::Bunny(client) => client.download_range(path, range).await,
+            Self::LocalFs(fs) => fs.download_range(path, range).await,
+            Self::Indexed(inner, _) => Box::pin(inner.download_range(path, range)).await,
+        }
+    }
+
+    async fn upload(&self, path: &str, body: Bytes, options: UploadOptions) -> Result<()> {
+        match self {
+            Self::Bunny(client) => client.upload(path, body, options).await,
+            Self::LocalFs(fs) => fs.upload(path, body, options).await,
+            Self::Indexed(inner, index) => {
+                Box::pin(inner.upload(path, body, options)).await?;
+                index.record_write(inner, path).await;
+                Ok(())
+            }
+        }
+    }
+
+    async fn upload_stream(
+        &self,
+        path: &str,
+        stream: impl Stream<Item = std::result::Result<Bytes, std::io::Error>> + Send + 'static,
+        content_length: Option<u64>,
+        content_type: Option<&str>,
+    ) -> Result<()> {
+        match self {
+            Self::Bunny(client) => {
+                client
+                    .upload_stream(path, stream, content_length, content_type)
+                    .await
+            }
+            Self::LocalFs(fs) => {
+                fs.upload_stream(path, stream, content_length, content_type)
+                    .await
+            }
+            Self::Indexed(inner, index) => {
+                Box::pin(inner.upload_stream(path, stream, content_length, content_type)).await?;
+                index.record_write(inner, path).await;
+                Ok(())
+            }
+        }
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        match self {
+            Self::Bunny(client) => client.delete(path).await,
+            Self::LocalFs(fs) => fs.delete(path).await,
+            Self::Indexed(inner, index) => {
+                let result = Box::pin(inner.delete(path)).await;
+                if matches!(result, Ok(()) | Err(ProxyError::NotFound(_))) {
+                    index.record_delete(inner, path);
+                }
+                result
+            }
+        }
+    }
+}
+
+enum DownloadBody {
+    Http(Response),
+    Local {
+        status: StatusCode,
+        headers: HeaderMap,
+        content_length: u64,
+        stream: BoxStream<'static, std::io::Result<Bytes>>,
+    },
+}
+
+/// An object, or a range of it, being read from a backend.
+pub struct DownloadResponse {
+    body: DownloadBody,
+    usage: Option<Arc<UpstreamUsage>>,
+}
+
+impl DownloadResponse {
+    pub(super) fn http(response: Response) -> Self {
+        Self {
+            body: DownloadBody::Http(response),
+            usage: accounting::current(),
+        }
+    }
+
+    pub(super) fn local(
+        status: StatusCode,
+        headers: HeaderMap,
+        content_length: u64,
+        stream: impl Stream<Item = std::io::Result<Bytes>> + Send + 'static,
+    ) -> Self {
+        Self {
+            body: DownloadBody::Local {
+                status,
+                headers,
+                content_length,
+                stream: stream.boxed(),
+            },
+            usage: None,
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        let headers = match &self.body {
+            DownloadBody::Http(response) => response.headers(),
+            DownloadBody::Local { headers, .. } => headers,
+        };
+        headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        match &self.body {
+            DownloadBody::Http(response) => response.content_length(),
+            DownloadBody::Local { content_length, .. } => Some(*content_length),
+        }
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("content-type")
+    }
+
+    pub fn etag(&self) -> Option<String> {
+        self.header("etag").map(|s| s.to_string())
+    }
+
+    pub fn last_modified(&self) -> Option<String> {
+        self.header("last-modified").map(|s| s.to_string())
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match &self.body {
+            DownloadBody::Http(response) => response.status(),
+            DownloadBody::Local { status, .. } => *status,
+        }
+    }
+
+    pub fn content_range(&self) -> Option<String> {
+        self.header("content-range").map(|s| s.to_string())
+    }
+
+    pub async fn bytes(self) -> Result<Bytes> {
+        match self.body {
+            DownloadBody::Http(response) => {
+                let bytes = response.bytes().await?;
+                if let Some(usage) = &self.usage {
+                    usage.add_received(bytes.len());
+                }
+                Ok(bytes)
+            }
+            DownloadBody::Local { stream, .. } => {
+                let bytes = stream
+                    .try_fold(BytesMut::new(), |mut buf, chunk| async move {
+                        buf.extend_from_slice(&chunk);
+                        Ok(buf)
+                    })
+                    .await?;
+                Ok(bytes.freeze())
+            }
+        }
+    }
+
+    /// Streams the body, counting bytes as they arrive since the stream is
+    /// usually drained after the handler has returned.
+    pub fn bytes_stream(
+        self,
+    ) -> impl Stream<Item = std::result::Result<Bytes, std::io::Error>> + Send + 'static {
+        match self.body {
+            DownloadBody::Http(response) => {
+                let usage = self.usage;
+                Either::Left(
+                    response
+                        .bytes_stream()
+                        .inspect(move |chunk| {
+                            if let (Some(usage), Ok(chunk)) = (&usage, chunk) {
+                                usage.add_received(chunk.len());
+                            }
+                        })
+                        .map_err(std::io::Error::other),
+                )
+            }
+            DownloadBody::Local { stream, .. } => Either::Right(stream),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_backend_selection() {
+        let config = Config::parse_from(["bunny-s3-proxy", "-z", "zone", "-k", "key"]);
+        assert!(matches!(
+            Backend::new(&config, (&config).into()),
+            Backend::Bunny(_)
+        ));
+
+        // The local backend needs no access key.
+        let config =
+            Config::try_parse_from(["bunny-s3-proxy", "-z", "zone", "--backend", "localfs"])
+                .unwrap();
+        assert_eq!(config.localfs_root, std::path::Path::new("./data"));
+        let backend = Backend::new(&config, (&config).into());
+        assert!(matches!(backend, Backend::LocalFs(_)));
+        assert_eq!(backend.scoped("a").root(), "a/");
+        assert!(Config::try_parse_from(["bunny-s3-proxy", "-z", "zone"]).is_err());
+    }
+}
@@ -1,77 +1,357 @@
 use bytes::Bytes;
 use futures::Stream;
-use reqwest::{Body, Client, Method, Response, StatusCode};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use reqwest::{Body, Method, RequestBuilder, Response, StatusCode};
+use std::fmt::Write as _;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::config::StorageZoneConfig;
 use crate::error::{ProxyError, Result};
 
-use super::types::{StorageObject, UploadOptions};
+use super::accounting::{self, MeteredStream};
+use super::backend::{DownloadResponse, StorageBackend};
+use super::pool::ClientPool;
+use super::types::{StorageObject, UploadOptions, ZoneStatistics};
+
+/// Bunny account API, used for zone-level statistics.
+const ACCOUNT_API_URL: &str = "https://api.bunny.net";
+
+/// Extra attempts made for idempotent requests that fail with a retryable error.
+const MAX_RETRIES: u32 = 2;
+
+/// Retry counters shared by every clone of a [`BunnyClient`].
+#[derive(Debug, Default)]
+pub struct UpstreamStats {
+    /// Requests re-sent after a retryable failure.
+    pub retries: AtomicU64,
+    /// Requests that still failed after the last retry.
+    pub retries_exhausted: AtomicU64,
+    /// Multipart assemblies started again after a failed upload.
+    pub assembly_retries: AtomicU64,
+    /// Connection pools replaced to pick up new endpoint addresses.
+    pub pool_refreshes: AtomicU64,
+}
+
+impl UpstreamStats {
+    /// Retry counters in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        for (name, help, counter) in [
+            (
+                "bunny_s3_proxy_upstream_retries_total",
+                "Bunny requests re-sent after a retryable failure.",
+                &self.retries,
+            ),
+            (
+                "bunny_s3_proxy_upstream_retries_exhausted_total",
+                "Bunny requests that still failed after the last retry.",
+                &self.retries_exhausted,
+            ),
+            (
+                "bunny_s3_proxy_multipart_assembly_retries_total",
+                "Multipart assemblies started again after a failed upload.",
+                &self.assembly_retries,
+            ),
+            (
+                "bunny_s3_proxy_upstream_pool_refreshes_total",
+                "Bunny connection pools replaced after their maximum lifetime or connect failures.",
+                &self.pool_refreshes,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+/// Characters a path segment keeps as they are: RFC 3986's unreserved set.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Percent-encodes each `/`-separated segment of `path`, so that keys with
+/// `?`, `#`, `%`, spaces or non-ASCII characters reach Bunny intact.
+pub fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Whether `path` could resolve outside the folder it is relative to. URL
+/// normalization collapses `.` and `..` segments and treats `\` as a
+/// separator; percent-encoded forms are checked too in case they are decoded
+/// on the way.
+pub fn escapes_root(path: &str) -> bool {
+    let decoded = path
+        .to_ascii_lowercase()
+        .replace("%2e", ".")
+        .replace("%2f", "/")
+        .replace("%5c", "/");
+    decoded
+        .split(['/', '\\'])
+        .any(|segment| segment == "." || segment == "..")
+}
+
+/// Parses `--key-prefix` into the folder it names, ending in `/`.
+pub fn parse_key_prefix(s: &str) -> std::result::Result<String, String> {
+    let prefix = s.trim_matches('/');
+    if prefix.is_empty() {
+        return Err("the prefix must name a folder".to_string());
+    }
+    if escapes_root(prefix) || prefix.split('/').any(str::is_empty) {
+        return Err("the prefix must not contain empty, '.' or '..' segments".to_string());
+    }
+    Ok(format!("{}/", prefix))
+}
 
 #[derive(Clone)]
 pub struct BunnyClient {
-    client: Client,
+    pool: Arc<ClientPool>,
     config: Arc<StorageZoneConfig>,
+    stats: Arc<UpstreamStats>,
+    /// Folder inside the zone that paths are relative to, empty or ending in `/`.
+    root: Arc<str>,
+    base_url: Arc<str>,
 }
 
 impl BunnyClient {
     pub fn new(config: StorageZoneConfig) -> Self {
-        let client = Client::builder()
-            .user_agent("bunny-s3-proxy/0.1.0")
-            .connect_timeout(std::time::Duration::from_secs(30))
-            .http2_adaptive_window(true)
-            .build()
-            .expect("Failed to create HTTP client");
-
+        let stats = Arc::<UpstreamStats>::default();
+        let pool = Arc::new(ClientPool::new(config.pool, Arc::clone(&stats)));
+        let base_url = match &config.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/'),
+            None => config.region.base_url(),
+        };
         Self {
-            client,
+            pool,
+            base_url: Arc::from(base_url),
             config: Arc::new(config),
+            stats,
+            root: Arc::from(""),
         }
     }
 
     pub fn fresh(&self) -> Self {
         Self {
-            client: self.client.clone(),
+            pool: Arc::clone(&self.pool),
             config: Arc::clone(&self.config),
+            stats: Arc::clone(&self.stats),
+            root: Arc::clone(&self.root),
+            base_url: Arc::clone(&self.base_url),
+        }
+    }
+
+    /// Points the client at a stand-in for the Bunny storage API.
+    #[cfg(test)]
+    pub fn with_base_url(self, base_url: &str) -> Self {
+        Self {
+            base_url: Arc::from(base_url.trim_end_matches('/')),
+            ..self
+        }
+    }
+
+    /// Rewrites the path of a listed object as if the zone started at this
+    /// client's root.
+    fn relativize(&self, mut object: StorageObject) -> StorageObject {
+        if self.root.is_empty() {
+            return object;
+        }
+        let zone_root = format!("/{}/{}", object.storage_zone_name, self.root);
+        if let Some(rest) = object.path.strip_prefix(&zone_root) {
+            object.path = format!("/{}/{}", object.storage_zone_name, rest);
         }
+        object
+    }
+
+    pub fn stats(&self) -> &UpstreamStats {
+        &self.stats
     }
 
-    fn build_url(&self, path: &str) -> String {
-        let base = self.config.region.base_url();
+    /// The URL of `path` under this client's root. Paths with `.` or `..`
+    /// segments, which the URL would resolve to another path, are refused
+    /// with AccessDenied.
+    fn build_url(&self, path: &str) -> Result<String> {
+        if escapes_root(path) {
+            tracing::warn!("Refusing path {} with dot segments", path);
+            return Err(ProxyError::AccessDenied);
+        }
+        let base = &self.base_url;
         let zone = &self.config.name;
         let clean_path = path.trim_start_matches('/');
 
-        if clean_path.is_empty() {
-            format!("{}/{}/", base, zone)
+        Ok(format!(
+            "{}/{}/{}",
+            base,
+            zone,
+            encode_path(&format!("{}{}", self.root, clean_path))
+        ))
+    }
+
+    /// A storage API request, authenticated and carrying the current trace
+    /// context.
+    fn storage_request(&self, method: Method, url: &str) -> RequestBuilder {
+        self.pool
+            .client()
+            .request(method, url)
+            .header("AccessKey", &self.config.access_key)
+            .headers(crate::telemetry::trace_headers())
+    }
+
+    /// Sends a request, logging it and the response with `--debug-http`,
+    /// and counting connect failures towards replacing the pool.
+    async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let result = Self::send_logged(request).await;
+        self.pool.record(&result);
+        result
+    }
+
+    async fn send_logged(request: RequestBuilder) -> reqwest::Result<Response> {
+        if !crate::debug_http::enabled() {
+            return request.send().await;
+        }
+        let (client, request) = request.build_split();
+        let request = request?;
+        crate::debug_http::bunny_request(&request);
+        let response = client.execute(request).await?;
+        crate::debug_http::bunny_response(&response);
+        Ok(response)
+    }
+
+    /// Sends a request without a streaming body, retrying retryable failures.
+    async fn send_idempotent(&self, request: RequestBuilder) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            accounting::record_call();
+            let Some(req) = request.try_clone() else {
+                return Ok(self.send(request).await?);
+            };
+            match self.send(req).await {
+                Ok(r) => return Ok(r),
+                Err(e) => {
+                    let err = ProxyError::from(e);
+                    if !err.is_retryable() {
+                        return Err(err);
+                    }
+                    if attempt >= MAX_RETRIES {
+                        self.stats.retries_exhausted.fetch_add(1, Ordering::Relaxed);
+                        return Err(err);
+                    }
+                    attempt += 1;
+                    self.stats.retries.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!(
+                        "Retrying Bunny.net request after {} (attempt {})",
+                        err.internal_code(),
+                        attempt
+                    );
+                    tokio::time::sleep(std::time::Duration::from_millis(100 << attempt)).await;
+                }
+            }
+        }
+    }
+
+    /// Totals for this storage zone from the account API, or `None` without
+    /// an account API key.
+    #[tracing::instrument(name = "bunny.zone_statistics", skip(self), fields(status))]
+    pub async fn zone_statistics(&self) -> Result<Option<ZoneStatistics>> {
+        let Some(api_key) = &self.config.api_key else {
+            return Ok(None);
+        };
+
+        let request = self
+            .pool
+            .client()
+            .get(format!(
+                "{}/storagezone?search={}",
+                ACCOUNT_API_URL,
+                url::form_urlencoded::byte_serialize(self.config.name.as_bytes())
+                    .collect::<String>()
+            ))
+            .header("AccessKey", api_key)
+            .headers(crate::telemetry::trace_headers())
+            .header("Accept", "application/json");
+        let response = self.send_idempotent(request).await?;
+
+        let status = response.status();
+        tracing::Span::current().record("status", status.as_u16());
+        match status {
+            StatusCode::OK => {
+                let body = response.bytes().await?;
+                accounting::record_received(body.len());
+                let zones: Vec<ZoneStatistics> = serde_json::from_slice(&body)?;
+                Ok(zones.into_iter().find(|z| z.name == self.config.name))
+            }
+            StatusCode::UNAUTHORIZED => Err(ProxyError::AccessDenied),
+            _ => {
+                let body = response.text().await.unwrap_or_default();
+                tracing::error!(
+                    "Bunny.net storage zone lookup returned {}: {}",
+                    status,
+                    body
+                );
+                Err(ProxyError::BunnyApi(format!(
+                    "Storage zone lookup failed: {}",
+                    status
+                )))
+            }
+        }
+    }
+}
+
+impl StorageBackend for BunnyClient {
+    fn zone(&self) -> &str {
+        &self.config.name
+    }
+
+    fn root(&self) -> &str {
+        &self.root
+    }
+
+    fn scoped(&self, prefix: &str) -> Self {
+        let prefix = prefix.trim_matches('/');
+        let root = if prefix.is_empty() {
+            self.root.to_string()
         } else {
-            format!("{}/{}/{}", base, zone, clean_path)
+            format!("{}{}/", self.root, prefix)
+        };
+        Self {
+            root: Arc::from(root),
+            ..self.clone()
         }
     }
 
-    pub async fn list(&self, path: &str) -> Result<Vec<StorageObject>> {
-        let mut url = self.build_url(path);
+    #[tracing::instrument(name = "bunny.list", skip(self), fields(status))]
+    async fn list(&self, path: &str) -> Result<Vec<StorageObject>> {
+        let mut url = self.build_url(path)?;
         if !url.ends_with('/') {
             url.push('/');
         }
 
-        let response = match self
-            .client
-            .get(&url)
-            .header("AccessKey", &self.config.access_key)
-            .header("Accept", "application/json")
-            .send()
-            .await
-        {
+        let request = self
+            .storage_request(Method::GET, &url)
+            .header("Accept", "application/json");
+        let response = match self.send_idempotent(request).await {
             Ok(r) => r,
             Err(e) => {
                 tracing::error!("Bunny.net LIST {} request failed: {:?}", path, e);
-                return Err(e.into());
+                return Err(e);
             }
         };
 
         let status = response.status();
+        tracing::Span::current().record("status", status.as_u16());
         match status {
-            StatusCode::OK => Ok(response.json().await?),
+            StatusCode::OK => {
+                let body = response.bytes().await?;
+                accounting::record_received(body.len());
+                let objects: Vec<StorageObject> = serde_json::from_slice(&body)?;
+                Ok(objects.into_iter().map(|o| self.relativize(o)).collect())
+            }
             StatusCode::NOT_FOUND => Ok(Vec::new()),
             StatusCode::UNAUTHORIZED => Err(ProxyError::AccessDenied),
             _ => {
@@ -82,60 +362,34 @@ impl BunnyClient {
         }
     }
 
-    pub async fn list_recursive(
-        &self,
-        prefix: &str,
-        max_keys: Option<usize>,
-    ) -> Result<Vec<StorageObject>> {
-        let mut all_objects = Vec::new();
-        let mut dirs_to_process = vec![prefix.to_string()];
-
-        while let Some(dir) = dirs_to_process.pop() {
-            if let Some(max) = max_keys
-                && all_objects.len() >= max
-            {
-                break;
-            }
-
-            let objects = self.list(&dir).await?;
-            for obj in objects {
-                if obj.is_directory {
-                    dirs_to_process.push(obj.full_path());
-                } else {
-                    all_objects.push(obj);
-                    if let Some(max) = max_keys
-                        && all_objects.len() >= max
-                    {
-                        break;
-                    }
-                }
-            }
-        }
-
-        Ok(all_objects)
-    }
-
-    pub async fn describe(&self, path: &str) -> Result<StorageObject> {
-        let url = self.build_url(path);
+    #[tracing::instrument(name = "bunny.describe", skip(self), fields(status))]
+    async fn describe(&self, path: &str) -> Result<StorageObject> {
+        let url = self.build_url(path)?;
 
-        let response = match self
-            .client
-            .request(Method::from_bytes(b"DESCRIBE").unwrap(), &url)
-            .header("AccessKey", &self.config.access_key)
-            .header("Accept", "application/json")
-            .send()
-            .await
-        {
+        let request = self
+            .storage_request(Method::from_bytes(b"DESCRIBE").unwrap(), &url)
+            .header("Accept", "application/json");
+        let response = match self.send_idempotent(request).await {
             Ok(r) => r,
             Err(e) => {
                 tracing::error!("Bunny.net DESCRIBE {} request failed: {:?}", path, e);
-                return Err(e.into());
+                return Err(e);
             }
         };
 
         let status = response.status();
+        tracing::Span::current().record("status", status.as_u16());
         match status {
-            StatusCode::OK => Ok(response.json().await?),
+            StatusCode::OK => {
+                let body = response.bytes().await?;
+                accounting::record_received(body.len());
+                let obj: StorageObject = serde_json::from_slice(&body)?;
+                // A missing file is described with a Length of -1.
+                if obj.length < 0 && !obj.is_directory {
+                    return Err(ProxyError::NotFound(path.to_string()));
+                }
+                Ok(self.relativize(obj))
+            }
             StatusCode::NOT_FOUND => Err(ProxyError::NotFound(path.to_string())),
             StatusCode::UNAUTHORIZED => Err(ProxyError::AccessDenied),
             _ => {
@@ -146,37 +400,33 @@ impl BunnyClient {
         }
     }
 
-    pub async fn download(&self, path: &str) -> Result<DownloadResponse> {
-        self.download_range(path, None).await
-    }
+    #[tracing::instrument(name = "bunny.get", skip(self), fields(status, bytes))]
+    async fn download_range(&self, path: &str, range: Option<&str>) -> Result<DownloadResponse> {
+        let url = self.build_url(path)?;
 
-    pub async fn download_range(
-        &self,
-        path: &str,
-        range: Option<&str>,
-    ) -> Result<DownloadResponse> {
-        let url = self.build_url(path);
-
-        let mut request = self
-            .client
-            .get(&url)
-            .header("AccessKey", &self.config.access_key);
+        let mut request = self.storage_request(Method::GET, &url);
 
         if let Some(range_value) = range {
             request = request.header("Range", range_value);
         }
 
-        let response = match request.send().await {
+        let response = match self.send_idempotent(request).await {
             Ok(r) => r,
             Err(e) => {
                 tracing::error!("Bunny.net GET {} request failed: {:?}", path, e);
-                return Err(e.into());
+                return Err(e);
             }
         };
 
         let status = response.status();
+        tracing::Span::current().record("status", status.as_u16());
         match status {
-            StatusCode::OK | StatusCode::PARTIAL_CONTENT => Ok(DownloadResponse::new(response)),
+            StatusCode::OK | StatusCode::PARTIAL_CONTENT => {
+                if let Some(len) = response.content_length() {
+                    tracing::Span::current().record("bytes", len);
+                }
+                Ok(DownloadResponse::http(response))
+            }
             StatusCode::NOT_FOUND => Err(ProxyError::NotFound(path.to_string())),
             StatusCode::UNAUTHORIZED => Err(ProxyError::AccessDenied),
             _ => {
@@ -187,13 +437,16 @@ impl BunnyClient {
         }
     }
 
-    pub async fn upload(&self, path: &str, body: Bytes, options: UploadOptions) -> Result<()> {
-        let url = self.build_url(path);
+    #[tracing::instrument(
+        name = "bunny.put",
+        skip(self, body, options),
+        fields(status, bytes = body.len())
+    )]
+    async fn upload(&self, path: &str, body: Bytes, options: UploadOptions) -> Result<()> {
+        let url = self.build_url(path)?;
 
         let mut request = self
-            .client
-            .put(&url)
-            .header("AccessKey", &self.config.access_key)
+            .storage_request(Method::PUT, &url)
             .header("Content-Type", "application/octet-stream");
 
         if let Some(checksum) = options.sha256_checksum {
@@ -204,7 +457,11 @@ impl BunnyClient {
         }
 
         tracing::debug!("Bunny.net PUT {} starting", path);
-        let response = match request.body(body).send().await {
+        accounting::record_call();
+        accounting::record_sent(body.len());
+        #[cfg(feature = "chaos")]
+        let body = crate::chaos::upload_body(path, body);
+        let response = match self.send(request.body(body)).await {
             Ok(r) => r,
             Err(e) => {
                 tracing::error!("Bunny.net PUT {} request failed: {:?}", path, e);
@@ -213,6 +470,7 @@ impl BunnyClient {
         };
 
         let status = response.status();
+        tracing::Span::current().record("status", status.as_u16());
         tracing::debug!("Bunny.net PUT {} returned {}", path, status);
         match status {
             StatusCode::OK | StatusCode::CREATED => Ok(()),
@@ -232,27 +490,37 @@ impl BunnyClient {
         }
     }
 
-    pub async fn upload_stream(
+    #[tracing::instrument(
+        name = "bunny.put_stream",
+        skip(self, stream, content_type),
+        fields(status, bytes = content_length)
+    )]
+    async fn upload_stream(
         &self,
         path: &str,
         stream: impl Stream<Item = std::result::Result<Bytes, std::io::Error>> + Send + 'static,
         content_length: Option<u64>,
+        content_type: Option<&str>,
     ) -> Result<()> {
-        let url = self.build_url(path);
-        let body = Body::wrap_stream(stream);
+        let url = self.build_url(path)?;
+        #[cfg(feature = "chaos")]
+        let stream = crate::chaos::wrap_upload(path, content_length, Box::pin(stream));
+        let body = Body::wrap_stream(MeteredStream::new(stream));
 
         let mut request = self
-            .client
-            .put(&url)
-            .header("AccessKey", &self.config.access_key)
+            .storage_request(Method::PUT, &url)
             .header("Content-Type", "application/octet-stream");
 
         if let Some(len) = content_length {
             request = request.header("Content-Length", len);
         }
+        if let Some(content_type) = content_type {
+            request = request.header("Override-Content-Type", content_type);
+        }
 
         tracing::debug!("Bunny.net PUT (stream) {} starting", path);
-        let response = match request.body(body).send().await {
+        accounting::record_call();
+        let response = match self.send(request.body(body)).await {
             Ok(r) => r,
             Err(e) => {
                 tracing::error!("Bunny.net PUT (stream) {} request failed: {:?}", path, e);
@@ -261,6 +529,7 @@ impl BunnyClient {
         };
 
         let status = response.status();
+        tracing::Span::current().record("status", status.as_u16());
         tracing::debug!("Bunny.net PUT (stream) {} returned {}", path, status);
         match status {
             StatusCode::OK | StatusCode::CREATED => Ok(()),
@@ -290,24 +559,21 @@ impl BunnyClient {
         }
     }
 
-    pub async fn delete(&self, path: &str) -> Result<()> {
-        let url = self.build_url(path);
+    #[tracing::instrument(name = "bunny.delete", skip(self), fields(status))]
+    async fn delete(&self, path: &str) -> Result<()> {
+        let url = self.build_url(path)?;
 
-        let response = match self
-            .client
-            .delete(&url)
-            .header("AccessKey", &self.config.access_key)
-            .send()
-            .await
-        {
+        let request = self.storage_request(Method::DELETE, &url);
+        let response = match self.send_idempotent(request).await {
             Ok(r) => r,
             Err(e) => {
                 tracing::error!("Bunny.net DELETE {} request failed: {:?}", path, e);
-                return Err(e.into());
+                return Err(e);
             }
         };
 
         let status = response.status();
+        tracing::Span::current().record("status", status.as_u16());
         match status {
             StatusCode::OK | StatusCode::NOT_FOUND | StatusCode::BAD_REQUEST => Ok(()),
             StatusCode::UNAUTHORIZED => Err(ProxyError::AccessDenied),
@@ -318,69 +584,173 @@ impl BunnyClient {
             }
         }
     }
-
-    pub async fn copy(&self, source: &str, dest: &str) -> Result<()> {
-        let download = self.download(source).await?;
-        let bytes = download.bytes().await?;
-        self.upload(dest, bytes, UploadOptions::default()).await
-    }
 }
 
-pub struct DownloadResponse {
-    response: Response,
-}
-
-impl DownloadResponse {
-    fn new(response: Response) -> Self {
-        Self { response }
-    }
-
-    pub fn content_length(&self) -> Option<u64> {
-        self.response.content_length()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageRegion;
+    use std::collections::HashMap;
+
+    fn client() -> BunnyClient {
+        BunnyClient::new(StorageZoneConfig {
+            name: "zone".into(),
+            access_key: "key".into(),
+            region: StorageRegion::Falkenstein,
+            api_key: None,
+            endpoint: None,
+            pool: Default::default(),
+        })
     }
 
-    pub fn content_type(&self) -> Option<&str> {
-        self.response
-            .headers()
-            .get("content-type")
-            .and_then(|v| v.to_str().ok())
+    fn object(path: &str, name: &str) -> StorageObject {
+        StorageObject {
+            guid: String::new(),
+            user_id: String::new(),
+            last_changed: chrono::Utc::now(),
+            date_created: chrono::Utc::now(),
+            storage_zone_name: "zone".into(),
+            path: path.into(),
+            object_name: name.into(),
+            length: 0,
+            storage_zone_id: 1,
+            is_directory: false,
+            server_id: 0,
+            checksum: None,
+            replicated_zones: None,
+            content_type: String::new(),
+        }
     }
 
-    pub fn etag(&self) -> Option<String> {
-        self.response
-            .headers()
-            .get("etag")
-            .and_then(|v| v.to_str().ok())
-            .map(|s| s.to_string())
+    #[test]
+    fn test_scoped_paths_are_relative_to_the_folder() {
+        let root = client();
+        assert_eq!(
+            root.build_url("").unwrap(),
+            "https://storage.bunnycdn.com/zone/"
+        );
+        assert_eq!(
+            root.build_url("/a/b").unwrap(),
+            "https://storage.bunnycdn.com/zone/a/b"
+        );
+
+        let scoped = root.scoped("photos").scoped("/2024/");
+        assert_eq!(
+            scoped.build_url("").unwrap(),
+            "https://storage.bunnycdn.com/zone/photos/2024/"
+        );
+        assert_eq!(
+            scoped.build_url("a/b").unwrap(),
+            "https://storage.bunnycdn.com/zone/photos/2024/a/b"
+        );
+
+        let listed = scoped.relativize(object("/zone/photos/2024/a/", "b"));
+        assert_eq!(listed.s3_key(), "a/b");
+        let top = scoped.relativize(object("/zone/photos/2024/", "c"));
+        assert_eq!(top.s3_key(), "c");
+        assert_eq!(root.relativize(object("/zone/x/", "y")).s3_key(), "x/y");
     }
 
-    pub fn last_modified(&self) -> Option<String> {
-        self.response
-            .headers()
-            .get("last-modified")
-            .and_then(|v| v.to_str().ok())
-            .map(|s| s.to_string())
+    #[test]
+    fn test_scoped_client_refuses_traversal() {
+        let scoped = client().scoped("apps/service-a");
+        for path in [
+            "../service-b/secret",
+            "a/../../b",
+            "./a",
+            "a/%2e%2e/%2E%2E/b",
+            "a\\..\\..\\b",
+            "..%2Fservice-b",
+            "..",
+        ] {
+            let err = scoped.build_url(path).unwrap_err();
+            assert_eq!(err.s3_error_code(), "AccessDenied", "{}", path);
+        }
+        assert!(scoped.build_url("a..b/c.d/...").is_ok());
+        // The URL would resolve to `b` even at the zone root.
+        assert!(client().build_url("a/../b").is_err());
+        assert!(client().build_url("a..b/c.d/...").is_ok());
     }
 
-    pub fn status(&self) -> StatusCode {
-        self.response.status()
+    #[test]
+    fn test_encode_path() {
+        assert_eq!(
+            encode_path("reports/2024#final?.csv"),
+            "reports/2024%23final%3F.csv"
+        );
+        assert_eq!(encode_path("a b+c.txt"), "a%20b%2Bc.txt");
+        assert_eq!(encode_path("emoji-🦀.bin"), "emoji-%F0%9F%A6%80.bin");
+        assert_eq!(encode_path("100%/x~y_z/"), "100%25/x~y_z/");
     }
 
-    pub fn content_range(&self) -> Option<String> {
-        self.response
-            .headers()
-            .get("content-range")
-            .and_then(|v| v.to_str().ok())
-            .map(|s| s.to_string())
+    /// A stand-in for Bunny's PUT and GET, keyed by the raw request path.
+    async fn storing_bunny() -> (String, Arc<std::sync::Mutex<HashMap<String, Bytes>>>) {
+        use axum::http::{Method as AxumMethod, StatusCode as AxumStatus, Uri};
+        use axum::response::IntoResponse;
+
+        let stored = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let objects = Arc::clone(&stored);
+        let app = axum::Router::new().route(
+            "/{*path}",
+            axum::routing::any(move |method: AxumMethod, uri: Uri, body: Bytes| {
+                let objects = Arc::clone(&objects);
+                async move {
+                    let path = uri.path().to_string();
+                    let mut objects = objects.lock().unwrap();
+                    if method == AxumMethod::PUT {
+                        objects.insert(path, body);
+                        return AxumStatus::CREATED.into_response();
+                    }
+                    match objects.get(&path) {
+                        Some(body) => body.clone().into_response(),
+                        None => AxumStatus::NOT_FOUND.into_response(),
+                    }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, stored)
     }
 
-    pub async fn bytes(self) -> Result<Bytes> {
-        Ok(self.response.bytes().await?)
+    #[tokio::test]
+    async fn test_keys_round_trip_through_bunny_urls() {
+        let (url, stored) = storing_bunny().await;
+        let client = client().with_base_url(&url);
+        for (key, path) in [
+            (
+                "reports/2024#final?.csv",
+                "/zone/reports/2024%23final%3F.csv",
+            ),
+            ("a b+c.txt", "/zone/a%20b%2Bc.txt"),
+            ("emoji-🦀.bin", "/zone/emoji-%F0%9F%A6%80.bin"),
+            ("100%.txt", "/zone/100%25.txt"),
+        ] {
+            let body = Bytes::from(format!("contents of {}", key));
+            client
+                .upload(key, body.clone(), UploadOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(stored.lock().unwrap().get(path), Some(&body), "{}", key);
+            let read = client.download(key).await.unwrap().bytes().await.unwrap();
+            assert_eq!(read, body);
+        }
+        assert_eq!(stored.lock().unwrap().len(), 4);
     }
 
-    pub fn bytes_stream(
-        self,
-    ) -> impl futures::Stream<Item = std::result::Result<Bytes, reqwest::Error>> + Send {
-        self.response.bytes_stream()
+    #[test]
+    fn test_parse_key_prefix() {
+        assert_eq!(
+            parse_key_prefix("apps/service-a/").unwrap(),
+            "apps/service-a/"
+        );
+        assert_eq!(
+            parse_key_prefix("/apps/service-a").unwrap(),
+            "apps/service-a/"
+        );
+        assert!(parse_key_prefix("/").is_err());
+        assert!(parse_key_prefix("apps/../etc").is_err());
+        assert!(parse_key_prefix("apps//a").is_err());
     }
 }
@@ -0,0 +1,565 @@
+//! `--listing-index`: a sorted, in-memory index of every key in a zone, so
+//! listings without a delimiter are answered without walking Bunny's
+//! directory tree, which for millions of keys takes minutes.
+//!
+//! The index is built by a full walk at startup and reconciled by another
+//! every `--listing-index-interval`; writes made while a walk runs are
+//! replayed over its result. Every write the proxy makes through an indexed
+//! [`Backend`] updates the index before it returns: uploads describe the
+//! stored object for its size, ETag and timestamp, and deletes drop the key
+//! or the folder's keys. Writes by other processes or straight to Bunny are
+//! only seen by the next walk, so the index may lag by up to
+//! `--listing-index-max-age`, past which, or until the first walk, or after
+//! a write it could not follow, listings fall back to the live walk.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use tokio::sync::Notify;
+
+use crate::config::Config;
+use crate::error::{ProxyError, Result};
+
+use super::backend::{Backend, StorageBackend};
+use super::types::StorageObject;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    length: i64,
+    last_changed: DateTime<Utc>,
+    etag: String,
+}
+
+impl Entry {
+    fn of(obj: &StorageObject) -> Self {
+        Self {
+            length: obj.length,
+            last_changed: obj.last_changed,
+            etag: obj.etag(),
+        }
+    }
+}
+
+/// A write made while a walk was running, replayed over the walk's result.
+#[derive(Debug)]
+enum Touch {
+    Key(String),
+    Folder(String),
+}
+
+#[derive(Debug, Default)]
+struct Status {
+    built_at: Option<DateTime<Utc>>,
+    /// Why the index cannot be trusted until the next walk.
+    stale: Option<String>,
+    /// Writes since the running walk started, if one is running.
+    touched: Option<Vec<Touch>>,
+}
+
+#[derive(Debug, Default)]
+struct IndexStats {
+    rebuilds: AtomicU64,
+    failures: AtomicU64,
+    served: AtomicU64,
+    fallbacks: AtomicU64,
+    added: AtomicU64,
+    removed: AtomicU64,
+    changed: AtomicU64,
+    duration_ms: AtomicU64,
+}
+
+/// Reads one metric of an index.
+type Metric = fn(&ListingIndex) -> u64;
+
+/// The state of one zone's index, for the admin endpoint.
+#[derive(Debug, Serialize)]
+pub struct IndexStatus {
+    pub bucket: String,
+    pub keys: usize,
+    pub built_at: Option<DateTime<Utc>>,
+    pub fresh: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stale: Option<String>,
+    pub rebuilding: bool,
+}
+
+pub struct ListingIndex {
+    bucket: String,
+    /// The backend walked to rebuild the index, whose keys it holds.
+    base: Backend,
+    exclude: fn(&str) -> bool,
+    concurrency: usize,
+    max_age: chrono::Duration,
+    entries: RwLock<BTreeMap<String, Entry>>,
+    status: Mutex<Status>,
+    rebuild_requested: Notify,
+    stats: IndexStats,
+}
+
+impl ListingIndex {
+    /// An empty index of `base`, serving `bucket`, that leaves out keys for
+    /// which `exclude` holds.
+    pub fn new(config: &Config, bucket: &str, base: Backend, exclude: fn(&str) -> bool) -> Self {
+        Self {
+            bucket: bucket.to_string(),
+            base,
+            exclude,
+            concurrency: config.usage_walk_concurrency,
+            max_age: config.listing_index_max_age,
+            entries: RwLock::default(),
+            status: Mutex::default(),
+            rebuild_requested: Notify::new(),
+            stats: IndexStats::default(),
+        }
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /// Where the folder of `backend`, the base or a scope of it, sits
+    /// within the index.
+    fn offset<'a>(&self, backend: &'a Backend) -> &'a str {
+        backend
+            .root()
+            .strip_prefix(self.base.root())
+            .unwrap_or_default()
+    }
+
+    fn is_fresh(&self, status: &Status) -> bool {
+        status.stale.is_none()
+            && status
+                .built_at
+                .is_some_and(|at| Utc::now() - at <= self.max_age)
+    }
+
+    /// Up to `limit` objects of `backend` under `prefix` after
+    /// `start_after`, in key order, leaving out those `skip` rejects; `None`
+    /// when the index is not fresh enough to answer.
+    pub fn list(
+        &self,
+        backend: &Backend,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+        skip: &dyn Fn(&str) -> bool,
+    ) -> Option<Vec<StorageObject>> {
+        if !self.is_fresh(&self.status.lock().unwrap()) {
+            self.stats.fallbacks.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.stats.served.fetch_add(1, Ordering::Relaxed);
+        let offset = self.offset(backend);
+        let from = format!("{}{}", offset, prefix);
+        let start = match start_after.map(|s| format!("{}{}", offset, s)) {
+            Some(after) if after >= from => Bound::Excluded(after),
+            _ => Bound::Included(from.clone()),
+        };
+        let entries = self.entries.read().unwrap();
+        Some(
+            entries
+                .range((start, Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(&from))
+                .map(|(key, entry)| (&key[offset.len()..], entry))
+                .filter(|(key, _)| !skip(key))
+                .take(limit)
+                .map(|(key, entry)| StorageObject {
+                    guid: String::new(),
+                    user_id: String::new(),
+                    last_changed: entry.last_changed,
+                    date_created: entry.last_changed,
+                    storage_zone_name: backend.zone().to_string(),
+                    path: format!("/{}/", backend.zone()),
+                    object_name: key.to_string(),
+                    length: entry.length,
+                    storage_zone_id: 0,
+                    is_directory: false,
+                    server_id: 0,
+                    checksum: Some(entry.etag.clone()),
+                    replicated_zones: None,
+                    content_type: String::new(),
+                })
+                .collect(),
+        )
+    }
+
+    fn touch(&self, status: &mut Status, touch: Touch) {
+        if let Some(touched) = &mut status.touched {
+            touched.push(touch);
+        }
+    }
+
+    fn mark_stale(&self, reason: String) {
+        tracing::warn!("Listing index of {} is stale: {}", self.bucket, reason);
+        self.status.lock().unwrap().stale = Some(reason);
+        self.rebuild_requested.notify_one();
+    }
+
+    /// Follows a write of `path` through `backend`, successful or not, by
+    /// describing what is stored there now.
+    pub async fn record_write(&self, backend: &Backend, path: &str) {
+        let key = format!("{}{}", self.offset(backend), path);
+        if key.ends_with('/') || (self.exclude)(&key) {
+            return;
+        }
+        let entry = match backend.describe(path).await {
+            Ok(obj) if !obj.is_directory && obj.length >= 0 => Some(Entry::of(&obj)),
+            Ok(_) | Err(ProxyError::NotFound(_)) => None,
+            Err(e) => {
+                self.mark_stale(format!("could not describe {} after a write: {}", key, e));
+                return;
+            }
+        };
+        let mut status = self.status.lock().unwrap();
+        let mut entries = self.entries.write().unwrap();
+        match entry {
+            Some(entry) => entries.insert(key.clone(), entry),
+            None => entries.remove(&key),
+        };
+        self.touch(&mut status, Touch::Key(key));
+    }
+
+    /// Follows a delete of `path` through `backend`: the key, or a folder
+    /// with everything below it.
+    pub fn record_delete(&self, backend: &Backend, path: &str) {
+        let key = format!("{}{}", self.offset(backend), path);
+        let folder = format!("{}/", key.trim_end_matches('/'));
+        let mut status = self.status.lock().unwrap();
+        let mut entries = self.entries.write().unwrap();
+        entries.remove(&key);
+        let below: Vec<String> = entries
+            .range(folder.clone()..)
+            .take_while(|(k, _)| k.starts_with(&folder))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for k in below {
+            entries.remove(&k);
+        }
+        self.touch(&mut status, Touch::Key(key));
+        self.touch(&mut status, Touch::Folder(folder));
+    }
+
+    /// Asks the background task to walk the zone again now.
+    pub fn request_rebuild(&self) {
+        self.rebuild_requested.notify_one();
+    }
+
+    /// Walks the zone and replaces the index with what it found, keeping
+    /// the writes made meanwhile.
+    pub async fn rebuild(&self) {
+        {
+            let mut status = self.status.lock().unwrap();
+            if status.touched.is_some() {
+                return;
+            }
+            status.touched = Some(Vec::new());
+        }
+        let started = Instant::now();
+        let walked = self
+            .base
+            .list_recursive_concurrent("", self.concurrency)
+            .await;
+        self.finish(walked, started);
+    }
+
+    /// Replaces the index with the result of a walk begun at `started`.
+    fn finish(&self, walked: Result<Vec<StorageObject>>, started: Instant) {
+        let mut status = self.status.lock().unwrap();
+        let touched = status.touched.take().unwrap_or_default();
+        let objects = match walked {
+            Ok(objects) => objects,
+            Err(e) => {
+                self.stats.failures.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!("Listing index walk of {} failed: {}", self.bucket, e);
+                return;
+            }
+        };
+        let mut rebuilt: BTreeMap<String, Entry> = objects
+            .iter()
+            .filter(|obj| !obj.is_directory && obj.length >= 0)
+            .map(|obj| (obj.s3_key(), Entry::of(obj)))
+            .filter(|(key, _)| !(self.exclude)(key))
+            .collect();
+
+        let mut entries = self.entries.write().unwrap();
+        // What the proxy wrote during the walk is known better than the walk.
+        for touch in touched {
+            match touch {
+                Touch::Key(key) => match entries.get(&key) {
+                    Some(entry) => rebuilt.insert(key, entry.clone()),
+                    None => rebuilt.remove(&key),
+                },
+                Touch::Folder(folder) => {
+                    rebuilt.retain(|k, _| !k.starts_with(&folder) || entries.contains_key(k));
+                    None
+                }
+            };
+        }
+        let (mut added, mut changed) = (0, 0);
+        for (key, entry) in &rebuilt {
+            match entries.get(key) {
+                None => added += 1,
+                Some(known) if known != entry => changed += 1,
+                Some(_) => {}
+            }
+        }
+        let removed = entries.keys().filter(|k| !rebuilt.contains_key(*k)).count();
+        let keys = rebuilt.len();
+        *entries = rebuilt;
+        status.built_at = Some(Utc::now());
+        status.stale = None;
+
+        let first = self.stats.rebuilds.fetch_add(1, Ordering::Relaxed) == 0;
+        let stats = &self.stats;
+        stats.added.store(added, Ordering::Relaxed);
+        stats.removed.store(removed as u64, Ordering::Relaxed);
+        stats.changed.store(changed, Ordering::Relaxed);
+        stats
+            .duration_ms
+            .store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        match first {
+            true => tracing::info!(
+                "Listing index of {} built: {} keys in {:?}",
+                self.bucket,
+                keys,
+                started.elapsed()
+            ),
+            false => tracing::info!(
+                "Listing index of {} reconciled: {} keys in {:?}, {} added, {} removed, {} changed",
+                self.bucket,
+                keys,
+                started.elapsed(),
+                added,
+                removed,
+                changed
+            ),
+        }
+    }
+
+    /// Rebuilds the index now, every `interval` and whenever asked to.
+    pub async fn run(self: Arc<Self>, interval: std::time::Duration) {
+        loop {
+            self.rebuild().await;
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = self.rebuild_requested.notified() => {}
+            }
+        }
+    }
+
+    pub fn status(&self) -> IndexStatus {
+        let status = self.status.lock().unwrap();
+        IndexStatus {
+            bucket: self.bucket.clone(),
+            keys: self.entries.read().unwrap().len(),
+            built_at: status.built_at,
+            fresh: self.is_fresh(&status),
+            stale: status.stale.clone(),
+            rebuilding: status.touched.is_some(),
+        }
+    }
+
+    /// Index gauges and counters in the Prometheus text exposition format,
+    /// one series per indexed bucket; empty without indexes.
+    pub fn render_metrics(indexes: &[Arc<ListingIndex>]) -> String {
+        let mut out = String::new();
+        if indexes.is_empty() {
+            return out;
+        }
+        let series: [(&str, &str, &str, Metric); 9] = [
+            ("keys", "Keys in the listing index.", "gauge", |i| {
+                i.entries.read().unwrap().len() as u64
+            }),
+            (
+                "fresh",
+                "1 while listings are served from the index, 0 while they fall back to walking.",
+                "gauge",
+                |i| i.is_fresh(&i.status.lock().unwrap()) as u64,
+            ),
+            (
+                "rebuilds_total",
+                "Walks that rebuilt the listing index.",
+                "counter",
+                |i| i.stats.rebuilds.load(Ordering::Relaxed),
+            ),
+            (
+                "rebuild_failures_total",
+                "Walks of the zone that failed, leaving the index as it was.",
+                "counter",
+                |i| i.stats.failures.load(Ordering::Relaxed),
+            ),
+            (
+                "served_total",
+                "Listings answered from the index.",
+                "counter",
+                |i| i.stats.served.load(Ordering::Relaxed),
+            ),
+            (
+                "fallbacks_total",
+                "Listings that walked the zone because the index was not fresh.",
+                "counter",
+                |i| i.stats.fallbacks.load(Ordering::Relaxed),
+            ),
+            (
+                "drift_added",
+                "Keys the latest walk found that the index did not have.",
+                "gauge",
+                |i| i.stats.added.load(Ordering::Relaxed),
+            ),
+            (
+                "drift_removed",
+                "Keys the latest walk no longer found.",
+                "gauge",
+                |i| i.stats.removed.load(Ordering::Relaxed),
+            ),
+            (
+                "drift_changed",
+                "Keys the latest walk found changed.",
+                "gauge",
+                |i| i.stats.changed.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, help, kind, value) in series {
+            let name = format!("bunny_s3_proxy_listing_index_{}", name);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for index in indexes {
+                let _ = writeln!(
+                    out,
+                    "{}{{bucket=\"{}\"}} {}",
+                    name,
+                    index.bucket,
+                    value(index)
+                );
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bunny::UploadOptions;
+    use crate::bunny::localfs::LocalFsBackend;
+    use bytes::Bytes;
+
+    fn indexed() -> (Arc<ListingIndex>, Backend) {
+        let dir = std::env::temp_dir().join(format!("localfs-{}", uuid::Uuid::new_v4()));
+        let base = Backend::LocalFs(LocalFsBackend::new(&dir, "zone"));
+        let index = Arc::new(ListingIndex {
+            bucket: "zone".into(),
+            base: base.clone(),
+            exclude: |key| key.starts_with("__multipart/"),
+            concurrency: 4,
+            max_age: chrono::Duration::hours(1),
+            entries: RwLock::default(),
+            status: Mutex::default(),
+            rebuild_requested: Notify::new(),
+            stats: IndexStats::default(),
+        });
+        (Arc::clone(&index), base.indexed(index))
+    }
+
+    async fn put(backend: &Backend, path: &str) {
+        backend
+            .upload(
+                path,
+                Bytes::from(path.to_string()),
+                UploadOptions::default(),
+            )
+            .await
+            .unwrap();
+    }
+
+    fn keys(objects: Option<Vec<StorageObject>>) -> Vec<String> {
+        objects.unwrap().iter().map(StorageObject::s3_key).collect()
+    }
+
+    #[tokio::test]
+    async fn test_listings_page_through_the_index() {
+        let (index, backend) = indexed();
+        for path in ["a/1", "a/2", "a/3", "b/1", "__multipart/u/00001", "c"] {
+            put(&backend, path).await;
+        }
+        let none = &|_: &str| false;
+        // Nothing is served until the first walk.
+        assert!(backend.indexed_listing("", None, 10, none).is_none());
+        index.rebuild().await;
+
+        assert_eq!(
+            keys(backend.indexed_listing("", None, 10, none)),
+            ["a/1", "a/2", "a/3", "b/1", "c"]
+        );
+        assert_eq!(
+            keys(backend.indexed_listing("a/", Some("a/1"), 1, none)),
+            ["a/2"]
+        );
+        assert_eq!(
+            keys(backend.indexed_listing("", Some("a/3"), 10, &|k| k == "c")),
+            ["b/1"]
+        );
+        assert_eq!(
+            keys(backend.indexed_listing("b", Some("a"), 10, none)),
+            ["b/1"]
+        );
+        let listed = backend.indexed_listing("c", None, 1, none).unwrap();
+        assert_eq!(listed[0].length, 1);
+        assert_eq!(
+            listed[0].etag(),
+            backend.describe("c").await.unwrap().etag()
+        );
+
+        // Scoped backends list relative to their folder.
+        let scoped = backend.scoped("a");
+        assert_eq!(
+            keys(scoped.indexed_listing("", Some("1"), 10, none)),
+            ["2", "3"]
+        );
+
+        index.status.lock().unwrap().built_at = Some(Utc::now() - chrono::Duration::hours(2));
+        assert!(backend.indexed_listing("", None, 10, none).is_none());
+        assert_eq!(index.stats.fallbacks.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_writes_are_recorded_and_replayed_over_walks() {
+        let (index, backend) = indexed();
+        put(&backend, "keep").await;
+        put(&backend, "dir/a").await;
+        put(&backend, "dir/b").await;
+        index.rebuild().await;
+        let all = || keys(backend.indexed_listing("", None, 10, &|_| false));
+
+        put(&backend, "new").await;
+        backend.delete("dir/").await.unwrap();
+        assert_eq!(all(), ["keep", "new"]);
+
+        // A walk that began before these writes does not undo them.
+        let stale_walk = index.base.list_recursive("", None).await.unwrap();
+        index.status.lock().unwrap().touched = Some(Vec::new());
+        put(&backend, "during").await;
+        backend.delete("new").await.unwrap();
+        let mut walked = stale_walk;
+        walked.retain(|obj| obj.s3_key() != "keep");
+        index.finish(Ok(walked), Instant::now());
+        assert_eq!(all(), ["during"]);
+        assert_eq!(index.stats.removed.load(Ordering::Relaxed), 1);
+
+        // Files written around the proxy are found by the next walk.
+        put(&index.base, "outside").await;
+        assert_eq!(all(), ["during"]);
+        index.rebuild().await;
+        assert_eq!(all(), ["during", "keep", "outside"]);
+        assert_eq!(index.stats.added.load(Ordering::Relaxed), 2);
+        assert!(
+            ListingIndex::render_metrics(&[index])
+                .contains("bunny_s3_proxy_listing_index_keys{bucket=\"zone\"} 3\n")
+        );
+    }
+}
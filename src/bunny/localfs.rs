@@ -0,0 +1,727 @@
+//! `--backend localfs`: objects as files below `--localfs-root`, so the
+//! proxy runs without a storage zone, e.g. in development or CI. Each zone
+//! is the directory `<root>/<zone>`.
+//!
+//! What Bunny keeps beside a file, its checksum, content type, GUID and
+//! creation time, is kept as JSON at the same path below
+//! `<root>/.localfs/meta/<zone>`. It is recomputed when the file's size or
+//! modification time no longer match, so files copied in by other means are
+//! served too. Uploads are written to `<root>/.localfs/tmp` and renamed into
+//! place, so a reader never sees half an object.
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use reqwest::StatusCode;
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+use crate::error::{ProxyError, Result};
+
+use super::backend::{DownloadResponse, StorageBackend};
+use super::client::{UpstreamStats, escapes_root};
+use super::types::{StorageObject, UploadOptions};
+
+/// Below `--localfs-root`, next to the zones: metadata and temporary files.
+const STATE_DIR: &str = ".localfs";
+
+/// What Bunny would report about a file that its size and time do not say.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FileMeta {
+    guid: String,
+    checksum: String,
+    content_type: String,
+    created: DateTime<Utc>,
+    /// The size and modification time of the file this describes.
+    length: u64,
+    modified: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct LocalFsBackend {
+    dir: Arc<Path>,
+    zone: Arc<str>,
+    /// Folder inside the zone that paths are relative to, empty or ending in `/`.
+    root: Arc<str>,
+    stats: Arc<UpstreamStats>,
+    /// Held while metadata is written, so a reader refreshing stale metadata
+    /// cannot overwrite what an upload just recorded.
+    meta_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl LocalFsBackend {
+    pub fn new(dir: &Path, zone: &str) -> Self {
+        Self {
+            dir: Arc::from(dir),
+            zone: Arc::from(zone),
+            root: Arc::from(""),
+            stats: Arc::default(),
+            meta_lock: Arc::default(),
+        }
+    }
+
+    /// Always zero, as nothing is retried; kept for the metrics endpoint.
+    pub fn stats(&self) -> &UpstreamStats {
+        &self.stats
+    }
+
+    /// `path` relative to the zone directory. Paths that could resolve
+    /// outside it are refused with AccessDenied.
+    fn resolve(&self, path: &str) -> Result<String> {
+        if escapes_root(path) {
+            tracing::warn!("Refusing path {} outside of {}", path, self.zone);
+            return Err(ProxyError::AccessDenied);
+        }
+        Ok(format!("{}{}", self.root, path.trim_start_matches('/')))
+    }
+
+    fn data_path(&self, rel: &str) -> PathBuf {
+        self.dir.join(&*self.zone).join(rel)
+    }
+
+    fn meta_path(&self, rel: &str) -> PathBuf {
+        self.dir
+            .join(STATE_DIR)
+            .join("meta")
+            .join(&*self.zone)
+            .join(rel)
+    }
+
+    async fn temp_file(&self) -> Result<(PathBuf, tokio::fs::File)> {
+        let dir = self.dir.join(STATE_DIR).join("tmp");
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join(uuid::Uuid::new_v4().to_string());
+        let file = tokio::fs::File::create(&path).await?;
+        Ok((path, file))
+    }
+
+    async fn read_meta(&self, rel: &str) -> Option<FileMeta> {
+        let json = tokio::fs::read(self.meta_path(rel)).await.ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    async fn write_meta(&self, rel: &str, meta: &FileMeta) -> Result<()> {
+        let (temp, mut file) = self.temp_file().await?;
+        file.write_all(&serde_json::to_vec(meta)?).await?;
+        drop(file);
+        let path = self.meta_path(rel);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(temp, path).await?;
+        Ok(())
+    }
+
+    /// The metadata of the file at `rel`, whose size and modification time
+    /// are in `stat`, computing it again if the file changed outside the
+    /// proxy.
+    async fn file_meta(&self, rel: &str, stat: &std::fs::Metadata) -> Result<FileMeta> {
+        let modified: DateTime<Utc> = stat.modified()?.into();
+        let stored = self.read_meta(rel).await;
+        if let Some(meta) = &stored
+            && meta.length == stat.len()
+            && meta.modified == modified
+        {
+            return Ok(meta.clone());
+        }
+
+        let meta = FileMeta {
+            guid: uuid::Uuid::new_v4().to_string(),
+            checksum: hash_file(&self.data_path(rel)).await?,
+            content_type: match &stored {
+                Some(meta) => meta.content_type.clone(),
+                None => guess_content_type(rel),
+            },
+            created: stored.map_or(modified, |meta| meta.created),
+            length: stat.len(),
+            modified,
+        };
+        let _guard = self.meta_lock.lock().await;
+        let current = tokio::fs::metadata(self.data_path(rel)).await?;
+        if current.len() == meta.length && DateTime::<Utc>::from(current.modified()?) == modified {
+            self.write_meta(rel, &meta).await?;
+        }
+        Ok(meta)
+    }
+
+    /// Moves a fully written temporary file to `rel` and records its metadata.
+    async fn commit(
+        &self,
+        rel: &str,
+        temp: &Path,
+        checksum: String,
+        content_type: Option<&str>,
+    ) -> Result<()> {
+        let stat = tokio::fs::metadata(temp).await?;
+        let target = self.data_path(rel);
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let _guard = self.meta_lock.lock().await;
+        let existing = match tokio::fs::metadata(&target).await {
+            Ok(stat) if stat.is_file() => self.read_meta(rel).await,
+            _ => None,
+        };
+        let meta = FileMeta {
+            guid: uuid::Uuid::new_v4().to_string(),
+            checksum,
+            content_type: content_type
+                .map(str::to_string)
+                .unwrap_or_else(|| guess_content_type(rel)),
+            created: existing.map_or_else(Utc::now, |meta| meta.created),
+            length: stat.len(),
+            modified: stat.modified()?.into(),
+        };
+        self.write_meta(rel, &meta).await?;
+        tokio::fs::rename(temp, &target).await?;
+        Ok(())
+    }
+
+    /// A listing entry for `rel`, with the path relative to this backend's
+    /// root as [`BunnyClient`](super::BunnyClient) reports it.
+    fn object(&self, rel: &str) -> StorageObject {
+        let visible = rel
+            .strip_prefix(&*self.root)
+            .unwrap_or(rel)
+            .trim_end_matches('/');
+        let (dir, name) = visible.rsplit_once('/').unwrap_or(("", visible));
+        let now = Utc::now();
+        StorageObject {
+            guid: String::new(),
+            user_id: "localfs".into(),
+            last_changed: now,
+            date_created: now,
+            storage_zone_name: self.zone.to_string(),
+            path: match dir {
+                "" => format!("/{}/", self.zone),
+                dir => format!("/{}/{}/", self.zone, dir),
+            },
+            object_name: name.into(),
+            length: 0,
+            storage_zone_id: 0,
+            is_directory: true,
+            server_id: 0,
+            checksum: None,
+            replicated_zones: None,
+            content_type: String::new(),
+        }
+    }
+
+    fn describe_dir(&self, rel: &str, stat: Option<&std::fs::Metadata>) -> StorageObject {
+        let mut obj = self.object(rel);
+        if let Some(modified) = stat.and_then(|s| s.modified().ok()) {
+            obj.last_changed = modified.into();
+            obj.date_created = stat
+                .and_then(|s| s.created().ok())
+                .map_or(obj.last_changed, Into::into);
+        }
+        obj
+    }
+
+    async fn describe_file(&self, rel: &str, stat: &std::fs::Metadata) -> Result<StorageObject> {
+        let meta = self.file_meta(rel, stat).await?;
+        Ok(StorageObject {
+            guid: meta.guid,
+            last_changed: meta.modified,
+            date_created: meta.created,
+            length: meta.length as i64,
+            is_directory: false,
+            checksum: Some(meta.checksum),
+            content_type: meta.content_type,
+            ..self.object(rel)
+        })
+    }
+}
+
+impl StorageBackend for LocalFsBackend {
+    fn zone(&self) -> &str {
+        &self.zone
+    }
+
+    fn root(&self) -> &str {
+        &self.root
+    }
+
+    fn scoped(&self, prefix: &str) -> Self {
+        let prefix = prefix.trim_matches('/');
+        let root = if prefix.is_empty() {
+            self.root.to_string()
+        } else {
+            format!("{}{}/", self.root, prefix)
+        };
+        Self {
+            root: Arc::from(root),
+            ..self.clone()
+        }
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<StorageObject>> {
+        let dir = self.resolve(path)?;
+        let dir = match dir.is_empty() || dir.ends_with('/') {
+            true => dir,
+            false => format!("{}/", dir),
+        };
+        let mut entries = match tokio::fs::read_dir(self.data_path(&dir)).await {
+            Ok(entries) => entries,
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+                return Ok(Vec::new());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut found = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                tracing::warn!("Skipping non-UTF-8 file name in {}", dir);
+                continue;
+            };
+            found.push((name, tokio::fs::metadata(entry.path()).await?));
+        }
+        found.sort_by(|(a, a_stat), (b, b_stat)| {
+            b_stat.is_dir().cmp(&a_stat.is_dir()).then(a.cmp(b))
+        });
+
+        let mut objects = Vec::with_capacity(found.len());
+        for (name, stat) in found {
+            let rel = format!("{}{}", dir, name);
+            objects.push(match stat.is_dir() {
+                true => self.describe_dir(&rel, Some(&stat)),
+                false => self.describe_file(&rel, &stat).await?,
+            });
+        }
+        Ok(objects)
+    }
+
+    async fn describe(&self, path: &str) -> Result<StorageObject> {
+        let rel = self.resolve(path)?;
+        if path.trim_matches('/').is_empty() {
+            let stat = tokio::fs::metadata(self.data_path(&rel)).await.ok();
+            return Ok(self.describe_dir(&rel, stat.as_ref()));
+        }
+        match tokio::fs::metadata(self.data_path(&rel)).await {
+            Ok(stat) if stat.is_dir() => Ok(self.describe_dir(&rel, Some(&stat))),
+            Ok(stat) => self.describe_file(&rel, &stat).await,
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+                Err(ProxyError::NotFound(path.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn download_range(&self, path: &str, range: Option<&str>) -> Result<DownloadResponse> {
+        let rel = self.resolve(path)?;
+        let mut file = match tokio::fs::File::open(self.data_path(&rel)).await {
+            Ok(file) => file,
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+                return Err(ProxyError::NotFound(path.to_string()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let stat = file.metadata().await?;
+        if stat.is_dir() {
+            return Err(ProxyError::NotFound(path.to_string()));
+        }
+        let meta = self.file_meta(&rel, &stat).await?;
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            (header::CONTENT_TYPE, meta.content_type.clone()),
+            (header::ETAG, format!("\"{}\"", meta.checksum)),
+            (
+                header::LAST_MODIFIED,
+                meta.modified
+                    .format("%a, %d %b %Y %H:%M:%S GMT")
+                    .to_string(),
+            ),
+        ] {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        }
+
+        let Some(range) = range else {
+            let stream = ReaderStream::new(file);
+            return Ok(DownloadResponse::local(
+                StatusCode::OK,
+                headers,
+                meta.length,
+                stream,
+            ));
+        };
+        let (start, end) = byte_range(range, meta.length).ok_or(ProxyError::InvalidRange)?;
+        file.seek(SeekFrom::Start(start)).await?;
+        let content_range = format!("bytes {}-{}/{}", start, end, meta.length);
+        headers.insert(
+            header::CONTENT_RANGE,
+            HeaderValue::from_str(&content_range).unwrap(),
+        );
+        let length = end - start + 1;
+        Ok(DownloadResponse::local(
+            StatusCode::PARTIAL_CONTENT,
+            headers,
+            length,
+            ReaderStream::new(file.take(length)),
+        ))
+    }
+
+    async fn upload(&self, path: &str, body: Bytes, options: UploadOptions) -> Result<()> {
+        let rel = self.resolve(path)?;
+        if path.ends_with('/') {
+            tokio::fs::create_dir_all(self.data_path(&rel)).await?;
+            return Ok(());
+        }
+        let checksum = hex::encode_upper(Sha256::digest(&body));
+        let checksum_matches = options
+            .sha256_checksum
+            .is_none_or(|expected| expected.eq_ignore_ascii_case(&checksum));
+        if path.trim_matches('/').is_empty() || !checksum_matches {
+            return Err(ProxyError::InvalidRequest(
+                "Invalid path or checksum".into(),
+            ));
+        }
+
+        let (temp, mut file) = self.temp_file().await?;
+        let written = async {
+            file.write_all(&body).await?;
+            file.flush().await?;
+            drop(file);
+            self.commit(&rel, &temp, checksum, options.content_type.as_deref())
+                .await
+        }
+        .await;
+        if written.is_err() {
+            let _ = tokio::fs::remove_file(&temp).await;
+        }
+        written
+    }
+
+    async fn upload_stream(
+        &self,
+        path: &str,
+        stream: impl Stream<Item = std::result::Result<Bytes, std::io::Error>> + Send + 'static,
+        _content_length: Option<u64>,
+        content_type: Option<&str>,
+    ) -> Result<()> {
+        let rel = self.resolve(path)?;
+        if path.trim_matches('/').is_empty() || path.ends_with('/') {
+            return Err(ProxyError::InvalidRequest(
+                "Invalid path or checksum".into(),
+            ));
+        }
+
+        let (temp, mut file) = self.temp_file().await?;
+        let written = async {
+            let mut stream = std::pin::pin!(stream);
+            let mut hasher = Sha256::new();
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                hasher.update(&chunk);
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            drop(file);
+            let checksum = hex::encode_upper(hasher.finalize());
+            self.commit(&rel, &temp, checksum, content_type).await
+        }
+        .await;
+        if written.is_err() {
+            let _ = tokio::fs::remove_file(&temp).await;
+        }
+        written
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        let rel = self.resolve(path)?;
+        if rel.trim_matches('/').is_empty() {
+            return Ok(());
+        }
+        let target = self.data_path(&rel);
+        let removed = match tokio::fs::metadata(&target).await {
+            Ok(stat) if stat.is_dir() => tokio::fs::remove_dir_all(&target).await,
+            Ok(_) => tokio::fs::remove_file(&target).await,
+            Err(e) => Err(e),
+        };
+        match removed {
+            Err(e) if !matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+                return Err(e.into());
+            }
+            _ => {}
+        }
+
+        let meta = self.meta_path(&rel);
+        let removed = match tokio::fs::metadata(&meta).await {
+            Ok(stat) if stat.is_dir() => tokio::fs::remove_dir_all(&meta).await,
+            Ok(_) => tokio::fs::remove_file(&meta).await,
+            Err(_) => Ok(()),
+        };
+        match removed {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn guess_content_type(path: &str) -> String {
+    mime_guess::from_path(path)
+        .first_raw()
+        .unwrap_or("application/octet-stream")
+        .to_string()
+}
+
+async fn hash_file(path: &Path) -> Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf).await? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hex::encode_upper(hasher.finalize()))
+}
+
+/// The inclusive bounds of a single `bytes=` range over `len` bytes.
+pub(crate) fn byte_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (len.checked_sub(suffix.min(len))?, len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.checked_sub(1)?),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.min(len.checked_sub(1)?),
+        ),
+    };
+    (start <= end && end < len).then_some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend() -> (PathBuf, LocalFsBackend) {
+        let dir = std::env::temp_dir().join(format!("localfs-{}", uuid::Uuid::new_v4()));
+        let backend = LocalFsBackend::new(&dir, "zone");
+        (dir, backend)
+    }
+
+    #[tokio::test]
+    async fn test_files_round_trip() {
+        let (dir, fs) = backend();
+        let body = Bytes::from_static(b"hello world");
+        let options = UploadOptions {
+            sha256_checksum: Some(hex::encode_upper(Sha256::digest(&body))),
+            content_type: Some("text/plain".into()),
+        };
+        fs.upload("a/b/c d.txt", body.clone(), options)
+            .await
+            .unwrap();
+        let bad_checksum = UploadOptions {
+            sha256_checksum: Some("00".repeat(32)),
+            content_type: None,
+        };
+        let err = fs
+            .upload("x", body.clone(), bad_checksum)
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "InvalidRequest");
+        assert!(!dir.join("zone/x").exists());
+        assert!(dir.join("zone/a/b/c d.txt").is_file());
+
+        let obj = fs.describe("a/b/c d.txt").await.unwrap();
+        assert_eq!(obj.length, 11);
+        assert_eq!(obj.s3_key(), "a/b/c d.txt");
+        assert_eq!(obj.content_type, "text/plain");
+        assert_eq!(obj.checksum, Some(hex::encode_upper(Sha256::digest(&body))));
+        assert!(fs.describe("a").await.unwrap().is_directory);
+        assert!(fs.describe("").await.unwrap().is_directory);
+        assert!(matches!(
+            fs.describe("a/missing").await,
+            Err(ProxyError::NotFound(_))
+        ));
+
+        let root = fs.list("").await.unwrap();
+        assert_eq!(root.len(), 1);
+        assert!(root[0].is_directory && root[0].s3_key() == "a");
+        assert_eq!(fs.list_recursive("", None).await.unwrap().len(), 1);
+        assert!(fs.list("nothing/").await.unwrap().is_empty());
+
+        let read = fs.download("a/b/c d.txt").await.unwrap();
+        assert_eq!(read.etag(), Some(format!("\"{}\"", obj.etag())));
+        assert_eq!(read.content_type(), Some("text/plain"));
+        assert_eq!(read.bytes().await.unwrap(), body);
+        let range = fs
+            .download_range("a/b/c d.txt", Some("bytes=6-"))
+            .await
+            .unwrap();
+        assert_eq!(range.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(range.content_range().as_deref(), Some("bytes 6-10/11"));
+        assert_eq!(range.content_length(), Some(5));
+        assert_eq!(range.bytes().await.unwrap(), "world");
+        assert!(matches!(
+            fs.download_range("a/b/c d.txt", Some("bytes=20-")).await,
+            Err(ProxyError::InvalidRange)
+        ));
+
+        // Overwriting keeps the creation time.
+        let stream = futures::stream::iter([Ok(Bytes::from_static(b"bye"))]);
+        fs.upload_stream("a/b/c d.txt", stream, Some(3), None)
+            .await
+            .unwrap();
+        let replaced = fs.describe("a/b/c d.txt").await.unwrap();
+        assert_eq!(replaced.length, 3);
+        assert_eq!(replaced.date_created, obj.date_created);
+        assert_ne!(replaced.checksum, obj.checksum);
+
+        fs.delete("a").await.unwrap();
+        fs.delete("a").await.unwrap();
+        assert!(fs.list_recursive("", None).await.unwrap().is_empty());
+        assert!(matches!(
+            fs.download("a/b/c d.txt").await,
+            Err(ProxyError::NotFound(_))
+        ));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_files_written_outside_the_proxy() {
+        let (dir, fs) = backend();
+        std::fs::create_dir_all(dir.join("zone/docs")).unwrap();
+        std::fs::write(dir.join("zone/docs/readme.md"), b"# hi").unwrap();
+
+        let obj = fs.describe("docs/readme.md").await.unwrap();
+        assert_eq!(obj.length, 4);
+        assert_eq!(obj.content_type, "text/markdown");
+        assert_eq!(
+            obj.checksum,
+            Some(hex::encode_upper(Sha256::digest(b"# hi")))
+        );
+        assert_eq!(fs.describe("docs/readme.md").await.unwrap().guid, obj.guid);
+
+        // A changed file is hashed again.
+        std::fs::write(dir.join("zone/docs/readme.md"), b"# hello").unwrap();
+        let changed = fs.describe("docs/readme.md").await.unwrap();
+        assert_eq!(changed.length, 7);
+        assert_eq!(changed.content_type, "text/markdown");
+        assert_ne!(changed.checksum, obj.checksum);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_scoped_backend() {
+        let (dir, fs) = backend();
+        let scoped = fs.scoped("apps/service-a");
+        scoped
+            .upload(
+                "x/y.txt",
+                Bytes::from_static(b"y"),
+                UploadOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert!(dir.join("zone/apps/service-a/x/y.txt").is_file());
+        let listed = scoped.list_recursive("", None).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].s3_key(), "x/y.txt");
+        assert_eq!(
+            fs.list("apps/").await.unwrap()[0].s3_key(),
+            "apps/service-a"
+        );
+
+        for path in ["../service-b/secret", "a/../../b", "..%2Fzone"] {
+            let err = scoped.describe(path).await.unwrap_err();
+            assert_eq!(err.s3_error_code(), "AccessDenied", "{}", path);
+        }
+        assert!(fs.describe("../other-zone").await.is_err());
+
+        // Deleting the scoped root removes the folder; the zone root stays.
+        scoped.delete("").await.unwrap();
+        fs.delete("").await.unwrap();
+        assert!(!dir.join("zone/apps/service-a").exists());
+        assert!(dir.join("zone/apps").is_dir());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_list_recursive_after_resumes_in_key_order() {
+        let (dir, fs) = backend();
+        for key in [
+            "a.txt",
+            "a/b.txt",
+            "a-c/d.txt",
+            "a/e/f.txt",
+            "g.txt",
+            "a/.hidden",
+        ] {
+            fs.upload(key, Bytes::from_static(b"x"), UploadOptions::default())
+                .await
+                .unwrap();
+        }
+        let skip = |key: &str| key.ends_with(".hidden");
+        let mut keys = Vec::new();
+        let mut start_after: Option<String> = None;
+        loop {
+            let page = fs
+                .list_recursive_after("", start_after.as_deref(), 2, &skip)
+                .await
+                .unwrap();
+            let Some(last) = page.last() else { break };
+            start_after = Some(last.s3_key());
+            keys.extend(page.iter().map(|o| o.s3_key()));
+        }
+        assert_eq!(
+            keys,
+            ["a-c/d.txt", "a.txt", "a/b.txt", "a/e/f.txt", "g.txt"]
+        );
+
+        let rest = fs
+            .list_recursive_after("a/", Some("a/b.txt"), 10, &skip)
+            .await
+            .unwrap();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].s3_key(), "a/e/f.txt");
+
+        // A skipped folder is never walked, so nothing in it is asked about.
+        let asked = std::sync::Mutex::new(Vec::new());
+        let skip_folder = |key: &str| {
+            asked.lock().unwrap().push(key.to_string());
+            key == "a/"
+        };
+        let keys: Vec<String> = fs
+            .list_recursive_after("", None, 10, &skip_folder)
+            .await
+            .unwrap()
+            .iter()
+            .map(|o| o.s3_key())
+            .collect();
+        assert_eq!(keys, ["a-c/d.txt", "a.txt", "g.txt"]);
+        assert!(
+            !asked
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|k| k.starts_with("a/") && k != "a/")
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(byte_range("bytes=0-4", 10), Some((0, 4)));
+        assert_eq!(byte_range("bytes=5-", 10), Some((5, 9)));
+        assert_eq!(byte_range("bytes=-3", 10), Some((7, 9)));
+        assert_eq!(byte_range("bytes=8-100", 10), Some((8, 9)));
+        assert_eq!(byte_range("bytes=10-", 10), None);
+        assert_eq!(byte_range("bytes=0-0", 0), None);
+    }
+}
@@ -1,5 +1,11 @@
+pub mod accounting;
+pub mod backend;
 pub mod client;
+pub mod listing_index;
+pub mod localfs;
+pub mod pool;
 pub mod types;
 
+pub use backend::{Backend, DownloadResponse, StorageBackend};
 pub use client::BunnyClient;
 pub use types::UploadOptions;
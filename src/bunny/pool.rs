@@ -0,0 +1,245 @@
+//! The HTTP client a [`BunnyClient`](super::BunnyClient) sends through.
+//!
+//! reqwest resolves a host only when it opens a connection, and a busy pool
+//! keeps its connections for as long as they work, so a proxy running for
+//! weeks would keep talking to the addresses an endpoint had at startup. The
+//! client is therefore replaced, with a fresh pool, once it is older than
+//! `--bunny-connection-max-lifetime` or after
+//! `--bunny-connect-failure-threshold` connect failures in a row. Requests
+//! already under way finish on the connections they have. Every new
+//! connection resolves the host again, and a change in the addresses a host
+//! resolves to is logged.
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{Client, Response};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use super::client::UpstreamStats;
+use crate::config::Config;
+
+/// When the client and its pooled connections are replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolOptions {
+    /// Age after which the client is replaced; `None` keeps it forever.
+    pub max_lifetime: Option<Duration>,
+    /// Consecutive connect failures that replace the client; 0 never does.
+    pub connect_failure_threshold: u32,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            max_lifetime: Some(Duration::from_secs(300)),
+            connect_failure_threshold: 3,
+        }
+    }
+}
+
+impl From<&Config> for PoolOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            max_lifetime: config
+                .bunny_connection_max_lifetime
+                .to_std()
+                .ok()
+                .filter(|lifetime| !lifetime.is_zero()),
+            connect_failure_threshold: config.bunny_connect_failure_threshold,
+        }
+    }
+}
+
+/// Resolves through the system resolver on every new connection, logging
+/// when a host's address set changes. Clones share what they have seen, so
+/// the addresses survive the client being replaced.
+#[derive(Debug, Default, Clone)]
+struct TrackingResolver {
+    known: Arc<Mutex<HashMap<String, Vec<IpAddr>>>>,
+}
+
+impl TrackingResolver {
+    /// Records `addrs` for `host`, returning the previous set if it differs.
+    fn observe(&self, host: &str, mut addrs: Vec<IpAddr>) -> Option<Vec<IpAddr>> {
+        addrs.sort();
+        addrs.dedup();
+        let mut known = self.known.lock().unwrap_or_else(|e| e.into_inner());
+        match known.insert(host.to_string(), addrs.clone()) {
+            Some(previous) if previous != addrs => Some(previous),
+            _ => None,
+        }
+    }
+}
+
+impl Resolve for TrackingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            let mut ips: Vec<IpAddr> = addrs.iter().map(SocketAddr::ip).collect();
+            ips.sort();
+            ips.dedup();
+            if let Some(previous) = resolver.observe(&host, ips.clone()) {
+                tracing::info!("{} now resolves to {:?}, was {:?}", host, ips, previous);
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Current {
+    client: Client,
+    created: Instant,
+}
+
+#[derive(Debug)]
+pub struct ClientPool {
+    options: PoolOptions,
+    resolver: TrackingResolver,
+    current: RwLock<Current>,
+    connect_failures: AtomicU32,
+    stats: Arc<UpstreamStats>,
+}
+
+impl ClientPool {
+    pub fn new(options: PoolOptions, stats: Arc<UpstreamStats>) -> Self {
+        let resolver = TrackingResolver::default();
+        Self {
+            options,
+            current: RwLock::new(Current {
+                client: Self::build(&resolver),
+                created: Instant::now(),
+            }),
+            resolver,
+            connect_failures: AtomicU32::new(0),
+            stats,
+        }
+    }
+
+    fn build(resolver: &TrackingResolver) -> Client {
+        Client::builder()
+            .user_agent("bunny-s3-proxy/0.1.0")
+            .connect_timeout(Duration::from_secs(30))
+            .http2_adaptive_window(true)
+            .dns_resolver(resolver.clone())
+            .build()
+            .expect("Failed to create HTTP client")
+    }
+
+    /// The client to send the next request with, replaced first if it has
+    /// outlived `max_lifetime`.
+    pub fn client(&self) -> Client {
+        let current = self.current.read().unwrap_or_else(|e| e.into_inner());
+        match self.options.max_lifetime {
+            Some(lifetime) if current.created.elapsed() >= lifetime => {
+                drop(current);
+                self.refresh(|| "connections reached their maximum lifetime".to_string())
+            }
+            _ => current.client.clone(),
+        }
+    }
+
+    /// Counts connect failures, replacing the client once there are
+    /// `connect_failure_threshold` of them in a row.
+    pub fn record(&self, result: &reqwest::Result<Response>) {
+        match result {
+            Err(e) if e.is_connect() => {
+                let failures = self.connect_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                let threshold = self.options.connect_failure_threshold;
+                if threshold > 0 && failures >= threshold {
+                    self.connect_failures.store(0, Ordering::Relaxed);
+                    self.refresh(|| format!("{} connect failures in a row", failures));
+                }
+            }
+            Err(_) => {}
+            Ok(_) => self.connect_failures.store(0, Ordering::Relaxed),
+        }
+    }
+
+    fn refresh(&self, reason: impl FnOnce() -> String) -> Client {
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        // Another request may have replaced it while this one waited.
+        if current.created.elapsed() < Duration::from_secs(1) {
+            return current.client.clone();
+        }
+        tracing::info!("Replacing Bunny.net connection pool: {}", reason());
+        *current = Current {
+            client: Self::build(&self.resolver),
+            created: Instant::now(),
+        };
+        self.stats.pool_refreshes.fetch_add(1, Ordering::Relaxed);
+        current.client.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_changes_are_noticed() {
+        let resolver = TrackingResolver::default();
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        assert_eq!(resolver.observe("storage.bunnycdn.com", vec![a, b]), None);
+        // Order and duplicates do not count as changes.
+        assert_eq!(
+            resolver.observe("storage.bunnycdn.com", vec![b, a, a]),
+            None
+        );
+        assert_eq!(
+            resolver.observe("storage.bunnycdn.com", vec![b]),
+            Some(vec![a, b])
+        );
+        assert_eq!(resolver.observe("uk.storage.bunnycdn.com", vec![a]), None);
+    }
+
+    #[tokio::test]
+    async fn test_connect_failures_replace_the_pool() {
+        let stats = Arc::new(UpstreamStats::default());
+        let options = PoolOptions {
+            max_lifetime: None,
+            connect_failure_threshold: 2,
+        };
+        let pool = ClientPool::new(options, Arc::clone(&stats));
+        // Nothing listens on a port just released.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = format!("http://127.0.0.1:{}/", port);
+        let refreshes = || stats.pool_refreshes.load(Ordering::Relaxed);
+
+        let result = pool.client().get(&url).send().await;
+        pool.record(&result);
+        assert_eq!(refreshes(), 0);
+        // Pools younger than a second are not replaced again.
+        pool.current.write().unwrap().created -= Duration::from_secs(2);
+        let result = pool.client().get(&url).send().await;
+        pool.record(&result);
+        assert_eq!(refreshes(), 1);
+        assert_eq!(pool.connect_failures.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_old_clients_are_replaced() {
+        let stats = Arc::new(UpstreamStats::default());
+        let options = PoolOptions {
+            max_lifetime: Some(Duration::from_secs(60)),
+            connect_failure_threshold: 0,
+        };
+        let pool = ClientPool::new(options, Arc::clone(&stats));
+        pool.client();
+        assert_eq!(stats.pool_refreshes.load(Ordering::Relaxed), 0);
+        pool.current.write().unwrap().created -= Duration::from_secs(61);
+        pool.client();
+        assert_eq!(stats.pool_refreshes.load(Ordering::Relaxed), 1);
+        assert!(pool.current.read().unwrap().created.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -84,6 +84,15 @@ mod bunny_datetime {
     }
 }
 
+/// Zone totals reported by the Bunny account API.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ZoneStatistics {
+    pub name: String,
+    pub storage_used: u64,
+    pub files_stored: u64,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct UploadOptions {
     pub sha256_checksum: Option<String>,
@@ -0,0 +1,332 @@
+//! Fault injection for resilience testing with `--chaos-config`, compiled only
+//! with the `chaos` feature so production builds cannot enable it.
+//!
+//! The TOML file lists faults, each with a probability and optional matchers:
+//!
+//! ```toml
+//! [[fault]]
+//! kind = "latency"          # latency, error, truncate_body, drop_upload or delay_completion
+//! probability = 0.1
+//! operations = ["GetObject"]
+//! prefix = "zerofs-test/"
+//! ms = 500
+//! ```
+//!
+//! Request faults (`latency`, `error`, `truncate_body`) are applied around the
+//! request's dispatch, `drop_upload` fails the stream of a Bunny PUT half-way
+//! and `delay_completion` holds CompleteMultipartUpload after its keepalive
+//! started. Every injected fault is logged with a `[chaos]` marker.
+
+use axum::body::Body;
+use axum::http::{StatusCode, header};
+use axum::response::Response;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use serde::Deserialize;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::error::ProxyError;
+
+type ByteStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+static CHAOS: OnceLock<Chaos> = OnceLock::new();
+
+#[derive(Debug, Deserialize)]
+struct Chaos {
+    #[serde(default, rename = "fault")]
+    faults: Vec<Fault>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Fault {
+    probability: f64,
+    #[serde(default)]
+    operations: Vec<String>,
+    #[serde(default)]
+    prefix: Option<String>,
+    #[serde(flatten)]
+    action: Action,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Action {
+    Latency { ms: u64 },
+    Error { status: u16 },
+    TruncateBody,
+    DropUpload,
+    DelayCompletion { ms: u64 },
+}
+
+impl Fault {
+    fn matches(&self, operation: Option<&str>, key: &str) -> bool {
+        let operation_matches = self.operations.is_empty()
+            || operation.is_some_and(|op| self.operations.iter().any(|o| o == op));
+        operation_matches
+            && self.prefix.as_deref().is_none_or(|p| key.starts_with(p))
+            && fastrand::f64() < self.probability
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&self.probability) {
+            anyhow::bail!("probability {} is not between 0 and 1", self.probability);
+        }
+        match self.action {
+            Action::Error { status } if status != 500 && status != 503 => {
+                anyhow::bail!("error faults return 500 or 503, not {}", status)
+            }
+            Action::DropUpload if !self.operations.is_empty() => {
+                anyhow::bail!("drop_upload faults match Bunny paths by prefix only")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Chaos {
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let chaos: Chaos = toml::from_str(text)?;
+        for (i, fault) in chaos.faults.iter().enumerate() {
+            fault
+                .validate()
+                .map_err(|e| anyhow::anyhow!("fault {}: {}", i + 1, e))?;
+        }
+        Ok(chaos)
+    }
+
+    /// The first fault of the wanted kind that matches and fires.
+    fn fire(
+        &self,
+        operation: Option<&str>,
+        key: &str,
+        kind: impl Fn(&Action) -> bool,
+    ) -> Option<&Action> {
+        self.faults
+            .iter()
+            .filter(|f| kind(&f.action))
+            .find(|f| f.matches(operation, key))
+            .map(|f| &f.action)
+    }
+}
+
+/// Loads the fault list; faults apply from then on.
+pub fn init(path: &Path) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("cannot read {}: {}", path.display(), e))?;
+    let chaos = Chaos::parse(&text)
+        .map_err(|e| anyhow::anyhow!("invalid chaos config {}: {}", path.display(), e))?;
+    tracing::warn!(
+        "[chaos] Fault injection enabled with {} fault(s) from {}",
+        chaos.faults.len(),
+        path.display()
+    );
+    let _ = CHAOS.set(chaos);
+    Ok(())
+}
+
+/// Sleeps or fails a request before it is dispatched.
+pub async fn before_request(operation: &str, key: &str) -> Result<(), ProxyError> {
+    let Some(chaos) = CHAOS.get() else {
+        return Ok(());
+    };
+    if let Some(Action::Latency { ms }) = chaos.fire(Some(operation), key, |a| {
+        matches!(a, Action::Latency { .. })
+    }) {
+        tracing::warn!("[chaos] Delaying {} {} by {}ms", operation, key, ms);
+        tokio::time::sleep(Duration::from_millis(*ms)).await;
+    }
+    match chaos.fire(Some(operation), key, |a| matches!(a, Action::Error { .. })) {
+        Some(Action::Error { status: 503 }) => {
+            tracing::warn!("[chaos] Failing {} {} with 503", operation, key);
+            Err(ProxyError::UpstreamUnavailable(
+                "injected by chaos mode".to_string(),
+            ))
+        }
+        Some(Action::Error { status }) => {
+            tracing::warn!("[chaos] Failing {} {} with {}", operation, key, status);
+            Err(ProxyError::BunnyApi("injected by chaos mode".to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Cuts a successful response body off half-way, keeping its Content-Length
+/// so the client sees a short read.
+pub fn after_request(operation: &str, key: &str, response: Response) -> Response {
+    let Some(chaos) = CHAOS.get() else {
+        return response;
+    };
+    let length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let (Some(length), true) = (length, response.status().is_success()) else {
+        return response;
+    };
+    if length < 2
+        || response.status() == StatusCode::NO_CONTENT
+        || chaos
+            .fire(Some(operation), key, |a| matches!(a, Action::TruncateBody))
+            .is_none()
+    {
+        return response;
+    }
+    tracing::warn!(
+        "[chaos] Truncating {} {} after {} of {} bytes",
+        operation,
+        key,
+        length / 2,
+        length
+    );
+    let (parts, body) = response.into_parts();
+    let stream = body
+        .into_data_stream()
+        .map(|r| r.map_err(std::io::Error::other));
+    Response::from_parts(
+        parts,
+        Body::from_stream(cut_off(Box::pin(stream), length / 2)),
+    )
+}
+
+/// Fails a Bunny upload stream half-way through `length` bytes, or after its
+/// first chunk when the length is unknown.
+pub fn wrap_upload(path: &str, length: Option<u64>, stream: ByteStream) -> ByteStream {
+    if !drops_upload(path) {
+        return stream;
+    }
+    let limit = length.map_or(1, |l| (l / 2).max(1));
+    tracing::warn!("[chaos] Dropping upload of {} after {} bytes", path, limit);
+    cut_off(stream, limit)
+}
+
+/// The request body for a buffered Bunny upload, failed half-way like
+/// [`wrap_upload`] when a `drop_upload` fault fires.
+pub fn upload_body(path: &str, body: Bytes) -> reqwest::Body {
+    if !drops_upload(path) {
+        return body.into();
+    }
+    let limit = (body.len() as u64 / 2).max(1);
+    tracing::warn!("[chaos] Dropping upload of {} after {} bytes", path, limit);
+    let stream: ByteStream = Box::pin(futures::stream::once(async { Ok(body) }));
+    reqwest::Body::wrap_stream(cut_off(stream, limit))
+}
+
+fn drops_upload(path: &str) -> bool {
+    CHAOS.get().is_some_and(|chaos| {
+        chaos
+            .fire(None, path, |a| matches!(a, Action::DropUpload))
+            .is_some()
+    })
+}
+
+/// Holds a multipart completion before it starts assembling.
+pub async fn delay_completion(key: &str) {
+    let Some(chaos) = CHAOS.get() else {
+        return;
+    };
+    if let Some(Action::DelayCompletion { ms }) =
+        chaos.fire(Some("CompleteMultipartUpload"), key, |a| {
+            matches!(a, Action::DelayCompletion { .. })
+        })
+    {
+        tracing::warn!("[chaos] Delaying completion of {} by {}ms", key, ms);
+        tokio::time::sleep(Duration::from_millis(*ms)).await;
+    }
+}
+
+/// Passes through at most `limit` bytes, then fails.
+fn cut_off(stream: ByteStream, limit: u64) -> ByteStream {
+    Box::pin(futures::stream::unfold(
+        (stream, 0u64),
+        move |(mut stream, sent)| async move {
+            if sent >= limit {
+                return None;
+            }
+            match stream.next().await? {
+                Ok(chunk) => {
+                    let room = (limit - sent) as usize;
+                    if chunk.len() < room {
+                        return Some((Ok(chunk.clone()), (stream, sent + chunk.len() as u64)));
+                    }
+                    let stream: ByteStream = Box::pin(futures::stream::once(async {
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::ConnectionReset,
+                            "[chaos] connection dropped",
+                        ))
+                    }));
+                    Some((Ok(chunk.slice(..room)), (stream, 0)))
+                }
+                Err(e) => Some((Err(e), (stream, limit))),
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_validate() {
+        let chaos = Chaos::parse(
+            r#"
+            [[fault]]
+            kind = "latency"
+            probability = 1.0
+            operations = ["GetObject"]
+            prefix = "slow/"
+            ms = 5
+
+            [[fault]]
+            kind = "error"
+            probability = 0.0
+            status = 503
+
+            [[fault]]
+            kind = "drop_upload"
+            probability = 0.5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(chaos.faults.len(), 3);
+        let latency = |op, key| chaos.fire(Some(op), key, |a| matches!(a, Action::Latency { .. }));
+        assert!(latency("GetObject", "slow/a").is_some());
+        assert!(latency("PutObject", "slow/a").is_none());
+        assert!(latency("GetObject", "fast/a").is_none());
+        assert!(
+            chaos
+                .fire(Some("GetObject"), "x", |a| matches!(
+                    a,
+                    Action::Error { .. }
+                ))
+                .is_none()
+        );
+
+        for bad in [
+            "[[fault]]\nkind = \"latency\"\nprobability = 1.5\nms = 1",
+            "[[fault]]\nkind = \"error\"\nprobability = 1.0\nstatus = 404",
+            "[[fault]]\nkind = \"drop_upload\"\nprobability = 1.0\noperations = [\"PutObject\"]",
+            "[[fault]]\nkind = \"explode\"\nprobability = 1.0",
+        ] {
+            assert!(Chaos::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cut_off_fails_after_limit() {
+        let chunks = ["abc", "defg", "hij"].map(|c| Ok(Bytes::from(c)));
+        let stream: ByteStream = Box::pin(futures::stream::iter(chunks));
+        let items: Vec<_> = cut_off(stream, 5).collect().await;
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].as_ref().unwrap(), "abc");
+        assert_eq!(items[1].as_ref().unwrap(), "de");
+        assert_eq!(
+            items[2].as_ref().unwrap_err().kind(),
+            std::io::ErrorKind::ConnectionReset
+        );
+    }
+}
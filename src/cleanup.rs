@@ -0,0 +1,272 @@
+//! `bunny-s3-proxy cleanup-multipart`: reports the multipart staging area and
+//! deletes uploads that were abandoned by crashed clients.
+
+use chrono::{DateTime, Duration, Utc};
+use futures::StreamExt;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::bunny::{Backend, StorageBackend};
+use crate::config::Config;
+use crate::s3::handlers::is_valid_bucket_name;
+use crate::s3::multipart::MultipartManager;
+
+/// Uploads inspected at once while building the report.
+const INSPECT_CONCURRENCY: usize = 8;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct CleanupArgs {
+    /// Uploads started longer ago than this are stale, e.g. `90m`, `48h`, `7d`
+    #[arg(long, value_parser = parse_age)]
+    pub older_than: Option<Duration>,
+
+    /// Delete the stale uploads; without it nothing is deleted
+    #[arg(long, conflicts_with = "dry_run", requires = "older_than")]
+    pub yes: bool,
+
+    /// Only report what would be deleted (the default)
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Print the report as JSON instead of a table
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Parses an age such as `30s`, `90m`, `48h` or `7d`.
+pub fn parse_age(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(split);
+    let amount: i64 = amount.parse().map_err(|_| format!("invalid age '{}'", s))?;
+    match unit {
+        "s" => Ok(Duration::seconds(amount)),
+        "m" => Ok(Duration::minutes(amount)),
+        "h" | "" => Ok(Duration::hours(amount)),
+        "d" => Ok(Duration::days(amount)),
+        _ => Err(format!("invalid age '{}' (use s, m, h or d)", s)),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct UploadReport {
+    bucket: Option<String>,
+    upload_id: String,
+    /// `None` when the upload's metadata was never written.
+    key: Option<String>,
+    initiated: DateTime<Utc>,
+    age_secs: i64,
+    parts: usize,
+    staged_bytes: u64,
+    stale: bool,
+    deleted: bool,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Summary {
+    dry_run: bool,
+    uploads: usize,
+    stale: usize,
+    deleted: usize,
+    failed: usize,
+    freed_bytes: u64,
+}
+
+pub async fn run(config: &Config, args: &CleanupArgs) -> anyhow::Result<()> {
+    let mut root = Backend::new(config, config.into());
+    if let Some(prefix) = &config.key_prefix {
+        root = root.scoped(prefix);
+    }
+    let areas = if config.bucket_as_prefix {
+        root.list("")
+            .await?
+            .into_iter()
+            .filter(|obj| obj.is_directory && is_valid_bucket_name(&obj.object_name))
+            .map(|obj| (Some(obj.object_name.clone()), root.scoped(&obj.object_name)))
+            .collect()
+    } else {
+        vec![(None, root)]
+    };
+
+    let delete = args.yes && !args.dry_run;
+    let now = Utc::now();
+    let mut reports = Vec::new();
+    for (bucket, client) in areas {
+        let keys: HashMap<String, (String, DateTime<Utc>)> =
+            MultipartManager::list_uploads(&client, "")
+                .await?
+                .into_iter()
+                .map(|(key, upload_id, initiated)| (upload_id, (key, initiated)))
+                .collect();
+        let dirs = MultipartManager::staging_dirs(&client).await?;
+        let mut inspected = futures::stream::iter(dirs)
+            .map(|(upload_id, created)| {
+                let client = client.clone();
+                let bucket = bucket.clone();
+                let (key, initiated) = match keys.get(&upload_id) {
+                    Some((key, initiated)) => (Some(key.clone()), *initiated),
+                    None => (None, created),
+                };
+                async move {
+                    let (parts, staged_bytes) = MultipartManager::staged(&client, &upload_id)
+                        .await
+                        .unwrap_or_default();
+                    let stale = args.older_than.is_some_and(|age| now - initiated > age);
+                    let mut report = UploadReport {
+                        bucket,
+                        upload_id,
+                        key,
+                        initiated,
+                        age_secs: (now - initiated).num_seconds(),
+                        parts,
+                        staged_bytes,
+                        stale,
+                        deleted: false,
+                        error: None,
+                    };
+                    if stale && delete {
+                        match MultipartManager::cleanup(&client, &report.upload_id).await {
+                            Ok(()) => report.deleted = true,
+                            Err(e) => report.error = Some(e.to_string()),
+                        }
+                    }
+                    report
+                }
+            })
+            .buffer_unordered(INSPECT_CONCURRENCY);
+        while let Some(report) = inspected.next().await {
+            reports.push(report);
+        }
+    }
+    reports.sort_by_key(|r| std::cmp::Reverse(r.age_secs));
+
+    let summary = Summary {
+        dry_run: !delete,
+        uploads: reports.len(),
+        stale: reports.iter().filter(|r| r.stale).count(),
+        deleted: reports.iter().filter(|r| r.deleted).count(),
+        failed: reports.iter().filter(|r| r.error.is_some()).count(),
+        freed_bytes: reports
+            .iter()
+            .filter(|r| r.deleted)
+            .map(|r| r.staged_bytes)
+            .sum(),
+    };
+
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "uploads": reports,
+                "summary": summary,
+            }))?
+        );
+    } else {
+        print_table(&reports, &summary);
+    }
+
+    if summary.failed > 0 {
+        anyhow::bail!("{} of {} deletions failed", summary.failed, summary.stale);
+    }
+    Ok(())
+}
+
+fn print_table(reports: &[UploadReport], summary: &Summary) {
+    println!(
+        "{:<36}  {:>10}  {:>6}  {:>14}  {:<8}  KEY",
+        "UPLOAD ID", "AGE", "PARTS", "BYTES", "ACTION"
+    );
+    for report in reports {
+        let action = match (report.deleted, &report.error, report.stale) {
+            (true, _, _) => "deleted",
+            (_, Some(_), _) => "FAILED",
+            (_, _, true) if summary.dry_run => "would",
+            _ => "keep",
+        };
+        let key = report.key.as_deref().unwrap_or("<unknown>");
+        let key = match &report.bucket {
+            Some(bucket) => format!("{}/{}", bucket, key),
+            None => key.to_string(),
+        };
+        println!(
+            "{:<36}  {:>10}  {:>6}  {:>14}  {:<8}  {}",
+            report.upload_id,
+            format_age(report.age_secs),
+            report.parts,
+            report.staged_bytes,
+            action,
+            key
+        );
+        if let Some(error) = &report.error {
+            println!("    error: {}", error);
+        }
+    }
+    println!(
+        "\n{} uploads, {} stale, {} deleted, {} failed, {} bytes freed{}",
+        summary.uploads,
+        summary.stale,
+        summary.deleted,
+        summary.failed,
+        summary.freed_bytes,
+        if summary.dry_run && summary.stale > 0 {
+            " (dry run: pass --yes to delete)"
+        } else {
+            ""
+        }
+    );
+}
+
+fn format_age(secs: i64) -> String {
+    match secs {
+        s if s >= 86400 => format!("{}d{}h", s / 86400, s % 86400 / 3600),
+        s if s >= 3600 => format!("{}h{}m", s / 3600, s % 3600 / 60),
+        s => format!("{}m", s / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("48h"), Ok(Duration::hours(48)));
+        assert_eq!(parse_age("90m"), Ok(Duration::minutes(90)));
+        assert_eq!(parse_age("7d"), Ok(Duration::days(7)));
+        assert_eq!(parse_age("12"), Ok(Duration::hours(12)));
+        assert!(parse_age("h").is_err());
+        assert!(parse_age("3w").is_err());
+        assert_eq!(format_age(3 * 86400 + 7200), "3d2h");
+        assert_eq!(format_age(5400), "1h30m");
+    }
+
+    #[test]
+    fn test_subcommand_parsing() {
+        let base = ["bunny-s3-proxy", "-z", "zone", "-k", "key"];
+        assert!(Config::parse_from(base).command.is_none());
+
+        let config = Config::parse_from(base.iter().chain(&[
+            "cleanup-multipart",
+            "--older-than",
+            "48h",
+            "--yes",
+        ]));
+        let Some(crate::config::Command::CleanupMultipart(args)) = config.command else {
+            panic!("expected cleanup-multipart");
+        };
+        assert_eq!(args.older_than, Some(Duration::hours(48)));
+        assert!(args.yes && !args.dry_run && !args.json);
+
+        let with_both = base.iter().chain(&[
+            "cleanup-multipart",
+            "--older-than",
+            "1d",
+            "--yes",
+            "--dry-run",
+        ]);
+        assert!(Config::try_parse_from(with_both).is_err());
+        let yes_without_age = base.iter().chain(&["cleanup-multipart", "--yes"]);
+        assert!(Config::try_parse_from(yes_without_age).is_err());
+    }
+}
@@ -4,6 +4,8 @@ use std::fmt;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
+use crate::bunny::pool::PoolOptions;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
 #[serde(rename_all = "lowercase")]
 #[derive(Default)]
@@ -87,25 +89,182 @@ impl fmt::Display for LogLevel {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable single-line output.
+    #[default]
+    Pretty,
+    /// Abbreviated human-readable output.
+    Compact,
+    /// One JSON object per line, with span fields as top-level keys.
+    Json,
+}
+
+/// Where objects are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum BackendKind {
+    /// The Bunny Edge Storage API.
+    #[default]
+    Bunny,
+    /// Files below `--localfs-root`, one directory per storage zone.
+    Localfs,
+}
+
+/// How writes carrying `If-None-Match: *`, `If-Match` or
+/// `If-Unmodified-Since` are checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ConditionalWrites {
+    /// Ignore the preconditions and always write. Unsafe with more than one writer.
+    Off,
+    /// Check the current object without taking a lock; a concurrent write can
+    /// slip between the check and the write.
+    Fast,
+    /// Check the current object while holding the key's lock.
+    #[default]
+    Locked,
+}
+
+impl fmt::Display for ConditionalWrites {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Off => write!(f, "off"),
+            Self::Fast => write!(f, "fast"),
+            Self::Locked => write!(f, "locked"),
+        }
+    }
+}
+
+/// Which browser GETs of a bucket or prefix get an HTML index page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum HtmlListing {
+    /// Always answer with S3's XML.
+    #[default]
+    Off,
+    /// Every bucket and prefix.
+    All,
+    /// Only what `--access-config` opens to anonymous reads.
+    Public,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum Command {
+    /// Run the proxy (the default when no subcommand is given)
+    Serve,
+    /// Report multipart staging data and delete abandoned uploads
+    CleanupMultipart(crate::cleanup::CleanupArgs),
+    /// List a directory of the storage zone as the Bunny API reports it
+    Ls(crate::inspect::LsArgs),
+    /// Describe an object on Bunny, with the ETag and size the proxy serves
+    Stat(crate::inspect::StatArgs),
+    /// Delete an object directly on Bunny
+    Rm(crate::inspect::RmArgs),
+    /// Print a presigned URL for downloading or uploading an object
+    Presign(crate::presign::PresignArgs),
+    /// List the configured inventory reports, or write them with `--now`
+    Inventory(crate::s3::inventory::InventoryArgs),
+    /// Mirror a local directory into a folder of the zone, or the reverse
+    Sync(crate::sync::SyncArgs),
+    /// Probe every storage region's latency and throughput and check the configuration
+    Doctor(crate::doctor::DoctorArgs),
+    /// Serve a mock of the Bunny storage API for local testing
+    #[cfg(feature = "mock-bunny")]
+    MockBunny(crate::mock_bunny::MockArgs),
+}
+
 #[derive(Debug, Clone, Parser)]
 #[command(name = "bunny-s3-proxy")]
 #[command(about = "S3-compatible proxy for Bunny.net storage")]
 pub struct Config {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[arg(short = 'z', long, env = "BUNNY_STORAGE_ZONE")]
     pub storage_zone: String,
 
-    #[arg(short = 'k', long, env = "BUNNY_ACCESS_KEY")]
+    #[arg(
+        short = 'k',
+        long,
+        env = "BUNNY_ACCESS_KEY",
+        required = false,
+        default_value_if("backend", "localfs", "")
+    )]
     pub access_key: String,
 
     #[arg(short = 'r', long, env = "BUNNY_REGION", default_value = "de")]
     pub region: StorageRegion,
 
+    #[arg(long, env = "BUNNY_ENDPOINT")]
+    pub bunny_endpoint: Option<String>,
+
+    #[arg(long, env = "BUNNY_CONNECTION_MAX_LIFETIME", default_value = "5m", value_parser = crate::cleanup::parse_age)]
+    pub bunny_connection_max_lifetime: chrono::Duration,
+
+    #[arg(long, env = "BUNNY_CONNECT_FAILURE_THRESHOLD", default_value = "3")]
+    pub bunny_connect_failure_threshold: u32,
+
+    #[arg(long, env = "STORAGE_BACKEND", default_value = "bunny")]
+    pub backend: BackendKind,
+
+    #[arg(long, env = "LOCALFS_ROOT", default_value = "./data")]
+    pub localfs_root: PathBuf,
+
+    #[arg(long, env = "EXTRA_ZONES", value_delimiter = ',', value_parser = parse_extra_zone)]
+    pub extra_zone: Vec<ExtraZone>,
+
     #[arg(long, env = "S3_ACCESS_KEY_ID", default_value = "bunny")]
     pub s3_access_key_id: String,
 
     #[arg(long, env = "S3_SECRET_ACCESS_KEY", default_value = "bunny")]
     pub s3_secret_access_key: String,
 
+    #[arg(long, env = "S3_CREDENTIALS", value_delimiter = ',', value_parser = parse_s3_credential)]
+    pub s3_credentials: Vec<S3Credential>,
+
+    #[arg(long, env = "REQUIRE_AUTH")]
+    pub require_auth: bool,
+
+    #[arg(long, env = "ACCESS_CONFIG")]
+    pub access_config: Option<PathBuf>,
+
+    #[arg(long, env = "HTML_LISTING", default_value = "off")]
+    pub html_listing: HtmlListing,
+
+    #[arg(long, env = "RETENTION_CONFIG")]
+    pub retention_config: Option<PathBuf>,
+
+    #[arg(long, env = "RETENTION_OVERRIDE_TOKEN", requires = "retention_config")]
+    pub retention_override_token: Option<String>,
+
+    #[arg(long, env = "KEY_RULES_CONFIG")]
+    pub key_rules_config: Option<PathBuf>,
+
+    #[arg(long, env = "KEY_POLICIES_CONFIG")]
+    pub key_policies_config: Option<PathBuf>,
+
+    #[arg(long, env = "CACHE_POLICY_CONFIG")]
+    pub cache_policy_config: Option<PathBuf>,
+
+    #[arg(long, env = "RESPONSE_HEADERS_CONFIG")]
+    pub response_headers_config: Option<PathBuf>,
+
+    #[arg(long, env = "QUOTA_CONFIG")]
+    pub quota_config: Option<PathBuf>,
+
+    #[arg(long, env = "QUOTA_RECONCILE_INTERVAL", default_value = "1h", value_parser = crate::cleanup::parse_age)]
+    pub quota_reconcile_interval: chrono::Duration,
+
+    #[arg(long, env = "EMULATE_VERSIONING_PREFIXES", value_delimiter = ',')]
+    pub emulate_versioning: Vec<String>,
+
+    #[arg(long, env = "INVENTORY_CONFIG")]
+    pub inventory_config: Option<PathBuf>,
+
+    #[arg(long, env = "TRASH_PREFIX")]
+    pub trash_prefix: Option<String>,
+
+    #[arg(long, env = "TRASH_RETENTION", default_value = "7d", value_parser = crate::cleanup::parse_age)]
+    pub trash_retention: chrono::Duration,
+
     #[arg(
         short = 'l',
         long,
@@ -114,12 +273,332 @@ pub struct Config {
     )]
     pub listen_addr: SocketAddr,
 
+    #[arg(long, env = "REUSE_PORT")]
+    pub reuse_port: bool,
+
+    #[arg(long, env = "TCP_BACKLOG", default_value_t = 1024)]
+    pub tcp_backlog: i32,
+
+    #[arg(long, env = "TCP_NODELAY", default_value_t = true, action = clap::ArgAction::Set)]
+    pub tcp_nodelay: bool,
+
+    #[arg(long, env = "TCP_KEEPALIVE", value_parser = crate::cleanup::parse_age)]
+    pub tcp_keepalive: Option<chrono::Duration>,
+
+    #[arg(long, env = "TCP_KEEPALIVE_INTERVAL", value_parser = crate::cleanup::parse_age, requires = "tcp_keepalive")]
+    pub tcp_keepalive_interval: Option<chrono::Duration>,
+
+    #[arg(long, env = "TCP_KEEPALIVE_RETRIES", requires = "tcp_keepalive")]
+    pub tcp_keepalive_retries: Option<u32>,
+
+    #[arg(long, env = "SO_RCVBUF")]
+    pub so_rcvbuf: Option<usize>,
+
+    #[arg(long, env = "SO_SNDBUF")]
+    pub so_sndbuf: Option<usize>,
+
+    #[arg(long, env = "ALLOW_IPS", value_delimiter = ',', value_parser = crate::ip_filter::parse_net)]
+    pub allow_ip: Vec<ipnet::IpNet>,
+
+    #[arg(long, env = "DENY_IPS", value_delimiter = ',', value_parser = crate::ip_filter::parse_net)]
+    pub deny_ip: Vec<ipnet::IpNet>,
+
+    #[arg(long, env = "IP_FILTER_CONFIG")]
+    pub ip_filter_config: Option<PathBuf>,
+
+    #[arg(long, env = "TRUSTED_PROXIES", value_delimiter = ',', value_parser = crate::ip_filter::parse_net)]
+    pub trusted_proxy: Vec<ipnet::IpNet>,
+
     #[arg(short = 's', long, env = "SOCKET_PATH")]
     pub socket_path: Option<PathBuf>,
 
+    #[arg(long, env = "PIPE_NAME", conflicts_with = "socket_path")]
+    pub pipe_name: Option<String>,
+
+    #[arg(long, env = "TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    #[arg(long, env = "TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    #[arg(long, env = "TLS_CLIENT_CA", requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    #[arg(long, env = "TLS_REQUIRE_CLIENT_CERT", requires = "tls_client_ca")]
+    pub tls_require_client_cert: bool,
+
+    #[arg(
+        long,
+        env = "TLS_CLIENT_CRLS",
+        value_delimiter = ',',
+        requires = "tls_client_ca"
+    )]
+    pub tls_client_crl: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        env = "TLS_CLIENT_CERT_FINGERPRINTS",
+        value_delimiter = ',',
+        value_parser = crate::tls::parse_fingerprint,
+        requires = "tls_client_ca"
+    )]
+    pub tls_client_cert_fingerprint: Vec<String>,
+
+    #[arg(
+        long,
+        env = "MAP_CLIENT_CERT_TO_KEYS",
+        value_delimiter = ',',
+        value_parser = crate::tls::parse_cert_mapping,
+        requires = "tls_client_ca"
+    )]
+    pub map_client_cert_to_key: Vec<(String, String)>,
+
     #[arg(short = 'L', long, env = "LOG_LEVEL", default_value = "info")]
     pub log_level: LogLevel,
 
+    #[arg(long, env = "LOG_FORMAT", default_value = "pretty")]
+    pub log_format: LogFormat,
+
+    #[arg(long, env = "DEBUG_HTTP")]
+    pub debug_http: bool,
+
+    #[arg(long, env = "MAX_OBJECT_SIZE", default_value = "5368709120")]
+    pub max_object_size: u64,
+
+    #[arg(long, env = "MAX_CONCURRENT_COMPLETIONS", default_value = "2")]
+    pub max_concurrent_completions: usize,
+
+    #[arg(long, env = "MAX_QUEUED_COMPLETIONS", default_value = "64")]
+    pub max_queued_completions: usize,
+
+    #[arg(long, env = "MAX_BUFFERED_BYTES", default_value = "268435456")]
+    pub max_buffered_bytes: u64,
+
+    #[arg(long, env = "MAX_BUFFERED_WAIT", default_value = "2s", value_parser = crate::cleanup::parse_age)]
+    pub max_buffered_wait: chrono::Duration,
+
+    #[arg(long, env = "LIFECYCLE_INTERVAL_SECS", default_value = "3600")]
+    pub lifecycle_interval_secs: u64,
+
+    #[arg(long, env = "CLAIM_SSE_S3")]
+    pub claim_sse_s3: bool,
+
+    #[arg(long, env = "SSE_C")]
+    pub sse_c: bool,
+
+    #[arg(long, env = "VERIFY_DOWNLOADS")]
+    pub verify_downloads: bool,
+
+    #[arg(long, env = "VERIFY_WRITES")]
+    pub verify_writes: bool,
+
+    #[arg(
+        long,
+        env = "VERIFY_WRITES_WINDOW_MS",
+        default_value = "2000",
+        requires = "verify_writes"
+    )]
+    pub verify_writes_window_ms: u64,
+
+    #[arg(long, env = "VERIFY_WRITES_STRICT", requires = "verify_writes")]
+    pub verify_writes_strict: bool,
+
+    #[arg(long, env = "DEDUPE_WRITES")]
+    pub dedupe_writes: bool,
+
+    #[arg(
+        long,
+        env = "DEDUPE_VERIFY",
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        requires = "dedupe_writes"
+    )]
+    pub dedupe_verify: bool,
+
+    #[arg(long, env = "STAGE_OVERWRITES")]
+    pub stage_overwrites: bool,
+
+    #[arg(long, env = "REDIRECT_READS", requires = "redirect_base_url")]
+    pub redirect_reads: bool,
+
+    #[arg(long, env = "REDIRECT_BASE_URL")]
+    pub redirect_base_url: Option<String>,
+
+    #[arg(long, env = "REDIRECT_TOKEN_KEY", requires = "redirect_reads")]
+    pub redirect_token_key: Option<String>,
+
+    #[arg(long, env = "REDIRECT_TTL_SECS", default_value = "300")]
+    pub redirect_ttl_secs: u64,
+
+    #[arg(long, env = "REDIRECT_MIN_SIZE", default_value = "0")]
+    pub redirect_min_size: u64,
+
+    #[arg(
+        long,
+        env = "REDIRECT_PREFIXES",
+        value_delimiter = ',',
+        requires = "redirect_reads"
+    )]
+    pub redirect_prefix: Vec<String>,
+
+    #[arg(long, env = "COMPRESS_RESPONSES", default_value_t = true, action = clap::ArgAction::Set)]
+    pub compress_responses: bool,
+
+    #[arg(long, env = "GUESS_CONTENT_TYPE", default_value_t = true, action = clap::ArgAction::Set)]
+    pub guess_content_type: bool,
+
+    #[arg(
+        long,
+        env = "MIME_MAPS",
+        value_delimiter = ',',
+        value_parser = crate::s3::content_type::parse_mime_map
+    )]
+    pub mime_map: Vec<(String, String)>,
+
+    #[arg(long, env = "REJECT_BUCKET_POLICY")]
+    pub reject_bucket_policy: bool,
+
+    #[arg(long, env = "OMIT_PUBLIC_ACCESS_BLOCK")]
+    pub omit_public_access_block: bool,
+
+    #[arg(long, env = "CREATE_BUCKET_CONFLICT")]
+    pub create_bucket_conflict: bool,
+
+    #[arg(long, env = "ALLOW_BUCKET_PURGE")]
+    pub allow_bucket_purge: bool,
+
+    #[arg(long, env = "REQUIRE_DELETE_MD5")]
+    pub require_delete_md5: bool,
+
+    #[arg(long, env = "ALLOW_RENAME")]
+    pub allow_rename: bool,
+
+    #[arg(long, env = "BUCKET_AS_PREFIX")]
+    pub bucket_as_prefix: bool,
+
+    #[arg(long, env = "KEY_PREFIX", value_parser = crate::bunny::client::parse_key_prefix)]
+    pub key_prefix: Option<String>,
+
+    #[arg(long, env = "OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    #[arg(long, env = "CHAOS_CONFIG")]
+    pub chaos_config: Option<PathBuf>,
+
+    #[arg(long, env = "ADMIN_ADDR", requires = "admin_token")]
+    pub admin_addr: Option<SocketAddr>,
+
+    #[arg(long, env = "ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
+
+    #[arg(long, env = "EVENT_WEBHOOK_URL")]
+    pub event_webhook_url: Option<String>,
+
+    #[arg(long, env = "EVENT_WEBHOOK_SECRET")]
+    pub event_webhook_secret: Option<String>,
+
+    #[arg(
+        long,
+        env = "EVENT_TYPES",
+        value_delimiter = ',',
+        value_parser = crate::s3::events::parse_event_type,
+        default_value = "s3:ObjectCreated:*,s3:ObjectRemoved:*"
+    )]
+    pub event_types: Vec<String>,
+
+    #[arg(long, env = "EVENT_PREFIX")]
+    pub event_prefix: Option<String>,
+
+    #[arg(long, env = "EVENT_SUFFIX")]
+    pub event_suffix: Option<String>,
+
+    #[arg(long, env = "EVENT_QUEUE_SIZE", default_value = "10000")]
+    pub event_queue_size: usize,
+
+    #[arg(long, env = "BUNNY_API_KEY")]
+    pub bunny_api_key: Option<String>,
+
+    #[arg(long, env = "USAGE_CACHE_SECS", default_value = "300")]
+    pub usage_cache_secs: u64,
+
+    #[arg(long, env = "USAGE_WALK_CONCURRENCY", default_value = "4")]
+    pub usage_walk_concurrency: usize,
+
+    #[arg(long, env = "LISTING_INDEX")]
+    pub listing_index: bool,
+
+    #[arg(long, env = "LISTING_INDEX_INTERVAL", default_value = "1h", value_parser = crate::cleanup::parse_age)]
+    pub listing_index_interval: chrono::Duration,
+
+    #[arg(long, env = "LISTING_INDEX_MAX_AGE", default_value = "6h", value_parser = crate::cleanup::parse_age)]
+    pub listing_index_max_age: chrono::Duration,
+
+    #[arg(long, env = "ENCRYPTION_KEY_FILE")]
+    pub encryption_key_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "DECRYPTION_KEY_FILES",
+        value_delimiter = ',',
+        requires = "encryption_key_file"
+    )]
+    pub decryption_key_file: Vec<PathBuf>,
+
+    #[arg(long, env = "COMPRESS", value_parser = crate::s3::compression::parse_compress)]
+    pub compress: Option<i32>,
+
+    #[arg(
+        long,
+        env = "COMPRESS_PREFIXES",
+        value_delimiter = ',',
+        requires = "compress"
+    )]
+    pub compress_prefix: Vec<String>,
+
+    #[arg(
+        long,
+        env = "COMPRESS_CONTENT_TYPES",
+        value_delimiter = ',',
+        requires = "compress"
+    )]
+    pub compress_content_type: Vec<String>,
+
+    #[arg(long, env = "COMPRESS_REJECT_RANGES", requires = "compress")]
+    pub compress_reject_ranges: bool,
+
+    #[arg(long, env = "SHADOW_ZONE", requires = "shadow_key")]
+    pub shadow_zone: Option<String>,
+
+    #[arg(long, env = "SHADOW_KEY", requires = "shadow_zone")]
+    pub shadow_key: Option<String>,
+
+    #[arg(long, env = "SHADOW_REGION", requires = "shadow_zone")]
+    pub shadow_region: Option<StorageRegion>,
+
+    #[arg(long, env = "SHADOW_QUEUE_SIZE", default_value = "10000")]
+    pub shadow_queue_size: usize,
+
+    #[arg(long, env = "SHADOW_STRICT", requires = "shadow_zone")]
+    pub shadow_strict: bool,
+
+    #[arg(long, env = "SHADOW_DEAD_LETTER_PATH", requires = "shadow_zone")]
+    pub shadow_dead_letter_path: Option<PathBuf>,
+
+    #[arg(long, env = "AUDIT_LOG_PATH")]
+    pub audit_log_path: Option<PathBuf>,
+
+    #[arg(long, env = "AUDIT_LOG_MAX_BYTES", default_value = "104857600")]
+    pub audit_log_max_bytes: u64,
+
+    #[arg(long, env = "VALIDATE_ONLY")]
+    pub validate_only: bool,
+
+    #[arg(long, env = "STRICT_STARTUP", default_value_t = true, action = clap::ArgAction::Set)]
+    pub strict_startup: bool,
+
+    #[arg(long, env = "CONDITIONAL_WRITES", default_value = "locked")]
+    pub conditional_writes: ConditionalWrites,
+
     #[arg(long, env = "REDIS_URL")]
     pub redis_url: Option<String>,
 
@@ -132,6 +611,93 @@ pub struct StorageZoneConfig {
     pub name: String,
     pub access_key: String,
     pub region: StorageRegion,
+    pub api_key: Option<String>,
+    /// Replaces the region's storage URL, e.g. to use `mock-bunny`.
+    pub endpoint: Option<String>,
+    pub pool: PoolOptions,
+}
+
+/// Another storage zone served as a bucket of its own name, from
+/// `--extra-zone <zone>:<access-key>[:<region>]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtraZone {
+    pub name: String,
+    pub access_key: String,
+    /// Defaults to the main zone's region.
+    pub region: Option<StorageRegion>,
+}
+
+impl ExtraZone {
+    /// The zone's connection settings; the API key and endpoint are the main
+    /// zone's, since they belong to the account rather than the zone.
+    pub fn storage_zone_config(&self, config: &Config) -> StorageZoneConfig {
+        StorageZoneConfig {
+            name: self.name.clone(),
+            access_key: self.access_key.clone(),
+            region: self.region.unwrap_or(config.region),
+            api_key: config.bunny_api_key.clone(),
+            endpoint: config.bunny_endpoint.clone(),
+            pool: PoolOptions::from(config),
+        }
+    }
+}
+
+pub fn parse_extra_zone(s: &str) -> std::result::Result<ExtraZone, String> {
+    let mut parts = s.trim().splitn(3, ':');
+    let (Some(name), Some(access_key)) = (parts.next(), parts.next()) else {
+        return Err(format!(
+            "expected <zone>:<access-key>[:<region>], got '{}'",
+            s
+        ));
+    };
+    if name.is_empty() || access_key.is_empty() {
+        return Err(format!(
+            "expected <zone>:<access-key>[:<region>], got '{}'",
+            s
+        ));
+    }
+    let region = parts
+        .next()
+        .map(|r| <StorageRegion as clap::ValueEnum>::from_str(r, true))
+        .transpose()?;
+    Ok(ExtraZone {
+        name: name.to_string(),
+        access_key: access_key.to_string(),
+        region,
+    })
+}
+
+/// Another S3 key pair the proxy accepts, from
+/// `--s3-credentials <access-key-id>:<secret-access-key>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Credential {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+/// Parses one `<access-key-id>:<secret-access-key>` pair. The secret is
+/// never repeated in the error.
+pub fn parse_s3_credential(s: &str) -> std::result::Result<S3Credential, String> {
+    match s.trim().split_once(':') {
+        Some((access_key_id, secret_access_key))
+            if !access_key_id.is_empty() && !secret_access_key.is_empty() =>
+        {
+            Ok(S3Credential {
+                access_key_id: access_key_id.to_string(),
+                secret_access_key: secret_access_key.to_string(),
+            })
+        }
+        _ => Err("expected <access-key-id>:<secret-access-key>".to_string()),
+    }
+}
+
+/// Parses a comma-separated list of credentials, as `S3_CREDENTIALS_FILE`
+/// holds.
+pub fn parse_s3_credentials(s: &str) -> std::result::Result<Vec<S3Credential>, String> {
+    s.split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(parse_s3_credential)
+        .collect()
 }
 
 impl From<&Config> for StorageZoneConfig {
@@ -140,6 +706,9 @@ impl From<&Config> for StorageZoneConfig {
             name: config.storage_zone.clone(),
             access_key: config.access_key.clone(),
             region: config.region,
+            api_key: config.bunny_api_key.clone(),
+            endpoint: config.bunny_endpoint.clone(),
+            pool: PoolOptions::from(config),
         }
     }
 }
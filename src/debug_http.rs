@@ -0,0 +1,346 @@
+//! `--debug-http`: trace-level logs of the HTTP exchanges on both sides of the
+//! proxy, for diagnosing SDK compatibility without a packet capture.
+//!
+//! Inbound requests are logged with their method, path, query and headers,
+//! plus the first [`PREVIEW_LIMIT`] bytes of XML and form bodies; object data
+//! is never previewed. Responses are logged with their status and headers,
+//! and Bunny requests and responses the same way. Credentials, signatures
+//! and SSE-C keys are redacted before anything is formatted.
+//!
+//! Events go to the `bunny_s3_proxy::debug_http` target at trace level. When
+//! the flag is off every hook returns after one atomic load.
+
+use axum::body::Body;
+use axum::http::{HeaderMap, Method, StatusCode, Uri, header};
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+
+/// How much of an XML or form body is logged.
+pub const PREVIEW_LIMIT: usize = 4096;
+
+const REDACTED: &str = "<redacted>";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Headers whose values are secrets in full.
+const SECRET_HEADERS: &[&str] = &[
+    "accesskey",
+    "cookie",
+    "x-amz-security-token",
+    "x-amz-server-side-encryption-customer-key",
+    "x-amz-copy-source-server-side-encryption-customer-key",
+    "x-admin-token",
+    "x-bunny-retention-override",
+];
+
+/// Query and form parameters whose values are secrets.
+const SECRET_PARAMS: &[&str] = &["x-amz-signature", "x-amz-security-token", "signature"];
+
+pub fn init(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Logs an inbound request and returns its body, wrapped to log a preview
+/// when it is XML or a form and `operation` does not carry object data.
+pub fn inbound_request(
+    request_id: &str,
+    operation: &str,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: Body,
+) -> Body {
+    if !enabled() {
+        return body;
+    }
+    tracing::trace!(
+        target: "bunny_s3_proxy::debug_http",
+        "--> {} {} {} {}\n{}",
+        request_id,
+        method,
+        uri.path(),
+        redact_query(uri.query().unwrap_or("")),
+        format_headers(headers)
+    );
+    if !previews_body(operation, headers) {
+        return body;
+    }
+    let label = format!("{} {} body", request_id, operation);
+    let form = content_type(headers).starts_with("application/x-www-form-urlencoded");
+    Body::from_stream(Preview::new(body.into_data_stream(), label, form))
+}
+
+/// Logs the response to an inbound request.
+pub fn inbound_response(request_id: &str, status: StatusCode, headers: &HeaderMap) {
+    if !enabled() {
+        return;
+    }
+    tracing::trace!(
+        target: "bunny_s3_proxy::debug_http",
+        "<-- {} {}\n{}",
+        request_id,
+        status,
+        format_headers(headers)
+    );
+}
+
+/// Logs a request about to be sent to Bunny.
+pub fn bunny_request(request: &reqwest::Request) {
+    if !enabled() {
+        return;
+    }
+    let url = request.url();
+    tracing::trace!(
+        target: "bunny_s3_proxy::debug_http",
+        "==> Bunny {} {}{} {}\n{}",
+        request.method(),
+        url.host_str().unwrap_or(""),
+        url.path(),
+        redact_query(url.query().unwrap_or("")),
+        format_headers(request.headers())
+    );
+}
+
+/// Logs a response from Bunny.
+pub fn bunny_response(response: &reqwest::Response) {
+    if !enabled() {
+        return;
+    }
+    tracing::trace!(
+        target: "bunny_s3_proxy::debug_http",
+        "<== Bunny {} {}\n{}",
+        response.status(),
+        response.url().path(),
+        format_headers(response.headers())
+    );
+}
+
+/// Only bodies of requests that describe something are previewed: XML and
+/// URL-encoded forms, never the data of an object or part.
+fn previews_body(operation: &str, headers: &HeaderMap) -> bool {
+    if matches!(operation, "PutObject" | "UploadPart" | "PostObject") {
+        return false;
+    }
+    let content_type = content_type(headers);
+    content_type.contains("xml") || content_type.starts_with("application/x-www-form-urlencoded")
+}
+
+fn content_type(headers: &HeaderMap) -> String {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_ascii_lowercase()
+}
+
+/// One `name: value` line per header, secrets redacted.
+fn format_headers(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            format!("    {}: {}", name, redact_header(name.as_str(), &value))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn redact_header(name: &str, value: &str) -> String {
+    if name.eq_ignore_ascii_case(header::AUTHORIZATION.as_str()) {
+        return redact_authorization(value);
+    }
+    if SECRET_HEADERS.iter().any(|h| name.eq_ignore_ascii_case(h)) {
+        return REDACTED.to_string();
+    }
+    value.to_string()
+}
+
+/// Keeps the scheme, credential scope and signed headers of a SigV4
+/// Authorization header, which are what compatibility problems come down
+/// to, and drops the signature. Any other scheme is redacted whole.
+fn redact_authorization(value: &str) -> String {
+    let Some((scheme, params)) = value.split_once(' ') else {
+        return REDACTED.to_string();
+    };
+    if !scheme.starts_with("AWS4-") {
+        return format!("{} {}", scheme, REDACTED);
+    }
+    let params: Vec<String> = params
+        .split(',')
+        .map(|param| match param.trim().split_once('=') {
+            Some(("Signature", _)) => format!("Signature={}", REDACTED),
+            _ => param.trim().to_string(),
+        })
+        .collect();
+    format!("{} {}", scheme, params.join(", "))
+}
+
+/// The query string with signature and token values replaced.
+fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if is_secret_param(name) => format!("{}={}", name, REDACTED),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn is_secret_param(name: &str) -> bool {
+    let name = url::form_urlencoded::parse(name.as_bytes())
+        .next()
+        .map(|(name, _)| name.into_owned())
+        .unwrap_or_default();
+    SECRET_PARAMS.iter().any(|p| name.eq_ignore_ascii_case(p))
+}
+
+/// Passes a body through, keeping its first [`PREVIEW_LIMIT`] bytes to log
+/// once the body has been read or dropped.
+struct Preview<S> {
+    inner: S,
+    label: String,
+    /// URL-encoded forms get the same redaction as query strings.
+    form: bool,
+    seen: BytesMut,
+    total: usize,
+}
+
+impl<S> Preview<S> {
+    fn new(inner: S, label: String, form: bool) -> Self {
+        Self {
+            inner,
+            label,
+            form,
+            seen: BytesMut::new(),
+            total: 0,
+        }
+    }
+}
+
+impl<S, E> Stream for Preview<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = self.inner.poll_next_unpin(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &item {
+            let room = PREVIEW_LIMIT.saturating_sub(self.seen.len());
+            let keep = chunk.len().min(room);
+            self.seen.extend_from_slice(&chunk[..keep]);
+            self.total += chunk.len();
+        }
+        item
+    }
+}
+
+impl<S> Drop for Preview<S> {
+    fn drop(&mut self) {
+        let text = String::from_utf8_lossy(&self.seen);
+        let text = match self.form {
+            true => redact_query(&text),
+            false => text.into_owned(),
+        };
+        tracing::trace!(
+            target: "bunny_s3_proxy::debug_http",
+            "--> {} ({} bytes{}):\n{}",
+            self.label,
+            self.total,
+            if self.total > PREVIEW_LIMIT {
+                ", truncated"
+            } else {
+                ""
+            },
+            text
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secrets_are_redacted() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            "AWS4-HMAC-SHA256 Credential=AKID/20240501/de/s3/aws4_request, SignedHeaders=host;x-amz-date, Signature=abcdef0123456789"
+                .parse()
+                .unwrap(),
+        );
+        headers.insert("AccessKey", "zone-password".parse().unwrap());
+        headers.insert(
+            "x-amz-server-side-encryption-customer-key",
+            "c2VjcmV0LWtleQ==".parse().unwrap(),
+        );
+        headers.insert("x-amz-date", "20240501T000000Z".parse().unwrap());
+        let formatted = format_headers(&headers);
+        for secret in ["abcdef0123456789", "zone-password", "c2VjcmV0LWtleQ=="] {
+            assert!(
+                !formatted.contains(secret),
+                "{} leaked:\n{}",
+                secret,
+                formatted
+            );
+        }
+        assert!(formatted.contains("Credential=AKID/20240501/de/s3/aws4_request"));
+        assert!(formatted.contains("SignedHeaders=host;x-amz-date"));
+        assert!(formatted.contains("x-amz-date: 20240501T000000Z"));
+
+        assert_eq!(
+            redact_authorization("Basic dXNlcjpwYXNz"),
+            "Basic <redacted>"
+        );
+        assert_eq!(redact_authorization("opaque"), "<redacted>");
+
+        let query =
+            redact_query("uploads&X-Amz-Signature=deadbeef&X%2DAmz%2DSignature=cafe&prefix=a");
+        assert!(!query.contains("deadbeef") && !query.contains("cafe"));
+        assert!(query.starts_with("uploads&") && query.ends_with("&prefix=a"));
+    }
+
+    #[tokio::test]
+    async fn test_only_descriptive_bodies_are_previewed() {
+        let xml = |ct: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, ct.parse().unwrap());
+            headers
+        };
+        assert!(previews_body(
+            "CompleteMultipartUpload",
+            &xml("application/xml")
+        ));
+        assert!(previews_body(
+            "DeleteObjects",
+            &xml("text/xml; charset=utf-8")
+        ));
+        assert!(!previews_body("PutObject", &xml("application/xml")));
+        assert!(!previews_body("UploadPart", &xml("application/xml")));
+        assert!(!previews_body("PostObject", &xml("multipart/form-data")));
+        assert!(!previews_body("PutBucketTagging", &HeaderMap::new()));
+
+        let chunks = [Bytes::from(vec![b'a'; 3000]), Bytes::from(vec![b'b'; 3000])];
+        let mut preview = Preview::new(
+            futures::stream::iter(chunks.map(Ok::<_, std::io::Error>)),
+            "body".into(),
+            false,
+        );
+        let mut passed = 0;
+        while let Some(chunk) = preview.next().await {
+            passed += chunk.unwrap().len();
+        }
+        assert_eq!(passed, 6000);
+        assert_eq!(preview.total, 6000);
+        assert_eq!(preview.seen.len(), PREVIEW_LIMIT);
+    }
+}
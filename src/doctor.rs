@@ -0,0 +1,515 @@
+//! `bunny-s3-proxy doctor`: measures each storage region from where the
+//! proxy runs, to choose `--region`, and checks the configuration for the
+//! mistakes that otherwise only show once clients are failing.
+//!
+//! Every region is probed the same way: a TCP connect to its endpoint, an
+//! authenticated DESCRIBE of the zone root on a new connection and again on
+//! the same one, and, where the zone answers, an upload and download of a
+//! temporary object in the multipart staging area, deleted afterwards. The
+//! TLS handshake is not timed on its own: it is estimated as what the first
+//! DESCRIBE took beyond the second and the TCP connect. With
+//! `--bunny-endpoint` only that endpoint is probed.
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use reqwest::{Method, StatusCode, header};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+use crate::bunny::{Backend, BunnyClient, StorageBackend};
+use crate::config::{BackendKind, Config, StorageRegion, StorageZoneConfig};
+use crate::error::ProxyError;
+use crate::preflight;
+use crate::s3::AppState;
+
+/// Where the throughput sample is written, next to the preflight probe.
+const SAMPLE_PREFIX: &str = "__multipart/.doctor-";
+
+/// Clients' SigV4 signatures are refused more than 15 minutes off the
+/// proxy's clock.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(15 * 60);
+
+/// Clock skew worth a warning, well before it breaks signatures.
+const WARN_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// How many times faster another region must answer for the configured one
+/// to count as clearly wrong rather than merely not the fastest.
+const CLEARLY_FASTER: f64 = 2.0;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct DoctorArgs {
+    /// Bytes uploaded and downloaded per region to sample throughput; 0 skips it
+    #[arg(long, default_value = "4194304")]
+    pub sample_bytes: usize,
+
+    /// Print the report as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct RegionProbe {
+    region: String,
+    endpoint: String,
+    configured: bool,
+    connect_ms: Option<f64>,
+    tls_ms: Option<f64>,
+    request_ms: Option<f64>,
+    /// Whether the endpoint accepted the zone's access key.
+    serves_zone: bool,
+    upload_mbps: Option<f64>,
+    download_mbps: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip)]
+    server_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+struct CheckResult {
+    name: String,
+    ok: bool,
+    detail: String,
+}
+
+/// What the probes say about `--region`.
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct Assessment {
+    fastest: Option<String>,
+    /// Why the configured region is clearly wrong.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    problem: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    zone: String,
+    configured_region: String,
+    regions: Vec<RegionProbe>,
+    region: Assessment,
+    clock_skew_secs: Option<f64>,
+    checks: Vec<CheckResult>,
+}
+
+fn millis(elapsed: Duration) -> f64 {
+    (elapsed.as_secs_f64() * 10_000.0).round() / 10.0
+}
+
+fn mbps(bytes: usize, elapsed: Duration) -> f64 {
+    let rate = bytes as f64 / 1_000_000.0 / elapsed.as_secs_f64().max(1e-6);
+    (rate * 10.0).round() / 10.0
+}
+
+/// The endpoints to probe: every region's, or only `--bunny-endpoint`.
+fn endpoints(config: &Config) -> Vec<(String, String, bool)> {
+    match &config.bunny_endpoint {
+        Some(endpoint) => vec![(
+            config.region.code().to_string(),
+            endpoint.trim_end_matches('/').to_string(),
+            true,
+        )],
+        None => <StorageRegion as clap::ValueEnum>::value_variants()
+            .iter()
+            .map(|region| {
+                (
+                    region.code().to_string(),
+                    region.base_url().to_string(),
+                    *region == config.region,
+                )
+            })
+            .collect(),
+    }
+}
+
+async fn probe(
+    zone: &StorageZoneConfig,
+    (region, endpoint, configured): (String, String, bool),
+    sample_bytes: usize,
+) -> RegionProbe {
+    let mut probe = RegionProbe {
+        region,
+        endpoint,
+        configured,
+        ..Default::default()
+    };
+    if let Err(e) = measure(&mut probe, zone, sample_bytes).await {
+        probe.error = Some(e);
+    }
+    probe
+}
+
+async fn measure(
+    probe: &mut RegionProbe,
+    zone: &StorageZoneConfig,
+    sample_bytes: usize,
+) -> Result<(), String> {
+    let url = url::Url::parse(&probe.endpoint).map_err(|e| e.to_string())?;
+    let host = url.host_str().ok_or("endpoint has no host")?.to_string();
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    let started = Instant::now();
+    match tokio::time::timeout(PROBE_TIMEOUT, tokio::net::TcpStream::connect((host, port))).await {
+        Ok(Ok(_)) => probe.connect_ms = Some(millis(started.elapsed())),
+        Ok(Err(e)) => return Err(format!("connect failed: {}", e)),
+        Err(_) => return Err("connect timed out".to_string()),
+    }
+
+    let client = reqwest::Client::builder()
+        .user_agent("bunny-s3-proxy/0.1.0")
+        .connect_timeout(PROBE_TIMEOUT)
+        .timeout(PROBE_TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let describe = || async {
+        let started = Instant::now();
+        let response = client
+            .request(
+                Method::from_bytes(b"DESCRIBE").unwrap(),
+                format!("{}/{}/", probe.endpoint, zone.name),
+            )
+            .header("AccessKey", &zone.access_key)
+            .send()
+            .await
+            .map_err(|e| format!("DESCRIBE failed: {}", e))?;
+        let elapsed = started.elapsed();
+        Ok::<_, String>((response, elapsed))
+    };
+    let (_, cold) = describe().await?;
+    let (response, warm) = describe().await?;
+    probe.request_ms = Some(millis(warm));
+    if url.scheme() == "https" {
+        let connect = Duration::from_secs_f64(probe.connect_ms.unwrap_or_default() / 1000.0);
+        probe.tls_ms = Some(millis(cold.saturating_sub(warm).saturating_sub(connect)));
+    }
+    probe.server_date = response
+        .headers()
+        .get(header::DATE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+        .map(|d| d.with_timezone(&Utc));
+    match response.status() {
+        StatusCode::OK | StatusCode::NOT_FOUND => probe.serves_zone = true,
+        StatusCode::UNAUTHORIZED => return Ok(()),
+        status => return Err(format!("DESCRIBE answered {}", status)),
+    }
+
+    if sample_bytes == 0 {
+        return Ok(());
+    }
+    let backend = Backend::Bunny(BunnyClient::new(StorageZoneConfig {
+        endpoint: Some(probe.endpoint.clone()),
+        ..zone.clone()
+    }));
+    let key = format!("{}{}", SAMPLE_PREFIX, uuid::Uuid::new_v4());
+    let body = Bytes::from(vec![0u8; sample_bytes]);
+    let started = Instant::now();
+    match backend.upload(&key, body, Default::default()).await {
+        Ok(()) => probe.upload_mbps = Some(mbps(sample_bytes, started.elapsed())),
+        Err(ProxyError::AccessDenied) => {
+            return Err(
+                "the access key can read but not write: is it the read-only password?".to_string(),
+            );
+        }
+        Err(e) => return Err(format!("sample upload failed: {}", e)),
+    }
+    let started = Instant::now();
+    let downloaded = match backend.download(&key).await {
+        Ok(download) => download.bytes().await,
+        Err(e) => Err(e),
+    };
+    let result = match downloaded {
+        Ok(bytes) if bytes.len() == sample_bytes => {
+            probe.download_mbps = Some(mbps(sample_bytes, started.elapsed()));
+            Ok(())
+        }
+        Ok(bytes) => Err(format!(
+            "sample download returned {} of {} bytes",
+            bytes.len(),
+            sample_bytes
+        )),
+        Err(e) => Err(format!("sample download failed: {}", e)),
+    };
+    if let Err(e) = backend.delete(&key).await {
+        return Err(format!("could not delete the sample {}: {}", key, e));
+    }
+    result
+}
+
+/// Compares the configured region with the fastest one serving the zone.
+fn assess(probes: &[RegionProbe]) -> Assessment {
+    let fastest = probes
+        .iter()
+        .filter(|p| p.serves_zone)
+        .filter_map(|p| Some((p, p.request_ms?)))
+        .min_by(|a, b| a.1.total_cmp(&b.1));
+    let mut assessment = Assessment {
+        fastest: fastest.map(|(p, _)| p.region.clone()),
+        ..Default::default()
+    };
+    let Some(configured) = probes.iter().find(|p| p.configured) else {
+        return assessment;
+    };
+    if !configured.serves_zone {
+        assessment.problem = Some(match (&configured.error, &assessment.fastest) {
+            (Some(e), _) => format!("the configured region {} failed: {}", configured.region, e),
+            (None, Some(fastest)) => format!(
+                "the configured region {} does not serve the zone; {} does",
+                configured.region, fastest
+            ),
+            (None, None) => format!(
+                "no region accepted the access key, including the configured {}",
+                configured.region
+            ),
+        });
+        return assessment;
+    }
+    if let (Some((fastest, best)), Some(ms)) = (fastest, configured.request_ms)
+        && fastest.region != configured.region
+    {
+        let message = format!(
+            "{} answers in {}ms, the configured {} in {}ms",
+            fastest.region, best, configured.region, ms
+        );
+        match ms >= best * CLEARLY_FASTER {
+            true => assessment.problem = Some(message),
+            false => assessment.warning = Some(message),
+        }
+    }
+    assessment
+}
+
+/// Whether the proxy's clock is close enough to Bunny's for SigV4.
+fn check_clock(skew: Option<chrono::Duration>) -> CheckResult {
+    let name = "clock skew".to_string();
+    let Some(skew) = skew else {
+        return CheckResult {
+            name,
+            ok: true,
+            detail: "unknown: no region sent a Date header".to_string(),
+        };
+    };
+    let off = skew.abs().to_std().unwrap_or_default();
+    let detail = format!("{:+.1}s from Bunny's clock", skew.as_seconds_f64());
+    CheckResult {
+        name,
+        ok: off <= MAX_CLOCK_SKEW,
+        detail: match off {
+            off if off > MAX_CLOCK_SKEW => format!("{}; SigV4 signatures will be refused", detail),
+            off if off > WARN_CLOCK_SKEW => format!("{}; check NTP", detail),
+            _ => detail,
+        },
+    }
+}
+
+fn format_ms(value: Option<f64>) -> String {
+    value.map_or("-".to_string(), |ms| format!("{:.1}ms", ms))
+}
+
+fn format_rate(value: Option<f64>) -> String {
+    value.map_or("-".to_string(), |rate| format!("{:.1} MB/s", rate))
+}
+
+fn print_report(report: &Report) {
+    println!(
+        "{:<6}{:<36}{:>10}{:>10}{:>10}{:>12}{:>12}  ZONE",
+        "REGION", "ENDPOINT", "CONNECT", "TLS", "REQUEST", "UPLOAD", "DOWNLOAD"
+    );
+    for probe in &report.regions {
+        let region = match probe.configured {
+            true => format!("{}*", probe.region),
+            false => probe.region.clone(),
+        };
+        let zone = match (&probe.error, probe.serves_zone) {
+            (Some(e), _) => format!("error: {}", e),
+            (None, true) => "yes".to_string(),
+            (None, false) => "no".to_string(),
+        };
+        println!(
+            "{:<6}{:<36}{:>10}{:>10}{:>10}{:>12}{:>12}  {}",
+            region,
+            probe.endpoint,
+            format_ms(probe.connect_ms),
+            format_ms(probe.tls_ms),
+            format_ms(probe.request_ms),
+            format_rate(probe.upload_mbps),
+            format_rate(probe.download_mbps),
+            zone
+        );
+    }
+    println!();
+    for check in &report.checks {
+        let outcome = if check.ok { "ok" } else { "FAILED" };
+        println!("{}: {}: {}", check.name, outcome, check.detail);
+    }
+    match (&report.region.problem, &report.region.warning) {
+        (Some(problem), _) => println!("region: WRONG: {}", problem),
+        (None, Some(warning)) => println!(
+            "region: {} is not the fastest: {}",
+            report.configured_region, warning
+        ),
+        (None, None) => println!("region: ok: {} is the fastest", report.configured_region),
+    }
+}
+
+pub async fn run(config: &Config, args: &DoctorArgs) -> anyhow::Result<()> {
+    if config.backend == BackendKind::Localfs {
+        anyhow::bail!("doctor probes Bunny storage regions, which --backend localfs does not use");
+    }
+    let zone: StorageZoneConfig = config.into();
+    let mut regions = Vec::new();
+    for endpoint in endpoints(config) {
+        regions.push(probe(&zone, endpoint, args.sample_bytes).await);
+    }
+
+    let skew = regions
+        .iter()
+        .filter_map(|p| p.server_date)
+        .next()
+        .map(|date| Utc::now() - date);
+    let mut checks = vec![check_clock(skew)];
+    let state = AppState::new(config.clone())?;
+    for check in preflight::run(&state).await {
+        checks.push(CheckResult {
+            name: check.name,
+            ok: check.result.is_ok(),
+            detail: check
+                .result
+                .err()
+                .unwrap_or_else(|| "reachable".to_string()),
+        });
+    }
+
+    let report = Report {
+        zone: config.storage_zone.clone(),
+        configured_region: config.region.code().to_string(),
+        region: assess(&regions),
+        regions,
+        clock_skew_secs: skew.map(|s| s.as_seconds_f64()),
+        checks,
+    };
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report);
+    }
+
+    let mut problems: Vec<String> = report
+        .checks
+        .iter()
+        .filter(|c| !c.ok)
+        .map(|c| format!("{}: {}", c.name, c.detail))
+        .collect();
+    problems.extend(report.region.problem.clone());
+    if !problems.is_empty() {
+        anyhow::bail!("{}", problems.join("; "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn probe_of(region: &str, configured: bool, request_ms: Option<f64>) -> RegionProbe {
+        RegionProbe {
+            region: region.into(),
+            configured,
+            request_ms,
+            serves_zone: request_ms.is_some(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_region_assessment() {
+        let fine = assess(&[
+            probe_of("de", true, Some(20.0)),
+            probe_of("uk", false, Some(25.0)),
+        ]);
+        assert_eq!(fine.fastest.as_deref(), Some("de"));
+        assert_eq!((fine.problem, fine.warning), (None, None));
+
+        let close = assess(&[
+            probe_of("de", true, Some(30.0)),
+            probe_of("uk", false, Some(20.0)),
+        ]);
+        assert!(close.problem.is_none());
+        assert!(close.warning.unwrap().starts_with("uk answers in 20ms"));
+
+        let slow = assess(&[
+            probe_of("de", true, Some(90.0)),
+            probe_of("uk", false, Some(20.0)),
+        ]);
+        assert!(slow.problem.is_some());
+
+        let unserved = assess(&[
+            probe_of("de", true, None),
+            probe_of("ny", false, Some(50.0)),
+        ]);
+        assert_eq!(
+            unserved.problem.as_deref(),
+            Some("the configured region de does not serve the zone; ny does")
+        );
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        assert!(check_clock(Some(chrono::Duration::seconds(2))).ok);
+        let drifting = check_clock(Some(chrono::Duration::seconds(-120)));
+        assert!(drifting.ok && drifting.detail.contains("check NTP"));
+        let broken = check_clock(Some(chrono::Duration::minutes(20)));
+        assert!(!broken.ok && broken.detail.contains("SigV4"));
+        assert!(check_clock(None).ok);
+    }
+
+    #[tokio::test]
+    async fn test_probe_against_the_mock() {
+        let url = crate::mock_bunny::spawn("test-key").await;
+        let config = |key: &str| {
+            Config::parse_from([
+                "bunny-s3-proxy",
+                "-z",
+                "zone",
+                "-k",
+                key,
+                "--bunny-endpoint",
+                &url,
+            ])
+        };
+        let good = config("test-key");
+        let targets = endpoints(&good);
+        assert_eq!(targets.len(), 1);
+        let zone: StorageZoneConfig = (&good).into();
+        let probe = probe(&zone, targets[0].clone(), 1024).await;
+        assert!(probe.serves_zone, "{:?}", probe.error);
+        assert!(probe.error.is_none(), "{:?}", probe.error);
+        assert!(probe.connect_ms.is_some() && probe.tls_ms.is_none());
+        assert!(probe.upload_mbps.is_some() && probe.download_mbps.is_some());
+        assert!(probe.server_date.is_some());
+        let client = Backend::Bunny(BunnyClient::new(zone));
+        assert!(client.list("__multipart/").await.unwrap().is_empty());
+
+        let bad = config("wrong-key");
+        let probe = super::probe(&(&bad).into(), targets[0].clone(), 1024).await;
+        assert!(!probe.serves_zone && probe.error.is_none());
+        assert!(assess(&[probe]).problem.is_some());
+
+        let all = endpoints(&Config::parse_from([
+            "bunny-s3-proxy",
+            "-z",
+            "zone",
+            "-k",
+            "k",
+        ]));
+        assert_eq!(all.len(), 9);
+        assert_eq!(
+            all.iter().filter(|(_, _, configured)| *configured).count(),
+            1
+        );
+    }
+}
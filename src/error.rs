@@ -1,5 +1,8 @@
-use axum::http::StatusCode;
+use axum::http::{HeaderValue, StatusCode, header};
 use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -10,10 +13,54 @@ pub enum ProxyError {
     NotFound(String),
     #[error("Bucket not found: {0}")]
     BucketNotFound(String),
+    #[error("The specified version does not exist: {0}")]
+    NoSuchVersion(String),
+    #[error("The lifecycle configuration does not exist")]
+    NoSuchLifecycleConfiguration,
+    #[error("The TagSet does not exist")]
+    NoSuchTagSet,
+    #[error("The server side encryption configuration was not found")]
+    NoSuchEncryptionConfiguration,
+    #[error("The bucket policy does not exist")]
+    NoSuchBucketPolicy,
+    #[error("The public access block configuration was not found")]
+    NoSuchPublicAccessBlockConfiguration,
     #[error("Access denied")]
     AccessDenied,
+    #[error("Invalid according to Policy: {0}")]
+    PostPolicyFailed(String),
+    #[error("Object is under retention: {0}")]
+    RetentionActive(String),
+    #[error("Access denied for client address {0}")]
+    ClientAddressDenied(std::net::IpAddr),
+    #[error("Access denied by key rule {0}")]
+    KeyRuleDenied(String),
+    #[error("Access denied by the policy of access key {0}")]
+    KeyPolicyDenied(String),
+    #[error("The storage quota of {limit} bytes for {prefix} would be exceeded")]
+    QuotaExceeded { prefix: String, limit: u64 },
+    #[error(
+        "Bucket {0} is not served by this proxy; buckets map to pre-provisioned Bunny storage zones"
+    )]
+    BucketNotProvisioned(String),
+    #[error("Your previous request to create the named bucket succeeded and you already own it")]
+    BucketAlreadyOwnedByYou(String),
+    #[error("The bucket you tried to delete is not empty: {0}")]
+    BucketNotEmpty(String),
+    #[error("The storage class you specified is not valid: {0}")]
+    InvalidStorageClass(String),
+    #[error("The specified bucket is not valid: {0}")]
+    InvalidBucketName(String),
     #[error("Invalid request: {0}")]
     InvalidRequest(String),
+    #[error("{0}")]
+    InvalidArgument(String),
+    #[error("The XML you provided was not well-formed: {0}")]
+    MalformedXml(String),
+    #[error("Invalid tag: {0}")]
+    InvalidTag(String),
+    #[error("Policies must be valid JSON: {0}")]
+    MalformedPolicy(String),
     #[error("Invalid signature")]
     InvalidSignature,
     #[error("Missing authentication")]
@@ -22,8 +69,64 @@ pub enum ProxyError {
     MultipartNotFound(String),
     #[error("Invalid part: {0}")]
     InvalidPart(String),
+    #[error("You must provide the Content-Length HTTP header")]
+    MissingContentLength,
+    #[error("Missing required header for this request: Content-MD5")]
+    MissingContentMd5,
+    #[error("The Content-MD5 you specified is not valid")]
+    InvalidDigest,
+    #[error("The Content-MD5 you specified did not match what was received")]
+    BadDigest,
+    #[error("Your proposed upload exceeds the maximum allowed size of {0} bytes")]
+    EntityTooLarge(u64),
+    #[error("Your proposed upload is smaller than the minimum allowed size of {0} bytes")]
+    EntityTooSmall(u64),
+    #[error("At least one of the pre-conditions you specified did not hold")]
+    PreconditionFailed,
+    #[error("A conflicting conditional operation is currently in progress against this resource")]
+    ConditionalRequestConflict,
+    #[error("Your key is too long: {length} bytes, the maximum is {max}")]
+    KeyTooLong { length: usize, max: usize },
+    #[error("Your metadata headers exceed the maximum allowed metadata size of {0} bytes")]
+    MetadataTooLarge(usize),
+    #[error(
+        "You did not provide the number of bytes specified by the Content-Length HTTP header (expected {expected}, received {received})"
+    )]
+    IncompleteBody { expected: u64, received: u64 },
+    #[error("The requested range is not satisfiable")]
+    InvalidRange,
+    #[error("The requested partnumber is not satisfiable")]
+    InvalidPartNumber,
+    /// A request handler panicked; the panic is logged, not reported.
+    #[error("We encountered an internal error. Please try again.")]
+    Panicked,
+    #[error("Failed to decrypt stored object: {0}")]
+    Decryption(String),
+    #[error("{0} is not implemented by this proxy")]
+    NotImplemented(String),
+    /// A SelectObjectContent request the proxy cannot evaluate faithfully,
+    /// with the S3 Select error code describing why.
+    #[error("{message}")]
+    InvalidSelect { code: &'static str, message: String },
+    #[error("The specified method is not allowed against this resource: {method}")]
+    MethodNotAllowed {
+        method: String,
+        allowed: &'static str,
+    },
+    #[error("Please reduce your request rate: {0}")]
+    SlowDown(String),
+    #[error("Upstream request timed out: {0}")]
+    UpstreamTimeout(String),
+    #[error("Upstream unavailable: {0}")]
+    UpstreamUnavailable(String),
+    #[error("Upstream TLS error: {0}")]
+    UpstreamTls(String),
+    #[error("Failed to decode upstream response: {0}")]
+    UpstreamDecode(String),
     #[error("HTTP client error: {0}")]
-    HttpClient(#[from] reqwest::Error),
+    HttpClient(reqwest::Error),
+    #[error("Local storage error: {0}")]
+    LocalStorage(#[from] std::io::Error),
     #[error("XML error: {0}")]
     Xml(#[from] quick_xml::Error),
     #[error("JSON error: {0}")]
@@ -35,49 +138,428 @@ impl ProxyError {
         match self {
             Self::NotFound(_) => "NoSuchKey",
             Self::BucketNotFound(_) => "NoSuchBucket",
-            Self::AccessDenied | Self::InvalidSignature | Self::MissingAuth => "AccessDenied",
+            Self::NoSuchVersion(_) => "NoSuchVersion",
+            Self::NoSuchLifecycleConfiguration => "NoSuchLifecycleConfiguration",
+            Self::NoSuchTagSet => "NoSuchTagSet",
+            Self::NoSuchEncryptionConfiguration => "ServerSideEncryptionConfigurationNotFoundError",
+            Self::NoSuchBucketPolicy => "NoSuchBucketPolicy",
+            Self::NoSuchPublicAccessBlockConfiguration => "NoSuchPublicAccessBlockConfiguration",
+            Self::AccessDenied
+            | Self::InvalidSignature
+            | Self::MissingAuth
+            | Self::BucketNotProvisioned(_)
+            | Self::PostPolicyFailed(_)
+            | Self::RetentionActive(_)
+            | Self::ClientAddressDenied(_)
+            | Self::KeyRuleDenied(_)
+            | Self::KeyPolicyDenied(_) => "AccessDenied",
+            Self::QuotaExceeded { .. } => "QuotaExceeded",
+            Self::BucketAlreadyOwnedByYou(_) => "BucketAlreadyOwnedByYou",
+            Self::BucketNotEmpty(_) => "BucketNotEmpty",
+            Self::InvalidBucketName(_) => "InvalidBucketName",
+            Self::InvalidStorageClass(_) => "InvalidStorageClass",
             Self::InvalidRequest(_) => "InvalidRequest",
+            Self::InvalidArgument(_) => "InvalidArgument",
+            Self::MalformedXml(_) => "MalformedXML",
+            Self::InvalidTag(_) => "InvalidTag",
+            Self::MalformedPolicy(_) => "MalformedPolicy",
             Self::MultipartNotFound(_) => "NoSuchUpload",
             Self::InvalidPart(_) => "InvalidPart",
+            Self::MissingContentLength => "MissingContentLength",
+            Self::MissingContentMd5 => "MissingContentMD5",
+            Self::InvalidDigest => "InvalidDigest",
+            Self::BadDigest => "BadDigest",
+            Self::EntityTooLarge(_) => "EntityTooLarge",
+            Self::EntityTooSmall(_) => "EntityTooSmall",
+            Self::MetadataTooLarge(_) => "MetadataTooLarge",
+            Self::KeyTooLong { .. } => "KeyTooLongError",
+            Self::PreconditionFailed => "PreconditionFailed",
+            Self::ConditionalRequestConflict => "ConditionalRequestConflict",
+            Self::IncompleteBody { .. } => "IncompleteBody",
+            Self::InvalidRange => "InvalidRange",
+            Self::InvalidPartNumber => "InvalidPartNumber",
+            Self::NotImplemented(_) => "NotImplemented",
+            Self::InvalidSelect { code, .. } => code,
+            Self::MethodNotAllowed { .. } => "MethodNotAllowed",
+            Self::SlowDown(_) | Self::UpstreamTimeout(_) => "SlowDown",
+            Self::UpstreamUnavailable(_) => "ServiceUnavailable",
             _ => "InternalError",
         }
     }
 
     pub fn status_code(&self) -> StatusCode {
         match self {
-            Self::NotFound(_) | Self::BucketNotFound(_) | Self::MultipartNotFound(_) => {
-                StatusCode::NOT_FOUND
+            Self::NotFound(_)
+            | Self::BucketNotFound(_)
+            | Self::NoSuchVersion(_)
+            | Self::MultipartNotFound(_)
+            | Self::NoSuchLifecycleConfiguration
+            | Self::NoSuchTagSet
+            | Self::NoSuchEncryptionConfiguration
+            | Self::NoSuchBucketPolicy
+            | Self::NoSuchPublicAccessBlockConfiguration => StatusCode::NOT_FOUND,
+            Self::AccessDenied
+            | Self::InvalidSignature
+            | Self::MissingAuth
+            | Self::BucketNotProvisioned(_)
+            | Self::PostPolicyFailed(_)
+            | Self::RetentionActive(_)
+            | Self::ClientAddressDenied(_)
+            | Self::KeyRuleDenied(_)
+            | Self::KeyPolicyDenied(_)
+            | Self::QuotaExceeded { .. } => StatusCode::FORBIDDEN,
+            Self::BucketAlreadyOwnedByYou(_)
+            | Self::BucketNotEmpty(_)
+            | Self::ConditionalRequestConflict => StatusCode::CONFLICT,
+            Self::InvalidRequest(_)
+            | Self::InvalidArgument(_)
+            | Self::InvalidBucketName(_)
+            | Self::InvalidStorageClass(_)
+            | Self::MalformedXml(_)
+            | Self::InvalidTag(_)
+            | Self::MalformedPolicy(_)
+            | Self::InvalidPart(_)
+            | Self::EntityTooLarge(_)
+            | Self::EntityTooSmall(_)
+            | Self::MetadataTooLarge(_)
+            | Self::KeyTooLong { .. }
+            | Self::MissingContentMd5
+            | Self::InvalidDigest
+            | Self::BadDigest
+            | Self::IncompleteBody { .. }
+            | Self::InvalidSelect { .. } => StatusCode::BAD_REQUEST,
+            Self::MissingContentLength => StatusCode::LENGTH_REQUIRED,
+            Self::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            Self::InvalidRange | Self::InvalidPartNumber => StatusCode::RANGE_NOT_SATISFIABLE,
+            Self::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+            Self::MethodNotAllowed { .. } => StatusCode::METHOD_NOT_ALLOWED,
+            Self::SlowDown(_) | Self::UpstreamTimeout(_) | Self::UpstreamUnavailable(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
             }
-            Self::AccessDenied | Self::InvalidSignature | Self::MissingAuth => {
-                StatusCode::FORBIDDEN
-            }
-            Self::InvalidRequest(_) | Self::InvalidPart(_) => StatusCode::BAD_REQUEST,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    /// Finer-grained code for logs, distinguishing failures that share an S3 code.
+    pub fn internal_code(&self) -> &'static str {
+        match self {
+            Self::UpstreamTimeout(_) => "UpstreamTimeout",
+            Self::UpstreamUnavailable(_) => "UpstreamUnavailable",
+            Self::UpstreamTls(_) => "UpstreamTlsError",
+            Self::UpstreamDecode(_) => "UpstreamDecodeError",
+            _ => self.s3_error_code(),
+        }
+    }
+
+    /// Whether retrying the upstream call may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::UpstreamTimeout(_) | Self::UpstreamUnavailable(_)
+        )
+    }
+
+    /// Attaches the bucket/key being operated on so the error body can name it.
+    pub fn with_resource(self, bucket: Option<&str>, key: Option<&str>) -> S3Error {
+        S3Error {
+            error: self,
+            bucket: bucket.map(|b| b.to_string()),
+            key: key.map(|k| k.to_string()),
+            request_id: None,
+        }
+    }
+
+    /// Renders the S3 `<Error>` element for this error, without the XML declaration.
+    pub fn to_xml(&self, bucket: Option<&str>, key: Option<&str>, request_id: &str) -> String {
+        let resource = match (bucket, key) {
+            (Some(b), Some(k)) => format!("<Resource>/{}/{}</Resource>", esc(b), esc(k)),
+            (Some(b), None) => format!("<Resource>/{}</Resource>", esc(b)),
+            _ => "<Resource>/</Resource>".to_string(),
+        };
+        let key_xml = key
+            .map(|k| format!("<Key>{}</Key>", esc(k)))
+            .unwrap_or_default();
+        let bucket_xml = bucket
+            .map(|b| format!("<BucketName>{}</BucketName>", esc(b)))
+            .unwrap_or_default();
+
+        format!(
+            r#"<Error><Code>{}</Code><Message>{}</Message>{}{}{}<RequestId>{}</RequestId><HostId>{}</HostId></Error>"#,
+            self.s3_error_code(),
+            esc(&self.to_string()),
+            bucket_xml,
+            key_xml,
+            resource,
+            request_id,
+            host_id()
+        )
+    }
 }
 
-impl IntoResponse for ProxyError {
+impl From<reqwest::Error> for ProxyError {
+    fn from(e: reqwest::Error) -> Self {
+        let chain = error_chain(&e);
+        // reqwest's own message names the URL, and so the object key; only
+        // the causes below it say what went wrong.
+        let causes = std::error::Error::source(&e)
+            .map(error_chain)
+            .unwrap_or_default();
+        let error = if e.is_timeout() {
+            Self::UpstreamTimeout(chain)
+        } else if is_tls_error(&causes) {
+            Self::UpstreamTls(chain)
+        } else if e.is_connect() || is_connection_reset(&e) {
+            Self::UpstreamUnavailable(chain)
+        } else if e.is_decode() || e.is_body() {
+            Self::UpstreamDecode(chain)
+        } else {
+            Self::HttpClient(e)
+        };
+        tracing::debug!("Classified upstream failure as {}", error.internal_code());
+        if matches!(error, Self::UpstreamTls(_)) {
+            tracing::error!("[{}] {}", error.internal_code(), error);
+        }
+        error
+    }
+}
+
+fn error_chain(e: &(dyn std::error::Error + 'static)) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+fn is_tls_error(chain: &str) -> bool {
+    let chain = chain.to_ascii_lowercase();
+    [
+        "certificate",
+        "tls",
+        "ssl",
+        "handshake",
+        "corrupt message",
+        "received fatal alert",
+    ]
+    .iter()
+    .any(|needle| chain.contains(needle))
+}
+
+fn is_connection_reset(e: &reqwest::Error) -> bool {
+    use std::io::ErrorKind;
+    let mut source = std::error::Error::source(e);
+    while let Some(cause) = source {
+        if let Some(io) = cause.downcast_ref::<std::io::Error>()
+            && matches!(
+                io.kind(),
+                ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::ConnectionRefused
+                    | ErrorKind::BrokenPipe
+            )
+        {
+            return true;
+        }
+        source = cause.source();
+    }
+    false
+}
+
+/// A [`ProxyError`] together with the resource the request targeted.
+#[derive(Debug)]
+pub struct S3Error {
+    pub error: ProxyError,
+    pub bucket: Option<String>,
+    pub key: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl S3Error {
+    /// Reports `request_id` instead of a fresh one, so the response matches the logs.
+    pub fn with_request_id(mut self, request_id: String) -> Self {
+        self.request_id = Some(request_id);
+        self
+    }
+}
+
+impl IntoResponse for S3Error {
     fn into_response(self) -> Response {
+        let request_id = self
+            .request_id
+            .clone()
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
         let body = format!(
-            r#"<?xml version="1.0" encoding="UTF-8"?><Error><Code>{}</Code><Message>{}</Message><RequestId>{}</RequestId></Error>"#,
-            self.s3_error_code(),
-            self.to_string()
-                .replace('&', "&amp;")
-                .replace('<', "&lt;")
-                .replace('>', "&gt;"),
-            uuid::Uuid::new_v4()
+            r#"<?xml version="1.0" encoding="UTF-8"?>{}"#,
+            self.error
+                .to_xml(self.bucket.as_deref(), self.key.as_deref(), &request_id)
         );
-        (
-            self.status_code(),
+        let mut response = (
+            self.error.status_code(),
             [
                 ("content-type", "application/xml"),
-                ("x-amz-request-id", &uuid::Uuid::new_v4().to_string()),
+                ("x-amz-request-id", request_id.as_str()),
+                ("x-amz-id-2", host_id()),
             ],
             body,
         )
-            .into_response()
+            .into_response();
+        if let ProxyError::MethodNotAllowed { allowed, .. } = &self.error {
+            response
+                .headers_mut()
+                .insert(header::ALLOW, HeaderValue::from_static(allowed));
+        }
+        crate::s3::response_compression::compressible(response)
     }
 }
 
+impl IntoResponse for ProxyError {
+    fn into_response(self) -> Response {
+        self.with_resource(None, None).into_response()
+    }
+}
+
+/// Stable identifier for this proxy host, reported as `<HostId>` / `x-amz-id-2`.
+pub fn host_id() -> &'static str {
+    static HOST_ID: OnceLock<String> = OnceLock::new();
+    HOST_ID.get_or_init(|| {
+        let hostname = std::env::var("HOSTNAME")
+            .ok()
+            .filter(|h| !h.is_empty())
+            .or_else(|| {
+                std::fs::read_to_string("/proc/sys/kernel/hostname")
+                    .ok()
+                    .map(|h| h.trim().to_string())
+            })
+            .unwrap_or_else(|| "bunny-s3-proxy".to_string());
+        base64::engine::general_purpose::STANDARD.encode(Sha256::digest(hostname.as_bytes()))
+    })
+}
+
+fn esc(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
 pub type Result<T> = std::result::Result<T, ProxyError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Accepts one connection and hands it to `respond`.
+    async fn mock_server<F, Fut>(respond: F) -> std::net::SocketAddr
+    where
+        F: FnOnce(tokio::net::TcpStream) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            if let Ok((stream, _)) = listener.accept().await {
+                respond(stream).await;
+            }
+        });
+        addr
+    }
+
+    async fn read_request(stream: &mut tokio::net::TcpStream) {
+        let mut buf = [0u8; 4096];
+        let _ = stream.read(&mut buf).await;
+    }
+
+    #[tokio::test]
+    async fn test_connection_refused_is_unavailable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let err: ProxyError = reqwest::get(format!("http://{}/", addr))
+            .await
+            .unwrap_err()
+            .into();
+        assert!(matches!(err, ProxyError::UpstreamUnavailable(_)), "{err:?}");
+        assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_timeout_is_slow_down() {
+        let addr = mock_server(|mut stream| async move {
+            read_request(&mut stream).await;
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        })
+        .await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let err: ProxyError = client
+            .get(format!("http://{}/", addr))
+            .send()
+            .await
+            .unwrap_err()
+            .into();
+        assert!(matches!(err, ProxyError::UpstreamTimeout(_)), "{err:?}");
+        assert_eq!(err.s3_error_code(), "SlowDown");
+        assert!(err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_tls_failure_is_internal() {
+        let addr = mock_server(|mut stream| async move {
+            read_request(&mut stream).await;
+            let _ = stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n")
+                .await;
+        })
+        .await;
+
+        let err: ProxyError = reqwest::get(format!("https://{}/", addr))
+            .await
+            .unwrap_err()
+            .into();
+        assert!(matches!(err, ProxyError::UpstreamTls(_)), "{err:?}");
+        assert_eq!(err.internal_code(), "UpstreamTlsError");
+        assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_key_does_not_make_a_tls_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        for key in ["certificates/a.pem", "tls/ssl-handshake.log"] {
+            let err: ProxyError = reqwest::get(format!("http://{}/zone/{}", addr, key))
+                .await
+                .unwrap_err()
+                .into();
+            assert!(matches!(err, ProxyError::UpstreamUnavailable(_)), "{err:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_decode_failure() {
+        let addr = mock_server(|mut stream| async move {
+            read_request(&mut stream).await;
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 9\r\n\r\nnot json!")
+                .await;
+        })
+        .await;
+
+        let response = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+        let err: ProxyError = response.json::<Vec<u8>>().await.unwrap_err().into();
+        assert!(matches!(err, ProxyError::UpstreamDecode(_)), "{err:?}");
+        assert!(!err.is_retryable());
+    }
+}
@@ -0,0 +1,326 @@
+//! `bunny-s3-proxy ls`, `stat` and `rm`: look at the storage zone through the
+//! Bunny API directly, to compare with what the proxy reports. Paths are
+//! relative to `--key-prefix` when one is set; no listener is bound.
+
+use serde::Serialize;
+
+use crate::bunny::types::StorageObject;
+use crate::bunny::{Backend, StorageBackend};
+use crate::config::Config;
+use crate::error::ProxyError;
+use crate::s3::object_meta::{ObjectMeta, ObjectMetaStore};
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct LsArgs {
+    /// Directory to list, e.g. `photos/2024/`; the zone root when omitted
+    #[arg(default_value = "")]
+    pub prefix: String,
+
+    /// List every object below the prefix instead of one level
+    #[arg(short, long)]
+    pub recursive: bool,
+
+    /// Show size, checksum and last change of each entry
+    #[arg(short, long)]
+    pub long: bool,
+
+    /// Print the listing as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct StatArgs {
+    pub key: String,
+
+    /// Print the description as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct RmArgs {
+    pub key: String,
+
+    /// Delete the object; without it the deletion is only described
+    #[arg(long)]
+    pub yes: bool,
+
+    /// Print the result as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    key: String,
+    is_directory: bool,
+    size: i64,
+    checksum: Option<String>,
+    last_changed: String,
+}
+
+impl From<&StorageObject> for Entry {
+    fn from(obj: &StorageObject) -> Self {
+        let mut key = obj.s3_key();
+        if obj.is_directory {
+            key.push('/');
+        }
+        Self {
+            key,
+            is_directory: obj.is_directory,
+            size: obj.length,
+            checksum: obj.checksum.clone(),
+            last_changed: obj.last_changed.to_rfc3339(),
+        }
+    }
+}
+
+/// What the proxy serves for an object, which differs from Bunny's view for
+/// objects stored encrypted or compressed.
+#[derive(Debug, Serialize, PartialEq)]
+struct S3View {
+    etag: String,
+    size: u64,
+    storage_class: String,
+    encrypted: bool,
+    compressed: Option<String>,
+}
+
+impl S3View {
+    fn new(obj: &StorageObject, meta: &ObjectMeta) -> Self {
+        let (size, etag) = match meta.original() {
+            Some((size, etag)) => (size, etag.to_string()),
+            None => (obj.length.max(0) as u64, obj.etag()),
+        };
+        Self {
+            etag: format!("\"{}\"", etag),
+            size,
+            storage_class: meta.storage_class().to_string(),
+            encrypted: meta.encryption.is_some(),
+            compressed: meta.compression.as_ref().map(|c| c.algorithm.clone()),
+        }
+    }
+}
+
+fn client(config: &Config) -> Backend {
+    let client = Backend::new(config, config.into());
+    match &config.key_prefix {
+        Some(prefix) => client.scoped(prefix),
+        None => client,
+    }
+}
+
+pub async fn ls(config: &Config, args: &LsArgs) -> anyhow::Result<()> {
+    let client = client(config);
+    let mut objects = if args.recursive {
+        client.list_recursive(&args.prefix, None).await?
+    } else {
+        client.list(&args.prefix).await?
+    };
+    objects.sort_by_key(|obj| obj.s3_key());
+    let entries: Vec<Entry> = objects.iter().map(Entry::from).collect();
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        for entry in &entries {
+            println!("{}", format_entry(entry, args.long));
+        }
+    }
+    Ok(())
+}
+
+fn format_entry(entry: &Entry, long: bool) -> String {
+    if !long {
+        return entry.key.clone();
+    }
+    let size = match entry.is_directory {
+        true => "-".to_string(),
+        false => entry.size.to_string(),
+    };
+    format!(
+        "{:>14}  {:<32}  {:<64}  {}",
+        size,
+        entry.last_changed,
+        entry.checksum.as_deref().unwrap_or("-"),
+        entry.key
+    )
+}
+
+pub async fn stat(config: &Config, args: &StatArgs) -> anyhow::Result<()> {
+    let client = client(config);
+    let obj = describe(&client, &args.key).await?;
+    let s3 = match obj.is_directory {
+        true => None,
+        false => Some(S3View::new(
+            &obj,
+            &ObjectMetaStore::get(&client, &args.key).await?,
+        )),
+    };
+
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "bunny": obj,
+                "s3": s3,
+            }))?
+        );
+        return Ok(());
+    }
+    let rows = [
+        ("Key", args.key.clone()),
+        ("Directory", obj.is_directory.to_string()),
+        ("Length", obj.length.to_string()),
+        (
+            "Checksum",
+            obj.checksum.clone().unwrap_or_else(|| "-".into()),
+        ),
+        ("Content-Type", obj.content_type.clone()),
+        ("Last changed", obj.last_changed.to_rfc3339()),
+        ("Created", obj.date_created.to_rfc3339()),
+        ("Guid", obj.guid.clone()),
+        ("Server", obj.server_id.to_string()),
+        (
+            "Replicated to",
+            obj.replicated_zones.clone().unwrap_or_else(|| "-".into()),
+        ),
+    ];
+    for (name, value) in rows {
+        println!("{:<16}{}", format!("{}:", name), value);
+    }
+    if let Some(s3) = s3 {
+        println!("{:<16}{}", "S3 ETag:", s3.etag);
+        println!("{:<16}{}", "S3 size:", s3.size);
+        println!("{:<16}{}", "Storage class:", s3.storage_class);
+        println!("{:<16}{}", "Encrypted:", s3.encrypted);
+        if let Some(algorithm) = s3.compressed {
+            println!("{:<16}{}", "Compressed:", algorithm);
+        }
+    }
+    Ok(())
+}
+
+pub async fn rm(config: &Config, args: &RmArgs) -> anyhow::Result<()> {
+    let client = client(config);
+    let obj = describe(&client, &args.key).await?;
+    if obj.is_directory {
+        anyhow::bail!("{} is a directory", args.key);
+    }
+    if args.yes {
+        client.delete(&args.key).await?;
+        // The sidecar goes with the object, as on DeleteObject.
+        match ObjectMetaStore::delete(&client, &args.key).await {
+            Ok(()) | Err(ProxyError::NotFound(_)) => {}
+            Err(e) => eprintln!("warning: metadata of {} left behind: {}", args.key, e),
+        }
+    }
+
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "key": args.key,
+                "size": obj.length,
+                "deleted": args.yes,
+            }))?
+        );
+    } else if args.yes {
+        println!("deleted {} ({} bytes)", args.key, obj.length);
+    } else {
+        println!(
+            "would delete {} ({} bytes) (dry run: pass --yes to delete)",
+            args.key, obj.length
+        );
+    }
+    Ok(())
+}
+
+async fn describe(client: &Backend, key: &str) -> anyhow::Result<StorageObject> {
+    match client.describe(key).await {
+        Ok(obj) => Ok(obj),
+        Err(ProxyError::NotFound(_)) => anyhow::bail!("{}: not found", key),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Command;
+    use crate::s3::object_meta::CompressionMeta;
+    use clap::Parser;
+
+    fn object(checksum: Option<&str>) -> StorageObject {
+        serde_json::from_value(serde_json::json!({
+            "Guid": "g",
+            "UserId": "u",
+            "StorageZoneName": "zone",
+            "Path": "/zone/photos/",
+            "ObjectName": "cat.jpg",
+            "Length": 120,
+            "LastChanged": "2024-05-01T12:00:00",
+            "DateCreated": "2024-05-01T12:00:00",
+            "StorageZoneId": 1,
+            "IsDirectory": false,
+            "ServerId": 1,
+            "Checksum": checksum,
+            "ContentType": "image/jpeg",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_s3_view_matches_what_head_serves() {
+        let plain = S3View::new(&object(Some("ABC123")), &ObjectMeta::default());
+        assert_eq!(plain.etag, "\"ABC123\"");
+        assert_eq!(plain.size, 120);
+        assert_eq!(plain.storage_class, "STANDARD");
+
+        let meta = ObjectMeta {
+            compression: Some(CompressionMeta {
+                algorithm: "zstd".into(),
+                size: 500,
+                etag: "original".into(),
+            }),
+            ..Default::default()
+        };
+        let compressed = S3View::new(&object(Some("ABC123")), &meta);
+        assert_eq!(compressed.etag, "\"original\"");
+        assert_eq!(compressed.size, 500);
+        assert_eq!(compressed.compressed.as_deref(), Some("zstd"));
+
+        let entry = Entry::from(&object(None));
+        assert_eq!(entry.key, "photos/cat.jpg");
+        assert!(format_entry(&entry, true).contains("  -  "));
+        assert_eq!(format_entry(&entry, false), "photos/cat.jpg");
+    }
+
+    #[test]
+    fn test_subcommand_parsing() {
+        let base = ["bunny-s3-proxy", "-z", "zone", "-k", "key"];
+        let parse = |extra: &[&str]| Config::parse_from(base.iter().chain(extra)).command;
+
+        let Some(Command::Ls(ls)) = parse(&["ls", "-rl", "photos/"]) else {
+            panic!("expected ls");
+        };
+        assert_eq!(ls.prefix, "photos/");
+        assert!(ls.recursive && ls.long && !ls.json);
+        let Some(Command::Ls(root)) = parse(&["ls"]) else {
+            panic!("expected ls");
+        };
+        assert_eq!(root.prefix, "");
+
+        let Some(Command::Stat(stat)) = parse(&["stat", "a/b.txt", "--json"]) else {
+            panic!("expected stat");
+        };
+        assert!(stat.key == "a/b.txt" && stat.json);
+
+        let Some(Command::Rm(rm)) = parse(&["rm", "a/b.txt"]) else {
+            panic!("expected rm");
+        };
+        assert!(!rm.yes);
+        assert!(Config::try_parse_from(base.iter().chain(&["rm"])).is_err());
+    }
+}
@@ -0,0 +1,428 @@
+//! Network ACL for the S3 listener from `--allow-ip`, `--deny-ip` and
+//! `--ip-filter-config`:
+//!
+//! ```toml
+//! allow = ["10.0.0.0/8", "2001:db8::/32"]
+//! deny = ["10.66.0.0/16"]
+//! ```
+//!
+//! Deny rules are checked first; with any allow rule an address must then
+//! match one, otherwise every address not denied is let in. Connections are
+//! refused at accept time, before a byte is read. Connections from
+//! `--trusted-proxy` addresses are let through and each of their requests
+//! is checked instead against the client address in X-Forwarded-For, with a
+//! 403. SIGHUP re-reads the file; the command-line lists stay as they are.
+
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::fmt::Write as _;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::error::{ProxyError, Result};
+
+/// Rejections are logged at most once per window, with a count of those
+/// left out, so a scan cannot flood the log.
+const LOG_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IpFilterFile {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct Lists {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl Lists {
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let file: IpFilterFile = toml::from_str(text)?;
+        let parse = |nets: Vec<String>| -> anyhow::Result<Vec<IpNet>> {
+            nets.iter()
+                .map(|net| parse_net(net).map_err(|e| anyhow::anyhow!(e)))
+                .collect()
+        };
+        Ok(Self {
+            allow: parse(file.allow)?,
+            deny: parse(file.deny)?,
+        })
+    }
+}
+
+/// Parses a CIDR block, or a single address as a block of one.
+pub fn parse_net(s: &str) -> std::result::Result<IpNet, String> {
+    let s = s.trim();
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid address or CIDR block '{}'", s))
+}
+
+/// Parses an X-Forwarded-For entry: an address, an IPv4 address with a
+/// port, or a bracketed IPv6 address with or without one.
+fn parse_forwarded(entry: &str) -> Option<IpAddr> {
+    let entry = entry.trim();
+    if let Ok(ip) = entry.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = entry.strip_prefix('[') {
+        let (ip, port) = rest.split_once(']')?;
+        if !port.is_empty() && port.strip_prefix(':')?.parse::<u16>().is_err() {
+            return None;
+        }
+        return ip.parse::<std::net::Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    entry
+        .parse::<SocketAddr>()
+        .ok()
+        .filter(SocketAddr::is_ipv4)
+        .map(|addr| addr.ip())
+}
+
+fn contains(nets: &[IpNet], ip: IpAddr) -> bool {
+    nets.iter().any(|net| net.contains(&ip))
+}
+
+#[derive(Debug)]
+struct RejectionLog {
+    window_start: Instant,
+    suppressed: u64,
+}
+
+#[derive(Debug)]
+pub struct IpFilter {
+    flags: Lists,
+    path: Option<PathBuf>,
+    file: RwLock<Arc<Lists>>,
+    trusted_proxies: Vec<IpNet>,
+    rejected_connections: AtomicU64,
+    rejected_requests: AtomicU64,
+    log: Mutex<Option<RejectionLog>>,
+}
+
+impl IpFilter {
+    pub fn new(config: &Config) -> anyhow::Result<Option<Self>> {
+        if config.allow_ip.is_empty()
+            && config.deny_ip.is_empty()
+            && config.ip_filter_config.is_none()
+        {
+            return Ok(None);
+        }
+        let file = match &config.ip_filter_config {
+            Some(path) => Self::read(path)?,
+            None => Lists::default(),
+        };
+        Ok(Some(Self {
+            flags: Lists {
+                allow: config.allow_ip.clone(),
+                deny: config.deny_ip.clone(),
+            },
+            path: config.ip_filter_config.clone(),
+            file: RwLock::new(Arc::new(file)),
+            trusted_proxies: config.trusted_proxy.clone(),
+            rejected_connections: AtomicU64::new(0),
+            rejected_requests: AtomicU64::new(0),
+            log: Mutex::new(None),
+        }))
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Lists> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("--ip-filter-config {}: {}", path.display(), e))?;
+        Lists::parse(&text)
+            .map_err(|e| anyhow::anyhow!("--ip-filter-config {}: {}", path.display(), e))
+    }
+
+    /// Re-reads `--ip-filter-config`, keeping the current lists if it fails.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let lists = Self::read(path)?;
+        tracing::info!(
+            "IP filter reloaded: {} allowed and {} denied blocks from {}",
+            lists.allow.len(),
+            lists.deny.len(),
+            path.display()
+        );
+        *self.file.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(lists);
+        Ok(())
+    }
+
+    /// Whether `ip` passes the lists: not denied, and allowed if any allow
+    /// rule exists. IPv4-mapped IPv6 addresses are matched as IPv4.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let file = Arc::clone(&self.file.read().unwrap_or_else(|e| e.into_inner()));
+        if contains(&self.flags.deny, ip) || contains(&file.deny, ip) {
+            return false;
+        }
+        (self.flags.allow.is_empty() && file.allow.is_empty())
+            || contains(&self.flags.allow, ip)
+            || contains(&file.allow, ip)
+    }
+
+    fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        contains(&self.trusted_proxies, ip.to_canonical())
+    }
+
+    /// Whether to serve a connection from `peer`. Trusted proxies are always
+    /// admitted; their requests are checked one by one.
+    pub fn admit_connection(&self, peer: SocketAddr) -> bool {
+        if self.is_trusted_proxy(peer.ip()) || self.allows(peer.ip()) {
+            return true;
+        }
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+        self.log_rejection("connection", peer.ip());
+        false
+    }
+
+    /// Refuses a request relayed by a trusted proxy whose client address is
+    /// not allowed. The client is the last X-Forwarded-For address that is
+    /// not itself a trusted proxy; without the header, the proxy's own.
+    /// Entries may carry a port. An unreadable entry from the client's
+    /// onwards, which a trusted proxy wrote, refuses the request.
+    pub fn check_request(&self, peer: SocketAddr, headers: &HeaderMap) -> Result<()> {
+        if !self.is_trusted_proxy(peer.ip()) {
+            return Ok(());
+        }
+        let mut entries = Vec::new();
+        for value in headers.get_all("x-forwarded-for") {
+            let Ok(value) = value.to_str() else {
+                return self.refuse_forwarded(peer, "<non-ASCII>");
+            };
+            entries.extend(value.split(',').filter(|e| !e.trim().is_empty()));
+        }
+        let mut client = None;
+        for entry in entries.iter().rev() {
+            let Some(ip) = parse_forwarded(entry) else {
+                return self.refuse_forwarded(peer, entry);
+            };
+            client = Some(ip);
+            if !self.is_trusted_proxy(ip) {
+                break;
+            }
+        }
+        let client = client.unwrap_or(peer.ip());
+        if self.allows(client) {
+            return Ok(());
+        }
+        self.rejected_requests.fetch_add(1, Ordering::Relaxed);
+        self.log_rejection("request", client);
+        Err(ProxyError::ClientAddressDenied(client.to_canonical()))
+    }
+
+    fn refuse_forwarded(&self, peer: SocketAddr, entry: &str) -> Result<()> {
+        self.rejected_requests.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(
+            "Rejected request relayed by {}: unreadable X-Forwarded-For entry '{}'",
+            peer.ip(),
+            entry.trim()
+        );
+        Err(ProxyError::AccessDenied)
+    }
+
+    fn log_rejection(&self, what: &str, ip: IpAddr) {
+        let mut log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        match log.as_mut() {
+            Some(window) if window.window_start.elapsed() < LOG_WINDOW => {
+                window.suppressed += 1;
+            }
+            _ => {
+                let suppressed = log.as_ref().map_or(0, |w| w.suppressed);
+                if suppressed > 0 {
+                    tracing::warn!(
+                        "Rejected {} from {} by the IP filter ({} more rejections not logged)",
+                        what,
+                        ip,
+                        suppressed
+                    );
+                } else {
+                    tracing::warn!("Rejected {} from {} by the IP filter", what, ip);
+                }
+                *log = Some(RejectionLog {
+                    window_start: Instant::now(),
+                    suppressed: 0,
+                });
+            }
+        }
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let name = "bunny_s3_proxy_ip_filter_rejections_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Connections and requests refused by the IP filter.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (layer, count) in [
+            ("connection", &self.rejected_connections),
+            ("request", &self.rejected_requests),
+        ] {
+            let _ = writeln!(
+                out,
+                "{}{{layer=\"{}\"}} {}",
+                name,
+                layer,
+                count.load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn filter(args: &[&str]) -> IpFilter {
+        let base = ["bunny-s3-proxy", "-z", "zone", "-k", "key"];
+        IpFilter::new(&Config::parse_from(base.iter().chain(args)))
+            .unwrap()
+            .unwrap()
+    }
+
+    fn peer(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 40000)
+    }
+
+    #[test]
+    fn test_deny_is_checked_before_allow() {
+        let filter = filter(&[
+            "--allow-ip",
+            "10.0.0.0/8,2001:db8::/32",
+            "--deny-ip",
+            "10.66.0.0/16",
+            "--deny-ip",
+            "2001:db8::1",
+        ]);
+        assert!(filter.admit_connection(peer("10.1.2.3")));
+        assert!(!filter.admit_connection(peer("10.66.0.9")));
+        assert!(!filter.admit_connection(peer("192.0.2.1")));
+        assert!(filter.admit_connection(peer("2001:db8::2")));
+        assert!(!filter.admit_connection(peer("2001:db8::1")));
+        // IPv4 clients of a dual-stack listener arrive IPv4-mapped.
+        assert!(filter.admit_connection(peer("::ffff:10.1.2.3")));
+        assert!(!filter.admit_connection(peer("::ffff:10.66.0.9")));
+        assert!(
+            filter
+                .render_metrics()
+                .contains("bunny_s3_proxy_ip_filter_rejections_total{layer=\"connection\"} 4")
+        );
+
+        let deny_only = self::filter(&["--deny-ip", "192.0.2.0/24"]);
+        assert!(deny_only.admit_connection(peer("198.51.100.1")));
+        assert!(!deny_only.admit_connection(peer("192.0.2.200")));
+    }
+
+    #[test]
+    fn test_trusted_proxy_requests_use_forwarded_address() {
+        let filter = filter(&[
+            "--allow-ip",
+            "203.0.113.0/24",
+            "--trusted-proxy",
+            "172.16.0.0/12",
+        ]);
+        let proxy = peer("172.16.0.5");
+        assert!(filter.admit_connection(proxy));
+        assert!(!filter.admit_connection(peer("198.51.100.1")));
+
+        let forwarded = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", value.parse().unwrap());
+            headers
+        };
+        assert!(
+            filter
+                .check_request(proxy, &forwarded("203.0.113.7"))
+                .is_ok()
+        );
+        // The client can prepend anything; only what the proxy appended counts.
+        assert!(
+            filter
+                .check_request(proxy, &forwarded("203.0.113.7, 198.51.100.1"))
+                .is_err()
+        );
+        assert!(
+            filter
+                .check_request(proxy, &forwarded("198.51.100.1, 203.0.113.7, 172.16.0.9"))
+                .is_ok()
+        );
+        let err = filter.check_request(proxy, &HeaderMap::new()).unwrap_err();
+        assert_eq!(err.s3_error_code(), "AccessDenied");
+
+        // Entries with ports, IPv6 in brackets included, are read.
+        for allowed in ["203.0.113.7:51234", "[2001:db8::7]:443, 203.0.113.7:80"] {
+            assert!(
+                filter.check_request(proxy, &forwarded(allowed)).is_ok(),
+                "{}",
+                allowed
+            );
+        }
+        assert!(
+            filter
+                .check_request(proxy, &forwarded("203.0.113.7, [2001:db8::7]"))
+                .is_err()
+        );
+        // An entry a trusted proxy wrote that cannot be read refuses the
+        // request; the client's own garbage before its address does not.
+        for refused in [
+            "203.0.113.7, unknown",
+            "unknown, 172.16.0.9",
+            "203.0.113.7:x",
+        ] {
+            let err = filter
+                .check_request(proxy, &forwarded(refused))
+                .unwrap_err();
+            assert_eq!(err.s3_error_code(), "AccessDenied", "{}", refused);
+        }
+        assert!(
+            filter
+                .check_request(proxy, &forwarded("garbage, 203.0.113.7, 172.16.0.9"))
+                .is_ok()
+        );
+        // Direct peers were already checked at accept time.
+        assert!(
+            filter
+                .check_request(peer("203.0.113.8"), &HeaderMap::new())
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_reload_reads_the_file() {
+        let path = std::env::temp_dir().join(format!("ip-filter-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "deny = [\"192.0.2.0/24\"]\n").unwrap();
+        let filter = filter(&[
+            "--ip-filter-config",
+            path.to_str().unwrap(),
+            "--deny-ip",
+            "198.51.100.1",
+        ]);
+        assert!(!filter.allows("192.0.2.1".parse().unwrap()));
+
+        std::fs::write(&path, "allow = [\"192.0.2.0/24\"]\n").unwrap();
+        filter.reload().unwrap();
+        assert!(filter.allows("192.0.2.1".parse().unwrap()));
+        assert!(!filter.allows("198.51.100.1".parse().unwrap()));
+        assert!(!filter.allows("203.0.113.1".parse().unwrap()));
+
+        std::fs::write(&path, "allow = [\"not-an-address\"]\n").unwrap();
+        assert!(filter.reload().is_err());
+        assert!(filter.allows("192.0.2.1".parse().unwrap()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+//! The S3 TCP listener. With `--reuse-port` it is bound with SO_REUSEPORT so
+//! several proxy processes can accept on the same address, which spreads load
+//! across cores and lets a new process start before the old one stops.
+//!
+//! The listener is built through socket2 so that the backlog and buffer
+//! sizes can be set before it listens; TCP_NODELAY and keepalive are set on
+//! each accepted connection.
+//!
+//! For local clients the proxy can listen on a Unix socket instead, or on
+//! Windows on a named pipe.
+
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::Config;
+
+/// Every Windows named pipe lives under this namespace.
+const PIPE_NAMESPACE: &str = r"\\.\pipe\";
+
+/// Rejects listener options this platform cannot serve, before anything is
+/// bound.
+pub fn validate(config: &Config) -> anyhow::Result<()> {
+    if config.socket_path.is_some() && !cfg!(unix) {
+        anyhow::bail!(
+            "--socket-path needs Unix domain sockets, which this platform lacks; use --pipe-name on Windows"
+        );
+    }
+    if config.reuse_port && !cfg!(unix) {
+        anyhow::bail!("--reuse-port needs SO_REUSEPORT, which this platform lacks");
+    }
+    if config.tcp_keepalive_retries.is_some() && cfg!(windows) {
+        anyhow::bail!("--tcp-keepalive-retries cannot be set on Windows");
+    }
+    if config.tcp_backlog <= 0 {
+        anyhow::bail!("--tcp-backlog must be positive");
+    }
+    if let Some(name) = &config.pipe_name {
+        if !cfg!(windows) {
+            anyhow::bail!("--pipe-name is only available on Windows; use --socket-path");
+        }
+        if name.len() <= PIPE_NAMESPACE.len()
+            || !name[..PIPE_NAMESPACE.len()].eq_ignore_ascii_case(PIPE_NAMESPACE)
+        {
+            anyhow::bail!(
+                "--pipe-name {} must start with {}, e.g. {}bunny-s3-proxy",
+                name,
+                PIPE_NAMESPACE,
+                PIPE_NAMESPACE
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Resolves on Ctrl+C, or on Unix also on SIGTERM, the signal service
+/// managers stop the proxy with.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::warn!("Cannot listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                () = ctrl_c => {}
+                _ = terminate.recv() => {}
+            },
+            Err(e) => {
+                tracing::warn!("Cannot listen for SIGTERM: {}", e);
+                ctrl_c.await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    ctrl_c.await;
+}
+
+/// Socket options of the S3 TCP listener and the connections it accepts.
+#[derive(Debug, Clone)]
+pub struct TcpOptions {
+    pub reuse_port: bool,
+    pub backlog: i32,
+    pub nodelay: bool,
+    pub keepalive: Option<TcpKeepalive>,
+    pub recv_buffer: Option<usize>,
+    pub send_buffer: Option<usize>,
+}
+
+impl TcpOptions {
+    pub fn new(config: &Config) -> Self {
+        let seconds = |d: chrono::Duration| Duration::from_secs(d.num_seconds().max(1) as u64);
+        let keepalive = config.tcp_keepalive.map(|idle| {
+            let mut keepalive = TcpKeepalive::new().with_time(seconds(idle));
+            if let Some(interval) = config.tcp_keepalive_interval {
+                keepalive = keepalive.with_interval(seconds(interval));
+            }
+            #[cfg(not(windows))]
+            if let Some(retries) = config.tcp_keepalive_retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+            keepalive
+        });
+        Self {
+            reuse_port: config.reuse_port,
+            backlog: config.tcp_backlog,
+            nodelay: config.tcp_nodelay,
+            keepalive,
+            recv_buffer: config.so_rcvbuf,
+            send_buffer: config.so_sndbuf,
+        }
+    }
+
+    /// Sets the per-connection options on an accepted stream. Buffer sizes
+    /// are inherited from the listener.
+    pub fn apply(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(keepalive) = &self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+/// Binds `addr` with `options`. SO_REUSEADDR is set on Unix, as tokio's own
+/// `bind` does, and SO_REUSEPORT too with `reuse_port`. Buffer sizes are set
+/// before listening so that the window scale offered to clients fits them.
+pub async fn bind_tcp(addr: SocketAddr, options: &TcpOptions) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    #[cfg(unix)]
+    {
+        socket.set_reuse_address(true)?;
+        if options.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+    }
+    if let Some(size) = options.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(options.backlog)?;
+    log_effective(&socket, options);
+    TcpListener::from_std(socket.into())
+}
+
+/// Logs the options in force, with buffer sizes as the kernel reports them,
+/// which may differ from those requested (Linux doubles them).
+fn log_effective(socket: &Socket, options: &TcpOptions) {
+    let keepalive = match (&options.keepalive, socket.tcp_keepalive_time()) {
+        (Some(_), Ok(idle)) => format!("after {:?} idle", idle),
+        (Some(_), Err(_)) => "on".to_string(),
+        (None, _) => "off".to_string(),
+    };
+    let buffer = |size: std::io::Result<usize>| match size {
+        Ok(size) => size.to_string(),
+        Err(e) => format!("unknown ({})", e),
+    };
+    tracing::info!(
+        "TCP options: backlog {}, TCP_NODELAY {}, keepalive {}, SO_RCVBUF {}, SO_SNDBUF {}",
+        options.backlog,
+        if options.nodelay { "on" } else { "off" },
+        keepalive,
+        buffer(socket.recv_buffer_size()),
+        buffer(socket.send_buffer_size())
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_listener_options_for_this_platform() {
+        let base = ["bunny-s3-proxy", "-z", "zone", "-k", "key"];
+        let check = |extra: &[&str]| validate(&Config::parse_from(base.iter().chain(extra)));
+        assert!(check(&[]).is_ok());
+        let socket = check(&["--socket-path", "/tmp/bunny-s3-proxy.sock"]);
+        let reuse_port = check(&["--reuse-port"]);
+        let pipe = check(&["--pipe-name", r"\\.\pipe\bunny-s3-proxy"]);
+
+        #[cfg(unix)]
+        {
+            assert!(socket.is_ok() && reuse_port.is_ok());
+            assert!(
+                pipe.unwrap_err()
+                    .to_string()
+                    .contains("only available on Windows")
+            );
+        }
+        #[cfg(windows)]
+        {
+            assert!(socket.unwrap_err().to_string().contains("--pipe-name"));
+            assert!(reuse_port.is_err());
+            assert!(pipe.is_ok());
+            assert!(check(&["--pipe-name", "bunny-s3-proxy"]).is_err());
+        }
+        let both = ["--socket-path", "a.sock", "--pipe-name", r"\\.\pipe\a"];
+        assert!(Config::try_parse_from(base.iter().chain(&both)).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reuse_port_shares_the_address() {
+        let options = |reuse_port: bool| {
+            let mut options = TcpOptions::new(&Config::parse_from([
+                "bunny-s3-proxy",
+                "-z",
+                "zone",
+                "-k",
+                "key",
+            ]));
+            options.reuse_port = reuse_port;
+            options
+        };
+        let first = bind_tcp("127.0.0.1:0".parse().unwrap(), &options(true))
+            .await
+            .unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind_tcp(addr, &options(true)).await.unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+
+        // Without the flag the address is still exclusive.
+        assert!(bind_tcp(addr, &options(false)).await.is_err());
+
+        // Either listener may take the connection.
+        let _client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        tokio::select! {
+            accepted = first.accept() => assert!(accepted.is_ok()),
+            accepted = second.accept() => assert!(accepted.is_ok()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_options_reach_accepted_connections() {
+        let config = Config::parse_from([
+            "bunny-s3-proxy",
+            "-z",
+            "zone",
+            "-k",
+            "key",
+            "--tcp-keepalive",
+            "90s",
+            "--tcp-keepalive-interval",
+            "15s",
+            "--so-rcvbuf",
+            "65536",
+        ]);
+        let options = TcpOptions::new(&config);
+        assert!(options.nodelay);
+        assert_eq!(options.backlog, 1024);
+
+        let listener = bind_tcp("127.0.0.1:0".parse().unwrap(), &options)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        options.apply(&stream).unwrap();
+
+        assert!(stream.nodelay().unwrap());
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(
+            socket.tcp_keepalive_time().unwrap(),
+            Duration::from_secs(90)
+        );
+        // The kernel may round or double the requested size.
+        assert!(socket.recv_buffer_size().unwrap() >= 65536 / 2);
+
+        let off = Config::parse_from([
+            "bunny-s3-proxy",
+            "-z",
+            "zone",
+            "-k",
+            "key",
+            "--tcp-nodelay",
+            "false",
+        ]);
+        TcpOptions::new(&off).apply(&stream).unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert!(
+            Config::try_parse_from([
+                "bunny-s3-proxy",
+                "-z",
+                "z",
+                "-k",
+                "k",
+                "--tcp-keepalive-retries",
+                "3"
+            ])
+            .is_err()
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use dashmap::DashMap;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub struct LockGuard {
     #[allow(dead_code)]
@@ -23,7 +23,7 @@ pub trait ConditionalLock: Send + Sync {
 
 #[derive(Clone)]
 pub struct InMemoryLock {
-    locks: Arc<DashMap<String, ()>>,
+    locks: Arc<DashMap<String, Instant>>,
 }
 
 impl InMemoryLock {
@@ -46,7 +46,7 @@ impl ConditionalLock for InMemoryLock {
         match self.locks.entry(key.to_string()) {
             Entry::Occupied(_) => None,
             Entry::Vacant(v) => {
-                v.insert(());
+                v.insert(Instant::now());
                 let locks = self.locks.clone();
                 let key = key.to_string();
                 Some(LockGuard {
@@ -64,6 +64,8 @@ pub struct RedisLock {
     client: redis::Client,
     ttl: Duration,
     prefix: String,
+    /// Locks this instance currently holds; other instances' locks are not visible.
+    held: Arc<DashMap<String, Instant>>,
 }
 
 impl RedisLock {
@@ -73,6 +75,7 @@ impl RedisLock {
             client,
             ttl,
             prefix: "bunny-s3-lock:".to_string(),
+            held: Arc::new(DashMap::new()),
         })
     }
 
@@ -101,10 +104,14 @@ impl ConditionalLock for RedisLock {
             let client = self.client.clone();
             let lock_key_owned = lock_key.clone();
             let lock_value_owned = lock_value.clone();
+            let held = self.held.clone();
+            let held_key = key.to_string();
+            held.insert(held_key.clone(), Instant::now());
 
             Some(LockGuard {
                 key: key.to_string(),
                 release: Some(Box::new(move || {
+                    held.remove(&held_key);
                     tokio::spawn(async move {
                         if let Ok(mut conn) = client.get_multiplexed_async_connection().await {
                             let script = redis::Script::new(
@@ -130,11 +137,47 @@ pub enum Lock {
     Redis(RedisLock),
 }
 
+impl Lock {
+    /// Checks that the backend is reachable: a PING for Redis.
+    pub async fn ping(&self) -> Result<(), String> {
+        match self {
+            Lock::InMemory(_) => Ok(()),
+            Lock::Redis(lock) => {
+                let mut conn = lock
+                    .client
+                    .get_multiplexed_async_connection()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                redis::cmd("PING")
+                    .query_async::<String>(&mut conn)
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Keys locked by this instance and how long each has been held.
+    pub fn held(&self) -> Vec<(String, Duration)> {
+        let locks = match self {
+            Lock::InMemory(lock) => &lock.locks,
+            Lock::Redis(lock) => &lock.held,
+        };
+        locks
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().elapsed()))
+            .collect()
+    }
+}
+
 impl ConditionalLock for Lock {
+    #[tracing::instrument(name = "lock.try_lock", skip(self), fields(acquired))]
     async fn try_lock(&self, key: &str) -> Option<LockGuard> {
-        match self {
+        let guard = match self {
             Lock::InMemory(lock) => lock.try_lock(key).await,
             Lock::Redis(lock) => lock.try_lock(key).await,
-        }
+        };
+        tracing::Span::current().record("acquired", guard.is_some());
+        guard
     }
 }
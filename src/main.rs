@@ -1,91 +1,296 @@
 mod bunny;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod cleanup;
 mod config;
+mod debug_http;
+mod doctor;
 mod error;
+mod inspect;
+mod ip_filter;
+mod listener;
 mod lock;
+#[cfg(any(test, feature = "mock-bunny"))]
+mod mock_bunny;
+mod preflight;
+mod presign;
 mod s3;
+mod secrets;
+mod sync;
+mod telemetry;
+mod tls;
 
-use axum::{Router, extract::DefaultBodyLimit, routing::any};
-use clap::Parser;
-use tokio::net::{TcpListener, UnixListener};
+use axum::{
+    Router,
+    extract::{ConnectInfo, DefaultBodyLimit},
+    routing::any,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tower_http::trace::TraceLayer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use config::Config;
+use config::{Command, ConditionalWrites, Config};
+use s3::inventory::Inventory;
+use s3::lifecycle::LifecycleManager;
+use s3::quota::Quotas;
+use s3::response_headers::ResponseHeaders;
+use s3::trash::Trash;
 use s3::{AppState, handle_s3_request};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Parse CLI arguments
-    let config = Config::parse();
-
-    // Initialize logging
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                format!("bunny_s3_proxy={0},tower_http={0}", config.log_level).into()
-            }),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    // Parse CLI arguments, reading secrets from their *_FILE variants
+    let config = secrets::parse_config()?;
+
+    match &config.command {
+        Some(Command::CleanupMultipart(args)) => cleanup::run(&config, args).await,
+        Some(Command::Ls(args)) => inspect::ls(&config, args).await,
+        Some(Command::Stat(args)) => inspect::stat(&config, args).await,
+        Some(Command::Rm(args)) => inspect::rm(&config, args).await,
+        Some(Command::Presign(args)) => presign::run(&config, args),
+        Some(Command::Inventory(args)) => s3::inventory::command(&config, args).await,
+        Some(Command::Sync(args)) => sync::run(&config, args).await,
+        Some(Command::Doctor(args)) => doctor::run(&config, args).await,
+        #[cfg(feature = "mock-bunny")]
+        Some(Command::MockBunny(args)) => mock_bunny::run(&config, args).await,
+        Some(Command::Serve) | None => serve(config).await,
+    }
+}
+
+async fn serve(config: Config) -> anyhow::Result<()> {
+    listener::validate(&config)?;
+
+    // Initialize logging and trace export
+    let _telemetry = telemetry::init(&config)?;
+    s3::panics::install_hook();
+    debug_http::init(config.debug_http);
+
+    #[cfg(feature = "chaos")]
+    if let Some(path) = &config.chaos_config {
+        chaos::init(path)?;
+    }
+    #[cfg(not(feature = "chaos"))]
+    if config.chaos_config.is_some() {
+        anyhow::bail!("--chaos-config requires a build with the `chaos` feature");
+    }
 
     tracing::info!("Starting bunny-s3-proxy v{}", env!("CARGO_PKG_VERSION"));
     tracing::info!("Storage zone: {}", config.storage_zone);
     tracing::info!("Region: {}", config.region);
+    if config.debug_http {
+        tracing::warn!(
+            "HTTP debug logging enabled: headers and request bodies are logged at trace level"
+        );
+    }
+    match config.conditional_writes {
+        ConditionalWrites::Off => tracing::warn!(
+            "Conditional writes: off; If-None-Match, If-Match and If-Unmodified-Since on writes are ignored"
+        ),
+        strategy => tracing::info!("Conditional writes: {}", strategy),
+    }
+    if config.bucket_as_prefix {
+        tracing::info!("Bucket-as-prefix mode: each bucket is a top-level folder of the zone");
+    }
+    if config.allow_bucket_purge {
+        tracing::warn!("DeleteBucket purge enabled: DELETE on the bucket removes every object");
+    }
+    if let Some(prefix) = &config.trash_prefix {
+        tracing::info!(
+            "Soft deletes enabled: deleted objects are kept under {} for {} hours",
+            prefix,
+            config.trash_retention.num_hours()
+        );
+    }
 
     // Create application state
-    let state = AppState::new(config.clone());
+    let state = AppState::new(config.clone())?;
+    let response_headers = config
+        .response_headers_config
+        .as_deref()
+        .map(ResponseHeaders::load)
+        .transpose()?;
+
+    // Check the zone, lock backend and staging area before taking traffic
+    let checks = preflight::run(&state).await;
+    let failed = preflight::report(&checks);
+    if !failed.is_empty() && (config.strict_startup || config.validate_only) {
+        let reasons: Vec<String> = failed
+            .iter()
+            .filter_map(|c| Some(format!("{}: {}", c.name, c.result.as_ref().err()?)))
+            .collect();
+        anyhow::bail!("Preflight failed: {}", reasons.join("; "));
+    }
+    if config.validate_only {
+        tracing::info!("Preflight passed; exiting (--validate-only)");
+        return Ok(());
+    }
+
+    // Enforce bucket lifecycle rules in the background
+    if config.lifecycle_interval_secs > 0 {
+        let interval = std::time::Duration::from_secs(config.lifecycle_interval_secs);
+        if config.bucket_as_prefix {
+            tokio::spawn(LifecycleManager::run_per_bucket(state.clone(), interval));
+        } else {
+            tokio::spawn(LifecycleManager::run(
+                state.clone(),
+                config.storage_zone.clone(),
+                interval,
+            ));
+        }
+        for zone in state.zones.keys() {
+            tokio::spawn(LifecycleManager::run(state.clone(), zone.clone(), interval));
+        }
+    }
+
+    // Purge trash past its retention window in the background
+    if state.trash.is_some() {
+        tokio::spawn(Trash::run(state.clone()));
+    }
+
+    // Seed and reconcile quota usage in the background
+    if state.quotas.is_some() {
+        tokio::spawn(Quotas::run(state.clone()));
+    }
+
+    // Build and reconcile the listing indexes in the background
+    let interval = config.listing_index_interval.to_std().unwrap_or_default();
+    for index in state.listing_indexes.iter() {
+        tokio::spawn(Arc::clone(index).run(interval));
+    }
+
+    // Reload the key rules and policies, cache policies, IP filter and S3
+    // secret files on SIGHUP
+    #[cfg(unix)]
+    if state.key_rules.is_some()
+        || state.key_policies.is_some()
+        || state.cache_policies.is_some()
+        || state.ip_filter.is_some()
+        || secrets::file_for("S3_SECRET_ACCESS_KEY").is_some()
+        || secrets::file_for("S3_CREDENTIALS").is_some()
+    {
+        tokio::spawn(reload_on_hangup(state.clone()));
+    }
+
+    // Write scheduled inventory reports in the background
+    if state.inventory.is_some() {
+        tokio::spawn(Inventory::run(state.clone()));
+    }
+
+    // Serve the admin status endpoint on its own listener
+    if let Some(admin_addr) = config.admin_addr {
+        let listener = TcpListener::bind(admin_addr).await?;
+        tracing::info!("Admin endpoint: http://{}/status", admin_addr);
+        let admin = s3::admin::router(state.clone());
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, admin).await {
+                tracing::error!("Admin listener failed: {}", e);
+            }
+        });
+    }
 
     // Build router
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/", any(handle_s3_request))
         .route("/{*path}", any(handle_s3_request))
-        .layer(DefaultBodyLimit::disable())
-        .layer(TraceLayer::new_for_http())
-        .with_state(state);
+        .layer(DefaultBodyLimit::disable());
+    if config.compress_responses {
+        app = app.layer(s3::response_compression::layer());
+    }
+    if let Some(headers) = response_headers {
+        app = s3::response_headers::layer(app, Arc::new(headers));
+    }
+    let ip_filter = state.ip_filter.clone();
+    let app = app.layer(TraceLayer::new_for_http()).with_state(state);
 
-    // Start server based on configuration
+    // Start server based on configuration, until a shutdown signal
+    tokio::select! {
+        served = serve_listener(&config, ip_filter, app) => served?,
+        () = listener::shutdown_signal() => tracing::info!("Shutdown signal received, stopping"),
+    }
+    #[cfg(unix)]
     if let Some(socket_path) = &config.socket_path {
-        // Unix socket mode
-        tracing::info!("Listening on Unix socket: {}", socket_path.display());
-
-        // Remove existing socket file if it exists
-        if socket_path.exists() {
-            std::fs::remove_file(socket_path)?;
-        }
-
-        let listener = UnixListener::bind(socket_path)?;
+        let _ = std::fs::remove_file(socket_path);
+    }
 
-        // Set permissions to allow connections
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o777))?;
-        }
+    Ok(())
+}
 
-        serve_unix(listener, app).await?;
+async fn serve_listener(
+    config: &Config,
+    ip_filter: Option<Arc<ip_filter::IpFilter>>,
+    app: Router,
+) -> anyhow::Result<()> {
+    if config.tls_cert.is_some() && (config.socket_path.is_some() || config.pipe_name.is_some()) {
+        tracing::warn!("TLS options apply to the TCP listener only; local listeners are plain");
+    }
+    if let Some(socket_path) = &config.socket_path {
+        serve_unix(socket_path, app).await
+    } else if let Some(pipe_name) = &config.pipe_name {
+        serve_pipe(pipe_name, app).await
     } else {
         // TCP mode
-        tracing::info!("Listening on http://{}", config.listen_addr);
-        tracing::info!("S3 endpoint: http://{}", config.listen_addr);
-        tracing::info!("Access Key ID: {}", config.s3_access_key_id);
+        let tls = tls::TlsListener::new(config)?.map(Arc::new);
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        tracing::info!("Listening on {}://{}", scheme, config.listen_addr);
+        tracing::info!("S3 endpoint: {}://{}", scheme, config.listen_addr);
+        tracing::info!("Access Key ID: {}", secrets::mask(&config.s3_access_key_id));
+        for credential in &config.s3_credentials {
+            tracing::info!(
+                "Also accepting Access Key ID: {}",
+                secrets::mask(&credential.access_key_id)
+            );
+        }
+        if config.tls_require_client_cert {
+            tracing::info!("Client certificates required, verified against the client CA");
+        } else if config.tls_client_ca.is_some() {
+            tracing::info!("Client certificates verified when presented");
+        }
 
-        let listener = TcpListener::bind(config.listen_addr).await?;
-        serve_tcp(listener, app).await?;
+        let options = listener::TcpOptions::new(config);
+        let listener = listener::bind_tcp(config.listen_addr, &options).await?;
+        if config.reuse_port {
+            tracing::info!("SO_REUSEPORT set: other processes may share this address");
+        }
+        serve_tcp(listener, options, ip_filter, tls, app).await
     }
-
-    Ok(())
 }
 
-async fn serve_tcp(listener: TcpListener, app: Router) -> anyhow::Result<()> {
-    use hyper::server::conn::{http1, http2};
-    use hyper_util::rt::{TokioExecutor, TokioIo};
-    use tower::ServiceExt;
-
+async fn serve_tcp(
+    listener: TcpListener,
+    options: listener::TcpOptions,
+    ip_filter: Option<Arc<ip_filter::IpFilter>>,
+    tls: Option<Arc<tls::TlsListener>>,
+    app: Router,
+) -> anyhow::Result<()> {
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, peer) = listener.accept().await?;
+        if ip_filter
+            .as_ref()
+            .is_some_and(|filter| !filter.admit_connection(peer))
+        {
+            continue;
+        }
+        if let Err(e) = options.apply(&stream) {
+            tracing::debug!("Could not set socket options for {}: {}", peer, e);
+        }
         let app = app.clone();
+        let tls = tls.clone();
 
         tokio::spawn(async move {
+            if let Some(tls) = tls {
+                match tls.accept(stream).await {
+                    Ok(accepted) => {
+                        let cert = accepted.client_cert.map(Arc::new);
+                        serve_connection(accepted.stream, accepted.is_h2, peer, cert, app).await
+                    }
+                    Err(e) => tracing::warn!("TLS handshake with {} failed: {}", peer, e),
+                }
+                return;
+            }
+
             // Peek at first bytes to detect HTTP/2 preface
             let mut buf = [0u8; 24];
             let n = match stream.peek(&mut buf).await {
@@ -97,51 +302,178 @@ async fn serve_tcp(listener: TcpListener, app: Router) -> anyhow::Result<()> {
             };
 
             let is_h2 = n >= 24 && &buf[..24] == b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
-            let io = TokioIo::new(stream);
+            serve_connection(stream, is_h2, peer, None, app).await;
+        });
+    }
+}
 
-            let service = hyper::service::service_fn(move |req| {
-                let app = app.clone();
-                async move { app.oneshot(req).await }
-            });
+async fn serve_connection<I>(
+    io: I,
+    is_h2: bool,
+    peer: SocketAddr,
+    client_cert: Option<Arc<tls::ClientCert>>,
+    app: Router,
+) where
+    I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    use hyper::server::conn::{http1, http2};
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use tower::ServiceExt;
 
-            if is_h2 {
-                let conn = http2::Builder::new(TokioExecutor::new())
-                    .adaptive_window(true)
-                    .serve_connection(io, service);
+    let io = TokioIo::new(io);
+    let service = hyper::service::service_fn(move |mut req: hyper::Request<_>| {
+        req.extensions_mut().insert(ConnectInfo(peer));
+        if let Some(cert) = &client_cert {
+            req.extensions_mut().insert(Arc::clone(cert));
+        }
+        let app = app.clone();
+        async move { app.oneshot(req).await }
+    });
 
-                if let Err(err) = conn.await {
-                    tracing::error!("Error serving HTTP/2 connection: {}", err);
-                }
-            } else {
-                let conn = http1::Builder::new().serve_connection(io, service);
+    if is_h2 {
+        let conn = http2::Builder::new(TokioExecutor::new())
+            .adaptive_window(true)
+            .serve_connection(io, service);
 
-                if let Err(err) = conn.await {
-                    tracing::error!("Error serving HTTP/1 connection: {}", err);
-                }
-            }
-        });
+        if let Err(err) = conn.await {
+            tracing::error!("Error serving HTTP/2 connection: {}", err);
+        }
+    } else {
+        let conn = http1::Builder::new().serve_connection(io, service);
+
+        if let Err(err) = conn.await {
+            tracing::error!("Error serving HTTP/1 connection: {}", err);
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn serve_unix(socket_path: &std::path::Path, app: Router) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    tracing::info!("Listening on Unix socket: {}", socket_path.display());
+
+    // Remove existing socket file if it exists
+    if socket_path.exists() {
+        std::fs::remove_file(socket_path)?;
+    }
+
+    let listener = UnixListener::bind(socket_path)?;
+
+    // Set permissions to allow connections
+    std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o777))?;
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(serve_local_connection(stream, app.clone()));
     }
 }
 
-async fn serve_unix(listener: UnixListener, app: Router) -> anyhow::Result<()> {
+#[cfg(not(unix))]
+async fn serve_unix(_socket_path: &std::path::Path, _app: Router) -> anyhow::Result<()> {
+    unreachable!("--socket-path is rejected by listener::validate on this platform")
+}
+
+/// Serves `\\.\pipe\<name>`, creating the next pipe instance as soon as a
+/// client takes the current one so that connecting clients never find the
+/// name missing.
+#[cfg(windows)]
+async fn serve_pipe(pipe_name: &str, app: Router) -> anyhow::Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    tracing::info!("Listening on named pipe: {}", pipe_name);
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(pipe_name)?;
+    loop {
+        server.connect().await?;
+        let connected = std::mem::replace(&mut server, ServerOptions::new().create(pipe_name)?);
+        tokio::spawn(serve_local_connection(connected, app.clone()));
+    }
+}
+
+#[cfg(not(windows))]
+async fn serve_pipe(_pipe_name: &str, _app: Router) -> anyhow::Result<()> {
+    unreachable!("--pipe-name is rejected by listener::validate on this platform")
+}
+
+/// Serves one connection of the Unix socket or named pipe, which carry
+/// plain HTTP/1 from local clients.
+async fn serve_local_connection<I>(io: I, app: Router)
+where
+    I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
     use hyper::server::conn::http1;
     use hyper_util::rt::TokioIo;
     use tower::ServiceExt;
 
-    loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
+    let io = TokioIo::new(io);
+    let service = hyper::service::service_fn(move |req| {
         let app = app.clone();
+        async move { app.oneshot(req).await }
+    });
 
-        tokio::spawn(async move {
-            let service = hyper::service::service_fn(move |req| {
-                let app = app.clone();
-                async move { app.oneshot(req).await }
-            });
+    if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+        tracing::error!("Error serving connection: {}", err);
+    }
+}
 
-            if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
-                tracing::error!("Error serving connection: {}", err);
+/// Re-reads `--key-rules-config`, `--key-policies-config`,
+/// `--cache-policy-config`, `--ip-filter-config`, `S3_SECRET_ACCESS_KEY_FILE`
+/// and `S3_CREDENTIALS_FILE` on every SIGHUP.
+/// A file that fails to load is logged and its previous contents stay in force.
+#[cfg(unix)]
+async fn reload_on_hangup(state: AppState) {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::warn!(
+                "Cannot listen for SIGHUP, configuration will not reload: {}",
+                e
+            );
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        if let Some(rules) = &state.key_rules
+            && let Err(e) = rules.reload()
+        {
+            tracing::error!("Keeping the previous key rules: {}", e);
+        }
+        if let Some(policies) = &state.key_policies
+            && let Err(e) = policies.reload()
+        {
+            tracing::error!("Keeping the previous key policies: {}", e);
+        }
+        if let Some(policies) = &state.cache_policies
+            && let Err(e) = policies.reload()
+        {
+            tracing::error!("Keeping the previous cache policies: {}", e);
+        }
+        if let Some(filter) = &state.ip_filter
+            && let Err(e) = filter.reload()
+        {
+            tracing::error!("Keeping the previous IP filter: {}", e);
+        }
+        match secrets::reread("S3_SECRET_ACCESS_KEY") {
+            Some(Ok(secret)) => {
+                state.auth.set_secret_access_key(secret);
+                tracing::info!("S3 secret access key reloaded from S3_SECRET_ACCESS_KEY_FILE");
             }
-        });
+            Some(Err(e)) => tracing::error!("Keeping the previous S3 secret access key: {}", e),
+            None => {}
+        }
+        match secrets::reread("S3_CREDENTIALS").map(|text| {
+            let credentials = config::parse_s3_credentials(&text?).map_err(anyhow::Error::msg)?;
+            state.auth.set_credentials(&credentials)
+        }) {
+            Some(Ok(())) => tracing::info!(
+                "S3 credentials reloaded from S3_CREDENTIALS_FILE, {} keys accepted",
+                state.auth.key_count()
+            ),
+            Some(Err(e)) => tracing::error!("Keeping the previous S3 credentials: {}", e),
+            None => {}
+        }
     }
 }
@@ -0,0 +1,552 @@
+//! A stand-in for the Bunny Edge Storage API, for local development and for
+//! running the e2e tests without a storage zone: `bunny-s3-proxy mock-bunny`
+//! with the `mock-bunny` feature, or [`spawn`] in tests.
+//!
+//! It covers what [`BunnyClient`](crate::bunny::BunnyClient) uses: GET of a
+//! directory (ending in `/`) lists it as `StorageObject` JSON, GET, PUT and
+//! DELETE of files, DESCRIBE, and the `AccessKey` header. Like Bunny, a PUT
+//! creates the parent directories, a `Checksum` header is checked against
+//! the body's SHA-256, deleting a directory deletes everything below it, and
+//! DESCRIBE of a missing file answers 200 with a `Length` of -1. Objects live
+//! in memory or, with `--dir`, as files below that directory.
+
+use axum::Router;
+use axum::body::Body;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header};
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+
+use crate::bunny::localfs::byte_range;
+use crate::bunny::types::StorageObject;
+
+#[cfg(feature = "mock-bunny")]
+#[derive(Debug, Clone, clap::Args)]
+pub struct MockArgs {
+    /// Address to serve the storage API on
+    #[arg(long, default_value = "127.0.0.1:8800")]
+    pub listen: std::net::SocketAddr,
+
+    /// Keep objects as files below this directory instead of in memory
+    #[arg(long)]
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+struct File {
+    /// The contents, when not kept on disk.
+    data: Option<Bytes>,
+    length: i64,
+    checksum: String,
+    content_type: String,
+    guid: String,
+    created: DateTime<Utc>,
+    changed: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct Objects {
+    /// Files by path, `zone/dir/name`.
+    files: BTreeMap<String, File>,
+    /// Directories by path, `zone/dir`, without the zone roots.
+    dirs: BTreeSet<String>,
+}
+
+#[derive(Clone)]
+struct Mock {
+    access_key: Arc<str>,
+    dir: Option<Arc<Path>>,
+    objects: Arc<Mutex<Objects>>,
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+fn is_zone_root(path: &str) -> bool {
+    !path.is_empty() && !path.contains('/')
+}
+
+fn status_json(status: StatusCode, message: &str) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/json")],
+        serde_json::json!({ "HttpCode": status.as_u16(), "Message": message }).to_string(),
+    )
+        .into_response()
+}
+
+impl Objects {
+    fn is_dir(&self, path: &str) -> bool {
+        is_zone_root(path) || self.dirs.contains(path)
+    }
+
+    fn add_parents(&mut self, path: &str) {
+        let mut dir = parent(path);
+        while !dir.is_empty() && !is_zone_root(dir) {
+            self.dirs.insert(dir.to_string());
+            dir = parent(dir);
+        }
+    }
+
+    fn describe_file(path: &str, file: &File) -> StorageObject {
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        StorageObject {
+            guid: file.guid.clone(),
+            user_id: "mock".into(),
+            last_changed: file.changed,
+            date_created: file.created,
+            storage_zone_name: path.split('/').next().unwrap_or("").into(),
+            path: format!("/{}/", dir),
+            object_name: name.into(),
+            length: file.length,
+            storage_zone_id: 1,
+            is_directory: false,
+            server_id: 1,
+            checksum: Some(file.checksum.clone()),
+            replicated_zones: Some(String::new()),
+            content_type: file.content_type.clone(),
+        }
+    }
+
+    fn describe_dir(path: &str) -> StorageObject {
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let now = Utc::now();
+        StorageObject {
+            guid: String::new(),
+            user_id: "mock".into(),
+            last_changed: now,
+            date_created: now,
+            storage_zone_name: path.split('/').next().unwrap_or("").into(),
+            path: match dir {
+                "" => "/".into(),
+                dir => format!("/{}/", dir),
+            },
+            object_name: name.into(),
+            length: 0,
+            storage_zone_id: 1,
+            is_directory: true,
+            server_id: 1,
+            checksum: None,
+            replicated_zones: None,
+            content_type: String::new(),
+        }
+    }
+
+    fn describe_missing(path: &str) -> StorageObject {
+        StorageObject {
+            length: -1,
+            is_directory: false,
+            ..Self::describe_dir(path)
+        }
+    }
+
+    fn list(&self, dir: &str) -> Vec<StorageObject> {
+        let below = format!("{}/", dir);
+        let dirs = self
+            .dirs
+            .range(below.clone()..)
+            .take_while(|d| d.starts_with(&below))
+            .filter(|d| parent(d) == dir)
+            .map(|d| Self::describe_dir(d));
+        let files = self
+            .files
+            .range(below.clone()..)
+            .take_while(|(p, _)| p.starts_with(&below))
+            .filter(|(p, _)| parent(p) == dir)
+            .map(|(p, f)| Self::describe_file(p, f));
+        dirs.chain(files).collect()
+    }
+}
+
+impl Mock {
+    fn new(access_key: &str, dir: Option<PathBuf>) -> std::io::Result<Self> {
+        let mut objects = Objects::default();
+        if let Some(root) = &dir {
+            std::fs::create_dir_all(root)?;
+            load(root, root, &mut objects)?;
+        }
+        Ok(Self {
+            access_key: Arc::from(access_key),
+            dir: dir.map(Arc::from),
+            objects: Arc::new(Mutex::new(objects)),
+        })
+    }
+
+    fn file_path(&self, path: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|root| root.join(path))
+    }
+
+    async fn read(&self, path: &str, file: &File) -> std::io::Result<Bytes> {
+        match (&file.data, self.file_path(path)) {
+            (Some(data), _) => Ok(data.clone()),
+            (None, Some(on_disk)) => Ok(tokio::fs::read(on_disk).await?.into()),
+            (None, None) => Ok(Bytes::new()),
+        }
+    }
+}
+
+/// Rebuilds the object list from files left by an earlier run.
+fn load(root: &Path, dir: &Path, objects: &mut Objects) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let Some(path) = entry
+            .path()
+            .strip_prefix(root)
+            .ok()
+            .and_then(|p| p.to_str())
+            .map(|p| p.replace('\\', "/"))
+        else {
+            continue;
+        };
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            if !is_zone_root(&path) {
+                objects.dirs.insert(path);
+            }
+            load(root, &entry.path(), objects)?;
+            continue;
+        }
+        let data = std::fs::read(entry.path())?;
+        let changed: DateTime<Utc> = metadata.modified()?.into();
+        objects.files.insert(
+            path,
+            File {
+                data: None,
+                length: data.len() as i64,
+                checksum: hex::encode_upper(Sha256::digest(&data)),
+                content_type: "application/octet-stream".into(),
+                guid: uuid::Uuid::new_v4().to_string(),
+                created: changed,
+                changed,
+            },
+        );
+    }
+    Ok(())
+}
+
+async fn handle(
+    State(mock): State<Mock>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let authorized = headers
+        .get("AccessKey")
+        .is_some_and(|key| key.as_bytes() == mock.access_key.as_bytes());
+    if !authorized {
+        return status_json(StatusCode::UNAUTHORIZED, "Unauthorized");
+    }
+    let Ok(decoded) = percent_encoding::percent_decode_str(uri.path()).decode_utf8() else {
+        return status_json(StatusCode::BAD_REQUEST, "Invalid path");
+    };
+    let is_dir_path = decoded.ends_with('/');
+    let path = decoded.trim_matches('/').to_string();
+    if path.is_empty() {
+        return status_json(StatusCode::NOT_FOUND, "Object Not Found");
+    }
+    if path.split('/').any(|segment| segment == "..") {
+        return status_json(StatusCode::BAD_REQUEST, "Invalid path");
+    }
+
+    let result = match method.as_str() {
+        "GET" if is_dir_path => Ok(list(&mock, &path)),
+        "GET" => get(&mock, &path, &headers).await,
+        "DESCRIBE" => Ok(describe(&mock, &path)),
+        "PUT" if is_dir_path => create_dir(&mock, &path).await,
+        "PUT" => put(&mock, &path, &headers, body).await,
+        "DELETE" => delete(&mock, &path).await,
+        _ => Ok(status_json(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed",
+        )),
+    };
+    result.unwrap_or_else(|e| {
+        tracing::error!("mock-bunny {} {} failed: {}", method, path, e);
+        status_json(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+    })
+}
+
+fn list(mock: &Mock, dir: &str) -> Response {
+    let objects = mock.objects.lock().unwrap();
+    axum::Json(objects.list(dir)).into_response()
+}
+
+fn describe(mock: &Mock, path: &str) -> Response {
+    let objects = mock.objects.lock().unwrap();
+    let obj = match objects.files.get(path) {
+        Some(file) => Objects::describe_file(path, file),
+        None if objects.is_dir(path) => Objects::describe_dir(path),
+        None => Objects::describe_missing(path),
+    };
+    axum::Json(obj).into_response()
+}
+
+async fn get(mock: &Mock, path: &str, headers: &HeaderMap) -> std::io::Result<Response> {
+    let Some(file) = mock.objects.lock().unwrap().files.get(path).cloned() else {
+        return Ok(status_json(StatusCode::NOT_FOUND, "Object Not Found"));
+    };
+    let data = mock.read(path, &file).await?;
+    let mut response_headers = HeaderMap::new();
+    let quoted = format!("\"{}\"", file.checksum);
+    for (name, value) in [
+        (header::CONTENT_TYPE, file.content_type.as_str()),
+        (header::ETAG, quoted.as_str()),
+        (
+            header::LAST_MODIFIED,
+            &file.changed.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+        ),
+    ] {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            response_headers.insert(name, value);
+        }
+    }
+
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+    let Some(range) = range else {
+        return Ok((response_headers, data).into_response());
+    };
+    match byte_range(range, data.len() as u64) {
+        Some((start, end)) => {
+            let content_range = format!("bytes {}-{}/{}", start, end, data.len());
+            response_headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&content_range).unwrap(),
+            );
+            let slice = data.slice(start as usize..=end as usize);
+            Ok((StatusCode::PARTIAL_CONTENT, response_headers, slice).into_response())
+        }
+        None => Ok(StatusCode::RANGE_NOT_SATISFIABLE.into_response()),
+    }
+}
+
+async fn create_dir(mock: &Mock, path: &str) -> std::io::Result<Response> {
+    if let Some(on_disk) = mock.file_path(path) {
+        tokio::fs::create_dir_all(on_disk).await?;
+    }
+    let mut objects = mock.objects.lock().unwrap();
+    if !is_zone_root(path) {
+        objects.dirs.insert(path.to_string());
+    }
+    objects.add_parents(path);
+    Ok(status_json(StatusCode::CREATED, "Directory created."))
+}
+
+async fn put(
+    mock: &Mock,
+    path: &str,
+    headers: &HeaderMap,
+    body: Body,
+) -> std::io::Result<Response> {
+    if is_zone_root(path) {
+        return Ok(status_json(StatusCode::BAD_REQUEST, "Invalid path"));
+    }
+    let data = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(std::io::Error::other)?;
+    let checksum = hex::encode_upper(Sha256::digest(&data));
+    if let Some(expected) = headers.get("Checksum").and_then(|v| v.to_str().ok())
+        && !expected.eq_ignore_ascii_case(&checksum)
+    {
+        return Ok(status_json(
+            StatusCode::BAD_REQUEST,
+            "Checksum does not match the uploaded file.",
+        ));
+    }
+    let content_type = headers
+        .get("Override-Content-Type")
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| mime_guess::from_path(path).first_raw())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+
+    let stored = match mock.file_path(path) {
+        Some(on_disk) => {
+            if let Some(dir) = on_disk.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::write(on_disk, &data).await?;
+            None
+        }
+        None => Some(data.clone()),
+    };
+    let now = Utc::now();
+    let mut objects = mock.objects.lock().unwrap();
+    let created = objects.files.get(path).map_or(now, |f| f.created);
+    objects.files.insert(
+        path.to_string(),
+        File {
+            data: stored,
+            length: data.len() as i64,
+            checksum,
+            content_type,
+            guid: uuid::Uuid::new_v4().to_string(),
+            created,
+            changed: now,
+        },
+    );
+    objects.add_parents(path);
+    Ok(status_json(StatusCode::CREATED, "File uploaded."))
+}
+
+async fn delete(mock: &Mock, path: &str) -> std::io::Result<Response> {
+    let is_file = {
+        let mut objects = mock.objects.lock().unwrap();
+        if objects.files.remove(path).is_some() {
+            true
+        } else if objects.is_dir(path) {
+            let below = format!("{}/", path);
+            objects.files.retain(|p, _| !p.starts_with(&below));
+            objects.dirs.retain(|d| d != path && !d.starts_with(&below));
+            false
+        } else {
+            return Ok(status_json(StatusCode::NOT_FOUND, "Object Not Found"));
+        }
+    };
+    if let Some(on_disk) = mock.file_path(path) {
+        let removed = match is_file {
+            true => tokio::fs::remove_file(on_disk).await,
+            false => tokio::fs::remove_dir_all(on_disk).await,
+        };
+        match removed {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(status_json(StatusCode::OK, "File deleted successfuly."))
+}
+
+fn router(mock: Mock) -> Router {
+    Router::new()
+        .route("/", axum::routing::any(handle))
+        .route("/{*path}", axum::routing::any(handle))
+        .layer(DefaultBodyLimit::disable())
+        .with_state(mock)
+}
+
+/// Serves an in-memory mock accepting `access_key` on an ephemeral port and
+/// returns its base URL, for use as `--bunny-endpoint`.
+#[cfg(test)]
+pub async fn spawn(access_key: &str) -> String {
+    let mock = Mock::new(access_key, None).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router(mock)).await });
+    url
+}
+
+/// Runs `bunny-s3-proxy mock-bunny`, accepting `--access-key` as the
+/// storage zone password.
+#[cfg(feature = "mock-bunny")]
+pub async fn run(config: &crate::config::Config, args: &MockArgs) -> anyhow::Result<()> {
+    let _telemetry = crate::telemetry::init(config)?;
+    let mock = Mock::new(&config.access_key, args.dir.clone())?;
+    let listener = TcpListener::bind(args.listen).await?;
+    tracing::info!(
+        "Mock Bunny storage API on http://{} ({})",
+        args.listen,
+        match &args.dir {
+            Some(dir) => format!("files in {}", dir.display()),
+            None => "in memory".into(),
+        }
+    );
+    tracing::info!(
+        "Point the proxy at it with --bunny-endpoint http://{}",
+        args.listen
+    );
+    axum::serve(listener, router(mock)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bunny::{BunnyClient, StorageBackend, UploadOptions};
+    use crate::config::{StorageRegion, StorageZoneConfig};
+    use crate::error::ProxyError;
+
+    fn client(url: &str, access_key: &str) -> BunnyClient {
+        BunnyClient::new(StorageZoneConfig {
+            name: "zone".into(),
+            access_key: access_key.into(),
+            region: StorageRegion::Falkenstein,
+            api_key: None,
+            endpoint: Some(url.into()),
+            pool: Default::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_client_against_the_mock() {
+        let url = spawn("secret").await;
+        let client = client(&url, "secret");
+
+        let body = Bytes::from_static(b"hello world");
+        let options = UploadOptions {
+            sha256_checksum: Some(hex::encode_upper(Sha256::digest(&body))),
+            content_type: Some("text/plain".into()),
+        };
+        client
+            .upload("a/b/c d.txt", body.clone(), options)
+            .await
+            .unwrap();
+        let bad_checksum = UploadOptions {
+            sha256_checksum: Some("00".repeat(32)),
+            content_type: None,
+        };
+        assert!(
+            client
+                .upload("x", body.clone(), bad_checksum)
+                .await
+                .is_err()
+        );
+
+        let obj = client.describe("a/b/c d.txt").await.unwrap();
+        assert_eq!(obj.length, 11);
+        assert_eq!(obj.s3_key(), "a/b/c d.txt");
+        assert_eq!(obj.content_type, "text/plain");
+        assert!(client.describe("a").await.unwrap().is_directory);
+        assert!(matches!(
+            client.describe("a/missing").await,
+            Err(ProxyError::NotFound(_))
+        ));
+
+        let root = client.list("").await.unwrap();
+        assert_eq!(root.len(), 1);
+        assert!(root[0].is_directory && root[0].s3_key() == "a");
+        let all = client.list_recursive("", None).await.unwrap();
+        assert_eq!(all.len(), 1);
+        assert!(client.list("nothing/").await.unwrap().is_empty());
+
+        let read = client.download("a/b/c d.txt").await.unwrap();
+        assert_eq!(read.etag(), Some(format!("\"{}\"", obj.etag())));
+        assert_eq!(read.bytes().await.unwrap(), body);
+        let range = client
+            .download_range("a/b/c d.txt", Some("bytes=6-"))
+            .await
+            .unwrap();
+        assert_eq!(range.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(range.content_range().as_deref(), Some("bytes 6-10/11"));
+        assert_eq!(range.bytes().await.unwrap(), "world");
+
+        client.delete("a").await.unwrap();
+        assert!(client.list_recursive("", None).await.unwrap().is_empty());
+        assert!(matches!(
+            client.download("a/b/c d.txt").await,
+            Err(ProxyError::NotFound(_))
+        ));
+
+        let wrong_key = self::client(&url, "wrong");
+        assert!(matches!(
+            wrong_key.list("").await,
+            Err(ProxyError::AccessDenied)
+        ));
+    }
+}
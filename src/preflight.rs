@@ -0,0 +1,157 @@
+//! Startup checks that surface configuration mistakes, such as a wrong access
+//! key or an unreachable lock backend, before the first client request does.
+
+use bytes::Bytes;
+
+use crate::bunny::{Backend, StorageBackend};
+use crate::config::ConditionalWrites;
+use crate::error::ProxyError;
+use crate::lock::Lock;
+use crate::s3::AppState;
+
+/// Written to and removed from the multipart staging area to prove it is writable.
+const STAGING_PROBE: &str = "__multipart/.preflight";
+
+pub struct Check {
+    pub name: String,
+    pub result: Result<(), String>,
+}
+
+/// Runs every check that applies to the configuration.
+pub async fn run(state: &AppState) -> Vec<Check> {
+    let mut checks = vec![Check {
+        name: format!("storage zone {}", state.config.storage_zone),
+        result: check_zone(&state.bucket_root).await,
+    }];
+    for zone in &state.config.extra_zone {
+        checks.push(Check {
+            name: format!("extra zone {}", zone.name),
+            result: check_zone(&state.zones[&zone.name]).await,
+        });
+    }
+    if let (Some(replication), Some(zone)) = (&state.replication, &state.config.shadow_zone) {
+        checks.push(Check {
+            name: format!("shadow zone {}", zone),
+            result: check_zone(replication.shadow()).await,
+        });
+    }
+    if state.config.redis_url.is_some() {
+        checks.push(Check {
+            name: "redis lock backend".to_string(),
+            result: match state.lock.as_ref() {
+                Lock::Redis(_) => state.lock.ping().await,
+                Lock::InMemory(_) => Err("the Redis URL could not be opened".to_string()),
+            },
+        });
+    }
+    if state.config.reuse_port {
+        checks.push(Check {
+            name: "lock sharing with --reuse-port".to_string(),
+            result: check_shared_locks(state),
+        });
+    }
+    checks.push(Check {
+        name: "multipart staging area".to_string(),
+        result: check_staging(&state.bucket_root).await,
+    });
+    checks
+}
+
+/// An authenticated DESCRIBE of the zone root; a 404 still proves the zone
+/// and key are valid, while a bad key or unknown zone yields 401.
+async fn check_zone(client: &Backend) -> Result<(), String> {
+    match client.describe("").await {
+        Ok(_) | Err(ProxyError::NotFound(_)) => Ok(()),
+        Err(ProxyError::AccessDenied) => {
+            Err("Bunny rejected the access key (check the zone name and key)".to_string())
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Processes sharing a port with `--reuse-port` cannot see each other's
+/// in-memory locks, so locked conditional writes need Redis.
+fn check_shared_locks(state: &AppState) -> Result<(), String> {
+    match (state.lock.as_ref(), state.config.conditional_writes) {
+        (Lock::InMemory(_), ConditionalWrites::Locked) => Err(
+            "in-memory locks are per process; set --redis-url, or --conditional-writes fast or off"
+                .to_string(),
+        ),
+        _ => Ok(()),
+    }
+}
+
+async fn check_staging(client: &Backend) -> Result<(), String> {
+    match client
+        .upload(STAGING_PROBE, Bytes::from_static(b"ok"), Default::default())
+        .await
+    {
+        Ok(()) => {}
+        Err(ProxyError::AccessDenied) => {
+            return Err(format!(
+                "cannot write {}: the access key is read-only (use the zone's password, not its read-only password)",
+                STAGING_PROBE
+            ));
+        }
+        Err(e) => return Err(format!("cannot write {}: {}", STAGING_PROBE, e)),
+    }
+    client
+        .delete(STAGING_PROBE)
+        .await
+        .map_err(|e| format!("cannot delete {}: {}", STAGING_PROBE, e))
+}
+
+/// Logs each check's outcome and returns the failures.
+pub fn report(checks: &[Check]) -> Vec<&Check> {
+    for check in checks {
+        match &check.result {
+            Ok(()) => tracing::info!("Preflight: {}: ok", check.name),
+            Err(e) => tracing::warn!("Preflight: {}: FAILED: {}", check.name, e),
+        }
+    }
+    checks.iter().filter(|c| c.result.is_err()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use clap::Parser;
+
+    #[test]
+    fn test_strict_startup_defaults_on() {
+        let base = ["bunny-s3-proxy", "-z", "zone", "-k", "key"];
+        let config = Config::parse_from(base);
+        assert!(config.strict_startup && !config.validate_only);
+        let lenient = Config::parse_from(base.iter().chain(&["--strict-startup", "false"]));
+        assert!(!lenient.strict_startup);
+    }
+
+    #[test]
+    fn test_reuse_port_needs_shared_locks() {
+        let state = |extra: &[&str]| {
+            let base = ["bunny-s3-proxy", "-z", "zone", "-k", "key", "--reuse-port"];
+            AppState::new(Config::parse_from(base.iter().chain(extra))).unwrap()
+        };
+        assert!(check_shared_locks(&state(&[])).is_err());
+        assert!(check_shared_locks(&state(&["--conditional-writes", "fast"])).is_ok());
+        assert!(check_shared_locks(&state(&["--conditional-writes", "off"])).is_ok());
+    }
+
+    #[test]
+    fn test_report_returns_failures() {
+        let checks = [
+            Check {
+                name: "a".into(),
+                result: Ok(()),
+            },
+            Check {
+                name: "b".into(),
+                result: Err("nope".into()),
+            },
+        ];
+        let failed = report(&checks);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].name, "b");
+    }
+}
@@ -0,0 +1,338 @@
+//! `bunny-s3-proxy presign` and the admin `POST /presign`: mint SigV4
+//! presigned URLs for the proxy's own endpoint with the configured S3
+//! credentials, so a download or upload link can be handed out without
+//! sharing them.
+
+use axum::http::Method;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::bunny::client::encode_path;
+use crate::config::Config;
+use crate::error::ProxyError;
+use crate::s3::auth::{AwsAuth, Presign};
+use crate::s3::handlers::is_valid_bucket_name;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PresignMethod {
+    /// A download link
+    Get,
+    /// An upload link
+    Put,
+}
+
+impl PresignMethod {
+    fn method(self) -> Method {
+        match self {
+            Self::Get => Method::GET,
+            Self::Put => Method::PUT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct PresignArgs {
+    pub method: PresignMethod,
+
+    /// The object, as `<bucket>/<key>`
+    pub target: String,
+
+    /// How long the URL stays valid, e.g. `15m`, `1h`, `7d` (at most 7 days)
+    #[arg(long, default_value = "1h", value_parser = crate::cleanup::parse_age)]
+    pub expires: Duration,
+
+    /// For `put`, the Content-Type the upload must be sent with
+    #[arg(long)]
+    pub content_type: Option<String>,
+
+    /// For `get`, the Content-Disposition the download is served with
+    #[arg(long)]
+    pub response_content_disposition: Option<String>,
+
+    /// Base URL clients reach the proxy at; defaults to the listen address
+    #[arg(long)]
+    pub endpoint: Option<String>,
+
+    /// Print the URL and its conditions as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// A presigned URL request, as the admin endpoint takes it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PresignRequest {
+    pub method: PresignMethod,
+    pub bucket: String,
+    pub key: String,
+    #[serde(default = "default_expires_secs")]
+    pub expires_secs: u64,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub response_content_disposition: Option<String>,
+    #[serde(default)]
+    pub endpoint: Option<String>,
+}
+
+fn default_expires_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Serialize)]
+pub struct Presigned {
+    pub url: String,
+    pub method: String,
+    pub expires_at: DateTime<Utc>,
+    /// Headers the request must be sent with for the signature to match.
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Signs `request` as of `now` for the proxy at `request.endpoint`, or at the
+/// listen address.
+pub fn presign(
+    config: &Config,
+    auth: &AwsAuth,
+    request: &PresignRequest,
+    now: DateTime<Utc>,
+) -> Result<Presigned, ProxyError> {
+    // The buckets the proxy serves, as in AppState::check_bucket.
+    let served = config.extra_zone.iter().any(|z| z.name == request.bucket)
+        || match config.bucket_as_prefix {
+            true => is_valid_bucket_name(&request.bucket),
+            false => request.bucket == config.storage_zone,
+        };
+    if !served {
+        return Err(ProxyError::BucketNotFound(request.bucket.clone()));
+    }
+    if request.key.is_empty() {
+        return Err(ProxyError::InvalidArgument("the key is empty".into()));
+    }
+    if request.content_type.is_some() && request.method != PresignMethod::Put {
+        return Err(ProxyError::InvalidArgument(
+            "a content type condition applies to uploads".into(),
+        ));
+    }
+    if request.response_content_disposition.is_some() && request.method != PresignMethod::Get {
+        return Err(ProxyError::InvalidArgument(
+            "a response disposition applies to downloads".into(),
+        ));
+    }
+
+    let endpoint = match &request.endpoint {
+        Some(endpoint) => endpoint.clone(),
+        None => default_endpoint(config),
+    };
+    let endpoint = url::Url::parse(&endpoint)
+        .map_err(|e| ProxyError::InvalidArgument(format!("invalid endpoint: {}", e)))?;
+    let Some(host) = endpoint.host_str() else {
+        return Err(ProxyError::InvalidArgument(
+            "the endpoint has no host".into(),
+        ));
+    };
+    if endpoint.path() != "/" || endpoint.query().is_some() {
+        // The proxy signs the path it sees; a path prefix would not match.
+        return Err(ProxyError::InvalidArgument(
+            "the endpoint must not have a path".into(),
+        ));
+    }
+    let host = match endpoint.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let path = format!("/{}/{}", request.bucket, encode_path(&request.key));
+
+    let mut headers = BTreeMap::new();
+    if let Some(content_type) = &request.content_type {
+        headers.insert("content-type".to_string(), content_type.clone());
+    }
+    let params = request
+        .response_content_disposition
+        .iter()
+        .map(|d| ("response-content-disposition".to_string(), d.clone()))
+        .collect();
+    let query = auth.presign(
+        &Presign {
+            method: request.method.method(),
+            host: &host,
+            path: &path,
+            params,
+            headers: headers.clone().into_iter().collect(),
+            region: config.region.code(),
+            expires_secs: request.expires_secs,
+        },
+        now,
+    )?;
+
+    Ok(Presigned {
+        url: format!("{}://{}{}?{}", endpoint.scheme(), host, path, query),
+        method: request.method.method().to_string(),
+        expires_at: now + Duration::seconds(request.expires_secs as i64),
+        headers,
+    })
+}
+
+/// `http(s)://<listen address>`, with `localhost` for an unspecified address.
+fn default_endpoint(config: &Config) -> String {
+    let scheme = match config.tls_cert {
+        Some(_) => "https",
+        None => "http",
+    };
+    let addr = config.listen_addr;
+    match addr.ip().is_unspecified() {
+        true => format!("{}://localhost:{}", scheme, addr.port()),
+        false => format!("{}://{}", scheme, addr),
+    }
+}
+
+pub fn run(config: &Config, args: &PresignArgs) -> anyhow::Result<()> {
+    let Some((bucket, key)) = args.target.split_once('/') else {
+        anyhow::bail!("expected <bucket>/<key>, got '{}'", args.target);
+    };
+    let request = PresignRequest {
+        method: args.method,
+        bucket: bucket.to_string(),
+        key: key.to_string(),
+        expires_secs: args.expires.num_seconds().max(0) as u64,
+        content_type: args.content_type.clone(),
+        response_content_disposition: args.response_content_disposition.clone(),
+        endpoint: args.endpoint.clone(),
+    };
+    let auth = AwsAuth::new(
+        config.s3_access_key_id.clone(),
+        config.s3_secret_access_key.clone(),
+    );
+    let presigned = presign(config, &auth, &request, Utc::now())?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&presigned)?);
+        return Ok(());
+    }
+    println!("{}", presigned.url);
+    for (name, value) in &presigned.headers {
+        eprintln!("send with: {}: {}", name, value);
+    }
+    eprintln!("expires: {}", presigned.expires_at.to_rfc3339());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Command;
+    use axum::http::{HeaderMap, Uri};
+    use clap::Parser;
+
+    fn config(extra: &[&str]) -> Config {
+        let base = [
+            "bunny-s3-proxy",
+            "-z",
+            "zone",
+            "-k",
+            "key",
+            "--s3-access-key-id",
+            "AKID",
+            "--s3-secret-access-key",
+            "secret",
+        ];
+        Config::parse_from(base.iter().chain(extra))
+    }
+
+    fn request(method: PresignMethod) -> PresignRequest {
+        PresignRequest {
+            method,
+            bucket: "zone".into(),
+            key: "2024/cat pic.jpg".into(),
+            expires_secs: 900,
+            content_type: None,
+            response_content_disposition: None,
+            endpoint: Some("https://s3.example.com".into()),
+        }
+    }
+
+    #[test]
+    fn test_presigned_urls_verify_against_the_proxy() {
+        let config = config(&[]);
+        let auth = AwsAuth::new("AKID".into(), "secret".into());
+        let now = Utc::now();
+
+        let get = PresignRequest {
+            response_content_disposition: Some("attachment".into()),
+            ..request(PresignMethod::Get)
+        };
+        let presigned = presign(&config, &auth, &get, now).unwrap();
+        assert!(
+            presigned
+                .url
+                .starts_with("https://s3.example.com/zone/2024/cat%20pic.jpg?")
+        );
+        assert!(
+            presigned
+                .url
+                .contains("response-content-disposition=attachment")
+        );
+        assert_eq!(presigned.expires_at, now + Duration::seconds(900));
+        let url = url::Url::parse(&presigned.url).unwrap();
+        let uri: Uri = format!("{}?{}", url.path(), url.query().unwrap())
+            .parse()
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "s3.example.com".parse().unwrap());
+        assert!(
+            auth.verify_request(&Method::GET, &uri, &headers, "UNSIGNED-PAYLOAD")
+                .is_ok()
+        );
+
+        let put = PresignRequest {
+            content_type: Some("image/jpeg".into()),
+            endpoint: None,
+            ..request(PresignMethod::Put)
+        };
+        let presigned = presign(&config, &auth, &put, now).unwrap();
+        assert!(presigned.url.starts_with("http://127.0.0.1:9000/zone/"));
+        assert_eq!(presigned.headers["content-type"], "image/jpeg");
+
+        for bad in [
+            PresignRequest {
+                content_type: Some("text/plain".into()),
+                ..request(PresignMethod::Get)
+            },
+            PresignRequest {
+                endpoint: Some("https://example.com/s3".into()),
+                ..request(PresignMethod::Get)
+            },
+            PresignRequest {
+                bucket: "photos".into(),
+                ..request(PresignMethod::Get)
+            },
+            PresignRequest {
+                expires_secs: 8 * 24 * 3600,
+                ..request(PresignMethod::Get)
+            },
+        ] {
+            assert!(presign(&config, &auth, &bad, now).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_subcommand_parsing() {
+        let Some(Command::Presign(args)) = config(&[
+            "presign",
+            "put",
+            "zone/a.jpg",
+            "--expires",
+            "15m",
+            "--content-type",
+            "image/jpeg",
+        ])
+        .command
+        else {
+            panic!("expected presign");
+        };
+        assert_eq!(args.method, PresignMethod::Put);
+        assert_eq!(args.target, "zone/a.jpg");
+        assert_eq!(args.expires, Duration::minutes(15));
+        assert_eq!(args.content_type.as_deref(), Some("image/jpeg"));
+    }
+}
@@ -0,0 +1,181 @@
+//! Per-prefix access rules from `--access-config`, which open parts of a
+//! bucket to unsigned reads while the rest stays signed:
+//!
+//! ```toml
+//! [[access]]
+//! prefix = "public/"
+//! anonymous = "read"
+//!
+//! [[access]]
+//! prefix = "public/drafts/"
+//! anonymous = "none"
+//! ```
+//!
+//! The rule with the longest prefix matching a key decides; a rule without
+//! `bucket` applies to every bucket, and one naming the key's bucket beats
+//! it at equal length. Rules only ever grant GetObject, HeadObject and
+//! listings: unsigned writes are refused whenever rules are configured.
+
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Debug, Deserialize)]
+struct AccessFile {
+    #[serde(default)]
+    access: Vec<AccessRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AccessRule {
+    pub prefix: String,
+    #[serde(default)]
+    pub bucket: Option<String>,
+    pub anonymous: Anonymous,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Anonymous {
+    /// Unsigned requests are refused, as if no rule matched under
+    /// `--require-auth`; used to close part of a public prefix.
+    None,
+    /// Unsigned GetObject, HeadObject and listings are allowed.
+    Read,
+}
+
+#[derive(Debug)]
+pub struct AccessRules {
+    rules: Vec<AccessRule>,
+}
+
+impl AccessRules {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("--access-config {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| anyhow::anyhow!("--access-config {}: {}", path.display(), e))
+    }
+
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let file: AccessFile = toml::from_str(text)?;
+        for (i, rule) in file.access.iter().enumerate() {
+            if file.access[..i]
+                .iter()
+                .any(|r| r.prefix == rule.prefix && r.bucket == rule.bucket)
+            {
+                anyhow::bail!("prefix '{}' has two rules", rule.prefix);
+            }
+        }
+        Ok(Self { rules: file.access })
+    }
+
+    /// The rule deciding unsigned access to `key` in `bucket`: the longest
+    /// matching prefix, preferring a rule naming the bucket at equal length.
+    fn rule_for(&self, bucket: &str, key: &str) -> Option<&AccessRule> {
+        self.rules
+            .iter()
+            .filter(|r| r.bucket.as_deref().is_none_or(|b| b == bucket))
+            .filter(|r| key.starts_with(&r.prefix))
+            .max_by_key(|r| (r.prefix.len(), r.bucket.is_some()))
+    }
+
+    /// Whether `key` may be read without a signature.
+    pub fn readable(&self, bucket: &str, key: &str) -> bool {
+        self.rule_for(bucket, key)
+            .is_some_and(|r| r.anonymous == Anonymous::Read)
+    }
+
+    /// Whether a listing of `bucket` may show the common prefix `prefix` to
+    /// an unsigned caller: it is readable itself, or leads to a readable
+    /// prefix further down.
+    pub fn leads_to_readable(&self, bucket: &str, prefix: &str) -> bool {
+        self.readable(bucket, prefix)
+            || self.rules.iter().any(|r| {
+                r.anonymous == Anonymous::Read
+                    && r.bucket.as_deref().is_none_or(|b| b == bucket)
+                    && r.prefix.starts_with(prefix)
+            })
+    }
+
+    /// Whether any part of `bucket` is readable without a signature.
+    pub fn has_readable(&self, bucket: &str) -> bool {
+        self.leads_to_readable(bucket, "")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"
+        [[access]]
+        prefix = "public/"
+        anonymous = "read"
+
+        [[access]]
+        prefix = "public/drafts/"
+        anonymous = "none"
+
+        [[access]]
+        prefix = "public/drafts/shared/"
+        anonymous = "read"
+
+        [[access]]
+        bucket = "assets"
+        prefix = ""
+        anonymous = "read"
+
+        [[access]]
+        bucket = "assets"
+        prefix = "public/"
+        anonymous = "none"
+    "#;
+
+    #[test]
+    fn test_longest_prefix_decides() {
+        let rules = AccessRules::parse(RULES).unwrap();
+        assert!(rules.readable("zone", "public/cat.jpg"));
+        assert!(!rules.readable("zone", "public/drafts/post.md"));
+        assert!(rules.readable("zone", "public/drafts/shared/post.md"));
+        assert!(!rules.readable("zone", "private/key.pem"));
+        assert!(!rules.readable("zone", "publicity.txt"));
+
+        // The bucket's own rule wins at equal length, but not over a longer one
+        assert!(rules.readable("assets", "logo.svg"));
+        assert!(!rules.readable("assets", "public/cat.jpg"));
+        assert!(!rules.readable("assets", "public/drafts/post.md"));
+        assert!(rules.readable("assets", "public/drafts/shared/post.md"));
+    }
+
+    #[test]
+    fn test_listing_prefixes() {
+        let rules = AccessRules::parse(RULES).unwrap();
+        assert!(rules.has_readable("zone"));
+        assert!(rules.leads_to_readable("zone", "public/"));
+        assert!(rules.leads_to_readable("zone", "pub"));
+        assert!(rules.leads_to_readable("zone", "public/drafts/"));
+        assert!(!rules.leads_to_readable("zone", "public/drafts/private/"));
+        assert!(!rules.leads_to_readable("zone", "private/"));
+
+        let closed = AccessRules::parse(
+            r#"
+            [[access]]
+            bucket = "other"
+            prefix = "public/"
+            anonymous = "read"
+            "#,
+        )
+        .unwrap();
+        assert!(!closed.has_readable("zone"));
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        for bad in [
+            "[[access]]\nprefix = \"a/\"\nanonymous = \"write\"",
+            "[[access]]\nanonymous = \"read\"",
+            "[[access]]\nprefix = \"a/\"\nanonymous = \"read\"\n[[access]]\nprefix = \"a/\"\nanonymous = \"none\"",
+        ] {
+            assert!(AccessRules::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
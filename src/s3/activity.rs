@@ -0,0 +1,466 @@
+use axum::body::Body;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use hyper::body::{Body as HttpBody, Frame, SizeHint};
+use serde::Serialize;
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use crate::bunny::accounting::UpstreamUsage;
+
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+const CALL_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0, 100.0, 500.0];
+const BYTE_BUCKETS: &[f64] = &[
+    0.0,
+    1024.0,
+    65536.0,
+    1048576.0,
+    16777216.0,
+    268435456.0,
+    1073741824.0,
+    5368709120.0,
+];
+const RATIO_BUCKETS: &[f64] = &[1.0, 1.5, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0];
+
+/// Name suffix, help text, bucket bounds and accessor of each exported histogram.
+type HistogramFamily = (
+    &'static str,
+    &'static str,
+    &'static [f64],
+    fn(&OperationMetrics) -> &Histogram,
+);
+
+const HISTOGRAMS: &[HistogramFamily] = &[
+    (
+        "request_duration_seconds",
+        "Time from receiving a request to finishing its response body.",
+        DURATION_BUCKETS,
+        |m| &m.duration,
+    ),
+    (
+        "request_upstream_calls",
+        "Bunny API calls made per request, including retries.",
+        CALL_BUCKETS,
+        |m| &m.upstream_calls,
+    ),
+    (
+        "request_upstream_received_bytes",
+        "Bytes received from Bunny per request.",
+        BYTE_BUCKETS,
+        |m| &m.upstream_bytes_in,
+    ),
+    (
+        "request_upstream_sent_bytes",
+        "Bytes sent to Bunny per request.",
+        BYTE_BUCKETS,
+        |m| &m.upstream_bytes_out,
+    ),
+    (
+        "request_client_received_bytes",
+        "Request body bytes received from the client.",
+        BYTE_BUCKETS,
+        |m| &m.client_bytes_in,
+    ),
+    (
+        "request_client_sent_bytes",
+        "Response body bytes sent to the client.",
+        BYTE_BUCKETS,
+        |m| &m.client_bytes_out,
+    ),
+    (
+        "request_compression_ratio",
+        "Original over stored size of object bodies compressed by --compress.",
+        RATIO_BUCKETS,
+        |m| &m.compression_ratio,
+    ),
+];
+
+/// Requests currently being served by this instance, plus per-operation
+/// histograms of finished ones. Both live in sharded maps so registering or
+/// finishing a request does not serialize the data path.
+#[derive(Default)]
+pub struct ActivityRegistry {
+    next_id: AtomicU64,
+    requests: DashMap<u64, Arc<InFlight>>,
+    metrics: DashMap<String, OperationMetrics>,
+}
+
+/// One in-flight S3 request. Byte counters are updated as bodies stream.
+pub struct InFlight {
+    request_id: String,
+    operation: String,
+    bucket: Option<String>,
+    key: Option<String>,
+    started_at: DateTime<Utc>,
+    started: Instant,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    upstream: Arc<UpstreamUsage>,
+}
+
+/// A point-in-time view of an [`InFlight`] request.
+#[derive(Serialize)]
+pub struct RequestSnapshot {
+    request_id: String,
+    operation: String,
+    bucket: Option<String>,
+    key: Option<String>,
+    started_at: DateTime<Utc>,
+    elapsed_ms: u128,
+    bytes_in: u64,
+    bytes_out: u64,
+    upstream_calls: u64,
+}
+
+impl ActivityRegistry {
+    pub fn register(
+        self: &Arc<Self>,
+        request_id: &str,
+        operation: &str,
+        bucket: Option<&str>,
+        key: Option<&str>,
+    ) -> InFlightGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = Arc::new(InFlight {
+            request_id: request_id.to_string(),
+            operation: operation.to_string(),
+            bucket: bucket.map(str::to_string),
+            key: key.map(str::to_string),
+            started_at: Utc::now(),
+            started: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            upstream: Arc::default(),
+        });
+        self.requests.insert(id, Arc::clone(&entry));
+        InFlightGuard {
+            registry: Arc::clone(self),
+            id,
+            entry,
+            status: None,
+            compression_ratio: None,
+        }
+    }
+
+    /// In-flight requests, oldest first.
+    pub fn snapshot(&self) -> Vec<RequestSnapshot> {
+        let mut requests: Vec<RequestSnapshot> = self
+            .requests
+            .iter()
+            .map(|entry| {
+                let request = entry.value();
+                RequestSnapshot {
+                    request_id: request.request_id.clone(),
+                    operation: request.operation.clone(),
+                    bucket: request.bucket.clone(),
+                    key: request.key.clone(),
+                    started_at: request.started_at,
+                    elapsed_ms: request.started.elapsed().as_millis(),
+                    bytes_in: request.bytes_in.load(Ordering::Relaxed),
+                    bytes_out: request.bytes_out.load(Ordering::Relaxed),
+                    upstream_calls: request.upstream.calls(),
+                }
+            })
+            .collect();
+        requests.sort_by_key(|r| r.started_at);
+        requests
+    }
+
+    /// Per-operation histograms in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut operations: Vec<_> = self
+            .metrics
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        operations.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut out = String::new();
+        for (name, help, bounds, histogram) in HISTOGRAMS {
+            let name = format!("bunny_s3_proxy_{}", name);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+            for (operation, metrics) in &operations {
+                histogram(metrics).render(&mut out, &name, operation, bounds);
+            }
+        }
+        out
+    }
+
+    fn observe(&self, operation: &str, summary: &Summary) {
+        let mut metrics = self.metrics.entry(operation.to_string()).or_default();
+        metrics
+            .duration
+            .observe(DURATION_BUCKETS, summary.duration_secs);
+        metrics
+            .upstream_calls
+            .observe(CALL_BUCKETS, summary.upstream_calls as f64);
+        metrics
+            .upstream_bytes_in
+            .observe(BYTE_BUCKETS, summary.upstream_bytes_in as f64);
+        metrics
+            .upstream_bytes_out
+            .observe(BYTE_BUCKETS, summary.upstream_bytes_out as f64);
+        metrics
+            .client_bytes_in
+            .observe(BYTE_BUCKETS, summary.client_bytes_in as f64);
+        metrics
+            .client_bytes_out
+            .observe(BYTE_BUCKETS, summary.client_bytes_out as f64);
+        if let Some(ratio) = summary.compression_ratio {
+            metrics.compression_ratio.observe(RATIO_BUCKETS, ratio);
+        }
+    }
+}
+
+struct Summary {
+    duration_secs: f64,
+    upstream_calls: u64,
+    upstream_bytes_in: u64,
+    upstream_bytes_out: u64,
+    client_bytes_in: u64,
+    client_bytes_out: u64,
+    compression_ratio: Option<f64>,
+}
+
+#[derive(Clone, Default)]
+struct OperationMetrics {
+    duration: Histogram,
+    upstream_calls: Histogram,
+    upstream_bytes_in: Histogram,
+    upstream_bytes_out: Histogram,
+    client_bytes_in: Histogram,
+    client_bytes_out: Histogram,
+    compression_ratio: Histogram,
+}
+
+/// A cumulative histogram; `counts[i]` holds observations `<= bounds[i]`.
+#[derive(Clone, Default)]
+struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, bounds: &[f64], value: f64) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; bounds.len()];
+        }
+        for (count, bound) in self.counts.iter_mut().zip(bounds) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, operation: &str, bounds: &[f64]) {
+        if self.count == 0 {
+            return;
+        }
+        for (count, bound) in self.counts.iter().zip(bounds) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{operation=\"{}\",le=\"{}\"}} {}",
+                name, operation, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{operation=\"{}\",le=\"+Inf\"}} {}",
+            name, operation, self.count
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum{{operation=\"{}\"}} {}",
+            name, operation, self.sum
+        );
+        let _ = writeln!(
+            out,
+            "{}_count{{operation=\"{}\"}} {}",
+            name, operation, self.count
+        );
+    }
+}
+
+/// Removes its request from the registry when dropped, logging a summary of
+/// the request and recording it in the per-operation histograms.
+pub struct InFlightGuard {
+    registry: Arc<ActivityRegistry>,
+    id: u64,
+    entry: Arc<InFlight>,
+    status: Option<u16>,
+    compression_ratio: Option<f64>,
+}
+
+impl InFlightGuard {
+    /// Bunny API usage to attribute to this request.
+    pub fn upstream(&self) -> Arc<UpstreamUsage> {
+        Arc::clone(&self.entry.upstream)
+    }
+
+    /// Request body bytes received from the client so far.
+    pub fn bytes_in(&self) -> u64 {
+        self.entry.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        self.status = Some(status);
+    }
+
+    /// Records the compression achieved on the object this request stored.
+    pub fn set_compression(&mut self, original: u64, stored: u64) {
+        if stored > 0 {
+            self.compression_ratio = Some(original as f64 / stored as f64);
+        }
+    }
+
+    /// Wraps the request body so received bytes are counted.
+    pub fn track_request(&self, body: Body) -> Body {
+        Body::new(CountingBody {
+            inner: body,
+            entry: Arc::clone(&self.entry),
+            outbound: false,
+            _guard: None,
+        })
+    }
+
+    /// Wraps the response body so sent bytes are counted and the request stays
+    /// listed until the body has been fully streamed or dropped.
+    pub fn track_response(self, body: Body) -> Body {
+        Body::new(CountingBody {
+            inner: body,
+            entry: Arc::clone(&self.entry),
+            outbound: true,
+            _guard: Some(self),
+        })
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.registry.requests.remove(&self.id);
+
+        let entry = &self.entry;
+        let summary = Summary {
+            duration_secs: entry.started.elapsed().as_secs_f64(),
+            upstream_calls: entry.upstream.calls(),
+            upstream_bytes_in: entry.upstream.bytes_received(),
+            upstream_bytes_out: entry.upstream.bytes_sent(),
+            client_bytes_in: entry.bytes_in.load(Ordering::Relaxed),
+            client_bytes_out: entry.bytes_out.load(Ordering::Relaxed),
+            compression_ratio: self.compression_ratio,
+        };
+        tracing::info!(
+            request_id = %entry.request_id,
+            operation = %entry.operation,
+            bucket = entry.bucket.as_deref().unwrap_or(""),
+            key = entry.key.as_deref().unwrap_or(""),
+            status = self.status.unwrap_or(0),
+            duration_ms = (summary.duration_secs * 1000.0) as u64,
+            upstream_calls = summary.upstream_calls,
+            upstream_bytes_in = summary.upstream_bytes_in,
+            upstream_bytes_out = summary.upstream_bytes_out,
+            client_bytes_in = summary.client_bytes_in,
+            client_bytes_out = summary.client_bytes_out,
+            compression_ratio = summary.compression_ratio,
+            "Request finished"
+        );
+        self.registry.observe(&entry.operation, &summary);
+    }
+}
+
+struct CountingBody {
+    inner: Body,
+    entry: Arc<InFlight>,
+    outbound: bool,
+    _guard: Option<InFlightGuard>,
+}
+
+impl HttpBody for CountingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Bytes>, axum::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll
+            && let Some(data) = frame.data_ref()
+        {
+            let counter = if self.outbound {
+                &self.entry.bytes_out
+            } else {
+                &self.entry.bytes_in
+            };
+            counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_tracks_requests_until_response_body_ends() {
+        let registry = Arc::new(ActivityRegistry::default());
+        let guard = registry.register("req-1", "GetObject", Some("zone"), Some("a.txt"));
+        assert_eq!(registry.snapshot().len(), 1);
+
+        let body = guard.track_response(Body::from("hello"));
+        assert_eq!(registry.snapshot().len(), 1);
+        drop(body);
+        assert!(registry.snapshot().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_finished_requests_feed_histograms() {
+        let registry = Arc::new(ActivityRegistry::default());
+        let mut guard = registry.register("req-2", "PutObject", Some("zone"), Some("b.txt"));
+        guard.set_status(200);
+        guard.set_compression(10, 4);
+
+        let request = guard.track_request(Body::from("12345"));
+        axum::body::to_bytes(request, usize::MAX).await.unwrap();
+        let response = guard.track_response(Body::from("ok"));
+        axum::body::to_bytes(response, usize::MAX).await.unwrap();
+        assert!(registry.snapshot().is_empty());
+
+        let metrics = registry.render_metrics();
+        assert!(metrics.contains(
+            "bunny_s3_proxy_request_client_received_bytes_bucket{operation=\"PutObject\",le=\"1024\"} 1"
+        ));
+        assert!(
+            metrics.contains(
+                "bunny_s3_proxy_request_client_sent_bytes_sum{operation=\"PutObject\"} 2"
+            )
+        );
+        assert!(
+            metrics
+                .contains("bunny_s3_proxy_request_upstream_calls_count{operation=\"PutObject\"} 1")
+        );
+        assert!(metrics.contains(
+            "bunny_s3_proxy_request_compression_ratio_bucket{operation=\"PutObject\",le=\"3\"} 1"
+        ));
+    }
+}
@@ -0,0 +1,403 @@
+use axum::{
+    Json, Router,
+    extract::{Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+
+use crate::bunny::listing_index::ListingIndex;
+use crate::presign::{self, PresignRequest};
+
+use super::activity::RequestSnapshot;
+use super::auth::constant_time_compare;
+use super::handlers::AppState;
+use super::multipart::MultipartManager;
+
+/// How many multipart uploads are inspected concurrently for a status report.
+const UPLOAD_FETCH_CONCURRENCY: usize = 8;
+
+#[derive(Serialize)]
+struct AdminStatus {
+    in_flight: Vec<RequestSnapshot>,
+    locks: Vec<LockStatus>,
+    multipart_uploads: Vec<UploadStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    multipart_uploads_error: Option<String>,
+    upstream: UpstreamStatus,
+}
+
+#[derive(Serialize)]
+struct LockStatus {
+    key: String,
+    held_ms: u128,
+}
+
+#[derive(Serialize)]
+struct UploadStatus {
+    upload_id: String,
+    key: String,
+    initiated: DateTime<Utc>,
+    age_secs: i64,
+    parts: Option<usize>,
+    staged_bytes: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct UpstreamStatus {
+    retries: u64,
+    retries_exhausted: u64,
+    assembly_retries: u64,
+}
+
+/// Routes served on the `--admin-addr` listener.
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/status", get(handle_status))
+        .route("/metrics", get(handle_metrics))
+        .route("/usage", get(handle_usage))
+        .route("/presign", post(handle_presign))
+        .route("/trash", get(handle_trash))
+        .route("/trash/restore", post(handle_trash_restore))
+        .route("/listing-index", get(handle_listing_index))
+        .route("/listing-index/rebuild", post(handle_listing_index_rebuild))
+        .with_state(state)
+}
+
+fn is_authorized(headers: &HeaderMap, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return false;
+    };
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|provided| constant_time_compare(provided.trim(), token))
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))],
+    )
+        .into_response()
+}
+
+async fn handle_metrics(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !is_authorized(&headers, state.config.admin_token.as_deref()) {
+        return unauthorized();
+    }
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        )],
+        state.activity.render_metrics()
+            + &state.events.render_metrics()
+            + &state.audit.render_metrics()
+            + &state.integrity.render_metrics()
+            + &state.dedupe.render_metrics()
+            + &state.panics.render_metrics()
+            + &state.buffers.render_metrics()
+            + &state.bunny.stats().render_metrics()
+            + &state.completions.render_metrics()
+            + &state
+                .quotas
+                .as_ref()
+                .map(|q| q.render_metrics())
+                .unwrap_or_default()
+            + &state
+                .ip_filter
+                .as_ref()
+                .map(|f| f.render_metrics())
+                .unwrap_or_default()
+            + &state
+                .inventory
+                .as_ref()
+                .map(|i| i.render_metrics())
+                .unwrap_or_default()
+            + &state
+                .replication
+                .as_ref()
+                .map(|r| r.render_metrics())
+                .unwrap_or_default()
+            + &ListingIndex::render_metrics(&state.listing_indexes),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    #[serde(default)]
+    prefix: String,
+}
+
+async fn handle_usage(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<UsageQuery>,
+) -> Response {
+    if !is_authorized(&headers, state.config.admin_token.as_deref()) {
+        return unauthorized();
+    }
+
+    let prefix = query.prefix.trim_start_matches('/');
+    match state.usage.report(&state.bunny, prefix).await {
+        Ok(report) => Json(&*report).into_response(),
+        Err(e) => {
+            tracing::warn!("Admin usage of '{}' failed: {}", prefix, e);
+            (
+                StatusCode::BAD_GATEWAY,
+                Json(serde_json::json!({ "error": e.to_string() })),
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn handle_presign(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<PresignRequest>,
+) -> Response {
+    if !is_authorized(&headers, state.config.admin_token.as_deref()) {
+        return unauthorized();
+    }
+
+    match presign::presign(&state.config, &state.auth, &request, Utc::now()) {
+        Ok(presigned) => {
+            tracing::info!(
+                "Admin presigned {} of {}/{} until {}",
+                presigned.method,
+                request.bucket,
+                request.key,
+                presigned.expires_at.to_rfc3339()
+            );
+            Json(presigned).into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct TrashQuery {
+    /// Defaults to the storage zone.
+    bucket: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RestoreRequest {
+    bucket: Option<String>,
+    key: String,
+    /// Which deletion to restore when the key was deleted more than once;
+    /// the latest by default.
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+async fn handle_trash(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<TrashQuery>,
+) -> Response {
+    if !is_authorized(&headers, state.config.admin_token.as_deref()) {
+        return unauthorized();
+    }
+    let Some(trash) = state.trash.clone() else {
+        return trash_disabled();
+    };
+
+    let bucket = query.bucket.unwrap_or(state.config.storage_zone.clone());
+    let result = match state.bucket_client(&bucket) {
+        Ok(client) => trash.list(&client).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(entries) => Json(entries).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn handle_trash_restore(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<RestoreRequest>,
+) -> Response {
+    if !is_authorized(&headers, state.config.admin_token.as_deref()) {
+        return unauthorized();
+    }
+    let Some(trash) = state.trash.clone() else {
+        return trash_disabled();
+    };
+
+    let bucket = request.bucket.unwrap_or(state.config.storage_zone.clone());
+    let result = match state.bucket_client(&bucket) {
+        Ok(client) => {
+            trash
+                .restore(&client, &request.key, request.deleted_at)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(entry) => {
+            tracing::info!(
+                "Admin restored {}/{} deleted at {}",
+                bucket,
+                entry.key,
+                entry.deleted_at.to_rfc3339()
+            );
+            Json(entry).into_response()
+        }
+        Err(e) => error_response(e),
+    }
+}
+
+async fn handle_listing_index(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !is_authorized(&headers, state.config.admin_token.as_deref()) {
+        return unauthorized();
+    }
+    let statuses: Vec<_> = state.listing_indexes.iter().map(|i| i.status()).collect();
+    Json(statuses).into_response()
+}
+
+#[derive(Deserialize)]
+struct RebuildQuery {
+    bucket: Option<String>,
+}
+
+/// Starts walking the zone of `bucket`, or of every indexed bucket, now
+/// rather than at the next `--listing-index-interval`.
+async fn handle_listing_index_rebuild(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<RebuildQuery>,
+) -> Response {
+    if !is_authorized(&headers, state.config.admin_token.as_deref()) {
+        return unauthorized();
+    }
+    let indexes: Vec<_> = state
+        .listing_indexes
+        .iter()
+        .filter(|i| query.bucket.as_deref().is_none_or(|b| b == i.bucket()))
+        .collect();
+    if indexes.is_empty() {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": "no listing index for that bucket (--listing-index)" })),
+        )
+            .into_response();
+    }
+    for index in indexes {
+        tracing::info!(
+            "Admin requested a listing index rebuild of {}",
+            index.bucket()
+        );
+        index.request_rebuild();
+    }
+    StatusCode::ACCEPTED.into_response()
+}
+
+fn trash_disabled() -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({ "error": "the trash is not enabled (--trash-prefix)" })),
+    )
+        .into_response()
+}
+
+fn error_response(e: crate::error::ProxyError) -> Response {
+    (
+        e.status_code(),
+        Json(serde_json::json!({ "error": e.to_string() })),
+    )
+        .into_response()
+}
+
+async fn handle_status(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !is_authorized(&headers, state.config.admin_token.as_deref()) {
+        return unauthorized();
+    }
+
+    let mut locks: Vec<LockStatus> = state
+        .lock
+        .held()
+        .into_iter()
+        .map(|(key, held)| LockStatus {
+            key,
+            held_ms: held.as_millis(),
+        })
+        .collect();
+    locks.sort_by_key(|l| std::cmp::Reverse(l.held_ms));
+
+    let (multipart_uploads, multipart_uploads_error) = match multipart_status(&state).await {
+        Ok(uploads) => (uploads, None),
+        Err(e) => {
+            tracing::warn!("Admin status could not list multipart uploads: {}", e);
+            (Vec::new(), Some(e.to_string()))
+        }
+    };
+
+    let stats = state.bunny.stats();
+    Json(AdminStatus {
+        in_flight: state.activity.snapshot(),
+        locks,
+        multipart_uploads,
+        multipart_uploads_error,
+        upstream: UpstreamStatus {
+            retries: stats.retries.load(Ordering::Relaxed),
+            retries_exhausted: stats.retries_exhausted.load(Ordering::Relaxed),
+            assembly_retries: stats.assembly_retries.load(Ordering::Relaxed),
+        },
+    })
+    .into_response()
+}
+
+async fn multipart_status(state: &AppState) -> crate::error::Result<Vec<UploadStatus>> {
+    let uploads = MultipartManager::list_uploads(&state.bunny, &state.config.storage_zone).await?;
+    let now = Utc::now();
+
+    let mut statuses: Vec<UploadStatus> = futures::stream::iter(uploads)
+        .map(|(key, upload_id, initiated)| {
+            let bunny = state.bunny.clone();
+            async move {
+                let staged = MultipartManager::staged(&bunny, &upload_id).await.ok();
+                UploadStatus {
+                    age_secs: (now - initiated).num_seconds(),
+                    parts: staged.map(|(parts, _)| parts),
+                    staged_bytes: staged.map(|(_, bytes)| bytes),
+                    upload_id,
+                    key,
+                    initiated,
+                }
+            }
+        })
+        .buffer_unordered(UPLOAD_FETCH_CONCURRENCY)
+        .collect()
+        .await;
+    statuses.sort_by_key(|u| u.initiated);
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_token_is_required() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_authorized(&headers, Some("secret")));
+        headers.insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert!(!is_authorized(&headers, Some("secret")));
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(is_authorized(&headers, Some("secret")));
+        assert!(!is_authorized(&headers, None));
+    }
+}
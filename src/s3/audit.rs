@@ -0,0 +1,390 @@
+use axum::http::{HeaderMap, header};
+use chrono::{NaiveDate, Utc};
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+
+use crate::config::Config;
+
+/// Records waiting to be written before new ones are dropped.
+const AUDIT_QUEUE_SIZE: usize = 65536;
+
+/// How often buffered records are flushed and fsynced, which bounds how much
+/// of the log a crash can lose.
+const AUDIT_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One mutation, as written to the audit log.
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    pub timestamp: String,
+    pub request_id: String,
+    pub access_key_id: Option<String>,
+    pub client_addr: Option<String>,
+    pub operation: String,
+    pub bucket: Option<String>,
+    pub key: Option<String>,
+    pub size: u64,
+    pub checksum: Option<String>,
+    pub etag: Option<String>,
+    pub status: u16,
+    pub error: Option<String>,
+    /// The request carried the retention override token.
+    pub retention_override: bool,
+}
+
+#[derive(Debug, Default)]
+struct AuditStats {
+    written: AtomicU64,
+    dropped: AtomicU64,
+    write_errors: AtomicU64,
+}
+
+/// Appends [`AuditRecord`]s as JSON lines to `--audit-log-path` from a
+/// write-behind task. Recording never waits on the disk: if the queue is full
+/// the record is dropped, counted and logged.
+pub struct AuditLog {
+    tx: Option<mpsc::Sender<AuditRecord>>,
+    stats: Arc<AuditStats>,
+}
+
+impl AuditLog {
+    /// Starts the writer task if an audit log path is configured.
+    pub fn new(config: &Config) -> Self {
+        let stats = Arc::new(AuditStats::default());
+        let tx = config.audit_log_path.as_ref().map(|path| {
+            let (tx, rx) = mpsc::channel(AUDIT_QUEUE_SIZE);
+            tokio::spawn(write_behind(
+                rx,
+                AuditFile::new(path.clone(), config.audit_log_max_bytes),
+                Arc::clone(&stats),
+            ));
+            tracing::info!("Writing audit log to {}", path.display());
+            tx
+        });
+        Self { tx, stats }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    pub fn record(&self, record: AuditRecord) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if let Err(e) = tx.try_send(record) {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            tracing::error!("Audit record dropped: {}", e);
+        }
+    }
+
+    /// Writer counters in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        for (name, help, counter) in [
+            (
+                "written",
+                "Audit records written to the log file.",
+                &self.stats.written,
+            ),
+            (
+                "dropped",
+                "Audit records discarded because the write queue was full.",
+                &self.stats.dropped,
+            ),
+            (
+                "write_errors",
+                "Failed writes, flushes, fsyncs or rotations of the audit log.",
+                &self.stats.write_errors,
+            ),
+        ] {
+            let name = format!("bunny_s3_proxy_audit_{}_total", name);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+/// The access key a request was signed with, from the `Authorization`
+/// header or a presigned URL's `X-Amz-Credential`, or their SigV2 forms.
+/// Not verified.
+pub fn access_key_id(headers: &HeaderMap, query: &str) -> Option<String> {
+    let credential = match headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(auth) => match auth.strip_prefix("AWS ") {
+            Some(v2) => v2.trim().rsplit_once(':').map(|(key, _)| key.to_string())?,
+            None => auth
+                .split_once("Credential=")
+                .map(|(_, rest)| rest.to_string())?,
+        },
+        None => url::form_urlencoded::parse(query.as_bytes())
+            .find(|(name, _)| name == "X-Amz-Credential" || name == "AWSAccessKeyId")
+            .map(|(_, value)| value.into_owned())?,
+    };
+    credential
+        .split('/')
+        .next()
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+}
+
+/// The checksum the client supplied for the payload, if any.
+pub fn request_checksum(headers: &HeaderMap) -> Option<String> {
+    headers
+        .iter()
+        .find_map(|(name, value)| {
+            let algorithm = name.as_str().strip_prefix("x-amz-checksum-")?;
+            (algorithm != "algorithm" && algorithm != "type").then_some((algorithm, value))
+        })
+        .or_else(|| headers.get("content-md5").map(|value| ("md5", value)))
+        .and_then(|(algorithm, value)| Some(format!("{}:{}", algorithm, value.to_str().ok()?)))
+}
+
+/// The current log file, opened lazily so a missing directory or full disk
+/// is retried on the next record instead of disabling the log.
+struct AuditFile {
+    path: PathBuf,
+    max_bytes: u64,
+    writer: Option<BufWriter<File>>,
+    size: u64,
+    opened_on: NaiveDate,
+    dirty: bool,
+}
+
+impl AuditFile {
+    fn new(path: PathBuf, max_bytes: u64) -> Self {
+        Self {
+            path,
+            max_bytes,
+            writer: None,
+            size: 0,
+            opened_on: Utc::now().date_naive(),
+            dirty: false,
+        }
+    }
+
+    async fn append(&mut self, line: &[u8]) -> std::io::Result<()> {
+        let today = Utc::now().date_naive();
+        if self.writer.is_some()
+            && (today != self.opened_on || self.size + line.len() as u64 > self.max_bytes)
+        {
+            self.rotate().await?;
+        }
+        if self.writer.is_none() {
+            self.open().await?;
+        }
+        let writer = self.writer.as_mut().expect("audit log opened above");
+        writer.write_all(line).await?;
+        self.size += line.len() as u64;
+        self.dirty = true;
+        Ok(())
+    }
+
+    async fn open(&mut self) -> std::io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        self.size = file.metadata().await?.len();
+        self.opened_on = Utc::now().date_naive();
+        self.writer = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> std::io::Result<()> {
+        if let Some(writer) = &mut self.writer
+            && self.dirty
+        {
+            writer.flush().await?;
+            writer.get_ref().sync_data().await?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Moves the current file aside as `<path>.<timestamp>` and starts a new one.
+    async fn rotate(&mut self) -> std::io::Result<()> {
+        self.sync().await?;
+        self.writer = None;
+        let rotated = rotated_path(&self.path, &Utc::now().format("%Y%m%dT%H%M%SZ").to_string());
+        tokio::fs::rename(&self.path, &rotated).await?;
+        tracing::info!("Rotated audit log to {}", rotated.display());
+        self.open().await
+    }
+}
+
+fn rotated_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+async fn write_behind(
+    mut rx: mpsc::Receiver<AuditRecord>,
+    mut file: AuditFile,
+    stats: Arc<AuditStats>,
+) {
+    let mut sync = tokio::time::interval(AUDIT_SYNC_INTERVAL);
+    loop {
+        tokio::select! {
+            record = rx.recv() => {
+                let Some(record) = record else {
+                    break;
+                };
+                let mut line = match serde_json::to_vec(&record) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        tracing::error!("Failed to serialize audit record: {}", e);
+                        continue;
+                    }
+                };
+                line.push(b'\n');
+                match file.append(&line).await {
+                    Ok(()) => {
+                        stats.written.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        stats.write_errors.fetch_add(1, Ordering::Relaxed);
+                        tracing::error!(
+                            "Failed to write audit log {}: {} (record for {} lost)",
+                            file.path.display(),
+                            e,
+                            record.request_id
+                        );
+                    }
+                }
+            }
+            _ = sync.tick() => {
+                if let Err(e) = file.sync().await {
+                    stats.write_errors.fetch_add(1, Ordering::Relaxed);
+                    tracing::error!("Failed to fsync audit log {}: {}", file.path.display(), e);
+                }
+            }
+        }
+    }
+    if let Err(e) = file.sync().await {
+        tracing::error!("Failed to fsync audit log {}: {}", file.path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(request_id: &str) -> AuditRecord {
+        AuditRecord {
+            timestamp: "2026-10-16T00:00:00.000Z".into(),
+            request_id: request_id.into(),
+            access_key_id: Some("bunny".into()),
+            client_addr: Some("127.0.0.1:5000".into()),
+            operation: "PutObject".into(),
+            bucket: Some("zone".into()),
+            key: Some("a.txt".into()),
+            size: 5,
+            checksum: None,
+            etag: Some("\"abc\"".into()),
+            status: 200,
+            error: None,
+            retention_override: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_appends_and_rotates_by_size() {
+        let dir = std::env::temp_dir().join(format!("audit-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("audit.log");
+
+        let (tx, rx) = mpsc::channel(16);
+        let stats = Arc::new(AuditStats::default());
+        let writer = tokio::spawn(write_behind(
+            rx,
+            AuditFile::new(path.clone(), 300),
+            Arc::clone(&stats),
+        ));
+        for id in ["r1", "r2", "r3"] {
+            tx.send(record(id)).await.unwrap();
+        }
+        drop(tx);
+        writer.await.unwrap();
+
+        assert_eq!(stats.written.load(Ordering::Relaxed), 3);
+        let current = tokio::fs::read_to_string(&path).await.unwrap();
+        let last: serde_json::Value =
+            serde_json::from_str(current.lines().last().unwrap()).unwrap();
+        assert_eq!(last["request_id"], "r3");
+        assert_eq!(last["status"], 200);
+
+        let mut entries = tokio::fs::read_dir(&dir).await.unwrap();
+        let mut files = 0;
+        while entries.next_entry().await.unwrap().is_some() {
+            files += 1;
+        }
+        assert!(files >= 2, "expected a rotated file next to the log");
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn test_access_key_id() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(access_key_id(&headers, ""), None);
+        assert_eq!(
+            access_key_id(
+                &headers,
+                "X-Amz-Credential=AKID%2F20261016%2Fus-east-1%2Fs3%2Faws4_request"
+            )
+            .as_deref(),
+            Some("AKID")
+        );
+        headers.insert(
+            header::AUTHORIZATION,
+            "AWS4-HMAC-SHA256 Credential=OTHER/20261016/us-east-1/s3/aws4_request, SignedHeaders=host, Signature=abc"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(access_key_id(&headers, "").as_deref(), Some("OTHER"));
+
+        // SigV2
+        headers.insert(
+            header::AUTHORIZATION,
+            "AWS LEGACY:bWq2s1WEIj+Ydj0vQ697zp+IXMU=".parse().unwrap(),
+        );
+        assert_eq!(access_key_id(&headers, "").as_deref(), Some("LEGACY"));
+        assert_eq!(
+            access_key_id(
+                &HeaderMap::new(),
+                "AWSAccessKeyId=LEGACY&Expires=1&Signature=x"
+            )
+            .as_deref(),
+            Some("LEGACY")
+        );
+    }
+
+    #[test]
+    fn test_request_checksum() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(request_checksum(&headers), None);
+        headers.insert("content-md5", "XrY7u+Ae7tCTyyK7j1rNww==".parse().unwrap());
+        assert_eq!(
+            request_checksum(&headers).as_deref(),
+            Some("md5:XrY7u+Ae7tCTyyK7j1rNww==")
+        );
+        headers.insert("x-amz-checksum-crc32", "AAAAAA==".parse().unwrap());
+        assert_eq!(
+            request_checksum(&headers).as_deref(),
+            Some("crc32:AAAAAA==")
+        );
+    }
+}
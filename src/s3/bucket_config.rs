@@ -0,0 +1,47 @@
+use bytes::Bytes;
+
+use crate::bunny::{Backend, StorageBackend};
+use crate::error::{ProxyError, Result};
+
+/// Prefix under which per-bucket configuration sidecars are stored in the zone.
+pub const CONFIG_PREFIX: &str = "__config";
+
+pub const LIFECYCLE_CONFIG: &str = "lifecycle.xml";
+pub const BUCKET_TAGGING_CONFIG: &str = "tagging.xml";
+pub const BUCKET_POLICY_CONFIG: &str = "policy.json";
+
+/// Stores bucket-level configuration documents (lifecycle, tagging, ...) as
+/// sidecar objects so every proxy instance sees the same state.
+pub struct BucketConfigStore;
+
+impl BucketConfigStore {
+    fn path(bucket: &str, name: &str) -> String {
+        format!("{}/{}/{}", CONFIG_PREFIX, bucket, name)
+    }
+
+    /// Whether a key belongs to the proxy's own bookkeeping rather than the user.
+    pub fn is_internal_key(key: &str) -> bool {
+        key == CONFIG_PREFIX
+            || key
+                .strip_prefix(CONFIG_PREFIX)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    pub async fn get(client: &Backend, bucket: &str, name: &str) -> Result<Option<Bytes>> {
+        match client.download(&Self::path(bucket, name)).await {
+            Ok(download) => Ok(Some(download.bytes().await?)),
+            Err(ProxyError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn put(client: &Backend, bucket: &str, name: &str, body: Bytes) -> Result<()> {
+        client
+            .upload(&Self::path(bucket, name), body, Default::default())
+            .await
+    }
+
+    pub async fn delete(client: &Backend, bucket: &str, name: &str) -> Result<()> {
+        client.delete(&Self::path(bucket, name)).await
+    }
+}
@@ -0,0 +1,216 @@
+//! Admission control for request bodies buffered in memory, with
+//! `--max-buffered-bytes`.
+//!
+//! XML bodies such as DeleteObjects and CompleteMultipartUpload are read
+//! whole before they are handled, up to 10 MiB each, so a burst of them can
+//! exhaust memory whatever the concurrency limits. Every buffered body holds
+//! a [`Reservation`] for its bytes until the request finishes. A body with a
+//! Content-Length reserves it up front, waiting up to `--max-buffered-wait`
+//! for others to release theirs before it is refused with SlowDown; a
+//! chunked body grows its reservation as it arrives and is refused at once
+//! when that would pass the limit. A body larger than the whole limit is
+//! only let in while nothing else is buffered.
+
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::config::Config;
+use crate::error::{ProxyError, Result};
+
+#[derive(Debug, Default)]
+pub struct BufferBudget {
+    /// 0 leaves buffering unlimited; the bytes are counted all the same.
+    limit: u64,
+    wait: Duration,
+    held: AtomicU64,
+    released: Notify,
+    waited: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// Bytes of a buffered body, given back when dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: Arc<BufferBudget>,
+    bytes: u64,
+}
+
+impl BufferBudget {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            limit: config.max_buffered_bytes,
+            wait: config.max_buffered_wait.to_std().unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    /// Takes `bytes` if they fit, or if nothing else is held.
+    fn try_take(&self, bytes: u64) -> bool {
+        self.held
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |held| {
+                let fits = self.limit == 0 || held == 0 || held + bytes <= self.limit;
+                fits.then_some(held + bytes)
+            })
+            .is_ok()
+    }
+
+    fn refuse(&self, bytes: u64) -> ProxyError {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        ProxyError::SlowDown(format!(
+            "{} bytes of request bodies are buffered; {} more would pass --max-buffered-bytes {}",
+            self.held.load(Ordering::Relaxed),
+            bytes,
+            self.limit
+        ))
+    }
+
+    /// Reserves `bytes`, waiting up to `--max-buffered-wait` for room.
+    pub async fn reserve(self: &Arc<Self>, bytes: u64) -> Result<Reservation> {
+        let reservation = || Reservation {
+            budget: Arc::clone(self),
+            bytes,
+        };
+        if self.try_take(bytes) {
+            return Ok(reservation());
+        }
+        self.waited.fetch_add(1, Ordering::Relaxed);
+        let deadline = tokio::time::Instant::now() + self.wait;
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            // Registered before trying, so a release in between still wakes us.
+            released.as_mut().enable();
+            if self.try_take(bytes) {
+                return Ok(reservation());
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return Err(self.refuse(bytes));
+            }
+        }
+    }
+
+    /// Bytes currently held by buffered bodies.
+    pub fn held(&self) -> u64 {
+        self.held.load(Ordering::Relaxed)
+    }
+
+    /// Buffering gauges and counters in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        for (name, help, kind, value) in [
+            (
+                "bunny_s3_proxy_buffered_bytes",
+                "Bytes of request bodies currently buffered in memory.",
+                "gauge",
+                self.held(),
+            ),
+            (
+                "bunny_s3_proxy_buffered_bytes_limit",
+                "The --max-buffered-bytes limit; 0 when unlimited.",
+                "gauge",
+                self.limit,
+            ),
+            (
+                "bunny_s3_proxy_buffered_waits_total",
+                "Buffered request bodies that waited for --max-buffered-bytes.",
+                "counter",
+                self.waited.load(Ordering::Relaxed),
+            ),
+            (
+                "bunny_s3_proxy_buffered_rejected_total",
+                "Buffered request bodies refused with SlowDown by --max-buffered-bytes.",
+                "counter",
+                self.rejected.load(Ordering::Relaxed),
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+impl Reservation {
+    /// Extends the reservation to `bytes` without waiting, for bodies whose
+    /// length is only known as they arrive.
+    pub fn grow_to(&mut self, bytes: u64) -> Result<()> {
+        if bytes <= self.bytes {
+            return Ok(());
+        }
+        let more = bytes - self.bytes;
+        if !self.budget.try_take(more) {
+            return Err(self.budget.refuse(more));
+        }
+        self.bytes = bytes;
+        Ok(())
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.held.fetch_sub(self.bytes, Ordering::AcqRel);
+        self.budget.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(limit: u64, wait_ms: u64) -> Arc<BufferBudget> {
+        Arc::new(BufferBudget {
+            limit,
+            wait: Duration::from_millis(wait_ms),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_reservations_are_released_on_drop() {
+        let budget = budget(100, 0);
+        let a = budget.reserve(60).await.unwrap();
+        let mut b = budget.reserve(40).await.unwrap();
+        assert_eq!(budget.held(), 100);
+        assert!(matches!(
+            budget.reserve(1).await.unwrap_err(),
+            ProxyError::SlowDown(_)
+        ));
+        assert!(b.grow_to(41).is_err());
+        assert_eq!(budget.held(), 100);
+        drop(a);
+        b.grow_to(90).unwrap();
+        assert_eq!(budget.held(), 90);
+        drop(b);
+        assert_eq!(budget.held(), 0);
+
+        // Alone, a body may be larger than the limit.
+        let big = budget.reserve(500).await.unwrap();
+        assert_eq!(budget.held(), 500);
+        drop(big);
+        assert!(
+            budget
+                .render_metrics()
+                .contains("bunny_s3_proxy_buffered_rejected_total 2\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_waiters_are_admitted_on_release() {
+        let budget = budget(100, 5_000);
+        let held = budget.reserve(80).await.unwrap();
+        let waiter = tokio::spawn({
+            let budget = Arc::clone(&budget);
+            async move { budget.reserve(50).await.map(|r| r.bytes) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+        drop(held);
+        assert_eq!(waiter.await.unwrap().unwrap(), 50);
+        assert_eq!(budget.held(), 0);
+        assert_eq!(budget.waited.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,342 @@
+//! Per-prefix Cache-Control from `--cache-policy-config`, so a CDN in front
+//! of the proxy can cache objects instead of revalidating every GET:
+//!
+//! ```toml
+//! precedence = "object"
+//!
+//! [[cache-policy]]
+//! prefix = "static/"
+//! cache-control = "public, max-age=86400, immutable"
+//! expires = "1d"
+//!
+//! [[cache-policy]]
+//! bucket = "assets"
+//! prefix = "drafts/"
+//! cache-control = "no-cache"
+//! ```
+//!
+//! The policy with the longest prefix matching a key applies, one naming the
+//! key's bucket beating one without at equal length. Only successful
+//! GetObject and HeadObject responses (200, 206 and 304) get the headers;
+//! errors, listings and redirects never do. A response that already carries
+//! a Cache-Control, such as one stored with the object, keeps it under
+//! `precedence = "object"` and has it replaced under `"policy"`. SIGHUP
+//! reloads the file; a file that no longer parses is logged and the previous
+//! policies stay in force.
+
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::response::Response;
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct CachePolicyFile {
+    #[serde(default)]
+    precedence: Precedence,
+    #[serde(default)]
+    cache_policy: Vec<CachePolicyEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct CachePolicyEntry {
+    prefix: String,
+    #[serde(default)]
+    bucket: Option<String>,
+    cache_control: String,
+    #[serde(default)]
+    expires: Option<String>,
+}
+
+/// Which Cache-Control wins when the response already has one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precedence {
+    /// The object's own Cache-Control is kept.
+    #[default]
+    Object,
+    /// The policy replaces it.
+    Policy,
+}
+
+#[derive(Debug)]
+struct CachePolicy {
+    prefix: String,
+    bucket: Option<String>,
+    cache_control: HeaderValue,
+    expires: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct PolicySet {
+    policies: Vec<CachePolicy>,
+    precedence: Precedence,
+}
+
+impl PolicySet {
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let file: CachePolicyFile = toml::from_str(text)?;
+        let mut policies: Vec<CachePolicy> = Vec::with_capacity(file.cache_policy.len());
+        for entry in file.cache_policy {
+            if policies
+                .iter()
+                .any(|p| p.prefix == entry.prefix && p.bucket == entry.bucket)
+            {
+                anyhow::bail!("prefix '{}' has two policies", entry.prefix);
+            }
+            let cache_control = HeaderValue::from_str(&entry.cache_control).map_err(|_| {
+                anyhow::anyhow!(
+                    "prefix '{}': invalid cache-control '{}'",
+                    entry.prefix,
+                    entry.cache_control
+                )
+            })?;
+            let expires = entry
+                .expires
+                .as_deref()
+                .map(crate::cleanup::parse_age)
+                .transpose()
+                .map_err(|e| anyhow::anyhow!("prefix '{}': expires: {}", entry.prefix, e))?;
+            policies.push(CachePolicy {
+                prefix: entry.prefix,
+                bucket: entry.bucket,
+                cache_control,
+                expires,
+            });
+        }
+        Ok(Self {
+            policies,
+            precedence: file.precedence,
+        })
+    }
+
+    /// The policy for `key` in `bucket`: the longest matching prefix,
+    /// preferring one naming the bucket at equal length.
+    fn policy_for(&self, bucket: &str, key: &str) -> Option<&CachePolicy> {
+        self.policies
+            .iter()
+            .filter(|p| p.bucket.as_deref().is_none_or(|b| b == bucket))
+            .filter(|p| key.starts_with(&p.prefix))
+            .max_by_key(|p| (p.prefix.len(), p.bucket.is_some()))
+    }
+}
+
+#[derive(Debug)]
+pub struct CachePolicies {
+    path: PathBuf,
+    current: RwLock<Arc<PolicySet>>,
+}
+
+impl CachePolicies {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let policies = Self::read(path)?;
+        tracing::info!(
+            "Cache policies: {} from {}, {:?} Cache-Control wins",
+            policies.policies.len(),
+            path.display(),
+            policies.precedence
+        );
+        Ok(Self {
+            path: path.to_path_buf(),
+            current: RwLock::new(Arc::new(policies)),
+        })
+    }
+
+    fn read(path: &Path) -> anyhow::Result<PolicySet> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("--cache-policy-config {}: {}", path.display(), e))?;
+        PolicySet::parse(&text)
+            .map_err(|e| anyhow::anyhow!("--cache-policy-config {}: {}", path.display(), e))
+    }
+
+    /// Re-reads the file, keeping the current policies if it fails to parse.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let policies = Self::read(&self.path)?;
+        let count = policies.policies.len();
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(policies);
+        tracing::info!(
+            "Cache policies reloaded: {} from {}",
+            count,
+            self.path.display()
+        );
+        Ok(())
+    }
+
+    /// Sets the Cache-Control and Expires of the policy for `key` on a
+    /// GetObject or HeadObject `response`.
+    pub fn apply(&self, bucket: &str, key: &str, response: &mut Response) {
+        if !matches!(
+            response.status(),
+            StatusCode::OK | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED
+        ) {
+            return;
+        }
+        let policies = Arc::clone(&self.current.read().unwrap_or_else(|e| e.into_inner()));
+        let Some(policy) = policies.policy_for(bucket, key) else {
+            return;
+        };
+        if policies.precedence == Precedence::Object
+            && response.headers().contains_key(header::CACHE_CONTROL)
+        {
+            return;
+        }
+        let headers = response.headers_mut();
+        headers.insert(header::CACHE_CONTROL, policy.cache_control.clone());
+        match policy.expires {
+            Some(expires) => {
+                let at = (Utc::now() + expires).format("%a, %d %b %Y %H:%M:%S GMT");
+                if let Ok(value) = HeaderValue::from_str(&at.to_string()) {
+                    headers.insert(header::EXPIRES, value);
+                }
+            }
+            None => {
+                headers.remove(header::EXPIRES);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+
+    const POLICIES: &str = r#"
+        [[cache-policy]]
+        prefix = "static/"
+        cache-control = "public, max-age=86400, immutable"
+        expires = "1d"
+
+        [[cache-policy]]
+        prefix = "static/live/"
+        cache-control = "no-cache"
+
+        [[cache-policy]]
+        bucket = "assets"
+        prefix = "static/"
+        cache-control = "public, max-age=60"
+    "#;
+
+    fn policies(text: &str) -> CachePolicies {
+        CachePolicies {
+            path: PathBuf::new(),
+            current: RwLock::new(Arc::new(PolicySet::parse(text).unwrap())),
+        }
+    }
+
+    fn applied(
+        policies: &CachePolicies,
+        bucket: &str,
+        key: &str,
+        status: StatusCode,
+        stored: Option<&'static str>,
+    ) -> Response {
+        let mut response = status.into_response();
+        if let Some(stored) = stored {
+            response
+                .headers_mut()
+                .insert(header::CACHE_CONTROL, HeaderValue::from_static(stored));
+        }
+        policies.apply(bucket, key, &mut response);
+        response
+    }
+
+    #[test]
+    fn test_longest_prefix_applies() {
+        let policies = policies(POLICIES);
+        let cache_control = |bucket: &str, key: &str| {
+            applied(&policies, bucket, key, StatusCode::OK, None)
+                .headers()
+                .get(header::CACHE_CONTROL)
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+        assert_eq!(
+            cache_control("zone", "static/app.js").as_deref(),
+            Some("public, max-age=86400, immutable")
+        );
+        assert_eq!(
+            cache_control("zone", "static/live/feed.json").as_deref(),
+            Some("no-cache")
+        );
+        assert_eq!(
+            cache_control("assets", "static/app.js").as_deref(),
+            Some("public, max-age=60")
+        );
+        assert_eq!(cache_control("zone", "uploads/static/app.js"), None);
+        assert_eq!(cache_control("zone", "static"), None);
+
+        let response = applied(&policies, "zone", "static/app.js", StatusCode::OK, None);
+        assert!(
+            response.headers()[header::EXPIRES]
+                .to_str()
+                .unwrap()
+                .ends_with(" GMT")
+        );
+        let response = applied(&policies, "zone", "static/live/a", StatusCode::OK, None);
+        assert!(!response.headers().contains_key(header::EXPIRES));
+    }
+
+    #[test]
+    fn test_errors_are_never_cacheable() {
+        let policies = policies(POLICIES);
+        for status in [
+            StatusCode::NOT_FOUND,
+            StatusCode::FORBIDDEN,
+            StatusCode::PRECONDITION_FAILED,
+            StatusCode::TEMPORARY_REDIRECT,
+            StatusCode::SERVICE_UNAVAILABLE,
+        ] {
+            let response = applied(&policies, "zone", "static/app.js", status, None);
+            assert!(!response.headers().contains_key(header::CACHE_CONTROL));
+        }
+        for status in [StatusCode::PARTIAL_CONTENT, StatusCode::NOT_MODIFIED] {
+            let response = applied(&policies, "zone", "static/app.js", status, None);
+            assert!(response.headers().contains_key(header::CACHE_CONTROL));
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        let stored = Some("private, max-age=5");
+        let object_wins = policies(POLICIES);
+        let response = applied(&object_wins, "zone", "static/a.js", StatusCode::OK, stored);
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "private, max-age=5"
+        );
+        assert!(!response.headers().contains_key(header::EXPIRES));
+
+        let policy_wins = policies(&format!("precedence = \"policy\"\n{}", POLICIES));
+        let response = applied(&policy_wins, "zone", "static/a.js", StatusCode::OK, stored);
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "public, max-age=86400, immutable"
+        );
+    }
+
+    #[test]
+    fn test_invalid_policies_are_rejected() {
+        for (text, expected) in [
+            (
+                "[[cache-policy]]\nprefix = \"a/\"\ncache-control = \"x\"\n\
+                 [[cache-policy]]\nprefix = \"a/\"\ncache-control = \"y\"\n",
+                "two policies",
+            ),
+            (
+                "[[cache-policy]]\nprefix = \"a/\"\ncache-control = \"bad\\nvalue\"\n",
+                "invalid cache-control",
+            ),
+            (
+                "[[cache-policy]]\nprefix = \"a/\"\ncache-control = \"x\"\nexpires = \"soon\"\n",
+                "expires",
+            ),
+            ("precedence = \"cdn\"\n", "unknown variant"),
+        ] {
+            let err = PolicySet::parse(text).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: {}", text, err);
+        }
+    }
+}
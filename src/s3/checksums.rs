@@ -0,0 +1,307 @@
+//! The flexible checksums S3 clients send with a PUT or UploadPart as
+//! `x-amz-checksum-crc32`, `-crc32c` or `-sha1`: the base64 of the
+//! big-endian digest of the body. Bunny knows none of them, so the proxy
+//! computes the one a request names as its body passes through, refuses
+//! the write with BadDigest if it differs, and records it in the sidecar
+//! for HEAD and GetObjectAttributes. `x-amz-checksum-sha256` is Bunny's to
+//! check.
+
+use axum::http::HeaderMap;
+use base64::Engine;
+use bytes::Bytes;
+use futures::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::Digest;
+use std::sync::{Arc, Mutex};
+
+use crate::error::{ProxyError, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ChecksumAlgorithm {
+    Crc32,
+    Crc32c,
+    Sha1,
+}
+
+const ALGORITHMS: &[ChecksumAlgorithm] = &[
+    ChecksumAlgorithm::Crc32,
+    ChecksumAlgorithm::Crc32c,
+    ChecksumAlgorithm::Sha1,
+];
+
+impl ChecksumAlgorithm {
+    /// The header carrying a checksum of this algorithm.
+    pub fn header(self) -> &'static str {
+        match self {
+            Self::Crc32 => "x-amz-checksum-crc32",
+            Self::Crc32c => "x-amz-checksum-crc32c",
+            Self::Sha1 => "x-amz-checksum-sha1",
+        }
+    }
+
+    /// The algorithm whose checksum `header` carries, if the proxy
+    /// computes it.
+    pub fn of_header(header: &str) -> Option<Self> {
+        ALGORITHMS
+            .iter()
+            .copied()
+            .find(|algorithm| algorithm.header().eq_ignore_ascii_case(header))
+    }
+
+    /// The element of a `<Checksum>` holding a checksum of this algorithm.
+    pub fn xml_element(self) -> &'static str {
+        match self {
+            Self::Crc32 => "ChecksumCRC32",
+            Self::Crc32c => "ChecksumCRC32C",
+            Self::Sha1 => "ChecksumSHA1",
+        }
+    }
+
+    fn digest_len(self) -> usize {
+        match self {
+            Self::Crc32 | Self::Crc32c => 4,
+            Self::Sha1 => 20,
+        }
+    }
+}
+
+/// A checksum as S3 reports it: its algorithm and base64 value.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub value: String,
+}
+
+impl Checksum {
+    /// Refuses a body whose `computed` checksum is not the one claimed.
+    pub fn verify(&self, computed: &Checksum) -> Result<()> {
+        match self == computed {
+            true => Ok(()),
+            false => Err(ProxyError::BadDigest),
+        }
+    }
+}
+
+/// The checksum a write request claims for its body, if it sends one of
+/// the algorithms the proxy computes. Malformed values, and more than one
+/// checksum, are refused as S3 refuses them.
+pub fn requested(headers: &HeaderMap) -> Result<Option<Checksum>> {
+    let mut claimed = None;
+    for &algorithm in ALGORITHMS {
+        let Some(value) = headers.get(algorithm.header()) else {
+            continue;
+        };
+        if claimed.is_some() {
+            return Err(ProxyError::InvalidRequest(
+                "Expecting a single x-amz-checksum- header. Multiple checksum Types are not allowed."
+                    .to_string(),
+            ));
+        }
+        let invalid = || {
+            ProxyError::InvalidRequest(format!(
+                "Value for {} header is invalid.",
+                algorithm.header()
+            ))
+        };
+        let value = value.to_str().map_err(|_| invalid())?;
+        let digest = base64::engine::general_purpose::STANDARD
+            .decode(value)
+            .map_err(|_| invalid())?;
+        if digest.len() != algorithm.digest_len() {
+            return Err(invalid());
+        }
+        claimed = Some(Checksum {
+            algorithm,
+            value: value.to_string(),
+        });
+    }
+    Ok(claimed)
+}
+
+/// Computes a checksum over data fed to it in pieces.
+#[derive(Clone)]
+pub enum Checksummer {
+    Crc32(crc32fast::Hasher),
+    Crc32c(u32),
+    Sha1(sha1::Sha1),
+}
+
+impl Checksummer {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Crc32 => Self::Crc32(crc32fast::Hasher::new()),
+            ChecksumAlgorithm::Crc32c => Self::Crc32c(0),
+            ChecksumAlgorithm::Sha1 => Self::Sha1(sha1::Sha1::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Crc32(hasher) => hasher.update(data),
+            Self::Crc32c(crc) => *crc = crc32c(*crc, data),
+            Self::Sha1(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finish(self) -> Checksum {
+        let (algorithm, digest) = match self {
+            Self::Crc32(hasher) => (
+                ChecksumAlgorithm::Crc32,
+                hasher.finalize().to_be_bytes().to_vec(),
+            ),
+            Self::Crc32c(crc) => (ChecksumAlgorithm::Crc32c, crc.to_be_bytes().to_vec()),
+            Self::Sha1(hasher) => (ChecksumAlgorithm::Sha1, hasher.finalize().to_vec()),
+        };
+        Checksum {
+            algorithm,
+            value: base64::engine::general_purpose::STANDARD.encode(digest),
+        }
+    }
+}
+
+/// The `algorithm` checksum of `data`.
+pub fn compute(algorithm: ChecksumAlgorithm, data: &[u8]) -> Checksum {
+    let mut checksummer = Checksummer::new(algorithm);
+    checksummer.update(data);
+    checksummer.finish()
+}
+
+/// The checksum of what a [`computing`] stream let through so far.
+#[derive(Clone)]
+pub struct Computed(Arc<Mutex<Checksummer>>);
+
+impl Computed {
+    pub fn checksum(&self) -> Checksum {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+            .finish()
+    }
+}
+
+/// `stream`, computing the `algorithm` checksum of its chunks as they pass.
+pub fn computing<S, E>(
+    stream: S,
+    algorithm: ChecksumAlgorithm,
+) -> (
+    impl Stream<Item = std::result::Result<Bytes, E>> + use<S, E>,
+    Computed,
+)
+where
+    S: Stream<Item = std::result::Result<Bytes, E>>,
+{
+    let computed = Computed(Arc::new(Mutex::new(Checksummer::new(algorithm))));
+    let checksummer = computed.clone();
+    let stream = stream.inspect_ok(move |chunk| {
+        checksummer
+            .0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .update(chunk)
+    });
+    (stream, computed)
+}
+
+/// CRC-32C (Castagnoli), reflected, as S3 computes it.
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0x82F6_3B78,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Extends the CRC-32C `crc` of some data with `data`.
+fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[test]
+    fn test_known_checksums() {
+        // The CRCs' standard check values, over "123456789".
+        let data = b"123456789";
+        assert_eq!(
+            compute(ChecksumAlgorithm::Crc32, data).value,
+            base64::engine::general_purpose::STANDARD.encode(0xCBF4_3926u32.to_be_bytes())
+        );
+        assert_eq!(
+            compute(ChecksumAlgorithm::Crc32c, data).value,
+            base64::engine::general_purpose::STANDARD.encode(0xE306_9283u32.to_be_bytes())
+        );
+        assert_eq!(
+            compute(ChecksumAlgorithm::Sha1, b"hello").value,
+            "qvTGHdzF6KLavt4PO0gs2a6pQ00="
+        );
+        assert_eq!(compute(ChecksumAlgorithm::Crc32, b"").value, "AAAAAA==");
+    }
+
+    #[tokio::test]
+    async fn test_computing_matches_whole_body() {
+        let chunks = ["hello", " ", "world"].map(|c| Ok::<_, std::io::Error>(Bytes::from(c)));
+        let (stream, computed) =
+            computing(futures::stream::iter(chunks), ChecksumAlgorithm::Crc32c);
+        let body: Vec<Bytes> = stream.map(|c| c.unwrap()).collect().await;
+        assert_eq!(body.concat(), b"hello world");
+        assert_eq!(
+            computed.checksum(),
+            compute(ChecksumAlgorithm::Crc32c, b"hello world")
+        );
+    }
+
+    #[test]
+    fn test_requested_checksums() {
+        let request = |headers: &[(&'static str, &str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in headers {
+                map.insert(*name, value.parse().unwrap());
+            }
+            requested(&map)
+        };
+        assert_eq!(request(&[]).unwrap(), None);
+        assert_eq!(
+            request(&[("x-amz-checksum-crc32c", "AAAAAA==")]).unwrap(),
+            Some(Checksum {
+                algorithm: ChecksumAlgorithm::Crc32c,
+                value: "AAAAAA==".to_string()
+            })
+        );
+        // SHA-256 is left to Bunny.
+        assert_eq!(
+            request(&[("x-amz-checksum-sha256", "AAAAAA==")]).unwrap(),
+            None
+        );
+        for headers in [
+            &[("x-amz-checksum-crc32", "not base64")][..],
+            &[("x-amz-checksum-sha1", "AAAAAA==")],
+            &[
+                ("x-amz-checksum-crc32", "AAAAAA=="),
+                ("x-amz-checksum-sha1", "qvTGHdzF6KLavt4PO0gs2a6pQ00="),
+            ],
+        ] {
+            let err = request(headers).unwrap_err();
+            assert_eq!(err.s3_error_code(), "InvalidRequest");
+        }
+    }
+}
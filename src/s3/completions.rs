@@ -0,0 +1,271 @@
+//! Admission control for CompleteMultipartUpload with
+//! `--max-concurrent-completions`.
+//!
+//! Each completion re-downloads and re-uploads every byte of the object, so a
+//! batch of them can saturate the uplink. Completions beyond the limit wait
+//! in FIFO order while the response keepalive holds the client connection;
+//! once `--max-queued-completions` are waiting, further ones are refused with
+//! SlowDown so clients back off.
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::config::Config;
+use crate::error::{ProxyError, Result};
+
+/// A completion waiting for a slot.
+struct Waiter {
+    serial: u64,
+    upload_id: String,
+    aborted: Arc<Notify>,
+}
+
+#[derive(Debug, Default)]
+struct CompletionStats {
+    rejected: AtomicU64,
+    waited: AtomicU64,
+    wait_micros: AtomicU64,
+}
+
+pub struct CompletionLimiter {
+    slots: Arc<Semaphore>,
+    max_concurrent: usize,
+    max_queued: usize,
+    queue: Mutex<Vec<Waiter>>,
+    next_serial: AtomicU64,
+    stats: CompletionStats,
+}
+
+/// A completion admitted to run, or to the queue. Dropping it, as happens
+/// when the client disconnects while waiting, leaves the queue.
+pub struct Admission {
+    limiter: Arc<CompletionLimiter>,
+    permit: Option<OwnedSemaphorePermit>,
+    queued: Option<(u64, String, Arc<Notify>)>,
+}
+
+impl CompletionLimiter {
+    pub fn new(config: &Config) -> Self {
+        let max_concurrent = config.max_concurrent_completions.max(1);
+        Self {
+            slots: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            max_queued: config.max_queued_completions,
+            queue: Mutex::default(),
+            next_serial: AtomicU64::new(0),
+            stats: CompletionStats::default(),
+        }
+    }
+
+    /// Takes a free slot for completing `upload_id` or joins the queue for
+    /// one; refuses with SlowDown when the queue is full.
+    pub fn admit(self: &Arc<Self>, upload_id: &str) -> Result<Admission> {
+        if let Ok(permit) = Arc::clone(&self.slots).try_acquire_owned() {
+            return Ok(Admission {
+                limiter: Arc::clone(self),
+                permit: Some(permit),
+                queued: None,
+            });
+        }
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.max_queued {
+            self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(ProxyError::SlowDown(format!(
+                "{} multipart completions are already waiting",
+                queue.len()
+            )));
+        }
+        let serial = self.next_serial.fetch_add(1, Ordering::Relaxed);
+        let aborted = Arc::new(Notify::new());
+        queue.push(Waiter {
+            serial,
+            upload_id: upload_id.to_string(),
+            aborted: Arc::clone(&aborted),
+        });
+        Ok(Admission {
+            limiter: Arc::clone(self),
+            permit: None,
+            queued: Some((serial, upload_id.to_string(), aborted)),
+        })
+    }
+
+    /// Wakes queued completions of `upload_id` after it was aborted, so they
+    /// fail at once instead of holding their place in the queue.
+    pub fn abort(&self, upload_id: &str) {
+        for waiter in self.queue.lock().unwrap().iter() {
+            if waiter.upload_id == upload_id {
+                waiter.aborted.notify_one();
+            }
+        }
+    }
+
+    fn leave(&self, serial: u64) {
+        self.queue.lock().unwrap().retain(|w| w.serial != serial);
+    }
+
+    fn queued(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// Completion gauges and counters in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let running = self.max_concurrent - self.slots.available_permits();
+        for (name, help, value) in [
+            (
+                "bunny_s3_proxy_multipart_completions_running",
+                "Multipart completions currently assembling their object.",
+                running,
+            ),
+            (
+                "bunny_s3_proxy_multipart_completions_queued",
+                "Multipart completions waiting for --max-concurrent-completions.",
+                self.queued(),
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        let name = "bunny_s3_proxy_multipart_completions_rejected_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Multipart completions refused with SlowDown because the queue was full.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(
+            out,
+            "{} {}",
+            name,
+            self.stats.rejected.load(Ordering::Relaxed)
+        );
+        let name = "bunny_s3_proxy_multipart_completion_wait_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {} Time queued multipart completions waited for a slot.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} summary", name);
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            self.stats.wait_micros.load(Ordering::Relaxed) as f64 / 1e6
+        );
+        let _ = writeln!(
+            out,
+            "{}_count {}",
+            name,
+            self.stats.waited.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+impl Admission {
+    /// Waits for a slot, held until the returned permit is dropped. Fails
+    /// with NoSuchUpload if the upload is aborted while queued.
+    pub async fn slot(mut self) -> Result<OwnedSemaphorePermit> {
+        if let Some(permit) = self.permit.take() {
+            return Ok(permit);
+        }
+        let (_, upload_id, aborted) = self.queued.as_ref().expect("queued without a permit");
+        let started = Instant::now();
+        let permit = tokio::select! {
+            permit = Arc::clone(&self.limiter.slots).acquire_owned() => {
+                permit.expect("the slot semaphore is never closed")
+            }
+            _ = aborted.notified() => return Err(ProxyError::MultipartNotFound(upload_id.clone())),
+        };
+        let stats = &self.limiter.stats;
+        stats.waited.fetch_add(1, Ordering::Relaxed);
+        stats
+            .wait_micros
+            .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        Ok(permit)
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        if let Some((serial, ..)) = self.queued {
+            self.limiter.leave(serial);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::time::Duration;
+
+    fn limiter(concurrent: &str, queued: &str) -> Arc<CompletionLimiter> {
+        Arc::new(CompletionLimiter::new(&Config::parse_from([
+            "bunny-s3-proxy",
+            "-z",
+            "zone",
+            "-k",
+            "key",
+            "--max-concurrent-completions",
+            concurrent,
+            "--max-queued-completions",
+            queued,
+        ])))
+    }
+
+    #[tokio::test]
+    async fn test_completions_queue_in_order_and_overflow() {
+        let limiter = limiter("1", "2");
+        let running = limiter.admit("a").unwrap().slot().await.unwrap();
+        let second = limiter.admit("b").unwrap();
+        let third = limiter.admit("c").unwrap();
+        let err = limiter.admit("d").err().unwrap();
+        assert_eq!(err.s3_error_code(), "SlowDown");
+        assert_eq!(
+            err.status_code(),
+            axum::http::StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(limiter.queued(), 2);
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for (name, admission) in [("b", second), ("c", third)] {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let _permit = admission.slot().await.unwrap();
+                tx.send(name).unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            });
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(running);
+        assert_eq!(rx.recv().await, Some("b"));
+        assert_eq!(rx.recv().await, Some("c"));
+        assert_eq!(limiter.queued(), 0);
+
+        let metrics = limiter.render_metrics();
+        assert!(metrics.contains("bunny_s3_proxy_multipart_completions_rejected_total 1"));
+        assert!(metrics.contains("bunny_s3_proxy_multipart_completion_wait_seconds_count 2"));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_and_aborted_completions_leave_the_queue() {
+        let limiter = limiter("1", "1");
+        let _running = limiter.admit("a").unwrap().slot().await.unwrap();
+
+        drop(limiter.admit("b").unwrap());
+        assert_eq!(limiter.queued(), 0);
+
+        let waiting = tokio::spawn(limiter.admit("c").unwrap().slot());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        limiter.abort("c");
+        let err = waiting.await.unwrap().err().unwrap();
+        assert_eq!(err.s3_error_code(), "NoSuchUpload");
+        assert_eq!(limiter.queued(), 0);
+        assert!(limiter.admit("d").is_ok());
+    }
+}
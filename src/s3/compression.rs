@@ -0,0 +1,225 @@
+//! Transparent compression of stored objects with `--compress zstd[:level]`.
+//!
+//! Matching PutObject bodies are compressed on the way to Bunny, before any
+//! encryption at rest. The object's metadata sidecar records the algorithm
+//! together with the size and ETag of the original bytes, which GET, HEAD and
+//! listings report instead of the stored ones.
+
+use async_compression::Level;
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use bytes::Bytes;
+use futures::{Stream, StreamExt, TryStreamExt};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::config::Config;
+
+pub const ZSTD: &str = "zstd";
+
+const DEFAULT_LEVEL: i32 = 3;
+const MAX_LEVEL: i32 = 22;
+
+type Io<T> = std::result::Result<T, std::io::Error>;
+
+/// Parses `--compress`, `zstd` or `zstd:<level>`, into the zstd level.
+pub fn parse_compress(s: &str) -> std::result::Result<i32, String> {
+    let (algorithm, level) = match s.split_once(':') {
+        Some((algorithm, level)) => (algorithm, Some(level)),
+        None => (s, None),
+    };
+    if algorithm != ZSTD {
+        return Err(format!(
+            "unsupported algorithm '{}' (expected zstd)",
+            algorithm
+        ));
+    }
+    match level {
+        None => Ok(DEFAULT_LEVEL),
+        Some(level) => level
+            .parse()
+            .ok()
+            .filter(|l| (1..=MAX_LEVEL).contains(l))
+            .ok_or_else(|| format!("zstd level must be between 1 and {}", MAX_LEVEL)),
+    }
+}
+
+/// Attached to the response of a write whose body was compressed, so the
+/// request summary can report the ratio achieved.
+#[derive(Debug, Clone, Copy)]
+pub struct CompressionStats {
+    pub original: u64,
+    pub stored: u64,
+}
+
+/// Which new objects get compressed, and how hard.
+pub struct Compressor {
+    level: i32,
+    prefixes: Vec<String>,
+    content_types: Vec<String>,
+}
+
+impl Compressor {
+    pub fn new(config: &Config) -> Option<Self> {
+        config.compress.map(|level| Self {
+            level,
+            prefixes: config.compress_prefix.clone(),
+            content_types: config.compress_content_type.clone(),
+        })
+    }
+
+    /// Whether an object written to `key` with `content_type` is compressed.
+    /// Each filter that is configured must match; `text/*` matches any text type.
+    pub fn applies(&self, key: &str, content_type: Option<&str>) -> bool {
+        let prefix_matches =
+            self.prefixes.is_empty() || self.prefixes.iter().any(|p| key.starts_with(p.as_str()));
+        let type_matches = self.content_types.is_empty()
+            || content_type.is_some_and(|ct| {
+                let essence = ct.split(';').next().unwrap_or_default().trim();
+                self.content_types
+                    .iter()
+                    .any(|pattern| match pattern.strip_suffix('*') {
+                        Some(prefix) => essence
+                            .to_ascii_lowercase()
+                            .starts_with(&prefix.to_ascii_lowercase()),
+                        None => essence.eq_ignore_ascii_case(pattern),
+                    })
+            });
+        prefix_matches && type_matches
+    }
+
+    /// Compresses a body stream. The counter ends up holding the compressed size.
+    pub fn compress<S>(
+        &self,
+        body: S,
+    ) -> (
+        impl Stream<Item = Io<Bytes>> + Send + use<S>,
+        Arc<AtomicU64>,
+    )
+    where
+        S: Stream<Item = Io<Bytes>> + Send + 'static,
+    {
+        let stored = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&stored);
+        let encoder =
+            ZstdEncoder::with_quality(StreamReader::new(body), Level::Precise(self.level));
+        let stream = ReaderStream::new(encoder).inspect_ok(move |chunk| {
+            counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        });
+        (stream, stored)
+    }
+
+    pub async fn compress_bytes(&self, body: &[u8]) -> Bytes {
+        let mut out = Vec::new();
+        ZstdEncoder::with_quality(body, Level::Precise(self.level))
+            .read_to_end(&mut out)
+            .await
+            .expect("compressing from memory does not fail");
+        out.into()
+    }
+}
+
+/// Restores the original bytes of a compressed object.
+pub fn decompress<S>(stored: S) -> impl Stream<Item = Io<Bytes>> + Send + use<S>
+where
+    S: Stream<Item = Io<Bytes>> + Send + 'static,
+{
+    ReaderStream::new(ZstdDecoder::new(StreamReader::new(stored)))
+}
+
+/// The inclusive byte range `start..=end` of `stream`, for serving a Range
+/// request from an object that can only be read from the beginning.
+pub fn slice<S>(stream: S, start: u64, end: u64) -> impl Stream<Item = Io<Bytes>> + Send + use<S>
+where
+    S: Stream<Item = Io<Bytes>> + Send + 'static,
+{
+    async_stream::try_stream! {
+        let mut stream = std::pin::pin!(stream);
+        let mut offset = 0u64;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            let chunk_start = offset;
+            offset += chunk.len() as u64;
+            if offset <= start {
+                continue;
+            }
+            let from = start.saturating_sub(chunk_start) as usize;
+            let to = ((end + 1 - chunk_start) as usize).min(chunk.len());
+            yield chunk.slice(from..to);
+            if offset > end {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressor(prefixes: &[&str], content_types: &[&str]) -> Compressor {
+        Compressor {
+            level: DEFAULT_LEVEL,
+            prefixes: prefixes.iter().map(|s| s.to_string()).collect(),
+            content_types: content_types.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn chunked(data: &[u8], size: usize) -> impl Stream<Item = Io<Bytes>> + Send + 'static {
+        let chunks: Vec<Io<Bytes>> = data
+            .chunks(size)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        futures::stream::iter(chunks)
+    }
+
+    async fn collect(stream: impl Stream<Item = Io<Bytes>>) -> Vec<u8> {
+        let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+        chunks.concat()
+    }
+
+    #[test]
+    fn test_parse_compress() {
+        assert_eq!(parse_compress("zstd"), Ok(3));
+        assert_eq!(parse_compress("zstd:19"), Ok(19));
+        assert!(parse_compress("zstd:0").is_err());
+        assert!(parse_compress("zstd:fast").is_err());
+        assert!(parse_compress("gzip").is_err());
+    }
+
+    #[test]
+    fn test_filters() {
+        let all = compressor(&[], &[]);
+        assert!(all.applies("a.bin", None));
+
+        let filtered = compressor(&["logs/", "data/"], &["application/json", "text/*"]);
+        assert!(filtered.applies("logs/a.json", Some("application/json; charset=utf-8")));
+        assert!(filtered.applies("data/a.csv", Some("text/csv")));
+        assert!(!filtered.applies("logs/a.png", Some("image/png")));
+        assert!(!filtered.applies("media/a.json", Some("application/json")));
+        assert!(!filtered.applies("logs/a.json", None));
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_and_slice() {
+        let data: Vec<u8> = (0..200_000u32)
+            .flat_map(|i| format!("{{\"row\":{}}}\n", i % 100).into_bytes())
+            .collect();
+        let compressor = compressor(&[], &[]);
+
+        let (stream, stored) = compressor.compress(chunked(&data, 7000));
+        let compressed = collect(stream).await;
+        assert_eq!(stored.load(Ordering::Relaxed), compressed.len() as u64);
+        assert!(compressed.len() * 10 < data.len());
+        assert_eq!(compressor.compress_bytes(&data).await, compressed);
+
+        let restored = collect(decompress(chunked(&compressed, 1000))).await;
+        assert_eq!(restored, data);
+
+        for (start, end) in [(0, 0), (5, 70_000), (100_000, data.len() as u64 - 1)] {
+            let sliced = collect(slice(chunked(&data, 4096), start, end)).await;
+            assert_eq!(sliced, &data[start as usize..=end as usize]);
+        }
+    }
+}
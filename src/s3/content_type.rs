@@ -0,0 +1,124 @@
+//! Content-Type detection from the key's extension for uploads that arrive
+//! without one, so Bunny serves `.html` and `.jpg` files as what they are
+//! instead of `application/octet-stream`. Disabled with
+//! `--guess-content-type false`; `--mime-map` adds or overrides extensions.
+
+use axum::http::{HeaderMap, header};
+use std::collections::HashMap;
+
+use crate::config::Config;
+
+/// Parses a `--mime-map` entry, `.ext=type/subtype`, into the lowercased
+/// extension without its dot and the type.
+pub fn parse_mime_map(s: &str) -> std::result::Result<(String, String), String> {
+    let (extension, mime) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected .ext=type/subtype, got '{}'", s))?;
+    let extension = extension.trim().trim_start_matches('.');
+    let mime = mime.trim();
+    if extension.is_empty() || extension.contains(['.', '/']) {
+        return Err(format!("invalid extension in '{}'", s));
+    }
+    if mime.parse::<mime_guess::Mime>().is_err() {
+        return Err(format!("invalid media type '{}'", mime));
+    }
+    Ok((extension.to_ascii_lowercase(), mime.to_string()))
+}
+
+pub struct ContentTypeGuesser {
+    custom: HashMap<String, String>,
+}
+
+impl ContentTypeGuesser {
+    pub fn new(config: &Config) -> Option<Self> {
+        config.guess_content_type.then(|| Self {
+            custom: config.mime_map.iter().cloned().collect(),
+        })
+    }
+
+    /// The type registered for the extension of `key`'s last segment.
+    pub fn guess(&self, key: &str) -> Option<String> {
+        let name = key.rsplit('/').next().unwrap_or(key);
+        let (stem, extension) = name.rsplit_once('.')?;
+        if stem.is_empty() {
+            return None;
+        }
+        let extension = extension.to_ascii_lowercase();
+        self.custom.get(&extension).cloned().or_else(|| {
+            mime_guess::from_ext(&extension)
+                .first()
+                .map(|mime| mime.to_string())
+        })
+    }
+}
+
+/// The Content-Type to store for `key`: the client's own, else a guess from
+/// the extension when `guesser` is enabled.
+pub fn resolve(
+    guesser: Option<&ContentTypeGuesser>,
+    key: &str,
+    headers: &HeaderMap,
+) -> Option<String> {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| guesser?.guess(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn guesser(extra: &[&str]) -> Option<ContentTypeGuesser> {
+        let base = ["bunny-s3-proxy", "-z", "zone", "-k", "key"];
+        ContentTypeGuesser::new(&Config::parse_from(base.iter().chain(extra)))
+    }
+
+    #[test]
+    fn test_guess_from_extension() {
+        let guesser = guesser(&["--mime-map", ".heic=image/heic,.HTML=text/x-custom"]).unwrap();
+        assert_eq!(
+            guesser.guess("site/index.htm").as_deref(),
+            Some("text/html")
+        );
+        assert_eq!(
+            guesser.guess("a/b/Photo.JPG").as_deref(),
+            Some("image/jpeg")
+        );
+        assert_eq!(guesser.guess("img.heic").as_deref(), Some("image/heic"));
+        assert_eq!(guesser.guess("page.html").as_deref(), Some("text/x-custom"));
+        assert_eq!(guesser.guess("dir.d/README"), None);
+        assert_eq!(guesser.guess(".bashrc"), None);
+        assert_eq!(guesser.guess("data.unknownext"), None);
+    }
+
+    #[test]
+    fn test_resolve_prefers_client_type() {
+        let guesser = guesser(&[]).unwrap();
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            resolve(Some(&guesser), "a.png", &headers).as_deref(),
+            Some("image/png")
+        );
+        assert_eq!(resolve(None, "a.png", &headers), None);
+        headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+        assert_eq!(
+            resolve(Some(&guesser), "a.png", &headers).as_deref(),
+            Some("text/plain")
+        );
+        assert!(self::guesser(&["--guess-content-type", "false"]).is_none());
+    }
+
+    #[test]
+    fn test_parse_mime_map() {
+        assert_eq!(
+            parse_mime_map(".HEIC=image/heic"),
+            Ok(("heic".to_string(), "image/heic".to_string()))
+        );
+        assert!(parse_mime_map("heic").is_err());
+        assert!(parse_mime_map(".=image/heic").is_err());
+        assert!(parse_mime_map(".heic=not a type").is_err());
+    }
+}
@@ -0,0 +1,209 @@
+//! `--dedupe-writes`: a PUT whose full-object checksum matches the object
+//! already stored under its key is acknowledged without uploading anything.
+//!
+//! A SHA-256 claim, from `x-amz-checksum-sha256` or a signed
+//! `x-amz-content-sha256`, is compared with the checksum Bunny keeps for the
+//! stored bytes, so it matches objects stored as sent. A Content-MD5 claim
+//! is compared with the ETag kept for compressed and encrypted objects, the
+//! only ones whose MD5 the proxy knows.
+
+use axum::http::HeaderMap;
+use base64::Engine;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::bunny::types::StorageObject;
+
+use super::object_meta::ObjectMeta;
+
+/// The checksum a PUT declares for its whole body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Claim {
+    /// Upper-case hex, as Bunny reports checksums.
+    Sha256(String),
+    /// Lower-case hex, as the proxy's ETags are.
+    Md5(String),
+}
+
+impl Claim {
+    /// The strongest full-object checksum among `headers` and the signed
+    /// payload hash, if any is well-formed.
+    pub fn from_request(headers: &HeaderMap, payload_hash: Option<&str>) -> Option<Self> {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        if let Some(sha256) = header("x-amz-checksum-sha256").and_then(|v| decode(v, 32)) {
+            return Some(Self::Sha256(sha256.to_ascii_uppercase()));
+        }
+        if let Some(sha256) = payload_hash.filter(|h| is_hex(h, 32)) {
+            return Some(Self::Sha256(sha256.to_ascii_uppercase()));
+        }
+        let md5 = header("content-md5").and_then(|v| decode(v, 16))?;
+        Some(Self::Md5(md5))
+    }
+
+    /// The ETag of `obj` if it holds `size` bytes with this checksum.
+    pub fn matches(&self, obj: &StorageObject, meta: &ObjectMeta, size: u64) -> Option<String> {
+        if obj.length < 0 || obj.is_directory {
+            return None;
+        }
+        match (self, meta.original()) {
+            (Self::Sha256(sha256), None) => (obj.length as u64 == size
+                && obj
+                    .checksum
+                    .as_deref()
+                    .is_some_and(|c| c.eq_ignore_ascii_case(sha256)))
+            .then(|| obj.etag()),
+            (Self::Md5(md5), Some((original_size, etag))) => {
+                (original_size == size && etag == md5).then(|| etag.to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `computed`, in hex, is the claimed checksum.
+    pub fn holds_for(&self, computed: &str) -> bool {
+        match self {
+            Self::Sha256(expected) | Self::Md5(expected) => expected.eq_ignore_ascii_case(computed),
+        }
+    }
+}
+
+/// Decodes a digest of `len` bytes given in base64, as S3 sends them, or in
+/// hex, as this proxy reports SHA-256 checksums, into lower-case hex.
+fn decode(value: &str, len: usize) -> Option<String> {
+    let value = value.trim();
+    if is_hex(value, len) {
+        return Some(value.to_ascii_lowercase());
+    }
+    base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .ok()
+        .filter(|digest| digest.len() == len)
+        .map(hex::encode)
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len * 2 && value.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Uploads `--dedupe-writes` skipped, and the bytes they did not send.
+#[derive(Debug, Default)]
+pub struct DedupeStats {
+    skipped: AtomicU64,
+    bytes_saved: AtomicU64,
+}
+
+impl DedupeStats {
+    pub fn record(&self, size: u64) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+        self.bytes_saved.fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        for (name, help, counter) in [
+            (
+                "skipped_total",
+                "PUTs acknowledged without an upload because the stored object was identical.",
+                &self.skipped,
+            ),
+            (
+                "bytes_saved_total",
+                "Bytes not uploaded to Bunny because the stored object was identical.",
+                &self.bytes_saved,
+            ),
+        ] {
+            let name = format!("bunny_s3_proxy_dedupe_{}", name);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::s3::object_meta::CompressionMeta;
+    use sha2::{Digest, Sha256};
+
+    fn object(body: &[u8]) -> StorageObject {
+        StorageObject {
+            guid: "guid".into(),
+            user_id: String::new(),
+            last_changed: chrono::Utc::now(),
+            date_created: chrono::Utc::now(),
+            storage_zone_name: "zone".into(),
+            path: "/zone/".into(),
+            object_name: "artifact.bin".into(),
+            length: body.len() as i64,
+            storage_zone_id: 1,
+            is_directory: false,
+            server_id: 0,
+            checksum: Some(hex::encode_upper(Sha256::digest(body))),
+            replicated_zones: None,
+            content_type: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_claims_from_headers() {
+        let body = b"artifact";
+        let sha256 = Sha256::digest(body);
+        let md5 = md5::Md5::digest(body);
+        let b64 = |digest: &[u8]| base64::engine::general_purpose::STANDARD.encode(digest);
+        let headers = |pairs: &[(&'static str, String)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            headers
+        };
+        let expected = Some(Claim::Sha256(hex::encode_upper(sha256)));
+
+        let checksum = headers(&[("x-amz-checksum-sha256", b64(&sha256))]);
+        assert_eq!(Claim::from_request(&checksum, None), expected);
+        let hex_checksum = headers(&[("x-amz-checksum-sha256", hex::encode(sha256))]);
+        assert_eq!(Claim::from_request(&hex_checksum, None), expected);
+        let signed = hex::encode(sha256);
+        assert_eq!(
+            Claim::from_request(&HeaderMap::new(), Some(&signed)),
+            expected
+        );
+        let content_md5 = headers(&[("content-md5", b64(&md5))]);
+        assert_eq!(
+            Claim::from_request(&content_md5, Some("UNSIGNED-PAYLOAD")),
+            Some(Claim::Md5(hex::encode(md5)))
+        );
+        let short = headers(&[("content-md5", b64(&md5[..8]))]);
+        assert_eq!(Claim::from_request(&short, None), None);
+    }
+
+    #[test]
+    fn test_only_identical_objects_match() {
+        let body = b"artifact";
+        let obj = object(body);
+        let sha256 = Claim::Sha256(hex::encode_upper(Sha256::digest(body)));
+        let plain = ObjectMeta::default();
+        assert_eq!(sha256.matches(&obj, &plain, 8), Some(obj.etag()));
+        assert_eq!(sha256.matches(&obj, &plain, 9), None);
+        assert_eq!(sha256.matches(&object(b"artefact"), &plain, 8), None);
+
+        let md5 = hex::encode(md5::Md5::digest(body));
+        let compressed = ObjectMeta {
+            compression: Some(CompressionMeta {
+                algorithm: "zstd".into(),
+                size: 8,
+                etag: md5.clone(),
+            }),
+            ..Default::default()
+        };
+        // The stored bytes of a compressed object are not what was sent.
+        assert_eq!(sha256.matches(&obj, &compressed, 8), None);
+        assert_eq!(
+            Claim::Md5(md5.clone()).matches(&obj, &compressed, 8),
+            Some(md5.clone())
+        );
+        assert_eq!(Claim::Md5(md5).matches(&obj, &plain, 8), None);
+    }
+}
@@ -0,0 +1,510 @@
+//! Encryption at rest with `--encryption-key-file`.
+//!
+//! A stored object is a 20-byte header followed by AES-256-GCM frames:
+//!
+//! ```text
+//! "BS3PENC1" | key id (u32 BE) | nonce prefix (8 random bytes)
+//! frame 0 | frame 1 | ... | final frame
+//! ```
+//!
+//! Each frame seals up to [`FRAME_SIZE`] plaintext bytes and carries a 16-byte
+//! tag. The nonce is the prefix followed by the frame index, and the header
+//! plus a final-frame flag are authenticated with every frame, so frames
+//! cannot be reordered, swapped between objects or truncated unnoticed. A
+//! plaintext of `n` bytes always has `max(1, ceil(n / FRAME_SIZE))` frames,
+//! which makes the stored length a pure function of the plaintext length.
+
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use ring::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::config::Config;
+
+/// Plaintext bytes sealed per frame.
+pub const FRAME_SIZE: u64 = 64 * 1024;
+
+const MAGIC: &[u8; 8] = b"BS3PENC1";
+const HEADER_LEN: u64 = 20;
+const TAG_LEN: u64 = 16;
+const KEY_LEN: usize = 32;
+
+type Io<T> = std::result::Result<T, std::io::Error>;
+
+/// Stored size of an object whose plaintext is `plain_len` bytes.
+pub fn encrypted_len(plain_len: u64) -> u64 {
+    HEADER_LEN + frame_count(plain_len) * TAG_LEN + plain_len
+}
+
+/// Plaintext size of an encrypted object stored as `stored_len` bytes, or
+/// `None` if no plaintext length encrypts to exactly that size.
+pub fn plaintext_len(stored_len: u64) -> Option<u64> {
+    let frames = stored_len.checked_sub(HEADER_LEN)?;
+    let full = frames / (FRAME_SIZE + TAG_LEN);
+    let rest = frames % (FRAME_SIZE + TAG_LEN);
+    match (full, rest) {
+        (0, 0) => None,
+        (_, 0) => Some(full * FRAME_SIZE),
+        (_, rest) if rest >= TAG_LEN => Some(full * FRAME_SIZE + rest - TAG_LEN),
+        _ => None,
+    }
+}
+
+fn frame_count(plain_len: u64) -> u64 {
+    plain_len.div_ceil(FRAME_SIZE).max(1)
+}
+
+fn frame_offset(index: u64) -> u64 {
+    HEADER_LEN + index * (FRAME_SIZE + TAG_LEN)
+}
+
+/// Which stored bytes to fetch for a plaintext byte range, and how to turn
+/// them back into exactly that range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadPlan {
+    /// First stored byte to fetch. Zero when the header is read inline.
+    pub stored_start: u64,
+    /// Last stored byte to fetch, inclusive.
+    pub stored_end: u64,
+    plain_len: u64,
+    first_frame: u64,
+    skip: u64,
+    len: u64,
+}
+
+impl ReadPlan {
+    /// Plans a read of plaintext bytes `start..=end` of an object whose
+    /// plaintext is `plain_len` bytes long; the range must lie within it.
+    pub fn new(plain_len: u64, start: u64, end: u64) -> Self {
+        let first_frame = start / FRAME_SIZE;
+        let last_frame = end / FRAME_SIZE;
+        Self {
+            stored_start: if first_frame == 0 {
+                0
+            } else {
+                frame_offset(first_frame)
+            },
+            stored_end: (frame_offset(last_frame + 1) - 1).min(encrypted_len(plain_len) - 1),
+            plain_len,
+            first_frame,
+            skip: start - first_frame * FRAME_SIZE,
+            len: end + 1 - start,
+        }
+    }
+
+    /// The whole object, header included.
+    pub fn full(plain_len: u64) -> Self {
+        let mut plan = Self::new(plain_len, 0, plain_len.saturating_sub(1));
+        plan.len = plain_len;
+        plan
+    }
+
+    /// Whether the fetched bytes start with the object header. Otherwise the
+    /// header has to be fetched separately with [`Header::RANGE`].
+    pub fn includes_header(&self) -> bool {
+        self.stored_start == 0
+    }
+}
+
+/// The per-object header written in front of the frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    key_id: u32,
+    nonce_prefix: [u8; 8],
+}
+
+impl Header {
+    /// HTTP range covering the header of a stored object.
+    pub const RANGE: &str = "bytes=0-19";
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN as usize || &bytes[..8] != MAGIC {
+            return None;
+        }
+        Some(Self {
+            key_id: u32::from_be_bytes(bytes[8..12].try_into().ok()?),
+            nonce_prefix: bytes[12..20].try_into().ok()?,
+        })
+    }
+
+    fn encode(&self) -> [u8; HEADER_LEN as usize] {
+        let mut out = [0u8; HEADER_LEN as usize];
+        out[..8].copy_from_slice(MAGIC);
+        out[8..12].copy_from_slice(&self.key_id.to_be_bytes());
+        out[12..20].copy_from_slice(&self.nonce_prefix);
+        out
+    }
+
+    fn nonce(&self, index: u64) -> Io<Nonce> {
+        let index = u32::try_from(index)
+            .map_err(|_| std::io::Error::other("object has too many encryption frames"))?;
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..8].copy_from_slice(&self.nonce_prefix);
+        nonce[8..].copy_from_slice(&index.to_be_bytes());
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+
+    fn aad(&self, is_final: bool) -> [u8; HEADER_LEN as usize + 1] {
+        let mut aad = [0u8; HEADER_LEN as usize + 1];
+        aad[..HEADER_LEN as usize].copy_from_slice(&self.encode());
+        aad[HEADER_LEN as usize] = is_final as u8;
+        aad
+    }
+}
+
+/// The key new objects are encrypted with, plus older keys that can still
+/// decrypt objects written before a rotation.
+pub struct Keyring {
+    active_id: u32,
+    keys: HashMap<u32, LessSafeKey>,
+    rng: SystemRandom,
+}
+
+impl Keyring {
+    /// Loads the configured keys, or `None` when encryption at rest is off.
+    pub fn load(config: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(active) = &config.encryption_key_file else {
+            return Ok(None);
+        };
+        let others = config
+            .decryption_key_file
+            .iter()
+            .map(|path| read_key(path))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let keyring = Self::new(read_key(active)?, others);
+        tracing::info!(
+            "Encrypting objects at rest with key {:08x} ({} keys loaded)",
+            keyring.active_id,
+            keyring.keys.len()
+        );
+        Ok(Some(keyring))
+    }
+
+    /// A keyring holding only a customer-provided (SSE-C) key.
+    pub fn for_customer_key(key: [u8; KEY_LEN]) -> Self {
+        Self::new(key, Vec::new())
+    }
+
+    fn new(active: [u8; KEY_LEN], others: Vec<[u8; KEY_LEN]>) -> Self {
+        let mut keys = HashMap::new();
+        for key in others.iter().chain(std::iter::once(&active)) {
+            let unbound = UnboundKey::new(&AES_256_GCM, key).expect("AES-256 key length");
+            keys.insert(key_id(key), LessSafeKey::new(unbound));
+        }
+        Self {
+            active_id: key_id(&active),
+            keys,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    fn new_header(&self) -> Header {
+        let mut nonce_prefix = [0u8; 8];
+        self.rng
+            .fill(&mut nonce_prefix)
+            .expect("system random number generator");
+        Header {
+            key_id: self.active_id,
+            nonce_prefix,
+        }
+    }
+
+    fn seal(&self, header: &Header, index: u64, is_final: bool, frame: &[u8]) -> Io<Bytes> {
+        let key = &self.keys[&header.key_id];
+        let mut sealed = Vec::with_capacity(frame.len() + TAG_LEN as usize);
+        sealed.extend_from_slice(frame);
+        key.seal_in_place_append_tag(
+            header.nonce(index)?,
+            Aad::from(header.aad(is_final)),
+            &mut sealed,
+        )
+        .map_err(|_| std::io::Error::other("encryption failed"))?;
+        Ok(Bytes::from(sealed))
+    }
+
+    /// Encrypts a whole object held in memory.
+    pub fn encrypt_bytes(&self, plaintext: &[u8]) -> Bytes {
+        let header = self.new_header();
+        let mut out = BytesMut::with_capacity(encrypted_len(plaintext.len() as u64) as usize);
+        out.extend_from_slice(&header.encode());
+        let frames = frame_count(plaintext.len() as u64);
+        let mut chunks = plaintext.chunks(FRAME_SIZE as usize);
+        for index in 0..frames {
+            let frame = chunks.next().unwrap_or_default();
+            let sealed = self
+                .seal(&header, index, index + 1 == frames, frame)
+                .expect("frame index fits in the nonce");
+            out.extend_from_slice(&sealed);
+        }
+        out.freeze()
+    }
+
+    /// Encrypts a plaintext stream into the stored format.
+    pub fn encrypt<S>(
+        self: &Arc<Self>,
+        plaintext: S,
+    ) -> impl Stream<Item = Io<Bytes>> + Send + use<S>
+    where
+        S: Stream<Item = Io<Bytes>> + Send + 'static,
+    {
+        let keyring = Arc::clone(self);
+        async_stream::try_stream! {
+            let header = keyring.new_header();
+            yield Bytes::copy_from_slice(&header.encode());
+
+            let mut plaintext = std::pin::pin!(plaintext);
+            let mut buf = BytesMut::new();
+            let mut index = 0;
+            while let Some(chunk) = plaintext.next().await {
+                buf.extend_from_slice(&chunk?);
+                // Keep the last full frame back until we know whether it is final.
+                while buf.len() as u64 > FRAME_SIZE {
+                    let frame = buf.split_to(FRAME_SIZE as usize);
+                    yield keyring.seal(&header, index, false, &frame)?;
+                    index += 1;
+                }
+            }
+            yield keyring.seal(&header, index, true, &buf)?;
+        }
+    }
+
+    /// Decrypts the stored bytes fetched for `plan`. `header` must be given
+    /// when the plan does not include it.
+    pub fn decrypt<S>(
+        self: &Arc<Self>,
+        stored: S,
+        header: Option<Header>,
+        plan: ReadPlan,
+    ) -> impl Stream<Item = Io<Bytes>> + Send + use<S>
+    where
+        S: Stream<Item = Io<Bytes>> + Send + 'static,
+    {
+        let keyring = Arc::clone(self);
+        async_stream::try_stream! {
+            let mut stored = std::pin::pin!(stored);
+            let mut buf = BytesMut::new();
+            let header = match header {
+                Some(header) => header,
+                None => {
+                    fill(&mut stored, &mut buf, HEADER_LEN as usize).await?;
+                    let header = Header::parse(&buf).ok_or_else(|| {
+                        std::io::Error::other("stored object has no encryption header")
+                    })?;
+                    let _ = buf.split_to(HEADER_LEN as usize);
+                    header
+                }
+            };
+            let key = keyring.keys.get(&header.key_id).ok_or_else(|| {
+                std::io::Error::other(format!("no decryption key {:08x}", header.key_id))
+            })?;
+
+            let frames = frame_count(plan.plain_len);
+            let mut index = plan.first_frame;
+            let mut skip = plan.skip as usize;
+            let mut remaining = plan.len;
+            while remaining > 0 {
+                let is_final = index + 1 == frames;
+                let frame_plain = if is_final {
+                    plan.plain_len - index * FRAME_SIZE
+                } else {
+                    FRAME_SIZE
+                };
+                let frame_len = (frame_plain + TAG_LEN) as usize;
+                fill(&mut stored, &mut buf, frame_len).await?;
+                let mut frame = buf.split_to(frame_len);
+                let plain = key
+                    .open_in_place(header.nonce(index)?, Aad::from(header.aad(is_final)), &mut frame)
+                    .map_err(|_| std::io::Error::other(format!("frame {} failed authentication", index)))?;
+                let end = plain.len().min(skip + remaining as usize);
+                let chunk = Bytes::copy_from_slice(&plain[skip..end]);
+                remaining -= chunk.len() as u64;
+                skip = 0;
+                index += 1;
+                yield chunk;
+            }
+        }
+    }
+}
+
+/// Reads from `stream` until `buf` holds at least `len` bytes.
+async fn fill<S>(stream: &mut std::pin::Pin<&mut S>, buf: &mut BytesMut, len: usize) -> Io<()>
+where
+    S: Stream<Item = Io<Bytes>>,
+{
+    while buf.len() < len {
+        match stream.next().await {
+            Some(chunk) => buf.extend_from_slice(&chunk?),
+            None => return Err(std::io::Error::other("stored object is truncated")),
+        }
+    }
+    Ok(())
+}
+
+fn key_id(key: &[u8; KEY_LEN]) -> u32 {
+    let digest = Sha256::digest(key);
+    u32::from_be_bytes(digest[..4].try_into().expect("digest is 32 bytes"))
+}
+
+/// Reads a 256-bit key stored raw, as hex or as base64.
+fn read_key(path: &Path) -> anyhow::Result<[u8; KEY_LEN]> {
+    use base64::Engine;
+
+    let contents = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read key file {}: {}", path.display(), e))?;
+    if let Ok(key) = <[u8; KEY_LEN]>::try_from(contents.as_slice()) {
+        return Ok(key);
+    }
+    let text = String::from_utf8_lossy(&contents);
+    let text = text.trim();
+    hex::decode(text)
+        .ok()
+        .or_else(|| base64::engine::general_purpose::STANDARD.decode(text).ok())
+        .and_then(|key| <[u8; KEY_LEN]>::try_from(key).ok())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Key file {} must hold a 256-bit key as 32 raw bytes, hex or base64",
+                path.display()
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyring(active: u8, others: &[u8]) -> Arc<Keyring> {
+        Arc::new(Keyring::new(
+            [active; KEY_LEN],
+            others.iter().map(|&k| [k; KEY_LEN]).collect(),
+        ))
+    }
+
+    fn plaintext(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    async fn collect(stream: impl Stream<Item = Io<Bytes>>) -> Io<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut stream = std::pin::pin!(stream);
+        while let Some(chunk) = stream.next().await {
+            out.extend_from_slice(&chunk?);
+        }
+        Ok(out)
+    }
+
+    fn chunked(data: &[u8], size: usize) -> impl Stream<Item = Io<Bytes>> + Send + 'static {
+        let chunks: Vec<Io<Bytes>> = data
+            .chunks(size)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        futures::stream::iter(chunks)
+    }
+
+    #[test]
+    fn test_lengths_round_trip() {
+        let frame = FRAME_SIZE as usize;
+        for len in [
+            0,
+            1,
+            100,
+            frame - 1,
+            frame,
+            frame + 1,
+            3 * frame,
+            3 * frame + 7,
+        ] {
+            let stored = encrypted_len(len as u64);
+            assert_eq!(plaintext_len(stored), Some(len as u64), "len {}", len);
+        }
+        assert_eq!(plaintext_len(HEADER_LEN), None);
+        assert_eq!(plaintext_len(5), None);
+    }
+
+    #[tokio::test]
+    async fn test_stream_round_trip_and_ranges() {
+        let keyring = keyring(1, &[]);
+        let data = plaintext(3 * FRAME_SIZE as usize + 123);
+        let stored = collect(keyring.encrypt(chunked(&data, 10_000)))
+            .await
+            .unwrap();
+        assert_eq!(stored.len() as u64, encrypted_len(data.len() as u64));
+        assert_eq!(
+            keyring.encrypt_bytes(&data).len(),
+            stored.len(),
+            "buffered and streamed encryption must frame alike"
+        );
+
+        let plain_len = data.len() as u64;
+        let full = ReadPlan::full(plain_len);
+        let decrypted = collect(keyring.decrypt(chunked(&stored, 777), None, full))
+            .await
+            .unwrap();
+        assert_eq!(decrypted, data);
+
+        let header = Header::parse(&stored).unwrap();
+        for (start, end) in [
+            (0, 0),
+            (5, 70_000),
+            (FRAME_SIZE, 2 * FRAME_SIZE - 1),
+            (2 * FRAME_SIZE + 3, plain_len - 1),
+        ] {
+            let plan = ReadPlan::new(plain_len, start, end);
+            let fetched = &stored[plan.stored_start as usize..=plan.stored_end as usize];
+            let header = (!plan.includes_header()).then_some(header);
+            let decrypted = collect(keyring.decrypt(chunked(fetched, 4096), header, plan))
+                .await
+                .unwrap();
+            assert_eq!(decrypted, &data[start as usize..=end as usize]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_object_and_rotation() {
+        let old = keyring(1, &[]);
+        let stored = old.encrypt_bytes(b"");
+        assert_eq!(stored.len() as u64, encrypted_len(0));
+
+        let rotated = keyring(2, &[1]);
+        let decrypted = collect(rotated.decrypt(chunked(&stored, 8), None, ReadPlan::full(0)))
+            .await
+            .unwrap();
+        assert!(decrypted.is_empty());
+
+        let stored = old.encrypt_bytes(b"secret");
+        let without_old = keyring(2, &[]);
+        assert!(
+            collect(without_old.decrypt(chunked(&stored, 8), None, ReadPlan::full(6)))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tampering_is_detected() {
+        let keyring = keyring(1, &[]);
+        let data = plaintext(2 * FRAME_SIZE as usize);
+        let stored = keyring.encrypt_bytes(&data);
+
+        let mut flipped = stored.to_vec();
+        flipped[HEADER_LEN as usize + 10] ^= 1;
+        let plan = ReadPlan::full(data.len() as u64);
+        assert!(
+            collect(keyring.decrypt(chunked(&flipped, 1000), None, plan.clone()))
+                .await
+                .is_err()
+        );
+
+        let truncated = &stored[..frame_offset(1) as usize];
+        let plan = ReadPlan::full(FRAME_SIZE);
+        assert!(
+            collect(keyring.decrypt(chunked(truncated, 1000), None, plan))
+                .await
+                .is_err(),
+            "a non-final frame must not pass as the last one"
+        );
+    }
+}
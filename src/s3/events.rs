@@ -0,0 +1,401 @@
+use bytes::Bytes;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde_json::{Value, json};
+use sha2::Sha256;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::config::Config;
+
+/// Header carrying the hex HMAC-SHA256 of the delivered body, keyed by
+/// `--event-webhook-secret`.
+pub const SIGNATURE_HEADER: &str = "x-bunny-s3-signature";
+
+/// Event types accepted by `--event-types`, in S3 notification configuration form.
+pub const SUPPORTED_EVENT_TYPES: &[&str] = &[
+    "s3:ObjectCreated:*",
+    "s3:ObjectCreated:Put",
+    "s3:ObjectCreated:Post",
+    "s3:ObjectCreated:Copy",
+    "s3:ObjectCreated:CompleteMultipartUpload",
+    "s3:ObjectRemoved:*",
+    "s3:ObjectRemoved:Delete",
+];
+
+/// Delivery attempts per event before it is counted as failed.
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Validates an `--event-types` entry.
+pub fn parse_event_type(s: &str) -> std::result::Result<String, String> {
+    if SUPPORTED_EVENT_TYPES.contains(&s) {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "unsupported event type (expected one of {})",
+            SUPPORTED_EVENT_TYPES.join(", ")
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventName {
+    Put,
+    Post,
+    Copy,
+    CompleteMultipartUpload,
+    Delete,
+}
+
+impl EventName {
+    /// The `eventName` of the notification record, e.g. `ObjectCreated:Put`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Put => "ObjectCreated:Put",
+            Self::Post => "ObjectCreated:Post",
+            Self::Copy => "ObjectCreated:Copy",
+            Self::CompleteMultipartUpload => "ObjectCreated:CompleteMultipartUpload",
+            Self::Delete => "ObjectRemoved:Delete",
+        }
+    }
+}
+
+/// Which events are sent, following S3's notification filter rules: an event
+/// type list (with `*` wildcards) plus optional key prefix and suffix.
+#[derive(Debug, Clone)]
+struct EventFilter {
+    types: Vec<String>,
+    prefix: Option<String>,
+    suffix: Option<String>,
+}
+
+impl EventFilter {
+    fn matches(&self, name: EventName, key: &str) -> bool {
+        let event = name.as_str();
+        let type_matches = self.types.iter().any(|t| {
+            let t = t.strip_prefix("s3:").unwrap_or(t);
+            match t.strip_suffix('*') {
+                Some(category) => event.starts_with(category),
+                None => t == event,
+            }
+        });
+        type_matches
+            && self.prefix.as_deref().is_none_or(|p| key.starts_with(p))
+            && self.suffix.as_deref().is_none_or(|s| key.ends_with(s))
+    }
+}
+
+#[derive(Debug, Default)]
+struct DeliveryStats {
+    delivered: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Queues S3-style event notifications for delivery to `--event-webhook-url`
+/// by a background task. Queueing never waits: when the bounded queue is full
+/// the event is dropped and counted.
+pub struct EventNotifier {
+    tx: Option<mpsc::Sender<Value>>,
+    filter: EventFilter,
+    region: String,
+    sequence: AtomicU64,
+    stats: Arc<DeliveryStats>,
+}
+
+impl EventNotifier {
+    /// Starts the delivery task if a webhook URL is configured.
+    pub fn new(config: &Config) -> Self {
+        let stats = Arc::new(DeliveryStats::default());
+        let tx = config.event_webhook_url.as_ref().map(|url| {
+            let (tx, rx) = mpsc::channel(config.event_queue_size.max(1));
+            tokio::spawn(deliver(
+                rx,
+                url.clone(),
+                config.event_webhook_secret.clone(),
+                Arc::clone(&stats),
+            ));
+            tracing::info!("Sending event notifications to {}", url);
+            tx
+        });
+
+        Self {
+            tx,
+            filter: EventFilter {
+                types: config.event_types.clone(),
+                prefix: config.event_prefix.clone(),
+                suffix: config.event_suffix.clone(),
+            },
+            region: config.region.code().to_string(),
+            sequence: AtomicU64::new(Utc::now().timestamp_nanos_opt().unwrap_or(0) as u64),
+            stats,
+        }
+    }
+
+    /// Queues a notification for a completed write or delete.
+    pub fn notify(
+        &self,
+        name: EventName,
+        bucket: &str,
+        key: &str,
+        size: Option<u64>,
+        etag: Option<&str>,
+    ) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if !self.filter.matches(name, key) {
+            return;
+        }
+
+        let sequencer = format!("{:016X}", self.sequence.fetch_add(1, Ordering::Relaxed));
+        let record = self.record(name, bucket, key, size, etag, &sequencer);
+        if let Err(e) = tx.try_send(record) {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("Dropping {} event for {}: {}", name.as_str(), key, e);
+        }
+    }
+
+    fn record(
+        &self,
+        name: EventName,
+        bucket: &str,
+        key: &str,
+        size: Option<u64>,
+        etag: Option<&str>,
+        sequencer: &str,
+    ) -> Value {
+        let mut object = json!({
+            "key": encode_key(key),
+            "sequencer": sequencer,
+        });
+        if let Some(size) = size {
+            object["size"] = size.into();
+        }
+        if let Some(etag) = etag {
+            object["eTag"] = etag.trim_matches('"').into();
+        }
+
+        json!({
+            "eventVersion": "2.1",
+            "eventSource": "aws:s3",
+            "awsRegion": self.region,
+            "eventTime": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "eventName": name.as_str(),
+            "s3": {
+                "s3SchemaVersion": "1.0",
+                "bucket": {
+                    "name": bucket,
+                    "arn": format!("arn:aws:s3:::{}", bucket),
+                },
+                "object": object,
+            },
+        })
+    }
+
+    /// Delivery counters in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        for (name, help, counter) in [
+            (
+                "delivered",
+                "Event notifications accepted by the webhook.",
+                &self.stats.delivered,
+            ),
+            (
+                "failed",
+                "Event notifications abandoned after every delivery attempt failed.",
+                &self.stats.failed,
+            ),
+            (
+                "dropped",
+                "Event notifications discarded because the queue was full.",
+                &self.stats.dropped,
+            ),
+        ] {
+            let name = format!("bunny_s3_proxy_events_{}_total", name);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+/// S3 URL-encodes object keys in event records, leaving `/` intact.
+fn encode_key(key: &str) -> String {
+    key.split('/')
+        .map(|segment| url::form_urlencoded::byte_serialize(segment.as_bytes()).collect())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Posts queued events one at a time, in order, retrying with exponential
+/// backoff before giving up on an event.
+async fn deliver(
+    mut rx: mpsc::Receiver<Value>,
+    url: String,
+    secret: Option<String>,
+    stats: Arc<DeliveryStats>,
+) {
+    let client = reqwest::Client::builder()
+        .user_agent("bunny-s3-proxy/0.1.0")
+        .timeout(DELIVERY_TIMEOUT)
+        .build()
+        .expect("Failed to create HTTP client");
+
+    while let Some(record) = rx.recv().await {
+        let body = Bytes::from(json!({ "Records": [record] }).to_string());
+        let signature = secret.as_deref().map(|s| sign(s, &body));
+
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=MAX_DELIVERY_ATTEMPTS {
+            let mut request = client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, format!("sha256={}", signature));
+            }
+
+            let error = match request.send().await {
+                Ok(r) if r.status().is_success() => {
+                    stats.delivered.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+                Ok(r) => format!("webhook returned {}", r.status()),
+                Err(e) => e.to_string(),
+            };
+            if attempt == MAX_DELIVERY_ATTEMPTS {
+                stats.failed.fetch_add(1, Ordering::Relaxed);
+                tracing::error!(
+                    "Giving up on event notification after {} attempts: {}",
+                    attempt,
+                    error
+                );
+                break;
+            }
+            tracing::warn!(
+                "Event notification attempt {} failed: {}; retrying",
+                attempt,
+                error
+            );
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, extract::State, http::HeaderMap, routing::post};
+    use clap::Parser;
+
+    fn filter(types: &[&str], prefix: Option<&str>, suffix: Option<&str>) -> EventFilter {
+        EventFilter {
+            types: types.iter().map(|t| t.to_string()).collect(),
+            prefix: prefix.map(str::to_string),
+            suffix: suffix.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_filter_rules() {
+        let all = filter(&["s3:ObjectCreated:*", "s3:ObjectRemoved:*"], None, None);
+        assert!(all.matches(EventName::Put, "a.txt"));
+        assert!(all.matches(EventName::CompleteMultipartUpload, "a.txt"));
+        assert!(all.matches(EventName::Delete, "a.txt"));
+
+        let puts = filter(&["s3:ObjectCreated:Put"], Some("images/"), Some(".jpg"));
+        assert!(puts.matches(EventName::Put, "images/cat.jpg"));
+        assert!(!puts.matches(EventName::Copy, "images/cat.jpg"));
+        assert!(!puts.matches(EventName::Put, "docs/cat.jpg"));
+        assert!(!puts.matches(EventName::Put, "images/cat.png"));
+        assert!(!puts.matches(EventName::Delete, "images/cat.jpg"));
+
+        assert!(parse_event_type("s3:ObjectRemoved:DeleteMarkerCreated").is_err());
+    }
+
+    #[test]
+    fn test_key_encoding() {
+        assert_eq!(encode_key("photos/my cat+1.jpg"), "photos/my+cat%2B1.jpg");
+    }
+
+    #[tokio::test]
+    async fn test_delivers_signed_records() {
+        let (tx, mut rx) = mpsc::channel::<(HeaderMap, Bytes)>(1);
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(
+                    |State(tx): State<mpsc::Sender<(HeaderMap, Bytes)>>,
+                     headers: HeaderMap,
+                     body: Bytes| async move {
+                        let _ = tx.send((headers, body)).await;
+                    },
+                ),
+            )
+            .with_state(tx);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let url = format!("http://{}/hook", addr);
+        let config = Config::parse_from([
+            "bunny-s3-proxy",
+            "--storage-zone",
+            "test-zone",
+            "--access-key",
+            "key",
+            "--event-webhook-url",
+            &url,
+            "--event-webhook-secret",
+            "s3cret",
+            "--event-suffix",
+            ".txt",
+        ]);
+        let notifier = EventNotifier::new(&config);
+        notifier.notify(EventName::Put, "test-zone", "skip.bin", Some(1), None);
+        notifier.notify(
+            EventName::Put,
+            "test-zone",
+            "dir/a b.txt",
+            Some(5),
+            Some("\"abc\""),
+        );
+
+        let (headers, body) = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            headers[SIGNATURE_HEADER].to_str().unwrap(),
+            format!("sha256={}", sign("s3cret", &body))
+        );
+        let event: Value = serde_json::from_slice(&body).unwrap();
+        let record = &event["Records"][0];
+        assert_eq!(record["eventName"], "ObjectCreated:Put");
+        assert_eq!(record["s3"]["bucket"]["name"], "test-zone");
+        assert_eq!(record["s3"]["object"]["key"], "dir/a+b.txt");
+        assert_eq!(record["s3"]["object"]["size"], 5);
+        assert_eq!(record["s3"]["object"]["eTag"], "abc");
+        assert!(
+            notifier
+                .render_metrics()
+                .contains("bunny_s3_proxy_events_dropped_total 0")
+        );
+    }
+}
@@ -1,33 +1,86 @@
 use axum::{
+    Extension,
     body::Body,
-    extract::State,
-    http::{HeaderMap, Method, StatusCode, Uri, header},
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header},
     response::{IntoResponse, Response},
 };
-use bytes::Bytes;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
 use chrono::Utc;
-use futures::StreamExt;
+use futures::{StreamExt, TryStreamExt};
 use sha2::{Digest, Sha256};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tokio::sync::oneshot;
+use tracing::Instrument;
 
-use crate::bunny::{BunnyClient, UploadOptions};
-use crate::config::Config;
+use crate::bunny::client::{encode_path, escapes_root};
+use crate::bunny::listing_index::ListingIndex;
+use crate::bunny::{Backend, StorageBackend, UploadOptions, accounting};
+use crate::config::{ConditionalWrites, Config, HtmlListing};
+use crate::debug_http;
 use crate::error::{ProxyError, Result};
-use crate::lock::{ConditionalLock, InMemoryLock, Lock};
+use crate::ip_filter::IpFilter;
+use crate::lock::{ConditionalLock, InMemoryLock, Lock, LockGuard};
+use crate::tls::{self, ClientCert};
 
-use super::auth::{AwsAuth, EMPTY_PAYLOAD_HASH, UNSIGNED_PAYLOAD, calculate_payload_hash};
+use super::access::AccessRules;
+use super::activity::ActivityRegistry;
+use super::audit::{self, AuditLog, AuditRecord};
+use super::auth::{
+    AwsAuth, EMPTY_PAYLOAD_HASH, UNSIGNED_PAYLOAD, calculate_payload_hash, constant_time_compare,
+};
+use super::bucket_config::{
+    BUCKET_POLICY_CONFIG, BUCKET_TAGGING_CONFIG, BucketConfigStore, LIFECYCLE_CONFIG,
+};
+use super::buffers::{BufferBudget, Reservation};
+use super::cache_policy::CachePolicies;
+use super::checksums;
+use super::chunked;
+use super::completions::CompletionLimiter;
+use super::compression::{self, CompressionStats, Compressor};
+use super::content_type::{self, ContentTypeGuesser};
+use super::dedupe::{Claim, DedupeStats};
+use super::encryption::{self, Header, Keyring, ReadPlan};
+use super::events::{EventName, EventNotifier};
+use super::html_listing;
+use super::integrity::IntegrityStats;
+use super::inventory::Inventory;
+use super::key_policies::{Access, KeyPolicies};
+use super::key_rules::KeyRules;
 use super::multipart::MultipartManager;
+use super::object_meta::{self, CompressionMeta, EncryptionMeta, ObjectMeta, ObjectMetaStore};
+use super::panics::PanicStats;
+use super::post_policy::PostPolicy;
+use super::quota::{QuotaCharge, Quotas};
+use super::redirect::ReadRedirect;
+use super::replication::Replicator;
+use super::response_compression::compressible;
+use super::retention::RetentionRules;
+use super::select::Select;
+use super::sse;
+use super::subresource::{Subresource, allowed_methods, operation_name};
+use super::trash::Trash;
 use super::types::{
-    CompleteMultipartUpload, CopySource, DeleteRequest, ListObjectsV2Query, S3Bucket,
-    S3CommonPrefix, S3Object, S3Owner,
+    AccessControlPolicy, CompleteMultipartUpload, CopySource, CreateBucketConfiguration,
+    DeleteRequest, LifecycleConfiguration, ListBucketsQuery, ListObjectVersionsQuery,
+    ListObjectsV1Query, ListObjectsV2Query, S3Bucket, S3CommonPrefix, S3Object, S3ObjectVersion,
+    S3Owner, SelectObjectContentRequest, Tagging, VersioningConfiguration,
 };
+use super::usage::UsageCache;
+use super::versions::{Deleted, NULL_VERSION_ID, VersionStore};
 use super::xml;
 
-struct HashingStream<S, H> {
+/// Hashes the chunks passing through. The hash is sent when the stream ends,
+/// or when it is dropped without having failed: hyper stops polling a body
+/// once it has sent Content-Length bytes, before the stream reports its end.
+struct HashingStream<S, H: Digest + Clone> {
     inner: S,
     hasher: H,
     hash_sender: Option<oneshot::Sender<String>>,
@@ -61,7 +114,7 @@ impl<S> HashingStream<S, md5::Md5> {
     }
 }
 
-impl<S: Unpin, H> Unpin for HashingStream<S, H> {}
+impl<S: Unpin, H: Digest + Clone> Unpin for HashingStream<S, H> {}
 
 impl<S, E, H> futures::Stream for HashingStream<S, H>
 where
@@ -77,12 +130,12 @@ where
                 this.hasher.update(&chunk);
                 Poll::Ready(Some(Ok(chunk)))
             }
-            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(Some(Err(e))) => {
+                this.hash_sender = None;
+                Poll::Ready(Some(Err(e)))
+            }
             Poll::Ready(None) => {
-                if let Some(sender) = this.hash_sender.take() {
-                    let hash = hex::encode(this.hasher.clone().finalize());
-                    let _ = sender.send(hash);
-                }
+                this.send_hash();
                 Poll::Ready(None)
             }
             Poll::Pending => Poll::Pending,
@@ -90,25 +143,716 @@ where
     }
 }
 
+impl<S, H: Digest + Clone> HashingStream<S, H> {
+    fn send_hash(&mut self) {
+        if let Some(sender) = self.hash_sender.take() {
+            let hash = hex::encode(self.hasher.clone().finalize());
+            let _ = sender.send(hash);
+        }
+    }
+}
+
+impl<S, H: Digest + Clone> Drop for HashingStream<S, H> {
+    fn drop(&mut self) {
+        self.send_hash();
+    }
+}
+
+/// A request body on its way to Bunny, possibly hashed and encrypted.
+type UploadStream =
+    Pin<Box<dyn futures::Stream<Item = std::result::Result<Bytes, std::io::Error>> + Send>>;
+
+/// Byte accounting shared between a [`LengthCheckedStream`] and its handler.
+#[derive(Default)]
+struct BodyProgress {
+    received: AtomicU64,
+    failed: AtomicBool,
+}
+
+/// Counts streamed bytes and fails the stream if it ends before `expected`,
+/// or as soon as it goes past it.
+struct LengthCheckedStream<S> {
+    inner: S,
+    expected: Option<u64>,
+    progress: Arc<BodyProgress>,
+    done: bool,
+}
+
+impl<S> LengthCheckedStream<S> {
+    fn new(inner: S, expected: Option<u64>) -> (Self, Arc<BodyProgress>) {
+        let progress = Arc::new(BodyProgress::default());
+        (
+            Self {
+                inner,
+                expected,
+                progress: progress.clone(),
+                done: false,
+            },
+            progress,
+        )
+    }
+}
+
+impl<S> futures::Stream for LengthCheckedStream<S>
+where
+    S: futures::Stream<Item = std::result::Result<Bytes, std::io::Error>> + Unpin,
+{
+    type Item = std::result::Result<Bytes, std::io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut this.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let received = this
+                    .progress
+                    .received
+                    .fetch_add(chunk.len() as u64, Ordering::Relaxed)
+                    + chunk.len() as u64;
+                match this.expected {
+                    Some(expected) if received > expected => {
+                        this.done = true;
+                        this.progress.failed.store(true, Ordering::Relaxed);
+                        Poll::Ready(Some(Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("body went past the declared {} bytes", expected),
+                        ))))
+                    }
+                    _ => Poll::Ready(Some(Ok(chunk))),
+                }
+            }
+            Poll::Ready(Some(Err(e))) => {
+                this.done = true;
+                this.progress.failed.store(true, Ordering::Relaxed);
+                Poll::Ready(Some(Err(e)))
+            }
+            Poll::Ready(None) => {
+                this.done = true;
+                let received = this.progress.received.load(Ordering::Relaxed);
+                match this.expected {
+                    Some(expected) if received < expected => {
+                        this.progress.failed.store(true, Ordering::Relaxed);
+                        Poll::Ready(Some(Err(std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            format!("body ended after {} of {} bytes", received, expected),
+                        ))))
+                    }
+                    _ => Poll::Ready(None),
+                }
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Where a streamed write puts its body until it is known to be whole.
+/// Bunny keeps whatever part of an upload it received, so with
+/// `--stage-overwrites`, or under a versioned prefix, a key that already
+/// holds an object is written to a staging path and only replaced once the
+/// body has arrived in full and matched its checksums. Anything else,
+/// including a multipart part, is written in place.
+#[derive(Clone)]
+struct UploadTarget {
+    key: String,
+    staging: Option<String>,
+}
+
+impl UploadTarget {
+    fn in_place(path: &str) -> Self {
+        Self {
+            key: path.to_string(),
+            staging: None,
+        }
+    }
+
+    /// Stages the write of `key` if Bunny holds an object there and either
+    /// `--stage-overwrites` is set or the key is versioned, whose current
+    /// object is archived only once the new one is whole. A failed DESCRIBE
+    /// fails the write rather than guess whether the key is taken.
+    async fn for_key(state: &AppState, key: &str) -> Result<Self> {
+        if !state.config.stage_overwrites && !state.is_versioned(key) {
+            return Ok(Self::in_place(key));
+        }
+        let vacant = match state.bunny.describe(key).await {
+            Ok(obj) => obj.length < 0 || obj.is_directory,
+            Err(ProxyError::NotFound(_)) => true,
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            key: key.to_string(),
+            staging: (!vacant).then(MultipartManager::staging_path),
+        })
+    }
+
+    /// The path the body is uploaded to.
+    fn path(&self) -> &str {
+        self.staging.as_deref().unwrap_or(&self.key)
+    }
+
+    /// Deletes what a failed upload left, never the object it was to replace.
+    async fn discard(&self, bunny: &Backend) {
+        let result = match &self.staging {
+            Some(staging) => MultipartManager::discard_staging(bunny, staging).await,
+            None => bunny.delete(&self.key).await,
+        };
+        if let Err(e) = result {
+            tracing::error!("Failed to delete the partial upload of {}: {}", self.key, e);
+        }
+    }
+
+    /// Moves a staged body of `length` stored bytes into place, once it is
+    /// known to be whole, archiving the object it replaces if that is
+    /// versioned. The move fails, and a versioned object is put back, if
+    /// Bunny hands back any other length.
+    async fn commit(
+        &self,
+        state: &AppState,
+        content_type: Option<&str>,
+        length: u64,
+    ) -> Result<()> {
+        let Some(staging) = &self.staging else {
+            return Ok(());
+        };
+        let bunny = &state.bunny;
+        let moved = state
+            .replacing(&self.key, async {
+                let download = bunny.download(staging).await?;
+                if let Some(found) = download.content_length()
+                    && found != length
+                {
+                    tracing::error!(
+                        "Staged upload {} of {} holds {} bytes, expected {}",
+                        staging,
+                        self.key,
+                        found,
+                        length
+                    );
+                    return Err(ProxyError::BunnyApi(format!(
+                        "staged upload of {} holds {} bytes, expected {}",
+                        self.key, found, length
+                    )));
+                }
+                let (stream, progress) =
+                    LengthCheckedStream::new(Box::pin(download.bytes_stream()), Some(length));
+                let result = bunny
+                    .upload_stream(&self.key, stream, Some(length), content_type)
+                    .await;
+                if let Err(e) = &result {
+                    tracing::error!(
+                        "Failed to move staged upload {} to {} after {} of {} bytes: {}",
+                        staging,
+                        self.key,
+                        progress.received.load(Ordering::Relaxed),
+                        length,
+                        e
+                    );
+                }
+                result
+            })
+            .await;
+        if let Err(e) = MultipartManager::discard_staging(bunny, staging).await {
+            tracing::warn!("Failed to delete staged upload {}: {}", staging, e);
+        }
+        moved
+    }
+}
+
+/// Deletes whatever an upload may have left on Bunny if the handler is dropped
+/// mid-upload, as hyper does when the client disconnects. Dropping the
+/// handler also drops the outbound request, so Bunny sees it aborted.
+struct PartialUploadGuard {
+    bunny: Option<Backend>,
+    target: UploadTarget,
+}
+
+impl PartialUploadGuard {
+    fn new(bunny: &Backend, target: &UploadTarget) -> Self {
+        Self {
+            bunny: Some(bunny.clone()),
+            target: target.clone(),
+        }
+    }
+
+    /// The upload ran to completion, successfully or not.
+    fn disarm(mut self) {
+        self.bunny = None;
+    }
+}
+
+impl Drop for PartialUploadGuard {
+    fn drop(&mut self) {
+        let Some(bunny) = self.bunny.take() else {
+            return;
+        };
+        let target = self.target.clone();
+        tracing::warn!(
+            "Upload of {} abandoned mid-stream, deleting it",
+            target.path()
+        );
+        tokio::spawn(async move { target.discard(&bunny).await });
+    }
+}
+
 #[derive(Clone)]
 pub struct AppState {
-    pub bunny: BunnyClient,
+    pub bunny: Backend,
+    /// The client buckets are resolved against. With `--bucket-as-prefix`,
+    /// `bunny` is this client scoped to the request's bucket folder.
+    pub bucket_root: Backend,
+    /// The zones of `--extra-zone`, each served as the bucket of its name.
+    pub zones: Arc<HashMap<String, Backend>>,
     pub auth: AwsAuth,
     pub config: Arc<Config>,
     pub lock: Arc<Lock>,
+    pub bucket_verified_at: Arc<std::sync::Mutex<Option<Instant>>>,
+    pub activity: Arc<ActivityRegistry>,
+    pub events: Arc<EventNotifier>,
+    pub audit: Arc<AuditLog>,
+    pub usage: Arc<UsageCache>,
+    pub encryption: Option<Arc<Keyring>>,
+    pub compression: Option<Arc<Compressor>>,
+    pub integrity: Arc<IntegrityStats>,
+    pub dedupe: Arc<DedupeStats>,
+    pub panics: Arc<PanicStats>,
+    pub buffers: Arc<BufferBudget>,
+    pub replication: Option<Arc<Replicator>>,
+    pub redirect: Option<Arc<ReadRedirect>>,
+    pub completions: Arc<CompletionLimiter>,
+    pub content_types: Option<Arc<ContentTypeGuesser>>,
+    pub access: Option<Arc<AccessRules>>,
+    pub retention: Option<Arc<RetentionRules>>,
+    pub key_rules: Option<Arc<KeyRules>>,
+    pub key_policies: Option<Arc<KeyPolicies>>,
+    pub cache_policies: Option<Arc<CachePolicies>>,
+    pub ip_filter: Option<Arc<IpFilter>>,
+    pub quotas: Option<Arc<Quotas>>,
+    pub trash: Option<Arc<Trash>>,
+    pub inventory: Option<Arc<Inventory>>,
+    /// The `--listing-index` of each zone, empty without one.
+    pub listing_indexes: Arc<Vec<Arc<ListingIndex>>>,
 }
 
 impl AppState {
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        let encryption = Keyring::load(&config)?;
         let lock = Self::create_lock(&config);
-        Self {
-            bunny: BunnyClient::new((&config).into()),
+        let events = EventNotifier::new(&config);
+        let audit = AuditLog::new(&config);
+        let usage = UsageCache::new(&config);
+        let compression = Compressor::new(&config);
+        let replication = Replicator::new(&config);
+        let redirect = ReadRedirect::new(&config);
+        let completions = CompletionLimiter::new(&config);
+        let buffers = BufferBudget::new(&config);
+        let content_types = ContentTypeGuesser::new(&config);
+        let access = config
+            .access_config
+            .as_deref()
+            .map(AccessRules::load)
+            .transpose()?;
+        if config.html_listing == HtmlListing::Public && access.is_none() {
+            anyhow::bail!("--html-listing public needs --access-config");
+        }
+        let retention = config
+            .retention_config
+            .as_deref()
+            .map(RetentionRules::load)
+            .transpose()?;
+        let key_rules = config
+            .key_rules_config
+            .as_deref()
+            .map(KeyRules::load)
+            .transpose()?;
+        let key_policies = config
+            .key_policies_config
+            .as_deref()
+            .map(KeyPolicies::load)
+            .transpose()?;
+        let cache_policies = config
+            .cache_policy_config
+            .as_deref()
+            .map(CachePolicies::load)
+            .transpose()?;
+        let ip_filter = IpFilter::new(&config)?;
+        let quotas = Quotas::load(&config)?;
+        let trash = Trash::new(&config)?;
+        let inventory = Inventory::load(&config)?;
+        let mut bunny = Backend::new(&config, (&config).into());
+        if let Some(prefix) = &config.key_prefix {
+            bunny = bunny.scoped(prefix);
+        }
+        let mut zones = Self::extra_zones(&config)?;
+        let mut listing_indexes = Vec::new();
+        if config.listing_index {
+            bunny = Self::index(&config, &config.storage_zone, bunny, &mut listing_indexes);
+            for (name, zone) in zones.iter_mut() {
+                *zone = Self::index(&config, name, zone.clone(), &mut listing_indexes);
+            }
+        }
+        Ok(Self {
+            bucket_root: bunny.clone(),
+            bunny,
+            zones: Arc::new(zones),
             auth: AwsAuth::new(
                 config.s3_access_key_id.clone(),
                 config.s3_secret_access_key.clone(),
-            ),
+            )
+            .with_credentials(&config.s3_credentials)?,
             config: Arc::new(config),
             lock: Arc::new(lock),
+            bucket_verified_at: Arc::new(std::sync::Mutex::new(None)),
+            activity: Arc::default(),
+            events: Arc::new(events),
+            audit: Arc::new(audit),
+            usage: Arc::new(usage),
+            encryption: encryption.map(Arc::new),
+            compression: compression.map(Arc::new),
+            integrity: Arc::default(),
+            dedupe: Arc::default(),
+            panics: Arc::default(),
+            buffers: Arc::new(buffers),
+            replication,
+            redirect: redirect.map(Arc::new),
+            completions: Arc::new(completions),
+            content_types: content_types.map(Arc::new),
+            access: access.map(Arc::new),
+            retention: retention.map(Arc::new),
+            key_rules: key_rules.map(Arc::new),
+            key_policies: key_policies.map(Arc::new),
+            cache_policies: cache_policies.map(Arc::new),
+            ip_filter: ip_filter.map(Arc::new),
+            quotas: quotas.map(Arc::new),
+            trash: trash.map(Arc::new),
+            inventory: inventory.map(Arc::new),
+            listing_indexes: Arc::new(listing_indexes),
+        })
+    }
+
+    /// `client` with a new listing index of its zone, which leaves out
+    /// staged multipart parts.
+    fn index(
+        config: &Config,
+        bucket: &str,
+        client: Backend,
+        indexes: &mut Vec<Arc<ListingIndex>>,
+    ) -> Backend {
+        let exclude: fn(&str) -> bool = if config.bucket_as_prefix {
+            |key| {
+                key.split_once('/')
+                    .is_some_and(|(_, rest)| MultipartManager::is_internal_key(rest))
+            }
+        } else {
+            MultipartManager::is_internal_key
+        };
+        let index = Arc::new(ListingIndex::new(config, bucket, client.clone(), exclude));
+        indexes.push(Arc::clone(&index));
+        client.indexed(index)
+    }
+
+    /// Clients for the zones of `--extra-zone`, by bucket name. Their names
+    /// must be bucket names distinct from the main zone's.
+    fn extra_zones(config: &Config) -> anyhow::Result<HashMap<String, Backend>> {
+        let mut zones = HashMap::new();
+        for zone in &config.extra_zone {
+            if !is_valid_bucket_name(&zone.name) {
+                anyhow::bail!("--extra-zone {} is not a valid bucket name", zone.name);
+            }
+            if zone.name == config.storage_zone || zones.contains_key(&zone.name) {
+                anyhow::bail!("--extra-zone {} is configured twice", zone.name);
+            }
+            let mut client = Backend::new(config, zone.storage_zone_config(config));
+            if let Some(prefix) = &config.key_prefix {
+                client = client.scoped(prefix);
+            }
+            zones.insert(zone.name.clone(), client);
+        }
+        Ok(zones)
+    }
+
+    /// The Content-Type to store `key` with: the client's, or a guess from
+    /// its extension.
+    fn content_type(&self, key: &str, headers: &HeaderMap) -> Option<String> {
+        content_type::resolve(self.content_types.as_deref(), key, headers)
+    }
+
+    /// Rejects buckets this proxy does not serve: anything but the storage
+    /// zone or an extra zone, or with `--bucket-as-prefix` any name S3 would
+    /// not accept.
+    fn check_bucket(&self, bucket: &str) -> Result<()> {
+        let served = if self.zones.contains_key(bucket) {
+            true
+        } else if self.config.bucket_as_prefix {
+            is_valid_bucket_name(bucket)
+        } else {
+            bucket == self.config.storage_zone
+        };
+        if served {
+            Ok(())
+        } else {
+            Err(ProxyError::BucketNotFound(bucket.to_string()))
+        }
+    }
+
+    /// The client holding the objects of `bucket`: an extra zone's own
+    /// client, its folder with `--bucket-as-prefix`, the whole zone otherwise.
+    pub fn bucket_client(&self, bucket: &str) -> Result<Backend> {
+        self.check_bucket(bucket)?;
+        Ok(if let Some(zone) = self.zones.get(bucket) {
+            zone.clone()
+        } else if self.config.bucket_as_prefix {
+            self.bucket_root.scoped(bucket)
+        } else {
+            self.bucket_root.clone()
+        })
+    }
+
+    /// Rejects keys with `.` or `..` segments, which Bunny would resolve to
+    /// another key than the one access rules, retention and policies saw,
+    /// keys starting with `/` under `--key-prefix` or `--bucket-as-prefix`,
+    /// and keys in the trash or the versions area.
+    fn check_key(&self, key: &str) -> Result<()> {
+        let confined = self.config.key_prefix.is_some() || self.config.bucket_as_prefix;
+        if escapes_root(key) || (confined && key.starts_with('/')) {
+            return Err(ProxyError::AccessDenied);
+        }
+        if self.trash.as_ref().is_some_and(|trash| trash.contains(key))
+            || (!self.config.emulate_versioning.is_empty() && VersionStore::is_internal_key(key))
+        {
+            return Err(ProxyError::AccessDenied);
+        }
+        Ok(())
+    }
+
+    /// Copies `key` into the trash before a delete, unless the trash is off
+    /// or the request asks for a hard delete.
+    async fn trash_before_delete(
+        &self,
+        key: &str,
+        headers: &HeaderMap,
+        deleted_at: chrono::DateTime<Utc>,
+    ) -> Result<()> {
+        match &self.trash {
+            Some(trash) if !Trash::is_hard_delete(headers) => {
+                trash.move_in(&self.bunny, key, deleted_at).await?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `key` is under an `--emulate-versioning` prefix.
+    pub fn is_versioned(&self, key: &str) -> bool {
+        self.config
+            .emulate_versioning
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+    }
+
+    /// The version ID of the object about to be written at `key`, if it is
+    /// versioned.
+    fn new_version(&self, key: &str) -> Option<String> {
+        self.is_versioned(key)
+            .then(|| VersionStore::new_version_id(Utc::now()))
+    }
+
+    /// Runs `write`, which replaces the object at `key`. If `key` is
+    /// versioned, the object is archived first and put back should the
+    /// write fail, so a failed write leaves no version behind.
+    async fn replacing<T>(&self, key: &str, write: impl Future<Output = Result<T>>) -> Result<T> {
+        if !self.is_versioned(key) {
+            return write.await;
+        }
+        let archived = VersionStore::archive_current(&self.bunny, key).await?;
+        let result = write.await;
+        if let (Err(_), Some(version_id)) = (&result, archived)
+            && let Err(e) = VersionStore::restore(&self.bunny, key, &version_id).await
+        {
+            tracing::error!("Failed to restore {} after a failed write: {}", key, e);
+        }
+        result
+    }
+
+    /// Mirrors a changed key to the shadow zone, if one is configured.
+    async fn replicate(&self, key: &str) -> Result<()> {
+        self.replicate_from(&self.bunny, key).await
+    }
+
+    /// Mirrors a key changed through `client` to the shadow zone, which
+    /// shadows the main zone only, not the extra zones.
+    async fn replicate_from(&self, client: &Backend, key: &str) -> Result<()> {
+        match &self.replication {
+            Some(replication) if client.zone() == self.config.storage_zone => {
+                replication.replicate(client, key).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Refuses an unsigned request unless an `--access-config` rule opens it
+    /// to anonymous reads, or it is a read and `--require-auth` is off.
+    /// Unsigned writes are always refused once rules are configured.
+    fn check_anonymous(
+        &self,
+        method: &Method,
+        bucket: Option<&str>,
+        key: Option<&str>,
+        query: &str,
+    ) -> Result<()> {
+        let read = matches!(*method, Method::GET | Method::HEAD);
+        let public = self.access.as_ref().is_some_and(|access| {
+            let plain = Subresource::from_query(query).is_none() && !query.contains("upload");
+            match (bucket, key) {
+                (Some(b), Some(k)) => read && plain && access.readable(b, k),
+                (Some(b), None) => *method == Method::GET && plain && access.has_readable(b),
+                _ => false,
+            }
+        });
+        if public || (read && !self.config.require_auth) {
+            return Ok(());
+        }
+        if self.config.require_auth || self.access.is_some() {
+            return Err(ProxyError::MissingAuth);
+        }
+        Ok(())
+    }
+
+    /// Whether the request carries `--retention-override-token`.
+    pub fn retention_override(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = self.config.retention_override_token.as_deref() else {
+            return false;
+        };
+        headers
+            .get(RETENTION_OVERRIDE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|provided| constant_time_compare(provided.trim(), token))
+    }
+
+    /// Refuses to overwrite or delete `key` while it is younger than the
+    /// `--retention-config` rule covering it says. Keys no rule covers are
+    /// passed without a Bunny call; new keys after one DESCRIBE. The
+    /// override token lets an emergency deletion through, with a warning.
+    async fn check_retention(&self, bucket: &str, key: &str, headers: &HeaderMap) -> Result<()> {
+        let Some(rule) = self
+            .retention
+            .as_ref()
+            .and_then(|rules| rules.rule_for(bucket, key))
+        else {
+            return Ok(());
+        };
+        let created = match self.bucket_client(bucket)?.describe(key).await {
+            Ok(obj) if obj.length >= 0 && !obj.is_directory => obj.date_created,
+            Ok(_) | Err(ProxyError::NotFound(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let until = rule.expires(created);
+        if Utc::now() >= until {
+            return Ok(());
+        }
+        if self.retention_override(headers) {
+            tracing::warn!(
+                "RETENTION OVERRIDE: {}/{} is protected by rule {} until {}, changed with the override token",
+                bucket,
+                key,
+                rule,
+                until.to_rfc3339()
+            );
+            return Ok(());
+        }
+        Err(ProxyError::RetentionActive(format!(
+            "rule {} protects {} until {}",
+            rule,
+            key,
+            until.to_rfc3339()
+        )))
+    }
+
+    /// Admits writing `size` bytes to `key` against the `--quota-config`
+    /// rules covering it, after one DESCRIBE for the size of the object it
+    /// replaces. A delete is a write of zero bytes. Keys no quota covers are
+    /// passed without a Bunny call.
+    async fn charge_quota(
+        &self,
+        bucket: &str,
+        key: &str,
+        size: u64,
+    ) -> Result<Option<QuotaCharge>> {
+        let Some(quotas) = self.quotas.as_ref().filter(|q| q.applies(bucket, key)) else {
+            return Ok(None);
+        };
+        let previous = match self.bucket_client(bucket)?.describe(key).await {
+            Ok(obj) if obj.length >= 0 && !obj.is_directory => obj.length as u64,
+            Ok(_) | Err(ProxyError::NotFound(_)) => 0,
+            Err(e) => return Err(e),
+        };
+        quotas.charge(bucket, key, previous, size).map(Some)
+    }
+
+    /// Records that a key charged with [`Self::charge_quota`] now holds
+    /// `size` bytes.
+    fn commit_quota(&self, charge: Option<QuotaCharge>, size: u64) {
+        if let (Some(quotas), Some(charge)) = (&self.quotas, charge) {
+            quotas.commit(charge, size);
+        }
+    }
+
+    /// Whether `bucket` is a folder of the main zone, as with
+    /// `--bucket-as-prefix` every bucket but the extra zones is.
+    fn is_bucket_folder(&self, bucket: &str) -> bool {
+        self.config.bucket_as_prefix && !self.zones.contains_key(bucket)
+    }
+
+    /// Whether the folder of `bucket` exists, with `--bucket-as-prefix`.
+    async fn bucket_folder_exists(&self, bucket: &str) -> Result<bool> {
+        Ok(self
+            .bucket_root
+            .list("")
+            .await?
+            .iter()
+            .any(|obj| obj.is_directory && obj.object_name == bucket))
+    }
+
+    /// Whether responses should report SSE-S3, either because objects really
+    /// are encrypted at rest or because `--claim-sse-s3` asks for it.
+    fn claims_sse(&self) -> bool {
+        self.config.claim_sse_s3 || self.encryption.is_some()
+    }
+
+    /// The keyring a new object is encrypted under: the customer's key for
+    /// SSE-C, otherwise the at-rest keyring if one is configured. The key's
+    /// MD5 comes along so the sidecar can record which key was used.
+    fn write_keyring(&self, headers: &HeaderMap) -> Result<(Option<Arc<Keyring>>, Option<String>)> {
+        Ok(match sse::CustomerKey::from_headers(headers, false)? {
+            Some(key) => (
+                Some(Arc::new(key.keyring())),
+                Some(key.key_md5().to_string()),
+            ),
+            None => (self.encryption.clone(), None),
+        })
+    }
+
+    /// The keyring to read an object with, checking the request's SSE-C
+    /// headers (or the copy-source ones) against the key it was stored with.
+    fn read_keyring(
+        &self,
+        headers: &HeaderMap,
+        enc: Option<&EncryptionMeta>,
+        copy_source: bool,
+    ) -> Result<Option<Arc<Keyring>>> {
+        let customer = sse::CustomerKey::from_headers(headers, copy_source)?;
+        match (enc.and_then(|e| e.customer_key_md5.as_deref()), customer) {
+            (Some(stored_md5), Some(key)) => {
+                key.check(stored_md5)?;
+                Ok(Some(Arc::new(key.keyring())))
+            }
+            (Some(_), None) => Err(ProxyError::InvalidRequest(
+                "The object was stored using a form of Server Side Encryption. The correct parameters must be provided to retrieve the object.".into(),
+            )),
+            (None, Some(_)) => Err(ProxyError::InvalidRequest(
+                "The encryption parameters are not applicable to this object.".into(),
+            )),
+            (None, None) => Ok(enc.and(self.encryption.clone())),
         }
     }
 
@@ -132,22 +876,263 @@ impl AppState {
     }
 }
 
+/// How long a successful HeadBucket probe is reused before asking Bunny again.
+const HEAD_BUCKET_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Largest accepted non-file field in a browser POST form.
+const MAX_POST_FORM_FIELD: u64 = 20 * 1024;
+
+const VERSION_ID_HEADER: &str = "x-amz-version-id";
+const DELETE_MARKER_HEADER: &str = "x-amz-delete-marker";
+const REQUEST_ID_HEADER: &str = "x-amz-request-id";
+
+/// The object size SDKs declare on CompleteMultipartUpload, checked against
+/// the sum of the parts.
+const MP_OBJECT_SIZE: &str = "x-amz-mp-object-size";
+
+/// How many parts an object read with `partNumber` has.
+const MP_PARTS_COUNT: &str = "x-amz-mp-parts-count";
+
+/// The rename extension's header, naming the source like `x-amz-copy-source`.
+pub const RENAME_SOURCE: &str = "x-bunny-rename-source";
+
+/// Carries `--retention-override-token` to change an object under retention.
+pub const RETENTION_OVERRIDE: &str = "x-bunny-retention-override";
+
+/// Upper bound and default for ListBuckets `max-buckets`.
+const MAX_LIST_BUCKETS: u32 = 10000;
+
+/// Number of metadata sidecars fetched concurrently while building a listing.
+const META_FETCH_CONCURRENCY: usize = 16;
+
+/// Number of concurrent Bunny DELETEs issued while purging a bucket.
+const BUCKET_PURGE_CONCURRENCY: usize = 16;
+
+/// Longest object key S3 accepts, in UTF-8 bytes.
+const MAX_KEY_LENGTH: usize = 1024;
+
+/// Maximum size of request bodies that are buffered in memory (XML payloads).
+const MAX_BUFFERED_BODY: u64 = 10 * 1024 * 1024;
+
+tokio::task_local! {
+    /// The `x-amz-request-id` of the request being handled on this task, for
+    /// handlers that report errors in a body streamed after the headers.
+    static REQUEST_ID: String;
+}
+
 pub async fn handle_s3_request(
     State(state): State<AppState>,
+    connect_info: Option<Extension<ConnectInfo<SocketAddr>>>,
+    client_cert: Option<Extension<Arc<ClientCert>>>,
     method: Method,
     uri: Uri,
     headers: HeaderMap,
     body: Body,
 ) -> Response {
-    let path = uri.path();
-    let (bucket, key) = parse_s3_path(path);
+    let (bucket, key) = parse_s3_path(uri.path());
+    let resource = (bucket.clone(), key.clone());
+    let sse_echo = sse::response_header(&method, &headers, key.is_some(), state.claims_sse());
+    let sse_c_echo = (state.config.sse_c && key.is_some())
+        .then(|| {
+            sse::CustomerKey::from_headers(&headers, false)
+                .ok()
+                .flatten()
+        })
+        .flatten();
+    let null_version = returns_null_version_id(&method, uri.query().unwrap_or(""), key.is_some());
+
+    let request_id = uuid::Uuid::new_v4().to_string();
+    let operation = operation_name(
+        &method,
+        bucket.is_some(),
+        key.is_some(),
+        uri.query().unwrap_or(""),
+        &headers,
+    );
+    let mut in_flight =
+        state
+            .activity
+            .register(&request_id, &operation, bucket.as_deref(), key.as_deref());
+    let body = in_flight.track_request(body);
+    let body = debug_http::inbound_request(&request_id, &operation, &method, &uri, &headers, body);
+
+    let span = tracing::info_span!(
+        "s3_request",
+        request_id = %request_id,
+        operation = %operation,
+        bucket = bucket.as_deref().unwrap_or(""),
+        key = key.as_deref().unwrap_or(""),
+        status = tracing::field::Empty,
+        conditional_writes = tracing::field::Empty,
+        client_cert = tracing::field::Empty,
+    );
+    if let Some(Extension(cert)) = &client_cert {
+        span.record("client_cert", cert.subject.as_str());
+    }
+    crate::telemetry::set_parent(&span, &headers);
+    let address_check = match (&state.ip_filter, &connect_info) {
+        (Some(filter), Some(Extension(ConnectInfo(peer)))) => filter.check_request(*peer, &headers),
+        _ => Ok(()),
+    };
+    let audit_log = Arc::clone(&state.audit);
+    let pending_audit = (audit_log.is_enabled()
+        && matches!(method, Method::PUT | Method::POST | Method::DELETE))
+    .then(|| AuditRecord {
+        timestamp: Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        request_id: request_id.clone(),
+        access_key_id: audit::access_key_id(&headers, uri.query().unwrap_or("")),
+        client_addr: connect_info.map(|Extension(ConnectInfo(addr))| addr.to_string()),
+        operation: operation.clone(),
+        bucket: bucket.clone(),
+        key: key.clone(),
+        size: 0,
+        checksum: audit::request_checksum(&headers),
+        etag: None,
+        status: 0,
+        error: None,
+        retention_override: state.retention_override(&headers),
+    });
+
+    let cert_check = tls::check_key_mapping(
+        &state.config.map_client_cert_to_key,
+        client_cert.as_ref().map(|Extension(cert)| cert.as_ref()),
+        audit::access_key_id(&headers, uri.query().unwrap_or("")).as_deref(),
+    );
+    #[cfg(feature = "chaos")]
+    let chaos_target = (operation.clone(), key.clone().unwrap_or_default());
+    let panics = Arc::clone(&state.panics);
+    let dispatched = dispatch_request(state, method, uri, headers, bucket, key, body);
+    #[cfg(feature = "chaos")]
+    let dispatched = async move {
+        let (operation, key) = chaos_target;
+        crate::chaos::before_request(&operation, &key).await?;
+        let response = dispatched.await?;
+        Ok::<_, ProxyError>(crate::chaos::after_request(&operation, &key, response))
+    };
+    let dispatched = async {
+        address_check?;
+        cert_check?;
+        panics.catch(dispatched).await
+    };
+    let dispatched = REQUEST_ID.scope(request_id.clone(), dispatched);
+    let result = accounting::scope(in_flight.upstream(), dispatched.instrument(span.clone())).await;
+    let status = match &result {
+        Ok(r) => r.status().as_u16(),
+        Err(e) => e.status_code().as_u16(),
+    };
+    span.record("status", status);
+    in_flight.set_status(status);
+    if let Ok(r) = &result
+        && let Some(stats) = r.extensions().get::<CompressionStats>()
+    {
+        in_flight.set_compression(stats.original, stats.stored);
+    }
+
+    if let Some(mut record) = pending_audit {
+        record.size = in_flight.bytes_in();
+        record.status = status;
+        match &result {
+            Ok(r) => {
+                record.etag = r
+                    .headers()
+                    .get(header::ETAG)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
+            }
+            Err(e) => record.error = Some(e.s3_error_code().to_string()),
+        }
+        audit_log.record(record);
+    }
+
+    let response = match result {
+        Ok(mut r) => {
+            if let Some(customer_key) = sse_c_echo
+                && r.status().is_success()
+            {
+                for (name, value) in sse::customer_response_headers(customer_key.key_md5()) {
+                    if let Ok(value) = HeaderValue::from_str(&value) {
+                        r.headers_mut().insert(name, value);
+                    }
+                }
+            } else if let Some(sse) = sse_echo
+                && r.status().is_success()
+            {
+                r.headers_mut().insert(sse::SSE_HEADER, sse);
+            }
+            if null_version
+                && r.status().is_success()
+                && !r.headers().contains_key(VERSION_ID_HEADER)
+            {
+                r.headers_mut()
+                    .insert(VERSION_ID_HEADER, HeaderValue::from_static(NULL_VERSION_ID));
+            }
+            if !r.headers().contains_key(REQUEST_ID_HEADER)
+                && let Ok(value) = HeaderValue::from_str(&request_id)
+            {
+                r.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            r
+        }
+        Err(e) => e
+            .with_resource(resource.0.as_deref(), resource.1.as_deref())
+            .with_request_id(request_id.clone())
+            .into_response(),
+    };
+    debug_http::inbound_response(&request_id, response.status(), response.headers());
+    response.map(|body| in_flight.track_response(panics.guard_body(body)))
+}
+
+async fn dispatch_request(
+    mut state: AppState,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    bucket: Option<String>,
+    key: Option<String>,
+    body: Body,
+) -> Result<Response> {
+    sse::validate_request_headers(&headers, state.config.sse_c)?;
+
+    if let Some(b) = bucket.as_deref()
+        && let Ok(bunny) = state.bucket_client(b)
+    {
+        state.bunny = bunny;
+    }
+    if let Some(k) = key.as_deref() {
+        validate_key(k)?;
+        state.check_key(k)?;
+    }
+
+    if let Some(k) = key.as_deref()
+        && !state.is_versioned(k)
+        && let Some(version_id) = requested_version_id(uri.query().unwrap_or(""))
+        && version_id != NULL_VERSION_ID
+    {
+        return Err(ProxyError::NoSuchVersion(version_id));
+    }
 
     let payload_hash = headers
         .get("x-amz-content-sha256")
         .and_then(|v| v.to_str().ok())
         .map(|s| s.to_string());
 
-    let has_auth = headers.get("authorization").is_some();
+    // Signed in its headers or, presigned, in its query.
+    let has_auth = !is_anonymous(&headers, &uri);
+    let browser_post = is_browser_post(
+        &method,
+        &headers,
+        bucket.as_deref(),
+        key.as_deref(),
+        uri.query().unwrap_or(""),
+    );
+    if !has_auth && !browser_post {
+        state.check_anonymous(
+            &method,
+            bucket.as_deref(),
+            key.as_deref(),
+            uri.query().unwrap_or(""),
+        )?;
+    }
     let content_length: Option<u64> = headers
         .get(header::CONTENT_LENGTH)
         .and_then(|v| v.to_str().ok())
@@ -156,56 +1141,132 @@ pub async fn handle_s3_request(
     let query = uri.query().unwrap_or("");
     let is_multipart_part = query.contains("partNumber") && query.contains("uploadId");
 
-    if method == Method::PUT && bucket.is_some() && key.is_some() {
+    // Object writes and, with filter-reads, reads are held to the key rules,
+    // as are the subresources of a key: SelectObjectContent and GETs read
+    // it, anything else changes it. Parts follow an initiation that already
+    // was; completions, which must name the key of their initiation, are
+    // checked again as the rules may have been reloaded meanwhile.
+    if let (Some(rules), Some(b), Some(k)) = (&state.key_rules, bucket.as_deref(), key.as_deref()) {
+        match (&method, Subresource::from_query(query)) {
+            (&Method::GET | &Method::HEAD, Some(_))
+            | (&Method::POST, Some(Subresource::Select)) => rules.check_read(b, k)?,
+            (_, Some(_)) => rules.check_write(b, k)?,
+            (&Method::PUT, None) if !query.contains("uploadId") => rules.check_write(b, k)?,
+            (&Method::POST, None) if query.contains("uploads") || query.contains("uploadId") => {
+                rules.check_write(b, k)?
+            }
+            (&Method::GET | &Method::HEAD, None) if !query.contains("uploadId") => {
+                rules.check_read(b, k)?
+            }
+            _ => {}
+        }
+    }
+
+    // Completing an upload re-encrypts the parts, but S3 clients never send
+    // the customer key with CompleteMultipartUpload.
+    if sse::has_customer_headers(&headers)
+        && (query.contains("uploads") || query.contains("uploadId"))
+    {
+        return Err(ProxyError::NotImplemented(
+            "Multipart uploads with customer-provided encryption keys".to_string(),
+        ));
+    }
+
+    if browser_post && let Some(b) = bucket.as_deref() {
+        return handle_post_object(state, b, &headers, body).await;
+    }
+
+    if method == Method::PUT
+        && let (Some(b), Some(k)) = (bucket.as_deref(), key.as_deref())
+        && Subresource::from_query(query).is_none()
+    {
         if has_auth {
             let hash_for_sig = payload_hash.as_deref().unwrap_or(UNSIGNED_PAYLOAD);
-            if let Err(e) = state
+            let access_key = state
                 .auth
-                .verify_request(&method, &uri, &headers, hash_for_sig)
-            {
-                return e.into_response();
-            }
+                .verify_request(&method, &uri, &headers, hash_for_sig)?;
+            check_key_policy(
+                &state,
+                &access_key,
+                &method,
+                &uri,
+                &headers,
+                bucket.as_deref(),
+                key.as_deref(),
+                &[],
+            )?;
         }
 
-        if is_multipart_part {
-            return match handle_upload_part_stream(
-                state,
-                bucket.as_deref().unwrap(),
-                query,
-                body,
-                content_length,
-            )
-            .await
-            {
-                Ok(r) => r,
-                Err(e) => e.into_response(),
+        // Copies and renames carry no body; their source is read from Bunny.
+        if headers.contains_key(RENAME_SOURCE) {
+            return handle_rename_object(state, b, k, &headers).await;
+        }
+        if headers.contains_key("x-amz-copy-source") {
+            return match is_multipart_part {
+                true => handle_upload_part_copy(state, b, query, &headers).await,
+                false => handle_copy_object(state, b, k, &headers).await,
             };
         }
 
-        let verify_hash = payload_hash.filter(|h| h != UNSIGNED_PAYLOAD);
-        return match handle_put_object_stream(
-            state,
-            bucket.as_deref().unwrap(),
-            key.as_deref().unwrap(),
-            &headers,
-            body,
-            content_length,
-            verify_hash,
-        )
-        .await
-        {
-            Ok(r) => r,
-            Err(e) => e.into_response(),
+        // aws-chunked bodies are stored without their framing, so the object
+        // is as long as the decoded length says.
+        let framing = chunked::Framing::of(payload_hash.as_deref())?;
+        let content_length = match framing {
+            Some(_) => headers
+                .get(chunked::DECODED_CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|s| s.parse().ok()),
+            None => content_length,
+        };
+        let content_length = match content_length {
+            Some(len) => len,
+            None => return Err(ProxyError::MissingContentLength),
+        };
+        if content_length > state.config.max_object_size {
+            return Err(ProxyError::EntityTooLarge(state.config.max_object_size));
+        }
+
+        let (body, decoding, verify_hash) = match framing {
+            Some(framing) => {
+                let signer = match framing {
+                    chunked::Framing::Signed if headers.contains_key(header::AUTHORIZATION) => {
+                        Some(state.auth.chunk_signer(&headers)?)
+                    }
+                    _ => None,
+                };
+                let trailer = chunked::trailer_checksum(&headers)?;
+                let (body, decoding) = chunked::decode(body, framing, signer, trailer);
+                (body, Some(decoding), None)
+            }
+            None => (body, None, payload_hash.filter(|h| h != UNSIGNED_PAYLOAD)),
+        };
+
+        let result = if is_multipart_part {
+            handle_upload_part_stream(state, b, query, &headers, body, Some(content_length)).await
+        } else {
+            handle_put_object_stream(
+                state,
+                b,
+                k,
+                &headers,
+                body,
+                Some(content_length),
+                verify_hash,
+            )
+            .await
+        };
+        return match decoding {
+            Some(decoding) => decoding.check(result),
+            None => result,
         };
     }
 
-    let body_bytes = match axum::body::to_bytes(body, 10 * 1024 * 1024).await {
-        Ok(b) => b,
-        Err(e) => {
-            return ProxyError::InvalidRequest(format!("Failed to read body: {}", e))
-                .into_response();
-        }
-    };
+    if content_length.is_some_and(|len| len > MAX_BUFFERED_BODY) {
+        return Err(ProxyError::EntityTooLarge(MAX_BUFFERED_BODY));
+    }
+    // Held until the request has been handled, not just read.
+    let mut reservation = state.buffers.reserve(content_length.unwrap_or(0)).await?;
+    let body_bytes = read_buffered_body(body, &mut reservation).await?;
 
     let payload_hash = payload_hash.unwrap_or_else(|| {
         if body_bytes.is_empty() {
@@ -215,40 +1276,211 @@ pub async fn handle_s3_request(
         }
     });
 
-    if has_auth
-        && let Err(e) = state
+    if has_auth {
+        let access_key = state
             .auth
-            .verify_request(&method, &uri, &headers, &payload_hash)
-    {
-        return e.into_response();
+            .verify_request(&method, &uri, &headers, &payload_hash)?;
+        check_key_policy(
+            &state,
+            &access_key,
+            &method,
+            &uri,
+            &headers,
+            bucket.as_deref(),
+            key.as_deref(),
+            &body_bytes,
+        )?;
+    }
+
+    route_request(state, method, uri, headers, bucket, key, body_bytes).await
+}
+
+/// Reads a body of at most [`MAX_BUFFERED_BODY`] bytes into memory, growing
+/// `reservation` as the bytes arrive.
+async fn read_buffered_body(body: Body, reservation: &mut Reservation) -> Result<Bytes> {
+    let mut stream = body.into_data_stream();
+    let mut buffer = BytesMut::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk =
+            chunk.map_err(|e| ProxyError::InvalidRequest(format!("Failed to read body: {}", e)))?;
+        let len = (buffer.len() + chunk.len()) as u64;
+        if len > MAX_BUFFERED_BODY {
+            return Err(ProxyError::EntityTooLarge(MAX_BUFFERED_BODY));
+        }
+        reservation.grow_to(len)?;
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(buffer.freeze())
+}
+
+/// Whether a request carries no signature, in its headers or its query.
+fn is_anonymous(headers: &HeaderMap, uri: &Uri) -> bool {
+    !headers.contains_key(header::AUTHORIZATION)
+        && !uri
+            .query()
+            .is_some_and(|q| q.contains("X-Amz-Signature") || q.contains("Signature="))
+}
+
+/// Refuses a signed request that the policy of `access_key`, the key its
+/// signature was verified with, does not allow. Copies and renames also
+/// need to read their source, and a rename to delete it; DeleteObjects
+/// needs to delete every key in `body`.
+#[allow(clippy::too_many_arguments)]
+fn check_key_policy(
+    state: &AppState,
+    access_key: &str,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    bucket: Option<&str>,
+    key: Option<&str>,
+    body: &[u8],
+) -> Result<()> {
+    let Some(policies) = &state.key_policies else {
+        return Ok(());
+    };
+    let query = uri.query().unwrap_or("");
+    let check = |method: &Method, access: Access<'_>| policies.check(access_key, method, access);
+    let param = |name: &str| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+
+    let Some(bucket) = bucket else {
+        return check(method, Access::Service);
+    };
+    if let Some(key) = key {
+        check(method, Access::Object { bucket, key })?;
+        for (header, methods) in [
+            ("x-amz-copy-source", &[Method::GET][..]),
+            (RENAME_SOURCE, &[Method::GET, Method::DELETE][..]),
+        ] {
+            if let Some(source) = headers
+                .get(header)
+                .and_then(|v| v.to_str().ok())
+                .and_then(CopySource::parse)
+            {
+                let access = Access::Object {
+                    bucket: &source.bucket,
+                    key: &source.key,
+                };
+                for method in methods {
+                    check(method, access)?;
+                }
+            }
+        }
+        return Ok(());
     }
 
-    match route_request(state, method, uri, headers, bucket, key, body_bytes).await {
-        Ok(r) => r,
-        Err(e) => e.into_response(),
+    let subresource = Subresource::from_query(query);
+    match *method {
+        Method::POST if query.contains("delete") => {
+            // A body that does not parse is refused by the handler.
+            let Ok(req) = xml::parse_request_body::<DeleteRequest>(body) else {
+                return Ok(());
+            };
+            req.object.iter().try_for_each(|obj| {
+                check(
+                    &Method::DELETE,
+                    Access::Object {
+                        bucket,
+                        key: &obj.key,
+                    },
+                )
+            })
+        }
+        Method::HEAD => check(method, Access::Bucket(bucket)),
+        Method::GET if param("location").is_some() && subresource.is_none() => {
+            check(method, Access::Bucket(bucket))
+        }
+        Method::GET
+            if matches!(subresource, None | Some(Subresource::Versions))
+                || param("uploads").is_some() =>
+        {
+            let prefix = param("prefix").unwrap_or_default();
+            check(
+                method,
+                Access::Listing {
+                    bucket,
+                    prefix: &prefix,
+                },
+            )
+        }
+        _ => check(method, Access::Settings(bucket)),
     }
 }
 
+/// Browser POST uploads are authorized by the signed policy in their form.
+/// Only a form posted to the bucket itself, with no key or query, is one;
+/// any other POST is held to the usual checks whatever its Content-Type.
+fn is_browser_post(
+    method: &Method,
+    headers: &HeaderMap,
+    bucket: Option<&str>,
+    key: Option<&str>,
+    query: &str,
+) -> bool {
+    *method == Method::POST
+        && bucket.is_some()
+        && key.is_none()
+        && query.is_empty()
+        && headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|ct| ct.starts_with("multipart/form-data"))
+}
+
+/// Splits a request path into bucket and key, percent-decoding both. `+`
+/// stays a literal plus, as in S3 paths.
 fn parse_s3_path(path: &str) -> (Option<String>, Option<String>) {
     let path = path.trim_start_matches('/');
     if path.is_empty() {
         return (None, None);
     }
+    let decode = |s: &str| {
+        percent_encoding::percent_decode_str(s)
+            .decode_utf8_lossy()
+            .into_owned()
+    };
     let parts: Vec<&str> = path.splitn(2, '/').collect();
     match parts.len() {
-        1 => (Some(parts[0].to_string()), None),
+        1 => (Some(decode(parts[0])), None),
         2 => {
             let key = parts[1];
             if key.is_empty() {
-                (Some(parts[0].to_string()), None)
+                (Some(decode(parts[0])), None)
             } else {
-                (Some(parts[0].to_string()), Some(key.to_string()))
+                (Some(decode(parts[0])), Some(decode(key)))
             }
         }
         _ => (None, None),
     }
 }
 
+/// Rejects keys S3 would not accept: empty, longer than [`MAX_KEY_LENGTH`]
+/// bytes, or containing control characters, which Bunny stores but no XML
+/// listing can return. Keys are otherwise free-form and left as they are.
+fn validate_key(key: &str) -> Result<()> {
+    if key.is_empty() {
+        return Err(ProxyError::InvalidArgument(
+            "Object keys must not be empty".into(),
+        ));
+    }
+    if key.len() > MAX_KEY_LENGTH {
+        return Err(ProxyError::KeyTooLong {
+            length: key.len(),
+            max: MAX_KEY_LENGTH,
+        });
+    }
+    if key.chars().any(|c| c.is_control()) {
+        return Err(ProxyError::InvalidArgument(
+            "Object keys must not contain control characters".into(),
+        ));
+    }
+    Ok(())
+}
+
 async fn route_request(
     state: AppState,
     method: Method,
@@ -260,19 +1492,82 @@ async fn route_request(
 ) -> Result<Response> {
     let query = uri.query().unwrap_or("");
 
-    match (&method, bucket.as_deref(), key.as_deref()) {
-        (&Method::GET, None, None) => handle_list_buckets(state).await,
+    let allowed = allowed_methods(bucket.is_some());
+    if !allowed.split(", ").any(|m| m == method.as_str()) {
+        return Err(ProxyError::MethodNotAllowed {
+            method: method.to_string(),
+            allowed,
+        });
+    }
+
+    // ListObjectVersions is a listing rather than a bucket configuration document
+    if method == Method::GET
+        && let (Some(b), None, Some(Subresource::Versions)) = (
+            bucket.as_deref(),
+            key.as_deref(),
+            Subresource::from_query(query),
+        )
+    {
+        return handle_list_object_versions(state, b, query).await;
+    }
+
+    if let (Some(b), Some(subresource)) = (bucket.as_deref(), Subresource::from_query(query)) {
+        return handle_subresource(
+            state,
+            &method,
+            subresource,
+            b,
+            key.as_deref(),
+            &headers,
+            body,
+        )
+        .await;
+    }
+
+    if method == Method::GET
+        && let Some(b) = bucket.as_deref()
+        && let Some(prefix) = html_listing_prefix(&state, b, key.as_deref(), query, &headers)
+    {
+        return handle_html_listing(state, b, &prefix, query).await;
+    }
+
+    let cache_policies = state.cache_policies.clone();
+    let mut response = match (&method, bucket.as_deref(), key.as_deref()) {
+        (&Method::GET, None, None) => handle_list_buckets(state, query).await,
         (&Method::HEAD, Some(b), None) => handle_head_bucket(state, b).await,
         (&Method::GET, Some(b), None) if query.contains("uploads") => {
             handle_list_multipart_uploads(state, b, query).await
         }
-        (&Method::GET, Some(b), None) => handle_list_objects_v2(state, b, &uri).await,
-        (&Method::PUT, Some(b), None) => handle_create_bucket(b).await,
-        (&Method::DELETE, Some(_), None) => {
-            Err(ProxyError::InvalidRequest("Cannot delete bucket".into()))
+        (&Method::GET, Some(b), None) => {
+            // Unsigned callers only see what the access rules open to them.
+            let public_only = match is_anonymous(&headers, &uri) && state.config.require_auth {
+                true => state.access.clone(),
+                false => None,
+            };
+            match query.split('&').any(|param| param == "list-type=2") {
+                true => handle_list_objects_v2(state, b, &uri, public_only.as_deref()).await,
+                false => handle_list_objects_v1(state, b, query, public_only.as_deref()).await,
+            }
         }
+        (&Method::PUT, Some(b), None) => handle_create_bucket(state, b, body).await,
+        (&Method::DELETE, Some(b), None) => handle_delete_bucket(state, b).await,
 
-        (&Method::HEAD, Some(b), Some(k)) => handle_head_object(state, b, k).await,
+        (&Method::GET | &Method::HEAD, Some(b), Some(k))
+            if query.contains("partNumber") && !query.contains("uploadId") =>
+        {
+            handle_get_object_part(state, &method, b, k, query, &headers).await
+        }
+        (&Method::GET | &Method::HEAD, Some(b), Some(k))
+            if state.is_versioned(k) && !query.contains("uploadId") =>
+        {
+            match requested_version_id(query) {
+                Some(version_id) => {
+                    handle_get_object_version(state, &method, b, k, &version_id, &headers).await
+                }
+                None => handle_get_current_version(state, &method, b, k, &headers).await,
+            }
+        }
+        (&Method::HEAD, Some(b), Some(k)) => handle_head_object(state, b, k, &headers).await,
         (&Method::GET, Some(b), Some(k)) if query.contains("uploadId") => {
             handle_list_parts(state, b, k, query).await
         }
@@ -284,15 +1579,17 @@ async fn route_request(
         (&Method::DELETE, Some(_), Some(_)) if query.contains("uploadId") => {
             handle_abort_multipart_upload(state, query).await
         }
-        (&Method::DELETE, Some(b), Some(k)) => handle_delete_object(state, b, k).await,
+        (&Method::DELETE, Some(b), Some(k)) => {
+            handle_delete_object(state, b, k, query, &headers).await
+        }
         (&Method::POST, Some(b), None) if query.contains("delete") => {
-            handle_delete_objects(state, b, body).await
+            handle_delete_objects(state, b, &headers, body).await
         }
         (&Method::POST, Some(b), Some(k)) if query.contains("uploads") => {
-            handle_initiate_multipart_upload(state, b, k).await
+            handle_initiate_multipart_upload(state, b, k, &headers).await
         }
         (&Method::POST, Some(b), Some(k)) if query.contains("uploadId") => {
-            handle_complete_multipart_upload(state, b, k, query, body).await
+            handle_complete_multipart_upload(state, b, k, query, &headers, body).await
         }
 
         _ => Err(ProxyError::InvalidRequest(format!(
@@ -300,760 +1597,7752 @@ async fn route_request(
             method,
             uri.path()
         ))),
+    }?;
+
+    // Objects read back get their cache policy; listings and errors never do
+    if let (Some(policies), Some(b), Some(k)) = (cache_policies, bucket.as_deref(), key.as_deref())
+        && matches!(method, Method::GET | Method::HEAD)
+        && !query.contains("uploadId")
+    {
+        policies.apply(b, k, &mut response);
     }
+    Ok(response)
 }
 
-async fn handle_list_buckets(state: AppState) -> Result<Response> {
-    let buckets = vec![S3Bucket {
-        name: state.config.storage_zone.clone(),
-        creation_date: Utc::now(),
-    }];
-    let owner = S3Owner {
-        id: state.auth.access_key_id().to_string(),
-        display_name: state.auth.access_key_id().to_string(),
-    };
-    Ok((
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/xml")],
-        xml::list_buckets_response(&buckets, &owner),
-    )
-        .into_response())
-}
+async fn handle_subresource(
+    state: AppState,
+    method: &Method,
+    subresource: Subresource,
+    bucket: &str,
+    key: Option<&str>,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
 
-async fn handle_head_bucket(state: AppState, bucket: &str) -> Result<Response> {
-    if bucket != state.config.storage_zone {
-        return Err(ProxyError::BucketNotFound(bucket.to_string()));
+    match (method, subresource, key) {
+        (&Method::GET, Subresource::Versioning, None) => handle_get_bucket_versioning(state).await,
+        (&Method::PUT, Subresource::Versioning, None) => {
+            handle_put_bucket_versioning(state, body).await
+        }
+        (&Method::GET, Subresource::Acl, None) => handle_get_acl(state).await,
+        (&Method::PUT, Subresource::Acl, None) => handle_put_acl(bucket, headers, body).await,
+        (&Method::GET, Subresource::Lifecycle, None) => {
+            handle_get_bucket_lifecycle(state, bucket).await
+        }
+        (&Method::PUT, Subresource::Lifecycle, None) => {
+            handle_put_bucket_lifecycle(state, bucket, body).await
+        }
+        (&Method::DELETE, Subresource::Lifecycle, None) => {
+            BucketConfigStore::delete(&state.bunny, bucket, LIFECYCLE_CONFIG).await?;
+            Ok((StatusCode::NO_CONTENT, "").into_response())
+        }
+        (&Method::GET, Subresource::Tagging, None) => {
+            let body = BucketConfigStore::get(&state.bunny, bucket, BUCKET_TAGGING_CONFIG)
+                .await?
+                .ok_or(ProxyError::NoSuchTagSet)?;
+            Ok((
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, "application/xml")],
+                body,
+            )
+                .into_response())
+        }
+        (&Method::PUT, Subresource::Tagging, None) => {
+            handle_put_bucket_tagging(state, bucket, body).await
+        }
+        (&Method::DELETE, Subresource::Tagging, None) => {
+            BucketConfigStore::delete(&state.bunny, bucket, BUCKET_TAGGING_CONFIG).await?;
+            Ok((StatusCode::NO_CONTENT, "").into_response())
+        }
+        (&Method::GET, Subresource::Encryption, None) => {
+            if !state.claims_sse() {
+                return Err(ProxyError::NoSuchEncryptionConfiguration);
+            }
+            xml_response(xml::encryption_configuration_response(
+                sse::SSE_S3_ALGORITHM,
+            ))
+        }
+        (&Method::GET, Subresource::PublicAccessBlock, None) => {
+            if state.config.omit_public_access_block {
+                return Err(ProxyError::NoSuchPublicAccessBlockConfiguration);
+            }
+            xml_response(xml::public_access_block_response())
+        }
+        (&Method::GET, Subresource::OwnershipControls, None) => {
+            xml_response(xml::ownership_controls_response("BucketOwnerEnforced"))
+        }
+        // Configuration the proxy accepts but has no way to apply
+        (
+            &Method::PUT,
+            Subresource::Encryption
+            | Subresource::PublicAccessBlock
+            | Subresource::OwnershipControls,
+            None,
+        ) => Ok((StatusCode::OK, "").into_response()),
+        (
+            &Method::DELETE,
+            Subresource::Encryption
+            | Subresource::PublicAccessBlock
+            | Subresource::OwnershipControls,
+            None,
+        ) => Ok((StatusCode::NO_CONTENT, "").into_response()),
+        (&Method::GET, Subresource::Policy, None) => {
+            let body = BucketConfigStore::get(&state.bunny, bucket, BUCKET_POLICY_CONFIG)
+                .await?
+                .ok_or(ProxyError::NoSuchBucketPolicy)?;
+            Ok((
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, "application/json")],
+                body,
+            )
+                .into_response())
+        }
+        (&Method::PUT, Subresource::Policy, None) => {
+            handle_put_bucket_policy(state, bucket, body).await
+        }
+        (&Method::DELETE, Subresource::Policy, None) => {
+            BucketConfigStore::delete(&state.bunny, bucket, BUCKET_POLICY_CONFIG).await?;
+            Ok((StatusCode::NO_CONTENT, "").into_response())
+        }
+        (&Method::GET, Subresource::PolicyStatus, None) => {
+            xml_response(xml::policy_status_response(false))
+        }
+        (&Method::GET, Subresource::Acl, Some(k)) => {
+            ensure_object_exists(&state, k).await?;
+            handle_get_acl(state).await
+        }
+        (&Method::PUT, Subresource::Acl, Some(k)) => {
+            ensure_object_exists(&state, k).await?;
+            handle_put_acl(k, headers, body).await
+        }
+        // Every storage class the proxy accepts is online, so a restore is a no-op
+        (&Method::POST, Subresource::Restore, Some(k)) => {
+            ensure_object_exists(&state, k).await?;
+            Ok((
+                StatusCode::OK,
+                [("x-amz-restore", "ongoing-request=\"false\"")],
+                "",
+            )
+                .into_response())
+        }
+        (&Method::POST, Subresource::Select, Some(k)) => {
+            handle_select_object_content(state, bucket, k, headers, body).await
+        }
+        (&Method::GET, Subresource::Attributes, Some(k)) => {
+            handle_get_object_attributes(state, k, headers).await
+        }
+        _ => Err(ProxyError::NotImplemented(
+            subresource.operation(method, key.is_some()),
+        )),
     }
-    state.bunny.list("").await?;
-    Ok((
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/xml")],
-        "",
-    )
-        .into_response())
 }
 
-async fn handle_create_bucket(_bucket: &str) -> Result<Response> {
-    Ok((StatusCode::OK, "").into_response())
+fn xml_response(body: String) -> Result<Response> {
+    Ok(compressible(
+        (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/xml")],
+            body,
+        )
+            .into_response(),
+    ))
 }
 
-async fn handle_list_objects_v2(state: AppState, bucket: &str, uri: &Uri) -> Result<Response> {
-    if bucket != state.config.storage_zone {
-        return Err(ProxyError::BucketNotFound(bucket.to_string()));
+/// The `versionId` a request names. Outside `--emulate-versioning` prefixes
+/// every object has exactly one version whose ID is the literal `null`, as S3
+/// reports for unversioned objects.
+fn requested_version_id(query: &str) -> Option<String> {
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == "versionId")
+        .map(|(_, v)| v.into_owned())
+}
+
+/// Whether a successful response should carry `x-amz-version-id: null`: object
+/// writes and deletes always do, reads only when a version was asked for.
+fn returns_null_version_id(method: &Method, query: &str, has_key: bool) -> bool {
+    if !has_key || Subresource::from_query(query).is_some() {
+        return false;
+    }
+    let is_multipart = query.contains("uploadId");
+    match *method {
+        Method::PUT | Method::DELETE => !is_multipart,
+        Method::POST => is_multipart,
+        Method::GET | Method::HEAD => requested_version_id(query).is_some(),
+        _ => false,
     }
+}
 
-    let query: ListObjectsV2Query = uri
-        .query()
-        .map(|q| serde_urlencoded::from_str(q).unwrap_or_default())
-        .unwrap_or_default();
+async fn handle_list_object_versions(
+    state: AppState,
+    bucket: &str,
+    query: &str,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+
+    let query: ListObjectVersionsQuery = serde_urlencoded::from_str(query).unwrap_or_default();
     let prefix = query.prefix.as_deref().unwrap_or("");
     let delimiter = query.delimiter.as_deref();
     let max_keys = query.max_keys.unwrap_or(1000).min(1000);
 
-    let objects = if delimiter.is_some() {
-        state.bunny.list(prefix).await?
-    } else {
-        state
-            .bunny
-            .list_recursive(prefix, Some(max_keys as usize + 1))
-            .await?
-    };
-
-    let mut s3_objects = Vec::new();
-    let mut common_prefixes_set = HashSet::new();
-
-    for obj in &objects {
-        let key = obj.s3_key();
-        if !key.starts_with(prefix) {
-            continue;
-        }
+    let Listing {
+        mut objects,
+        mut common_prefixes,
+        keys_with_meta,
+    } = list_bucket(&state, prefix, delimiter, None, max_keys as usize + 1).await?;
 
-        if let Some(delim) = delimiter {
-            let suffix = &key[prefix.len()..];
-            if let Some(pos) = suffix.find(delim) {
-                common_prefixes_set.insert(format!("{}{}{}", prefix, &suffix[..pos], delim));
+    if let Some(marker) = &query.key_marker {
+        objects.retain(|o| o.key.as_str() >= marker.as_str());
+    }
+    let metas = apply_object_meta(&state, &mut objects, &keys_with_meta).await?;
+    let mut versions: Vec<S3ObjectVersion> = objects
+        .into_iter()
+        .map(|o| S3ObjectVersion {
+            version_id: metas
+                .get(&o.key)
+                .and_then(|m| m.version_id.clone())
+                .unwrap_or_else(|| NULL_VERSION_ID.to_string()),
+            is_latest: true,
+            delete_marker: false,
+            key: o.key,
+            last_modified: o.last_modified,
+            etag: o.etag,
+            size: o.size,
+            storage_class: o.storage_class,
+        })
+        .collect();
+    if !state.config.emulate_versioning.is_empty() {
+        for entry in VersionStore::list(&state.bunny, prefix).await? {
+            if let Some(delim) = delimiter
+                && let Some(pos) = entry.key[prefix.len()..].find(delim)
+            {
+                let end = prefix.len() + pos + delim.len();
+                common_prefixes.insert(entry.key[..end].to_string());
                 continue;
             }
+            versions.push(S3ObjectVersion {
+                key: entry.key,
+                version_id: entry.version_id,
+                is_latest: false,
+                delete_marker: entry.delete_marker,
+                last_modified: entry.last_modified,
+                etag: entry.etag,
+                size: entry.size,
+                storage_class: object_meta::DEFAULT_STORAGE_CLASS.to_string(),
+            });
         }
+    }
 
-        if obj.is_directory {
-            if delimiter.is_some() {
-                common_prefixes_set.insert(if key.ends_with('/') {
-                    key.clone()
-                } else {
-                    format!("{}/", key)
-                });
-            }
-            continue;
+    // Each key's versions newest first, the `null` version oldest of all. A
+    // key without a current object is headed by its latest delete marker.
+    let order = |v: &S3ObjectVersion| match v.version_id.as_str() {
+        NULL_VERSION_ID => String::new(),
+        id => id.to_string(),
+    };
+    versions.sort_by(|a, b| {
+        a.key
+            .cmp(&b.key)
+            .then(b.is_latest.cmp(&a.is_latest))
+            .then(order(b).cmp(&order(a)))
+    });
+    versions.dedup_by(|b, a| a.key == b.key && a.version_id == b.version_id);
+    for i in 0..versions.len() {
+        if i == 0 || versions[i - 1].key != versions[i].key {
+            versions[i].is_latest = true;
         }
-
-        s3_objects.push(S3Object {
-            key,
-            last_modified: obj.last_changed,
-            etag: obj.etag(),
-            size: obj.length.max(0),
-            storage_class: "STANDARD".to_string(),
-            owner: None,
-        });
     }
 
-    if let Some(start_after) = &query.start_after {
-        s3_objects.retain(|o| o.key.as_str() > start_after.as_str());
+    if let Some(marker) = &query.key_marker {
+        let after_marker = query.version_id_marker.as_deref().and_then(|id| {
+            versions
+                .iter()
+                .position(|v| &v.key == marker && v.version_id == id)
+                .map(|i| i + 1)
+        });
+        let start = after_marker.unwrap_or_else(|| {
+            versions
+                .iter()
+                .position(|v| v.key.as_str() > marker.as_str())
+                .unwrap_or(versions.len())
+        });
+        versions.drain(..start);
     }
-    s3_objects.sort_by(|a, b| a.key.cmp(&b.key));
+    let is_truncated = versions.len() > max_keys as usize;
+    versions.truncate(max_keys as usize);
 
-    let is_truncated = s3_objects.len() > max_keys as usize;
-    let s3_objects: Vec<_> = s3_objects.into_iter().take(max_keys as usize).collect();
-    let next_token = if is_truncated {
-        s3_objects.last().map(|o| o.key.clone())
-    } else {
-        None
+    let (next_key_marker, next_version_id_marker) = match versions.last() {
+        Some(last) if is_truncated => (Some(last.key.as_str()), Some(last.version_id.as_str())),
+        _ => (None, None),
     };
-    let common_prefixes: Vec<S3CommonPrefix> = common_prefixes_set
+    let mut common_prefixes: Vec<S3CommonPrefix> = common_prefixes
         .into_iter()
         .map(|p| S3CommonPrefix { prefix: p })
         .collect();
+    common_prefixes.sort_by(|a, b| a.prefix.cmp(&b.prefix));
 
-    Ok((
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/xml")],
-        xml::list_objects_v2_response(xml::ListObjectsV2Params {
+    xml_response(xml::list_object_versions_response(
+        xml::ListObjectVersionsParams {
             bucket,
-            prefix: Some(prefix),
+            prefix,
             delimiter,
+            key_marker: query.key_marker.as_deref(),
+            version_id_marker: query.version_id_marker.as_deref(),
             max_keys,
-            objects: &s3_objects,
+            versions: &versions,
             common_prefixes: &common_prefixes,
             is_truncated,
-            next_continuation_token: next_token.as_deref(),
-            key_count: s3_objects.len() as u32,
-            continuation_token: query.continuation_token.as_deref(),
-            start_after: query.start_after.as_deref(),
-        }),
+            next_key_marker,
+            next_version_id_marker,
+            owner: &owner(&state),
+        },
+    ))
+}
+
+/// Reports versioning as enabled while `--emulate-versioning` covers some
+/// prefixes, and as never configured otherwise.
+async fn handle_get_bucket_versioning(state: AppState) -> Result<Response> {
+    let status = match state.config.emulate_versioning.is_empty() {
+        true => None,
+        false => Some("Enabled"),
+    };
+    xml_response(xml::versioning_configuration_response(status))
+}
+
+/// Enabling is accepted only when `--emulate-versioning` already applies;
+/// which prefixes are versioned is decided by the flag, not the request.
+async fn handle_put_bucket_versioning(state: AppState, body: Bytes) -> Result<Response> {
+    let config: VersioningConfiguration = xml::parse_request_body(&body)?;
+    match config.status.as_deref() {
+        None | Some("Suspended") => Ok((StatusCode::OK, "").into_response()),
+        Some("Enabled") if !state.config.emulate_versioning.is_empty() => {
+            Ok((StatusCode::OK, "").into_response())
+        }
+        Some("Enabled") => Err(ProxyError::NotImplemented(
+            "Enabling bucket versioning".to_string(),
+        )),
+        Some(other) => Err(ProxyError::MalformedXml(format!(
+            "Invalid versioning status: {}",
+            other
+        ))),
+    }
+}
+
+async fn handle_get_acl(state: AppState) -> Result<Response> {
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/xml")],
+        xml::access_control_policy_response(&owner(&state)),
     )
         .into_response())
 }
 
-async fn handle_head_object(state: AppState, bucket: &str, key: &str) -> Result<Response> {
-    if bucket != state.config.storage_zone {
-        return Err(ProxyError::BucketNotFound(bucket.to_string()));
-    }
-    let obj = state.bunny.describe(key).await?;
+/// Accepts canned ACLs, grant headers or an ACL document without applying them;
+/// access is governed by the proxy's credentials, not per-resource ACLs.
+async fn handle_put_acl(resource: &str, headers: &HeaderMap, body: Bytes) -> Result<Response> {
+    let canned = headers.get("x-amz-acl").and_then(|v| v.to_str().ok());
+    let has_grants = headers
+        .keys()
+        .any(|name| name.as_str().starts_with("x-amz-grant-"));
 
-    // Bunny returns Length: -1 for non-existent files, or isDirectory for folders
-    if obj.length < 0 || obj.is_directory {
-        return Err(ProxyError::NotFound(key.to_string()));
-    }
+    let grants_public = if body.is_empty() {
+        false
+    } else {
+        let policy: AccessControlPolicy = xml::parse_request_body(&body)?;
+        policy.grants_public_access()
+    };
 
-    let mut r = Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_LENGTH, obj.length)
-        .header(header::CONTENT_TYPE, &obj.content_type)
-        .header(
-            header::LAST_MODIFIED,
-            obj.last_changed
-                .format("%a, %d %b %Y %H:%M:%S GMT")
-                .to_string(),
-        )
-        .header(header::ETAG, format!("\"{}\"", obj.etag()));
-    if let Some(checksum) = &obj.checksum {
-        r = r.header("x-amz-checksum-sha256", checksum);
+    if canned.is_some_and(|acl| acl != "private") || has_grants || grants_public {
+        tracing::warn!(
+            "Ignoring non-private ACL on {} (canned: {}); ACLs are not enforced",
+            resource,
+            canned.unwrap_or("none")
+        );
     }
-    Ok(r.body(Body::empty()).unwrap())
+
+    Ok((StatusCode::OK, "").into_response())
 }
 
-async fn handle_get_object(
+async fn handle_get_bucket_lifecycle(state: AppState, bucket: &str) -> Result<Response> {
+    let body = BucketConfigStore::get(&state.bunny, bucket, LIFECYCLE_CONFIG)
+        .await?
+        .ok_or(ProxyError::NoSuchLifecycleConfiguration)?;
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/xml")],
+        body,
+    )
+        .into_response())
+}
+
+async fn handle_put_bucket_lifecycle(
     state: AppState,
     bucket: &str,
-    key: &str,
-    headers: &HeaderMap,
+    body: Bytes,
 ) -> Result<Response> {
-    if bucket != state.config.storage_zone {
-        return Err(ProxyError::BucketNotFound(bucket.to_string()));
-    }
+    let config: LifecycleConfiguration = xml::parse_request_body(&body)?;
+    config.validate().map_err(ProxyError::InvalidRequest)?;
+    BucketConfigStore::put(&state.bunny, bucket, LIFECYCLE_CONFIG, body).await?;
+    tracing::info!(
+        "Stored lifecycle configuration for {} with {} rule(s)",
+        bucket,
+        config.rule.len()
+    );
+    Ok((StatusCode::OK, "").into_response())
+}
 
-    // Forward Range header to Bunny to avoid buffering entire file
-    let range_header = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
-    let download = state.bunny.download_range(key, range_header).await?;
-
-    let content_length = download.content_length();
-    let content_type = download
-        .content_type()
-        .unwrap_or("application/octet-stream")
-        .to_string();
-    let etag = download.etag();
-    let last_modified = download.last_modified();
-    let is_partial = download.status() == StatusCode::PARTIAL_CONTENT;
-    let content_range = download.content_range();
+async fn handle_put_bucket_tagging(state: AppState, bucket: &str, body: Bytes) -> Result<Response> {
+    let tagging: Tagging = xml::parse_request_body(&body)?;
+    tagging.validate().map_err(ProxyError::InvalidTag)?;
+    let normalized = xml::tagging_response(&tagging.tag_set.tag);
+    BucketConfigStore::put(
+        &state.bunny,
+        bucket,
+        BUCKET_TAGGING_CONFIG,
+        Bytes::from(normalized),
+    )
+    .await?;
+    Ok((StatusCode::NO_CONTENT, "").into_response())
+}
 
-    // Handle If-None-Match conditional request
-    if let Some(if_none_match) = headers
-        .get(header::IF_NONE_MATCH)
-        .and_then(|v| v.to_str().ok())
-        && let Some(server_etag) = &etag
-    {
-        let server_etag_normalized = server_etag.trim_matches('"');
-        let matches = if_none_match == "*"
-            || if_none_match.split(',').any(|e| {
-                e.trim()
-                    .trim_matches('"')
-                    .trim_start_matches("W/")
-                    .trim_matches('"')
-                    == server_etag_normalized
-            });
-        if matches {
-            let mut r = Response::builder()
-                .status(StatusCode::NOT_MODIFIED)
-                .header(header::ETAG, format!("\"{}\"", server_etag_normalized));
-            if let Some(lm) = &last_modified {
-                r = r.header(header::LAST_MODIFIED, lm);
-            }
-            return Ok(r.body(Body::empty()).unwrap());
-        }
+/// Stores the policy as an inert sidecar; the proxy never evaluates it.
+async fn handle_put_bucket_policy(state: AppState, bucket: &str, body: Bytes) -> Result<Response> {
+    if state.config.reject_bucket_policy {
+        return Err(ProxyError::NotImplemented("PutBucketPolicy".to_string()));
     }
-
-    // Handle partial content (range request forwarded to Bunny)
-    if is_partial {
-        let mut r = Response::builder()
-            .status(StatusCode::PARTIAL_CONTENT)
-            .header(header::CONTENT_TYPE, &content_type)
-            .header(header::ACCEPT_RANGES, "bytes");
-        if let Some(len) = content_length {
-            r = r.header(header::CONTENT_LENGTH, len);
-        }
-        if let Some(range) = content_range {
-            r = r.header(header::CONTENT_RANGE, range);
-        }
-        if let Some(etag) = etag {
-            r = r.header(header::ETAG, format!("\"{}\"", etag.trim_matches('"')));
-        }
-        if let Some(lm) = last_modified {
-            r = r.header(header::LAST_MODIFIED, lm);
-        }
-        return Ok(r.body(Body::from_stream(download.bytes_stream())).unwrap());
+    let policy: serde_json::Value =
+        serde_json::from_slice(&body).map_err(|e| ProxyError::MalformedPolicy(e.to_string()))?;
+    if !policy.is_object() {
+        return Err(ProxyError::MalformedPolicy(
+            "policy document must be a JSON object".to_string(),
+        ));
     }
+    tracing::warn!("Storing bucket policy for {} without enforcing it", bucket);
+    BucketConfigStore::put(&state.bunny, bucket, BUCKET_POLICY_CONFIG, body).await?;
+    Ok((StatusCode::NO_CONTENT, "").into_response())
+}
 
-    // Full response
-    let mut r = Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, content_type)
-        .header(header::ACCEPT_RANGES, "bytes");
-    if let Some(size) = content_length {
-        r = r.header(header::CONTENT_LENGTH, size);
-    }
-    if let Some(etag) = etag {
-        r = r.header(header::ETAG, format!("\"{}\"", etag.trim_matches('"')));
-    }
-    if let Some(lm) = last_modified {
-        r = r.header(header::LAST_MODIFIED, lm);
+async fn ensure_object_exists(state: &AppState, key: &str) -> Result<()> {
+    let obj = state.bunny.describe(key).await?;
+    if obj.length < 0 || obj.is_directory {
+        return Err(ProxyError::NotFound(key.to_string()));
     }
+    Ok(())
+}
 
-    Ok(r.body(Body::from_stream(download.bytes_stream())).unwrap())
+fn owner(state: &AppState) -> S3Owner {
+    S3Owner {
+        id: state.auth.access_key_id().to_string(),
+        display_name: state.auth.access_key_id().to_string(),
+    }
 }
 
-async fn handle_put_object(
-    state: AppState,
-    bucket: &str,
-    key: &str,
-    headers: &HeaderMap,
-    body: Bytes,
-) -> Result<Response> {
-    if bucket != state.config.storage_zone {
-        return Err(ProxyError::BucketNotFound(bucket.to_string()));
+async fn handle_list_buckets(state: AppState, query: &str) -> Result<Response> {
+    let query: ListBucketsQuery = serde_urlencoded::from_str(query).unwrap_or_default();
+    let max_buckets = query.max_buckets.unwrap_or(MAX_LIST_BUCKETS) as usize;
+    if !(1..=MAX_LIST_BUCKETS as usize).contains(&max_buckets) {
+        return Err(ProxyError::InvalidRequest(format!(
+            "max-buckets must be between 1 and {}",
+            MAX_LIST_BUCKETS
+        )));
     }
 
-    let is_conditional = headers
-        .get(header::IF_NONE_MATCH)
-        .and_then(|v| v.to_str().ok())
-        .is_some_and(|v| v.trim() == "*");
-
-    let _lock_guard = if is_conditional {
-        match state.lock.try_lock(key).await {
-            Some(guard) => {
-                if state.bunny.describe(key).await.is_ok() {
-                    return Ok(Response::builder()
-                        .status(StatusCode::PRECONDITION_FAILED)
-                        .body(Body::empty())
-                        .unwrap());
-                }
-                Some(guard)
-            }
-            None => {
-                return Ok(Response::builder()
-                    .status(StatusCode::CONFLICT)
-                    .body(Body::from("Concurrent write in progress"))
-                    .unwrap());
-            }
+    let region = state.config.region.code().to_string();
+    let mut buckets: Vec<S3Bucket> = if state.config.bucket_as_prefix {
+        state
+            .bucket_root
+            .list("")
+            .await?
+            .into_iter()
+            .filter(|obj| obj.is_directory && is_valid_bucket_name(&obj.object_name))
+            .filter(|obj| !state.zones.contains_key(&obj.object_name))
+            .map(|obj| S3Bucket {
+                name: obj.object_name,
+                creation_date: obj.date_created,
+                region: region.clone(),
+            })
+            .collect()
+    } else {
+        vec![S3Bucket {
+            name: state.config.storage_zone.clone(),
+            creation_date: Utc::now(),
+            region,
+        }]
+    };
+    buckets.extend(state.config.extra_zone.iter().map(|zone| {
+        S3Bucket {
+            name: zone.name.clone(),
+            creation_date: Utc::now(),
+            region: zone
+                .region
+                .unwrap_or(state.config.region)
+                .code()
+                .to_string(),
         }
+    }));
+    buckets.retain(|b| {
+        query.prefix.as_ref().is_none_or(|p| b.name.starts_with(p.as_str()))
+            && query.bucket_region.as_ref().is_none_or(|r| b.region == *r)
+            // The continuation token is the name of the last bucket already returned
+            && query
+                .continuation_token
+                .as_ref()
+                .is_none_or(|t| b.name.as_str() > t.as_str())
+    });
+    buckets.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let next_token = if buckets.len() > max_buckets {
+        buckets.truncate(max_buckets);
+        buckets.last().map(|b| b.name.clone())
     } else {
         None
     };
 
-    let options = UploadOptions {
-        content_type: headers
-            .get(header::CONTENT_TYPE)
-            .and_then(|v| v.to_str().ok())
-            .map(|s| s.to_string()),
-        sha256_checksum: headers
-            .get("x-amz-checksum-sha256")
-            .and_then(|v| v.to_str().ok())
-            .map(|s| s.to_string()),
-    };
-    state.bunny.upload(key, body.clone(), options).await?;
+    xml_response(xml::list_buckets_response(
+        &buckets,
+        &owner(&state),
+        query.prefix.as_deref(),
+        next_token.as_deref(),
+    ))
+}
+
+async fn handle_head_bucket(state: AppState, bucket: &str) -> Result<Response> {
+    state.check_bucket(bucket)?;
+
+    let cached = state
+        .bucket_verified_at
+        .lock()
+        .unwrap()
+        .is_some_and(|at| at.elapsed() < HEAD_BUCKET_CACHE_TTL);
+    let main_zone = !state.zones.contains_key(bucket);
+    if state.is_bucket_folder(bucket) {
+        if !state.bucket_folder_exists(bucket).await? {
+            return Err(ProxyError::BucketNotFound(bucket.to_string()));
+        }
+    } else if !(cached && main_zone) {
+        // DESCRIBE of the zone root is cheap regardless of how many entries it holds;
+        // a 404 still proves the zone and key are valid, while a bad key yields 401.
+        match state.bunny.describe("").await {
+            Ok(_) | Err(ProxyError::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+        if main_zone {
+            *state.bucket_verified_at.lock().unwrap() = Some(Instant::now());
+        }
+    }
 
-    use md5::Digest;
-    let etag = format!("{:x}", md5::Md5::digest(&body));
     Ok((
         StatusCode::OK,
-        [(header::ETAG, format!("\"{}\"", etag))],
+        [
+            (header::CONTENT_TYPE, "application/xml"),
+            (
+                header::HeaderName::from_static("x-amz-bucket-region"),
+                state.config.region.code(),
+            ),
+            (
+                header::HeaderName::from_static("x-amz-access-point-alias"),
+                "false",
+            ),
+        ],
         "",
     )
         .into_response())
 }
 
-async fn handle_put_object_stream(
-    state: AppState,
-    bucket: &str,
-    key: &str,
-    headers: &HeaderMap,
-    body: Body,
-    content_length: Option<u64>,
-    claimed_hash: Option<String>,
-) -> Result<Response> {
-    if bucket != state.config.storage_zone {
-        return Err(ProxyError::BucketNotFound(bucket.to_string()));
+async fn handle_create_bucket(state: AppState, bucket: &str, body: Bytes) -> Result<Response> {
+    if !is_valid_bucket_name(bucket) {
+        return Err(ProxyError::InvalidBucketName(bucket.to_string()));
+    }
+    if !state.config.bucket_as_prefix
+        && bucket != state.config.storage_zone
+        && !state.zones.contains_key(bucket)
+    {
+        return Err(ProxyError::BucketNotProvisioned(bucket.to_string()));
     }
 
-    let is_conditional = headers
-        .get(header::IF_NONE_MATCH)
-        .and_then(|v| v.to_str().ok())
-        .is_some_and(|v| v.trim() == "*");
-
-    let _lock_guard = if is_conditional {
-        match state.lock.try_lock(key).await {
-            Some(guard) => {
-                if state.bunny.describe(key).await.is_ok() {
-                    return Ok(Response::builder()
-                        .status(StatusCode::PRECONDITION_FAILED)
-                        .body(Body::empty())
-                        .unwrap());
-                }
-                Some(guard)
-            }
-            None => {
-                return Ok(Response::builder()
-                    .status(StatusCode::CONFLICT)
-                    .body(Body::from("Concurrent write in progress"))
-                    .unwrap());
-            }
-        }
-    } else {
-        None
-    };
-
-    let stream = body.into_data_stream();
-    let stream = stream.map(|r| r.map_err(std::io::Error::other));
-
-    let computed_hash = if let Some(ref expected) = claimed_hash {
-        let (hashing_stream, hash_rx) = HashingStream::new_sha256(stream);
-        state
-            .bunny
-            .upload_stream(key, hashing_stream, content_length)
-            .await?;
-
-        let computed = hash_rx.await.map_err(|_| {
-            ProxyError::InvalidRequest("Failed to compute content hash".to_string())
-        })?;
-
-        if computed != *expected {
-            tracing::warn!(
-                "Content hash mismatch for {}: expected {}, got {}",
-                key,
-                expected,
-                computed
+    // Bunny zones live in a single region chosen at provisioning time, so any
+    // LocationConstraint is only checked for well-formedness.
+    if !body.is_empty() {
+        let config: CreateBucketConfiguration = xml::parse_request_body(&body)?;
+        if let Some(location) = config.location_constraint {
+            tracing::debug!(
+                "Ignoring LocationConstraint {} for bucket {}",
+                location,
+                bucket
             );
-            let _ = state.bunny.delete(key).await;
-            return Err(ProxyError::InvalidRequest(
-                "Content hash mismatch".to_string(),
-            ));
         }
-        Some(computed)
-    } else {
-        state
-            .bunny
-            .upload_stream(key, stream, content_length)
-            .await?;
-        None
-    };
+    }
 
-    let etag = computed_hash
-        .or_else(|| content_length.map(|l| format!("{:x}", l)))
-        .unwrap_or_else(|| "streaming".to_string());
+    let exists = !state.is_bucket_folder(bucket) || state.bucket_folder_exists(bucket).await?;
+    if exists && state.config.create_bucket_conflict {
+        return Err(ProxyError::BucketAlreadyOwnedByYou(bucket.to_string()));
+    }
+    if !exists {
+        state.bucket_root.create_directory(bucket).await?;
+    }
 
     Ok((
         StatusCode::OK,
-        [(header::ETAG, format!("\"{}\"", etag))],
+        [(header::LOCATION, format!("/{}", bucket))],
         "",
     )
         .into_response())
 }
 
-async fn handle_delete_object(state: AppState, bucket: &str, key: &str) -> Result<Response> {
-    if bucket != state.config.storage_zone {
+/// Deleting the zone itself is a Bunny account operation, so DeleteBucket only
+/// reports whether the zone is empty, optionally emptying it first. With
+/// `--bucket-as-prefix` the bucket's folder is removed once it is empty.
+async fn handle_delete_bucket(state: AppState, bucket: &str) -> Result<Response> {
+    state.check_bucket(bucket)?;
+    if state.is_bucket_folder(bucket) && !state.bucket_folder_exists(bucket).await? {
         return Err(ProxyError::BucketNotFound(bucket.to_string()));
     }
-    state.bunny.delete(key).await?;
-    Ok((StatusCode::NO_CONTENT, "").into_response())
-}
 
-async fn handle_copy_object(
-    state: AppState,
-    bucket: &str,
-    key: &str,
-    headers: &HeaderMap,
-) -> Result<Response> {
-    if bucket != state.config.storage_zone {
-        return Err(ProxyError::BucketNotFound(bucket.to_string()));
+    if state.config.allow_bucket_purge {
+        purge_bucket(&state, bucket).await?;
+        remove_bucket_folder(&state, bucket).await?;
+        return Ok(StatusCode::NO_CONTENT.into_response());
     }
 
-    let copy_source = headers
-        .get("x-amz-copy-source")
-        .and_then(|v| v.to_str().ok())
-        .ok_or_else(|| ProxyError::InvalidRequest("Missing x-amz-copy-source".into()))?;
-    let source = CopySource::parse(copy_source)
-        .ok_or_else(|| ProxyError::InvalidRequest("Invalid copy source".into()))?;
-    if source.bucket != state.config.storage_zone {
-        return Err(ProxyError::BucketNotFound(source.bucket));
+    let entries = state.bunny.list("").await?;
+    let has_objects = entries.iter().any(|obj| {
+        let key = obj.s3_key();
+        !BucketConfigStore::is_internal_key(&key)
+            && !MultipartManager::is_internal_key(&key)
+            && !ObjectMetaStore::is_internal_key(&key)
+            && !VersionStore::is_internal_key(&key)
+            && !state.trash.as_ref().is_some_and(|t| t.contains(&key))
+    });
+    if has_objects {
+        return Err(ProxyError::BucketNotEmpty(bucket.to_string()));
     }
+    remove_bucket_folder(&state, bucket).await?;
 
-    state.bunny.copy(&source.key, key).await?;
-    let obj = state.bunny.describe(key).await?;
-
-    Ok((
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/xml")],
-        xml::copy_object_response(&obj.etag(), obj.last_changed),
-    )
-        .into_response())
+    Ok(StatusCode::NO_CONTENT.into_response())
 }
 
-async fn handle_delete_objects(state: AppState, bucket: &str, body: Bytes) -> Result<Response> {
-    if bucket != state.config.storage_zone {
-        return Err(ProxyError::BucketNotFound(bucket.to_string()));
+/// Deletes the folder of an empty bucket, along with any sidecars, staged
+/// parts and configuration left in it.
+async fn remove_bucket_folder(state: &AppState, bucket: &str) -> Result<()> {
+    if !state.is_bucket_folder(bucket) {
+        return Ok(());
     }
+    state.bucket_root.delete(&format!("{}/", bucket)).await
+}
 
-    let req: DeleteRequest = quick_xml::de::from_str(
-        std::str::from_utf8(&body).map_err(|e| ProxyError::InvalidRequest(e.to_string()))?,
-    )
-    .map_err(|e| ProxyError::InvalidRequest(e.to_string()))?;
-    let quiet = req.quiet.unwrap_or(false);
-    let mut deleted = Vec::new();
-    let mut errors = Vec::new();
+async fn purge_bucket(state: &AppState, bucket: &str) -> Result<()> {
+    let objects = state.bunny.list_recursive("", None).await?;
+    let total = objects.len();
+    tracing::warn!(
+        "DeleteBucket purge of {} requested: deleting {} objects",
+        bucket,
+        total
+    );
 
-    for obj in req.object {
-        match state.bunny.delete(&obj.key).await {
-            Ok(_) => deleted.push((obj.key, obj.version_id)),
-            Err(e) => errors.push((obj.key, "InternalError".to_string(), e.to_string())),
+    let mut results = futures::stream::iter(objects)
+        .map(|obj| {
+            let bunny = state.bunny.clone();
+            async move { bunny.delete(&obj.s3_key()).await }
+        })
+        .buffer_unordered(BUCKET_PURGE_CONCURRENCY);
+
+    let mut deleted = 0;
+    while let Some(result) = results.next().await {
+        result?;
+        deleted += 1;
+        if deleted % 1000 == 0 {
+            tracing::info!(
+                "DeleteBucket purge of {}: {}/{} deleted",
+                bucket,
+                deleted,
+                total
+            );
         }
     }
 
-    Ok((
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/xml")],
-        xml::delete_objects_response(&deleted, &errors, quiet),
-    )
-        .into_response())
+    tracing::warn!(
+        "DeleteBucket purge of {} finished: {} objects deleted",
+        bucket,
+        deleted
+    );
+    Ok(())
 }
 
-async fn handle_initiate_multipart_upload(
-    state: AppState,
-    bucket: &str,
-    key: &str,
-) -> Result<Response> {
-    if bucket != state.config.storage_zone {
-        return Err(ProxyError::BucketNotFound(bucket.to_string()));
-    }
-    let upload_id = MultipartManager::create(&state.bunny, bucket, key).await?;
-    Ok((
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/xml")],
-        xml::initiate_multipart_upload_response(bucket, key, &upload_id),
-    )
-        .into_response())
+/// S3 bucket naming rules: 3-63 chars of lowercase letters, digits, dots and
+/// hyphens, starting and ending with a letter or digit.
+pub(crate) fn is_valid_bucket_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    (3..=63).contains(&bytes.len())
+        && bytes
+            .iter()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || *b == b'.' || *b == b'-')
+        && bytes[0].is_ascii_alphanumeric()
+        && bytes[bytes.len() - 1].is_ascii_alphanumeric()
+        && !name.contains("..")
 }
 
-async fn handle_upload_part_stream(
-    state: AppState,
-    bucket: &str,
-    query: &str,
-    body: Body,
-    content_length: Option<u64>,
-) -> Result<Response> {
-    if bucket != state.config.storage_zone {
-        return Err(ProxyError::BucketNotFound(bucket.to_string()));
-    }
+/// Objects and common prefixes under a prefix, with the proxy's internal keys hidden.
+struct Listing {
+    objects: Vec<S3Object>,
+    common_prefixes: HashSet<String>,
+    keys_with_meta: HashSet<String>,
+}
 
-    let params: std::collections::HashMap<String, String> =
-        serde_urlencoded::from_str(query).unwrap_or_default();
-    let upload_id = params
-        .get("uploadId")
-        .ok_or_else(|| ProxyError::InvalidRequest("Missing uploadId".into()))?;
-    let part_number: i32 = params
-        .get("partNumber")
-        .and_then(|s| s.parse().ok())
-        .ok_or_else(|| ProxyError::InvalidRequest("Invalid partNumber".into()))?;
+/// Whether `key` belongs to the proxy's own bookkeeping, hidden from listings.
+fn is_hidden_key(state: &AppState, key: &str) -> bool {
+    BucketConfigStore::is_internal_key(key)
+        || MultipartManager::is_internal_key(key)
+        || ObjectMetaStore::is_internal_key(key)
+        || VersionStore::is_internal_key(key)
+        || state.trash.as_ref().is_some_and(|t| t.contains(key))
+}
 
-    let path = format!("__multipart/{}/{:05}", upload_id, part_number);
+/// The objects and, with a delimiter, common prefixes under `prefix`.
+/// Without a delimiter the objects are the first `limit` after
+/// `start_after`, in key order, from the listing index when it is fresh and
+/// otherwise from a walk that resumes after `start_after`.
+async fn list_bucket(
+    state: &AppState,
+    prefix: &str,
+    delimiter: Option<&str>,
+    start_after: Option<&str>,
+    limit: usize,
+) -> Result<Listing> {
+    let (objects, keys_with_meta) = if delimiter.is_some() {
+        let (objects, keys_with_meta) = tokio::join!(
+            state.bunny.list(prefix),
+            ObjectMetaStore::keys_with_meta(&state.bunny, prefix, false)
+        );
+        (objects?, keys_with_meta?)
+    } else {
+        let skip = |key: &str| is_hidden_key(state, key);
+        let indexed = state
+            .bunny
+            .indexed_listing(prefix, start_after, limit, &skip);
+        let objects = async {
+            match indexed {
+                Some(objects) => Ok(objects),
+                None => {
+                    state
+                        .bunny
+                        .list_recursive_after(prefix, start_after, limit, &skip)
+                        .await
+                }
+            }
+        };
+        // Only the page's own keys need their sidecars looked up.
+        let objects = objects.await?;
+        let keys: Vec<String> = objects.iter().map(|obj| obj.s3_key()).collect();
+        let keys_with_meta = ObjectMetaStore::keys_with_meta_among(&state.bunny, &keys).await?;
+        (objects, keys_with_meta)
+    };
 
-    let stream = body.into_data_stream();
-    let stream = stream.map(|r| r.map_err(std::io::Error::other));
-    let (hashing_stream, hash_rx) = HashingStream::new_md5(stream);
+    let mut s3_objects = Vec::new();
+    let mut common_prefixes = HashSet::new();
 
-    state
-        .bunny
-        .upload_stream(&path, hashing_stream, content_length)
+    for obj in &objects {
+        let key = obj.s3_key();
+        if !key.starts_with(prefix) || is_hidden_key(state, &key) {
+            continue;
+        }
+
+        if let Some(delim) = delimiter {
+            let suffix = &key[prefix.len()..];
+            if let Some(pos) = suffix.find(delim) {
+                common_prefixes.insert(format!("{}{}{}", prefix, &suffix[..pos], delim));
+                continue;
+            }
+        }
+
+        if obj.is_directory {
+            if delimiter.is_some() {
+                common_prefixes.insert(if key.ends_with('/') {
+                    key.clone()
+                } else {
+                    format!("{}/", key)
+                });
+            }
+            continue;
+        }
+
+        s3_objects.push(S3Object {
+            key,
+            last_modified: obj.last_changed,
+            etag: obj.etag(),
+            size: obj.length.max(0),
+            storage_class: object_meta::DEFAULT_STORAGE_CLASS.to_string(),
+            owner: None,
+        });
+    }
+
+    Ok(Listing {
+        objects: s3_objects,
+        common_prefixes,
+        keys_with_meta,
+    })
+}
+
+/// Applies sidecar metadata to listed objects that have one: the storage
+/// class, and the original size and ETag of objects stored encrypted or
+/// compressed. Returns the sidecars read, by key.
+async fn apply_object_meta(
+    state: &AppState,
+    objects: &mut [S3Object],
+    keys_with_meta: &HashSet<String>,
+) -> Result<HashMap<String, ObjectMeta>> {
+    let keys: Vec<String> = objects
+        .iter()
+        .filter(|o| keys_with_meta.contains(&o.key))
+        .map(|o| o.key.clone())
+        .collect();
+    let metas: HashMap<String, ObjectMeta> = futures::stream::iter(keys)
+        .map(|key| {
+            let bunny = state.bunny.clone();
+            async move {
+                let meta = ObjectMetaStore::get(&bunny, &key).await?;
+                Ok::<_, ProxyError>((key, meta))
+            }
+        })
+        .buffer_unordered(META_FETCH_CONCURRENCY)
+        .try_collect()
         .await?;
+    for obj in objects {
+        if let Some(meta) = metas.get(&obj.key) {
+            obj.storage_class = meta.storage_class().to_string();
+            if let Some((size, etag)) = meta.original() {
+                obj.size = size as i64;
+                obj.etag = etag.to_string();
+            }
+        }
+    }
+    Ok(metas)
+}
 
-    let etag = hash_rx
-        .await
-        .map_err(|_| ProxyError::InvalidRequest("Failed to compute ETag".to_string()))?;
+/// One page of a bucket listing, as ListObjects and the HTML index show it.
+struct ListPage {
+    objects: Vec<S3Object>,
+    /// The page's common prefixes, sorted.
+    common_prefixes: Vec<String>,
+    is_truncated: bool,
+    /// The last key or common prefix of a truncated page, which the next
+    /// page starts after.
+    next_token: Option<String>,
+}
 
-    MultipartManager::store_part_etag(&state.bunny, upload_id, part_number, &etag).await?;
+/// Lists up to `max_keys` objects and common prefixes under `prefix` after
+/// `start_after`, in key order. With `public_only`, only what the access
+/// rules open to unsigned callers.
+async fn list_page(
+    state: &AppState,
+    bucket: &str,
+    prefix: &str,
+    delimiter: Option<&str>,
+    max_keys: u32,
+    start_after: Option<&str>,
+    public_only: Option<&AccessRules>,
+) -> Result<ListPage> {
+    let Listing {
+        objects: mut s3_objects,
+        mut common_prefixes,
+        keys_with_meta,
+    } = list_bucket(state, prefix, delimiter, start_after, max_keys as usize + 1).await?;
+    if let Some(access) = public_only {
+        s3_objects.retain(|o| access.readable(bucket, &o.key));
+        common_prefixes.retain(|p| access.leads_to_readable(bucket, p));
+    }
 
-    Ok((
-        StatusCode::OK,
-        [(header::ETAG, format!("\"{}\"", etag))],
-        "",
-    )
-        .into_response())
+    if let Some(start_after) = start_after {
+        s3_objects.retain(|o| o.key.as_str() > start_after);
+        common_prefixes.retain(|p| p.as_str() > start_after);
+    }
+    s3_objects.sort_by(|a, b| a.key.cmp(&b.key));
+    let mut common_prefixes: Vec<String> = common_prefixes.into_iter().collect();
+    common_prefixes.sort();
+
+    // Objects and common prefixes share the page, in key order.
+    let mut entries: Vec<&str> = s3_objects
+        .iter()
+        .map(|o| o.key.as_str())
+        .chain(common_prefixes.iter().map(String::as_str))
+        .collect();
+    entries.sort_unstable();
+    let is_truncated = entries.len() > max_keys as usize;
+    let next_token = match is_truncated {
+        true => (max_keys as usize)
+            .checked_sub(1)
+            .map(|last| entries[last].to_string()),
+        false => None,
+    };
+    if is_truncated {
+        let on_page = |key: &str| next_token.as_deref().is_some_and(|last| key <= last);
+        s3_objects.retain(|o| on_page(&o.key));
+        common_prefixes.retain(|p| on_page(p));
+    }
+
+    let mut objects = s3_objects;
+    apply_object_meta(state, &mut objects, &keys_with_meta).await?;
+    Ok(ListPage {
+        objects,
+        common_prefixes,
+        is_truncated,
+        next_token,
+    })
 }
 
-async fn handle_complete_multipart_upload(
+async fn handle_list_objects_v2(
     state: AppState,
     bucket: &str,
-    key: &str,
-    query: &str,
-    body: Bytes,
+    uri: &Uri,
+    public_only: Option<&AccessRules>,
 ) -> Result<Response> {
-    use axum::body::Body;
-
-    if bucket != state.config.storage_zone {
-        return Err(ProxyError::BucketNotFound(bucket.to_string()));
-    }
+    state.check_bucket(bucket)?;
 
-    let params: std::collections::HashMap<String, String> =
-        serde_urlencoded::from_str(query).unwrap_or_default();
-    let upload_id = params
-        .get("uploadId")
-        .ok_or_else(|| ProxyError::InvalidRequest("Missing uploadId".into()))?
-        .clone();
+    let query: ListObjectsV2Query = uri
+        .query()
+        .map(|q| serde_urlencoded::from_str(q).unwrap_or_default())
+        .unwrap_or_default();
+    let prefix = query.prefix.as_deref().unwrap_or("");
+    let delimiter = query.delimiter.as_deref();
+    let max_keys = query.max_keys.unwrap_or(1000).min(1000);
+    // A continuation token resumes after its cursor; `start-after` only
+    // matters on the first page.
+    let cursor = match query.continuation_token.as_deref() {
+        Some(token) => Some(decode_continuation_token(token)?),
+        None => None,
+    };
+    let start_after = cursor
+        .as_deref()
+        .or(query.start_after.as_deref())
+        .filter(|s| !s.is_empty());
 
-    let req: CompleteMultipartUpload = quick_xml::de::from_str(
-        std::str::from_utf8(&body).map_err(|e| ProxyError::InvalidRequest(e.to_string()))?,
+    let ListPage {
+        objects: s3_objects,
+        common_prefixes,
+        is_truncated,
+        next_token,
+    } = list_page(
+        &state,
+        bucket,
+        prefix,
+        delimiter,
+        max_keys,
+        start_after,
+        public_only,
     )
-    .map_err(|e| ProxyError::InvalidRequest(e.to_string()))?;
-    let parts: Vec<(i32, String)> = req
-        .part
+    .await?;
+    let next_token = next_token.as_deref().map(encode_continuation_token);
+    let common_prefixes: Vec<S3CommonPrefix> = common_prefixes
         .into_iter()
-        .map(|p| (p.part_number, p.etag))
+        .map(|p| S3CommonPrefix { prefix: p })
         .collect();
 
-    let bucket = bucket.to_string();
-    let key = key.to_string();
-    let region_base_url = state.config.region.base_url().to_string();
-
-    let (tx, rx) = tokio::sync::mpsc::channel::<std::result::Result<Bytes, std::io::Error>>(16);
-
-    tokio::spawn(async move {
-        let _ = tx
-            .send(Ok(Bytes::from(
-                "<?xml version=\"1.0\" encoding=\"UTF-8\"?><!-- ",
-            )))
-            .await;
+    xml_response(xml::list_objects_v2_response(xml::ListObjectsV2Params {
+        bucket,
+        prefix: Some(prefix),
+        delimiter,
+        max_keys,
+        objects: &s3_objects,
+        common_prefixes: &common_prefixes,
+        is_truncated,
+        next_continuation_token: next_token.as_deref(),
+        key_count: s3_objects.len() as u32,
+        continuation_token: query.continuation_token.as_deref(),
+        start_after: query.start_after.as_deref(),
+    }))
+}
 
-        let keepalive_tx = tx.clone();
-        let keepalive_handle = tokio::spawn(async move {
-            loop {
-                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-                if keepalive_tx.send(Ok(Bytes::from(" "))).await.is_err() {
-                    break;
-                }
-            }
-        });
+/// The NextContinuationToken of a page ending at `cursor`, the last key or
+/// common prefix it holds. Clients treat it as opaque.
+fn encode_continuation_token(cursor: &str) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(cursor)
+}
 
-        let result =
-            MultipartManager::complete(&state.bunny, &bucket, &upload_id, &key, &parts).await;
+fn decode_continuation_token(token: &str) -> Result<String> {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(token)
+        .ok()
+        .and_then(|cursor| String::from_utf8(cursor).ok())
+        .ok_or_else(|| {
+            ProxyError::InvalidArgument("The continuation token provided is incorrect".to_string())
+        })
+}
 
-        keepalive_handle.abort();
+/// ListObjects (V1), still sent by older tools: the same listing as
+/// ListObjectsV2, paginated with `marker` instead of continuation tokens.
+async fn handle_list_objects_v1(
+    state: AppState,
+    bucket: &str,
+    query: &str,
+    public_only: Option<&AccessRules>,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
 
-        match result {
-            Ok(etag) => {
-                let location = format!("{}/{}/{}", region_base_url, bucket, key);
-                let response = format!(
-                    r#" --><CompleteMultipartUploadResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><Location>{}</Location><Bucket>{}</Bucket><Key>{}</Key><ETag>"{}"</ETag></CompleteMultipartUploadResult>"#,
-                    location, bucket, key, etag
-                );
-                let _ = tx.send(Ok(Bytes::from(response))).await;
-            }
-            Err(e) => {
-                let error_xml = format!(
-                    r#" --><Error><Code>InternalError</Code><Message>{}</Message></Error>"#,
-                    e
-                );
-                let _ = tx.send(Ok(Bytes::from(error_xml))).await;
-            }
-        }
-    });
+    let query: ListObjectsV1Query = serde_urlencoded::from_str(query).unwrap_or_default();
+    let prefix = query.prefix.as_deref().unwrap_or("");
+    let delimiter = query.delimiter.as_deref();
+    let max_keys = query.max_keys.unwrap_or(1000).min(1000);
+    let marker = query.marker.as_deref().filter(|m| !m.is_empty());
 
-    let body = Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(rx));
+    let page = list_page(
+        &state,
+        bucket,
+        prefix,
+        delimiter,
+        max_keys,
+        marker,
+        public_only,
+    )
+    .await?;
+    let common_prefixes: Vec<S3CommonPrefix> = page
+        .common_prefixes
+        .into_iter()
+        .map(|p| S3CommonPrefix { prefix: p })
+        .collect();
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "application/xml")
-        .body(body)
-        .unwrap())
+    xml_response(xml::list_objects_v1_response(xml::ListObjectsV1Params {
+        bucket,
+        prefix: Some(prefix),
+        delimiter,
+        max_keys,
+        objects: &page.objects,
+        common_prefixes: &common_prefixes,
+        is_truncated: page.is_truncated,
+        marker,
+        next_marker: page.next_token.as_deref(),
+    }))
 }
 
-async fn handle_abort_multipart_upload(state: AppState, query: &str) -> Result<Response> {
-    let params: std::collections::HashMap<String, String> =
-        serde_urlencoded::from_str(query).unwrap_or_default();
-    let upload_id = params
-        .get("uploadId")
-        .ok_or_else(|| ProxyError::InvalidRequest("Missing uploadId".into()))?;
-    MultipartManager::abort(&state.bunny, upload_id).await?;
-    Ok((StatusCode::NO_CONTENT, "").into_response())
+/// The prefix a browser's GET of `bucket`, or of a `key` ending in `/`,
+/// should see as an `--html-listing` page instead of S3's answer, if any.
+fn html_listing_prefix(
+    state: &AppState,
+    bucket: &str,
+    key: Option<&str>,
+    query: &str,
+    headers: &HeaderMap,
+) -> Option<String> {
+    if state.config.html_listing == HtmlListing::Off
+        || query.contains("list-type")
+        || query.contains("uploads")
+        || query.contains("uploadId")
+        || !html_listing::prefers_html(headers)
+    {
+        return None;
+    }
+    let prefix = match key {
+        Some(key) if key.ends_with('/') => key.to_string(),
+        Some(_) => return None,
+        None => serde_urlencoded::from_str::<ListObjectsV2Query>(query)
+            .unwrap_or_default()
+            .prefix
+            .unwrap_or_default(),
+    };
+    if state.config.html_listing == HtmlListing::Public
+        && !state
+            .access
+            .as_ref()
+            .is_some_and(|access| access.leads_to_readable(bucket, &prefix))
+    {
+        return None;
+    }
+    Some(prefix)
 }
 
-async fn handle_list_parts(
+/// Serves the `--html-listing` page of `prefix`, paginated with
+/// `start-after`. Subfolders share the pages with the objects, in key order.
+async fn handle_html_listing(
     state: AppState,
     bucket: &str,
-    key: &str,
+    prefix: &str,
     query: &str,
 ) -> Result<Response> {
-    if bucket != state.config.storage_zone {
-        return Err(ProxyError::BucketNotFound(bucket.to_string()));
-    }
-
-    let params: std::collections::HashMap<String, String> =
-        serde_urlencoded::from_str(query).unwrap_or_default();
-    let upload_id = params
-        .get("uploadId")
-        .ok_or_else(|| ProxyError::InvalidRequest("Missing uploadId".into()))?;
-    let max_parts: u32 = params
-        .get("max-parts")
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(1000);
-
-    let parts = MultipartManager::list_parts(&state.bunny, upload_id).await?;
+    state.check_bucket(bucket)?;
+    let query: ListObjectsV2Query = serde_urlencoded::from_str(query).unwrap_or_default();
+    let max_keys = query.max_keys.unwrap_or(1000).min(1000);
+    let public_only = match state.config.html_listing {
+        HtmlListing::Public => state.access.clone(),
+        _ => None,
+    };
+    let page = list_page(
+        &state,
+        bucket,
+        prefix,
+        Some("/"),
+        max_keys,
+        query.start_after.as_deref(),
+        public_only.as_deref(),
+    )
+    .await?;
+    let body = html_listing::render(
+        bucket,
+        prefix,
+        &page.common_prefixes,
+        &page.objects,
+        page.next_token.as_deref(),
+    );
     Ok((
         StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/xml")],
-        xml::list_parts_response(bucket, key, upload_id, &parts, false, None, max_parts),
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (header::VARY, "Accept"),
+        ],
+        body,
     )
         .into_response())
 }
 
-async fn handle_list_multipart_uploads(
+async fn handle_head_object(
     state: AppState,
     bucket: &str,
-    query: &str,
+    key: &str,
+    headers: &HeaderMap,
 ) -> Result<Response> {
-    if bucket != state.config.storage_zone {
-        return Err(ProxyError::BucketNotFound(bucket.to_string()));
-    }
+    state.check_bucket(bucket)?;
+    let (obj, meta) = tokio::join!(
+        state.bunny.describe(key),
+        ObjectMetaStore::get(&state.bunny, key)
+    );
+    let obj = obj?;
 
-    let params: std::collections::HashMap<String, String> =
-        serde_urlencoded::from_str(query).unwrap_or_default();
-    let prefix = params.get("prefix").map(|s| s.as_str());
-    let delimiter = params.get("delimiter").map(|s| s.as_str());
-    let max_uploads: u32 = params
-        .get("max-uploads")
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(1000);
+    // Bunny returns Length: -1 for non-existent files, or isDirectory for folders
+    if obj.length < 0 || obj.is_directory {
+        return Err(ProxyError::NotFound(key.to_string()));
+    }
 
-    let uploads: Vec<_> = MultipartManager::list_uploads(&state.bunny, bucket)
-        .await?
-        .into_iter()
-        .filter(|(key, _, _)| prefix.map(|p| key.starts_with(p)).unwrap_or(true))
-        .take(max_uploads as usize)
-        .collect();
+    let meta = meta.unwrap_or_else(|e| {
+        tracing::warn!("Failed to read metadata sidecar for {}: {}", key, e);
+        ObjectMeta::default()
+    });
+    state.read_keyring(headers, meta.encryption.as_ref(), false)?;
 
-    Ok((
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/xml")],
-        xml::list_multipart_uploads_response(
-            bucket,
-            &uploads,
-            prefix,
-            delimiter,
-            max_uploads,
-            false,
-        ),
-    )
-        .into_response())
+    let (length, etag) = match meta.original() {
+        Some((size, etag)) => (size, etag.to_string()),
+        None => (obj.length as u64, obj.etag()),
+    };
+    let mut r = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_LENGTH, length)
+        .header(header::CONTENT_TYPE, &obj.content_type)
+        .header(
+            header::LAST_MODIFIED,
+            obj.last_changed
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        )
+        .header(header::ETAG, format!("\"{}\"", etag));
+    // Bunny's checksum covers the stored bytes, not what the client sent.
+    if let Some(checksum) = &obj.checksum
+        && meta.original().is_none()
+    {
+        r = r.header("x-amz-checksum-sha256", checksum);
+    }
+    if let Some(checksum) = &meta.checksum {
+        r = r.header(checksum.algorithm.header(), &checksum.value);
+    }
+    if let Some(class) = &meta.storage_class {
+        r = r.header(object_meta::STORAGE_CLASS_HEADER, class);
+    }
+    let mut response = r.body(Body::empty()).unwrap();
+    object_meta::replay_headers(&meta.headers, response.headers_mut());
+    Ok(response)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use futures::stream;
+/// GetObject and HeadObject with `partNumber`: the byte range of that part
+/// of an object assembled by CompleteMultipartUpload, with the number of
+/// parts. Any other object is a single part, read whole.
+async fn handle_get_object_part(
+    state: AppState,
+    method: &Method,
+    bucket: &str,
+    key: &str,
+    query: &str,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+    let part_number = url::form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == "partNumber")
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .filter(|n| (1..=10000).contains(n))
+        .ok_or_else(|| {
+            ProxyError::InvalidArgument(
+                "Part number must be an integer between 1 and 10000, inclusive".to_string(),
+            )
+        })?;
+    if headers.contains_key(header::RANGE) {
+        return Err(ProxyError::InvalidRequest(
+            "Cannot specify both Range header and partNumber query parameter".to_string(),
+        ));
+    }
 
-    #[tokio::test]
-    async fn test_hashing_stream_computes_correct_sha256() {
-        let data = b"hello world";
-        let expected_hash = hex::encode(Sha256::digest(data));
+    let (obj, meta) = tokio::join!(
+        state.bunny.describe(key),
+        ObjectMetaStore::get(&state.bunny, key)
+    );
+    let obj = obj?;
+    if obj.length < 0 || obj.is_directory {
+        return Err(ProxyError::NotFound(key.to_string()));
+    }
+    let meta = meta?;
+    let size = match meta.original() {
+        Some((size, _)) => size,
+        None => obj.length as u64,
+    };
+    let (start, len) = meta
+        .part_range(part_number, size)
+        .ok_or(ProxyError::InvalidPartNumber)?;
+    let end = (start + len).saturating_sub(1);
 
-        let chunks: Vec<std::result::Result<Bytes, std::io::Error>> =
-            vec![Ok(Bytes::from_static(data))];
-        let input_stream = stream::iter(chunks);
+    let mut response = match (*method == Method::HEAD, meta.parts.is_empty()) {
+        (true, true) => handle_head_object(state, bucket, key, headers).await?,
+        (false, true) => handle_get_object(state, bucket, key, headers).await?,
+        (true, false) => {
+            let mut response = handle_head_object(state, bucket, key, headers).await?;
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            let headers = response.headers_mut();
+            headers.insert(header::CONTENT_LENGTH, len.into());
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, size)).unwrap(),
+            );
+            response
+        }
+        (false, false) => {
+            let mut ranged = headers.clone();
+            ranged.remove(header::IF_RANGE);
+            ranged.insert(
+                header::RANGE,
+                HeaderValue::from_str(&format!("bytes={}-{}", start, end)).unwrap(),
+            );
+            handle_get_object(state, bucket, key, &ranged).await?
+        }
+    };
+    if !meta.parts.is_empty() {
+        response
+            .headers_mut()
+            .insert(MP_PARTS_COUNT, meta.parts.len().into());
+    }
+    Ok(response)
+}
 
-        let (hashing_stream, hash_rx) = HashingStream::new_sha256(input_stream);
+/// GetObjectAttributes: what HeadObject would report, limited to the
+/// attributes named in `x-amz-object-attributes`. `ObjectParts` lists the
+/// part sizes recorded when a multipart upload completed, paged by
+/// `x-amz-max-parts` and `x-amz-part-number-marker`; an object written in
+/// one request has none.
+async fn handle_get_object_attributes(
+    state: AppState,
+    key: &str,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    let mut wanted = HashSet::new();
+    for value in headers.get_all("x-amz-object-attributes") {
+        let value = value.to_str().map_err(|_| {
+            ProxyError::InvalidArgument("Invalid x-amz-object-attributes header".to_string())
+        })?;
+        for attribute in value.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+            if !matches!(
+                attribute,
+                "ETag" | "Checksum" | "ObjectParts" | "StorageClass" | "ObjectSize"
+            ) {
+                return Err(ProxyError::InvalidArgument(format!(
+                    "Invalid attribute name specified: {}",
+                    attribute
+                )));
+            }
+            wanted.insert(attribute);
+        }
+    }
+    if wanted.is_empty() {
+        return Err(ProxyError::InvalidArgument(
+            "The x-amz-object-attributes header specifying the attributes to be retrieved is either missing or empty".to_string(),
+        ));
+    }
+
+    let (obj, meta) = tokio::join!(
+        state.bunny.describe(key),
+        ObjectMetaStore::get(&state.bunny, key)
+    );
+    let obj = obj?;
+    if obj.length < 0 || obj.is_directory {
+        return Err(ProxyError::NotFound(key.to_string()));
+    }
+    let meta = meta.unwrap_or_else(|e| {
+        tracing::warn!("Failed to read metadata sidecar for {}: {}", key, e);
+        ObjectMeta::default()
+    });
+    state.read_keyring(headers, meta.encryption.as_ref(), false)?;
+
+    let (size, etag) = match meta.original() {
+        Some((size, etag)) => (size, etag.to_string()),
+        None => (obj.length as u64, obj.etag()),
+    };
+    // Bunny's checksum is the hex SHA-256 of the stored bytes, which are
+    // only what the client sent when neither encrypted nor compressed.
+    let sha256 = obj
+        .checksum
+        .as_deref()
+        .filter(|_| meta.original().is_none())
+        .and_then(|hex_digest| hex::decode(hex_digest).ok())
+        .map(|digest| base64::engine::general_purpose::STANDARD.encode(digest));
+
+    let mut checksums: Vec<(&str, &str)> = Vec::new();
+    if wanted.contains("Checksum") {
+        if let Some(stored) = &meta.checksum {
+            checksums.push((stored.algorithm.xml_element(), &stored.value));
+        }
+        if let Some(sha256) = &sha256 {
+            checksums.push(("ChecksumSHA256", sha256));
+        }
+    }
+
+    let object_parts = if wanted.contains("ObjectParts") && !meta.parts.is_empty() {
+        let number = |name: &str, default: usize| -> Result<usize> {
+            headers.get(name).map_or(Ok(default), |value| {
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|v| v.trim().parse().ok())
+                    .ok_or_else(|| ProxyError::InvalidArgument(format!("Invalid {} header", name)))
+            })
+        };
+        let max_parts = number("x-amz-max-parts", 1000)?.min(1000);
+        let marker = number("x-amz-part-number-marker", 0)?;
+        Some(xml::object_parts(&meta.parts, marker, max_parts))
+    } else {
+        None
+    };
+
+    let body = xml::object_attributes_response(
+        wanted.contains("ETag").then_some(etag.as_str()),
+        &checksums,
+        object_parts.as_deref(),
+        wanted
+            .contains("StorageClass")
+            .then(|| meta.storage_class()),
+        wanted.contains("ObjectSize").then_some(size),
+    );
+    let mut response = xml_response(body)?;
+    response.headers_mut().insert(
+        header::LAST_MODIFIED,
+        HeaderValue::from_str(
+            &obj.last_changed
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        )
+        .unwrap(),
+    );
+    Ok(response)
+}
+
+/// SelectObjectContent: reads the object as GetObject would, so encrypted
+/// and compressed objects are queried by their content, and streams the
+/// query's results back as events.
+async fn handle_select_object_content(
+    state: AppState,
+    bucket: &str,
+    key: &str,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<Response> {
+    let request: SelectObjectContentRequest = xml::parse_request_body(&body)?;
+    let select = Select::new(&request)?;
+
+    let mut read_headers = headers.clone();
+    for name in [
+        header::RANGE,
+        header::IF_NONE_MATCH,
+        header::IF_MODIFIED_SINCE,
+    ] {
+        read_headers.remove(name);
+    }
+    let object = handle_get_object(state, bucket, key, &read_headers).await?;
+    if object.status() != StatusCode::OK {
+        return Err(ProxyError::NotImplemented(
+            "SelectObjectContent on objects served by redirect".into(),
+        ));
+    }
+    let stored = object
+        .into_body()
+        .into_data_stream()
+        .map_err(std::io::Error::other);
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .body(Body::from_stream(select.run(stored)))
+        .unwrap())
+}
+
+/// GetObject and HeadObject of a versioned key with `versionId`: the current
+/// object when the ID is its own, otherwise the copy in the versions area.
+async fn handle_get_object_version(
+    state: AppState,
+    method: &Method,
+    bucket: &str,
+    key: &str,
+    version_id: &str,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+    let path = match VersionStore::current(&state.bunny, key).await? {
+        Some((current, _)) if current == version_id => key.to_string(),
+        _ => {
+            let entry = VersionStore::find(&state.bunny, key, version_id).await?;
+            if entry.delete_marker {
+                return Err(ProxyError::MethodNotAllowed {
+                    method: method.to_string(),
+                    allowed: "DELETE",
+                });
+            }
+            entry.path
+        }
+    };
+    let mut response = match *method {
+        Method::HEAD => handle_head_object(state, bucket, &path, headers).await?,
+        _ => handle_get_object(state, bucket, &path, headers).await?,
+    };
+    set_version_id(&mut response, Some(version_id));
+    Ok(response)
+}
+
+/// GetObject and HeadObject of a versioned key without `versionId`, which
+/// also report the current version's ID.
+async fn handle_get_current_version(
+    state: AppState,
+    method: &Method,
+    bucket: &str,
+    key: &str,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    let client = state.bunny.clone();
+    let mut response = match *method {
+        Method::HEAD => handle_head_object(state, bucket, key, headers).await?,
+        _ => handle_get_object(state, bucket, key, headers).await?,
+    };
+    if response.status().is_success() {
+        let meta = ObjectMetaStore::get(&client, key).await?;
+        set_version_id(
+            &mut response,
+            Some(meta.version_id.as_deref().unwrap_or(NULL_VERSION_ID)),
+        );
+    }
+    Ok(response)
+}
+
+fn set_version_id(response: &mut Response, version_id: Option<&str>) {
+    if let Some(value) = version_id.and_then(|id| HeaderValue::from_str(id).ok()) {
+        response.headers_mut().insert(VERSION_ID_HEADER, value);
+    }
+}
+
+async fn handle_get_object(
+    state: AppState,
+    bucket: &str,
+    key: &str,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+
+    let mut known_meta = None;
+    if state.encryption.is_some() || state.config.sse_c || state.compression.is_some() {
+        let meta = ObjectMetaStore::get(&state.bunny, key).await?;
+        let keyring = state.read_keyring(headers, meta.encryption.as_ref(), false)?;
+        let stored = match (&meta.compression, keyring, &meta.encryption) {
+            (Some(comp), keyring, _) => {
+                Some(get_compressed_object(&state, keyring, key, headers, &meta, comp).await)
+            }
+            (None, Some(keyring), Some(enc)) => {
+                Some(get_encrypted_object(&state, &keyring, key, headers, enc).await)
+            }
+            _ => None,
+        };
+        if let Some(response) = stored {
+            let mut response = response?;
+            object_meta::replay_headers(&meta.headers, response.headers_mut());
+            return Ok(response);
+        }
+        known_meta = Some(meta);
+    }
+
+    if let Some(redirect) = &state.redirect
+        && redirect.applies_to(key)
+    {
+        let obj = state.bunny.describe(key).await?;
+        if obj.length < 0 || obj.is_directory {
+            return Err(ProxyError::NotFound(key.to_string()));
+        }
+        if redirect.applies_to_size(obj.length as u64) {
+            if let Some(r) = not_modified(headers, &format!("\"{}\"", obj.etag())) {
+                return Ok(r);
+            }
+            return Ok(redirect.response(&format!("{}{}", state.bunny.root(), key)));
+        }
+    }
+
+    // Forward Range header to Bunny to avoid buffering entire file
+    let range_header = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+    // Only full-object reads can be checked against the stored checksum.
+    let download = async {
+        if state.config.verify_downloads && range_header.is_none() {
+            let (download, described) = tokio::join!(
+                state.bunny.download_range(key, None),
+                state.bunny.describe(key)
+            );
+            let checksum = match described {
+                Ok(obj) => obj.checksum,
+                Err(e) => {
+                    tracing::warn!("Cannot verify download of {}: {}", key, e);
+                    None
+                }
+            };
+            Ok((download?, checksum))
+        } else {
+            Ok((state.bunny.download_range(key, range_header).await?, None))
+        }
+    };
+    // The sidecar is only read for its stored headers here, alongside the
+    // download, and a sidecar that cannot be read just leaves them out.
+    let stored_headers = async {
+        match known_meta {
+            Some(meta) => meta.headers,
+            None => match ObjectMetaStore::get(&state.bunny, key).await {
+                Ok(meta) => meta.headers,
+                Err(e) => {
+                    tracing::warn!("Failed to read metadata sidecar for {}: {}", key, e);
+                    BTreeMap::new()
+                }
+            },
+        }
+    };
+    let (download, stored_headers): (Result<_>, _) = tokio::join!(download, stored_headers);
+    let (mut download, expected_checksum) = download?;
+    // Bunny reports the current validators only with the response, so a stale
+    // If-Range costs a second, full download.
+    if download.status() == StatusCode::PARTIAL_CONTENT
+        && !if_range_holds(
+            headers,
+            download.etag().as_deref(),
+            download.last_modified().as_deref(),
+        )
+    {
+        download = state.bunny.download_range(key, None).await?;
+    }
+
+    let content_length = download.content_length();
+    let content_type = download
+        .content_type()
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    let etag = download.etag();
+    let last_modified = download.last_modified();
+    let is_partial = download.status() == StatusCode::PARTIAL_CONTENT;
+    let content_range = download.content_range();
+
+    // Handle If-None-Match conditional request
+    if let Some(if_none_match) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        && let Some(server_etag) = &etag
+    {
+        let server_etag_normalized = server_etag.trim_matches('"');
+        let matches = if_none_match == "*"
+            || if_none_match.split(',').any(|e| {
+                e.trim()
+                    .trim_matches('"')
+                    .trim_start_matches("W/")
+                    .trim_matches('"')
+                    == server_etag_normalized
+            });
+        if matches {
+            let mut r = Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, format!("\"{}\"", server_etag_normalized));
+            if let Some(lm) = &last_modified {
+                r = r.header(header::LAST_MODIFIED, lm);
+            }
+            let mut response = r.body(Body::empty()).unwrap();
+            object_meta::replay_headers(&stored_headers, response.headers_mut());
+            return Ok(response);
+        }
+    }
+
+    // Handle partial content (range request forwarded to Bunny)
+    if is_partial {
+        let mut r = Response::builder()
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_TYPE, &content_type)
+            .header(header::ACCEPT_RANGES, "bytes");
+        if let Some(len) = content_length {
+            r = r.header(header::CONTENT_LENGTH, len);
+        }
+        if let Some(range) = content_range {
+            r = r.header(header::CONTENT_RANGE, range);
+        }
+        if let Some(etag) = etag {
+            r = r.header(header::ETAG, format!("\"{}\"", etag.trim_matches('"')));
+        }
+        if let Some(lm) = last_modified {
+            r = r.header(header::LAST_MODIFIED, lm);
+        }
+        let mut response = r.body(Body::from_stream(download.bytes_stream())).unwrap();
+        object_meta::replay_headers(&stored_headers, response.headers_mut());
+        return Ok(response);
+    }
+
+    // Full response
+    let mut r = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some(size) = content_length {
+        r = r.header(header::CONTENT_LENGTH, size);
+    }
+    if let Some(etag) = etag {
+        r = r.header(header::ETAG, format!("\"{}\"", etag.trim_matches('"')));
+    }
+    if let Some(lm) = last_modified {
+        r = r.header(header::LAST_MODIFIED, lm);
+    }
+
+    let body = match expected_checksum {
+        Some(expected) => {
+            let stream = Box::pin(download.bytes_stream());
+            Body::from_stream(verify_download(&state, key, stream, expected))
+        }
+        None => Body::from_stream(download.bytes_stream()),
+    };
+    let mut response = r.body(body).unwrap();
+    object_meta::replay_headers(&stored_headers, response.headers_mut());
+    Ok(response)
+}
+
+/// Hashes a full-object GET body as it streams and, once it ends, compares the
+/// digest with Bunny's stored SHA-256. The headers are long sent by then, so a
+/// mismatch fails the body and the client's own integrity checks fire.
+fn verify_download<S>(
+    state: &AppState,
+    key: &str,
+    body: S,
+    expected: String,
+) -> impl futures::Stream<Item = std::result::Result<Bytes, std::io::Error>> + Send + use<S>
+where
+    S: futures::Stream<Item = std::result::Result<Bytes, std::io::Error>> + Send + Unpin + 'static,
+{
+    let (hashing_stream, hash_rx) = HashingStream::new_sha256(body);
+    let integrity = Arc::clone(&state.integrity);
+    let key = key.to_string();
+    let check = futures::stream::once(async move {
+        let computed = hash_rx.await.ok()?;
+        let matched = computed.eq_ignore_ascii_case(&expected);
+        integrity.record(matched);
+        if matched {
+            return None;
+        }
+        tracing::error!(
+            "Checksum mismatch streaming {}: Bunny stores {}, streamed bytes hash to {}",
+            key,
+            expected,
+            computed
+        );
+        Some(Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} does not match its stored checksum", key),
+        )))
+    })
+    .filter_map(futures::future::ready);
+    hashing_stream.chain(check)
+}
+
+/// Under `--verify-writes`, DESCRIBEs a freshly uploaded key until Bunny
+/// reports the stored length, and the SHA-256 when one was computed, retrying
+/// for `--verify-writes-window-ms` since its replicas converge asynchronously.
+async fn verify_write(
+    state: &AppState,
+    key: &str,
+    stored_length: u64,
+    sha256: Option<&str>,
+) -> Result<()> {
+    if !state.config.verify_writes {
+        return Ok(());
+    }
+    let window = Duration::from_millis(state.config.verify_writes_window_ms);
+    let started = Instant::now();
+    let mut attempts = 0;
+    let observed = loop {
+        attempts += 1;
+        let observed = match state.bunny.describe(key).await {
+            Ok(obj) => {
+                let length_matches = obj.length.max(0) as u64 == stored_length;
+                let checksum_matches = match (sha256, obj.checksum.as_deref()) {
+                    (Some(sent), Some(stored)) => sent.eq_ignore_ascii_case(stored),
+                    _ => true,
+                };
+                if length_matches && checksum_matches {
+                    state
+                        .integrity
+                        .record_write(Some(started.elapsed()), attempts);
+                    return Ok(());
+                }
+                format!(
+                    "{} bytes, checksum {}",
+                    obj.length,
+                    obj.checksum.as_deref().unwrap_or("none")
+                )
+            }
+            Err(ProxyError::NotFound(_)) => "not found".to_string(),
+            Err(e) => return Err(e),
+        };
+        if started.elapsed() >= window {
+            break observed;
+        }
+        tokio::time::sleep(Duration::from_millis(50 << attempts.min(4))).await;
+    };
+
+    state.integrity.record_write(None, attempts);
+    tracing::error!(
+        "Write verification of {} failed after {:?}: sent {} bytes, Bunny reports {}",
+        key,
+        started.elapsed(),
+        stored_length,
+        observed
+    );
+    if state.config.verify_writes_strict {
+        return Err(ProxyError::UpstreamUnavailable(format!(
+            "Bunny did not report {} as written within {:?}",
+            key, window
+        )));
+    }
+    Ok(())
+}
+
+/// Parses a single-range `Range: bytes=...` header against an object of `len`
+/// bytes into an inclusive range. `None` means serve the whole object, as S3
+/// does for ranges it cannot parse.
+fn parse_range(value: &str, len: u64) -> Option<Result<(u64, u64)>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            if suffix == 0 {
+                return Some(Err(ProxyError::InvalidRange));
+            }
+            (len.saturating_sub(suffix), len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => {
+            let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            (start, end.min(len.saturating_sub(1)))
+        }
+    };
+    if range.0 >= len {
+        return Some(Err(ProxyError::InvalidRange));
+    }
+    Some(Ok(range))
+}
+
+/// Whether a range may be served under `If-Range`: always without the header,
+/// otherwise only if it names the current `etag` (strong comparison) or the
+/// exact `last_modified` date, so a resumed download never splices together
+/// two versions of an object.
+fn if_range_holds(headers: &HeaderMap, etag: Option<&str>, last_modified: Option<&str>) -> bool {
+    let Some(validator) = headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    let Ok(validator) = validator.to_str().map(str::trim) else {
+        return false;
+    };
+    if validator.starts_with('"') {
+        return etag.is_some_and(|etag| validator.trim_matches('"') == etag.trim_matches('"'));
+    }
+    if validator.starts_with("W/") {
+        return false;
+    }
+    let date = |s: &str| chrono::DateTime::parse_from_rfc2822(s.trim()).ok();
+    match (date(validator), last_modified.and_then(date)) {
+        (Some(since), Some(current)) => since == current,
+        _ => false,
+    }
+}
+
+/// A 304 response if `If-None-Match` matches the quoted `etag`.
+fn not_modified(headers: &HeaderMap, etag: &str) -> Option<Response> {
+    let if_none_match = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())?;
+    let matches = if_none_match == "*"
+        || if_none_match
+            .split(',')
+            .any(|e| e.trim().trim_start_matches("W/") == etag);
+    matches.then(|| {
+        Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, etag)
+            .body(Body::empty())
+            .unwrap()
+    })
+}
+
+/// Serves an object stored compressed. The whole object is read and
+/// decompressed, so a range is cut out of the decompressed stream unless
+/// `--compress-reject-ranges` declines it.
+async fn get_compressed_object(
+    state: &AppState,
+    keyring: Option<Arc<Keyring>>,
+    key: &str,
+    headers: &HeaderMap,
+    meta: &ObjectMeta,
+    comp: &CompressionMeta,
+) -> Result<Response> {
+    if comp.algorithm != compression::ZSTD {
+        return Err(ProxyError::NotImplemented(format!(
+            "Reading objects compressed with {}",
+            comp.algorithm
+        )));
+    }
+    let etag = format!("\"{}\"", comp.etag);
+    if let Some(r) = not_modified(headers, &etag) {
+        return Ok(r);
+    }
+
+    let range = match headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| if_range_holds(headers, Some(&etag), None))
+        .and_then(|v| parse_range(v, comp.size))
+    {
+        Some(range) => Some(range?),
+        None => None,
+    };
+    if range.is_some() && state.config.compress_reject_ranges {
+        return Err(ProxyError::NotImplemented(
+            "Range requests on compressed objects".to_string(),
+        ));
+    }
+
+    let download = state.bunny.download_range(key, None).await?;
+    let content_type = download
+        .content_type()
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    let last_modified = download.last_modified();
+    let stored = download.bytes_stream();
+    let stored: UploadStream = match (keyring, &meta.encryption) {
+        (Some(keyring), Some(enc)) => {
+            Box::pin(keyring.decrypt(stored, None, ReadPlan::full(enc.size)))
+        }
+        _ => Box::pin(stored),
+    };
+    let original = compression::decompress(stored);
+    let key = key.to_string();
+    let log_error = move |e: &std::io::Error| tracing::error!("Failed to read {}: {}", key, e);
+
+    let mut r = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, etag);
+    if let Some(lm) = last_modified {
+        r = r.header(header::LAST_MODIFIED, lm);
+    }
+    let r = match range {
+        Some((start, end)) => r
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_LENGTH, end + 1 - start)
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, comp.size),
+            )
+            .body(Body::from_stream(
+                compression::slice(original, start, end).inspect_err(log_error),
+            )),
+        None => r
+            .status(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, comp.size)
+            .body(Body::from_stream(original.inspect_err(log_error))),
+    };
+    Ok(r.unwrap())
+}
+
+/// Serves an object stored encrypted at rest, fetching and decrypting only
+/// the frames a range request needs.
+async fn get_encrypted_object(
+    state: &AppState,
+    keyring: &Arc<Keyring>,
+    key: &str,
+    headers: &HeaderMap,
+    enc: &EncryptionMeta,
+) -> Result<Response> {
+    let etag = format!("\"{}\"", enc.etag);
+    if let Some(r) = not_modified(headers, &etag) {
+        return Ok(r);
+    }
+
+    let range = match headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| if_range_holds(headers, Some(&etag), None))
+        .and_then(|v| parse_range(v, enc.size))
+    {
+        Some(range) => Some(range?),
+        None => None,
+    };
+    let plan = match range {
+        Some((start, end)) => ReadPlan::new(enc.size, start, end),
+        None => ReadPlan::full(enc.size),
+    };
+
+    let stored_range = format!("bytes={}-{}", plan.stored_start, plan.stored_end);
+    let (download, header) = if plan.includes_header() {
+        let download = state
+            .bunny
+            .download_range(key, range.is_some().then_some(stored_range.as_str()))
+            .await?;
+        (download, None)
+    } else {
+        let (download, header) = tokio::join!(
+            state.bunny.download_range(key, Some(&stored_range)),
+            state.bunny.download_range(key, Some(Header::RANGE))
+        );
+        let header = Header::parse(&header?.bytes().await?)
+            .ok_or_else(|| ProxyError::Decryption(format!("{} has no encryption header", key)))?;
+        (download?, Some(header))
+    };
+
+    let content_type = download
+        .content_type()
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    let last_modified = download.last_modified();
+    let stream = download.bytes_stream();
+    let key = key.to_string();
+    let body = Body::from_stream(
+        keyring
+            .decrypt(stream, header, plan)
+            .inspect_err(move |e| tracing::error!("Failed to decrypt {}: {}", key, e)),
+    );
+
+    let mut r = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, etag);
+    if let Some(lm) = last_modified {
+        r = r.header(header::LAST_MODIFIED, lm);
+    }
+    r = match range {
+        Some((start, end)) => r
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_LENGTH, end + 1 - start)
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, enc.size),
+            ),
+        None => r
+            .status(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, enc.size),
+    };
+    Ok(r.body(body).unwrap())
+}
+
+async fn handle_put_object(
+    state: AppState,
+    bucket: &str,
+    key: &str,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+    object_meta::check_user_metadata(headers)?;
+    let mut meta = ObjectMeta {
+        storage_class: object_meta::requested_storage_class(headers)?,
+        encryption: None,
+        compression: None,
+        version_id: None,
+        headers: object_meta::stored_headers(headers),
+        parts: Vec::new(),
+        checksum: checksums::requested(headers)?,
+    };
+    if let Some(claimed) = &meta.checksum {
+        claimed.verify(&checksums::compute(claimed.algorithm, &body))?;
+    }
+
+    let _lock_guard = lock_for_conditional_write(&state, key, headers).await?;
+    state.check_retention(bucket, key, headers).await?;
+    let quota = state.charge_quota(bucket, key, body.len() as u64).await?;
+    meta.version_id = state.new_version(key);
+
+    use md5::Digest;
+    let etag = format!("{:x}", md5::Md5::digest(&body));
+
+    let mut options = UploadOptions {
+        content_type: state.content_type(key, headers),
+        sha256_checksum: headers
+            .get("x-amz-checksum-sha256")
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_string()),
+    };
+    let (keyring, customer_key_md5) = state.write_keyring(headers)?;
+    let mut stored = body.clone();
+    let mut stats = None;
+    if let Some(compressor) = &state.compression
+        && compressor.applies(key, options.content_type.as_deref())
+    {
+        stored = compressor.compress_bytes(&body).await;
+        stats = Some(CompressionStats {
+            original: body.len() as u64,
+            stored: stored.len() as u64,
+        });
+        meta.compression = Some(CompressionMeta {
+            algorithm: compression::ZSTD.to_string(),
+            size: body.len() as u64,
+            etag: etag.clone(),
+        });
+    }
+    if let Some(keyring) = &keyring {
+        meta.encryption = Some(EncryptionMeta {
+            size: stored.len() as u64,
+            etag: etag.clone(),
+            customer_key_md5,
+        });
+        stored = keyring.encrypt_bytes(&stored);
+    }
+    if meta.original().is_some() {
+        // Bunny would check the client's checksum against the stored bytes.
+        options.sha256_checksum = None;
+    }
+    let stored_length = stored.len() as u64;
+    let sha256 = state
+        .config
+        .verify_writes
+        .then(|| hex::encode(Sha256::digest(&stored)));
+    state
+        .replacing(key, async {
+            state.bunny.upload(key, stored, options).await?;
+            verify_write(&state, key, stored_length, sha256.as_deref()).await
+        })
+        .await?;
+    state.commit_quota(quota, stored_length);
+    ObjectMetaStore::put(&state.bunny, key, &meta).await?;
+    state.replicate(key).await?;
+    state.events.notify(
+        EventName::Put,
+        bucket,
+        key,
+        Some(body.len() as u64),
+        Some(&etag),
+    );
+    let mut response = (
+        StatusCode::OK,
+        [(header::ETAG, format!("\"{}\"", etag))],
+        "",
+    )
+        .into_response();
+    if let Some(stats) = stats {
+        response.extensions_mut().insert(stats);
+    }
+    set_version_id(&mut response, meta.version_id.as_deref());
+    Ok(response)
+}
+
+async fn handle_put_object_stream(
+    state: AppState,
+    bucket: &str,
+    key: &str,
+    headers: &HeaderMap,
+    body: Body,
+    content_length: Option<u64>,
+    claimed_hash: Option<String>,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+    object_meta::check_user_metadata(headers)?;
+    let mut meta = ObjectMeta {
+        storage_class: object_meta::requested_storage_class(headers)?,
+        encryption: None,
+        compression: None,
+        version_id: None,
+        headers: object_meta::stored_headers(headers),
+        parts: Vec::new(),
+        checksum: checksums::requested(headers)?,
+    };
+
+    let _lock_guard = lock_for_conditional_write(&state, key, headers).await?;
+    state.check_retention(bucket, key, headers).await?;
+    if state.config.dedupe_writes
+        && let Some((claim, etag)) = find_duplicate(
+            &state,
+            key,
+            headers,
+            content_length,
+            claimed_hash.as_deref(),
+        )
+        .await?
+    {
+        let size = content_length.unwrap_or(0);
+        return skip_duplicate_put(&state, bucket, key, body, size, &claim, etag).await;
+    }
+    let quota = state
+        .charge_quota(bucket, key, content_length.unwrap_or(0))
+        .await?;
+    meta.version_id = state.new_version(key);
+
+    let stream = body.into_data_stream();
+    let stream = stream.map(|r| r.map_err(std::io::Error::other));
+    let (stream, received) = LengthCheckedStream::new(stream, content_length);
+    let mut stream: UploadStream = Box::pin(stream);
+
+    let mut computed_checksum = None;
+    if let Some(claimed) = &meta.checksum {
+        let (checksumming_stream, computed) = checksums::computing(stream, claimed.algorithm);
+        stream = Box::pin(checksumming_stream);
+        computed_checksum = Some(computed);
+    }
+    let mut sha256_rx = None;
+    if claimed_hash.is_some() {
+        let (hashing_stream, hash_rx) = HashingStream::new_sha256(stream);
+        stream = Box::pin(hashing_stream);
+        sha256_rx = Some(hash_rx);
+    }
+    let mut md5_rx = None;
+    let mut stored_length = content_length;
+    let (keyring, customer_key_md5) = state.write_keyring(headers)?;
+    let content_type = state.content_type(key, headers);
+    let compressor = state
+        .compression
+        .as_ref()
+        .filter(|c| c.applies(key, content_type.as_deref()));
+    if keyring.is_some() || compressor.is_some() {
+        let (hashing_stream, hash_rx) = HashingStream::new_md5(stream);
+        stream = Box::pin(hashing_stream);
+        md5_rx = Some(hash_rx);
+    }
+    let mut compressed_size = None;
+    if let Some(compressor) = compressor {
+        let (compressed, size) = compressor.compress(stream);
+        stream = Box::pin(compressed);
+        compressed_size = Some(size);
+        stored_length = None;
+    }
+    if let Some(keyring) = &keyring {
+        stream = Box::pin(keyring.encrypt(stream));
+        stored_length = stored_length.map(encryption::encrypted_len);
+    }
+
+    let target = UploadTarget::for_key(&state, key).await?;
+    let guard = PartialUploadGuard::new(&state.bunny, &target);
+    let result = state
+        .bunny
+        .upload_stream(
+            target.path(),
+            stream,
+            stored_length,
+            content_type.as_deref(),
+        )
+        .await;
+    guard.disarm();
+    check_body_complete(&state, &target, content_length, &received).await?;
+    result?;
+
+    if let (Some(claimed), Some(computed)) = (&meta.checksum, computed_checksum)
+        && let Err(e) = claimed.verify(&computed.checksum())
+    {
+        tracing::warn!("{} mismatch for {}", claimed.algorithm.header(), key);
+        target.discard(&state.bunny).await;
+        return Err(e);
+    }
+    let computed_hash = if let (Some(expected), Some(hash_rx)) = (&claimed_hash, sha256_rx) {
+        let computed = hash_rx.await.map_err(|_| {
+            ProxyError::InvalidRequest("Failed to compute content hash".to_string())
+        })?;
+
+        if computed != *expected {
+            tracing::warn!(
+                "Content hash mismatch for {}: expected {}, got {}",
+                key,
+                expected,
+                computed
+            );
+            target.discard(&state.bunny).await;
+            return Err(ProxyError::InvalidRequest(
+                "Content hash mismatch".to_string(),
+            ));
+        }
+        Some(computed)
+    } else {
+        None
+    };
+    let size = received.received.load(Ordering::Relaxed);
+    let compressed_size = compressed_size.map(|c| c.load(Ordering::Relaxed));
+    let mut stored_size = compressed_size.unwrap_or(size);
+    if keyring.is_some() {
+        stored_size = encryption::encrypted_len(stored_size);
+    }
+    target
+        .commit(&state, content_type.as_deref(), stored_size)
+        .await?;
+    let stored_hash = computed_hash
+        .as_deref()
+        .filter(|_| compressor.is_none() && keyring.is_none());
+    verify_write(&state, key, stored_size, stored_hash).await?;
+    state.commit_quota(quota, stored_size);
+    if let Some(hash_rx) = md5_rx {
+        let etag = hash_rx.await.map_err(|_| {
+            ProxyError::InvalidRequest("Failed to compute content hash".to_string())
+        })?;
+        if compressed_size.is_some() {
+            meta.compression = Some(CompressionMeta {
+                algorithm: compression::ZSTD.to_string(),
+                size,
+                etag: etag.clone(),
+            });
+        }
+        if keyring.is_some() {
+            meta.encryption = Some(EncryptionMeta {
+                size: compressed_size.unwrap_or(size),
+                etag,
+                customer_key_md5,
+            });
+        }
+    }
+    ObjectMetaStore::put(&state.bunny, key, &meta).await?;
+    state.replicate(key).await?;
+
+    let etag = match meta.original() {
+        Some((_, etag)) => etag.to_string(),
+        None => computed_hash
+            .or_else(|| content_length.map(|l| format!("{:x}", l)))
+            .unwrap_or_else(|| "streaming".to_string()),
+    };
+    state
+        .events
+        .notify(EventName::Put, bucket, key, content_length, Some(&etag));
+
+    let mut response = (
+        StatusCode::OK,
+        [(header::ETAG, format!("\"{}\"", etag))],
+        "",
+    )
+        .into_response();
+    if let Some(stored) = compressed_size {
+        response.extensions_mut().insert(CompressionStats {
+            original: size,
+            stored,
+        });
+    }
+    set_version_id(&mut response, meta.version_id.as_deref());
+    Ok(response)
+}
+
+/// With `--dedupe-writes`, the checksum a PUT claims and the ETag of the
+/// identical object already stored under `key`, if there is one. A write
+/// that would change more than the bytes, such as the storage class,
+/// content type, customer key or a version, is never skipped.
+async fn find_duplicate(
+    state: &AppState,
+    key: &str,
+    headers: &HeaderMap,
+    content_length: Option<u64>,
+    payload_hash: Option<&str>,
+) -> Result<Option<(Claim, String)>> {
+    let (Some(size), Some(claim)) = (content_length, Claim::from_request(headers, payload_hash))
+    else {
+        return Ok(None);
+    };
+    if state.is_versioned(key) || sse::has_customer_headers(headers) {
+        return Ok(None);
+    }
+    let (obj, meta) = tokio::join!(
+        state.bunny.describe(key),
+        ObjectMetaStore::get(&state.bunny, key)
+    );
+    // Anything short of a clear answer means uploading as usual.
+    let (Ok(obj), Ok(meta)) = (obj, meta) else {
+        return Ok(None);
+    };
+    if meta.storage_class != object_meta::requested_storage_class(headers)?
+        || meta.headers != object_meta::stored_headers(headers)
+        || meta.checksum != checksums::requested(headers)?
+        || meta
+            .encryption
+            .as_ref()
+            .is_some_and(|e| e.customer_key_md5.is_some())
+        || state
+            .content_type(key, headers)
+            .is_some_and(|ct| ct != obj.content_type)
+    {
+        return Ok(None);
+    }
+    Ok(claim.matches(&obj, &meta, size).map(|etag| (claim, etag)))
+}
+
+/// Acknowledges a PUT found identical to the stored object without
+/// uploading it. The body is still read to the end, its length checked and,
+/// with `--dedupe-verify`, its claimed checksum too.
+async fn skip_duplicate_put(
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+    body: Body,
+    expected: u64,
+    claim: &Claim,
+    etag: String,
+) -> Result<Response> {
+    let verify = state.config.dedupe_verify;
+    let mut sha256 = Sha256::new();
+    let mut md5 = md5::Md5::new();
+    let mut received = 0u64;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk =
+            chunk.map_err(|e| ProxyError::InvalidRequest(format!("Failed to read body: {}", e)))?;
+        received += chunk.len() as u64;
+        match claim {
+            Claim::Sha256(_) if verify => sha256.update(&chunk),
+            Claim::Md5(_) if verify => md5.update(&chunk),
+            _ => {}
+        }
+    }
+    if received != expected {
+        return Err(ProxyError::IncompleteBody { expected, received });
+    }
+    if verify {
+        let computed = match claim {
+            Claim::Sha256(_) => hex::encode(sha256.finalize()),
+            Claim::Md5(_) => hex::encode(md5.finalize()),
+        };
+        if !claim.holds_for(&computed) {
+            return Err(ProxyError::BadDigest);
+        }
+    }
+    state.dedupe.record(expected);
+    tracing::info!(
+        "Skipped uploading {}/{}: the {} bytes stored are identical",
+        bucket,
+        key,
+        expected
+    );
+    state
+        .events
+        .notify(EventName::Put, bucket, key, Some(expected), Some(&etag));
+    Ok((
+        StatusCode::OK,
+        [(header::ETAG, format!("\"{}\"", etag))],
+        "",
+    )
+        .into_response())
+}
+
+/// Browser-based upload: a `multipart/form-data` POST authenticated by a signed
+/// policy document in the form rather than by request headers.
+async fn handle_post_object(
+    state: AppState,
+    bucket: &str,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+
+    let boundary = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|ct| multer::parse_boundary(ct).ok())
+        .ok_or_else(|| ProxyError::InvalidRequest("Missing multipart/form-data boundary".into()))?;
+    let constraints = multer::Constraints::new().size_limit(
+        multer::SizeLimit::new()
+            .per_field(MAX_POST_FORM_FIELD)
+            .for_field("file", u64::MAX),
+    );
+    let mut form =
+        multer::Multipart::with_constraints(body.into_data_stream(), boundary, constraints);
+    let malformed =
+        |e: multer::Error| ProxyError::InvalidRequest(format!("Malformed POST form: {}", e));
+
+    // Fields after the file are ignored, as in S3, so the file can be streamed.
+    let mut fields = HashMap::from([("bucket".to_string(), bucket.to_string())]);
+    let (file, filename) = loop {
+        let field = form.next_field().await.map_err(malformed)?.ok_or_else(|| {
+            ProxyError::InvalidRequest("POST requires exactly one file upload per request".into())
+        })?;
+        let name = field.name().unwrap_or_default().to_ascii_lowercase();
+        if name == "file" {
+            let filename = field.file_name().unwrap_or_default().to_string();
+            break (field, filename);
+        }
+        let value = field.text().await.map_err(malformed)?;
+        fields.insert(name, value);
+    };
+    if [
+        sse::SSE_C_ALGORITHM_HEADER,
+        sse::SSE_C_KEY_HEADER,
+        sse::SSE_C_KEY_MD5_HEADER,
+    ]
+    .iter()
+    .any(|name| fields.contains_key(*name))
+    {
+        return Err(ProxyError::NotImplemented(
+            "POST uploads with customer-provided encryption keys".to_string(),
+        ));
+    }
+
+    let required = |name: &str| {
+        fields.get(name).cloned().ok_or_else(|| {
+            ProxyError::InvalidRequest(format!("Bucket POST must contain a field named '{}'", name))
+        })
+    };
+    let policy = required("policy")?;
+    if required("x-amz-algorithm")? != "AWS4-HMAC-SHA256" {
+        return Err(ProxyError::InvalidRequest(
+            "Only AWS4-HMAC-SHA256 POST policies are supported".into(),
+        ));
+    }
+    let credential = required("x-amz-credential")?;
+    state
+        .auth
+        .verify_post_policy(&policy, &credential, &required("x-amz-signature")?)?;
+
+    let key = required("key")?.replace("${filename}", &filename);
+    if key.is_empty() {
+        return Err(ProxyError::InvalidRequest(
+            "POST key must not be empty".into(),
+        ));
+    }
+    // The key only exists once the form is read, so it is held to the checks
+    // a key in the URL gets here.
+    validate_key(&key)?;
+    state.check_key(&key)?;
+    fields.insert("key".to_string(), key.clone());
+
+    let policy = PostPolicy::decode(&policy)?;
+    policy.check(&fields)?;
+    if let Some(rules) = &state.key_rules {
+        rules.check_write(bucket, &key)?;
+    }
+    if let Some(policies) = &state.key_policies {
+        let access_key = credential.split('/').next().unwrap_or_default();
+        let access = Access::Object { bucket, key: &key };
+        policies.check(access_key, &Method::POST, access)?;
+    }
+    state.check_retention(bucket, &key, headers).await?;
+    // The form's length bounds the file's, which is not known until read.
+    let declared = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    let quota = state.charge_quota(bucket, &key, declared).await?;
+    let version_id = state.new_version(&key);
+    let (min_size, max_size) = policy.content_length_range().unwrap_or((0, u64::MAX));
+    let max_size = max_size.min(state.config.max_object_size);
+
+    let too_large = Arc::new(AtomicBool::new(false));
+    let too_large_flag = too_large.clone();
+    let mut size = 0u64;
+    let stream = file.map(move |chunk| {
+        let chunk = chunk.map_err(std::io::Error::other)?;
+        size += chunk.len() as u64;
+        if size > max_size {
+            too_large_flag.store(true, Ordering::Relaxed);
+            return Err(std::io::Error::other("upload exceeds allowed size"));
+        }
+        Ok(chunk)
+    });
+    let (stream, progress) = LengthCheckedStream::new(stream, None);
+    let (stream, md5_rx) = HashingStream::new_md5(stream);
+    let stream: UploadStream = match &state.encryption {
+        Some(keyring) => Box::pin(keyring.encrypt(stream)),
+        None => Box::pin(stream),
+    };
+
+    let content_type = fields.get("content-type").cloned().or_else(|| {
+        state
+            .content_types
+            .as_ref()
+            .and_then(|guesser| guesser.guess(&key))
+    });
+    let target = UploadTarget::for_key(&state, &key).await?;
+    let guard = PartialUploadGuard::new(&state.bunny, &target);
+    let result = state
+        .bunny
+        .upload_stream(target.path(), stream, None, content_type.as_deref())
+        .await;
+    guard.disarm();
+    if too_large.load(Ordering::Relaxed) {
+        target.discard(&state.bunny).await;
+        return Err(ProxyError::EntityTooLarge(max_size));
+    }
+    check_body_complete(&state, &target, None, &progress).await?;
+    result?;
+    if progress.received.load(Ordering::Relaxed) < min_size {
+        target.discard(&state.bunny).await;
+        return Err(ProxyError::EntityTooSmall(min_size));
+    }
+    let received = progress.received.load(Ordering::Relaxed);
+    let stored = match &state.encryption {
+        Some(_) => encryption::encrypted_len(received),
+        None => received,
+    };
+    target
+        .commit(&state, content_type.as_deref(), stored)
+        .await?;
+    state.commit_quota(quota, received);
+
+    let md5 = md5_rx
+        .await
+        .map_err(|_| ProxyError::InvalidRequest("Failed to compute content hash".to_string()))?;
+    let meta = ObjectMeta {
+        storage_class: None,
+        encryption: state.encryption.as_ref().map(|_| EncryptionMeta {
+            size: progress.received.load(Ordering::Relaxed),
+            etag: md5.clone(),
+            customer_key_md5: None,
+        }),
+        compression: None,
+        version_id,
+        headers: BTreeMap::new(),
+        parts: Vec::new(),
+        checksum: None,
+    };
+    ObjectMetaStore::put(&state.bunny, &key, &meta).await?;
+    state.replicate(&key).await?;
+    let etag = format!("\"{}\"", md5);
+    state.events.notify(
+        EventName::Post,
+        bucket,
+        &key,
+        Some(progress.received.load(Ordering::Relaxed)),
+        Some(&etag),
+    );
+
+    let redirect = fields
+        .get("success_action_redirect")
+        .or_else(|| fields.get("redirect"))
+        .and_then(|r| url::Url::parse(r).ok());
+    if let Some(mut redirect) = redirect {
+        redirect
+            .query_pairs_mut()
+            .append_pair("bucket", bucket)
+            .append_pair("key", &key)
+            .append_pair("etag", &etag);
+        return Ok((
+            StatusCode::SEE_OTHER,
+            [
+                (header::LOCATION, redirect.to_string()),
+                (header::ETAG, etag),
+            ],
+            "",
+        )
+            .into_response());
+    }
+
+    let location = format!("/{}/{}", bucket, key);
+    match fields.get("success_action_status").map(String::as_str) {
+        Some("201") => Ok((
+            StatusCode::CREATED,
+            [
+                (header::CONTENT_TYPE, "application/xml".to_string()),
+                (header::ETAG, etag.clone()),
+                (header::LOCATION, location.clone()),
+            ],
+            xml::post_object_response(&location, bucket, &key, &etag),
+        )
+            .into_response()),
+        Some("200") => Ok((
+            StatusCode::OK,
+            [(header::ETAG, etag), (header::LOCATION, location)],
+            "",
+        )
+            .into_response()),
+        _ => Ok((
+            StatusCode::NO_CONTENT,
+            [(header::ETAG, etag), (header::LOCATION, location)],
+            "",
+        )
+            .into_response()),
+    }
+}
+
+/// Fails with `IncompleteBody` if the client sent fewer or more bytes than it
+/// announced, removing whatever partial upload may have reached Bunny but
+/// not the object it was to replace.
+async fn check_body_complete(
+    state: &AppState,
+    target: &UploadTarget,
+    content_length: Option<u64>,
+    progress: &BodyProgress,
+) -> Result<()> {
+    let path = target.path();
+    let received = progress.received.load(Ordering::Relaxed);
+    match content_length {
+        Some(expected) if received != expected && progress.failed.load(Ordering::Relaxed) => {
+            tracing::warn!(
+                "Body of {} does not match its Content-Length: expected {} bytes, received {}",
+                path,
+                expected,
+                received
+            );
+            target.discard(&state.bunny).await;
+            Err(ProxyError::IncompleteBody { expected, received })
+        }
+        None if progress.failed.load(Ordering::Relaxed) => {
+            tracing::warn!(
+                "Body for {} failed after {} bytes, deleting it",
+                path,
+                received
+            );
+            target.discard(&state.bunny).await;
+            Err(ProxyError::InvalidRequest(
+                "The request body ended before it was complete".to_string(),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn handle_delete_object(
+    state: AppState,
+    bucket: &str,
+    key: &str,
+    query: &str,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+    let _lock_guard = lock_for_conditional_write(&state, key, headers).await?;
+    state.check_retention(bucket, key, headers).await?;
+    if state.is_versioned(key) {
+        let deleted =
+            VersionStore::delete(&state.bunny, key, requested_version_id(query).as_deref()).await?;
+        state.replicate(key).await?;
+        state
+            .events
+            .notify(EventName::Delete, bucket, key, None, None);
+        let mut response = (StatusCode::NO_CONTENT, "").into_response();
+        set_version_id(&mut response, Some(&deleted.version_id));
+        if deleted.delete_marker {
+            response
+                .headers_mut()
+                .insert(DELETE_MARKER_HEADER, HeaderValue::from_static("true"));
+        }
+        return Ok(response);
+    }
+    let quota = state.charge_quota(bucket, key, 0).await?;
+    state.trash_before_delete(key, headers, Utc::now()).await?;
+    let (deleted, meta_deleted) = tokio::join!(
+        state.bunny.delete(key),
+        ObjectMetaStore::delete(&state.bunny, key)
+    );
+    deleted?;
+    meta_deleted?;
+    state.commit_quota(quota, 0);
+    state.replicate(key).await?;
+    state
+        .events
+        .notify(EventName::Delete, bucket, key, None, None);
+    Ok((StatusCode::NO_CONTENT, "").into_response())
+}
+
+/// Evaluates the preconditions of a PUT, DELETE or multipart completion
+/// (`If-None-Match: *`, `If-Match` and `If-Unmodified-Since`) as
+/// `--conditional-writes` says: under the key's lock, so another conditional
+/// write cannot land between the check and the write, without the lock, or
+/// not at all. Writes without preconditions take no lock.
+async fn lock_for_conditional_write(
+    state: &AppState,
+    key: &str,
+    headers: &HeaderMap,
+) -> Result<Option<LockGuard>> {
+    let Some(preconditions) = WritePreconditions::from_headers(headers) else {
+        return Ok(None);
+    };
+
+    let strategy = state.config.conditional_writes;
+    tracing::Span::current().record("conditional_writes", tracing::field::display(strategy));
+    let guard = match strategy {
+        ConditionalWrites::Off => {
+            tracing::debug!(
+                "Ignoring preconditions on {} (--conditional-writes off)",
+                key
+            );
+            return Ok(None);
+        }
+        ConditionalWrites::Fast => None,
+        ConditionalWrites::Locked => Some(
+            state
+                .lock
+                .try_lock(key)
+                .await
+                .ok_or(ProxyError::ConditionalRequestConflict)?,
+        ),
+    };
+    preconditions.check(state, key).await?;
+    Ok(guard)
+}
+
+/// The preconditions a write carries.
+#[derive(Debug, Clone)]
+struct WritePreconditions {
+    if_none_match: bool,
+    if_match: Option<String>,
+    if_unmodified_since: Option<chrono::DateTime<Utc>>,
+}
+
+impl WritePreconditions {
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let header_str = |name| {
+            headers
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+        };
+        let preconditions = Self {
+            if_none_match: header_str(header::IF_NONE_MATCH).is_some_and(|v| v.trim() == "*"),
+            if_match: header_str(header::IF_MATCH).map(str::to_string),
+            // Unparseable dates are ignored, as HTTP requires.
+            if_unmodified_since: header_str(header::IF_UNMODIFIED_SINCE)
+                .and_then(|v| chrono::DateTime::parse_from_rfc2822(v).ok())
+                .map(|d| d.with_timezone(&Utc)),
+        };
+        (preconditions.if_none_match
+            || preconditions.if_match.is_some()
+            || preconditions.if_unmodified_since.is_some())
+        .then_some(preconditions)
+    }
+
+    /// Checks the preconditions against the object now stored under `key`.
+    async fn check(&self, state: &AppState, key: &str) -> Result<()> {
+        let current = match state.bunny.describe(key).await {
+            Ok(obj) if obj.length >= 0 && !obj.is_directory => Some(obj),
+            Ok(_) | Err(ProxyError::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        let Some(obj) = current else {
+            // Only If-Match needs the object to exist.
+            return match self.if_match {
+                Some(_) => Err(ProxyError::NotFound(key.to_string())),
+                None => Ok(()),
+            };
+        };
+        if self.if_none_match {
+            return Err(ProxyError::PreconditionFailed);
+        }
+        if let Some(if_match) = &self.if_match {
+            // Compared with the ETag HEAD reports, which for encrypted or
+            // compressed objects comes from the sidecar.
+            let meta = ObjectMetaStore::get(&state.bunny, key).await?;
+            let etag = match meta.original() {
+                Some((_, etag)) => etag.to_string(),
+                None => obj.etag(),
+            };
+            if !etag_matches(if_match, &etag) {
+                return Err(ProxyError::PreconditionFailed);
+            }
+        } else if let Some(since) = self.if_unmodified_since
+            && !unmodified_since(obj.last_changed, since)
+        {
+            return Err(ProxyError::PreconditionFailed);
+        }
+        Ok(())
+    }
+}
+
+/// Whether an `If-Match` list names `etag`, or is `*`.
+fn etag_matches(if_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_matches('"');
+    if_match.trim() == "*"
+        || if_match
+            .split(',')
+            .any(|e| e.trim().trim_matches('"') == etag)
+}
+
+/// Whether an object last changed at `last_changed` satisfies
+/// `If-Unmodified-Since: since`. Bunny's timestamps have sub-second precision
+/// but HTTP dates, including the Last-Modified a client would echo back, are
+/// whole seconds, so the timestamp is truncated to its second first.
+fn unmodified_since(last_changed: chrono::DateTime<Utc>, since: chrono::DateTime<Utc>) -> bool {
+    use chrono::SubsecRound;
+    last_changed.trunc_subsecs(0) <= since
+}
+
+async fn handle_copy_object(
+    state: AppState,
+    bucket: &str,
+    key: &str,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+    let (source, source_bunny) = copy_source(&state, headers, "x-amz-copy-source")?;
+    let (etag, last_changed, version_id) =
+        copy_object(&state, bucket, key, headers, &source, &source_bunny).await?;
+
+    let mut response = (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/xml")],
+        xml::copy_object_response(&etag, last_changed),
+    )
+        .into_response();
+    set_version_id(&mut response, version_id.as_deref());
+    Ok(response)
+}
+
+/// The rename extension, enabled with `--allow-rename`: a PUT carrying
+/// `x-bunny-rename-source` copies the source to `key` like CopyObject, then
+/// deletes it. The source is only deleted once the copy and its sidecar are
+/// stored, so a failure leaves at worst both keys behind. Under
+/// `--conditional-writes locked` the destination's lock is held throughout,
+/// so a rename cannot race a conditional create of the same key.
+async fn handle_rename_object(
+    state: AppState,
+    bucket: &str,
+    key: &str,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    if !state.config.allow_rename {
+        return Err(ProxyError::NotImplemented(format!(
+            "{} without --allow-rename",
+            RENAME_SOURCE
+        )));
+    }
+    state.check_bucket(bucket)?;
+    let (source, source_bunny) = copy_source(&state, headers, RENAME_SOURCE)?;
+    if source.bucket == bucket && source.key == key {
+        return Err(ProxyError::InvalidRequest(
+            "An object cannot be renamed to itself".into(),
+        ));
+    }
+
+    let _lock_guard = match lock_for_conditional_write(&state, key, headers).await? {
+        Some(guard) => Some(guard),
+        None if state.config.conditional_writes == ConditionalWrites::Locked => Some(
+            state
+                .lock
+                .try_lock(key)
+                .await
+                .ok_or(ProxyError::ConditionalRequestConflict)?,
+        ),
+        None => None,
+    };
+    state
+        .check_retention(&source.bucket, &source.key, headers)
+        .await?;
+    let (etag, last_changed, version_id) =
+        copy_object(&state, bucket, key, headers, &source, &source_bunny).await?;
+
+    if state.is_versioned(&source.key) {
+        VersionStore::delete(&source_bunny, &source.key, None).await?;
+    } else {
+        let quota = state.charge_quota(&source.bucket, &source.key, 0).await?;
+        let (deleted, meta_deleted) = tokio::join!(
+            source_bunny.delete(&source.key),
+            ObjectMetaStore::delete(&source_bunny, &source.key)
+        );
+        deleted?;
+        meta_deleted?;
+        state.commit_quota(quota, 0);
+    }
+    state.replicate_from(&source_bunny, &source.key).await?;
+    state
+        .events
+        .notify(EventName::Delete, &source.bucket, &source.key, None, None);
+
+    let mut response = (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "application/xml".to_string()),
+            (header::ETAG, format!("\"{}\"", etag)),
+        ],
+        xml::copy_object_response(&etag, last_changed),
+    )
+        .into_response();
+    set_version_id(&mut response, version_id.as_deref());
+    Ok(response)
+}
+
+/// Copies `source` to `key` with its sidecar, replicates and announces the
+/// new object, and returns its ETag, Last-Modified and, under
+/// `--emulate-versioning`, version ID.
+async fn copy_object(
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+    headers: &HeaderMap,
+    source: &CopySource,
+    source_bunny: &Backend,
+) -> Result<(String, chrono::DateTime<Utc>, Option<String>)> {
+    state.check_retention(bucket, key, headers).await?;
+    let quota = match state.quotas.as_ref().filter(|q| q.applies(bucket, key)) {
+        Some(_) => {
+            let size = source_bunny.describe(&source.key).await?.length.max(0) as u64;
+            state.charge_quota(bucket, key, size).await?
+        }
+        None => None,
+    };
+    // Metadata is only taken from the request when it replaces the source's.
+    let replace_metadata = headers
+        .get("x-amz-metadata-directive")
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"REPLACE"));
+    if replace_metadata {
+        object_meta::check_user_metadata(headers)?;
+    }
+
+    let source_meta = ObjectMetaStore::get(source_bunny, &source.key).await?;
+    let source_headers = source_meta.headers.clone();
+    let source_checksum = source_meta.checksum.clone();
+    let source_keyring = state.read_keyring(headers, source_meta.encryption.as_ref(), true)?;
+    let customer_source = source_meta
+        .encryption
+        .as_ref()
+        .is_some_and(|enc| enc.customer_key_md5.is_some());
+    let version_id = state.new_version(key);
+    let copied = async {
+        if customer_source || sse::has_customer_headers(headers) {
+            return copy_reencrypted(
+                state,
+                source_bunny,
+                &source.key,
+                key,
+                headers,
+                source_meta,
+                source_keyring,
+            )
+            .await;
+        }
+        // The stored bytes are copied as they are, so an encrypted or compressed
+        // source stays readable only if its sidecar travels with it.
+        state
+            .bunny
+            .copy_from(source_bunny, &source.key, key)
+            .await?;
+        Ok(ObjectMeta {
+            storage_class: None,
+            encryption: source_meta.encryption,
+            compression: source_meta.compression,
+            version_id: None,
+            headers: BTreeMap::new(),
+            parts: Vec::new(),
+            checksum: None,
+        })
+    };
+    let mut meta = state.replacing(key, copied).await?;
+    meta.version_id = version_id.clone();
+    meta.headers = match replace_metadata {
+        true => object_meta::stored_headers(headers),
+        false => source_headers,
+    };
+    // The bytes are the source's, whatever else the copy replaces.
+    meta.checksum = source_checksum;
+    ObjectMetaStore::put(&state.bunny, key, &meta).await?;
+    state.replicate(key).await?;
+    let obj = state.bunny.describe(key).await?;
+    state.commit_quota(quota, obj.length.max(0) as u64);
+    let (size, etag) = match meta.original() {
+        Some((size, etag)) => (size, etag.to_string()),
+        None => (obj.length.max(0) as u64, obj.etag()),
+    };
+    state
+        .events
+        .notify(EventName::Copy, bucket, key, Some(size), Some(&etag));
+    Ok((etag, obj.last_changed, version_id))
+}
+
+/// The object named by the `header` of a copy or rename, and the client of
+/// the zone or folder holding it, which need not be the destination's. The
+/// source is held to the same bucket and key checks as a read of it.
+fn copy_source(
+    state: &AppState,
+    headers: &HeaderMap,
+    header: &str,
+) -> Result<(CopySource, Backend)> {
+    let copy_source = headers
+        .get(header)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| ProxyError::InvalidRequest(format!("Missing {}", header)))?;
+    let source = CopySource::parse(copy_source)
+        .ok_or_else(|| ProxyError::InvalidRequest("Invalid copy source".into()))?;
+    let source_bunny = state.bucket_client(&source.bucket)?;
+    validate_key(&source.key)?;
+    state.check_key(&source.key)?;
+    Ok((source, source_bunny))
+}
+
+/// Copies an object through the proxy when SSE-C is involved on either side,
+/// since the source must be decrypted with one key and stored under another.
+async fn copy_reencrypted(
+    state: &AppState,
+    source_bunny: &Backend,
+    source: &str,
+    key: &str,
+    headers: &HeaderMap,
+    source_meta: ObjectMeta,
+    source_keyring: Option<Arc<Keyring>>,
+) -> Result<ObjectMeta> {
+    let download = source_bunny.download_range(source, None).await?;
+    let content_type = download.content_type().map(str::to_string);
+    let (size, etag) = match &source_meta.encryption {
+        Some(enc) => (enc.size, enc.etag.clone()),
+        None => {
+            let obj = source_bunny.describe(source).await?;
+            let etag = match &source_meta.compression {
+                Some(comp) => comp.etag.clone(),
+                None => obj.etag(),
+            };
+            (obj.length.max(0) as u64, etag)
+        }
+    };
+    let stream = download.bytes_stream();
+    let stream: UploadStream = match (source_keyring, &source_meta.encryption) {
+        (Some(keyring), Some(enc)) => {
+            Box::pin(keyring.decrypt(stream, None, ReadPlan::full(enc.size)))
+        }
+        _ => Box::pin(stream),
+    };
+
+    let (keyring, customer_key_md5) = state.write_keyring(headers)?;
+    let (stream, stored_length): (UploadStream, _) = match &keyring {
+        Some(keyring) => (
+            Box::pin(keyring.encrypt(stream)),
+            encryption::encrypted_len(size),
+        ),
+        None => (stream, size),
+    };
+    state
+        .bunny
+        .upload_stream(key, stream, Some(stored_length), content_type.as_deref())
+        .await?;
+
+    Ok(ObjectMeta {
+        storage_class: None,
+        encryption: keyring.map(|_| EncryptionMeta {
+            size,
+            etag,
+            customer_key_md5,
+        }),
+        compression: source_meta.compression,
+        version_id: None,
+        headers: BTreeMap::new(),
+        parts: Vec::new(),
+        checksum: None,
+    })
+}
+
+async fn handle_delete_objects(
+    state: AppState,
+    bucket: &str,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+    // A truncated or corrupted list must not delete the wrong keys.
+    check_content_md5(headers, &body, state.config.require_delete_md5)?;
+
+    let req: DeleteRequest = xml::parse_request_body(&body)?;
+    let quiet = req.quiet.unwrap_or(false);
+    // One trash folder for the whole request.
+    let deleted_at = Utc::now();
+    let mut deleted = Vec::new();
+    let mut errors = Vec::new();
+
+    for obj in req.object {
+        if let Some(version_id) = obj.version_id.as_deref()
+            && version_id != NULL_VERSION_ID
+            && !state.is_versioned(&obj.key)
+        {
+            let err = ProxyError::NoSuchVersion(version_id.to_string());
+            errors.push((obj.key, err.s3_error_code().to_string(), err.to_string()));
+            continue;
+        }
+        if let Err(err) = validate_key(&obj.key).and_then(|()| state.check_key(&obj.key)) {
+            errors.push((obj.key, err.s3_error_code().to_string(), err.to_string()));
+            continue;
+        }
+        if let Err(err) = state.check_retention(bucket, &obj.key, headers).await {
+            errors.push((obj.key, err.s3_error_code().to_string(), err.to_string()));
+            continue;
+        }
+        if state.is_versioned(&obj.key) {
+            let result =
+                match VersionStore::delete(&state.bunny, &obj.key, obj.version_id.as_deref()).await
+                {
+                    Ok(deleted) => state.replicate(&obj.key).await.map(|()| deleted),
+                    Err(e) => Err(e),
+                };
+            match result {
+                Ok(Deleted {
+                    version_id,
+                    delete_marker,
+                }) => {
+                    state
+                        .events
+                        .notify(EventName::Delete, bucket, &obj.key, None, None);
+                    let marker = delete_marker.then(|| version_id.clone());
+                    deleted.push((obj.key, obj.version_id, marker));
+                }
+                Err(e @ ProxyError::NoSuchVersion(_)) => {
+                    errors.push((obj.key, e.s3_error_code().to_string(), e.to_string()))
+                }
+                Err(e) => errors.push((obj.key, "InternalError".to_string(), e.to_string())),
+            }
+            continue;
+        }
+        let quota = match state.charge_quota(bucket, &obj.key, 0).await {
+            Ok(quota) => quota,
+            Err(err) => {
+                errors.push((obj.key, "InternalError".to_string(), err.to_string()));
+                continue;
+            }
+        };
+        if let Err(err) = state
+            .trash_before_delete(&obj.key, headers, deleted_at)
+            .await
+        {
+            errors.push((obj.key, "InternalError".to_string(), err.to_string()));
+            continue;
+        }
+        let (result, meta_result) = tokio::join!(
+            state.bunny.delete(&obj.key),
+            ObjectMetaStore::delete(&state.bunny, &obj.key)
+        );
+        let result = match result.and(meta_result) {
+            Ok(()) => state.replicate(&obj.key).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(_) => {
+                state.commit_quota(quota, 0);
+                state
+                    .events
+                    .notify(EventName::Delete, bucket, &obj.key, None, None);
+                deleted.push((obj.key, obj.version_id, None))
+            }
+            Err(e) => errors.push((obj.key, "InternalError".to_string(), e.to_string())),
+        }
+    }
+
+    xml_response(xml::delete_objects_response(&deleted, &errors, quiet))
+}
+
+/// Checks a Content-MD5 header against `body`. Without one the body passes,
+/// unless `required`; S3 then also accepts an `x-amz-checksum-*` header, as
+/// SDKs using flexible checksums send one instead.
+fn check_content_md5(headers: &HeaderMap, body: &[u8], required: bool) -> Result<()> {
+    let Some(value) = headers.get("content-md5") else {
+        let has_checksum = headers
+            .keys()
+            .any(|name| name.as_str().starts_with("x-amz-checksum-"));
+        return match required && !has_checksum {
+            true => Err(ProxyError::MissingContentMd5),
+            false => Ok(()),
+        };
+    };
+    let claimed =
+        base64::Engine::decode(&base64::engine::general_purpose::STANDARD, value.as_bytes())
+            .ok()
+            .filter(|digest| digest.len() == 16)
+            .ok_or(ProxyError::InvalidDigest)?;
+    if md5::Md5::digest(body).as_slice() != claimed.as_slice() {
+        return Err(ProxyError::BadDigest);
+    }
+    Ok(())
+}
+
+async fn handle_initiate_multipart_upload(
+    state: AppState,
+    bucket: &str,
+    key: &str,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+    object_meta::check_user_metadata(headers)?;
+    let storage_class = object_meta::requested_storage_class(headers)?;
+    let upload_id = MultipartManager::create(
+        &state.bunny,
+        bucket,
+        key,
+        storage_class.as_deref(),
+        state.content_type(key, headers).as_deref(),
+        &object_meta::stored_headers(headers),
+    )
+    .await?;
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/xml")],
+        xml::initiate_multipart_upload_response(bucket, key, &upload_id),
+    )
+        .into_response())
+}
+
+async fn handle_upload_part_stream(
+    state: AppState,
+    bucket: &str,
+    query: &str,
+    headers: &HeaderMap,
+    body: Body,
+    content_length: Option<u64>,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+    let (upload_id, part_number) = part_params(query)?;
+    let checksum = checksums::requested(headers)?;
+
+    let stream = body.into_data_stream();
+    let stream = Box::pin(stream.map(|r| r.map_err(std::io::Error::other)));
+    let etag = store_part(
+        &state,
+        &upload_id,
+        part_number,
+        stream,
+        content_length,
+        checksum.as_ref(),
+    )
+    .await?;
+
+    let mut response = (
+        StatusCode::OK,
+        [(header::ETAG, format!("\"{}\"", etag))],
+        "",
+    )
+        .into_response();
+    if let Some(checksum) = checksum
+        && let Ok(value) = HeaderValue::from_str(&checksum.value)
+    {
+        response
+            .headers_mut()
+            .insert(checksum.algorithm.header(), value);
+    }
+    Ok(response)
+}
+
+/// UploadPartCopy: stores a part read from an existing object, whole or the
+/// `x-amz-copy-source-range` of it, in whichever zone or folder holds the
+/// source bucket. The source is streamed, never buffered.
+async fn handle_upload_part_copy(
+    state: AppState,
+    bucket: &str,
+    query: &str,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+    let (upload_id, part_number) = part_params(query)?;
+    let (source, source_bunny) = copy_source(&state, headers, "x-amz-copy-source")?;
+
+    let source_meta = ObjectMetaStore::get(&source_bunny, &source.key).await?;
+    let customer_source = source_meta
+        .encryption
+        .as_ref()
+        .is_some_and(|enc| enc.customer_key_md5.is_some());
+    if customer_source || source_meta.compression.is_some() {
+        return Err(ProxyError::NotImplemented(
+            "UploadPartCopy from compressed or customer-encrypted objects".to_string(),
+        ));
+    }
+    let range = headers
+        .get("x-amz-copy-source-range")
+        .map(|v| v.to_str().unwrap_or_default());
+
+    let (stream, len): (UploadStream, u64) = match (&source_meta.encryption, range) {
+        (Some(_), Some(_)) => {
+            return Err(ProxyError::NotImplemented(
+                "UploadPartCopy ranges of encrypted objects".to_string(),
+            ));
+        }
+        (Some(enc), None) => {
+            let keyring = state
+                .read_keyring(headers, Some(enc), true)?
+                .ok_or(ProxyError::AccessDenied)?;
+            let download = source_bunny.download(&source.key).await?;
+            let stream = download.bytes_stream();
+            (
+                Box::pin(keyring.decrypt(stream, None, ReadPlan::full(enc.size))),
+                enc.size,
+            )
+        }
+        (None, range) => {
+            let byte_range = match range {
+                Some(range) => {
+                    let size = source_bunny.describe(&source.key).await?.length.max(0) as u64;
+                    let (start, end) = parse_copy_source_range(range, size)?;
+                    Some(format!("bytes={}-{}", start, end))
+                }
+                None => None,
+            };
+            let download = source_bunny
+                .download_range(&source.key, byte_range.as_deref())
+                .await?;
+            let len = download
+                .content_length()
+                .ok_or_else(|| ProxyError::BunnyApi("Download without a length".into()))?;
+            (Box::pin(download.bytes_stream()), len)
+        }
+    };
+    if len > state.config.max_object_size {
+        return Err(ProxyError::EntityTooLarge(state.config.max_object_size));
+    }
+    let etag = store_part(&state, &upload_id, part_number, stream, Some(len), None).await?;
+
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/xml")],
+        xml::copy_part_response(&etag, Utc::now()),
+    )
+        .into_response())
+}
+
+/// Parses `x-amz-copy-source-range`, which unlike `Range` must name both ends
+/// of a range within the source's `len` bytes.
+fn parse_copy_source_range(value: &str, len: u64) -> Result<(u64, u64)> {
+    let range = value
+        .trim()
+        .strip_prefix("bytes=")
+        .and_then(|spec| spec.split_once('-'))
+        .and_then(|(start, end)| Some((start.parse::<u64>().ok()?, end.parse::<u64>().ok()?)))
+        .filter(|(start, end)| start <= end)
+        .ok_or_else(|| {
+            ProxyError::InvalidArgument(
+                "x-amz-copy-source-range must be of the form bytes=first-last".into(),
+            )
+        })?;
+    if range.1 >= len {
+        return Err(ProxyError::InvalidRange);
+    }
+    Ok(range)
+}
+
+/// The `uploadId` and `partNumber` of an UploadPart or UploadPartCopy.
+fn part_params(query: &str) -> Result<(String, i32)> {
+    let params: std::collections::HashMap<String, String> =
+        serde_urlencoded::from_str(query).unwrap_or_default();
+    let upload_id = params
+        .get("uploadId")
+        .ok_or_else(|| ProxyError::InvalidRequest("Missing uploadId".into()))?;
+    let part_number: i32 = params
+        .get("partNumber")
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| ProxyError::InvalidRequest("Invalid partNumber".into()))?;
+    Ok((upload_id.clone(), part_number))
+}
+
+/// Stores part `part_number` of `upload_id` from `stream`, encrypted if the
+/// zone is, and records its ETag, the MD5 of the plaintext, which it returns.
+/// A part that does not hold the `checksum` its client sent is removed.
+async fn store_part(
+    state: &AppState,
+    upload_id: &str,
+    part_number: i32,
+    stream: UploadStream,
+    content_length: Option<u64>,
+    checksum: Option<&checksums::Checksum>,
+) -> Result<String> {
+    let path = format!("__multipart/{}/{:05}", upload_id, part_number);
+
+    let (stream, received) = LengthCheckedStream::new(stream, content_length);
+    let (stream, computed): (UploadStream, _) = match checksum {
+        Some(claimed) => {
+            let (stream, computed) = checksums::computing(stream, claimed.algorithm);
+            (Box::pin(stream), Some(computed))
+        }
+        None => (Box::pin(stream), None),
+    };
+    let (hashing_stream, hash_rx) = HashingStream::new_md5(stream);
+    let (stream, stored_length): (UploadStream, _) = match &state.encryption {
+        Some(keyring) => (
+            Box::pin(keyring.encrypt(hashing_stream)),
+            content_length.map(encryption::encrypted_len),
+        ),
+        None => (Box::pin(hashing_stream), content_length),
+    };
+
+    let target = UploadTarget::in_place(&path);
+    let guard = PartialUploadGuard::new(&state.bunny, &target);
+    let result = state
+        .bunny
+        .upload_stream(&path, stream, stored_length, None)
+        .await;
+    guard.disarm();
+    check_body_complete(state, &target, content_length, &received).await?;
+    result?;
+    if let (Some(claimed), Some(computed)) = (checksum, computed)
+        && let Err(e) = claimed.verify(&computed.checksum())
+    {
+        tracing::warn!("{} mismatch for {}", claimed.algorithm.header(), path);
+        target.discard(&state.bunny).await;
+        return Err(e);
+    }
+
+    let etag = hash_rx
+        .await
+        .map_err(|_| ProxyError::InvalidRequest("Failed to compute ETag".to_string()))?;
+
+    MultipartManager::store_part_etag(&state.bunny, upload_id, part_number, &etag).await?;
+    Ok(etag)
+}
+
+async fn handle_complete_multipart_upload(
+    state: AppState,
+    bucket: &str,
+    key: &str,
+    query: &str,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<Response> {
+    use axum::body::Body;
+
+    state.check_bucket(bucket)?;
+
+    let params: std::collections::HashMap<String, String> =
+        serde_urlencoded::from_str(query).unwrap_or_default();
+    let upload_id = params
+        .get("uploadId")
+        .ok_or_else(|| ProxyError::InvalidRequest("Missing uploadId".into()))?
+        .clone();
+
+    MultipartManager::check_key(&state.bunny.fresh(), &upload_id, key).await?;
+
+    let req: CompleteMultipartUpload = xml::parse_request_body(&body)?;
+    let parts: Vec<(i32, String)> = req
+        .part
+        .into_iter()
+        .map(|p| (p.part_number, p.etag))
+        .collect();
+    let object_size = headers
+        .get(MP_OBJECT_SIZE)
+        .map(|v| {
+            v.to_str()
+                .ok()
+                .and_then(|s| s.trim().parse::<u64>().ok())
+                .ok_or_else(|| {
+                    ProxyError::InvalidArgument(format!(
+                        "{} must be a non-negative integer",
+                        MP_OBJECT_SIZE
+                    ))
+                })
+        })
+        .transpose()?;
+
+    // Held until the object is assembled, like a PUT's for its upload.
+    let lock_guard = lock_for_conditional_write(&state, key, headers).await?;
+    // Checked again once a slot frees up, as the object may change meanwhile.
+    let preconditions = match state.config.conditional_writes {
+        ConditionalWrites::Off => None,
+        _ => WritePreconditions::from_headers(headers),
+    };
+    state.check_retention(bucket, key, headers).await?;
+    let quota = match state.quotas.as_ref().filter(|q| q.applies(bucket, key)) {
+        Some(_) => {
+            let (_, staged) = MultipartManager::staged(&state.bunny, &upload_id).await?;
+            state.charge_quota(bucket, key, staged).await?
+        }
+        None => None,
+    };
+    let admission = state.completions.admit(&upload_id)?;
+
+    let mut meta = ObjectMeta {
+        storage_class: MultipartManager::storage_class(&state.bunny, &upload_id).await?,
+        encryption: None,
+        compression: None,
+        version_id: state.new_version(key),
+        headers: MultipartManager::stored_headers(&state.bunny, &upload_id).await?,
+        parts: Vec::new(),
+        checksum: None,
+    };
+    let version_id = meta.version_id.clone();
+    let request_id = REQUEST_ID
+        .try_with(String::clone)
+        .unwrap_or_else(|_| uuid::Uuid::new_v4().to_string());
+
+    let bucket = bucket.to_string();
+    let key = key.to_string();
+    let region_base_url = state.config.region.base_url().to_string();
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<std::result::Result<Bytes, std::io::Error>>(16);
+
+    tokio::spawn(accounting::propagate(async move {
+        let _lock_guard = lock_guard;
+        let _ = tx
+            .send(Ok(Bytes::from(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?><!-- ",
+            )))
+            .await;
+
+        let keepalive_tx = tx.clone();
+        let keepalive_handle = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+                if keepalive_tx.send(Ok(Bytes::from(" "))).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        #[cfg(feature = "chaos")]
+        crate::chaos::delay_completion(&key).await;
+
+        // Waiting for a slot ends early if the client goes away.
+        let slot = tokio::select! {
+            slot = admission.slot() => slot,
+            _ = tx.closed() => return,
+        };
+        let checked = match (&slot, &preconditions) {
+            (Ok(_), Some(preconditions)) => preconditions.check(&state, &key).await,
+            _ => Ok(()),
+        };
+        let result = match slot.and_then(|slot| checked.map(|_| slot)) {
+            Err(e) => Err(e),
+            Ok(_slot) => match state
+                .replacing(
+                    &key,
+                    MultipartManager::complete(
+                        &state.bunny,
+                        &bucket,
+                        &upload_id,
+                        &key,
+                        &parts,
+                        object_size,
+                        state.encryption.as_ref(),
+                    ),
+                )
+                .await
+            {
+                Ok((etag, part_sizes)) => {
+                    let size = part_sizes.iter().sum();
+                    meta.parts = part_sizes;
+                    state.commit_quota(quota, size);
+                    if state.encryption.is_some() {
+                        meta.encryption = Some(EncryptionMeta {
+                            size,
+                            etag: etag.clone(),
+                            customer_key_md5: None,
+                        });
+                    }
+                    let stored = match ObjectMetaStore::put(&state.bunny, &key, &meta).await {
+                        Ok(()) => state.replicate(&key).await,
+                        Err(e) => Err(e),
+                    };
+                    stored.map(|_| {
+                        state.events.notify(
+                            EventName::CompleteMultipartUpload,
+                            &bucket,
+                            &key,
+                            Some(size),
+                            Some(&etag),
+                        );
+                        etag
+                    })
+                }
+                Err(e) => Err(e),
+            },
+        };
+
+        keepalive_handle.abort();
+
+        match result {
+            Ok(etag) => {
+                let location = format!("{}/{}/{}", region_base_url, bucket, encode_path(&key));
+                let response = format!(
+                    " -->{}",
+                    xml::complete_multipart_upload_result(&location, &bucket, &key, &etag)
+                );
+                let _ = tx.send(Ok(Bytes::from(response))).await;
+            }
+            Err(e) => {
+                let error_xml = format!(" -->{}", e.to_xml(Some(&bucket), Some(&key), &request_id));
+                let _ = tx.send(Ok(Bytes::from(error_xml))).await;
+            }
+        }
+    }));
+
+    let body = Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(rx));
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/xml")
+        .body(body)
+        .unwrap();
+    set_version_id(&mut response, version_id.as_deref());
+    Ok(response)
+}
+
+async fn handle_abort_multipart_upload(state: AppState, query: &str) -> Result<Response> {
+    let params: std::collections::HashMap<String, String> =
+        serde_urlencoded::from_str(query).unwrap_or_default();
+    let upload_id = params
+        .get("uploadId")
+        .ok_or_else(|| ProxyError::InvalidRequest("Missing uploadId".into()))?;
+    MultipartManager::abort(&state.bunny, upload_id).await?;
+    state.completions.abort(upload_id);
+    Ok((StatusCode::NO_CONTENT, "").into_response())
+}
+
+async fn handle_list_parts(
+    state: AppState,
+    bucket: &str,
+    key: &str,
+    query: &str,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+
+    let params: std::collections::HashMap<String, String> =
+        serde_urlencoded::from_str(query).unwrap_or_default();
+    let upload_id = params
+        .get("uploadId")
+        .ok_or_else(|| ProxyError::InvalidRequest("Missing uploadId".into()))?;
+    let max_parts: u32 = params
+        .get("max-parts")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1000);
+
+    let parts = MultipartManager::list_parts(&state.bunny, upload_id).await?;
+    xml_response(xml::list_parts_response(
+        bucket, key, upload_id, &parts, false, None, max_parts,
+    ))
+}
+
+async fn handle_list_multipart_uploads(
+    state: AppState,
+    bucket: &str,
+    query: &str,
+) -> Result<Response> {
+    state.check_bucket(bucket)?;
+
+    let params: std::collections::HashMap<String, String> =
+        serde_urlencoded::from_str(query).unwrap_or_default();
+    let prefix = params.get("prefix").map(|s| s.as_str());
+    let delimiter = params.get("delimiter").map(|s| s.as_str());
+    let max_uploads: u32 = params
+        .get("max-uploads")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1000);
+
+    let uploads: Vec<_> = MultipartManager::list_uploads(&state.bunny, bucket)
+        .await?
+        .into_iter()
+        .filter(|(key, _, _)| prefix.map(|p| key.starts_with(p)).unwrap_or(true))
+        .take(max_uploads as usize)
+        .collect();
+
+    xml_response(xml::list_multipart_uploads_response(
+        bucket,
+        &uploads,
+        prefix,
+        delimiter,
+        max_uploads,
+        false,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use futures::stream;
+
+    fn test_state() -> AppState {
+        AppState::new(Config::parse_from([
+            "bunny-s3-proxy",
+            "--storage-zone",
+            "test-zone",
+            "--access-key",
+            "test-key",
+        ]))
+        .unwrap()
+    }
+
+    async fn subresource_request(
+        method: Method,
+        subresource: Subresource,
+        key: Option<&str>,
+        body: &'static str,
+    ) -> Result<Response> {
+        handle_subresource(
+            test_state(),
+            &method,
+            subresource,
+            "test-zone",
+            key,
+            &HeaderMap::new(),
+            Bytes::from_static(body.as_bytes()),
+        )
+        .await
+    }
+
+    /// The AccessControlPolicy every ACL read returns: the configured key
+    /// as owner, holding FULL_CONTROL.
+    const OWNER_ONLY_ACL: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<AccessControlPolicy xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+<Owner><ID>bunny</ID><DisplayName>bunny</DisplayName></Owner>
+<AccessControlList><Grant><Grantee xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:type="CanonicalUser"><ID>bunny</ID><DisplayName>bunny</DisplayName></Grantee><Permission>FULL_CONTROL</Permission></Grant></AccessControlList>
+</AccessControlPolicy>"#;
+
+    async fn body_string(response: Response) -> String {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    /// Dispatches `method` on `uri` as a client would send it: the bucket
+    /// and key from the path, and a Content-Length that `headers` may
+    /// override.
+    async fn send(
+        state: &AppState,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> Result<Response> {
+        let (bucket, key) = parse_s3_path(uri.split('?').next().unwrap());
+        let mut header_map = HeaderMap::new();
+        header_map.insert(header::CONTENT_LENGTH, body.len().into());
+        for (name, value) in headers {
+            header_map.insert(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        dispatch_request(
+            state.clone(),
+            method,
+            uri.parse().unwrap(),
+            header_map,
+            bucket,
+            key,
+            Body::from(body.to_string()),
+        )
+        .await
+    }
+
+    /// The `UploadId` of a CreateMultipartUpload response body.
+    fn parse_upload_id(body: &str) -> String {
+        body.split("<UploadId>")
+            .nth(1)
+            .and_then(|rest| rest.split("</UploadId>").next())
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_hashing_stream_computes_correct_sha256() {
+        let data = b"hello world";
+        let expected_hash = hex::encode(Sha256::digest(data));
+
+        let chunks: Vec<std::result::Result<Bytes, std::io::Error>> =
+            vec![Ok(Bytes::from_static(data))];
+        let input_stream = stream::iter(chunks);
+
+        let (hashing_stream, hash_rx) = HashingStream::new_sha256(input_stream);
+
+        let collected: Vec<_> = hashing_stream.collect().await;
+        assert_eq!(collected.len(), 1);
+        assert_eq!(collected[0].as_ref().unwrap().as_ref(), data);
+
+        let computed_hash = hash_rx.await.unwrap();
+        assert_eq!(computed_hash, expected_hash);
+    }
+
+    #[tokio::test]
+    async fn test_hashing_stream_multiple_chunks() {
+        let chunk1 = b"hello ";
+        let chunk2 = b"world";
+        let mut hasher = Sha256::new();
+        hasher.update(chunk1);
+        hasher.update(chunk2);
+        let expected_hash = hex::encode(hasher.finalize());
+
+        let chunks: Vec<std::result::Result<Bytes, std::io::Error>> = vec![
+            Ok(Bytes::from_static(chunk1)),
+            Ok(Bytes::from_static(chunk2)),
+        ];
+        let input_stream = stream::iter(chunks);
+
+        let (hashing_stream, hash_rx) = HashingStream::new_sha256(input_stream);
+
+        let collected: Vec<_> = hashing_stream.collect().await;
+        assert_eq!(collected.len(), 2);
+
+        let computed_hash = hash_rx.await.unwrap();
+        assert_eq!(computed_hash, expected_hash);
+    }
+
+    #[tokio::test]
+    async fn test_hashing_stream_empty() {
+        let expected_hash = hex::encode(Sha256::digest(b""));
+
+        let chunks: Vec<std::result::Result<Bytes, std::io::Error>> = vec![];
+        let input_stream = stream::iter(chunks);
+
+        let (hashing_stream, hash_rx) = HashingStream::new_sha256(input_stream);
+
+        let collected: Vec<_> = hashing_stream.collect().await;
+        assert_eq!(collected.len(), 0);
+
+        let computed_hash = hash_rx.await.unwrap();
+        assert_eq!(computed_hash, expected_hash);
+    }
+
+    #[tokio::test]
+    async fn test_verify_download_fails_body_on_mismatch() {
+        let state = test_state();
+        let chunks = || {
+            stream::iter(vec![
+                Ok::<_, std::io::Error>(Bytes::from("hello ")),
+                Ok(Bytes::from("world")),
+            ])
+        };
+        let good = hex::encode_upper(Sha256::digest(b"hello world"));
+
+        let collected: Vec<_> = verify_download(&state, "a.txt", chunks(), good)
+            .collect()
+            .await;
+        assert_eq!(collected.len(), 2);
+        assert!(collected.iter().all(|c| c.is_ok()));
+
+        let bad = hex::encode(Sha256::digest(b"hello there"));
+        let collected: Vec<_> = verify_download(&state, "a.txt", chunks(), bad)
+            .collect()
+            .await;
+        assert_eq!(collected.len(), 3);
+        assert!(collected[2].is_err());
+
+        let metrics = state.integrity.render_metrics();
+        assert!(metrics.contains("bunny_s3_proxy_download_verified_total 1"));
+        assert!(metrics.contains("bunny_s3_proxy_download_checksum_mismatches_total 1"));
+    }
+
+    #[tokio::test]
+    async fn test_length_checked_stream_detects_short_body() {
+        let chunks: Vec<std::result::Result<Bytes, std::io::Error>> =
+            vec![Ok(Bytes::from_static(b"hello"))];
+        let (checked, progress) = LengthCheckedStream::new(stream::iter(chunks), Some(10));
+
+        let collected: Vec<_> = checked.collect().await;
+        assert_eq!(collected.len(), 2);
+        assert!(collected[1].is_err());
+        assert_eq!(progress.received.load(Ordering::Relaxed), 5);
+        assert!(progress.failed.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_length_checked_stream_complete_body() {
+        let chunks: Vec<std::result::Result<Bytes, std::io::Error>> =
+            vec![Ok(Bytes::from_static(b"hello"))];
+        let (checked, progress) = LengthCheckedStream::new(stream::iter(chunks), Some(5));
+
+        let collected: Vec<_> = checked.collect().await;
+        assert_eq!(collected.len(), 1);
+        assert!(!progress.failed.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_length_checked_stream_detects_long_body() {
+        let chunks: Vec<std::result::Result<Bytes, std::io::Error>> = vec![
+            Ok(Bytes::from_static(b"hello")),
+            Ok(Bytes::from_static(b" world")),
+            Ok(Bytes::from_static(b"!")),
+        ];
+        let (checked, progress) = LengthCheckedStream::new(stream::iter(chunks), Some(8));
+
+        // The chunk crossing the declared length is never forwarded.
+        let collected: Vec<_> = checked.collect().await;
+        assert_eq!(collected.len(), 2);
+        assert!(collected[1].is_err());
+        assert_eq!(progress.received.load(Ordering::Relaxed), 11);
+        assert!(progress.failed.load(Ordering::Relaxed));
+    }
+
+    /// Serves the S3 API of `state` on a local port as the proxy's listener
+    /// does, speaking HTTP/2 when `h2` and HTTP/1.1 otherwise.
+    async fn serve_s3(state: AppState, h2: bool) -> std::net::SocketAddr {
+        use hyper_util::rt::{TokioExecutor, TokioIo};
+        use tower::ServiceExt;
+
+        let app = axum::Router::new()
+            .route("/{*path}", axum::routing::any(handle_s3_request))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let app = app.clone();
+                tokio::spawn(async move {
+                    let io = TokioIo::new(stream);
+                    let service = hyper::service::service_fn(move |req| app.clone().oneshot(req));
+                    let _ = match h2 {
+                        true => {
+                            hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                                .serve_connection(io, service)
+                                .await
+                        }
+                        false => {
+                            hyper::server::conn::http1::Builder::new()
+                                .serve_connection(io, service)
+                                .await
+                        }
+                    };
+                });
+            }
+        });
+        addr
+    }
+
+    /// Sends a PUT of `path` declaring `declared` bytes but carrying `body`
+    /// over raw HTTP/1.1, half-closing the connection after it, and returns
+    /// what the proxy answered.
+    async fn put_http1(
+        addr: std::net::SocketAddr,
+        path: &str,
+        extra_headers: &str,
+        declared: usize,
+        body: &[u8],
+    ) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut conn = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let head = format!(
+            "PUT /test-zone/{} HTTP/1.1\r\nhost: localhost\r\ncontent-length: {}\r\n{}\r\n",
+            path, declared, extra_headers
+        );
+        conn.write_all(head.as_bytes()).await.unwrap();
+        conn.write_all(body).await.unwrap();
+        conn.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        let _ = conn.read_to_end(&mut response).await;
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_content_length_mismatch_http1() {
+        let state = mock_state(&[]).await;
+        let addr = serve_s3(state.clone(), false).await;
+
+        let response = put_http1(addr, "short.bin", "", 100, b"hello").await;
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+        assert!(response.contains("<Code>IncompleteBody</Code>"));
+        assert!(response.contains("(expected 100, received 5)"));
+        assert!(state.bunny.describe("short.bin").await.is_err());
+
+        // A checksum of the missing bytes cannot hold, but the short body
+        // is what gets reported.
+        let md5 = "content-md5: XrY7u+Ae7tCTyyK7j1rNww==\r\n";
+        let response = put_http1(addr, "short.bin", md5, 11, b"hello").await;
+        assert!(
+            response.contains("<Code>IncompleteBody</Code>"),
+            "{}",
+            response
+        );
+
+        let initiated = send(
+            &state,
+            Method::POST,
+            "/test-zone/parts.bin?uploads",
+            &[],
+            "",
+        )
+        .await
+        .unwrap();
+        let body = body_string(initiated).await;
+        let upload_id = parse_upload_id(&body);
+        let part = format!("parts.bin?partNumber=1&uploadId={}", upload_id);
+        let response = put_http1(addr, &part, "", 100, b"hello").await;
+        assert!(
+            response.contains("<Code>IncompleteBody</Code>"),
+            "{}",
+            response
+        );
+        let staged = format!("__multipart/{}/00001", upload_id);
+        assert!(state.bunny.describe(&staged).await.is_err());
+
+        // To HTTP/1.1 the bytes past the declared length are the next
+        // request, which is refused; the object holds just the declared ones.
+        let response = put_http1(addr, "long.bin", "", 5, b"hello world").await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        assert!(response.contains("HTTP/1.1 400 Bad Request"));
+        assert_eq!(state.bunny.describe("long.bin").await.unwrap().length, 5);
+    }
+
+    #[tokio::test]
+    async fn test_short_body_overwrite_keeps_the_object() {
+        let state = mock_state(&["--stage-overwrites"]).await;
+        let addr = serve_s3(state.clone(), false).await;
+
+        let response = send(&state, Method::PUT, "/test-zone/doc.txt", &[], "hello")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = put_http1(addr, "doc.txt", "", 100, b"bye").await;
+        assert!(
+            response.contains("<Code>IncompleteBody</Code>"),
+            "{}",
+            response
+        );
+
+        let response = send(&state, Method::GET, "/test-zone/doc.txt", &[], "")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "hello");
+    }
+
+    #[tokio::test]
+    async fn test_streamed_completion_error_keeps_the_request_id() {
+        let state = mock_state(&[]).await;
+        let addr = serve_s3(state.clone(), false).await;
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/test-zone/big.bin", addr);
+
+        let body = client
+            .post(format!("{}?uploads", url))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let upload_id = parse_upload_id(&body);
+        let part = client
+            .put(format!("{}?partNumber=1&uploadId={}", url, upload_id))
+            .body("hello")
+            .send()
+            .await
+            .unwrap();
+        let complete = format!(
+            "<CompleteMultipartUpload><Part><PartNumber>1</PartNumber><ETag>{}</ETag></Part></CompleteMultipartUpload>",
+            part.headers()[header::ETAG].to_str().unwrap()
+        );
+        let response = client
+            .post(format!("{}?uploadId={}", url, upload_id))
+            .header(MP_OBJECT_SIZE, "6")
+            .body(complete)
+            .send()
+            .await
+            .unwrap();
+        let request_id = response.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = response.text().await.unwrap();
+        assert!(body.contains("<Code>InvalidRequest</Code>"), "{}", body);
+        assert!(
+            body.contains(&format!("<RequestId>{}</RequestId>", request_id)),
+            "{}",
+            body
+        );
+    }
+
+    /// Sends a PUT declaring `declared` bytes but carrying `body` over
+    /// HTTP/2, and returns the status, or the error if the stream was reset.
+    async fn put_http2(
+        addr: std::net::SocketAddr,
+        key: &str,
+        declared: usize,
+        body: &'static [u8],
+    ) -> std::result::Result<StatusCode, String> {
+        use hyper_util::rt::{TokioExecutor, TokioIo};
+        let conn = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(conn))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let request = axum::http::Request::put(format!("http://localhost/test-zone/{}", key))
+            .header(header::CONTENT_LENGTH, declared)
+            .body(Body::from_stream(stream::iter([Ok::<_, std::io::Error>(
+                Bytes::from_static(body),
+            )])))
+            .unwrap();
+        match sender.send_request(request).await {
+            Ok(response) => Ok(response.status()),
+            Err(e) => Err(format!("{:?}", e)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_content_length_mismatch_http2() {
+        let state = mock_state(&[]).await;
+        let addr = serve_s3(state.clone(), true).await;
+
+        // HTTP/2 resets the stream, so the client never sees the S3 error,
+        // but nothing is stored either way.
+        for (key, declared, body) in [
+            ("short.bin", 100, &b"hello"[..]),
+            ("long.bin", 5, &b"hello world"[..]),
+        ] {
+            let reset = put_http2(addr, key, declared, body).await.unwrap_err();
+            assert!(reset.contains("PROTOCOL_ERROR"), "{}", reset);
+            for _ in 0..200 {
+                if state.activity.snapshot().is_empty() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert!(state.activity.snapshot().is_empty());
+            assert!(state.bunny.describe(key).await.is_err(), "{}", key);
+        }
+        assert_eq!(
+            put_http2(addr, "exact.bin", 5, b"hello").await,
+            Ok(StatusCode::OK)
+        );
+        assert_eq!(state.bunny.describe("exact.bin").await.unwrap().length, 5);
+    }
+
+    type StoredBytes = Arc<std::sync::Mutex<HashMap<String, usize>>>;
+
+    /// A stand-in for Bunny's PUT, DESCRIBE and DELETE that persists uploads
+    /// as their bytes arrive, so an interrupted upload leaves a truncated
+    /// object behind.
+    async fn partial_keeping_bunny() -> (String, StoredBytes) {
+        let stored = StoredBytes::default();
+        let objects = Arc::clone(&stored);
+        let app = axum::Router::new().route(
+            "/{*path}",
+            axum::routing::any(move |method: Method, uri: Uri, body: Body| {
+                let objects = Arc::clone(&objects);
+                async move {
+                    let path = uri.path().to_string();
+                    match method.as_str() {
+                        "DELETE" => {
+                            objects.lock().unwrap().retain(|p, _| {
+                                *p != path && !(path.ends_with('/') && p.starts_with(&path))
+                            });
+                            return StatusCode::OK.into_response();
+                        }
+                        "DESCRIBE" => {
+                            let Some(length) = objects.lock().unwrap().get(&path).copied() else {
+                                return StatusCode::NOT_FOUND.into_response();
+                            };
+                            let (dir, name) = path.rsplit_once('/').unwrap();
+                            return serde_json::json!({
+                                "Guid": "g", "UserId": "u", "StorageZoneName": "test-zone",
+                                "Path": format!("{}/", dir), "ObjectName": name, "Length": length,
+                                "LastChanged": "2024-05-01T12:00:00.750",
+                                "DateCreated": "2024-05-01T12:00:00.750",
+                                "StorageZoneId": 1, "IsDirectory": false, "ServerId": 1,
+                                "Checksum": "ABC123", "ContentType": "text/plain",
+                            })
+                            .to_string()
+                            .into_response();
+                        }
+                        _ => {}
+                    }
+                    objects.lock().unwrap().insert(path.clone(), 0);
+                    let mut body = body.into_data_stream();
+                    while let Some(Ok(chunk)) = body.next().await {
+                        *objects.lock().unwrap().entry(path.clone()).or_default() += chunk.len();
+                    }
+                    StatusCode::CREATED.into_response()
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, stored)
+    }
+
+    /// A body that sends one chunk of a much larger declared length, then
+    /// either fails or stalls, like a client that went away.
+    fn interrupted_body(fail: bool) -> Body {
+        let first = stream::once(async { Ok(Bytes::from(vec![7u8; 64 * 1024])) });
+        if fail {
+            let broken = stream::once(async {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionReset,
+                    "client went away",
+                ))
+            });
+            Body::from_stream(first.chain(broken))
+        } else {
+            Body::from_stream(first.chain(stream::pending::<std::io::Result<Bytes>>()))
+        }
+    }
+
+    async fn wait_for(stored: &StoredBytes, done: impl Fn(&HashMap<String, usize>) -> bool) {
+        for _ in 0..200 {
+            if done(&stored.lock().unwrap()) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!(
+            "mock Bunny state never settled: {:?}",
+            stored.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_uploads_leave_nothing_behind() {
+        let (url, stored) = partial_keeping_bunny().await;
+        let mut state = test_state();
+        state.bunny = state.bunny.with_base_url(&url);
+        let declared = Some(1024 * 1024);
+
+        // The body fails: the handler deletes what Bunny kept and reports it.
+        let err = handle_put_object_stream(
+            state.clone(),
+            "test-zone",
+            "broken.bin",
+            &HeaderMap::new(),
+            interrupted_body(true),
+            declared,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "IncompleteBody");
+        assert!(stored.lock().unwrap().is_empty());
+
+        // The client disconnects and hyper drops the handler mid-upload.
+        let put = tokio::spawn({
+            let state = state.clone();
+            async move {
+                let headers = HeaderMap::new();
+                let body = interrupted_body(false);
+                handle_put_object_stream(
+                    state,
+                    "test-zone",
+                    "dropped.bin",
+                    &headers,
+                    body,
+                    declared,
+                    None,
+                )
+                .await
+            }
+        });
+        let part = tokio::spawn({
+            let state = state.clone();
+            async move {
+                handle_upload_part_stream(
+                    state,
+                    "test-zone",
+                    "uploadId=abc&partNumber=1",
+                    &HeaderMap::new(),
+                    interrupted_body(false),
+                    declared,
+                )
+                .await
+            }
+        });
+        wait_for(&stored, |objects| {
+            objects.values().filter(|len| **len > 0).count() == 2
+        })
+        .await;
+        assert!(
+            stored
+                .lock()
+                .unwrap()
+                .contains_key("/test-zone/__multipart/abc/00001")
+        );
+        put.abort();
+        part.abort();
+        wait_for(&stored, HashMap::is_empty).await;
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_overwrites_keep_the_object() {
+        let (url, stored) = partial_keeping_bunny().await;
+        let mut state = test_state();
+        let mut config = (*state.config).clone();
+        config.stage_overwrites = true;
+        state.config = Arc::new(config);
+        state.bunny = state.bunny.with_base_url(&url);
+        let declared = Some(1024 * 1024);
+        let original = HashMap::from([("/test-zone/doc.bin".to_string(), 5)]);
+        *stored.lock().unwrap() = original.clone();
+
+        // The truncated body went to a staging path, which is all that goes.
+        let err = handle_put_object_stream(
+            state.clone(),
+            "test-zone",
+            "doc.bin",
+            &HeaderMap::new(),
+            interrupted_body(true),
+            declared,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "IncompleteBody");
+        assert_eq!(*stored.lock().unwrap(), original);
+
+        let put = tokio::spawn({
+            let state = state.clone();
+            async move {
+                handle_put_object_stream(
+                    state,
+                    "test-zone",
+                    "doc.bin",
+                    &HeaderMap::new(),
+                    interrupted_body(false),
+                    declared,
+                    None,
+                )
+                .await
+            }
+        });
+        wait_for(&stored, |objects| {
+            objects
+                .keys()
+                .any(|path| path.starts_with("/test-zone/__multipart/"))
+        })
+        .await;
+        put.abort();
+        wait_for(&stored, |objects| *objects == original).await;
+    }
+
+    #[tokio::test]
+    async fn test_staged_overwrites_fail_loudly() {
+        let uploaded = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = Arc::clone(&uploaded);
+        let app = axum::Router::new().route(
+            "/{*path}",
+            axum::routing::any(move |method: Method, uri: Uri| {
+                let log = Arc::clone(&log);
+                async move {
+                    let path = uri.path().to_string();
+                    match method.as_str() {
+                        "DESCRIBE" if path == "/test-zone/doc.txt" => serde_json::json!({
+                            "Guid": "g", "UserId": "u", "StorageZoneName": "test-zone",
+                            "Path": "/test-zone/", "ObjectName": "doc.txt", "Length": 5,
+                            "LastChanged": "2024-05-01T12:00:00.750",
+                            "DateCreated": "2024-05-01T12:00:00.750",
+                            "StorageZoneId": 1, "IsDirectory": false, "ServerId": 1,
+                            "Checksum": "ABC123", "ContentType": "text/plain",
+                        })
+                        .to_string()
+                        .into_response(),
+                        "DESCRIBE" if path == "/test-zone/broken.txt" => {
+                            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                        }
+                        "DESCRIBE" => StatusCode::NOT_FOUND.into_response(),
+                        // The staged copy comes back shorter than was sent.
+                        "GET" => "abc".into_response(),
+                        "PUT" => {
+                            log.lock().unwrap().push(path);
+                            StatusCode::CREATED.into_response()
+                        }
+                        _ => StatusCode::OK.into_response(),
+                    }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let mut state = test_state();
+        let mut config = (*state.config).clone();
+        config.stage_overwrites = true;
+        state.config = Arc::new(config);
+        state.bunny = state.bunny.with_base_url(&url);
+        let headers = HeaderMap::new();
+        let put = |key: &'static str| {
+            handle_put_object_stream(
+                state.clone(),
+                "test-zone",
+                key,
+                &headers,
+                Body::from("hello"),
+                Some(5),
+                None,
+            )
+        };
+
+        // The body was staged in full, but the move is refused.
+        let err = put("doc.txt").await.unwrap_err();
+        assert_eq!(err.s3_error_code(), "InternalError");
+        let paths = uploaded.lock().unwrap().clone();
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].starts_with("/test-zone/__multipart/"));
+
+        // Nothing is written when it is unknown whether the key is taken.
+        uploaded.lock().unwrap().clear();
+        put("broken.txt").await.unwrap_err();
+        assert!(uploaded.lock().unwrap().is_empty());
+
+        // A key Bunny does not hold is written in place.
+        put("new.txt").await.unwrap();
+        assert_eq!(*uploaded.lock().unwrap(), ["/test-zone/new.txt"]);
+    }
+
+    /// A stand-in for Bunny holding one object, `doc.txt`, last changed at
+    /// 12:00:00.750; records the paths deleted.
+    async fn single_object_bunny() -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        let deleted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = Arc::clone(&deleted);
+        let app = axum::Router::new().route(
+            "/{*path}",
+            axum::routing::any(move |method: Method, uri: Uri| {
+                let log = Arc::clone(&log);
+                async move {
+                    let path = uri.path().to_string();
+                    match method.as_str() {
+                        "DESCRIBE" if path == "/test-zone/doc.txt" => serde_json::json!({
+                            "Guid": "g", "UserId": "u", "StorageZoneName": "test-zone",
+                            "Path": "/test-zone/", "ObjectName": "doc.txt", "Length": 5,
+                            "LastChanged": "2024-05-01T12:00:00.750",
+                            "DateCreated": "2024-05-01T12:00:00.750",
+                            "StorageZoneId": 1, "IsDirectory": false, "ServerId": 1,
+                            "Checksum": "ABC123", "ContentType": "text/plain",
+                        })
+                        .to_string()
+                        .into_response(),
+                        "DELETE" => {
+                            log.lock().unwrap().push(path);
+                            StatusCode::OK.into_response()
+                        }
+                        _ => StatusCode::NOT_FOUND.into_response(),
+                    }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, deleted)
+    }
+
+    #[test]
+    fn test_conditional_write_comparisons() {
+        let changed = chrono::DateTime::parse_from_rfc3339("2024-05-01T12:00:00.750Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let date = |s| {
+            chrono::DateTime::parse_from_rfc2822(s)
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+        // The Last-Modified the proxy reports for it, echoed back, still holds.
+        assert!(unmodified_since(
+            changed,
+            date("Wed, 01 May 2024 12:00:00 GMT")
+        ));
+        assert!(unmodified_since(
+            changed,
+            date("Wed, 01 May 2024 12:00:01 GMT")
+        ));
+        assert!(!unmodified_since(
+            changed,
+            date("Wed, 01 May 2024 11:59:59 GMT")
+        ));
+
+        assert!(etag_matches("\"ABC123\"", "ABC123"));
+        assert!(etag_matches("\"x\", \"ABC123\"", "\"ABC123\""));
+        assert!(etag_matches("*", "ABC123"));
+        assert!(!etag_matches("\"abc\"", "ABC123"));
+    }
+
+    #[tokio::test]
+    async fn test_conditional_delete() {
+        let (url, deleted) = single_object_bunny().await;
+        let mut state = test_state();
+        state.bunny = state.bunny.with_base_url(&url);
+        let delete = |key: &'static str, pairs: &[(header::HeaderName, &str)]| {
+            let state = state.clone();
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(name.clone(), value.parse().unwrap());
+            }
+            async move { handle_delete_object(state, "test-zone", key, "", &headers).await }
+        };
+
+        let stale = [(header::IF_UNMODIFIED_SINCE, "Wed, 01 May 2024 11:59:59 GMT")];
+        let err = delete("doc.txt", &stale).await.unwrap_err();
+        assert_eq!(err.s3_error_code(), "PreconditionFailed");
+        let err = delete("doc.txt", &[(header::IF_MATCH, "\"other\"")])
+            .await
+            .unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::PRECONDITION_FAILED);
+        let err = delete("missing.txt", &[(header::IF_MATCH, "*")])
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "NoSuchKey");
+        assert!(deleted.lock().unwrap().is_empty());
+
+        // If-Match wins over a failing If-Unmodified-Since, as in RFC 9110.
+        let both = [
+            (header::IF_MATCH, "\"ABC123\""),
+            (header::IF_UNMODIFIED_SINCE, "Wed, 01 May 2024 11:59:59 GMT"),
+        ];
+        delete("doc.txt", &both).await.unwrap();
+        let current = [(header::IF_UNMODIFIED_SINCE, "Wed, 01 May 2024 12:00:00 GMT")];
+        delete("doc.txt", &current).await.unwrap();
+        delete("missing.txt", &current).await.unwrap();
+        let mut paths = deleted.lock().unwrap().clone();
+        paths.retain(|p| !p.contains("/__meta/"));
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "/test-zone/doc.txt",
+                "/test-zone/doc.txt",
+                "/test-zone/missing.txt"
+            ]
+        );
+
+        // A conditional write already holding the key's lock conflicts.
+        let _held = state.lock.try_lock("doc.txt").await.unwrap();
+        let err = delete("doc.txt", &current).await.unwrap_err();
+        assert_eq!(err.s3_error_code(), "ConditionalRequestConflict");
+    }
+
+    #[tokio::test]
+    async fn test_conditional_write_strategies() {
+        let (url, deleted) = single_object_bunny().await;
+        let state_for = |strategy: ConditionalWrites| {
+            let mut state = test_state();
+            let mut config = (*state.config).clone();
+            config.conditional_writes = strategy;
+            state.config = Arc::new(config);
+            state.bunny = state.bunny.with_base_url(&url);
+            state
+        };
+        let headers = |name: header::HeaderName, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, value.parse().unwrap());
+            headers
+        };
+        let stale = headers(header::IF_MATCH, "\"other\"");
+        let current = headers(header::IF_MATCH, "\"ABC123\"");
+
+        // Fast still checks the object, but not under the lock.
+        let state = state_for(ConditionalWrites::Fast);
+        let _held = state.lock.try_lock("doc.txt").await.unwrap();
+        let err = lock_for_conditional_write(&state, "doc.txt", &stale)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.s3_error_code(), "PreconditionFailed");
+        let guard = lock_for_conditional_write(&state, "doc.txt", &current)
+            .await
+            .unwrap();
+        assert!(guard.is_none());
+
+        // Locked holds the key's lock for the write.
+        let state = state_for(ConditionalWrites::Locked);
+        assert!(
+            lock_for_conditional_write(&state, "doc.txt", &current)
+                .await
+                .unwrap()
+                .is_some()
+        );
+
+        // Off writes whatever the preconditions say.
+        let state = state_for(ConditionalWrites::Off);
+        handle_delete_object(state, "test-zone", "doc.txt", "", &stale)
+            .await
+            .unwrap();
+        assert!(
+            deleted
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|p| p == "/test-zone/doc.txt")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_uploads_without_content_type_get_a_guessed_one() {
+        let sent = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let log = Arc::clone(&sent);
+        let app = axum::Router::new().route(
+            "/{*path}",
+            axum::routing::any(move |method: Method, uri: Uri, headers: HeaderMap| {
+                let log = Arc::clone(&log);
+                async move {
+                    if method != Method::PUT {
+                        return StatusCode::OK;
+                    }
+                    let content_type = headers
+                        .get("Override-Content-Type")
+                        .map(|v| v.to_str().unwrap().to_string());
+                    log.lock()
+                        .unwrap()
+                        .insert(uri.path().to_string(), content_type);
+                    StatusCode::CREATED
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let mut state = test_state();
+        state.bunny = state.bunny.with_base_url(&url);
+
+        let mut headers = HeaderMap::new();
+        let body = Bytes::from_static(b"<html></html>");
+        handle_put_object(
+            state.clone(),
+            "test-zone",
+            "site/index.html",
+            &headers,
+            body.clone(),
+        )
+        .await
+        .unwrap();
+        handle_put_object_stream(
+            state.clone(),
+            "test-zone",
+            "photo.JPG",
+            &headers,
+            Body::from("jpeg"),
+            Some(4),
+            None,
+        )
+        .await
+        .unwrap();
+        headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+        handle_put_object(state.clone(), "test-zone", "notes.html", &headers, body)
+            .await
+            .unwrap();
+
+        let sent = sent.lock().unwrap();
+        let sent = |path: &str| sent[path].as_deref();
+        assert_eq!(sent("/test-zone/site/index.html"), Some("text/html"));
+        assert_eq!(sent("/test-zone/photo.JPG"), Some("image/jpeg"));
+        assert_eq!(sent("/test-zone/notes.html"), Some("text/plain"));
+    }
+
+    #[tokio::test]
+    async fn test_get_bucket_versioning_is_unversioned() {
+        let response = subresource_request(Method::GET, Subresource::Versioning, None, "")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_string(response).await;
+        assert!(body.contains("<VersioningConfiguration"));
+        assert!(!body.contains("<Status>"));
+    }
+
+    #[tokio::test]
+    async fn test_put_bucket_versioning() {
+        let suspended = r#"<VersioningConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><Status>Suspended</Status></VersioningConfiguration>"#;
+        let response = subresource_request(Method::PUT, Subresource::Versioning, None, suspended)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let empty = "<VersioningConfiguration/>";
+        let response = subresource_request(Method::PUT, Subresource::Versioning, None, empty)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let enabled = "<VersioningConfiguration><Status>Enabled</Status></VersioningConfiguration>";
+        let err = subresource_request(Method::PUT, Subresource::Versioning, None, enabled)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::NOT_IMPLEMENTED);
+    }
+
+    #[tokio::test]
+    async fn test_get_bucket_acl_shape() {
+        let response = subresource_request(Method::GET, Subresource::Acl, None, "")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/xml");
+        assert_eq!(body_string(response).await, OWNER_ONLY_ACL);
+    }
+
+    #[tokio::test]
+    async fn test_put_bucket_acl_accepts_document() {
+        let body = r#"<AccessControlPolicy><Owner><ID>x</ID></Owner><AccessControlList><Grant><Grantee xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:type="Group"><URI>http://acs.amazonaws.com/groups/global/AllUsers</URI></Grantee><Permission>READ</Permission></Grant></AccessControlList></AccessControlPolicy>"#;
+        let response = subresource_request(Method::PUT, Subresource::Acl, None, body)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_object_acl_needs_the_object() {
+        let state = mock_state(&[]).await;
+        send(&state, Method::PUT, "/test-zone/doc.txt", &[], "hello")
+            .await
+            .unwrap();
+        let response = send(&state, Method::GET, "/test-zone/doc.txt?acl", &[], "")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, OWNER_ONLY_ACL);
+        let response = send(
+            &state,
+            Method::PUT,
+            "/test-zone/doc.txt?acl",
+            &[("x-amz-acl", "private")],
+            "",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for method in [Method::GET, Method::PUT] {
+            let err = send(&state, method, "/test-zone/missing.txt?acl", &[], "")
+                .await
+                .unwrap_err();
+            assert_eq!(err.s3_error_code(), "NoSuchKey");
+            assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_bucket_encryption_not_claimed() {
+        let err = subresource_request(Method::GET, Subresource::Encryption, None, "")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.s3_error_code(),
+            "ServerSideEncryptionConfigurationNotFoundError"
+        );
+        assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_sse_header_handling() {
+        let mut headers = HeaderMap::new();
+        headers.insert(sse::SSE_HEADER, "AES256".parse().unwrap());
+        assert!(sse::validate_request_headers(&headers, false).is_ok());
+        assert!(sse::response_header(&Method::PUT, &headers, true, false).is_some());
+        assert!(sse::response_header(&Method::GET, &headers, true, false).is_none());
+        assert!(sse::response_header(&Method::GET, &HeaderMap::new(), true, true).is_some());
+
+        headers.insert(sse::SSE_HEADER, "aws:kms".parse().unwrap());
+        let err = sse::validate_request_headers(&headers, false).unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::NOT_IMPLEMENTED);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-amz-server-side-encryption-customer-algorithm",
+            "AES256".parse().unwrap(),
+        );
+        assert!(sse::validate_request_headers(&headers, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bucket_policy_status_and_validation() {
+        let response = subresource_request(Method::GET, Subresource::PolicyStatus, None, "")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            body_string(response)
+                .await
+                .contains("<PolicyStatus xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\"><IsPublic>false</IsPublic></PolicyStatus>")
+        );
+
+        let err = subresource_request(Method::PUT, Subresource::Policy, None, "{not json")
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "MalformedPolicy");
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_public_access_block_and_ownership_controls() {
+        let response = subresource_request(Method::GET, Subresource::PublicAccessBlock, None, "")
+            .await
+            .unwrap();
+        let body = body_string(response).await;
+        for field in [
+            "BlockPublicAcls",
+            "IgnorePublicAcls",
+            "BlockPublicPolicy",
+            "RestrictPublicBuckets",
+        ] {
+            assert!(body.contains(&format!("<{0}>true</{0}>", field)), "{field}");
+        }
+
+        let response = subresource_request(Method::GET, Subresource::OwnershipControls, None, "")
+            .await
+            .unwrap();
+        assert!(
+            body_string(response)
+                .await
+                .contains("<Rule><ObjectOwnership>BucketOwnerEnforced</ObjectOwnership></Rule>")
+        );
+
+        let response =
+            subresource_request(Method::DELETE, Subresource::PublicAccessBlock, None, "")
+                .await
+                .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_create_bucket_validates_name() {
+        let body = "<CreateBucketConfiguration><LocationConstraint>eu-west-1</LocationConstraint></CreateBucketConfiguration>";
+        let response = handle_create_bucket(
+            test_state(),
+            "test-zone",
+            Bytes::from_static(body.as_bytes()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::LOCATION], "/test-zone");
+
+        let err = handle_create_bucket(test_state(), "other-zone", Bytes::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "AccessDenied");
+
+        let err = handle_create_bucket(test_state(), "Bad_Name", Bytes::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "InvalidBucketName");
+    }
+
+    #[tokio::test]
+    async fn test_create_bucket_conflict_mode() {
+        let mut state = test_state();
+        Arc::get_mut(&mut state.config)
+            .unwrap()
+            .create_bucket_conflict = true;
+        let err = handle_create_bucket(state, "test-zone", Bytes::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "BucketAlreadyOwnedByYou");
+        assert_eq!(err.status_code(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn test_bucket_as_prefix_resolution() {
+        let state = test_state();
+        assert!(state.check_bucket("test-zone").is_ok());
+        assert_eq!(
+            state.check_bucket("photos").unwrap_err().s3_error_code(),
+            "NoSuchBucket"
+        );
+
+        let mut state = test_state();
+        Arc::get_mut(&mut state.config).unwrap().bucket_as_prefix = true;
+        assert!(state.bucket_client("photos").is_ok());
+        assert!(state.bucket_client("test-zone").is_ok());
+        for reserved in ["__multipart", "__meta", "__config", "../photos", "Photos"] {
+            assert_eq!(
+                state.check_bucket(reserved).unwrap_err().s3_error_code(),
+                "NoSuchBucket"
+            );
+        }
+    }
+
+    fn post_form(fields: &[(&str, &str)]) -> (HeaderMap, Body) {
+        let boundary = "----proxyformboundary";
+        let mut body = String::new();
+        for (name, value) in fields {
+            body.push_str(&format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            ));
+        }
+        body.push_str(&format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"photo.jpg\"\r\nContent-Type: image/jpeg\r\n\r\ndata\r\n--{}--\r\n",
+            boundary, boundary
+        ));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={}", boundary)
+                .parse()
+                .unwrap(),
+        );
+        (headers, Body::from(body))
+    }
+
+    fn sign_policy(policy: &str, credential: &str) -> String {
+        use hmac::{Hmac, Mac};
+        let hmac = |key: &[u8], data: &str| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+            mac.update(data.as_bytes());
+            mac.finalize().into_bytes().to_vec()
+        };
+        let scope: Vec<&str> = credential.split('/').collect();
+        let key = hmac(b"AWS4bunny", scope[1]);
+        let key = hmac(&key, scope[2]);
+        let key = hmac(&key, scope[3]);
+        let key = hmac(&key, "aws4_request");
+        hex::encode(hmac(&key, policy))
+    }
+
+    async fn post_object(
+        policy_json: &str,
+        extra: &[(&str, &str)],
+        sign: bool,
+    ) -> Result<Response> {
+        use base64::Engine;
+        let credential = "bunny/20261016/us-east-1/s3/aws4_request";
+        let policy = base64::engine::general_purpose::STANDARD.encode(policy_json);
+        let signature = if sign {
+            sign_policy(&policy, credential)
+        } else {
+            "0".repeat(64)
+        };
+        let mut fields = vec![
+            ("key", "uploads/${filename}"),
+            ("x-amz-algorithm", "AWS4-HMAC-SHA256"),
+            ("x-amz-credential", credential),
+            ("x-amz-date", "20261016T000000Z"),
+            ("policy", policy.as_str()),
+            ("x-amz-signature", signature.as_str()),
+        ];
+        fields.extend_from_slice(extra);
+        let (headers, body) = post_form(&fields);
+        handle_post_object(test_state(), "test-zone", &headers, body).await
+    }
+
+    #[tokio::test]
+    async fn test_post_object_rejects_bad_signature() {
+        let policy = r#"{"expiration": "2999-01-01T00:00:00Z", "conditions": []}"#;
+        let err = post_object(policy, &[], false).await.unwrap_err();
+        assert_eq!(err.s3_error_code(), "AccessDenied");
+    }
+
+    #[tokio::test]
+    async fn test_post_object_enforces_policy_conditions() {
+        let policy = r#"{"expiration": "2999-01-01T00:00:00Z", "conditions": [{"bucket": "test-zone"}, ["starts-with", "$key", "private/"], {"x-amz-algorithm": "AWS4-HMAC-SHA256"}, {"x-amz-credential": "bunny/20261016/us-east-1/s3/aws4_request"}, {"x-amz-date": "20261016T000000Z"}]}"#;
+        let err = post_object(policy, &[], true).await.unwrap_err();
+        assert!(err.to_string().contains("$key"), "{}", err);
+
+        let policy = r#"{"expiration": "2000-01-01T00:00:00Z", "conditions": []}"#;
+        let err = post_object(policy, &[], true).await.unwrap_err();
+        assert!(err.to_string().contains("Policy expired"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_post_object_checks_the_key() {
+        let policy = r#"{"expiration": "2999-01-01T00:00:00Z", "conditions": []}"#;
+        for key in ["uploads/../private/${filename}", "./${filename}"] {
+            let err = post_object(policy, &[("key", key)], true)
+                .await
+                .unwrap_err();
+            assert_eq!(err.s3_error_code(), "AccessDenied", "{}", key);
+        }
+        let long = "a".repeat(1025);
+        let err = post_object(policy, &[("key", &long)], true)
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "KeyTooLongError");
+    }
+
+    #[tokio::test]
+    async fn test_only_bucket_forms_skip_the_anonymous_check() {
+        let state = mock_state(&["--require-auth"]).await;
+        state
+            .bunny
+            .upload("doc.txt", Bytes::from("secret"), UploadOptions::default())
+            .await
+            .unwrap();
+        let form = [("content-type", "multipart/form-data; boundary=x")];
+        for uri in [
+            "/test-zone/doc.txt?select&select-type=2",
+            "/test-zone/doc.txt?uploads",
+            "/test-zone/doc.txt?uploadId=abc",
+            "/test-zone?delete",
+        ] {
+            let err = send(&state, Method::POST, uri, &form, "")
+                .await
+                .unwrap_err();
+            assert_eq!(err.s3_error_code(), "AccessDenied", "{}", uri);
+        }
+        // The form itself still reaches its handler, which wants a policy.
+        let err = send(&state, Method::POST, "/test-zone", &form, "")
+            .await
+            .unwrap_err();
+        assert_ne!(err.s3_error_code(), "AccessDenied", "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_post_object_requires_policy() {
+        let (headers, body) = post_form(&[("key", "uploads/photo.jpg")]);
+        let err = handle_post_object(test_state(), "test-zone", &headers, body)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("'policy'"), "{}", err);
+    }
+
+    #[test]
+    fn test_null_version_id_header_placement() {
+        assert!(returns_null_version_id(&Method::PUT, "", true));
+        assert!(returns_null_version_id(&Method::DELETE, "", true));
+        assert!(returns_null_version_id(&Method::POST, "uploadId=abc", true));
+        assert!(returns_null_version_id(
+            &Method::GET,
+            "versionId=null",
+            true
+        ));
+        assert!(!returns_null_version_id(&Method::GET, "", true));
+        assert!(!returns_null_version_id(
+            &Method::PUT,
+            "partNumber=1&uploadId=abc",
+            true
+        ));
+        assert!(!returns_null_version_id(&Method::POST, "uploads", true));
+        assert!(!returns_null_version_id(&Method::PUT, "acl", true));
+        assert!(!returns_null_version_id(&Method::DELETE, "", false));
+    }
+
+    #[test]
+    fn test_parse_s3_path_decodes_keys() {
+        assert_eq!(
+            parse_s3_path("/zone/reports/2024%23final%3F.csv"),
+            (
+                Some("zone".to_string()),
+                Some("reports/2024#final?.csv".to_string())
+            )
+        );
+        assert_eq!(
+            parse_s3_path("/zone/a%20b+c.txt").1.as_deref(),
+            Some("a b+c.txt")
+        );
+        assert_eq!(
+            parse_s3_path("/zone/emoji-%F0%9F%A6%80.bin").1.as_deref(),
+            Some("emoji-🦀.bin")
+        );
+        assert_eq!(parse_s3_path("/zone/"), (Some("zone".to_string()), None));
+        let source = CopySource::parse("/zone/a%20b%25.txt?versionId=null").unwrap();
+        assert_eq!(source.key, "a b%.txt");
+    }
+
+    #[test]
+    fn test_validate_key_limits() {
+        let longest = "k".repeat(MAX_KEY_LENGTH);
+        assert!(validate_key(&longest).is_ok());
+        // Multi-byte characters count in UTF-8 bytes: 341 * 3 + 1 = 1024.
+        assert!(validate_key(&format!("{}k", "€".repeat(341))).is_ok());
+        assert!(validate_key(&format!("{}kk", "€".repeat(341))).is_err());
+
+        let too_long = format!("{}k", longest);
+        let err = validate_key(&too_long).unwrap_err();
+        assert_eq!(err.s3_error_code(), "KeyTooLongError");
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        let xml = err.to_xml(Some("zone"), Some(&too_long), "id");
+        assert!(xml.contains("1025 bytes, the maximum is 1024"));
+        assert!(xml.contains(&format!("<Key>{}</Key>", too_long)));
+
+        for bad in ["", "a\u{0}b", "line\nbreak", "tab\there", "del\u{7f}"] {
+            let err = validate_key(bad).unwrap_err();
+            assert_eq!(err.s3_error_code(), "InvalidArgument", "{:?}", bad);
+        }
+        for good in ["a b/c?d#e%", " leading", "dots..", "emoji 🦀", "ends/"] {
+            assert!(validate_key(good).is_ok(), "{:?}", good);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_objects_rejects_invalid_keys() {
+        let (url, deleted) = single_object_bunny().await;
+        let mut state = test_state();
+        state.bunny = state.bunny.with_base_url(&url);
+        let body = format!(
+            "<Delete><Object><Key>{}</Key></Object><Object><Key>doc.txt</Key></Object></Delete>",
+            "k".repeat(MAX_KEY_LENGTH + 1)
+        );
+        let response =
+            handle_delete_objects(state, "test-zone", &HeaderMap::new(), Bytes::from(body))
+                .await
+                .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("<Code>KeyTooLongError</Code>"));
+        assert!(body.contains("<Deleted><Key>doc.txt</Key>"));
+        assert!(!deleted.lock().unwrap().iter().any(|p| p.contains("kkk")));
+    }
+
+    #[tokio::test]
+    async fn test_delete_objects_content_md5() {
+        let (url, deleted) = single_object_bunny().await;
+        let mut state = test_state();
+        state.bunny = state.bunny.with_base_url(&url);
+        let body = Bytes::from_static(b"<Delete><Object><Key>doc.txt</Key></Object></Delete>");
+        let md5 = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("content-md5", value.parse().unwrap());
+            headers
+        };
+
+        for (headers, code) in [
+            (md5("XrY7u+Ae7tCTyyK7j1rNww=="), "BadDigest"),
+            (md5("not base64!"), "InvalidDigest"),
+            (md5("AAAA"), "InvalidDigest"),
+        ] {
+            let err = handle_delete_objects(state.clone(), "test-zone", &headers, body.clone())
+                .await
+                .unwrap_err();
+            assert_eq!(err.s3_error_code(), code);
+            assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        }
+        assert!(deleted.lock().unwrap().is_empty());
+
+        let mut strict = state.clone();
+        let mut config = (*state.config).clone();
+        config.require_delete_md5 = true;
+        strict.config = Arc::new(config);
+        let err =
+            handle_delete_objects(strict.clone(), "test-zone", &HeaderMap::new(), body.clone())
+                .await
+                .unwrap_err();
+        assert_eq!(err.s3_error_code(), "MissingContentMD5");
+
+        let digest = <md5::Md5 as md5::Digest>::digest(&body);
+        let value = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, digest);
+        handle_delete_objects(strict.clone(), "test-zone", &md5(&value), body.clone())
+            .await
+            .unwrap();
+        let mut checksum = HeaderMap::new();
+        checksum.insert("x-amz-checksum-crc32", "AAAAAA==".parse().unwrap());
+        handle_delete_objects(strict, "test-zone", &checksum, body)
+            .await
+            .unwrap();
+        assert!(
+            deleted
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|p| p.ends_with("doc.txt"))
+        );
+    }
+
+    #[test]
+    fn test_if_range_validators() {
+        let holds = |if_range: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(v) = if_range {
+                headers.insert(header::IF_RANGE, v.parse().unwrap());
+            }
+            if_range_holds(
+                &headers,
+                Some("\"abc\""),
+                Some("Wed, 01 May 2024 12:00:00 GMT"),
+            )
+        };
+        assert!(holds(None));
+        assert!(holds(Some("\"abc\"")));
+        assert!(!holds(Some("\"old\"")));
+        assert!(!holds(Some("W/\"abc\"")));
+        assert!(holds(Some("Wed, 01 May 2024 12:00:00 GMT")));
+        assert!(!holds(Some("Wed, 01 May 2024 11:59:59 GMT")));
+        assert!(!holds(Some("Wed, 01 May 2024 12:00:01 GMT")));
+        assert!(!holds(Some("yesterday")));
+    }
+
+    #[tokio::test]
+    async fn test_get_object_if_range() {
+        let app = axum::Router::new().route(
+            "/test-zone/movie.mp4",
+            axum::routing::get(|headers: HeaderMap| async move {
+                let validators = [
+                    (header::ETAG, "\"v2\""),
+                    (header::LAST_MODIFIED, "Wed, 01 May 2024 12:00:00 GMT"),
+                ];
+                match headers.get(header::RANGE) {
+                    Some(_) => (
+                        StatusCode::PARTIAL_CONTENT,
+                        [(header::CONTENT_RANGE, "bytes 2-4/10")],
+                        validators,
+                        "234",
+                    )
+                        .into_response(),
+                    None => (validators, "0123456789").into_response(),
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let mut state = test_state();
+        state.bunny = state.bunny.with_base_url(&url);
+
+        let get = |if_range: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::RANGE, "bytes=2-4".parse().unwrap());
+            headers.insert(header::IF_RANGE, if_range.parse().unwrap());
+            let state = state.clone();
+            async move {
+                let response = handle_get_object(state, "test-zone", "movie.mp4", &headers)
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (status, body)
+            }
+        };
+
+        for current in ["\"v2\"", "Wed, 01 May 2024 12:00:00 GMT"] {
+            let (status, body) = get(current).await;
+            assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+            assert_eq!(body, "234");
+        }
+        for stale in ["\"v1\"", "Tue, 30 Apr 2024 08:00:00 GMT"] {
+            let (status, body) = get(stale).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, "0123456789");
+        }
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let range = |v: &str| parse_range(v, 100).map(|r| r.map_err(|e| e.s3_error_code()));
+        assert_eq!(range("bytes=0-9"), Some(Ok((0, 9))));
+        assert_eq!(range("bytes=90-"), Some(Ok((90, 99))));
+        assert_eq!(range("bytes=-10"), Some(Ok((90, 99))));
+        assert_eq!(range("bytes=-500"), Some(Ok((0, 99))));
+        assert_eq!(range("bytes=50-500"), Some(Ok((50, 99))));
+        assert_eq!(range("bytes=100-"), Some(Err("InvalidRange")));
+        assert_eq!(range("bytes=-0"), Some(Err("InvalidRange")));
+        assert_eq!(range("bytes=0-1,5-6"), None);
+        assert_eq!(range("bytes=9-1"), None);
+        assert_eq!(range("items=0-1"), None);
+        assert_eq!(parse_range("bytes=0-", 0).map(|r| r.is_err()), Some(true));
+    }
+
+    #[test]
+    fn test_operation_name() {
+        let none = HeaderMap::new();
+        let mut copy = HeaderMap::new();
+        copy.insert("x-amz-copy-source", "/zone/src".parse().unwrap());
+        let mut rename = HeaderMap::new();
+        rename.insert(RENAME_SOURCE, "/zone/src".parse().unwrap());
+
+        let cases = [
+            (Method::GET, false, false, "", &none, "ListBuckets"),
+            (
+                Method::GET,
+                true,
+                false,
+                "list-type=2",
+                &none,
+                "ListObjectsV2",
+            ),
+            (Method::GET, true, false, "marker=a", &none, "ListObjects"),
+            (
+                Method::GET,
+                true,
+                false,
+                "uploads",
+                &none,
+                "ListMultipartUploads",
+            ),
+            (
+                Method::GET,
+                true,
+                false,
+                "versions",
+                &none,
+                "ListObjectVersions",
+            ),
+            (
+                Method::PUT,
+                true,
+                false,
+                "tagging",
+                &none,
+                "PutBucketTagging",
+            ),
+            (Method::POST, true, false, "delete", &none, "DeleteObjects"),
+            (Method::GET, true, true, "", &none, "GetObject"),
+            (Method::PUT, true, true, "", &copy, "CopyObject"),
+            (Method::PUT, true, true, "", &rename, "RenameObject"),
+            (
+                Method::PUT,
+                true,
+                true,
+                "partNumber=1&uploadId=x",
+                &none,
+                "UploadPart",
+            ),
+            (
+                Method::PUT,
+                true,
+                true,
+                "partNumber=1&uploadId=x",
+                &copy,
+                "UploadPartCopy",
+            ),
+            (
+                Method::POST,
+                true,
+                true,
+                "uploads",
+                &none,
+                "CreateMultipartUpload",
+            ),
+            (
+                Method::POST,
+                true,
+                true,
+                "uploadId=x",
+                &none,
+                "CompleteMultipartUpload",
+            ),
+            (
+                Method::DELETE,
+                true,
+                true,
+                "uploadId=x",
+                &none,
+                "AbortMultipartUpload",
+            ),
+        ];
+        for (method, has_bucket, has_key, query, headers, expected) in cases {
+            assert_eq!(
+                operation_name(&method, has_bucket, has_key, query, headers),
+                expected,
+                "{} {}",
+                method,
+                query
+            );
+        }
+    }
+
+    /// An [`AppState`] from [`mock_state`] that removes its `--localfs-root`
+    /// when dropped.
+    struct MockState {
+        state: AppState,
+        root: std::path::PathBuf,
+    }
+
+    impl std::ops::Deref for MockState {
+        type Target = AppState;
+
+        fn deref(&self) -> &AppState {
+            &self.state
+        }
+    }
+
+    impl std::ops::DerefMut for MockState {
+        fn deref_mut(&mut self) -> &mut AppState {
+            &mut self.state
+        }
+    }
+
+    impl Drop for MockState {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    /// A state for `test-zone` and the extra zone `staging`, backed by a
+    /// fresh `mock-bunny` or, with `TEST_STORAGE_BACKEND=localfs`, by a fresh
+    /// `--localfs-root`, so the same tests cover both backends.
+    async fn mock_state(extra: &[&str]) -> MockState {
+        let url = crate::mock_bunny::spawn("test-key").await;
+        let backend = std::env::var("TEST_STORAGE_BACKEND").unwrap_or_else(|_| "bunny".into());
+        let root = std::env::temp_dir().join(format!("localfs-{}", uuid::Uuid::new_v4()));
+        let args = [
+            "bunny-s3-proxy",
+            "--storage-zone",
+            "test-zone",
+            "--access-key",
+            "test-key",
+            "--bunny-endpoint",
+            &url,
+            "--extra-zone",
+            "staging:test-key",
+            "--backend",
+            &backend,
+            "--localfs-root",
+            root.to_str().unwrap(),
+        ];
+        let state = AppState::new(Config::parse_from(args.iter().chain(extra))).unwrap();
+        MockState { state, root }
+    }
+
+    #[tokio::test]
+    async fn test_cross_zone_copies() {
+        let state = mock_state(&[]).await;
+        state.zones["staging"]
+            .upload(
+                "src.txt",
+                Bytes::from_static(b"hello cross-zone world"),
+                UploadOptions::default(),
+            )
+            .await
+            .unwrap();
+        send(
+            &state,
+            Method::PUT,
+            "/test-zone/copy.txt",
+            &[("x-amz-copy-source", "/staging/src.txt")],
+            "",
+        )
+        .await
+        .unwrap();
+        let copied = state.bunny.download("copy.txt").await.unwrap();
+        assert_eq!(copied.bytes().await.unwrap(), "hello cross-zone world");
+
+        let response = send(&state, Method::POST, "/test-zone/part.txt?uploads", &[], "")
+            .await
+            .unwrap();
+        let body = body_string(response).await;
+        let upload_id = parse_upload_id(&body);
+        let response = send(
+            &state,
+            Method::PUT,
+            &format!("/test-zone/part.txt?partNumber=1&uploadId={}", upload_id),
+            &[
+                ("x-amz-copy-source", "/staging/src.txt"),
+                ("x-amz-copy-source-range", "bytes=6-15"),
+            ],
+            "",
+        )
+        .await
+        .unwrap();
+        let body = body_string(response).await;
+        let etag = hex::encode(<md5::Md5 as md5::Digest>::digest(b"cross-zone"));
+        assert!(body.contains("<CopyPartResult>"), "{}", body);
+        assert!(body.contains(&etag), "{}", body);
+        let part = state
+            .bunny
+            .download(&format!("__multipart/{}/00001", upload_id))
+            .await
+            .unwrap();
+        assert_eq!(part.bytes().await.unwrap(), "cross-zone");
+
+        let err = send(
+            &state,
+            Method::PUT,
+            "/test-zone/copy.txt",
+            &[("x-amz-copy-source", "/unknown/src.txt")],
+            "",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "NoSuchBucket");
+        let err = send(
+            &state,
+            Method::PUT,
+            &format!("/test-zone/part.txt?partNumber=2&uploadId={}", upload_id),
+            &[
+                ("x-amz-copy-source", "/staging/src.txt"),
+                ("x-amz-copy-source-range", "bytes=20-30"),
+            ],
+            "",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "InvalidRange");
+    }
+
+    #[tokio::test]
+    async fn test_multipart_and_conditional_writes() {
+        let state = mock_state(&[]).await;
+        let create = [("content-length", "5"), ("if-none-match", "*")];
+        send(&state, Method::PUT, "/test-zone/doc.txt", &create, "first")
+            .await
+            .unwrap();
+        let err = send(&state, Method::PUT, "/test-zone/doc.txt", &create, "again")
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "PreconditionFailed");
+        let etag = format!(
+            "\"{}\"",
+            state.bunny.describe("doc.txt").await.unwrap().etag()
+        );
+        let stale = [("content-length", "6"), ("if-match", "\"other\"")];
+        let err = send(&state, Method::PUT, "/test-zone/doc.txt", &stale, "second")
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "PreconditionFailed");
+        let current = [("content-length", "6"), ("if-match", etag.as_str())];
+        send(
+            &state,
+            Method::PUT,
+            "/test-zone/doc.txt",
+            &current,
+            "second",
+        )
+        .await
+        .unwrap();
+        let stored = state.bunny.download("doc.txt").await.unwrap();
+        assert_eq!(stored.bytes().await.unwrap(), "second");
+
+        let response = send(&state, Method::POST, "/test-zone/big.txt?uploads", &[], "")
+            .await
+            .unwrap();
+        let body = body_string(response).await;
+        let upload_id = parse_upload_id(&body);
+        let response = send(
+            &state,
+            Method::PUT,
+            &format!("/test-zone/big.txt?partNumber=1&uploadId={}", upload_id),
+            &[("content-length", "11")],
+            "hello world",
+        )
+        .await
+        .unwrap();
+        let part_etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let complete = format!(
+            "<CompleteMultipartUpload><Part><PartNumber>1</PartNumber><ETag>{}</ETag></Part></CompleteMultipartUpload>",
+            part_etag
+        );
+        let uri = format!("/test-zone/big.txt?uploadId={}", upload_id);
+        // An upload completes only onto the key it was initiated for.
+        let err = send(
+            &state,
+            Method::POST,
+            &format!("/test-zone/doc.txt?uploadId={}", upload_id),
+            &[],
+            &complete,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "NoSuchUpload");
+        let stored = state.bunny.download("doc.txt").await.unwrap();
+        assert_eq!(stored.bytes().await.unwrap(), "second");
+
+        let response = send(&state, Method::POST, "/test-zone/doc.txt?uploads", &[], "")
+            .await
+            .unwrap();
+        let body = body_string(response).await;
+        let doc_upload_id = parse_upload_id(&body);
+        send(
+            &state,
+            Method::PUT,
+            &format!("/test-zone/doc.txt?partNumber=1&uploadId={}", doc_upload_id),
+            &[("content-length", "11")],
+            "hello world",
+        )
+        .await
+        .unwrap();
+        for precondition in [("if-none-match", "*"), ("if-match", "\"other\"")] {
+            let err = send(
+                &state,
+                Method::POST,
+                &format!("/test-zone/doc.txt?uploadId={}", doc_upload_id),
+                &[precondition],
+                &complete,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.s3_error_code(), "PreconditionFailed");
+        }
+
+        // The size recent SDKs declare must be that of the parts, and is
+        // checked before anything is assembled.
+        let err = send(
+            &state,
+            Method::POST,
+            &uri,
+            &[(MP_OBJECT_SIZE, "eleven")],
+            &complete,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "InvalidArgument");
+        let response = send(
+            &state,
+            Method::POST,
+            &uri,
+            &[(MP_OBJECT_SIZE, "12")],
+            &complete,
+        )
+        .await
+        .unwrap();
+        let body = body_string(response).await;
+        assert!(body.contains("<Code>InvalidRequest</Code>"), "{}", body);
+        assert!(state.bunny.describe("big.txt").await.is_err());
+        let response = send(
+            &state,
+            Method::POST,
+            &uri,
+            &[(MP_OBJECT_SIZE, "11")],
+            &complete,
+        )
+        .await
+        .unwrap();
+        let body = body_string(response).await;
+        assert!(body.contains("<CompleteMultipartUploadResult"), "{}", body);
+        let assembled = state.bunny.download("big.txt").await.unwrap();
+        assert_eq!(assembled.bytes().await.unwrap(), "hello world");
+    }
+
+    #[tokio::test]
+    async fn test_complete_result_is_escaped() {
+        let state = mock_state(&[]).await;
+        let path = "/test-zone/a%26b%20%3Cc%3E.txt";
+        let uploads = format!("{}?uploads", path);
+        let body = body_string(send(&state, Method::POST, &uploads, &[], "").await.unwrap()).await;
+        let upload_id = parse_upload_id(&body);
+        let part = send(
+            &state,
+            Method::PUT,
+            &format!("{}?partNumber=1&uploadId={}", path, upload_id),
+            &[],
+            "hello",
+        )
+        .await
+        .unwrap();
+        let complete = format!(
+            "<CompleteMultipartUpload><Part><PartNumber>1</PartNumber><ETag>{}</ETag></Part></CompleteMultipartUpload>",
+            part.headers()[header::ETAG].to_str().unwrap()
+        );
+        let uri = format!("{}?uploadId={}", path, upload_id);
+        let response = send(&state, Method::POST, &uri, &[], &complete)
+            .await
+            .unwrap();
+        let body = body_string(response).await;
+        assert!(
+            body.contains("<Key>a&amp;b &lt;c&gt;.txt</Key>"),
+            "{}",
+            body
+        );
+        let mut reader = quick_xml::Reader::from_str(&body);
+        while !matches!(reader.read_event().unwrap(), quick_xml::events::Event::Eof) {}
+    }
+
+    #[tokio::test]
+    async fn test_rename_extension() {
+        let rename = |state: &AppState, key: &str, source: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RENAME_SOURCE, source.parse().unwrap());
+            let uri = format!("/test-zone/{}", key);
+            dispatch_request(
+                state.clone(),
+                Method::PUT,
+                uri.parse().unwrap(),
+                headers,
+                Some("test-zone".to_string()),
+                Some(key.to_string()),
+                Body::empty(),
+            )
+        };
+        let exists = |client: &Backend, key: &str| {
+            let client = client.clone();
+            let key = key.to_string();
+            async move { client.describe(&key).await.is_ok() }
+        };
+
+        let state = mock_state(&[]).await;
+        let err = rename(&state, "new.txt", "/test-zone/old.txt")
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "NotImplemented");
+
+        let state = mock_state(&["--allow-rename"]).await;
+        for (client, key) in [
+            (&state.bunny, "old.txt"),
+            (&state.zones["staging"], "in.txt"),
+        ] {
+            client
+                .upload(
+                    key,
+                    Bytes::from_static(b"payload"),
+                    UploadOptions::default(),
+                )
+                .await
+                .unwrap();
+        }
+        let response = rename(&state, "new.txt", "/test-zone/old.txt")
+            .await
+            .unwrap();
+        let etag = state.bunny.describe("new.txt").await.unwrap().etag();
+        assert_eq!(response.headers()[header::ETAG], format!("\"{}\"", etag));
+        assert!(!exists(&state.bunny, "old.txt").await);
+        let renamed = state.bunny.download("new.txt").await.unwrap();
+        assert_eq!(renamed.bytes().await.unwrap(), "payload");
+
+        // Across zones, and never onto itself
+        rename(&state, "moved.txt", "/staging/in.txt")
+            .await
+            .unwrap();
+        assert!(!exists(&state.zones["staging"], "in.txt").await);
+        assert!(exists(&state.bunny, "moved.txt").await);
+        let err = rename(&state, "moved.txt", "/test-zone/moved.txt")
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "InvalidRequest");
+
+        // A conditional write holding the destination's lock wins
+        let _guard = state.lock.try_lock("held.txt").await.unwrap();
+        let err = rename(&state, "held.txt", "/test-zone/new.txt")
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "ConditionalRequestConflict");
+        assert!(exists(&state.bunny, "new.txt").await);
+
+        // A failed copy keeps the source
+        let err = rename(&state, "other.txt", "/test-zone/missing.txt")
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "NoSuchKey");
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_access_rules() {
+        let path = std::env::temp_dir().join(format!("access-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            "[[access]]\nprefix = \"public/\"\nanonymous = \"read\"\n\n\
+             [[access]]\nprefix = \"public/drafts/\"\nanonymous = \"none\"\n",
+        )
+        .unwrap();
+        let state =
+            mock_state(&["--require-auth", "--access-config", path.to_str().unwrap()]).await;
+        std::fs::remove_file(&path).unwrap();
+        for key in [
+            "public/cat.txt",
+            "public/drafts/post.txt",
+            "private/key.txt",
+        ] {
+            state
+                .bunny
+                .upload(key, Bytes::from_static(b"data"), UploadOptions::default())
+                .await
+                .unwrap();
+        }
+        let response = send(&state, Method::GET, "/test-zone/public/cat.txt", &[], "")
+            .await
+            .unwrap();
+        assert_eq!(body_string(response).await, "data");
+        assert!(
+            send(&state, Method::HEAD, "/test-zone/public/cat.txt", &[], "")
+                .await
+                .is_ok()
+        );
+        for (method, uri) in [
+            (Method::GET, "/test-zone/public/drafts/post.txt"),
+            (Method::GET, "/test-zone/private/key.txt"),
+            // Bunny would resolve these to private/key.txt.
+            (Method::GET, "/test-zone/public/%2E%2E/private/key.txt"),
+            (Method::GET, "/test-zone/public/../private/key.txt"),
+            (Method::GET, "/test-zone/public/.%2E%5Cprivate/key.txt"),
+            (Method::GET, "/test-zone/public/cat.txt?tagging"),
+            (Method::PUT, "/test-zone/public/new.txt"),
+            (Method::DELETE, "/test-zone/public/cat.txt"),
+            (Method::GET, "/test-zone?versions"),
+            (Method::GET, "/staging/private/key.txt"),
+            (Method::GET, "/"),
+        ] {
+            let err = send(&state, method.clone(), uri, &[], "")
+                .await
+                .unwrap_err();
+            assert_eq!(err.s3_error_code(), "AccessDenied", "{} {}", method, uri);
+        }
+
+        // Listings only show the public keys and the prefixes leading to them
+        let listing = body_string(
+            send(&state, Method::GET, "/test-zone?list-type=2", &[], "")
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert!(listing.contains("<Key>public/cat.txt</Key>"), "{}", listing);
+        assert!(
+            !listing.contains("drafts") && !listing.contains("private"),
+            "{}",
+            listing
+        );
+        let listing = body_string(
+            send(
+                &state,
+                Method::GET,
+                "/test-zone?list-type=2&delimiter=/",
+                &[],
+                "",
+            )
+            .await
+            .unwrap(),
+        )
+        .await;
+        assert!(listing.contains("<Prefix>public/</Prefix>"), "{}", listing);
+        assert!(!listing.contains("private"), "{}", listing);
+    }
+
+    #[tokio::test]
+    async fn test_retention_rules() {
+        let path = std::env::temp_dir().join(format!("retention-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "[[retention]]\nprefix = \"audit/\"\ndays = 90\n").unwrap();
+        let state = mock_state(&[
+            "--retention-config",
+            path.to_str().unwrap(),
+            "--retention-override-token",
+            "break-glass",
+        ])
+        .await;
+        std::fs::remove_file(&path).unwrap();
+        for key in ["audit/2024.log", "tmp/scratch.txt"] {
+            state
+                .bunny
+                .upload(key, Bytes::from_static(b"data"), UploadOptions::default())
+                .await
+                .unwrap();
+        }
+        for (method, uri, headers) in [
+            (Method::PUT, "/test-zone/audit/2024.log", &[][..]),
+            (Method::DELETE, "/test-zone/audit/2024.log", &[][..]),
+            (
+                Method::PUT,
+                "/test-zone/audit/2024.log",
+                &[("x-amz-copy-source", "/test-zone/tmp/scratch.txt")][..],
+            ),
+            (
+                Method::DELETE,
+                "/test-zone/audit/2024.log",
+                &[(RETENTION_OVERRIDE, "guess")][..],
+            ),
+        ] {
+            let err = send(&state, method.clone(), uri, headers, "")
+                .await
+                .unwrap_err();
+            assert_eq!(err.s3_error_code(), "AccessDenied", "{} {}", method, uri);
+            assert!(err.to_string().contains("audit/ (90 days)"), "{}", err);
+        }
+        let deleted = send(
+            &state,
+            Method::POST,
+            "/test-zone?delete",
+            &[],
+            "<Delete><Object><Key>audit/2024.log</Key></Object>\
+             <Object><Key>tmp/scratch.txt</Key></Object></Delete>",
+        )
+        .await
+        .unwrap();
+        let deleted = body_string(deleted).await;
+        assert!(deleted.contains("<Code>AccessDenied</Code>"), "{}", deleted);
+        assert!(
+            deleted.contains("<Deleted><Key>tmp/scratch.txt</Key>"),
+            "{}",
+            deleted
+        );
+
+        // Dot segments cannot lead a write outside the protected prefix and
+        // back into it.
+        for (method, uri) in [
+            (Method::PUT, "/test-zone/tmp/../audit/2024.log"),
+            (Method::DELETE, "/test-zone/tmp/%2E%2E/audit/2024.log"),
+        ] {
+            let err = send(&state, method.clone(), uri, &[], "")
+                .await
+                .unwrap_err();
+            assert_eq!(err.s3_error_code(), "AccessDenied", "{} {}", method, uri);
+        }
+        let deleted = send(
+            &state,
+            Method::POST,
+            "/test-zone?delete",
+            &[],
+            "<Delete><Object><Key>tmp/../audit/2024.log</Key></Object></Delete>",
+        )
+        .await
+        .unwrap();
+        let deleted = body_string(deleted).await;
+        assert!(deleted.contains("<Code>AccessDenied</Code>"), "{}", deleted);
+        let kept = state.bunny.download("audit/2024.log").await.unwrap();
+        assert_eq!(kept.bytes().await.unwrap(), "data");
+
+        // New keys are written normally, then protected themselves
+        send(&state, Method::PUT, "/test-zone/audit/2025.log", &[], "new")
+            .await
+            .unwrap();
+        assert!(
+            send(
+                &state,
+                Method::PUT,
+                "/test-zone/audit/2025.log",
+                &[],
+                "again"
+            )
+            .await
+            .is_err()
+        );
+
+        send(
+            &state,
+            Method::DELETE,
+            "/test-zone/audit/2024.log",
+            &[(RETENTION_OVERRIDE, "break-glass")],
+            "",
+        )
+        .await
+        .unwrap();
+        assert!(state.bunny.describe("audit/2024.log").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_quota_enforcement() {
+        let path = std::env::temp_dir().join(format!("quota-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "[[quota]]\nprefix = \"teams/alpha/\"\nlimit = 10\n").unwrap();
+        let state = mock_state(&["--quota-config", path.to_str().unwrap()]).await;
+        std::fs::remove_file(&path).unwrap();
+        state
+            .bunny
+            .upload(
+                "teams/alpha/seed.txt",
+                Bytes::from_static(b"123456"),
+                UploadOptions::default(),
+            )
+            .await
+            .unwrap();
+        let quotas = state.quotas.clone().unwrap();
+        quotas.reconcile(&state).await;
+        let usage = || {
+            quotas
+                .render_metrics()
+                .lines()
+                .find(|l| l.starts_with("bunny_s3_proxy_quota_usage_bytes{"))
+                .unwrap()
+                .rsplit(' ')
+                .next()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(usage(), "6");
+
+        let err = send(
+            &state,
+            Method::PUT,
+            "/test-zone/teams/alpha/big.txt",
+            &[],
+            "12345",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "QuotaExceeded");
+        assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+        assert!(
+            err.to_string().contains("10 bytes for teams/alpha/"),
+            "{}",
+            err
+        );
+        assert!(state.bunny.describe("teams/alpha/big.txt").await.is_err());
+
+        // Other prefixes and reads are unaffected
+        send(
+            &state,
+            Method::PUT,
+            "/test-zone/teams/beta/big.txt",
+            &[],
+            "12345",
+        )
+        .await
+        .unwrap();
+        send(
+            &state,
+            Method::GET,
+            "/test-zone/teams/alpha/seed.txt",
+            &[],
+            "",
+        )
+        .await
+        .unwrap();
+
+        // Replacing an object only counts the difference
+        send(
+            &state,
+            Method::PUT,
+            "/test-zone/teams/alpha/seed.txt",
+            &[],
+            "12345678",
+        )
+        .await
+        .unwrap();
+        assert_eq!(usage(), "8");
+        send(
+            &state,
+            Method::DELETE,
+            "/test-zone/teams/alpha/seed.txt",
+            &[],
+            "",
+        )
+        .await
+        .unwrap();
+        assert_eq!(usage(), "0");
+        send(
+            &state,
+            Method::PUT,
+            "/test-zone/teams/alpha/big.txt",
+            &[],
+            "12345",
+        )
+        .await
+        .unwrap();
+        assert_eq!(usage(), "5");
+        assert!(
+            quotas
+                .render_metrics()
+                .contains("bunny_s3_proxy_quota_rejections_total 1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_key_rules_block_writes() {
+        let path = std::env::temp_dir().join(format!("key-rules-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            "[[rule]]\nname = \"no-executables\"\naction = \"deny\"\nglob = \"*.exe\"\n",
+        )
+        .unwrap();
+        let state = mock_state(&["--key-rules-config", path.to_str().unwrap()]).await;
+        state
+            .bunny
+            .upload(
+                "legacy/tool.exe",
+                Bytes::from_static(b"MZ"),
+                UploadOptions::default(),
+            )
+            .await
+            .unwrap();
+        let err = send(&state, Method::PUT, "/test-zone/bin/setup.exe", &[], "MZ")
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "AccessDenied");
+        assert!(err.to_string().contains("no-executables"), "{}", err);
+        let copy = [("x-amz-copy-source", "/test-zone/legacy/tool.exe")];
+        assert!(
+            send(&state, Method::PUT, "/test-zone/bin/tool.exe", &copy, "MZ")
+                .await
+                .is_err()
+        );
+        assert!(
+            send(
+                &state,
+                Method::POST,
+                "/test-zone/bin/big.exe?uploads",
+                &[],
+                "MZ"
+            )
+            .await
+            .is_err()
+        );
+        assert!(state.bunny.describe("bin/setup.exe").await.is_err());
+        // An upload initiated for an allowed key cannot complete onto a
+        // denied one.
+        let body = body_string(
+            send(
+                &state,
+                Method::POST,
+                "/test-zone/bin/big.bin?uploads",
+                &[],
+                "MZ",
+            )
+            .await
+            .unwrap(),
+        )
+        .await;
+        let upload_id = parse_upload_id(&body);
+        let err = send(
+            &state,
+            Method::POST,
+            &format!("/test-zone/bin/big.exe?uploadId={}", upload_id),
+            &[],
+            "MZ",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "AccessDenied");
+
+        // Nor can the denied key's subresources be changed.
+        for (method, query) in [
+            (Method::PUT, "acl"),
+            (Method::PUT, "tagging"),
+            (Method::DELETE, "tagging"),
+        ] {
+            let uri = format!("/test-zone/legacy/tool.exe?{}", query);
+            let err = send(&state, method, &uri, &[], "").await.unwrap_err();
+            assert_eq!(err.s3_error_code(), "AccessDenied");
+        }
+
+        // Copying the denied key elsewhere, and reading it, stay allowed.
+        send(&state, Method::PUT, "/test-zone/bin/tool.bin", &copy, "MZ")
+            .await
+            .unwrap();
+        send(&state, Method::GET, "/test-zone/legacy/tool.exe", &[], "MZ")
+            .await
+            .unwrap();
+        send(
+            &state,
+            Method::GET,
+            "/test-zone/legacy/tool.exe?acl",
+            &[],
+            "",
+        )
+        .await
+        .unwrap();
+
+        std::fs::write(
+            &path,
+            "filter-reads = true\n[[rule]]\naction = \"deny\"\nglob = \"*.exe\"\n",
+        )
+        .unwrap();
+        state.key_rules.as_ref().unwrap().reload().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let err = send(&state, Method::GET, "/test-zone/legacy/tool.exe", &[], "MZ")
+            .await
+            .unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+        for (method, query) in [
+            (Method::POST, "select&select-type=2"),
+            (Method::GET, "attributes"),
+        ] {
+            let uri = format!("/test-zone/legacy/tool.exe?{}", query);
+            let err = send(&state, method, &uri, &[], "").await.unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dedupe_writes_skip_identical_uploads() {
+        let state = mock_state(&["--dedupe-writes"]).await;
+        let body = "the same artifact, built again";
+        let checksum = base64::Engine::encode(
+            &base64::engine::general_purpose::STANDARD,
+            Sha256::digest(body),
+        );
+        let put = |body: &'static str, extra: &[(&'static str, &str)]| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_LENGTH, body.len().into());
+            headers.insert("x-amz-checksum-sha256", checksum.parse().unwrap());
+            for (name, value) in extra {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            dispatch_request(
+                state.clone(),
+                Method::PUT,
+                "/test-zone/ci/app.tar".parse().unwrap(),
+                headers,
+                Some("test-zone".to_string()),
+                Some("ci/app.tar".to_string()),
+                Body::from(body),
+            )
+        };
+        let skipped = || {
+            state
+                .dedupe
+                .render_metrics()
+                .lines()
+                .find(|l| l.starts_with("bunny_s3_proxy_dedupe_skipped_total "))
+                .unwrap()
+                .to_string()
+        };
+
+        put(body, &[]).await.unwrap();
+        assert!(skipped().ends_with(" 0"));
+        let stored = state.bunny.describe("ci/app.tar").await.unwrap();
+        let second = put(body, &[]).await.unwrap();
+        assert!(skipped().ends_with(" 1"));
+        // The ETag is the stored object's, as HEAD reports it.
+        assert_eq!(
+            second.headers()[header::ETAG],
+            format!("\"{}\"", stored.etag()).as_str()
+        );
+        let unchanged = state.bunny.describe("ci/app.tar").await.unwrap();
+        assert_eq!(unchanged.last_changed, stored.last_changed);
+        assert!(state.dedupe.render_metrics().contains(&format!(
+            "bunny_s3_proxy_dedupe_bytes_saved_total {}",
+            body.len()
+        )));
+
+        // A body that does not hold the claimed checksum is refused.
+        let forged = "the same artifact, built agaiN";
+        let err = put(forged, &[]).await.unwrap_err();
+        assert_eq!(err.s3_error_code(), "BadDigest");
+        // Preconditions still apply before anything is skipped.
+        let err = put(body, &[("if-none-match", "*")]).await.unwrap_err();
+        assert_eq!(err.s3_error_code(), "PreconditionFailed");
+        // A different storage class is a real change.
+        put(body, &[("x-amz-storage-class", "STANDARD_IA")])
+            .await
+            .unwrap();
+        assert!(skipped().ends_with(" 1"));
+    }
+
+    #[tokio::test]
+    async fn test_html_listing_for_browsers() {
+        let state = mock_state(&["--html-listing", "all"]).await;
+        for key in [
+            "docs/a.txt",
+            "docs/b.txt",
+            "docs/c.txt",
+            "docs/img/x.png",
+            "top.txt",
+        ] {
+            state
+                .bunny
+                .upload(key, Bytes::from(key.to_string()), UploadOptions::default())
+                .await
+                .unwrap();
+        }
+        let browse = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        let get = |uri: &str, accept: Option<&str>| {
+            let (bucket, key) = parse_s3_path(uri.split('?').next().unwrap());
+            let mut headers = HeaderMap::new();
+            if let Some(accept) = accept {
+                headers.insert(header::ACCEPT, accept.parse().unwrap());
+            }
+            let request = dispatch_request(
+                state.clone(),
+                Method::GET,
+                uri.parse().unwrap(),
+                headers,
+                bucket,
+                key,
+                Body::empty(),
+            );
+            async move {
+                let response = request.await.unwrap();
+                let content_type = response.headers()[header::CONTENT_TYPE]
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (content_type, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        let (content_type, page) = get("/test-zone", Some(browse)).await;
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        assert!(page.contains("<a href=\"/test-zone/docs/\">docs/</a>"));
+        assert!(page.contains("<a href=\"/test-zone/top.txt\">top.txt</a>"));
+        assert!(!page.contains("a.txt"));
+
+        let (_, page) = get("/test-zone/docs/", Some(browse)).await;
+        assert!(page.contains("<a href=\"/test-zone/\">../</a>"));
+        assert!(page.contains("<a href=\"/test-zone/docs/img/\">img/</a>"));
+        assert!(page.contains("<a href=\"/test-zone/docs/b.txt\">b.txt</a>"));
+
+        let (_, page) = get("/test-zone/docs/?max-keys=2", Some(browse)).await;
+        assert!(page.contains("b.txt") && !page.contains("c.txt"));
+        assert!(page.contains("href=\"/test-zone/docs/?start-after=docs%2Fb%2Etxt\""));
+        let (_, page) = get("/test-zone/docs/?start-after=docs%2Fb%2Etxt", Some(browse)).await;
+        assert!(page.contains("c.txt") && !page.contains("b.txt"));
+        assert!(!page.contains("Next page"));
+
+        // SDKs and tools still get S3's answers.
+        let (content_type, _) = get("/test-zone?list-type=2", Some(browse)).await;
+        assert_eq!(content_type, "application/xml");
+        let (content_type, _) = get("/test-zone", None).await;
+        assert_eq!(content_type, "application/xml");
+        let (_, body) = get("/test-zone/docs/a.txt", Some(browse)).await;
+        assert_eq!(body, "docs/a.txt");
+    }
+
+    #[tokio::test]
+    async fn test_cache_policies_on_reads() {
+        let path = std::env::temp_dir().join(format!("cache-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            "[[cache-policy]]\nprefix = \"static/\"\ncache-control = \"public, max-age=86400\"\n",
+        )
+        .unwrap();
+        let state = mock_state(&["--cache-policy-config", path.to_str().unwrap()]).await;
+        for key in ["static/app.js", "uploads/a.png"] {
+            state
+                .bunny
+                .upload(key, Bytes::from(key.to_string()), UploadOptions::default())
+                .await
+                .unwrap();
+        }
+        let cache_control = |method: Method, uri: &str| {
+            let (bucket, key) = parse_s3_path(uri.split('?').next().unwrap());
+            let request = dispatch_request(
+                state.clone(),
+                method,
+                uri.parse().unwrap(),
+                HeaderMap::new(),
+                bucket,
+                key,
+                Body::empty(),
+            );
+            async move {
+                request
+                    .await
+                    .unwrap()
+                    .headers()
+                    .get(header::CACHE_CONTROL)
+                    .map(|v| v.to_str().unwrap().to_string())
+            }
+        };
+
+        assert_eq!(
+            cache_control(Method::GET, "/test-zone/static/app.js")
+                .await
+                .as_deref(),
+            Some("public, max-age=86400")
+        );
+        assert_eq!(
+            cache_control(Method::HEAD, "/test-zone/static/app.js")
+                .await
+                .as_deref(),
+            Some("public, max-age=86400")
+        );
+        assert_eq!(
+            cache_control(Method::GET, "/test-zone/uploads/a.png").await,
+            None
+        );
+        assert_eq!(
+            cache_control(Method::GET, "/test-zone?list-type=2&prefix=static/").await,
+            None
+        );
+
+        std::fs::write(
+            &path,
+            "[[cache-policy]]\nprefix = \"\"\ncache-control = \"no-cache\"\n",
+        )
+        .unwrap();
+        state.cache_policies.as_ref().unwrap().reload().unwrap();
+        assert_eq!(
+            cache_control(Method::GET, "/test-zone/uploads/a.png")
+                .await
+                .as_deref(),
+            Some("no-cache")
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete_trash() {
+        let state = mock_state(&["--trash-prefix", "__trash/"]).await;
+        let trash = state.trash.clone().unwrap();
+        for key in ["docs/a.txt", "docs/b.txt", "docs/c.txt"] {
+            state
+                .bunny
+                .upload(key, Bytes::from(key.to_string()), UploadOptions::default())
+                .await
+                .unwrap();
+        }
+        send(&state, Method::DELETE, "/test-zone/docs/a.txt", &[], "")
+            .await
+            .unwrap();
+        send(
+            &state,
+            Method::POST,
+            "/test-zone?delete",
+            &[],
+            "<Delete><Object><Key>docs/b.txt</Key></Object></Delete>",
+        )
+        .await
+        .unwrap();
+        send(
+            &state,
+            Method::DELETE,
+            "/test-zone/docs/c.txt",
+            &[(crate::s3::trash::HARD_DELETE, "true")],
+            "",
+        )
+        .await
+        .unwrap();
+        let entries = trash.list(&state.bunny).await.unwrap();
+        let keys: Vec<&str> = entries.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(keys.len(), 2, "{:?}", keys);
+        assert!(keys.contains(&"docs/a.txt") && keys.contains(&"docs/b.txt"));
+
+        // Hidden from listings and out of reach of clients
+        let listing = body_string(
+            send(&state, Method::GET, "/test-zone?list-type=2", &[], "")
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert!(!listing.contains("<Key>"), "{}", listing);
+        let trash_uri = format!("/test-zone/{}", entries[0].trash_key);
+        let err = send(&state, Method::GET, &trash_uri, &[], "")
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "AccessDenied");
+
+        trash
+            .restore(&state.bunny, "docs/a.txt", None)
+            .await
+            .unwrap();
+        let restored = state.bunny.download("docs/a.txt").await.unwrap();
+        assert_eq!(restored.bytes().await.unwrap(), "docs/a.txt");
+        assert!(
+            trash
+                .restore(&state.bunny, "docs/a.txt", None)
+                .await
+                .is_err()
+        );
+
+        assert_eq!(trash.purge(&state.bunny, Utc::now()).await.unwrap(), 0);
+        let later = Utc::now() + chrono::Duration::days(8);
+        assert!(trash.purge(&state.bunny, later).await.unwrap() > 0);
+        assert!(trash.list(&state.bunny).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_emulated_versioning() {
+        let state = mock_state(&["--emulate-versioning", "docs/"]).await;
+        let version_of = |response: &Response| {
+            response.headers()[VERSION_ID_HEADER]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let v1 = version_of(
+            &send(&state, Method::PUT, "/test-zone/docs/a.txt", &[], "one")
+                .await
+                .unwrap(),
+        );
+        let v2 = version_of(
+            &send(&state, Method::PUT, "/test-zone/docs/a.txt", &[], "two")
+                .await
+                .unwrap(),
+        );
+        assert!(v1 < v2 && v1 != NULL_VERSION_ID);
+        let old = send(
+            &state,
+            Method::GET,
+            &format!("/test-zone/docs/a.txt?versionId={}", v1),
+            &[],
+            "",
+        )
+        .await
+        .unwrap();
+        assert_eq!(version_of(&old), v1);
+        assert_eq!(body_string(old).await, "one");
+        let current = send(&state, Method::GET, "/test-zone/docs/a.txt", &[], "")
+            .await
+            .unwrap();
+        assert_eq!(version_of(&current), v2);
+        assert_eq!(body_string(current).await, "two");
+
+        // A plain delete leaves a marker; deleting the marker brings v2 back
+        let deleted = send(&state, Method::DELETE, "/test-zone/docs/a.txt", &[], "")
+            .await
+            .unwrap();
+        assert_eq!(deleted.headers()[DELETE_MARKER_HEADER], "true");
+        let marker = version_of(&deleted);
+        let err = send(&state, Method::GET, "/test-zone/docs/a.txt", &[], "")
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "NoSuchKey");
+        let listing = body_string(
+            send(
+                &state,
+                Method::GET,
+                "/test-zone?versions&prefix=docs/",
+                &[],
+                "",
+            )
+            .await
+            .unwrap(),
+        )
+        .await;
+        assert!(
+            listing.contains(&format!(
+                "<DeleteMarker><Key>docs/a.txt</Key><VersionId>{}</VersionId><IsLatest>true</IsLatest>",
+                marker
+            )),
+            "{}",
+            listing
+        );
+        assert_eq!(listing.matches("<Version>").count(), 2, "{}", listing);
+        assert!(!listing.contains("__versions"), "{}", listing);
+
+        send(
+            &state,
+            Method::DELETE,
+            &format!("/test-zone/docs/a.txt?versionId={}", marker),
+            &[],
+            "",
+        )
+        .await
+        .unwrap();
+        let current = send(&state, Method::GET, "/test-zone/docs/a.txt", &[], "")
+            .await
+            .unwrap();
+        assert_eq!(version_of(&current), v2);
+        assert_eq!(body_string(current).await, "two");
+
+        // Deleting a version by ID removes it for good
+        send(
+            &state,
+            Method::DELETE,
+            &format!("/test-zone/docs/a.txt?versionId={}", v1),
+            &[],
+            "",
+        )
+        .await
+        .unwrap();
+        let err = send(
+            &state,
+            Method::GET,
+            &format!("/test-zone/docs/a.txt?versionId={}", v1),
+            &[],
+            "",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "NoSuchVersion");
+
+        // Keys outside the prefixes stay unversioned
+        let plain = send(&state, Method::PUT, "/test-zone/other.txt", &[], "x")
+            .await
+            .unwrap();
+        assert!(!plain.headers().contains_key(VERSION_ID_HEADER));
+        let err = send(
+            &state,
+            Method::GET,
+            &format!("/test-zone/other.txt?versionId={}", v2),
+            &[],
+            "",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "NoSuchVersion");
+        let versioning = body_string(
+            send(&state, Method::GET, "/test-zone?versioning", &[], "")
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert!(versioning.contains("<Status>Enabled</Status>"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_writes_leave_no_version() {
+        let state = mock_state(&["--emulate-versioning", "docs/"]).await;
+        let versions = || async {
+            let listing = body_string(
+                send(
+                    &state,
+                    Method::GET,
+                    "/test-zone?versions&prefix=docs/",
+                    &[],
+                    "",
+                )
+                .await
+                .unwrap(),
+            )
+            .await;
+            listing.matches("<Version>").count()
+        };
+
+        send(&state, Method::PUT, "/test-zone/docs/a.txt", &[], "old")
+            .await
+            .unwrap();
+        let err = send(
+            &state,
+            Method::PUT,
+            "/test-zone/docs/a.txt",
+            &[("x-amz-checksum-crc32", "AAAAAA==")],
+            "new",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "BadDigest");
+        assert_eq!(versions().await, 1);
+
+        // The completion fails once admitted, after the object was archived
+        // for it; the archived copy goes back in place.
+        let body = body_string(
+            send(
+                &state,
+                Method::POST,
+                "/test-zone/docs/a.txt?uploads",
+                &[],
+                "",
+            )
+            .await
+            .unwrap(),
+        )
+        .await;
+        let upload_id = parse_upload_id(&body);
+        let response = send(
+            &state,
+            Method::PUT,
+            &format!("/test-zone/docs/a.txt?partNumber=1&uploadId={}", upload_id),
+            &[],
+            "hello world",
+        )
+        .await
+        .unwrap();
+        let complete = format!(
+            "<CompleteMultipartUpload><Part><PartNumber>1</PartNumber><ETag>{}</ETag></Part></CompleteMultipartUpload>",
+            response.headers()[header::ETAG].to_str().unwrap()
+        );
+        let response = send(
+            &state,
+            Method::POST,
+            &format!("/test-zone/docs/a.txt?uploadId={}", upload_id),
+            &[(MP_OBJECT_SIZE, "12")],
+            &complete,
+        )
+        .await
+        .unwrap();
+        let body = body_string(response).await;
+        assert!(body.contains("<Code>InvalidRequest</Code>"), "{}", body);
+        assert_eq!(versions().await, 1);
+        let current = send(&state, Method::GET, "/test-zone/docs/a.txt", &[], "")
+            .await
+            .unwrap();
+        assert_eq!(body_string(current).await, "old");
+
+        let response = send(
+            &state,
+            Method::POST,
+            &format!("/test-zone/docs/a.txt?uploadId={}", upload_id),
+            &[],
+            &complete,
+        )
+        .await
+        .unwrap();
+        let body = body_string(response).await;
+        assert!(body.contains("<CompleteMultipartUploadResult"), "{}", body);
+        assert_eq!(versions().await, 2);
+    }
 
-        let collected: Vec<_> = hashing_stream.collect().await;
-        assert_eq!(collected.len(), 1);
-        assert_eq!(collected[0].as_ref().unwrap().as_ref(), data);
+    #[tokio::test]
+    async fn test_inventory_report() {
+        let path = std::env::temp_dir().join(format!("inventory-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            "[[inventory]]\nid = \"docs\"\nprefix = \"docs/\"\ndestination = \"inventory/\"\nschedule = \"daily\"\ngzip = true\n",
+        )
+        .unwrap();
+        let state = mock_state(&["--inventory-config", path.to_str().unwrap()]).await;
+        std::fs::remove_file(&path).unwrap();
+        for key in ["docs/a b.txt", "docs/sub/c.txt", "other.txt"] {
+            state
+                .bunny
+                .upload(key, Bytes::from(key.to_string()), UploadOptions::default())
+                .await
+                .unwrap();
+        }
+        let inventory = state.inventory.clone().unwrap();
 
-        let computed_hash = hash_rx.await.unwrap();
-        assert_eq!(computed_hash, expected_hash);
+        let runs = inventory.run_due(&state, false, None).await;
+        let run = runs[0].as_ref().unwrap();
+        assert_eq!((run.objects, run.total_bytes), (2, 26));
+        assert!(run.manifest_key.starts_with("inventory/test-zone/docs/"));
+        // Not due again until a day has passed
+        assert!(inventory.run_due(&state, false, None).await.is_empty());
+
+        let manifest = state.bunny.download(&run.manifest_key).await.unwrap();
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&manifest.bytes().await.unwrap()).unwrap();
+        assert_eq!(manifest["sourceBucket"], "test-zone");
+        assert_eq!(manifest["fileFormat"], "CSV");
+        let data_key = manifest["files"][0]["key"].as_str().unwrap();
+        assert!(data_key.ends_with(".csv.gz"));
+        let data = state.bunny.download(data_key).await.unwrap();
+        let mut csv = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::GzDecoder::new(&data.bytes().await.unwrap()[..]),
+            &mut csv,
+        )
+        .unwrap();
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows.len(), 2, "{}", csv);
+        assert!(rows[0].starts_with("\"test-zone\",\"docs/a%20b.txt\",\"12\","));
+        assert!(rows[1].starts_with("\"test-zone\",\"docs/sub/c.txt\",\"14\","));
+
+        let metrics = inventory.render_metrics();
+        assert!(metrics.contains("bunny_s3_proxy_inventory_runs_total 1\n"));
+        assert!(
+            metrics
+                .contains("bunny_s3_proxy_inventory_objects{bucket=\"test-zone\",id=\"docs\"} 2\n")
+        );
     }
 
     #[tokio::test]
-    async fn test_hashing_stream_multiple_chunks() {
-        let chunk1 = b"hello ";
-        let chunk2 = b"world";
-        let mut hasher = Sha256::new();
-        hasher.update(chunk1);
-        hasher.update(chunk2);
-        let expected_hash = hex::encode(hasher.finalize());
+    async fn test_select_object_content() {
+        let state = mock_state(&[]).await;
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut gzip, b"name\tqty\napple\t5\nbanana\t12\n").unwrap();
+        state
+            .bunny
+            .upload(
+                "fruit.tsv.gz",
+                Bytes::from(gzip.finish().unwrap()),
+                UploadOptions::default(),
+            )
+            .await
+            .unwrap();
+        let select = |expression: &str| {
+            let body = format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+                <SelectObjectContentRequest xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+                  <Expression>{}</Expression>
+                  <ExpressionType>SQL</ExpressionType>
+                  <InputSerialization>
+                    <CompressionType>GZIP</CompressionType>
+                    <CSV><FileHeaderInfo>USE</FileHeaderInfo><FieldDelimiter>&#9;</FieldDelimiter></CSV>
+                  </InputSerialization>
+                  <OutputSerialization><JSON/></OutputSerialization>
+                </SelectObjectContentRequest>"#,
+                expression
+            );
+            dispatch_request(
+                state.clone(),
+                Method::POST,
+                "/test-zone/fruit.tsv.gz?select&select-type=2"
+                    .parse()
+                    .unwrap(),
+                HeaderMap::new(),
+                Some("test-zone".to_string()),
+                Some("fruit.tsv.gz".to_string()),
+                Body::from(body),
+            )
+        };
 
-        let chunks: Vec<std::result::Result<Bytes, std::io::Error>> = vec![
-            Ok(Bytes::from_static(chunk1)),
-            Ok(Bytes::from_static(chunk2)),
-        ];
-        let input_stream = stream::iter(chunks);
+        let response = select("SELECT s.name FROM S3Object s WHERE s.qty &gt; 10")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let events = crate::s3::select::tests::decode(&body);
+        let kinds: Vec<&str> = events.iter().map(|(kind, _)| kind.as_str()).collect();
+        assert_eq!(kinds, ["Records", "Stats", "End"]);
+        assert_eq!(events[0].1, b"{\"name\":\"banana\"}\n");
+        assert!(
+            String::from_utf8_lossy(&events[1].1).contains("<BytesProcessed>27</BytesProcessed>")
+        );
 
-        let (hashing_stream, hash_rx) = HashingStream::new_sha256(input_stream);
+        let err = select("SELECT SUM(s.qty) FROM S3Object s")
+            .await
+            .unwrap_err();
+        assert_eq!(err.s3_error_code(), "UnsupportedSqlOperation");
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+    }
 
-        let collected: Vec<_> = hashing_stream.collect().await;
-        assert_eq!(collected.len(), 2);
+    #[test]
+    fn test_extra_zone_parsing() {
+        let zone = crate::config::parse_extra_zone("staging:key-1:ny").unwrap();
+        assert_eq!(zone.name, "staging");
+        assert_eq!(zone.access_key, "key-1");
+        assert_eq!(zone.region, Some(crate::config::StorageRegion::NewYork));
+        assert_eq!(
+            crate::config::parse_extra_zone("staging:key-1")
+                .unwrap()
+                .region,
+            None
+        );
+        for bad in ["staging", ":key", "staging:", "staging:key:mars"] {
+            assert!(crate::config::parse_extra_zone(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(parse_copy_source_range("bytes=0-9", 10).unwrap(), (0, 9));
+        for bad in ["bytes=5-", "bytes=-5", "bytes=5-1", "0-5"] {
+            assert_eq!(
+                parse_copy_source_range(bad, 10)
+                    .unwrap_err()
+                    .s3_error_code(),
+                "InvalidArgument"
+            );
+        }
+    }
 
-        let computed_hash = hash_rx.await.unwrap();
-        assert_eq!(computed_hash, expected_hash);
+    #[tokio::test]
+    async fn test_non_null_version_id_is_rejected() {
+        let err = dispatch_request(
+            test_state(),
+            Method::GET,
+            "/test-zone/file.txt?versionId=3HL4kqtJlcpXroDTDmJ"
+                .parse()
+                .unwrap(),
+            HeaderMap::new(),
+            Some("test-zone".to_string()),
+            Some("file.txt".to_string()),
+            Body::empty(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "NoSuchVersion");
+        assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            requested_version_id("versionId=null&partNumber=1").as_deref(),
+            Some("null")
+        );
     }
 
     #[tokio::test]
-    async fn test_hashing_stream_empty() {
-        let expected_hash = hex::encode(Sha256::digest(b""));
+    async fn test_key_prefix_rejects_traversal() {
+        let state = AppState::new(Config::parse_from([
+            "bunny-s3-proxy",
+            "--storage-zone",
+            "test-zone",
+            "--access-key",
+            "test-key",
+            "--key-prefix",
+            "apps/service-a",
+        ]))
+        .unwrap();
+        assert_eq!(state.config.key_prefix.as_deref(), Some("apps/service-a/"));
 
-        let chunks: Vec<std::result::Result<Bytes, std::io::Error>> = vec![];
-        let input_stream = stream::iter(chunks);
+        for key in [
+            "../service-b/secret",
+            "a/%2e%2e/%2e%2e/b",
+            "/etc/passwd",
+            "a\\..\\..\\b",
+        ] {
+            let err = dispatch_request(
+                state.clone(),
+                Method::GET,
+                format!("/test-zone/{}", key.replace('\\', "%5C"))
+                    .parse()
+                    .unwrap(),
+                HeaderMap::new(),
+                Some("test-zone".to_string()),
+                Some(key.to_string()),
+                Body::empty(),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.s3_error_code(), "AccessDenied", "{}", key);
+        }
 
-        let (hashing_stream, hash_rx) = HashingStream::new_sha256(input_stream);
+        for source in [
+            "test-zone/../service-b/secret",
+            "test-zone/..%2F..%2Fsecret",
+        ] {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-amz-copy-source", source.parse().unwrap());
+            let err = handle_copy_object(state.clone(), "test-zone", "copy.txt", &headers)
+                .await
+                .unwrap_err();
+            assert_eq!(err.s3_error_code(), "AccessDenied", "{}", source);
+        }
 
-        let collected: Vec<_> = hashing_stream.collect().await;
-        assert_eq!(collected.len(), 0);
+        assert!(state.check_key("reports/2024/../summary..txt").is_err());
+        assert!(state.check_key("reports/2024/summary..txt").is_ok());
+        assert!(test_state().check_key("a/../b").is_err());
+        assert!(test_state().check_key("a/..b").is_ok());
+    }
 
-        let computed_hash = hash_rx.await.unwrap();
-        assert_eq!(computed_hash, expected_hash);
+    #[tokio::test]
+    async fn test_list_buckets_pagination_parameters() {
+        // Query shape sent by the AWS SDK's ListBuckets paginator
+        let response =
+            handle_list_buckets(test_state(), "x-id=ListBuckets&max-buckets=1&prefix=test")
+                .await
+                .unwrap();
+        let body = body_string(response).await;
+        assert!(body.contains("<Name>test-zone</Name>"));
+        assert!(body.contains("<BucketRegion>de</BucketRegion>"));
+        assert!(body.contains("<Prefix>test</Prefix>"));
+        assert!(!body.contains("<ContinuationToken>"));
+
+        for query in [
+            "max-buckets=1&continuation-token=test-zone",
+            "prefix=other",
+            "bucket-region=uk",
+        ] {
+            let body = body_string(handle_list_buckets(test_state(), query).await.unwrap()).await;
+            assert!(!body.contains("<Bucket>"), "{}: {}", query, body);
+        }
+
+        let body = body_string(handle_list_buckets(test_state(), "").await.unwrap()).await;
+        assert!(body.contains("<Name>test-zone</Name>"));
+
+        let err = handle_list_buckets(test_state(), "max-buckets=0")
+            .await
+            .unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_listing_index_serves_recursive_listings() {
+        let state = mock_state(&["--listing-index"]).await;
+        assert_eq!(state.listing_indexes.len(), 2);
+        let index = Arc::clone(&state.listing_indexes[0]);
+        assert_eq!(index.bucket(), "test-zone");
+        for key in ["a.txt", "b.txt", "c/d.txt", "e.txt"] {
+            state
+                .bunny
+                .upload(key, Bytes::from(key.to_string()), UploadOptions::default())
+                .await
+                .unwrap();
+        }
+        index.rebuild().await;
+        let keys = |listing: &str| -> Vec<String> {
+            listing
+                .split("<Key>")
+                .skip(1)
+                .map(|s| s.split("</Key>").next().unwrap().to_string())
+                .collect()
+        };
+
+        let listing = body_string(
+            send(
+                &state,
+                Method::GET,
+                "/test-zone?list-type=2&max-keys=2&start-after=a.txt",
+                &[],
+                "",
+            )
+            .await
+            .unwrap(),
+        )
+        .await;
+        assert_eq!(keys(&listing), ["b.txt", "c/d.txt"]);
+        assert!(listing.contains("<IsTruncated>true</IsTruncated>"));
+
+        // The proxy's own writes show at once.
+        send(&state, Method::PUT, "/test-zone/c/f.txt", &[], "f")
+            .await
+            .unwrap();
+        send(&state, Method::DELETE, "/test-zone/b.txt", &[], "")
+            .await
+            .unwrap();
+        let listing = body_string(
+            send(
+                &state,
+                Method::GET,
+                "/test-zone?list-type=2&prefix=c/",
+                &[],
+                "",
+            )
+            .await
+            .unwrap(),
+        )
+        .await;
+        assert_eq!(keys(&listing), ["c/d.txt", "c/f.txt"]);
+        let listing = body_string(
+            send(&state, Method::GET, "/test-zone?list-type=2", &[], "")
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(keys(&listing), ["a.txt", "c/d.txt", "c/f.txt", "e.txt"]);
+        assert!(
+            ListingIndex::render_metrics(&state.listing_indexes)
+                .contains("bunny_s3_proxy_listing_index_fallbacks_total{bucket=\"test-zone\"} 0\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buffered_bodies_are_admitted_by_memory() {
+        let state = mock_state(&[
+            "--max-buffered-bytes",
+            "100000",
+            "--max-buffered-wait",
+            "0s",
+        ])
+        .await;
+        let xml = format!(
+            "<Delete><Object><Key>doc.txt</Key></Object></Delete>{}",
+            " ".repeat(30_000 - 52)
+        );
+        assert_eq!(xml.len(), 30_000);
+
+        // Each body is held back until every request has been admitted or refused.
+        let mut senders = Vec::new();
+        let mut requests = Vec::new();
+        for _ in 0..8 {
+            let (tx, rx) = tokio::sync::mpsc::channel::<std::io::Result<Bytes>>(1);
+            senders.push(tx);
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_LENGTH, xml.len().into());
+            requests.push(tokio::spawn(dispatch_request(
+                state.clone(),
+                Method::POST,
+                "/test-zone?delete".parse().unwrap(),
+                headers,
+                Some("test-zone".into()),
+                None,
+                Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(rx)),
+            )));
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while requests.iter().filter(|r| r.is_finished()).count() < 5 {
+            assert!(Instant::now() < deadline, "refusals never came");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(state.buffers.held(), 90_000);
+
+        for tx in senders {
+            let _ = tx.send(Ok(Bytes::from(xml.clone()))).await;
+        }
+        let (mut admitted, mut refused) = (0, 0);
+        for request in requests {
+            match request.await.unwrap() {
+                Ok(response) => {
+                    assert_eq!(response.status(), StatusCode::OK);
+                    admitted += 1;
+                }
+                Err(err) => {
+                    assert_eq!(err.s3_error_code(), "SlowDown");
+                    assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+                    refused += 1;
+                }
+            }
+        }
+        assert_eq!((admitted, refused), (3, 5));
+        assert_eq!(state.buffers.held(), 0);
+        let metrics = state.buffers.render_metrics();
+        assert!(
+            metrics.contains("bunny_s3_proxy_buffered_bytes 0\n"),
+            "{}",
+            metrics
+        );
+        assert!(
+            metrics.contains("bunny_s3_proxy_buffered_rejected_total 5\n"),
+            "{}",
+            metrics
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_object_strips_aws_chunked_framing() {
+        let state = mock_state(&["--stage-overwrites"]).await;
+        let put = |framed: &'static str, decoded_length: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_LENGTH, framed.len().into());
+            headers.insert(
+                "x-amz-content-sha256",
+                HeaderValue::from_static(chunked::STREAMING_UNSIGNED_TRAILER),
+            );
+            headers.insert(
+                chunked::DECODED_CONTENT_LENGTH,
+                decoded_length.parse().unwrap(),
+            );
+            headers.insert(
+                chunked::TRAILER,
+                HeaderValue::from_static("x-amz-checksum-crc32"),
+            );
+            dispatch_request(
+                state.clone(),
+                Method::PUT,
+                "/test-zone/chunked.txt".parse().unwrap(),
+                headers,
+                Some("test-zone".to_string()),
+                Some("chunked.txt".to_string()),
+                Body::from(framed),
+            )
+        };
+
+        put(
+            "5\r\nhello\r\n6\r\n world\r\n0\r\nx-amz-checksum-crc32:DUoRhQ==\r\n\r\n",
+            "11",
+        )
+        .await
+        .unwrap();
+        let stored = state.bunny.download("chunked.txt").await.unwrap();
+        assert_eq!(stored.bytes().await.unwrap(), "hello world");
+
+        // A broken body fails with its reason and leaves the object it was
+        // to replace as it was.
+        let err = put("5\r\nhello\r\nzz\r\n", "11").await.unwrap_err();
+        assert_eq!(err.s3_error_code(), "InvalidRequest");
+        let err = put(
+            "5\r\nHELLO\r\n6\r\n world\r\n0\r\nx-amz-checksum-crc32:DUoRhQ==\r\n\r\n",
+            "11",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "BadDigest");
+        let stored = state.bunny.download("chunked.txt").await.unwrap();
+        assert_eq!(stored.bytes().await.unwrap(), "hello world");
+    }
+
+    #[tokio::test]
+    async fn test_presigned_requests_are_verified() {
+        let state = mock_state(&["--require-auth"]).await;
+        state
+            .bunny
+            .upload("doc.txt", Bytes::from("secret"), UploadOptions::default())
+            .await
+            .unwrap();
+        let presign = crate::s3::auth::Presign {
+            method: Method::GET,
+            host: "localhost:9000",
+            path: "/test-zone/doc.txt",
+            params: Vec::new(),
+            headers: Vec::new(),
+            region: "de",
+            expires_secs: 60,
+        };
+        let query = state.auth.presign(&presign, chrono::Utc::now()).unwrap();
+        let get = |query: String| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::HOST, HeaderValue::from_static("localhost:9000"));
+            dispatch_request(
+                state.clone(),
+                Method::GET,
+                format!("/test-zone/doc.txt?{}", query).parse().unwrap(),
+                headers,
+                Some("test-zone".to_string()),
+                Some("doc.txt".to_string()),
+                Body::empty(),
+            )
+        };
+
+        let response = get(query.clone()).await.unwrap();
+        assert_eq!(body_string(response).await, "secret");
+
+        let (unsigned, _) = query.split_once("&X-Amz-Signature=").unwrap();
+        let forged = format!("{}&X-Amz-Signature={}", unsigned, "0".repeat(64));
+        let err = get(forged).await.unwrap_err();
+        assert!(matches!(err, ProxyError::InvalidSignature));
+    }
+
+    #[tokio::test]
+    async fn test_key_policies_limit_signed_requests() {
+        let path = std::env::temp_dir().join(format!("key-policies-{}", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            r#"
+            [[policy]]
+            access-key-id = "reporting"
+            methods = ["GET", "HEAD"]
+
+            [[policy]]
+            access-key-id = "backup"
+            prefixes = ["backups/"]
+            "#,
+        )
+        .unwrap();
+        let state = mock_state(&[
+            "--s3-credentials",
+            "reporting:secret1,backup:secret2",
+            "--key-policies-config",
+            path.to_str().unwrap(),
+        ])
+        .await;
+        state
+            .bunny
+            .upload(
+                "reports/q3.csv",
+                Bytes::from("q3"),
+                UploadOptions::default(),
+            )
+            .await
+            .unwrap();
+        let request =
+            |signer: &str, method: Method, uri: &str, headers: &[(&'static str, &str)]| {
+                let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+                let presign = crate::s3::auth::Presign {
+                    method: method.clone(),
+                    host: "localhost:9000",
+                    path,
+                    params: url::form_urlencoded::parse(query.as_bytes())
+                        .into_owned()
+                        .collect(),
+                    headers: Vec::new(),
+                    region: "de",
+                    expires_secs: 60,
+                };
+                let secret = match signer {
+                    "reporting" => "secret1",
+                    _ => "secret2",
+                };
+                let signed = AwsAuth::new(signer.into(), secret.into())
+                    .presign(&presign, Utc::now())
+                    .unwrap();
+                // A SigV2 key name goes first, ahead of X-Amz-Credential.
+                let mut pairs: Vec<&str> = signed.split('&').collect();
+                pairs.sort_by_key(|pair| !pair.starts_with("AWSAccessKeyId="));
+                let signed = pairs.join("&");
+                let (bucket, key) = parse_s3_path(path);
+                let mut header_map = HeaderMap::new();
+                header_map.insert(header::HOST, HeaderValue::from_static("localhost:9000"));
+                header_map.insert(header::CONTENT_LENGTH, 1.into());
+                for (name, value) in headers {
+                    header_map.insert(*name, value.parse().unwrap());
+                }
+                dispatch_request(
+                    state.clone(),
+                    method,
+                    format!("{}?{}", path, signed).parse().unwrap(),
+                    header_map,
+                    bucket,
+                    key,
+                    Body::from("x"),
+                )
+            };
+        let denied =
+            |result: Result<Response>| matches!(result, Err(ProxyError::KeyPolicyDenied(_)));
+
+        assert!(
+            request("reporting", Method::GET, "/test-zone/reports/q3.csv", &[])
+                .await
+                .is_ok()
+        );
+        assert!(
+            request("reporting", Method::GET, "/test-zone?list-type=2", &[])
+                .await
+                .is_ok()
+        );
+        assert!(denied(
+            request("reporting", Method::PUT, "/test-zone/reports/q4.csv", &[]).await
+        ));
+
+        assert!(
+            request("backup", Method::PUT, "/test-zone/backups/1.tar", &[])
+                .await
+                .is_ok()
+        );
+        assert!(denied(
+            request("backup", Method::PUT, "/test-zone/reports/q4.csv", &[]).await
+        ));
+        assert!(denied(
+            request("backup", Method::GET, "/test-zone/reports/q3.csv", &[]).await
+        ));
+        assert!(
+            request(
+                "backup",
+                Method::GET,
+                "/test-zone?list-type=2&prefix=backups/",
+                &[]
+            )
+            .await
+            .is_ok()
+        );
+        assert!(denied(
+            request("backup", Method::GET, "/test-zone?list-type=2", &[]).await
+        ));
+        assert!(denied(
+            request("backup", Method::GET, "/test-zone?lifecycle", &[]).await
+        ));
+        // Copying out of reach of the key is refused too.
+        let copy = [("x-amz-copy-source", "/test-zone/reports/q3.csv")];
+        assert!(denied(
+            request("backup", Method::PUT, "/test-zone/backups/q3.csv", &copy).await
+        ));
+        assert!(state.bunny.describe("backups/q3.csv").await.is_err());
+
+        // The policy is that of the key the signature was verified with, not
+        // of another key name signed into the query.
+        assert!(denied(
+            request(
+                "reporting",
+                Method::PUT,
+                "/test-zone/reports/q4.csv?AWSAccessKeyId=unpoliced",
+                &[]
+            )
+            .await
+        ));
+        assert!(state.bunny.describe("reports/q4.csv").await.is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_objects_v1_paginates_with_markers() {
+        let state = mock_state(&["--listing-index"]).await;
+        for key in ["a.txt", "b.txt", "c/d.txt", "e.txt"] {
+            state
+                .bunny
+                .upload(key, Bytes::from(key.to_string()), UploadOptions::default())
+                .await
+                .unwrap();
+        }
+        state.listing_indexes[0].rebuild().await;
+        let list = |uri: &str| {
+            let (bucket, key) = parse_s3_path(uri.split('?').next().unwrap());
+            let state = state.clone();
+            let uri = uri.to_string();
+            async move {
+                let response = dispatch_request(
+                    state,
+                    Method::GET,
+                    uri.parse().unwrap(),
+                    HeaderMap::new(),
+                    bucket,
+                    key,
+                    Body::empty(),
+                )
+                .await
+                .unwrap();
+                body_string(response).await
+            }
+        };
+
+        let page = list("/test-zone?max-keys=2").await;
+        assert!(page.contains("<Marker></Marker>"));
+        assert!(page.contains("<IsTruncated>true</IsTruncated>"));
+        assert!(page.contains("<NextMarker>b.txt</NextMarker>"));
+        assert!(!page.contains("<KeyCount>"));
+        assert!(!page.contains("c/d.txt"));
+
+        let page = list("/test-zone?max-keys=2&marker=b.txt").await;
+        assert!(page.contains("<Marker>b.txt</Marker>"));
+        assert!(page.contains("<Key>c/d.txt</Key>") && page.contains("<Key>e.txt</Key>"));
+        assert!(!page.contains("<Key>b.txt</Key>"));
+        assert!(page.contains("<IsTruncated>false</IsTruncated>"));
+        assert!(!page.contains("<NextMarker>"));
+
+        let page = list("/test-zone?delimiter=/&prefix=").await;
+        assert!(page.contains("<CommonPrefixes><Prefix>c/</Prefix></CommonPrefixes>"));
+
+        // list-type=2 still gets the V2 answer.
+        let page = list("/test-zone?list-type=2&max-keys=2").await;
+        assert!(page.contains("<KeyCount>2</KeyCount>"));
+        assert!(!page.contains("<Marker>"));
+    }
+
+    #[tokio::test]
+    async fn test_list_objects_v2_resumes_from_continuation_tokens() {
+        let state = mock_state(&[]).await;
+        let all = ["a.txt", "b.txt", "c/d.txt", "c/e/f.txt", "g.txt"];
+        for key in all {
+            state
+                .bunny
+                .upload(key, Bytes::from(key.to_string()), UploadOptions::default())
+                .await
+                .unwrap();
+        }
+        let between = |page: &str, open: &str, close: &str| -> Vec<String> {
+            page.split(open)
+                .skip(1)
+                .map(|s| s.split(close).next().unwrap().to_string())
+                .collect()
+        };
+
+        // Every key once, whatever the page size.
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let uri = match &token {
+                Some(token) => format!(
+                    "/test-zone?list-type=2&max-keys=2&continuation-token={}",
+                    token
+                ),
+                None => "/test-zone?list-type=2&max-keys=2".to_string(),
+            };
+            let page = body_string(send(&state, Method::GET, &uri, &[], "").await.unwrap()).await;
+            keys.extend(between(&page, "<Key>", "</Key>"));
+            token = between(&page, "<NextContinuationToken>", "</NextContinuationToken>").pop();
+            if token.is_none() {
+                assert!(page.contains("<IsTruncated>false</IsTruncated>"));
+                break;
+            }
+            assert!(!token.as_ref().unwrap().contains(".txt"));
+        }
+        assert_eq!(keys, all);
+
+        // With a delimiter, common prefixes take their place on the pages.
+        let page = body_string(
+            send(
+                &state,
+                Method::GET,
+                "/test-zone?list-type=2&delimiter=/&max-keys=2&start-after=a.txt",
+                &[],
+                "",
+            )
+            .await
+            .unwrap(),
+        )
+        .await;
+        assert_eq!(between(&page, "<Key>", "</Key>"), ["b.txt"]);
+        assert_eq!(
+            between(&page, "<CommonPrefixes><Prefix>", "</Prefix>"),
+            ["c/"]
+        );
+        let token = between(&page, "<NextContinuationToken>", "</NextContinuationToken>")
+            .pop()
+            .unwrap();
+        let page = body_string(
+            send(
+                &state,
+                Method::GET,
+                &format!(
+                    "/test-zone?list-type=2&delimiter=/&continuation-token={}&start-after=a.txt",
+                    token
+                ),
+                &[],
+                "",
+            )
+            .await
+            .unwrap(),
+        )
+        .await;
+        assert_eq!(between(&page, "<Key>", "</Key>"), ["g.txt"]);
+        assert!(!page.contains("<CommonPrefixes>"));
+
+        let err = send(
+            &state,
+            Method::GET,
+            "/test-zone?list-type=2&continuation-token=%21%21",
+            &[],
+            "",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "InvalidArgument");
+    }
+
+    #[tokio::test]
+    async fn test_get_object_attributes() {
+        let state = mock_state(&[]).await;
+        send(&state, Method::PUT, "/test-zone/doc.txt", &[], "hello")
+            .await
+            .unwrap();
+
+        let response = send(
+            &state,
+            Method::GET,
+            "/test-zone/doc.txt?attributes",
+            &[("x-amz-object-attributes", "ETag, ObjectSize,StorageClass")],
+            "",
+        )
+        .await
+        .unwrap();
+        assert!(response.headers().contains_key(header::LAST_MODIFIED));
+        let body = body_string(response).await;
+        let etag = hex::encode_upper(Sha256::digest(b"hello"));
+        assert!(body.contains(&format!("<ETag>{}</ETag>", etag)), "{}", body);
+        assert!(body.contains("<ObjectSize>5</ObjectSize>"));
+        assert!(body.contains("<StorageClass>STANDARD</StorageClass>"));
+        assert!(!body.contains("<Checksum>"));
+
+        let body = body_string(
+            send(
+                &state,
+                Method::GET,
+                "/test-zone/doc.txt?attributes",
+                &[("x-amz-object-attributes", "Checksum")],
+                "",
+            )
+            .await
+            .unwrap(),
+        )
+        .await;
+        let checksum = base64::engine::general_purpose::STANDARD.encode(Sha256::digest(b"hello"));
+        assert!(body.contains(&format!("<ChecksumSHA256>{}</ChecksumSHA256>", checksum)));
+        assert!(!body.contains("<ETag>"));
+
+        for attributes in [&[][..], &[("x-amz-object-attributes", "Size")]] {
+            let err = send(
+                &state,
+                Method::GET,
+                "/test-zone/doc.txt?attributes",
+                attributes,
+                "",
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.s3_error_code(), "InvalidArgument");
+        }
+        let err = send(
+            &state,
+            Method::GET,
+            "/test-zone/missing.txt?attributes",
+            &[("x-amz-object-attributes", "ETag")],
+            "",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "NoSuchKey");
+    }
+
+    #[tokio::test]
+    async fn test_put_headers_are_replayed_on_get_and_head() {
+        let state = mock_state(&[]).await;
+        send(
+            &state,
+            Method::PUT,
+            "/test-zone/report.csv",
+            &[
+                ("cache-control", "max-age=60"),
+                ("content-disposition", "attachment; filename=\"q3.csv\""),
+                ("content-encoding", "gzip"),
+            ],
+            "a,b",
+        )
+        .await
+        .unwrap();
+
+        for method in [Method::GET, Method::HEAD] {
+            let response = send(&state, method.clone(), "/test-zone/report.csv", &[], "")
+                .await
+                .unwrap();
+            let headers = response.headers();
+            assert_eq!(headers[header::CACHE_CONTROL], "max-age=60", "{}", method);
+            assert_eq!(
+                headers[header::CONTENT_DISPOSITION],
+                "attachment; filename=\"q3.csv\""
+            );
+            assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+        }
+        let response = send(
+            &state,
+            Method::GET,
+            "/test-zone/report.csv",
+            &[("range", "bytes=0-0")],
+            "",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=60");
+
+        // Copies keep the source's headers unless they replace its metadata.
+        send(
+            &state,
+            Method::PUT,
+            "/test-zone/copy.csv",
+            &[("x-amz-copy-source", "/test-zone/report.csv")],
+            "",
+        )
+        .await
+        .unwrap();
+        let response = send(&state, Method::HEAD, "/test-zone/copy.csv", &[], "")
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=60");
+        send(
+            &state,
+            Method::PUT,
+            "/test-zone/copy.csv",
+            &[
+                ("x-amz-copy-source", "/test-zone/report.csv"),
+                ("x-amz-metadata-directive", "REPLACE"),
+                ("cache-control", "no-store"),
+            ],
+            "",
+        )
+        .await
+        .unwrap();
+        let response = send(&state, Method::HEAD, "/test-zone/copy.csv", &[], "")
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+        assert!(!response.headers().contains_key(header::CONTENT_DISPOSITION));
+
+        // Overwriting without them clears them.
+        send(&state, Method::PUT, "/test-zone/report.csv", &[], "a,b")
+            .await
+            .unwrap();
+        let response = send(&state, Method::GET, "/test-zone/report.csv", &[], "")
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(header::CACHE_CONTROL));
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn test_part_number_reads_one_part() {
+        let state = mock_state(&[]).await;
+        let body = body_string(
+            send(&state, Method::POST, "/test-zone/big.bin?uploads", &[], "")
+                .await
+                .unwrap(),
+        )
+        .await;
+        let upload_id = parse_upload_id(&body);
+        let mut complete = String::from("<CompleteMultipartUpload>");
+        for (n, part) in [(1, "hello "), (2, "world")] {
+            let response = send(
+                &state,
+                Method::PUT,
+                &format!("/test-zone/big.bin?partNumber={}&uploadId={}", n, upload_id),
+                &[],
+                part,
+            )
+            .await
+            .unwrap();
+            complete.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                n,
+                response.headers()[header::ETAG].to_str().unwrap()
+            ));
+        }
+        complete.push_str("</CompleteMultipartUpload>");
+        let response = send(
+            &state,
+            Method::POST,
+            &format!("/test-zone/big.bin?uploadId={}", upload_id),
+            &[],
+            &complete,
+        )
+        .await
+        .unwrap();
+        assert!(body_string(response).await.contains("<ETag>"));
+
+        let response = send(
+            &state,
+            Method::GET,
+            "/test-zone/big.bin?partNumber=2",
+            &[],
+            "",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[MP_PARTS_COUNT], "2");
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 6-10/11");
+        assert_eq!(body_string(response).await, "world");
+
+        let response = send(
+            &state,
+            Method::HEAD,
+            "/test-zone/big.bin?partNumber=1",
+            &[],
+            "",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "6");
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 0-5/11");
+        assert_eq!(response.headers()[MP_PARTS_COUNT], "2");
+
+        let uri = "/test-zone/big.bin?attributes";
+        let parts = ("x-amz-object-attributes", "ObjectParts");
+        let body = body_string(send(&state, Method::GET, uri, &[parts], "").await.unwrap()).await;
+        assert!(
+            body.contains(
+                "<ObjectParts><TotalPartsCount>2</TotalPartsCount><PartNumberMarker>0</PartNumberMarker><MaxParts>1000</MaxParts><IsTruncated>false</IsTruncated><Part><PartNumber>1</PartNumber><Size>6</Size></Part><Part><PartNumber>2</PartNumber><Size>5</Size></Part></ObjectParts>"
+            ),
+            "{}",
+            body
+        );
+        let first = [parts, ("x-amz-max-parts", "1")];
+        let body = body_string(send(&state, Method::GET, uri, &first, "").await.unwrap()).await;
+        assert!(
+            body.contains("<NextPartNumberMarker>1</NextPartNumberMarker>"),
+            "{}",
+            body
+        );
+        assert!(body.contains("<IsTruncated>true</IsTruncated>"), "{}", body);
+        assert!(!body.contains("<PartNumber>2</PartNumber>"), "{}", body);
+        let rest = [parts, ("x-amz-part-number-marker", "1")];
+        let body = body_string(send(&state, Method::GET, uri, &rest, "").await.unwrap()).await;
+        assert!(body.contains("<PartNumber>2</PartNumber>"), "{}", body);
+        assert!(!body.contains("<PartNumber>1</PartNumber>"), "{}", body);
+
+        for (uri, code) in [
+            ("/test-zone/big.bin?partNumber=3", "InvalidPartNumber"),
+            ("/test-zone/big.bin?partNumber=0", "InvalidArgument"),
+        ] {
+            let err = send(&state, Method::GET, uri, &[], "").await.unwrap_err();
+            assert_eq!(err.s3_error_code(), code, "{}", uri);
+        }
+
+        // An object written in one PUT is its own single part.
+        send(&state, Method::PUT, "/test-zone/small.txt", &[], "small")
+            .await
+            .unwrap();
+        let response = send(
+            &state,
+            Method::GET,
+            "/test-zone/small.txt?partNumber=1",
+            &[],
+            "",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(MP_PARTS_COUNT));
+        assert_eq!(body_string(response).await, "small");
+        let response = send(
+            &state,
+            Method::GET,
+            "/test-zone/small.txt?attributes",
+            &[parts],
+            "",
+        )
+        .await
+        .unwrap();
+        assert!(!body_string(response).await.contains("<ObjectParts>"));
+        let err = send(
+            &state,
+            Method::GET,
+            "/test-zone/small.txt?partNumber=2",
+            &[],
+            "",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "InvalidPartNumber");
+    }
+
+    #[tokio::test]
+    async fn test_flexible_checksums_on_put_and_upload_part() {
+        let state = mock_state(&["--stage-overwrites"]).await;
+        let crc32c = checksums::compute(checksums::ChecksumAlgorithm::Crc32c, b"hello world").value;
+        send(
+            &state,
+            Method::PUT,
+            "/test-zone/doc.txt",
+            &[("x-amz-checksum-crc32c", &crc32c)],
+            "hello world",
+        )
+        .await
+        .unwrap();
+
+        let response = send(&state, Method::HEAD, "/test-zone/doc.txt", &[], "")
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-amz-checksum-crc32c"], crc32c.as_str());
+        let body = body_string(
+            send(
+                &state,
+                Method::GET,
+                "/test-zone/doc.txt?attributes",
+                &[("x-amz-object-attributes", "Checksum")],
+                "",
+            )
+            .await
+            .unwrap(),
+        )
+        .await;
+        assert!(
+            body.contains(&format!("<ChecksumCRC32C>{}</ChecksumCRC32C>", crc32c)),
+            "{}",
+            body
+        );
+
+        // A body that does not hold its checksum is refused and not kept.
+        let err = send(
+            &state,
+            Method::PUT,
+            "/test-zone/other.txt",
+            &[("x-amz-checksum-crc32", "AAAAAA==")],
+            "hello world",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "BadDigest");
+        assert!(state.bunny.describe("other.txt").await.is_err());
+
+        // Nor does it replace the object, or the checksum recorded for it.
+        let err = send(
+            &state,
+            Method::PUT,
+            "/test-zone/doc.txt",
+            &[("x-amz-checksum-crc32", "AAAAAA==")],
+            "goodbye",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "BadDigest");
+        let response = send(&state, Method::HEAD, "/test-zone/doc.txt", &[], "")
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-amz-checksum-crc32c"], crc32c.as_str());
+        assert!(!response.headers().contains_key("x-amz-checksum-crc32"));
+        let response = send(&state, Method::GET, "/test-zone/doc.txt", &[], "")
+            .await
+            .unwrap();
+        assert_eq!(body_string(response).await, "hello world");
+
+        let body = body_string(
+            send(&state, Method::POST, "/test-zone/big.bin?uploads", &[], "")
+                .await
+                .unwrap(),
+        )
+        .await;
+        let upload_id = parse_upload_id(&body);
+        let part = format!("/test-zone/big.bin?partNumber=1&uploadId={}", upload_id);
+        let sha1 = checksums::compute(checksums::ChecksumAlgorithm::Sha1, b"part").value;
+        let response = send(
+            &state,
+            Method::PUT,
+            &part,
+            &[("x-amz-checksum-sha1", &sha1)],
+            "part",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.headers()["x-amz-checksum-sha1"], sha1.as_str());
+        let err = send(
+            &state,
+            Method::PUT,
+            &part,
+            &[("x-amz-checksum-sha1", &sha1)],
+            "torn",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.s3_error_code(), "BadDigest");
     }
 }
@@ -0,0 +1,181 @@
+//! `--html-listing`: a plain index page for browsers opening a bucket or a
+//! prefix ending in `/`, instead of ListBucketResult XML. Only requests whose
+//! Accept header prefers HTML over XML get it, so SDKs and tools, which send
+//! no Accept, `*/*` or an XML type, see no change.
+
+use axum::http::{HeaderMap, header};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use std::fmt::Write;
+
+use crate::bunny::client::encode_path;
+
+use super::types::S3Object;
+
+/// Whether the Accept header ranks HTML above XML. `*/*` counts for
+/// neither, as browsers always list HTML explicitly.
+pub fn prefers_html(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let (mut html, mut xml) = (0.0f32, 0.0f32);
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = params
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        match media.as_str() {
+            "text/html" | "application/xhtml+xml" => html = html.max(quality),
+            "application/xml" | "text/xml" => xml = xml.max(quality),
+            _ => {}
+        }
+    }
+    html > 0.0 && html > xml
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The path-style URL of `key` in `bucket`.
+fn href(bucket: &str, key: &str) -> String {
+    format!("/{}/{}", encode_path(bucket), encode_path(key))
+}
+
+fn format_size(size: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size.max(0) as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", size),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+/// Renders one page of the listing of `prefix`: its parent, the sorted
+/// `prefixes` below it and `objects`, and with `next` a link to the page
+/// starting after that key.
+pub fn render(
+    bucket: &str,
+    prefix: &str,
+    prefixes: &[String],
+    objects: &[S3Object],
+    next: Option<&str>,
+) -> String {
+    let title = escape(&format!("{}/{}", bucket, prefix));
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Index of {title}</title>\n\
+         <style>body{{font-family:sans-serif}}td,th{{padding:0.1em 1em;text-align:left}}td.size{{text-align:right}}</style>\n\
+         </head>\n<body>\n<h1>Index of {title}</h1>\n<table>\n\
+         <tr><th>Name</th><th>Size</th><th>Last modified</th></tr>\n"
+    );
+    if !prefix.is_empty() {
+        let trimmed = prefix.trim_end_matches('/');
+        let parent = trimmed.rfind('/').map_or("", |i| &trimmed[..=i]);
+        let _ = writeln!(
+            out,
+            "<tr><td><a href=\"{}\">../</a></td><td></td><td></td></tr>",
+            escape(&href(bucket, parent))
+        );
+    }
+    for sub in prefixes {
+        let _ = writeln!(
+            out,
+            "<tr><td><a href=\"{}\">{}</a></td><td></td><td></td></tr>",
+            escape(&href(bucket, sub)),
+            escape(sub.strip_prefix(prefix).unwrap_or(sub))
+        );
+    }
+    for obj in objects {
+        let _ = writeln!(
+            out,
+            "<tr><td><a href=\"{}\">{}</a></td><td class=\"size\">{}</td><td>{}</td></tr>",
+            escape(&href(bucket, &obj.key)),
+            escape(obj.key.strip_prefix(prefix).unwrap_or(&obj.key)),
+            format_size(obj.size),
+            obj.last_modified.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+    }
+    out.push_str("</table>\n");
+    if let Some(next) = next {
+        let url = format!(
+            "{}?start-after={}",
+            href(bucket, prefix),
+            utf8_percent_encode(next, NON_ALPHANUMERIC)
+        );
+        let _ = writeln!(out, "<p><a href=\"{}\">Next page</a></p>", escape(&url));
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_only_browsers_prefer_html() {
+        let accept = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, value.parse().unwrap());
+            prefers_html(&headers)
+        };
+        assert!(accept(
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+        ));
+        assert!(!accept("*/*"));
+        assert!(!accept("application/xml"));
+        assert!(!accept("text/html;q=0.5, application/xml"));
+        assert!(!accept("text/html;q=0"));
+        assert!(!prefers_html(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_names_are_escaped_and_encoded() {
+        let objects = [S3Object {
+            key: "docs/<b>&co \"x\".txt".to_string(),
+            last_modified: Utc.with_ymd_and_hms(2026, 3, 1, 12, 0, 0).unwrap(),
+            etag: String::new(),
+            size: 1536,
+            storage_class: "STANDARD".to_string(),
+            owner: None,
+        }];
+        let page = render(
+            "bucket",
+            "docs/",
+            &["docs/a b/".to_string()],
+            &objects,
+            Some("docs/<b>&co \"x\".txt"),
+        );
+        assert!(page.contains("<a href=\"/bucket/\">../</a>"));
+        assert!(page.contains("<a href=\"/bucket/docs/a%20b/\">a b/</a>"));
+        assert!(page.contains(
+            "<a href=\"/bucket/docs/%3Cb%3E%26co%20%22x%22.txt\">&lt;b&gt;&amp;co &quot;x&quot;.txt</a>"
+        ));
+        assert!(page.contains("1.5 KiB"));
+        assert!(page.contains("2026-03-01 12:00:00 UTC"));
+        assert!(
+            page.contains("href=\"/bucket/docs/?start-after=docs%2F%3Cb%3E%26co%20%22x%22%2Etxt\"")
+        );
+        assert!(!page.contains("<b>"));
+    }
+}
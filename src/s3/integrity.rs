@@ -0,0 +1,128 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Outcomes of `--verify-downloads` checks of GET bodies against the SHA-256
+/// checksum Bunny stores for each object, and of `--verify-writes` checks
+/// that Bunny reports a written object as it was sent.
+#[derive(Debug, Default)]
+pub struct IntegrityStats {
+    verified: AtomicU64,
+    mismatches: AtomicU64,
+    writes_converged: AtomicU64,
+    writes_converged_late: AtomicU64,
+    writes_unconverged: AtomicU64,
+    convergence_micros: AtomicU64,
+}
+
+impl IntegrityStats {
+    pub fn record(&self, matched: bool) {
+        let counter = if matched {
+            &self.verified
+        } else {
+            &self.mismatches
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a write verification: the delay until Bunny reported the
+    /// written object, or `None` if it never did within the window.
+    pub fn record_write(&self, converged_after: Option<Duration>, attempts: u32) {
+        match converged_after {
+            Some(delay) => {
+                self.writes_converged.fetch_add(1, Ordering::Relaxed);
+                if attempts > 1 {
+                    self.writes_converged_late.fetch_add(1, Ordering::Relaxed);
+                }
+                self.convergence_micros
+                    .fetch_add(delay.as_micros() as u64, Ordering::Relaxed);
+            }
+            None => {
+                self.writes_unconverged.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        for (name, help, counter) in [
+            (
+                "verified",
+                "Full-object downloads whose streamed bytes matched the stored checksum.",
+                &self.verified,
+            ),
+            (
+                "checksum_mismatches",
+                "Full-object downloads whose streamed bytes did not match the stored checksum.",
+                &self.mismatches,
+            ),
+        ] {
+            let name = format!("bunny_s3_proxy_download_{}_total", name);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        for (name, help, counter) in [
+            (
+                "converged",
+                "Verified writes that Bunny reported with the expected length and checksum.",
+                &self.writes_converged,
+            ),
+            (
+                "converged_late",
+                "Verified writes that Bunny only reported correctly after a retried DESCRIBE.",
+                &self.writes_converged_late,
+            ),
+            (
+                "unconverged",
+                "Verified writes that Bunny did not report correctly within the window.",
+                &self.writes_unconverged,
+            ),
+        ] {
+            let name = format!("bunny_s3_proxy_write_verification_{}_total", name);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        let name = "bunny_s3_proxy_write_convergence_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {} Time from upload completion until Bunny reported the written object.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} summary", name);
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            self.convergence_micros.load(Ordering::Relaxed) as f64 / 1e6
+        );
+        let _ = writeln!(
+            out,
+            "{}_count {}",
+            name,
+            self.writes_converged.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_verification_metrics() {
+        let stats = IntegrityStats::default();
+        stats.record_write(Some(Duration::from_millis(0)), 1);
+        stats.record_write(Some(Duration::from_millis(1500)), 3);
+        stats.record_write(None, 8);
+
+        let metrics = stats.render_metrics();
+        assert!(metrics.contains("bunny_s3_proxy_write_verification_converged_total 2\n"));
+        assert!(metrics.contains("bunny_s3_proxy_write_verification_converged_late_total 1\n"));
+        assert!(metrics.contains("bunny_s3_proxy_write_verification_unconverged_total 1\n"));
+        assert!(metrics.contains("bunny_s3_proxy_write_convergence_seconds_sum 1.5\n"));
+        assert!(metrics.contains("bunny_s3_proxy_write_convergence_seconds_count 2\n"));
+    }
+}
@@ -0,0 +1,595 @@
+//! Scheduled inventory reports from `--inventory-config`, laid out like S3
+//! Inventory so that tables defined over S3 Inventory CSV reports, such as
+//! Athena's over the `hive/` symlinks, read them unchanged:
+//!
+//! ```toml
+//! [[inventory]]
+//! id = "all"
+//! prefix = ""
+//! destination = "inventory/"
+//! schedule = "daily"
+//! gzip = true
+//! ```
+//!
+//! Each run walks the prefix and writes, under
+//! `<destination><bucket>/<id>/`, the CSV data file `data/<uuid>.csv[.gz]`,
+//! `<timestamp>/manifest.json` with its `manifest.checksum`, and
+//! `hive/dt=<timestamp>/symlink.txt` naming the data file.
+
+use bytes::Bytes;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use md5::Digest;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::bunny::client::encode_path;
+use crate::bunny::{Backend, StorageBackend, UploadOptions};
+use crate::config::Config;
+use crate::error::{ProxyError, Result};
+
+use super::bucket_config::BucketConfigStore;
+use super::handlers::AppState;
+use super::multipart::MultipartManager;
+use super::object_meta::{ObjectMeta, ObjectMetaStore};
+use super::versions::VersionStore;
+
+/// How often schedules are checked for reports that are due.
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// The manifest folder names, as S3 Inventory writes them.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H-%MZ";
+
+const FILE_SCHEMA: &str = "Bucket, Key, Size, LastModifiedDate, ETag, ChecksumSHA256";
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct InventoryArgs {
+    /// Write every configured report now instead of listing them
+    #[arg(long)]
+    pub now: bool,
+
+    /// Only the report with this ID
+    #[arg(long)]
+    pub id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InventoryFile {
+    #[serde(default)]
+    inventory: Vec<InventoryRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct InventoryRule {
+    #[serde(default = "default_id")]
+    pub id: String,
+    /// Defaults to the storage zone.
+    #[serde(default)]
+    pub bucket: Option<String>,
+    #[serde(default)]
+    pub prefix: String,
+    pub destination: String,
+    pub schedule: Schedule,
+    #[serde(default)]
+    pub gzip: bool,
+}
+
+fn default_id() -> String {
+    "inventory".to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Schedule {
+    Daily,
+    Weekly,
+}
+
+impl Schedule {
+    fn period(self) -> Duration {
+        match self {
+            Self::Daily => Duration::days(1),
+            Self::Weekly => Duration::weeks(1),
+        }
+    }
+}
+
+impl InventoryRule {
+    /// Where this report's files go: `<destination><bucket>/<id>/`.
+    fn base(&self, bucket: &str) -> String {
+        let destination = self.destination.trim_matches('/');
+        match destination.is_empty() {
+            true => format!("{}/{}/", bucket, self.id),
+            false => format!("{}/{}/{}/", destination, bucket, self.id),
+        }
+    }
+}
+
+/// One written report.
+#[derive(Debug, Clone, Serialize)]
+pub struct InventoryRun {
+    pub bucket: String,
+    pub id: String,
+    pub objects: u64,
+    pub total_bytes: u64,
+    pub manifest_key: String,
+    pub written_at: DateTime<Utc>,
+    pub duration_secs: f64,
+}
+
+#[derive(Debug, Default)]
+struct InventoryStats {
+    runs: AtomicU64,
+    failures: AtomicU64,
+    /// The latest successful run of each report, by bucket and ID.
+    last: Mutex<BTreeMap<(String, String), InventoryRun>>,
+}
+
+#[derive(Debug)]
+pub struct Inventory {
+    rules: Vec<InventoryRule>,
+    concurrency: usize,
+    stats: InventoryStats,
+}
+
+impl Inventory {
+    pub fn load(config: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(path) = &config.inventory_config else {
+            return Ok(None);
+        };
+        let rules = Self::read(path)
+            .map_err(|e| anyhow::anyhow!("--inventory-config {}: {}", path.display(), e))?;
+        Ok(Some(Self {
+            rules,
+            concurrency: config.usage_walk_concurrency,
+            stats: InventoryStats::default(),
+        }))
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Vec<InventoryRule>> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn parse(text: &str) -> anyhow::Result<Vec<InventoryRule>> {
+        let file: InventoryFile = toml::from_str(text)?;
+        for (i, rule) in file.inventory.iter().enumerate() {
+            if rule.id.is_empty()
+                || !rule
+                    .id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            {
+                anyhow::bail!("invalid report ID '{}'", rule.id);
+            }
+            if file.inventory[..i]
+                .iter()
+                .any(|r| r.id == rule.id && r.bucket == rule.bucket)
+            {
+                anyhow::bail!("report '{}' is configured twice", rule.id);
+            }
+        }
+        Ok(file.inventory)
+    }
+
+    /// When the report was last written, from its newest manifest folder.
+    pub async fn last_run(
+        &self,
+        client: &Backend,
+        bucket: &str,
+        rule: &InventoryRule,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let folders = match client.list(&rule.base(bucket)).await {
+            Ok(folders) => folders,
+            Err(ProxyError::NotFound(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(folders
+            .iter()
+            .filter(|f| f.is_directory)
+            .filter_map(|f| NaiveDateTime::parse_from_str(&f.object_name, TIMESTAMP_FORMAT).ok())
+            .map(|t| t.and_utc())
+            .max())
+    }
+
+    /// Walks the rule's prefix and writes the data file, manifest and hive
+    /// symlink of a report as of `now`.
+    pub async fn write_report(
+        &self,
+        state: &AppState,
+        bucket: &str,
+        rule: &InventoryRule,
+        now: DateTime<Utc>,
+    ) -> Result<InventoryRun> {
+        let started = Instant::now();
+        let client = state.bucket_client(bucket)?;
+        tracing::info!(
+            "Inventory {}/{}: walking '{}'",
+            bucket,
+            rule.id,
+            rule.prefix
+        );
+        let dir = rule
+            .prefix
+            .rfind('/')
+            .map(|i| &rule.prefix[..=i])
+            .unwrap_or("");
+        let (listed, keys_with_meta) = tokio::join!(
+            client.list_recursive_concurrent(dir, self.concurrency),
+            ObjectMetaStore::keys_with_meta(&client, dir, true)
+        );
+        let mut objects: Vec<_> = listed?
+            .into_iter()
+            .filter(|obj| {
+                let key = obj.s3_key();
+                key.starts_with(&rule.prefix) && !is_internal(state, &key)
+            })
+            .collect();
+        objects.sort_by_key(|obj| obj.s3_key());
+        tracing::info!(
+            "Inventory {}/{}: listed {} objects in {:?}",
+            bucket,
+            rule.id,
+            objects.len(),
+            started.elapsed()
+        );
+
+        // Objects stored encrypted or compressed are reported as sent.
+        let keys_with_meta = keys_with_meta?;
+        let keys: Vec<String> = objects
+            .iter()
+            .map(|obj| obj.s3_key())
+            .filter(|key| keys_with_meta.contains(key))
+            .collect();
+        let metas: HashMap<String, ObjectMeta> = futures::stream::iter(keys)
+            .map(|key| {
+                let client = client.clone();
+                async move {
+                    let meta = ObjectMetaStore::get(&client, &key).await?;
+                    Ok::<_, ProxyError>((key, meta))
+                }
+            })
+            .buffer_unordered(self.concurrency.max(1))
+            .try_collect()
+            .await?;
+
+        let mut csv = String::new();
+        let mut total_bytes = 0;
+        for obj in &objects {
+            let key = obj.s3_key();
+            let (size, etag) = match metas.get(&key).and_then(|m| m.original()) {
+                Some((size, etag)) => (size, etag.to_string()),
+                None => (obj.length.max(0) as u64, obj.etag()),
+            };
+            total_bytes += size;
+            let _ = writeln!(
+                csv,
+                "\"{}\",\"{}\",\"{}\",\"{}\",\"{}\",\"{}\"",
+                bucket,
+                encode_path(&key),
+                size,
+                obj.last_changed.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                etag,
+                obj.checksum.as_deref().unwrap_or("").to_ascii_lowercase()
+            );
+        }
+
+        let base = rule.base(bucket);
+        let data = match rule.gzip {
+            true => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(csv.as_bytes())
+                    .and_then(|()| encoder.finish())
+                    .expect("compressing into memory does not fail")
+            }
+            false => csv.into_bytes(),
+        };
+        let data_key = format!(
+            "{}data/{}.csv{}",
+            base,
+            uuid::Uuid::new_v4(),
+            if rule.gzip { ".gz" } else { "" }
+        );
+        let data_md5 = hex::encode(md5::Md5::digest(&data));
+        let data_size = data.len();
+        client
+            .upload(&data_key, Bytes::from(data), UploadOptions::default())
+            .await?;
+
+        let manifest = serde_json::to_vec_pretty(&serde_json::json!({
+            "sourceBucket": bucket,
+            "destinationBucket": format!("arn:aws:s3:::{}", bucket),
+            "version": "2016-11-30",
+            "creationTimestamp": now.timestamp_millis().to_string(),
+            "fileFormat": "CSV",
+            "fileSchema": FILE_SCHEMA,
+            "files": [{
+                "key": data_key,
+                "size": data_size,
+                "MD5checksum": data_md5,
+            }],
+        }))?;
+        let folder = format!("{}{}/", base, now.format(TIMESTAMP_FORMAT));
+        let manifest_key = format!("{}manifest.json", folder);
+        let checksum = hex::encode(md5::Md5::digest(&manifest));
+        client
+            .upload(
+                &manifest_key,
+                Bytes::from(manifest),
+                UploadOptions::default(),
+            )
+            .await?;
+        client
+            .upload(
+                &format!("{}manifest.checksum", folder),
+                Bytes::from(checksum),
+                UploadOptions::default(),
+            )
+            .await?;
+        client
+            .upload(
+                &format!(
+                    "{}hive/dt={}/symlink.txt",
+                    base,
+                    now.format("%Y-%m-%d-%H-%M")
+                ),
+                Bytes::from(format!("s3://{}/{}\n", bucket, data_key)),
+                UploadOptions::default(),
+            )
+            .await?;
+
+        let run = InventoryRun {
+            bucket: bucket.to_string(),
+            id: rule.id.clone(),
+            objects: objects.len() as u64,
+            total_bytes,
+            manifest_key,
+            written_at: now,
+            duration_secs: started.elapsed().as_secs_f64(),
+        };
+        tracing::info!(
+            "Inventory {}/{}: wrote {} objects ({} bytes) to {} in {:.1}s",
+            bucket,
+            rule.id,
+            run.objects,
+            run.total_bytes,
+            run.manifest_key,
+            run.duration_secs
+        );
+        Ok(run)
+    }
+
+    /// Writes every report whose schedule is due, or every report when
+    /// `force`, recording the outcomes for the metrics. Failures are logged
+    /// and the other reports still run.
+    pub async fn run_due(
+        &self,
+        state: &AppState,
+        force: bool,
+        only: Option<&str>,
+    ) -> Vec<Result<InventoryRun>> {
+        let mut results = Vec::new();
+        for rule in self
+            .rules
+            .iter()
+            .filter(|r| only.is_none_or(|id| id == r.id))
+        {
+            let bucket = rule.bucket.as_deref().unwrap_or(&state.config.storage_zone);
+            let now = Utc::now();
+            if !force {
+                let client = match state.bucket_client(bucket) {
+                    Ok(client) => client,
+                    Err(e) => {
+                        tracing::warn!("Inventory {}/{}: {}", bucket, rule.id, e);
+                        continue;
+                    }
+                };
+                match self.last_run(&client, bucket, rule).await {
+                    Ok(Some(last)) if now - last < rule.schedule.period() => continue,
+                    Ok(_) => {}
+                    Err(e) => {
+                        tracing::warn!(
+                            "Inventory {}/{}: cannot read past runs: {}",
+                            bucket,
+                            rule.id,
+                            e
+                        );
+                        continue;
+                    }
+                }
+            }
+            self.stats.runs.fetch_add(1, Ordering::Relaxed);
+            let result = self.write_report(state, bucket, rule, now).await;
+            match &result {
+                Ok(run) => {
+                    self.stats
+                        .last
+                        .lock()
+                        .unwrap()
+                        .insert((bucket.to_string(), rule.id.clone()), run.clone());
+                }
+                Err(e) => {
+                    self.stats.failures.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!("Inventory {}/{} failed: {}", bucket, rule.id, e);
+                }
+            }
+            results.push(result);
+        }
+        results
+    }
+
+    /// Writes reports as their schedules come due until the process exits.
+    pub async fn run(state: AppState) {
+        let Some(inventory) = state.inventory.clone() else {
+            return;
+        };
+        let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            inventory.run_due(&state, false, None).await;
+        }
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        for (name, help, counter) in [
+            ("runs_total", "Inventory reports started.", &self.stats.runs),
+            (
+                "failures_total",
+                "Inventory reports that failed.",
+                &self.stats.failures,
+            ),
+        ] {
+            let name = format!("bunny_s3_proxy_inventory_{}", name);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        let last = self.stats.last.lock().unwrap();
+        for (i, (name, help)) in [
+            ("objects", "Objects in the latest report."),
+            ("bytes", "Bytes of the objects in the latest report."),
+            (
+                "duration_seconds",
+                "How long the latest report took to write.",
+            ),
+            (
+                "last_success_timestamp_seconds",
+                "When the latest report was written.",
+            ),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let name = format!("bunny_s3_proxy_inventory_{}", name);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for ((bucket, id), run) in last.iter() {
+                let value = [
+                    run.objects as f64,
+                    run.total_bytes as f64,
+                    run.duration_secs,
+                    run.written_at.timestamp() as f64,
+                ][i];
+                let _ = writeln!(
+                    out,
+                    "{}{{bucket=\"{}\",id=\"{}\"}} {}",
+                    name, bucket, id, value
+                );
+            }
+        }
+        out
+    }
+}
+
+/// Keys of the proxy's own bookkeeping, which reports leave out.
+fn is_internal(state: &AppState, key: &str) -> bool {
+    BucketConfigStore::is_internal_key(key)
+        || MultipartManager::is_internal_key(key)
+        || ObjectMetaStore::is_internal_key(key)
+        || VersionStore::is_internal_key(key)
+        || state.trash.as_ref().is_some_and(|t| t.contains(key))
+}
+
+/// `bunny-s3-proxy inventory`: lists the configured reports with their last
+/// run, or with `--now` writes them immediately.
+pub async fn command(config: &Config, args: &InventoryArgs) -> anyhow::Result<()> {
+    let state = AppState::new(config.clone())?;
+    let Some(inventory) = state.inventory.clone() else {
+        anyhow::bail!("no reports configured; set --inventory-config");
+    };
+    if let Some(id) = &args.id
+        && !inventory.rules.iter().any(|r| &r.id == id)
+    {
+        anyhow::bail!("no report with ID '{}'", id);
+    }
+
+    if args.now {
+        let results = inventory.run_due(&state, true, args.id.as_deref()).await;
+        let mut failed = 0;
+        for result in results {
+            match result {
+                Ok(run) => println!(
+                    "{}/{}: {} objects, {} bytes -> {}",
+                    run.bucket, run.id, run.objects, run.total_bytes, run.manifest_key
+                ),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 {
+            anyhow::bail!("{} reports failed", failed);
+        }
+        return Ok(());
+    }
+
+    for rule in inventory
+        .rules
+        .iter()
+        .filter(|r| args.id.as_ref().is_none_or(|id| id == &r.id))
+    {
+        let bucket = rule.bucket.as_deref().unwrap_or(&config.storage_zone);
+        let client = state.bucket_client(bucket)?;
+        let last = match inventory.last_run(&client, bucket, rule).await? {
+            Some(at) => at.to_rfc3339(),
+            None => "never".to_string(),
+        };
+        println!(
+            "{}/{}: prefix '{}', {:?}, to {}, last run {}",
+            bucket,
+            rule.id,
+            rule.prefix,
+            rule.schedule,
+            rule.base(bucket),
+            last
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = Inventory::parse(
+            r#"
+            [[inventory]]
+            destination = "inventory/"
+            schedule = "daily"
+
+            [[inventory]]
+            id = "logs"
+            bucket = "archive"
+            prefix = "logs/"
+            destination = "/reports/"
+            schedule = "weekly"
+            gzip = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(rules[0].id, "inventory");
+        assert_eq!(rules[0].base("zone"), "inventory/zone/inventory/");
+        assert_eq!(rules[1].base("archive"), "reports/archive/logs/");
+        assert_eq!(rules[1].schedule.period(), Duration::weeks(1));
+        assert!(rules[1].gzip);
+
+        for bad in [
+            "[[inventory]]\ndestination = \"a/\"\nschedule = \"hourly\"",
+            "[[inventory]]\nid = \"a/b\"\ndestination = \"a/\"\nschedule = \"daily\"",
+            "[[inventory]]\ndestination = \"a/\"\nschedule = \"daily\"\n[[inventory]]\ndestination = \"b/\"\nschedule = \"daily\"",
+        ] {
+            assert!(Inventory::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
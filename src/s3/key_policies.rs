@@ -0,0 +1,322 @@
+//! Per-credential policies from `--key-policies-config`, which narrow what a
+//! signed request may do by the access key that signed it:
+//!
+//! ```toml
+//! [[policy]]
+//! access-key-id = "reporting"
+//! methods = ["GET", "HEAD"]
+//!
+//! [[policy]]
+//! access-key-id = "backup"
+//! prefixes = ["backups/"]
+//! ```
+//!
+//! `methods` are the HTTP methods the key may send, with DeleteObjects
+//! counted as a DELETE of each key; `prefixes` the keys it may touch and
+//! `buckets` the buckets it may use. Each defaults to everything, and a key
+//! without a policy is unrestricted. A prefix-scoped key may list only
+//! under its prefixes and cannot read or change bucket settings. Policies
+//! are checked once the signature is; unsigned requests are left to
+//! `--access-config`. SIGHUP reloads the file; a file that no longer parses
+//! is logged and the previous policies stay in force.
+
+use axum::http::Method;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::error::{ProxyError, Result};
+
+const METHODS: &[Method] = &[
+    Method::GET,
+    Method::HEAD,
+    Method::PUT,
+    Method::POST,
+    Method::DELETE,
+];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyPoliciesFile {
+    #[serde(default)]
+    policy: Vec<KeyPolicyEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct KeyPolicyEntry {
+    access_key_id: String,
+    #[serde(default)]
+    methods: Option<Vec<String>>,
+    #[serde(default)]
+    prefixes: Option<Vec<String>>,
+    #[serde(default)]
+    buckets: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+struct KeyPolicy {
+    methods: Option<Vec<Method>>,
+    prefixes: Option<Vec<String>>,
+    buckets: Option<Vec<String>>,
+}
+
+/// What a request touches, as far as policies are concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access<'a> {
+    /// ListBuckets.
+    Service,
+    /// HeadBucket and GetBucketLocation, which reveal nothing of the keys.
+    Bucket(&'a str),
+    /// A listing of the keys of a bucket under a prefix.
+    Listing { bucket: &'a str, prefix: &'a str },
+    /// One object.
+    Object { bucket: &'a str, key: &'a str },
+    /// The bucket's settings, such as its lifecycle or tagging.
+    Settings(&'a str),
+}
+
+impl KeyPolicy {
+    fn allows(&self, method: &Method, access: Access) -> bool {
+        if self.methods.as_ref().is_some_and(|m| !m.contains(method)) {
+            return false;
+        }
+        let bucket = match access {
+            Access::Service => return true,
+            Access::Bucket(bucket) | Access::Settings(bucket) => bucket,
+            Access::Listing { bucket, .. } | Access::Object { bucket, .. } => bucket,
+        };
+        if self
+            .buckets
+            .as_ref()
+            .is_some_and(|b| !b.iter().any(|b| b == bucket))
+        {
+            return false;
+        }
+        let Some(prefixes) = &self.prefixes else {
+            return true;
+        };
+        match access {
+            Access::Listing { prefix: key, .. } | Access::Object { key, .. } => {
+                prefixes.iter().any(|p| key.starts_with(p.as_str()))
+            }
+            Access::Settings(_) => false,
+            Access::Service | Access::Bucket(_) => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PolicySet {
+    policies: HashMap<String, KeyPolicy>,
+}
+
+impl PolicySet {
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let file: KeyPoliciesFile = toml::from_str(text)?;
+        let mut policies = HashMap::new();
+        for entry in file.policy {
+            let methods = entry
+                .methods
+                .map(|methods| {
+                    methods
+                        .iter()
+                        .map(|m| {
+                            METHODS
+                                .iter()
+                                .find(|known| known.as_str().eq_ignore_ascii_case(m))
+                                .cloned()
+                                .ok_or_else(|| {
+                                    anyhow::anyhow!(
+                                        "policy for {}: unknown method '{}'",
+                                        entry.access_key_id,
+                                        m
+                                    )
+                                })
+                        })
+                        .collect::<anyhow::Result<Vec<_>>>()
+                })
+                .transpose()?;
+            let policy = KeyPolicy {
+                methods,
+                prefixes: entry.prefixes,
+                buckets: entry.buckets,
+            };
+            if policies
+                .insert(entry.access_key_id.clone(), policy)
+                .is_some()
+            {
+                anyhow::bail!("access key {} has two policies", entry.access_key_id);
+            }
+        }
+        Ok(Self { policies })
+    }
+
+    fn check(&self, access_key_id: &str, method: &Method, access: Access) -> Result<()> {
+        match self.policies.get(access_key_id) {
+            Some(policy) if !policy.allows(method, access) => {
+                Err(ProxyError::KeyPolicyDenied(access_key_id.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct KeyPolicies {
+    path: PathBuf,
+    current: RwLock<Arc<PolicySet>>,
+}
+
+impl KeyPolicies {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let policies = Self::read(path)?;
+        tracing::info!(
+            "Key policies: {} from {}",
+            policies.policies.len(),
+            path.display()
+        );
+        Ok(Self {
+            path: path.to_path_buf(),
+            current: RwLock::new(Arc::new(policies)),
+        })
+    }
+
+    fn read(path: &Path) -> anyhow::Result<PolicySet> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("--key-policies-config {}: {}", path.display(), e))?;
+        PolicySet::parse(&text)
+            .map_err(|e| anyhow::anyhow!("--key-policies-config {}: {}", path.display(), e))
+    }
+
+    /// Re-reads the file, keeping the current policies if it fails to parse.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let policies = Self::read(&self.path)?;
+        let count = policies.policies.len();
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(policies);
+        tracing::info!(
+            "Key policies reloaded: {} from {}",
+            count,
+            self.path.display()
+        );
+        Ok(())
+    }
+
+    /// Refuses `access` with `method` if the policy of `access_key_id`
+    /// does not allow it.
+    pub fn check(&self, access_key_id: &str, method: &Method, access: Access) -> Result<()> {
+        let policies = Arc::clone(&self.current.read().unwrap_or_else(|e| e.into_inner()));
+        policies.check(access_key_id, method, access)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICIES: &str = r#"
+        [[policy]]
+        access-key-id = "reporting"
+        methods = ["get", "HEAD"]
+
+        [[policy]]
+        access-key-id = "backup"
+        prefixes = ["backups/", "db/"]
+        buckets = ["zone"]
+    "#;
+
+    #[test]
+    fn test_policies_limit_methods_prefixes_and_buckets() {
+        let policies = PolicySet::parse(POLICIES).unwrap();
+        let allowed =
+            |key: &str, method: Method, access| policies.check(key, &method, access).is_ok();
+        let object = |key| Access::Object {
+            bucket: "zone",
+            key,
+        };
+
+        assert!(allowed("reporting", Method::GET, object("any/thing")));
+        assert!(allowed("reporting", Method::HEAD, Access::Settings("zone")));
+        assert!(!allowed("reporting", Method::PUT, object("any/thing")));
+        assert!(!allowed("reporting", Method::DELETE, object("any/thing")));
+
+        assert!(allowed("backup", Method::PUT, object("backups/2026.tar")));
+        assert!(allowed("backup", Method::DELETE, object("db/dump.sql")));
+        assert!(!allowed("backup", Method::PUT, object("backups2/x")));
+        assert!(!allowed("backup", Method::GET, object("reports/q3.pdf")));
+        assert!(!allowed(
+            "backup",
+            Method::PUT,
+            Access::Object {
+                bucket: "staging",
+                key: "backups/x"
+            }
+        ));
+        let listing = |prefix| Access::Listing {
+            bucket: "zone",
+            prefix,
+        };
+        assert!(allowed("backup", Method::GET, listing("backups/2026/")));
+        assert!(!allowed("backup", Method::GET, listing("")));
+        assert!(!allowed("backup", Method::GET, Access::Settings("zone")));
+        assert!(allowed("backup", Method::HEAD, Access::Bucket("zone")));
+        assert!(!allowed("backup", Method::HEAD, Access::Bucket("staging")));
+        assert!(allowed("backup", Method::GET, Access::Service));
+
+        // Keys without a policy are unrestricted.
+        assert!(allowed("admin", Method::DELETE, Access::Settings("zone")));
+        assert!(matches!(
+            policies.check("reporting", &Method::PUT, object("a")),
+            Err(ProxyError::KeyPolicyDenied(key)) if key == "reporting"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_policies_are_rejected() {
+        for (text, error) in [
+            (
+                "[[policy]]\naccess-key-id = \"a\"\nmethods = [\"PATCH\"]",
+                "unknown method 'PATCH'",
+            ),
+            (
+                "[[policy]]\naccess-key-id = \"a\"\n[[policy]]\naccess-key-id = \"a\"",
+                "two policies",
+            ),
+            (
+                "[[policy]]\naccess-key-id = \"a\"\nprefix = \"x/\"",
+                "unknown field",
+            ),
+        ] {
+            let err = PolicySet::parse(text).unwrap_err().to_string();
+            assert!(err.contains(error), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_reload_keeps_policies_on_error() {
+        let path = std::env::temp_dir().join(format!("key-policies-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, POLICIES).unwrap();
+        let policies = KeyPolicies::load(&path).unwrap();
+        let put = |key| {
+            policies.check(
+                "reporting",
+                &Method::PUT,
+                Access::Object {
+                    bucket: "zone",
+                    key,
+                },
+            )
+        };
+        assert!(put("a").is_err());
+
+        std::fs::write(&path, "[[policy]\n").unwrap();
+        assert!(policies.reload().is_err());
+        assert!(put("a").is_err());
+
+        std::fs::write(&path, "").unwrap();
+        policies.reload().unwrap();
+        assert!(put("a").is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+}
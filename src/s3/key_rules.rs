@@ -0,0 +1,305 @@
+//! Key allow/deny rules from `--key-rules-config`, which keep some kinds of
+//! object out of the zone whichever credential writes them:
+//!
+//! ```toml
+//! default = "allow"
+//! filter-reads = false
+//!
+//! [[rule]]
+//! name = "no-executables"
+//! action = "deny"
+//! glob = "*.exe"
+//!
+//! [[rule]]
+//! action = "deny"
+//! regex = "^secrets/"
+//! ```
+//!
+//! Rules are checked in file order and the first whose pattern matches
+//! decides; keys no rule matches get `default`. A glob's `*` and `?` stop at
+//! `/` while `**` crosses it, and a glob without `/` is matched against the
+//! last segment of the key, so `*.exe` covers every folder. A regex is
+//! matched anywhere in the key unless anchored. Rules are checked on PUT,
+//! the destination of a copy, multipart initiation and POST uploads, and with
+//! `filter-reads` also on GET and HEAD. SIGHUP reloads the file; a file that
+//! no longer parses is logged and the previous rules stay in force.
+
+use regex_lite::Regex;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::error::{ProxyError, Result};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct KeyRulesFile {
+    #[serde(default)]
+    default: Action,
+    #[serde(default)]
+    filter_reads: bool,
+    #[serde(default)]
+    rule: Vec<KeyRuleEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyRuleEntry {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    bucket: Option<String>,
+    action: Action,
+    #[serde(default)]
+    glob: Option<String>,
+    #[serde(default)]
+    regex: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Debug)]
+struct KeyRule {
+    name: String,
+    bucket: Option<String>,
+    action: Action,
+    pattern: Regex,
+}
+
+#[derive(Debug)]
+struct RuleSet {
+    rules: Vec<KeyRule>,
+    default: Action,
+    filter_reads: bool,
+}
+
+impl RuleSet {
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let file: KeyRulesFile = toml::from_str(text)?;
+        let mut rules = Vec::with_capacity(file.rule.len());
+        for (i, entry) in file.rule.into_iter().enumerate() {
+            let (source, pattern) = match (entry.glob, entry.regex) {
+                (Some(glob), None) => (glob.clone(), glob_to_regex(&glob)),
+                (None, Some(regex)) => (regex.clone(), regex),
+                _ => anyhow::bail!("rule {} needs exactly one of glob or regex", i + 1),
+            };
+            let pattern = Regex::new(&pattern)
+                .map_err(|e| anyhow::anyhow!("rule {} ('{}'): {}", i + 1, source, e))?;
+            rules.push(KeyRule {
+                name: entry.name.unwrap_or(source),
+                bucket: entry.bucket,
+                action: entry.action,
+                pattern,
+            });
+        }
+        Ok(Self {
+            rules,
+            default: file.default,
+            filter_reads: file.filter_reads,
+        })
+    }
+
+    /// The first rule matching `key` in `bucket`, if any.
+    fn rule_for(&self, bucket: &str, key: &str) -> Option<&KeyRule> {
+        self.rules.iter().find(|rule| {
+            rule.bucket.as_deref().is_none_or(|b| b == bucket) && rule.pattern.is_match(key)
+        })
+    }
+
+    fn check(&self, bucket: &str, key: &str) -> Result<()> {
+        match self.rule_for(bucket, key) {
+            Some(rule) if rule.action == Action::Deny => {
+                Err(ProxyError::KeyRuleDenied(rule.name.clone()))
+            }
+            Some(_) => Ok(()),
+            None if self.default == Action::Deny => {
+                Err(ProxyError::KeyRuleDenied("default".to_string()))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Translates a glob into an anchored regex: `**` matches anything, `*` and
+/// `?` anything but `/`. Without a `/` only the key's last segment counts.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from(if glob.contains('/') { "^" } else { "(?:^|/)" });
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex_lite::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[derive(Debug)]
+pub struct KeyRules {
+    path: PathBuf,
+    current: RwLock<Arc<RuleSet>>,
+}
+
+impl KeyRules {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let rules = Self::read(path)?;
+        tracing::info!(
+            "Key rules: {} from {}, default {:?}",
+            rules.rules.len(),
+            path.display(),
+            rules.default
+        );
+        Ok(Self {
+            path: path.to_path_buf(),
+            current: RwLock::new(Arc::new(rules)),
+        })
+    }
+
+    fn read(path: &Path) -> anyhow::Result<RuleSet> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("--key-rules-config {}: {}", path.display(), e))?;
+        RuleSet::parse(&text)
+            .map_err(|e| anyhow::anyhow!("--key-rules-config {}: {}", path.display(), e))
+    }
+
+    fn rules(&self) -> Arc<RuleSet> {
+        Arc::clone(&self.current.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Re-reads the file, keeping the current rules if it fails to parse.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let rules = Self::read(&self.path)?;
+        let count = rules.rules.len();
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(rules);
+        tracing::info!("Key rules reloaded: {} from {}", count, self.path.display());
+        Ok(())
+    }
+
+    /// Refuses writing `key` to `bucket` if the rules deny it.
+    pub fn check_write(&self, bucket: &str, key: &str) -> Result<()> {
+        self.rules().check(bucket, key)
+    }
+
+    /// Refuses reading `key` from `bucket` if the rules deny it and
+    /// `filter-reads` is set.
+    pub fn check_read(&self, bucket: &str, key: &str) -> Result<()> {
+        let rules = self.rules();
+        match rules.filter_reads {
+            true => rules.check(bucket, key),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"
+        [[rule]]
+        name = "shared-secrets"
+        action = "allow"
+        glob = "secrets/shared/**"
+
+        [[rule]]
+        action = "deny"
+        regex = "^secrets/"
+
+        [[rule]]
+        name = "no-executables"
+        action = "deny"
+        glob = "*.exe"
+
+        [[rule]]
+        bucket = "installers"
+        action = "allow"
+        glob = "**"
+    "#;
+
+    #[test]
+    fn test_first_matching_rule_decides() {
+        let rules = RuleSet::parse(RULES).unwrap();
+        let denied_by = |bucket: &str, key: &str| match rules.check(bucket, key) {
+            Err(ProxyError::KeyRuleDenied(name)) => Some(name),
+            _ => None,
+        };
+        assert_eq!(
+            denied_by("zone", "secrets/key.pem").as_deref(),
+            Some("^secrets/")
+        );
+        assert_eq!(denied_by("zone", "secrets/shared/key.pem"), None);
+        assert_eq!(
+            denied_by("zone", "downloads/setup.exe").as_deref(),
+            Some("no-executables")
+        );
+        assert_eq!(
+            denied_by("zone", "setup.exe").as_deref(),
+            Some("no-executables")
+        );
+        assert_eq!(denied_by("zone", "setup.exe.txt"), None);
+        assert_eq!(denied_by("zone", "docs/readme.md"), None);
+        // A later bucket rule cannot undo an earlier deny.
+        assert!(denied_by("installers", "setup.exe").is_some());
+
+        let allowlist = RuleSet::parse(
+            "default = \"deny\"\n[[rule]]\naction = \"allow\"\nglob = \"images/*.jpg\"\n",
+        )
+        .unwrap();
+        assert!(allowlist.check("zone", "images/cat.jpg").is_ok());
+        assert!(allowlist.check("zone", "images/2024/cat.jpg").is_err());
+    }
+
+    #[test]
+    fn test_invalid_rules_are_rejected() {
+        for (text, expected) in [
+            ("[[rule]]\naction = \"deny\"\n", "exactly one of"),
+            (
+                "[[rule]]\naction = \"deny\"\nglob = \"*\"\nregex = \".*\"\n",
+                "exactly one of",
+            ),
+            ("[[rule]]\naction = \"deny\"\nregex = \"(\"\n", "rule 1"),
+            (
+                "[[rule]]\naction = \"block\"\nglob = \"*\"\n",
+                "unknown variant",
+            ),
+        ] {
+            let err = RuleSet::parse(text).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: {}", text, err);
+        }
+    }
+
+    #[test]
+    fn test_reload_keeps_rules_on_error() {
+        let path = std::env::temp_dir().join(format!("key-rules-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "[[rule]]\naction = \"deny\"\nglob = \"*.exe\"\n").unwrap();
+        let rules = KeyRules::load(&path).unwrap();
+        assert!(rules.check_write("zone", "a.exe").is_err());
+        assert!(rules.check_read("zone", "a.exe").is_ok());
+
+        std::fs::write(
+            &path,
+            "filter-reads = true\n[[rule]]\naction = \"deny\"\nglob = \"*.bat\"\n",
+        )
+        .unwrap();
+        rules.reload().unwrap();
+        assert!(rules.check_write("zone", "a.exe").is_ok());
+        assert!(rules.check_read("zone", "a.bat").is_err());
+
+        std::fs::write(&path, "[[rule]]\naction = \"deny\"\n").unwrap();
+        assert!(rules.reload().is_err());
+        assert!(rules.check_write("zone", "a.bat").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,443 @@
+use chrono::{Duration, Utc};
+
+use crate::bunny::{Backend, StorageBackend};
+use crate::error::Result;
+
+use super::bucket_config::{BucketConfigStore, LIFECYCLE_CONFIG};
+use super::handlers::{AppState, is_valid_bucket_name};
+use super::multipart::MultipartManager;
+use super::object_meta::ObjectMetaStore;
+use super::types::LifecycleConfiguration;
+use super::versions::VersionStore;
+use super::xml;
+
+pub struct LifecycleManager;
+
+impl LifecycleManager {
+    pub async fn load(client: &Backend, bucket: &str) -> Result<Option<LifecycleConfiguration>> {
+        match BucketConfigStore::get(client, bucket, LIFECYCLE_CONFIG).await? {
+            Some(body) => Ok(Some(xml::parse_request_body(&body)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Periodically applies the bucket's lifecycle rules until the process exits.
+    pub async fn run(state: AppState, bucket: String, interval: std::time::Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = Self::apply(&state, &bucket).await {
+                tracing::warn!("Lifecycle scan for {} failed: {}", bucket, e);
+            }
+        }
+    }
+
+    /// Like [`Self::run`] for `--bucket-as-prefix`, applying the rules of
+    /// every bucket folder in the zone on each pass.
+    pub async fn run_per_bucket(state: AppState, interval: std::time::Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let folders = match state.bucket_root.list("").await {
+                Ok(folders) => folders,
+                Err(e) => {
+                    tracing::warn!("Lifecycle scan could not list buckets: {}", e);
+                    continue;
+                }
+            };
+            for folder in folders.iter().filter(|o| o.is_directory) {
+                let bucket = &folder.object_name;
+                if !is_valid_bucket_name(bucket) {
+                    continue;
+                }
+                if let Err(e) = Self::apply(&state, bucket).await {
+                    tracing::warn!("Lifecycle scan for {} failed: {}", bucket, e);
+                }
+            }
+        }
+    }
+
+    /// Applies the lifecycle rules of `bucket` once. Expired objects under
+    /// `--emulate-versioning` prefixes become noncurrent versions behind a
+    /// delete marker, as a DeleteObject would leave them; others go through
+    /// the trash when `--trash-prefix` is set. Objects a `--retention-config`
+    /// rule still protects are left alone.
+    pub async fn apply(state: &AppState, bucket: &str) -> Result<()> {
+        let client = &state.bucket_client(bucket)?;
+        let Some(config) = Self::load(client, bucket).await? else {
+            return Ok(());
+        };
+
+        for rule in config.rule.iter().filter(|r| r.is_enabled()) {
+            let prefix = rule.key_prefix();
+
+            if let Some(days) = rule.expiration_days() {
+                let cutoff = Utc::now() - Duration::days(days as i64);
+                let dir = prefix.rfind('/').map(|i| &prefix[..i]).unwrap_or("");
+                for obj in client.list_recursive(dir, None).await? {
+                    let key = obj.s3_key();
+                    if !key.starts_with(prefix)
+                        || BucketConfigStore::is_internal_key(&key)
+                        || MultipartManager::is_internal_key(&key)
+                        || ObjectMetaStore::is_internal_key(&key)
+                        || VersionStore::is_internal_key(&key)
+                        || state
+                            .trash
+                            .as_ref()
+                            .is_some_and(|trash| trash.contains(&key))
+                        || obj.last_changed >= cutoff
+                    {
+                        continue;
+                    }
+                    if let Some(retention) = state
+                        .retention
+                        .as_ref()
+                        .and_then(|rules| rules.rule_for(bucket, &key))
+                        .filter(|retention| Utc::now() < retention.expires(obj.date_created))
+                    {
+                        tracing::debug!(
+                            "Lifecycle rule {}: {} is protected by retention rule {}",
+                            rule.id.as_deref().unwrap_or("<unnamed>"),
+                            key,
+                            retention
+                        );
+                        continue;
+                    }
+                    match Self::expire(state, client, &key).await {
+                        Ok(()) => {
+                            tracing::info!(
+                                "Lifecycle rule {}: expired {} (last modified {})",
+                                rule.id.as_deref().unwrap_or("<unnamed>"),
+                                key,
+                                obj.last_changed
+                            )
+                        }
+                        Err(e) => tracing::warn!("Lifecycle: failed to expire {}: {}", key, e),
+                    }
+                }
+            }
+
+            // Versions kept by --emulate-versioning; delete markers stay.
+            if let Some(days) = rule.noncurrent_days() {
+                let cutoff = Utc::now() - Duration::days(days as i64);
+                for entry in VersionStore::list(client, prefix).await? {
+                    if entry.delete_marker || entry.noncurrent_since >= cutoff {
+                        continue;
+                    }
+                    match VersionStore::delete_entry(client, &entry).await {
+                        Ok(()) => tracing::info!(
+                            "Lifecycle rule {}: expired version {} of {} (noncurrent since {})",
+                            rule.id.as_deref().unwrap_or("<unnamed>"),
+                            entry.version_id,
+                            entry.key,
+                            entry.noncurrent_since
+                        ),
+                        Err(e) => tracing::warn!(
+                            "Lifecycle: failed to expire version {} of {}: {}",
+                            entry.version_id,
+                            entry.key,
+                            e
+                        ),
+                    }
+                }
+            }
+
+            if let Some(days) = rule.abort_days() {
+                let cutoff = Utc::now() - Duration::days(days as i64);
+                for (key, upload_id, initiated) in
+                    MultipartManager::list_uploads(client, bucket).await?
+                {
+                    if !key.starts_with(prefix) || initiated >= cutoff {
+                        continue;
+                    }
+                    match MultipartManager::abort(client, &upload_id).await {
+                        Ok(()) => tracing::info!(
+                            "Lifecycle rule {}: aborted upload {} for {} (initiated {})",
+                            rule.id.as_deref().unwrap_or("<unnamed>"),
+                            upload_id,
+                            key,
+                            initiated
+                        ),
+                        Err(e) => {
+                            tracing::warn!("Lifecycle: failed to abort {}: {}", upload_id, e)
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes `key`, leaving a delete marker if it is versioned and a copy
+    /// in the trash if that is enabled.
+    async fn expire(state: &AppState, client: &Backend, key: &str) -> Result<()> {
+        if state.is_versioned(key) {
+            VersionStore::delete(client, key, None).await?;
+            return Ok(());
+        }
+        if let Some(trash) = &state.trash {
+            trash.move_in(client, key, Utc::now()).await?;
+        }
+        client.delete(key).await?;
+        let _ = ObjectMetaStore::delete(client, key).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bunny::UploadOptions;
+    use crate::config::Config;
+    use bytes::Bytes;
+    use clap::Parser;
+
+    async fn mock_state(extra: &[&str]) -> AppState {
+        let url = crate::mock_bunny::spawn("test-key").await;
+        let args = [
+            "bunny-s3-proxy",
+            "--storage-zone",
+            "test-zone",
+            "--access-key",
+            "test-key",
+            "--bunny-endpoint",
+            &url,
+        ];
+        AppState::new(Config::parse_from(args.iter().chain(extra))).unwrap()
+    }
+
+    /// Stores `rules` as the lifecycle configuration of `test-zone`.
+    async fn configure(state: &AppState, rules: &str) {
+        let body = format!("<LifecycleConfiguration>{}</LifecycleConfiguration>", rules);
+        BucketConfigStore::put(&state.bunny, "test-zone", LIFECYCLE_CONFIG, body.into())
+            .await
+            .unwrap();
+    }
+
+    async fn put(state: &AppState, key: &str) {
+        state
+            .bunny
+            .upload(key, Bytes::from(key.to_string()), UploadOptions::default())
+            .await
+            .unwrap();
+    }
+
+    async fn exists(state: &AppState, key: &str) -> bool {
+        state.bunny.describe(key).await.is_ok_and(|o| o.length >= 0)
+    }
+
+    #[tokio::test]
+    async fn test_expiry_leaves_delete_markers_on_versioned_keys() {
+        let state = mock_state(&["--emulate-versioning", "docs/"]).await;
+        put(&state, "docs/a.txt").await;
+        put(&state, "logs/a.txt").await;
+        configure(
+            &state,
+            "<Rule><Status>Enabled</Status><Expiration><Days>0</Days></Expiration></Rule>",
+        )
+        .await;
+
+        LifecycleManager::apply(&state, "test-zone").await.unwrap();
+        assert!(!exists(&state, "docs/a.txt").await);
+        assert!(!exists(&state, "logs/a.txt").await);
+        let history = VersionStore::history(&state.bunny, "docs/a.txt")
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(history[0].delete_marker && !history[1].delete_marker);
+        assert!(
+            VersionStore::history(&state.bunny, "logs/a.txt")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expiry_spares_retained_objects() {
+        let path = std::env::temp_dir().join(format!("retention-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "[[retention]]\nprefix = \"audit/\"\ndays = 30\n").unwrap();
+        let state = mock_state(&["--retention-config", path.to_str().unwrap()]).await;
+        std::fs::remove_file(&path).unwrap();
+        put(&state, "audit/2024.log").await;
+        put(&state, "tmp/scratch.txt").await;
+        configure(
+            &state,
+            "<Rule><Status>Enabled</Status><Expiration><Days>0</Days></Expiration></Rule>",
+        )
+        .await;
+
+        LifecycleManager::apply(&state, "test-zone").await.unwrap();
+        assert!(exists(&state, "audit/2024.log").await);
+        assert!(!exists(&state, "tmp/scratch.txt").await);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_goes_through_the_trash() {
+        let state = mock_state(&["--trash-prefix", "__trash"]).await;
+        put(&state, "docs/a.txt").await;
+        configure(
+            &state,
+            "<Rule><Status>Enabled</Status><Expiration><Days>0</Days></Expiration></Rule>",
+        )
+        .await;
+
+        LifecycleManager::apply(&state, "test-zone").await.unwrap();
+        assert!(!exists(&state, "docs/a.txt").await);
+        let trash = state.trash.as_ref().unwrap();
+        let entries = trash.list(&state.bunny).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, "docs/a.txt");
+
+        // The trash itself is left to its own purge.
+        LifecycleManager::apply(&state, "test-zone").await.unwrap();
+        assert_eq!(trash.list(&state.bunny).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rules_match_by_prefix_and_filter() {
+        let state = mock_state(&[]).await;
+        for key in ["docs/a.txt", "logs/a.txt", "logs/sub/b.txt", "other.txt"] {
+            put(&state, key).await;
+        }
+        configure(
+            &state,
+            "<Rule><Status>Enabled</Status><Prefix>docs/</Prefix>\
+             <Expiration><Days>0</Days></Expiration></Rule>\
+             <Rule><Status>Enabled</Status><Filter><Prefix>logs/sub/</Prefix></Filter>\
+             <Expiration><Days>0</Days></Expiration></Rule>\
+             <Rule><Status>Disabled</Status><Filter><Prefix>other</Prefix></Filter>\
+             <Expiration><Days>0</Days></Expiration></Rule>",
+        )
+        .await;
+
+        LifecycleManager::apply(&state, "test-zone").await.unwrap();
+        assert!(!exists(&state, "docs/a.txt").await);
+        assert!(!exists(&state, "logs/sub/b.txt").await);
+        assert!(exists(&state, "logs/a.txt").await);
+        assert!(exists(&state, "other.txt").await);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_waits_for_the_day_threshold() {
+        let state = mock_state(&[]).await;
+        put(&state, "docs/a.txt").await;
+        configure(
+            &state,
+            "<Rule><Status>Enabled</Status><Expiration><Days>1</Days></Expiration></Rule>",
+        )
+        .await;
+        LifecycleManager::apply(&state, "test-zone").await.unwrap();
+        assert!(exists(&state, "docs/a.txt").await);
+
+        configure(
+            &state,
+            "<Rule><Status>Enabled</Status><Expiration><Days>0</Days></Expiration></Rule>",
+        )
+        .await;
+        LifecycleManager::apply(&state, "test-zone").await.unwrap();
+        assert!(!exists(&state, "docs/a.txt").await);
+    }
+
+    #[tokio::test]
+    async fn test_stale_uploads_are_aborted() {
+        let state = mock_state(&[]).await;
+        let headers = Default::default();
+        for key in ["docs/big.bin", "logs/big.bin"] {
+            MultipartManager::create(&state.bunny, "test-zone", key, None, None, &headers)
+                .await
+                .unwrap();
+        }
+        let pending = || async {
+            let mut keys: Vec<String> = MultipartManager::list_uploads(&state.bunny, "test-zone")
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(key, _, _)| key)
+                .collect();
+            keys.sort();
+            keys
+        };
+        let rules = |days: u32| {
+            format!(
+                "<Rule><Status>Enabled</Status><Filter><Prefix>docs/</Prefix></Filter>\
+                 <AbortIncompleteMultipartUpload><DaysAfterInitiation>{}</DaysAfterInitiation>\
+                 </AbortIncompleteMultipartUpload></Rule>",
+                days
+            )
+        };
+
+        configure(&state, &rules(1)).await;
+        LifecycleManager::apply(&state, "test-zone").await.unwrap();
+        assert_eq!(pending().await, ["docs/big.bin", "logs/big.bin"]);
+
+        configure(&state, &rules(0)).await;
+        LifecycleManager::apply(&state, "test-zone").await.unwrap();
+        assert_eq!(pending().await, ["logs/big.bin"]);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_skips_internal_prefixes() {
+        let state = mock_state(&["--emulate-versioning", "docs/"]).await;
+        put(&state, "docs/a.txt").await;
+        VersionStore::archive_current(&state.bunny, "docs/a.txt")
+            .await
+            .unwrap();
+        MultipartManager::create(
+            &state.bunny,
+            "test-zone",
+            "big.bin",
+            None,
+            None,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        configure(
+            &state,
+            "<Rule><Status>Enabled</Status><Expiration><Days>0</Days></Expiration></Rule>",
+        )
+        .await;
+
+        LifecycleManager::apply(&state, "test-zone").await.unwrap();
+        assert!(!exists(&state, "docs/a.txt").await);
+        // The configuration, the pending upload and the stored versions are
+        // the proxy's own, not objects to expire.
+        assert!(
+            LifecycleManager::load(&state.bunny, "test-zone")
+                .await
+                .unwrap()
+                .is_some()
+        );
+        let uploads = MultipartManager::list_uploads(&state.bunny, "test-zone")
+            .await
+            .unwrap();
+        assert_eq!(uploads.len(), 1);
+        let history = VersionStore::history(&state.bunny, "docs/a.txt")
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_extra_zones_apply_their_own_rules() {
+        let state = mock_state(&["--extra-zone", "staging:test-key"]).await;
+        let staging = state.bucket_client("staging").unwrap();
+        put(&state, "docs/a.txt").await;
+        staging
+            .upload("docs/a.txt", Bytes::from("a"), UploadOptions::default())
+            .await
+            .unwrap();
+        let rules = "<LifecycleConfiguration><Rule><Status>Enabled</Status>\
+                     <Expiration><Days>0</Days></Expiration></Rule></LifecycleConfiguration>";
+        BucketConfigStore::put(&staging, "staging", LIFECYCLE_CONFIG, rules.into())
+            .await
+            .unwrap();
+
+        LifecycleManager::apply(&state, "test-zone").await.unwrap();
+        LifecycleManager::apply(&state, "staging").await.unwrap();
+        assert!(exists(&state, "docs/a.txt").await);
+        assert!(staging.describe("docs/a.txt").await.is_err());
+    }
+}
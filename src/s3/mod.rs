@@ -1,7 +1,43 @@
+pub mod access;
+pub mod activity;
+pub mod admin;
+pub mod audit;
 pub mod auth;
+pub mod bucket_config;
+pub mod buffers;
+pub mod cache_policy;
+pub mod checksums;
+pub mod chunked;
+pub mod completions;
+pub mod compression;
+pub mod content_type;
+pub mod dedupe;
+pub mod encryption;
+pub mod events;
 pub mod handlers;
+pub mod html_listing;
+pub mod integrity;
+pub mod inventory;
+pub mod key_policies;
+pub mod key_rules;
+pub mod lifecycle;
 pub mod multipart;
+pub mod object_meta;
+pub mod panics;
+pub mod post_policy;
+pub mod quota;
+pub mod redirect;
+pub mod replication;
+pub mod response_compression;
+pub mod response_headers;
+pub mod retention;
+pub mod select;
+pub mod sse;
+pub mod subresource;
+pub mod trash;
 pub mod types;
+pub mod usage;
+pub mod versions;
 pub mod xml;
 
 pub use handlers::{AppState, handle_s3_request};
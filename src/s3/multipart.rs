@@ -1,19 +1,25 @@
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
-use futures::Stream;
+use futures::{Stream, StreamExt};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
-use crate::bunny::client::BunnyClient;
+use crate::bunny::{Backend, StorageBackend};
 use crate::error::{ProxyError, Result};
 
+use super::encryption::{self, Keyring, ReadPlan};
+
 enum PartState {
     NeedVerify,
     Verifying(
         Pin<
             Box<
                 dyn std::future::Future<
-                        Output = crate::error::Result<crate::bunny::client::DownloadResponse>,
+                        Output = crate::error::Result<crate::bunny::DownloadResponse>,
                     > + Send,
             >,
         >,
@@ -22,25 +28,35 @@ enum PartState {
         Pin<
             Box<
                 dyn std::future::Future<
-                        Output = crate::error::Result<crate::bunny::client::DownloadResponse>,
+                        Output = crate::error::Result<crate::bunny::DownloadResponse>,
                     > + Send,
             >,
         >,
     ),
-    Streaming(Pin<Box<dyn Stream<Item = std::result::Result<Bytes, reqwest::Error>> + Send>>),
+    Streaming(Pin<Box<dyn Stream<Item = std::result::Result<Bytes, std::io::Error>> + Send>>),
 }
 
 struct PartConcatStream {
-    client: BunnyClient,
+    client: Backend,
     upload_id: String,
     parts: std::vec::IntoIter<(i32, String)>,
     current_part: Option<(i32, String)>,
     state: PartState,
     verified_etags: Vec<String>,
+    /// Decrypts parts staged encrypted, given their plaintext sizes.
+    decrypt: Option<(Arc<Keyring>, HashMap<i32, u64>)>,
+    /// Set when a part fails its ETag check, which no retry can fix.
+    invalid_part: Arc<std::sync::Mutex<Option<String>>>,
 }
 
 impl PartConcatStream {
-    fn new(client: BunnyClient, upload_id: String, parts: Vec<(i32, String)>) -> Self {
+    fn new(
+        client: Backend,
+        upload_id: String,
+        parts: Vec<(i32, String)>,
+        decrypt: Option<(Arc<Keyring>, HashMap<i32, u64>)>,
+        invalid_part: Arc<std::sync::Mutex<Option<String>>>,
+    ) -> Self {
         Self {
             client,
             upload_id,
@@ -48,6 +64,8 @@ impl PartConcatStream {
             current_part: None,
             state: PartState::NeedVerify,
             verified_etags: Vec::new(),
+            decrypt,
+            invalid_part,
         }
     }
 }
@@ -109,14 +127,25 @@ impl Stream for PartConcatStream {
 
                 PartState::Downloading(fut) => match fut.as_mut().poll(cx) {
                     Poll::Ready(Ok(download)) => {
+                        let part_number = self.current_part.as_ref().map(|(n, _)| *n);
                         if let Some((_, expected_etag)) = self.current_part.take() {
                             self.verified_etags
                                 .push(expected_etag.trim_matches('"').to_string());
                         }
-                        self.state = PartState::Streaming(Box::pin(download.bytes_stream()));
+                        let stream = download.bytes_stream();
+                        self.state = PartState::Streaming(match (&self.decrypt, part_number) {
+                            (Some((keyring, sizes)), Some(n)) => {
+                                let plan = ReadPlan::full(sizes.get(&n).copied().unwrap_or(0));
+                                Box::pin(keyring.decrypt(stream, None, plan))
+                            }
+                            _ => Box::pin(stream),
+                        });
                         continue;
                     }
                     Poll::Ready(Err(e)) => {
+                        if let ProxyError::InvalidPart(message) = &e {
+                            *self.invalid_part.lock().unwrap() = Some(message.clone());
+                        }
                         return Poll::Ready(Some(Err(std::io::Error::other(e.to_string()))));
                     }
                     Poll::Pending => return Poll::Pending,
@@ -126,9 +155,7 @@ impl Stream for PartConcatStream {
                     Poll::Ready(Some(Ok(chunk))) => {
                         return Poll::Ready(Some(Ok(chunk)));
                     }
-                    Poll::Ready(Some(Err(e))) => {
-                        return Poll::Ready(Some(Err(std::io::Error::other(e.to_string()))));
-                    }
+                    Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
                     Poll::Ready(None) => {
                         if let Some((part_num, _)) = &self.current_part {
                             tracing::debug!("PartConcatStream: finished part {}", part_num);
@@ -146,9 +173,48 @@ impl Stream for PartConcatStream {
 
 const MULTIPART_PREFIX: &str = "__multipart";
 
+/// Times the final object is assembled before CompleteMultipartUpload fails.
+const MAX_ASSEMBLY_ATTEMPTS: u32 = 3;
+
+/// Wait before the first restart of an assembly, doubled for each further one.
+const ASSEMBLY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Staging-area file listing every upload as `upload_id|initiated|key`, so
+/// ListMultipartUploads needs no per-upload `_meta` reads.
+const INDEX_NAME: &str = "_index";
+
+/// `_meta` reads in flight while indexing uploads the index does not know.
+const META_FETCH_CONCURRENCY: usize = 16;
+
+/// Serializes read-modify-write cycles of the index within this process.
+/// Other instances sharing the zone can still race it; the drift is repaired
+/// on the next listing.
+static INDEX_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+type UploadEntry = (String, String, DateTime<Utc>);
+
+/// Whether an assembly that failed may succeed when started again: upstream
+/// trouble while reading parts or writing the object, rather than a request
+/// Bunny refused.
+fn assembly_retryable(e: &ProxyError) -> bool {
+    e.is_retryable()
+        || matches!(
+            e,
+            ProxyError::UpstreamDecode(_) | ProxyError::HttpClient(_) | ProxyError::BunnyApi(_)
+        )
+}
+
 pub struct MultipartManager;
 
 impl MultipartManager {
+    /// Whether a key lives in the multipart staging area.
+    pub fn is_internal_key(key: &str) -> bool {
+        key == MULTIPART_PREFIX
+            || key
+                .strip_prefix(MULTIPART_PREFIX)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+
     fn part_path(upload_id: &str, part_number: i32) -> String {
         format!("{}/{}/{:05}", MULTIPART_PREFIX, upload_id, part_number)
     }
@@ -161,13 +227,52 @@ impl MultipartManager {
         format!("{}/{}/_meta", MULTIPART_PREFIX, upload_id)
     }
 
+    fn storage_class_path(upload_id: &str) -> String {
+        format!("{}/{}/_storage_class", MULTIPART_PREFIX, upload_id)
+    }
+
+    fn content_type_path(upload_id: &str) -> String {
+        format!("{}/{}/_content_type", MULTIPART_PREFIX, upload_id)
+    }
+
+    fn headers_path(upload_id: &str) -> String {
+        format!("{}/{}/_headers", MULTIPART_PREFIX, upload_id)
+    }
+
     fn upload_dir(upload_id: &str) -> String {
         format!("{}/{}", MULTIPART_PREFIX, upload_id)
     }
 
-    pub async fn create(client: &BunnyClient, _bucket: &str, key: &str) -> Result<String> {
+    fn index_path() -> String {
+        format!("{}/{}", MULTIPART_PREFIX, INDEX_NAME)
+    }
+
+    pub async fn create(
+        client: &Backend,
+        _bucket: &str,
+        key: &str,
+        storage_class: Option<&str>,
+        content_type: Option<&str>,
+        headers: &BTreeMap<String, String>,
+    ) -> Result<String> {
         let upload_id = uuid::Uuid::new_v4().to_string();
-        let meta = format!("{}|{}", key, Utc::now().to_rfc3339());
+        let headers = match headers.is_empty() {
+            true => None,
+            false => Some(serde_json::to_string(headers)?),
+        };
+        for (path, value) in [
+            (Self::storage_class_path(&upload_id), storage_class),
+            (Self::content_type_path(&upload_id), content_type),
+            (Self::headers_path(&upload_id), headers.as_deref()),
+        ] {
+            if let Some(value) = value {
+                client
+                    .upload(&path, Bytes::from(value.to_string()), Default::default())
+                    .await?;
+            }
+        }
+        let initiated = Utc::now();
+        let meta = format!("{}|{}", key, initiated.to_rfc3339());
         client
             .upload(
                 &Self::meta_path(&upload_id),
@@ -175,11 +280,47 @@ impl MultipartManager {
                 Default::default(),
             )
             .await?;
+        let entry = (key.to_string(), upload_id.clone(), initiated);
+        if let Err(e) = Self::update_index(client, |uploads| uploads.push(entry)).await {
+            tracing::warn!("Failed to index multipart upload {}: {}", upload_id, e);
+        }
         Ok(upload_id)
     }
 
+    /// The non-default storage class requested when the upload was created.
+    pub async fn storage_class(client: &Backend, upload_id: &str) -> Result<Option<String>> {
+        Self::read_optional(client, &Self::storage_class_path(upload_id)).await
+    }
+
+    /// The Cache-Control and other stored headers sent when the upload was
+    /// created.
+    pub async fn stored_headers(
+        client: &Backend,
+        upload_id: &str,
+    ) -> Result<BTreeMap<String, String>> {
+        match Self::read_optional(client, &Self::headers_path(upload_id)).await? {
+            Some(json) => Ok(serde_json::from_str(&json)?),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// The Content-Type sent, or guessed, when the upload was created.
+    async fn content_type(client: &Backend, upload_id: &str) -> Result<Option<String>> {
+        Self::read_optional(client, &Self::content_type_path(upload_id)).await
+    }
+
+    async fn read_optional(client: &Backend, path: &str) -> Result<Option<String>> {
+        match client.download(path).await {
+            Ok(download) => Ok(Some(
+                String::from_utf8_lossy(&download.bytes().await?).into_owned(),
+            )),
+            Err(ProxyError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
     pub async fn store_part_etag(
-        client: &BunnyClient,
+        client: &Backend,
         upload_id: &str,
         part_number: i32,
         etag: &str,
@@ -190,11 +331,7 @@ impl MultipartManager {
             .await
     }
 
-    async fn read_part_etag(
-        client: &BunnyClient,
-        upload_id: &str,
-        part_number: i32,
-    ) -> Result<String> {
+    async fn read_part_etag(client: &Backend, upload_id: &str, part_number: i32) -> Result<String> {
         let path = Self::part_etag_path(upload_id, part_number);
         let download = client.download(&path).await?;
         let data = download.bytes().await?;
@@ -202,13 +339,17 @@ impl MultipartManager {
             .map_err(|_| ProxyError::InvalidPart(format!("Invalid ETag for part {}", part_number)))
     }
 
+    /// Assembles the upload's `parts` into `key`, returning the object's
+    /// ETag and the size of each part as the client sent it, in order.
     pub async fn complete(
-        client: &BunnyClient,
+        client: &Backend,
         _bucket: &str,
         upload_id: &str,
         key: &str,
         parts: &[(i32, String)],
-    ) -> Result<String> {
+        object_size: Option<u64>,
+        keyring: Option<&Arc<Keyring>>,
+    ) -> Result<(String, Vec<u64>)> {
         let fresh_client = client.fresh();
 
         tracing::debug!("CompleteMultipartUpload: checking if upload exists");
@@ -217,7 +358,9 @@ impl MultipartManager {
         }
 
         let mut total_size: u64 = 0;
+        let mut part_sizes = Vec::with_capacity(parts.len());
         let mut parts_with_etags = Vec::with_capacity(parts.len());
+        let mut plain_sizes = HashMap::new();
 
         tracing::debug!("CompleteMultipartUpload: describing {} parts", parts.len());
         for (part_number, expected_etag) in parts {
@@ -227,10 +370,29 @@ impl MultipartManager {
                 ProxyError::InvalidPart(format!("Part {} not found", part_number))
             })?;
 
-            total_size += obj.length.max(0) as u64;
+            let size = match keyring {
+                Some(_) => {
+                    encryption::plaintext_len(obj.length.max(0) as u64).ok_or_else(|| {
+                        ProxyError::InvalidPart(format!("Part {} is not encrypted", part_number))
+                    })?
+                }
+                None => obj.length.max(0) as u64,
+            };
+            total_size += size;
+            part_sizes.push(size);
+            plain_sizes.insert(*part_number, size);
             parts_with_etags.push((*part_number, expected_etag.clone()));
         }
 
+        if let Some(declared) = object_size
+            && declared != total_size
+        {
+            return Err(ProxyError::InvalidRequest(format!(
+                "The x-amz-mp-object-size of {} bytes does not match the {} bytes of the parts",
+                declared, total_size
+            )));
+        }
+
         tracing::debug!(
             "CompleteMultipartUpload: total size {} bytes, starting upload",
             total_size
@@ -243,28 +405,100 @@ impl MultipartManager {
             .collect();
         let final_etag = format!("{:x}-{}", md5::Md5::digest(&combined_md5), parts.len());
 
-        let stream = PartConcatStream::new(
-            fresh_client.clone(),
-            upload_id.to_string(),
-            parts_with_etags,
-        );
+        let content_type = Self::content_type(&fresh_client, upload_id).await?;
+        let stored_size = match keyring {
+            Some(_) => encryption::encrypted_len(total_size),
+            None => total_size,
+        };
+        let mut attempt = 1;
+        loop {
+            let invalid_part = Arc::default();
+            let stream = PartConcatStream::new(
+                fresh_client.clone(),
+                upload_id.to_string(),
+                parts_with_etags.clone(),
+                keyring.map(|k| (Arc::clone(k), plain_sizes.clone())),
+                Arc::clone(&invalid_part),
+            );
+            // Parts are decrypted above and the whole object sealed again, so it
+            // reads like any single-PUT object.
+            let stream: Pin<Box<dyn Stream<Item = _> + Send>> = match keyring {
+                Some(keyring) => Box::pin(keyring.encrypt(stream)),
+                None => Box::pin(stream),
+            };
 
-        if let Err(e) = fresh_client
-            .upload_stream(key, stream, Some(total_size))
-            .await
-        {
-            tracing::error!("CompleteMultipartUpload: upload_stream failed: {:?}", e);
-            return Err(e);
+            let e = match fresh_client
+                .upload_stream(key, stream, Some(stored_size), content_type.as_deref())
+                .await
+            {
+                Ok(()) => break,
+                Err(e) => e,
+            };
+            if let Some(message) = invalid_part.lock().unwrap().take() {
+                return Err(ProxyError::InvalidPart(message));
+            }
+            if attempt >= MAX_ASSEMBLY_ATTEMPTS || !assembly_retryable(&e) {
+                tracing::error!("CompleteMultipartUpload: upload_stream failed: {:?}", e);
+                return Err(e);
+            }
+            tracing::warn!(
+                "CompleteMultipartUpload: assembling {} failed (attempt {}), restarting: {}",
+                key,
+                attempt,
+                e
+            );
+            fresh_client
+                .stats()
+                .assembly_retries
+                .fetch_add(1, Ordering::Relaxed);
+            // Bunny may keep what arrived before the failure.
+            match fresh_client.delete(key).await {
+                Ok(()) | Err(ProxyError::NotFound(_)) => {}
+                Err(e) => tracing::warn!("Failed to delete partial assembly of {}: {}", key, e),
+            }
+            tokio::time::sleep(ASSEMBLY_RETRY_DELAY * 2u32.pow(attempt - 1)).await;
+            attempt += 1;
         }
 
         tracing::debug!("CompleteMultipartUpload: upload complete, cleaning up");
 
-        Self::cleanup(&fresh_client, upload_id).await?;
+        if let Err(e) = Self::cleanup(&fresh_client, upload_id).await {
+            tracing::warn!(
+                "CompleteMultipartUpload: staging data of {} left behind: {}",
+                upload_id,
+                e
+            );
+        }
+
+        Ok((final_etag, part_sizes))
+    }
+
+    /// A path in a folder of its own in the staging area, where a streamed
+    /// PUT is written before it replaces an existing object. Folders a
+    /// crash leaves behind have no `_meta`, so `cleanup-multipart` reports
+    /// and removes them like abandoned uploads.
+    pub fn staging_path() -> String {
+        format!("{}/{}/_object", MULTIPART_PREFIX, uuid::Uuid::new_v4())
+    }
 
-        Ok(final_etag)
+    /// Deletes a [`Self::staging_path`] with its folder.
+    pub async fn discard_staging(client: &Backend, path: &str) -> Result<()> {
+        let dir = path.rsplit_once('/').map_or(path, |(dir, _)| dir);
+        client.delete(&format!("{}/", dir)).await
     }
 
-    pub async fn abort(client: &BunnyClient, upload_id: &str) -> Result<()> {
+    /// Refuses `upload_id` with NoSuchUpload unless it exists and was
+    /// initiated for `key`, so an upload cannot be completed onto a key its
+    /// initiation was never checked against.
+    pub async fn check_key(client: &Backend, upload_id: &str, key: &str) -> Result<()> {
+        let meta = Self::read_optional(client, &Self::meta_path(upload_id)).await?;
+        match meta.as_deref().and_then(|meta| meta.rsplit_once('|')) {
+            Some((initiated_for, _)) if initiated_for == key => Ok(()),
+            _ => Err(ProxyError::MultipartNotFound(upload_id.to_string())),
+        }
+    }
+
+    pub async fn abort(client: &Backend, upload_id: &str) -> Result<()> {
         if !Self::exists(client, upload_id).await? {
             return Err(ProxyError::MultipartNotFound(upload_id.to_string()));
         }
@@ -272,7 +506,7 @@ impl MultipartManager {
     }
 
     pub async fn list_parts(
-        client: &BunnyClient,
+        client: &Backend,
         upload_id: &str,
     ) -> Result<Vec<(i32, String, i64, DateTime<Utc>)>> {
         if !Self::exists(client, upload_id).await? {
@@ -299,52 +533,530 @@ impl MultipartManager {
         Ok(parts)
     }
 
+    /// Every upload with its key and initiation time, from the index. The
+    /// index is checked against one listing of the staging area: uploads
+    /// whose directory is gone are dropped and unindexed ones, such as those
+    /// created by another instance, are added from their `_meta`. A missing
+    /// or unreadable index is rebuilt this way.
     pub async fn list_uploads(
-        client: &BunnyClient,
+        client: &Backend,
         _bucket: &str,
     ) -> Result<Vec<(String, String, DateTime<Utc>)>> {
-        let objects = client.list(MULTIPART_PREFIX).await?;
-        let mut uploads = Vec::new();
+        let dirs: HashSet<String> = Self::staging_dirs(client)
+            .await?
+            .into_iter()
+            .map(|(upload_id, _)| upload_id)
+            .collect();
+        let indexed = Self::read_index(client).await?;
+        let known: HashSet<&str> = indexed
+            .iter()
+            .flatten()
+            .map(|(_, upload_id, _)| upload_id.as_str())
+            .collect();
+        let missing: Vec<String> = dirs
+            .iter()
+            .filter(|upload_id| !known.contains(upload_id.as_str()))
+            .cloned()
+            .collect();
 
-        for obj in objects {
-            if !obj.is_directory {
-                continue;
+        let found: Vec<UploadEntry> = futures::stream::iter(missing)
+            .map(|upload_id| {
+                let client = client.clone();
+                async move { Self::read_meta(&client, &upload_id).await }
+            })
+            .buffer_unordered(META_FETCH_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        // Only entries seen stale here are dropped, so an upload created
+        // since the listing keeps its place.
+        let stale: HashSet<String> = indexed
+            .iter()
+            .flatten()
+            .filter(|(_, upload_id, _)| !dirs.contains(upload_id))
+            .map(|(_, upload_id, _)| upload_id.clone())
+            .collect();
+        if indexed.is_some() && found.is_empty() && stale.is_empty() {
+            return Ok(indexed.unwrap_or_default());
+        }
+
+        tracing::debug!(
+            "Reindexing multipart uploads: {} added, {} stale",
+            found.len(),
+            stale.len()
+        );
+        Self::update_index(client, |uploads| {
+            uploads.retain(|(_, upload_id, _)| !stale.contains(upload_id));
+            for entry in found {
+                if !uploads
+                    .iter()
+                    .any(|(_, upload_id, _)| *upload_id == entry.1)
+                {
+                    uploads.push(entry);
+                }
             }
-            let upload_id = obj.object_name.clone();
-            let meta_path = Self::meta_path(&upload_id);
-
-            if let Ok(download) = client.download(&meta_path).await
-                && let Ok(data) = download.bytes().await
-                && let Ok(meta) = String::from_utf8(data.to_vec())
-                && let Some((key, initiated)) = meta.split_once('|')
-                && let Ok(dt) = DateTime::parse_from_rfc3339(initiated)
-            {
-                uploads.push((key.to_string(), upload_id, dt.with_timezone(&Utc)));
+        })
+        .await
+    }
+
+    /// The key and initiation time in an upload's `_meta`, if it has one.
+    async fn read_meta(client: &Backend, upload_id: &str) -> Option<UploadEntry> {
+        let meta = Self::read_optional(client, &Self::meta_path(upload_id))
+            .await
+            .ok()??;
+        let (key, initiated) = meta.rsplit_once('|')?;
+        let initiated = DateTime::parse_from_rfc3339(initiated).ok()?;
+        Some((
+            key.to_string(),
+            upload_id.to_string(),
+            initiated.with_timezone(&Utc),
+        ))
+    }
+
+    /// The indexed uploads, or None when there is no readable index.
+    async fn read_index(client: &Backend) -> Result<Option<Vec<UploadEntry>>> {
+        let Some(index) = Self::read_optional(client, &Self::index_path()).await? else {
+            return Ok(None);
+        };
+        let mut uploads = Vec::new();
+        for line in index.lines().filter(|line| !line.is_empty()) {
+            let mut fields = line.splitn(3, '|');
+            match (
+                fields.next(),
+                fields.next().map(DateTime::parse_from_rfc3339),
+                fields.next(),
+            ) {
+                (Some(upload_id), Some(Ok(initiated)), Some(key)) => uploads.push((
+                    key.to_string(),
+                    upload_id.to_string(),
+                    initiated.with_timezone(&Utc),
+                )),
+                _ => {
+                    tracing::warn!("Multipart upload index is corrupt, rebuilding it");
+                    return Ok(None);
+                }
             }
         }
+        Ok(Some(uploads))
+    }
 
+    /// Applies `change` to the index and writes it back, returning the new
+    /// list. A missing index is treated as empty; the next listing adds any
+    /// uploads that were left out.
+    async fn update_index(
+        client: &Backend,
+        change: impl FnOnce(&mut Vec<UploadEntry>),
+    ) -> Result<Vec<UploadEntry>> {
+        let _guard = INDEX_LOCK.lock().await;
+        let indexed = Self::read_index(client).await?;
+        let mut uploads = indexed.clone().unwrap_or_default();
+        change(&mut uploads);
+        if indexed.as_ref() == Some(&uploads) {
+            return Ok(uploads);
+        }
+        let index: String = uploads
+            .iter()
+            .map(|(key, upload_id, initiated)| {
+                format!("{}|{}|{}\n", upload_id, initiated.to_rfc3339(), key)
+            })
+            .collect();
+        client
+            .upload(&Self::index_path(), Bytes::from(index), Default::default())
+            .await?;
         Ok(uploads)
     }
 
-    async fn exists(client: &BunnyClient, upload_id: &str) -> Result<bool> {
+    /// Number of parts received so far and their combined size in bytes.
+    pub async fn staged(client: &Backend, upload_id: &str) -> Result<(usize, u64)> {
+        let objects = client.list(&Self::upload_dir(upload_id)).await?;
+        Ok(objects
+            .iter()
+            .filter(|obj| !obj.is_directory && obj.object_name.parse::<i32>().is_ok())
+            .fold((0, 0), |(count, bytes), obj| {
+                (count + 1, bytes + obj.length.max(0) as u64)
+            }))
+    }
+
+    /// Whether the upload's `_meta` exists; one that is gone is also dropped
+    /// from the index.
+    async fn exists(client: &Backend, upload_id: &str) -> Result<bool> {
         let meta_path = Self::meta_path(upload_id);
         match client.describe(&meta_path).await {
             Ok(_) => Ok(true),
-            Err(ProxyError::NotFound(_)) => Ok(false),
+            Err(ProxyError::NotFound(_)) => {
+                Self::unindex(client, upload_id).await;
+                Ok(false)
+            }
             Err(e) => Err(e),
         }
     }
 
-    async fn cleanup(client: &BunnyClient, upload_id: &str) -> Result<()> {
+    /// Every upload directory in the staging area with its creation time,
+    /// including uploads whose metadata was never written.
+    pub async fn staging_dirs(client: &Backend) -> Result<Vec<(String, DateTime<Utc>)>> {
+        Ok(client
+            .list(MULTIPART_PREFIX)
+            .await?
+            .into_iter()
+            .filter(|obj| obj.is_directory)
+            .map(|obj| (obj.object_name, obj.date_created))
+            .collect())
+    }
+
+    /// Deletes everything staged for an upload and drops it from the index,
+    /// reporting the last failed deletion after attempting them all. `_meta`
+    /// goes first, so an interrupted cleanup leaves no upload behind.
+    pub async fn cleanup(client: &Backend, upload_id: &str) -> Result<()> {
         let dir = Self::upload_dir(upload_id);
-        let objects = client.list(&dir).await?;
+        let mut objects = client.list(&dir).await?;
+        objects.sort_by_key(|obj| obj.object_name != "_meta");
 
+        let mut result = Ok(());
         for obj in objects {
             let path = format!("{}/{}", dir, obj.object_name);
-            let _ = client.delete(&path).await;
+            if let Err(e) = client.delete(&path).await {
+                result = Err(e);
+            }
+        }
+
+        if let Err(e) = client.delete(&format!("{}/", dir)).await {
+            result = Err(e);
+        }
+        Self::unindex(client, upload_id).await;
+        result
+    }
+
+    async fn unindex(client: &Backend, upload_id: &str) {
+        if let Err(e) = Self::update_index(client, |uploads| {
+            uploads.retain(|(_, id, _)| id != upload_id)
+        })
+        .await
+        {
+            tracing::warn!("Failed to unindex multipart upload {}: {}", upload_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bunny::BunnyClient;
+    use crate::config::{StorageRegion, StorageZoneConfig};
+    use axum::body::Body;
+    use axum::http::{Method, StatusCode, Uri};
+    use axum::response::IntoResponse;
+    use futures::StreamExt;
+    use md5::Digest;
+    use std::sync::atomic::AtomicU32;
+
+    type Objects = Arc<std::sync::Mutex<HashMap<String, Bytes>>>;
+
+    struct Upload {
+        client: Backend,
+        objects: Objects,
+        final_puts: Arc<AtomicU32>,
+        meta_reads: Arc<AtomicU32>,
+        upload_id: String,
+    }
+
+    fn storage_object(name: &str, length: usize, is_directory: bool) -> serde_json::Value {
+        serde_json::json!({
+            "Guid": "g",
+            "UserId": "u",
+            "StorageZoneName": "zone",
+            "Path": "/zone/",
+            "ObjectName": name,
+            "Length": length,
+            "LastChanged": "2024-05-01T12:00:00",
+            "DateCreated": "2024-05-01T12:00:00",
+            "StorageZoneId": 1,
+            "IsDirectory": is_directory,
+            "ServerId": 1,
+            "ContentType": "application/octet-stream",
+        })
+    }
+
+    /// The immediate children of `dir`, as Bunny lists a directory.
+    fn listing(objects: &Objects, dir: &str) -> serde_json::Value {
+        let mut children = std::collections::BTreeMap::new();
+        for (path, data) in objects.lock().unwrap().iter() {
+            if let Some(rest) = path.strip_prefix(dir) {
+                match rest.split_once('/') {
+                    Some((name, _)) => children.insert(name.to_string(), (0, true)),
+                    None => children.insert(rest.to_string(), (data.len(), false)),
+                };
+            }
+        }
+        children
+            .iter()
+            .map(|(name, (length, is_directory))| storage_object(name, *length, *is_directory))
+            .collect()
+    }
+
+    /// A two-part upload staged on a stand-in for Bunny whose first `failures`
+    /// writes of `big.bin` break off with a 503, keeping what arrived.
+    async fn staged_upload(failures: u32) -> Upload {
+        let objects = Objects::default();
+        let final_puts = Arc::new(AtomicU32::new(0));
+        let meta_reads = Arc::new(AtomicU32::new(0));
+        let app = axum::Router::new().route(
+            "/{*path}",
+            axum::routing::any({
+                let objects = Arc::clone(&objects);
+                let final_puts = Arc::clone(&final_puts);
+                let meta_reads = Arc::clone(&meta_reads);
+                move |method: Method, uri: Uri, body: Body| {
+                    let objects = Arc::clone(&objects);
+                    let final_puts = Arc::clone(&final_puts);
+                    let meta_reads = Arc::clone(&meta_reads);
+                    async move {
+                        let path = uri.path().to_string();
+                        match method.as_str() {
+                            "DESCRIBE" => match objects.lock().unwrap().get(&path) {
+                                Some(data) => axum::Json(storage_object("x", data.len(), false))
+                                    .into_response(),
+                                None => StatusCode::NOT_FOUND.into_response(),
+                            },
+                            "GET" if path.ends_with('/') => {
+                                axum::Json(listing(&objects, &path)).into_response()
+                            }
+                            "GET" if path.ends_with("/_meta") => {
+                                meta_reads.fetch_add(1, Ordering::SeqCst);
+                                match objects.lock().unwrap().get(&path) {
+                                    Some(data) => data.clone().into_response(),
+                                    None => StatusCode::NOT_FOUND.into_response(),
+                                }
+                            }
+                            "GET" => match objects.lock().unwrap().get(&path) {
+                                Some(data) => data.clone().into_response(),
+                                None => StatusCode::NOT_FOUND.into_response(),
+                            },
+                            "PUT" => {
+                                let failing = path == "/zone/big.bin"
+                                    && final_puts.fetch_add(1, Ordering::SeqCst) < failures;
+                                let mut stream = body.into_data_stream();
+                                let mut data = Vec::new();
+                                while let Some(Ok(chunk)) = stream.next().await {
+                                    data.extend_from_slice(&chunk);
+                                    if failing {
+                                        break;
+                                    }
+                                }
+                                objects.lock().unwrap().insert(path, data.into());
+                                if failing {
+                                    StatusCode::SERVICE_UNAVAILABLE.into_response()
+                                } else {
+                                    StatusCode::CREATED.into_response()
+                                }
+                            }
+                            "DELETE" => {
+                                objects.lock().unwrap().remove(&path);
+                                StatusCode::OK.into_response()
+                            }
+                            _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+                        }
+                    }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = Backend::Bunny(BunnyClient::new(StorageZoneConfig {
+            name: "zone".into(),
+            access_key: "key".into(),
+            region: StorageRegion::Falkenstein,
+            api_key: None,
+            endpoint: None,
+            pool: Default::default(),
+        }))
+        .with_base_url(&url);
+        let upload_id =
+            MultipartManager::create(&client, "bucket", "big.bin", None, None, &BTreeMap::new())
+                .await
+                .unwrap();
+        for (n, (data, etag)) in parts().into_iter().enumerate() {
+            let n = n as i32 + 1;
+            client
+                .upload(
+                    &MultipartManager::part_path(&upload_id, n),
+                    Bytes::from(data),
+                    Default::default(),
+                )
+                .await
+                .unwrap();
+            MultipartManager::store_part_etag(&client, &upload_id, n, &etag)
+                .await
+                .unwrap();
         }
+        Upload {
+            client,
+            objects,
+            final_puts,
+            meta_reads,
+            upload_id,
+        }
+    }
+
+    fn parts() -> Vec<(&'static str, String)> {
+        ["hello ", "world"]
+            .into_iter()
+            .map(|data| (data, format!("{:x}", md5::Md5::digest(data))))
+            .collect()
+    }
+
+    fn etags() -> Vec<(i32, String)> {
+        parts()
+            .into_iter()
+            .enumerate()
+            .map(|(n, (_, etag))| (n as i32 + 1, etag))
+            .collect()
+    }
+
+    async fn complete(upload: &Upload, etags: &[(i32, String)]) -> Result<(String, Vec<u64>)> {
+        MultipartManager::complete(
+            &upload.client,
+            "bucket",
+            &upload.upload_id,
+            "big.bin",
+            etags,
+            None,
+            None,
+        )
+        .await
+    }
 
-        let _ = client.delete(&format!("{}/", dir)).await;
-        Ok(())
+    #[tokio::test]
+    async fn test_failed_assembly_is_restarted() {
+        let upload = staged_upload(1).await;
+        let (_, sizes) = complete(&upload, &etags()).await.unwrap();
+        assert_eq!(sizes.iter().sum::<u64>(), 11);
+        assert_eq!(upload.final_puts.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            upload.objects.lock().unwrap()["/zone/big.bin"],
+            Bytes::from("hello world")
+        );
+        let stats = upload.client.stats();
+        assert_eq!(stats.assembly_retries.load(Ordering::Relaxed), 1);
+        assert!(
+            stats
+                .render_metrics()
+                .contains("bunny_s3_proxy_multipart_assembly_retries_total 1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_assembly_gives_up_after_bounded_attempts() {
+        let upload = staged_upload(u32::MAX).await;
+        assert!(complete(&upload, &etags()).await.is_err());
+        assert_eq!(
+            upload.final_puts.load(Ordering::SeqCst),
+            MAX_ASSEMBLY_ATTEMPTS
+        );
+        // The staged parts are kept so the client can complete again.
+        assert!(
+            MultipartManager::exists(&upload.client, &upload.upload_id)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_declared_object_size_is_checked_before_assembly() {
+        let upload = staged_upload(0).await;
+        let etags = etags();
+        let complete = |size| {
+            MultipartManager::complete(
+                &upload.client,
+                "bucket",
+                &upload.upload_id,
+                "big.bin",
+                &etags,
+                Some(size),
+                None,
+            )
+        };
+        let err = complete(12).await.unwrap_err();
+        assert_eq!(err.s3_error_code(), "InvalidRequest");
+        assert!(err.to_string().contains("x-amz-mp-object-size"), "{}", err);
+        assert_eq!(upload.final_puts.load(Ordering::SeqCst), 0);
+        assert_eq!(complete(11).await.unwrap().1.iter().sum::<u64>(), 11);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_part_is_not_retried() {
+        let upload = staged_upload(0).await;
+        let mut etags = etags();
+        etags[1].1 = "0".repeat(32);
+        let err = complete(&upload, &etags).await.unwrap_err();
+        assert_eq!(err.s3_error_code(), "InvalidPart");
+        assert_eq!(upload.final_puts.load(Ordering::SeqCst), 1);
+        let retries = &upload.client.stats().assembly_retries;
+        assert_eq!(retries.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_uploads_are_listed_from_the_index() {
+        let upload = staged_upload(0).await;
+        let client = &upload.client;
+        let other =
+            MultipartManager::create(client, "bucket", "other.bin", None, None, &BTreeMap::new())
+                .await
+                .unwrap();
+        let ids = |uploads: Vec<UploadEntry>| {
+            let mut ids: Vec<_> = uploads.into_iter().map(|(key, id, _)| (key, id)).collect();
+            ids.sort();
+            ids
+        };
+        let expected = ids(vec![
+            ("big.bin".into(), upload.upload_id.clone(), Utc::now()),
+            ("other.bin".into(), other.clone(), Utc::now()),
+        ]);
+
+        // Both uploads were indexed on creation, so no _meta is read.
+        let listed = MultipartManager::list_uploads(client, "bucket")
+            .await
+            .unwrap();
+        assert_eq!(ids(listed), expected);
+        assert_eq!(upload.meta_reads.load(Ordering::SeqCst), 0);
+
+        // A lost index is rebuilt from the _meta objects and stored again.
+        upload
+            .objects
+            .lock()
+            .unwrap()
+            .remove("/zone/__multipart/_index");
+        let listed = MultipartManager::list_uploads(client, "bucket")
+            .await
+            .unwrap();
+        assert_eq!(ids(listed), expected);
+        assert_eq!(upload.meta_reads.load(Ordering::SeqCst), 2);
+        MultipartManager::list_uploads(client, "bucket")
+            .await
+            .unwrap();
+        assert_eq!(upload.meta_reads.load(Ordering::SeqCst), 2);
+
+        // An upload whose staging directory vanished is dropped.
+        upload
+            .objects
+            .lock()
+            .unwrap()
+            .retain(|path, _| !path.contains(&other));
+        let listed = MultipartManager::list_uploads(client, "bucket")
+            .await
+            .unwrap();
+        assert_eq!(ids(listed), expected[..1]);
+        let index = upload.objects.lock().unwrap()["/zone/__multipart/_index"].clone();
+        assert!(!String::from_utf8_lossy(&index).contains(&other));
+
+        // Aborting unindexes the upload.
+        MultipartManager::abort(client, &upload.upload_id)
+            .await
+            .unwrap();
+        let index = upload.objects.lock().unwrap()["/zone/__multipart/_index"].clone();
+        assert!(index.is_empty());
     }
 }
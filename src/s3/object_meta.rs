@@ -0,0 +1,422 @@
+use axum::http::{HeaderMap, HeaderName, HeaderValue, header};
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+use crate::bunny::{Backend, StorageBackend};
+use crate::error::{ProxyError, Result};
+
+use super::checksums::Checksum;
+
+/// Prefix under which per-object metadata sidecars mirror the object keys.
+pub const META_PREFIX: &str = "__meta";
+
+pub const STORAGE_CLASS_HEADER: &str = "x-amz-storage-class";
+pub const DEFAULT_STORAGE_CLASS: &str = "STANDARD";
+
+/// How many sidecar folders `keys_with_meta_among` lists at once.
+const LIST_CONCURRENCY: usize = 8;
+
+/// Storage classes whose only difference from STANDARD is pricing, so the
+/// proxy can record them without changing how objects are served.
+const ONLINE_STORAGE_CLASSES: &[&str] = &[
+    "STANDARD",
+    "STANDARD_IA",
+    "ONEZONE_IA",
+    "INTELLIGENT_TIERING",
+    "REDUCED_REDUNDANCY",
+    "GLACIER_IR",
+];
+
+/// Validates `x-amz-storage-class`, returning the class to record if it is not
+/// the default. Archive classes are rejected since Bunny has no restore step.
+pub fn requested_storage_class(headers: &HeaderMap) -> Result<Option<String>> {
+    let Some(class) = headers.get(STORAGE_CLASS_HEADER) else {
+        return Ok(None);
+    };
+    let class = class.to_str().map_err(|_| {
+        ProxyError::InvalidStorageClass(String::from_utf8_lossy(class.as_bytes()).into_owned())
+    })?;
+    if !ONLINE_STORAGE_CLASSES.contains(&class) {
+        return Err(ProxyError::InvalidStorageClass(class.to_string()));
+    }
+    Ok((class != DEFAULT_STORAGE_CLASS).then(|| class.to_string()))
+}
+
+pub const USER_METADATA_PREFIX: &str = "x-amz-meta-";
+
+/// S3's limit on the UTF-8 size of all user metadata names and values.
+pub const MAX_USER_METADATA_SIZE: usize = 2048;
+
+/// Rejects `x-amz-meta-*` headers that S3 would: names that are empty or not
+/// US-ASCII, and sets whose names (without the prefix) and values add up to
+/// more than [`MAX_USER_METADATA_SIZE`] bytes.
+pub fn check_user_metadata(headers: &HeaderMap) -> Result<()> {
+    let mut size = 0;
+    for (name, value) in headers {
+        let Some(name) = name.as_str().strip_prefix(USER_METADATA_PREFIX) else {
+            continue;
+        };
+        if name.is_empty() || !name.is_ascii() {
+            return Err(ProxyError::InvalidArgument(format!(
+                "Invalid metadata name '{}{}'",
+                USER_METADATA_PREFIX, name
+            )));
+        }
+        size += name.len() + value.len();
+    }
+    if size > MAX_USER_METADATA_SIZE {
+        return Err(ProxyError::MetadataTooLarge(MAX_USER_METADATA_SIZE));
+    }
+    Ok(())
+}
+
+/// Standard headers a PUT may set that S3 stores with the object and
+/// replays on GET and HEAD, while Bunny keeps only the Content-Type.
+pub const STORED_HEADERS: &[HeaderName] = &[
+    header::CACHE_CONTROL,
+    header::CONTENT_DISPOSITION,
+    header::CONTENT_ENCODING,
+];
+
+/// The [`STORED_HEADERS`] of a write request, by name. `aws-chunked`
+/// describes the upload's framing, not the object, so it is left out of
+/// Content-Encoding as S3 does.
+pub fn stored_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    STORED_HEADERS
+        .iter()
+        .filter_map(|name| {
+            let value = headers.get(name)?.to_str().ok()?;
+            let value = match *name {
+                header::CONTENT_ENCODING => value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|coding| !coding.eq_ignore_ascii_case("aws-chunked"))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                _ => value.to_string(),
+            };
+            (!value.is_empty()).then(|| (name.as_str().to_string(), value))
+        })
+        .collect()
+}
+
+/// Sets the headers [`stored_headers`] recorded on a GET or HEAD response.
+pub fn replay_headers(stored: &BTreeMap<String, String>, response: &mut HeaderMap) {
+    for (name, value) in stored {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            response.insert(name, value);
+        }
+    }
+}
+
+/// Object attributes Bunny cannot store natively.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ObjectMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_class: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionMeta>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionMeta>,
+    /// Set on objects written under `--emulate-versioning`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_id: Option<String>,
+    /// The object's [`stored_headers`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// The size of each part of an object assembled by
+    /// CompleteMultipartUpload, in order, so `partNumber` reads find them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<u64>,
+    /// The CRC32, CRC32C or SHA-1 checksum the client sent with the object.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<Checksum>,
+}
+
+/// What clients see of an object stored encrypted at rest, since Bunny only
+/// knows the size and checksum of the ciphertext. Objects under an SSE-C key
+/// also record the key's MD5, never the key itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptionMeta {
+    pub size: u64,
+    pub etag: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub customer_key_md5: Option<String>,
+}
+
+/// Marks an object stored compressed, with the size and ETag of the bytes
+/// the client sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressionMeta {
+    pub algorithm: String,
+    pub size: u64,
+    pub etag: String,
+}
+
+impl ObjectMeta {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Size and ETag of the object as the client sent it, when Bunny stores
+    /// something else.
+    pub fn original(&self) -> Option<(u64, &str)> {
+        match (&self.compression, &self.encryption) {
+            (Some(c), _) => Some((c.size, &c.etag)),
+            (None, Some(e)) => Some((e.size, &e.etag)),
+            (None, None) => None,
+        }
+    }
+
+    /// The offset and size of part `part_number`, counted from 1, or `None`
+    /// past the last part. An object not assembled from parts is its own
+    /// single part of `size` bytes.
+    pub fn part_range(&self, part_number: usize, size: u64) -> Option<(u64, u64)> {
+        if self.parts.is_empty() {
+            return (part_number == 1).then_some((0, size));
+        }
+        let index = part_number.checked_sub(1)?;
+        let part = *self.parts.get(index)?;
+        Some((self.parts[..index].iter().sum(), part))
+    }
+
+    pub fn storage_class(&self) -> &str {
+        self.storage_class
+            .as_deref()
+            .unwrap_or(DEFAULT_STORAGE_CLASS)
+    }
+}
+
+/// Stores [`ObjectMeta`] as a JSON sidecar next to the mirrored key. Objects
+/// without non-default metadata have no sidecar at all.
+pub struct ObjectMetaStore;
+
+impl ObjectMetaStore {
+    fn path(key: &str) -> String {
+        format!("{}/{}", META_PREFIX, key)
+    }
+
+    /// Whether a key belongs to the metadata sidecar area.
+    pub fn is_internal_key(key: &str) -> bool {
+        key == META_PREFIX
+            || key
+                .strip_prefix(META_PREFIX)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    pub async fn get(client: &Backend, key: &str) -> Result<ObjectMeta> {
+        match client.download(&Self::path(key)).await {
+            Ok(download) => Ok(serde_json::from_slice(&download.bytes().await?)?),
+            Err(ProxyError::NotFound(_)) => Ok(ObjectMeta::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the sidecar, or removes a stale one when `meta` is all defaults.
+    pub async fn put(client: &Backend, key: &str, meta: &ObjectMeta) -> Result<()> {
+        if meta.is_empty() {
+            return client.delete(&Self::path(key)).await;
+        }
+        client
+            .upload(
+                &Self::path(key),
+                Bytes::from(serde_json::to_vec(meta)?),
+                Default::default(),
+            )
+            .await
+    }
+
+    pub async fn delete(client: &Backend, key: &str) -> Result<()> {
+        client.delete(&Self::path(key)).await
+    }
+
+    /// Keys under `prefix` that have a sidecar, so listings only fetch those.
+    pub async fn keys_with_meta(
+        client: &Backend,
+        prefix: &str,
+        recursive: bool,
+    ) -> Result<HashSet<String>> {
+        let path = Self::path(prefix);
+        let indexed = recursive
+            .then(|| client.indexed_listing(&path, None, usize::MAX, &|_| false))
+            .flatten();
+        let objects = if let Some(objects) = indexed {
+            objects
+        } else if recursive {
+            client.list_recursive(&path, None).await?
+        } else {
+            client.list(&path).await?
+        };
+        Ok(objects
+            .iter()
+            .filter(|obj| !obj.is_directory)
+            .filter_map(|obj| {
+                obj.s3_key()
+                    .strip_prefix(META_PREFIX)
+                    .map(|k| k.trim_start_matches('/').to_string())
+            })
+            .collect())
+    }
+
+    /// Which of `keys` have a sidecar, listing only the sidecar folders of
+    /// their parents rather than everything under a prefix.
+    pub async fn keys_with_meta_among(
+        client: &Backend,
+        keys: &[String],
+    ) -> Result<HashSet<String>> {
+        let parents: HashSet<String> = keys
+            .iter()
+            .map(|key| key.rfind('/').map_or("", |pos| &key[..=pos]).to_string())
+            .collect();
+        let listings: Vec<HashSet<String>> = futures::stream::iter(parents)
+            .map(|parent| async move { Self::keys_with_meta(client, &parent, false).await })
+            .buffer_unordered(LIST_CONCURRENCY)
+            .try_collect()
+            .await?;
+        Ok(listings.into_iter().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers_with_class(class: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(STORAGE_CLASS_HEADER, class.parse().unwrap());
+        headers
+    }
+
+    fn headers_with_metadata(pairs: &[(&str, String)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(
+                axum::http::HeaderName::try_from(format!("{}{}", USER_METADATA_PREFIX, name))
+                    .unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn test_stored_headers_round_trip() {
+        let mut request = HeaderMap::new();
+        request.insert(header::CACHE_CONTROL, "max-age=60".parse().unwrap());
+        request.insert(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"a.csv\"".parse().unwrap(),
+        );
+        request.insert(
+            header::CONTENT_ENCODING,
+            "aws-chunked,gzip".parse().unwrap(),
+        );
+        request.insert(header::CONTENT_LANGUAGE, "en".parse().unwrap());
+        let stored = stored_headers(&request);
+        assert_eq!(stored.len(), 3);
+        assert_eq!(stored["content-encoding"], "gzip");
+
+        let mut response = HeaderMap::new();
+        replay_headers(&stored, &mut response);
+        assert_eq!(response[header::CACHE_CONTROL], "max-age=60");
+        assert_eq!(
+            response[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"a.csv\""
+        );
+        assert_eq!(response[header::CONTENT_ENCODING], "gzip");
+        assert!(!response.contains_key(header::CONTENT_LANGUAGE));
+
+        // Chunked framing alone leaves no Content-Encoding to replay.
+        request.insert(header::CONTENT_ENCODING, "aws-chunked".parse().unwrap());
+        assert!(!stored_headers(&request).contains_key("content-encoding"));
+    }
+
+    #[test]
+    fn test_part_ranges() {
+        let meta = ObjectMeta {
+            parts: vec![5, 5, 2],
+            ..Default::default()
+        };
+        assert_eq!(meta.part_range(1, 12), Some((0, 5)));
+        assert_eq!(meta.part_range(3, 12), Some((10, 2)));
+        assert_eq!(meta.part_range(4, 12), None);
+        assert_eq!(meta.part_range(0, 12), None);
+
+        let single = ObjectMeta::default();
+        assert_eq!(single.part_range(1, 12), Some((0, 12)));
+        assert_eq!(single.part_range(2, 12), None);
+    }
+
+    #[test]
+    fn test_user_metadata_limit_boundary() {
+        // Names count without the x-amz-meta- prefix: 4 + 2044 bytes.
+        let at_limit = headers_with_metadata(&[("note", "v".repeat(2044))]);
+        assert!(check_user_metadata(&at_limit).is_ok());
+        let over = headers_with_metadata(&[("note", "v".repeat(2045))]);
+        assert!(matches!(
+            check_user_metadata(&over),
+            Err(ProxyError::MetadataTooLarge(MAX_USER_METADATA_SIZE))
+        ));
+
+        let split = headers_with_metadata(&[("a", "x".repeat(1023)), ("b", "y".repeat(1023))]);
+        assert!(check_user_metadata(&split).is_ok());
+        let split = headers_with_metadata(&[("a", "x".repeat(1024)), ("b", "y".repeat(1023))]);
+        assert!(check_user_metadata(&split).is_err());
+
+        let mut other = headers_with_metadata(&[("note", "v".repeat(2044))]);
+        other.insert("x-amz-tagging", "k=v".repeat(500).parse().unwrap());
+        assert!(check_user_metadata(&other).is_ok());
+        assert!(matches!(
+            check_user_metadata(&headers_with_metadata(&[("", "v".into())])),
+            Err(ProxyError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_accepted_storage_classes() {
+        assert_eq!(requested_storage_class(&HeaderMap::new()).unwrap(), None);
+        assert_eq!(
+            requested_storage_class(&headers_with_class("STANDARD")).unwrap(),
+            None
+        );
+        for class in [
+            "STANDARD_IA",
+            "ONEZONE_IA",
+            "INTELLIGENT_TIERING",
+            "REDUCED_REDUNDANCY",
+            "GLACIER_IR",
+        ] {
+            assert_eq!(
+                requested_storage_class(&headers_with_class(class)).unwrap(),
+                Some(class.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_rejected_storage_classes() {
+        for class in [
+            "GLACIER",
+            "DEEP_ARCHIVE",
+            "OUTPOSTS",
+            "standard_ia",
+            "BOGUS",
+        ] {
+            let err = requested_storage_class(&headers_with_class(class)).unwrap_err();
+            assert_eq!(err.s3_error_code(), "InvalidStorageClass");
+        }
+    }
+
+    #[test]
+    fn test_default_meta_is_not_serialized() {
+        let meta = ObjectMeta::default();
+        assert!(meta.is_empty());
+        assert_eq!(meta.storage_class(), "STANDARD");
+        assert_eq!(serde_json::to_string(&meta).unwrap(), "{}");
+    }
+}
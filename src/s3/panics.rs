@@ -0,0 +1,190 @@
+//! Panics in request handling. A handler that panics would otherwise take
+//! its connection task down with it, so the client saw a reset instead of an
+//! S3 error and, on HTTP/2, lost every other stream on the connection too.
+//! A handler panic is answered with InternalError instead, and a panic while
+//! a response body streams ends that body with an error, which closes an
+//! HTTP/1 connection and resets only the one HTTP/2 stream.
+
+use axum::body::Body;
+use bytes::Bytes;
+use futures::FutureExt;
+use hyper::body::{Body as HttpBody, Frame, SizeHint};
+use std::any::Any;
+use std::fmt::Write;
+use std::future::Future;
+use std::panic::{AssertUnwindSafe, PanicHookInfo};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+use crate::error::{ProxyError, Result};
+
+/// Logs panics at error level with their location and a backtrace, in the
+/// span of the request that panicked, instead of printing them to stderr.
+pub fn install_hook() {
+    std::panic::set_hook(Box::new(|info: &PanicHookInfo<'_>| {
+        let location = info
+            .location()
+            .map(|l| format!("{}:{}", l.file(), l.line()))
+            .unwrap_or_default();
+        tracing::error!(
+            "Panic at {}: {}\n{}",
+            location,
+            message(info.payload()),
+            std::backtrace::Backtrace::force_capture()
+        );
+    }));
+}
+
+fn message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload")
+}
+
+/// Panics caught while handling requests.
+#[derive(Debug, Default)]
+pub struct PanicStats {
+    panics: AtomicU64,
+}
+
+impl PanicStats {
+    /// Runs `handler`, turning a panic into [`ProxyError::Panicked`].
+    pub async fn catch<F>(&self, handler: F) -> Result<axum::response::Response>
+    where
+        F: Future<Output = Result<axum::response::Response>>,
+    {
+        match AssertUnwindSafe(handler).catch_unwind().await {
+            Ok(result) => result,
+            Err(payload) => {
+                self.panics.fetch_add(1, Ordering::Relaxed);
+                tracing::error!("Request handler panicked: {}", message(payload.as_ref()));
+                Err(ProxyError::Panicked)
+            }
+        }
+    }
+
+    /// Wraps a response body so a panic while streaming it ends the body
+    /// with an error.
+    pub fn guard_body(self: &Arc<Self>, body: Body) -> Body {
+        Body::new(CatchPanicBody {
+            inner: body,
+            stats: Arc::clone(self),
+            done: false,
+        })
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let name = "bunny_s3_proxy_panics_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Request handlers and response bodies that panicked.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, self.panics.load(Ordering::Relaxed));
+        out
+    }
+}
+
+struct CatchPanicBody {
+    inner: Body,
+    stats: Arc<PanicStats>,
+    done: bool,
+}
+
+impl HttpBody for CatchPanicBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Bytes>, axum::Error>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let this = &mut *self;
+        let inner = &mut this.inner;
+        match std::panic::catch_unwind(AssertUnwindSafe(|| Pin::new(inner).poll_frame(cx))) {
+            Ok(poll) => poll,
+            Err(payload) => {
+                this.done = true;
+                this.stats.panics.fetch_add(1, Ordering::Relaxed);
+                let message = message(payload.as_ref()).to_string();
+                tracing::error!("Response body panicked while streaming: {}", message);
+                Poll::Ready(Some(Err(axum::Error::new(format!(
+                    "response body panicked: {}",
+                    message
+                )))))
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+    use futures::StreamExt;
+
+    fn panics(stats: &PanicStats) -> u64 {
+        stats.panics.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn test_handler_panics_become_internal_errors() {
+        let stats = PanicStats::default();
+        let ok = stats.catch(async { Ok("fine".into_response()) }).await;
+        assert_eq!(ok.unwrap().status(), 200);
+
+        let range = 2..10;
+        let err = stats
+            .catch(async move {
+                let data = [0u8; 4];
+                Ok(data[range].to_vec().into_response())
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ProxyError::Panicked));
+        assert_eq!(err.s3_error_code(), "InternalError");
+        assert_eq!(err.status_code(), 500);
+        assert_eq!(panics(&stats), 1);
+        assert!(
+            stats
+                .render_metrics()
+                .contains("bunny_s3_proxy_panics_total 1\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_panicking_bodies_end_with_an_error() {
+        let stats = Arc::new(PanicStats::default());
+        let chunks = futures::stream::iter(["first", ""]).map(|chunk| {
+            if chunk.is_empty() {
+                panic!("second chunk");
+            }
+            Ok::<_, std::io::Error>(Bytes::from(chunk))
+        });
+        let mut body = stats
+            .guard_body(Body::from_stream(chunks))
+            .into_data_stream();
+        assert_eq!(body.next().await.unwrap().unwrap(), "first");
+        let err = body.next().await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("second chunk"), "{}", err);
+        assert!(body.next().await.is_none());
+        assert_eq!(panics(&stats), 1);
+    }
+}
@@ -0,0 +1,279 @@
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::error::{ProxyError, Result};
+
+/// Form fields that are never covered by policy conditions.
+const UNCONDITIONED_FIELDS: &[&str] = &["policy", "x-amz-signature", "file"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Eq(String, String),
+    StartsWith(String, String),
+    ContentLengthRange(u64, u64),
+}
+
+/// A decoded browser-upload policy document.
+#[derive(Debug, Clone)]
+pub struct PostPolicy {
+    expiration: DateTime<Utc>,
+    conditions: Vec<Condition>,
+}
+
+impl PostPolicy {
+    /// Decodes the base64 `policy` form field.
+    pub fn decode(encoded: &str) -> Result<Self> {
+        let json = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .map_err(|_| ProxyError::InvalidRequest("Invalid Policy: Invalid Base64".into()))?;
+        let doc: Value = serde_json::from_slice(&json)
+            .map_err(|e| ProxyError::InvalidRequest(format!("Invalid Policy: {}", e)))?;
+
+        let expiration = doc
+            .get("expiration")
+            .and_then(Value::as_str)
+            .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+            .ok_or_else(|| {
+                ProxyError::InvalidRequest("Invalid Policy: Invalid 'expiration' value".into())
+            })?
+            .with_timezone(&Utc);
+
+        let conditions = doc
+            .get("conditions")
+            .and_then(Value::as_array)
+            .ok_or_else(|| {
+                ProxyError::InvalidRequest("Invalid Policy: Missing 'conditions' array".into())
+            })?
+            .iter()
+            .map(parse_condition)
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+
+        Ok(Self {
+            expiration,
+            conditions,
+        })
+    }
+
+    /// Checks the expiration and every field condition against the submitted
+    /// form. `fields` must be keyed by lowercased field name and include `bucket`.
+    pub fn check(&self, fields: &HashMap<String, String>) -> Result<()> {
+        if Utc::now() > self.expiration {
+            return Err(ProxyError::PostPolicyFailed("Policy expired.".into()));
+        }
+
+        for condition in &self.conditions {
+            let (name, ok) = match condition {
+                Condition::Eq(name, value) => (
+                    name,
+                    fields.get(name).map(String::as_str).unwrap_or("") == value,
+                ),
+                Condition::StartsWith(name, prefix) if name == "content-type" => (
+                    name,
+                    // A starts-with on Content-Type applies to each comma-separated value.
+                    fields
+                        .get(name)
+                        .map(String::as_str)
+                        .unwrap_or("")
+                        .split(',')
+                        .all(|ct| ct.trim().starts_with(prefix.as_str())),
+                ),
+                Condition::StartsWith(name, prefix) => (
+                    name,
+                    fields
+                        .get(name)
+                        .map(String::as_str)
+                        .unwrap_or("")
+                        .starts_with(prefix.as_str()),
+                ),
+                Condition::ContentLengthRange(..) => continue,
+            };
+            if !ok {
+                return Err(ProxyError::PostPolicyFailed(format!(
+                    "Policy Condition failed: [\"{}\", \"${}\"]",
+                    condition.operator(),
+                    name
+                )));
+            }
+        }
+
+        for name in fields.keys() {
+            if name == "bucket"
+                || UNCONDITIONED_FIELDS.contains(&name.as_str())
+                || name.starts_with("x-ignore-")
+            {
+                continue;
+            }
+            let covered = self.conditions.iter().any(|c| match c {
+                Condition::Eq(n, _) | Condition::StartsWith(n, _) => n == name,
+                Condition::ContentLengthRange(..) => false,
+            });
+            if !covered {
+                return Err(ProxyError::PostPolicyFailed(format!(
+                    "Extra input fields: {}",
+                    name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The inclusive `content-length-range` bounds, if the policy sets them.
+    pub fn content_length_range(&self) -> Option<(u64, u64)> {
+        self.conditions.iter().find_map(|c| match c {
+            Condition::ContentLengthRange(min, max) => Some((*min, *max)),
+            _ => None,
+        })
+    }
+}
+
+impl Condition {
+    fn operator(&self) -> &'static str {
+        match self {
+            Self::Eq(..) => "eq",
+            Self::StartsWith(..) => "starts-with",
+            Self::ContentLengthRange(..) => "content-length-range",
+        }
+    }
+}
+
+/// Parses one entry of the `conditions` array. The object form
+/// (`{"bucket": "name"}`) may hold several exact-match conditions.
+fn parse_condition(value: &Value) -> Result<Vec<Condition>> {
+    let invalid =
+        || ProxyError::InvalidRequest(format!("Invalid Policy: Invalid condition {}", value));
+
+    match value {
+        Value::Object(map) => map
+            .iter()
+            .map(|(name, v)| {
+                let v = v.as_str().ok_or_else(invalid)?;
+                Ok(Condition::Eq(
+                    name.trim_start_matches('$').to_ascii_lowercase(),
+                    v.to_string(),
+                ))
+            })
+            .collect(),
+        Value::Array(items) => {
+            let op = items
+                .first()
+                .and_then(Value::as_str)
+                .ok_or_else(invalid)?
+                .to_ascii_lowercase();
+            if items.len() != 3 {
+                return Err(invalid());
+            }
+            let condition = match op.as_str() {
+                "content-length-range" => {
+                    let bound = |v: &Value| {
+                        v.as_u64()
+                            .or_else(|| v.as_str().and_then(|s| s.parse().ok()))
+                            .ok_or_else(invalid)
+                    };
+                    Condition::ContentLengthRange(bound(&items[1])?, bound(&items[2])?)
+                }
+                "eq" | "starts-with" => {
+                    let name = items[1]
+                        .as_str()
+                        .and_then(|n| n.strip_prefix('$'))
+                        .ok_or_else(invalid)?
+                        .to_ascii_lowercase();
+                    let v = items[2].as_str().ok_or_else(invalid)?.to_string();
+                    if op == "eq" {
+                        Condition::Eq(name, v)
+                    } else {
+                        Condition::StartsWith(name, v)
+                    }
+                }
+                _ => return Err(invalid()),
+            };
+            Ok(vec![condition])
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(policy: &str) -> String {
+        base64::engine::general_purpose::STANDARD.encode(policy)
+    }
+
+    /// Shaped like the output of boto3's `generate_presigned_post` with a
+    /// `starts-with` key prefix and a size range added by the caller.
+    fn sdk_policy(expiration: &str) -> String {
+        encode(&format!(
+            r#"{{"expiration": "{}", "conditions": [{{"acl": "private"}}, ["starts-with", "$key", "uploads/"], ["content-length-range", 1, 1048576], {{"bucket": "test-zone"}}, ["starts-with", "$key", "uploads/"], {{"x-amz-algorithm": "AWS4-HMAC-SHA256"}}, {{"x-amz-credential": "bunny/20261016/us-east-1/s3/aws4_request"}}, {{"x-amz-date": "20261016T000000Z"}}]}}"#,
+            expiration
+        ))
+    }
+
+    fn sdk_fields(key: &str) -> HashMap<String, String> {
+        [
+            ("bucket", "test-zone"),
+            ("key", key),
+            ("acl", "private"),
+            ("x-amz-algorithm", "AWS4-HMAC-SHA256"),
+            (
+                "x-amz-credential",
+                "bunny/20261016/us-east-1/s3/aws4_request",
+            ),
+            ("x-amz-date", "20261016T000000Z"),
+            ("policy", "ignored"),
+            ("x-amz-signature", "ignored"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn test_sdk_policy_conditions() {
+        let policy = PostPolicy::decode(&sdk_policy("2999-01-01T00:00:00Z")).unwrap();
+        assert_eq!(policy.content_length_range(), Some((1, 1048576)));
+        policy.check(&sdk_fields("uploads/photo.jpg")).unwrap();
+
+        let err = policy.check(&sdk_fields("other/photo.jpg")).unwrap_err();
+        assert!(err.to_string().contains("$key"));
+
+        let mut fields = sdk_fields("uploads/photo.jpg");
+        fields.insert("bucket".into(), "other-zone".into());
+        assert!(policy.check(&fields).is_err());
+
+        let mut fields = sdk_fields("uploads/photo.jpg");
+        fields.insert("content-type".into(), "image/jpeg".into());
+        let err = policy.check(&fields).unwrap_err();
+        assert!(err.to_string().contains("Extra input fields: content-type"));
+
+        let mut fields = sdk_fields("uploads/photo.jpg");
+        fields.insert("x-ignore-tracking".into(), "1".into());
+        policy.check(&fields).unwrap();
+    }
+
+    #[test]
+    fn test_expired_policy_is_rejected() {
+        let policy = PostPolicy::decode(&sdk_policy("2000-01-01T00:00:00.000Z")).unwrap();
+        let err = policy.check(&sdk_fields("uploads/photo.jpg")).unwrap_err();
+        assert_eq!(err.s3_error_code(), "AccessDenied");
+        assert!(err.to_string().contains("Policy expired"));
+    }
+
+    #[test]
+    fn test_malformed_policy() {
+        assert!(PostPolicy::decode("not base64!").is_err());
+        assert!(PostPolicy::decode(&encode(r#"{"conditions": []}"#)).is_err());
+        assert!(
+            PostPolicy::decode(&encode(
+                r#"{"expiration": "2999-01-01T00:00:00Z", "conditions": [["between", "$key", "a"]]}"#
+            ))
+            .is_err()
+        );
+    }
+}
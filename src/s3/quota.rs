@@ -0,0 +1,495 @@
+//! Storage quotas from `--quota-config`. Bunny has no quotas of its own, so
+//! the proxy keeps an approximate usage figure for each configured prefix
+//! and refuses writes that would take it over the limit:
+//!
+//! ```toml
+//! [[quota]]
+//! prefix = "teams/alpha/"
+//! limit = "500GB"
+//! ```
+//!
+//! Usage is seeded by walking each prefix at startup, moved by the sizes of
+//! the writes and deletes the proxy serves, and walked again every
+//! `--quota-reconcile-interval` to correct what the proxy did not see:
+//! writes made straight to Bunny and versioned deletes. A write reserves
+//! its growth when admitted, so concurrent writes cannot all be admitted
+//! against the same figure, and gives the reservation back if it fails.
+//! A key is held to every rule whose
+//! prefix it starts with, so nested quotas all apply. Until a prefix's first
+//! walk finishes its writes are let through, and reads are never affected.
+
+use serde::{Deserialize, Deserializer};
+use std::fmt::{self, Write as _};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::bunny::StorageBackend;
+use crate::config::Config;
+use crate::error::{ProxyError, Result};
+
+use super::handlers::AppState;
+
+/// Reconciliation drift beyond this share of a rule's limit is logged as a
+/// warning rather than at info level.
+const DRIFT_WARN_RATIO: f64 = 0.01;
+
+#[derive(Debug, Deserialize)]
+struct QuotaFile {
+    #[serde(default)]
+    quota: Vec<QuotaRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct QuotaRule {
+    pub prefix: String,
+    /// Defaults to the storage zone.
+    #[serde(default)]
+    pub bucket: Option<String>,
+    /// In bytes, or a size such as `"500GB"` or `"2TiB"`.
+    #[serde(deserialize_with = "deserialize_size")]
+    pub limit: u64,
+}
+
+impl fmt::Display for QuotaRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.bucket {
+            Some(bucket) => write!(f, "{}/{}", bucket, self.prefix),
+            None => write!(f, "{}", self.prefix),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<u64, D::Error> {
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(bytes),
+        Size::Text(text) => parse_size(&text).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Parses a size in bytes with an optional decimal (`KB`, `MB`, `GB`, `TB`)
+/// or binary (`KiB`, `MiB`, `GiB`, `TiB`) unit.
+pub fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", s))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return Err(format!("unknown unit in size '{}'", s)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    bytes: AtomicU64,
+    /// Whether a walk has set `bytes`; until then the rule is not enforced.
+    seeded: AtomicBool,
+    /// What the latest walk changed `bytes` by.
+    drift: AtomicI64,
+}
+
+impl Usage {
+    /// Adds `bytes` unless that would take usage over `limit`, returning the
+    /// usage that refused it.
+    fn reserve(&self, bytes: u64, limit: u64) -> std::result::Result<(), u64> {
+        self.bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|total| *total <= limit)
+            })
+            .map(|_| ())
+    }
+
+    fn release(&self, bytes: u64) {
+        let _ = self
+            .bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some(used.saturating_sub(bytes))
+            });
+    }
+}
+
+/// A write or delete admitted against the quotas covering its key, carrying
+/// the size of the object it replaces so that only the difference counts.
+/// The growth it reserved is released when it is dropped uncommitted.
+#[derive(Debug)]
+pub struct QuotaCharge {
+    quotas: Arc<Quotas>,
+    /// Each covering rule, with what was reserved against it.
+    rules: Vec<(usize, u64)>,
+    previous: u64,
+}
+
+impl Drop for QuotaCharge {
+    fn drop(&mut self) {
+        for &(i, reserved) in &self.rules {
+            self.quotas.rules[i].1.release(reserved);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Quotas {
+    rules: Vec<(QuotaRule, Usage)>,
+    default_bucket: String,
+    interval: std::time::Duration,
+    concurrency: usize,
+    rejections: AtomicU64,
+}
+
+impl Quotas {
+    pub fn load(config: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(path) = &config.quota_config else {
+            return Ok(None);
+        };
+        let rules = Self::read(path)
+            .map_err(|e| anyhow::anyhow!("--quota-config {}: {}", path.display(), e))?;
+        Ok(Some(Self {
+            rules: rules
+                .into_iter()
+                .map(|rule| (rule, Usage::default()))
+                .collect(),
+            default_bucket: config.storage_zone.clone(),
+            interval: config
+                .quota_reconcile_interval
+                .to_std()
+                .unwrap_or_default()
+                .max(std::time::Duration::from_secs(60)),
+            concurrency: config.usage_walk_concurrency,
+            rejections: AtomicU64::new(0),
+        }))
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Vec<QuotaRule>> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn parse(text: &str) -> anyhow::Result<Vec<QuotaRule>> {
+        let file: QuotaFile = toml::from_str(text)?;
+        for (i, rule) in file.quota.iter().enumerate() {
+            if file.quota[..i]
+                .iter()
+                .any(|r| r.prefix == rule.prefix && r.bucket == rule.bucket)
+            {
+                anyhow::bail!("prefix '{}' has two quotas", rule.prefix);
+            }
+        }
+        Ok(file.quota)
+    }
+
+    fn bucket<'a>(&'a self, rule: &'a QuotaRule) -> &'a str {
+        rule.bucket.as_deref().unwrap_or(&self.default_bucket)
+    }
+
+    fn covering(&self, bucket: &str, key: &str) -> Vec<usize> {
+        self.rules
+            .iter()
+            .enumerate()
+            .filter(|(_, (rule, _))| self.bucket(rule) == bucket && key.starts_with(&rule.prefix))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Whether any quota covers `key` in `bucket`. Needs no Bunny call.
+    pub fn applies(&self, bucket: &str, key: &str) -> bool {
+        !self.covering(bucket, key).is_empty()
+    }
+
+    /// Admits writing `size` bytes to `key` in place of `previous` bytes,
+    /// reserving the growth against each seeded quota, or refuses it if
+    /// that would take one over its limit.
+    pub fn charge(
+        self: &Arc<Self>,
+        bucket: &str,
+        key: &str,
+        previous: u64,
+        size: u64,
+    ) -> Result<QuotaCharge> {
+        let growth = size.saturating_sub(previous);
+        // Built up as reservations are made, so a refusal releases those
+        // already made when it is dropped.
+        let mut charge = QuotaCharge {
+            quotas: self.clone(),
+            rules: Vec::new(),
+            previous,
+        };
+        for i in self.covering(bucket, key) {
+            let (rule, usage) = &self.rules[i];
+            if growth == 0 || !usage.seeded.load(Ordering::Relaxed) {
+                charge.rules.push((i, 0));
+                continue;
+            }
+            if let Err(used) = usage.reserve(growth, rule.limit) {
+                self.rejections.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    "Quota {}: refusing {} bytes for {}/{}, {} of {} bytes used",
+                    rule,
+                    growth,
+                    bucket,
+                    key,
+                    used,
+                    rule.limit
+                );
+                return Err(ProxyError::QuotaExceeded {
+                    prefix: rule.to_string(),
+                    limit: rule.limit,
+                });
+            }
+            charge.rules.push((i, growth));
+        }
+        Ok(charge)
+    }
+
+    /// Records that the charged key now holds `size` bytes, in place of
+    /// what the charge reserved.
+    pub fn commit(&self, mut charge: QuotaCharge, size: u64) {
+        for (i, reserved) in std::mem::take(&mut charge.rules) {
+            let usage = &self.rules[i].1;
+            let grown = size.saturating_sub(charge.previous);
+            let shrunk = charge.previous.saturating_sub(size);
+            if grown >= reserved {
+                usage.bytes.fetch_add(grown - reserved, Ordering::Relaxed);
+            } else {
+                usage.release(reserved - grown);
+            }
+            usage.release(shrunk);
+        }
+    }
+
+    /// Walks every quota's prefix and replaces its usage with the walked
+    /// total, logging how far the tracked figure had drifted. A prefix that
+    /// cannot be walked keeps its figure until the next run.
+    pub async fn reconcile(&self, state: &AppState) {
+        for (rule, usage) in &self.rules {
+            let started = Instant::now();
+            let bucket = self.bucket(rule);
+            let walked = match self.walk(state, bucket, &rule.prefix).await {
+                Ok(walked) => walked,
+                Err(e) => {
+                    tracing::warn!("Quota {}: walk failed: {}", rule, e);
+                    continue;
+                }
+            };
+            let tracked = usage.bytes.swap(walked, Ordering::Relaxed);
+            if !usage.seeded.swap(true, Ordering::Relaxed) {
+                tracing::info!(
+                    "Quota {}: {} of {} bytes used, walked in {:?}",
+                    rule,
+                    walked,
+                    rule.limit,
+                    started.elapsed()
+                );
+                continue;
+            }
+            let drift = walked as i64 - tracked as i64;
+            usage.drift.store(drift, Ordering::Relaxed);
+            if drift.unsigned_abs() as f64 > rule.limit as f64 * DRIFT_WARN_RATIO {
+                tracing::warn!(
+                    "Quota {}: tracked usage was off by {} bytes, now {} of {} bytes",
+                    rule,
+                    drift,
+                    walked,
+                    rule.limit
+                );
+            } else {
+                tracing::info!(
+                    "Quota {}: reconciled, drift {} bytes, {} of {} bytes used",
+                    rule,
+                    drift,
+                    walked,
+                    rule.limit
+                );
+            }
+        }
+    }
+
+    async fn walk(&self, state: &AppState, bucket: &str, prefix: &str) -> Result<u64> {
+        let client = state.bucket_client(bucket)?;
+        let dir = prefix.rfind('/').map(|i| &prefix[..=i]).unwrap_or("");
+        let objects = match client
+            .list_recursive_concurrent(dir, self.concurrency)
+            .await
+        {
+            Ok(objects) => objects,
+            Err(ProxyError::NotFound(_)) => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        Ok(objects
+            .iter()
+            .filter(|obj| !obj.is_directory && obj.s3_key().starts_with(prefix))
+            .map(|obj| obj.length.max(0) as u64)
+            .sum())
+    }
+
+    /// Seeds usage and reconciles it every `--quota-reconcile-interval`
+    /// until the process exits.
+    pub async fn run(state: AppState) {
+        let Some(quotas) = state.quotas.clone() else {
+            return;
+        };
+        let mut ticker = tokio::time::interval(quotas.interval);
+        loop {
+            ticker.tick().await;
+            quotas.reconcile(&state).await;
+        }
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let name = "bunny_s3_proxy_quota_rejections_total";
+        let _ = writeln!(out, "# HELP {} Writes refused for exceeding a quota.", name);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, self.rejections.load(Ordering::Relaxed));
+        for (i, (name, help)) in [
+            ("usage_bytes", "Tracked usage of the quota's prefix."),
+            ("limit_bytes", "The quota's limit."),
+            (
+                "drift_bytes",
+                "How far the latest reconciliation moved the tracked usage.",
+            ),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let name = format!("bunny_s3_proxy_quota_{}", name);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for (rule, usage) in &self.rules {
+                let value = [
+                    usage.bytes.load(Ordering::Relaxed) as i64,
+                    rule.limit as i64,
+                    usage.drift.load(Ordering::Relaxed),
+                ][i];
+                let _ = writeln!(
+                    out,
+                    "{}{{bucket=\"{}\",prefix=\"{}\"}} {}",
+                    name,
+                    self.bucket(rule),
+                    rule.prefix,
+                    value
+                );
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quotas(text: &str) -> Arc<Quotas> {
+        Arc::new(Quotas {
+            rules: Quotas::parse(text)
+                .unwrap()
+                .into_iter()
+                .map(|rule| (rule, Usage::default()))
+                .collect(),
+            default_bucket: "zone".into(),
+            interval: std::time::Duration::from_secs(3600),
+            concurrency: 1,
+            rejections: AtomicU64::new(0),
+        })
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("500GB"), Ok(500_000_000_000));
+        assert_eq!(parse_size("1.5 KiB"), Ok(1536));
+        assert_eq!(parse_size("2tib"), Ok(2 << 40));
+        assert!(parse_size("GB").is_err());
+        assert!(parse_size("3PB").is_err());
+    }
+
+    #[test]
+    fn test_nested_quotas_all_apply() {
+        let quotas = quotas(
+            r#"
+            [[quota]]
+            prefix = "teams/"
+            limit = 1000
+
+            [[quota]]
+            prefix = "teams/alpha/"
+            limit = "100B"
+            "#,
+        );
+        // Not enforced until seeded.
+        let charge = quotas.charge("zone", "teams/alpha/a", 0, 500).unwrap();
+        quotas.commit(charge, 500);
+        for (_, usage) in &quotas.rules {
+            usage.seeded.store(true, Ordering::Relaxed);
+        }
+
+        let err = quotas.charge("zone", "teams/alpha/b", 0, 1).unwrap_err();
+        assert_eq!(err.s3_error_code(), "QuotaExceeded");
+        assert!(err.to_string().contains("teams/alpha/"));
+        // Replacing an object with a smaller one always passes.
+        let charge = quotas.charge("zone", "teams/alpha/a", 500, 50).unwrap();
+        quotas.commit(charge, 50);
+        assert_eq!(quotas.rules[0].1.bytes.load(Ordering::Relaxed), 50);
+        quotas.charge("zone", "teams/alpha/b", 0, 50).unwrap();
+        quotas.charge("zone", "teams/beta/b", 0, 950).unwrap();
+        assert!(quotas.charge("zone", "teams/beta/b", 0, 951).is_err());
+        assert!(!quotas.applies("other", "teams/alpha/a"));
+        assert_eq!(quotas.rejections.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_charges_reserve_until_committed_or_dropped() {
+        let quotas = quotas("[[quota]]\nprefix = \"a/\"\nlimit = 100\n");
+        quotas.rules[0].1.seeded.store(true, Ordering::Relaxed);
+        let usage = || quotas.rules[0].1.bytes.load(Ordering::Relaxed);
+
+        // Two writes in flight cannot both be admitted against the same figure.
+        let first = quotas.charge("zone", "a/1", 0, 60).unwrap();
+        assert_eq!(usage(), 60);
+        assert!(quotas.charge("zone", "a/2", 0, 60).is_err());
+        assert_eq!(usage(), 60);
+
+        // A failed write gives its reservation back.
+        drop(first);
+        assert_eq!(usage(), 0);
+
+        // A write that stores less than it declared is charged what it stored.
+        let charge = quotas.charge("zone", "a/1", 0, 60).unwrap();
+        quotas.commit(charge, 40);
+        assert_eq!(usage(), 40);
+        let charge = quotas.charge("zone", "a/1", 40, 10).unwrap();
+        quotas.commit(charge, 10);
+        assert_eq!(usage(), 10);
+    }
+
+    #[test]
+    fn test_duplicate_rules_are_rejected() {
+        let err = Quotas::parse(
+            "[[quota]]\nprefix = \"a/\"\nlimit = 1\n[[quota]]\nprefix = \"a/\"\nlimit = 2\n",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("two quotas"));
+    }
+}
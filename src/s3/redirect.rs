@@ -0,0 +1,153 @@
+//! Redirected reads with `--redirect-reads`: GetObject answers with a 307 to
+//! the object on a Bunny pull zone instead of streaming it through the proxy.
+//!
+//! With `--redirect-token-key` the Location carries a short-lived token in the
+//! format of Bunny's token authentication, so the pull zone can stay private.
+//! Only objects whose stored bytes are the object are redirected; encrypted or
+//! compressed ones are still served by the proxy.
+
+use axum::body::Body;
+use axum::http::{StatusCode, header};
+use axum::response::Response;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use crate::bunny::client::encode_path;
+use crate::config::Config;
+
+/// Which GETs are redirected, and where to.
+pub struct ReadRedirect {
+    base_url: String,
+    token_key: Option<String>,
+    ttl_secs: u64,
+    min_size: u64,
+    prefixes: Vec<String>,
+}
+
+impl ReadRedirect {
+    pub fn new(config: &Config) -> Option<Self> {
+        if !config.redirect_reads {
+            return None;
+        }
+        Some(Self {
+            base_url: config
+                .redirect_base_url
+                .clone()?
+                .trim_end_matches('/')
+                .to_string(),
+            token_key: config.redirect_token_key.clone(),
+            ttl_secs: config.redirect_ttl_secs,
+            min_size: config.redirect_min_size,
+            prefixes: config.redirect_prefix.clone(),
+        })
+    }
+
+    /// Whether a GET of `key` may be redirected, before its size is known.
+    pub fn applies_to(&self, key: &str) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|p| key.starts_with(p.as_str()))
+    }
+
+    /// Whether an object of `size` bytes is large enough to be redirected.
+    pub fn applies_to_size(&self, size: u64) -> bool {
+        size >= self.min_size
+    }
+
+    /// The URL of the zone-relative `path`, signed over its encoded form to
+    /// expire `ttl_secs` after `now` when a token key is configured.
+    pub fn location(&self, path: &str, now: i64) -> String {
+        let path = format!("/{}", encode_path(path.trim_start_matches('/')));
+        match &self.token_key {
+            Some(key) => {
+                let expires = now + self.ttl_secs as i64;
+                format!(
+                    "{}{}?token={}&expires={}",
+                    self.base_url,
+                    path,
+                    sign(key, &path, expires),
+                    expires
+                )
+            }
+            None => format!("{}{}", self.base_url, path),
+        }
+    }
+
+    /// The redirect response for the zone-relative `path`. The client repeats
+    /// its own Range header against the Location, so partial reads are served
+    /// by the CDN.
+    pub fn response(&self, path: &str) -> Response {
+        Response::builder()
+            .status(StatusCode::TEMPORARY_REDIRECT)
+            .header(
+                header::LOCATION,
+                self.location(path, chrono::Utc::now().timestamp()),
+            )
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(Body::empty())
+            .unwrap()
+    }
+}
+
+/// Bunny's SHA-256 token: the URL-safe, unpadded base64 of
+/// `sha256(key + path + expires)`.
+fn sign(key: &str, path: &str, expires: i64) -> String {
+    let digest = Sha256::digest(format!("{}{}{}", key, path, expires));
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn redirect(extra: &[&str]) -> Option<ReadRedirect> {
+        let base = ["bunny-s3-proxy", "-z", "zone", "-k", "key"];
+        ReadRedirect::new(&Config::parse_from(base.iter().chain(extra)))
+    }
+
+    #[test]
+    fn test_signed_location() {
+        let redirect = redirect(&[
+            "--redirect-reads",
+            "--redirect-base-url",
+            "https://files.b-cdn.net/",
+            "--redirect-token-key",
+            "secret",
+            "--redirect-ttl-secs",
+            "60",
+        ])
+        .unwrap();
+        let expected = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(Sha256::digest("secret/a/b%20c.txt1060"));
+        assert_eq!(
+            redirect.location("a/b c.txt", 1000),
+            format!(
+                "https://files.b-cdn.net/a/b%20c.txt?token={}&expires=1060",
+                expected
+            )
+        );
+        assert!(!expected.contains(['+', '/', '=']));
+    }
+
+    #[test]
+    fn test_redirect_filters() {
+        assert!(redirect(&[]).is_none());
+        let open = redirect(&["--redirect-reads", "--redirect-base-url", "https://cdn"]).unwrap();
+        assert_eq!(open.location("/x", 0), "https://cdn/x");
+        assert!(open.applies_to("anything") && open.applies_to_size(0));
+
+        let filtered = redirect(&[
+            "--redirect-reads",
+            "--redirect-base-url",
+            "https://cdn",
+            "--redirect-min-size",
+            "1024",
+            "--redirect-prefix",
+            "videos/,images/",
+        ])
+        .unwrap();
+        assert!(filtered.applies_to("images/cat.jpg"));
+        assert!(!filtered.applies_to("docs/readme"));
+        assert!(filtered.applies_to_size(1024));
+        assert!(!filtered.applies_to_size(1023));
+    }
+}
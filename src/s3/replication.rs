@@ -0,0 +1,301 @@
+//! Dual-write to a shadow storage zone with `--shadow-zone`, for migrating
+//! between zones without a window of lost writes.
+//!
+//! Every change is replicated by copying the key's current state from the
+//! primary: its bytes and metadata sidecar, or its absence. Jobs for one key
+//! always run on the same worker, so the last job for a key leaves the shadow
+//! matching the primary however the writes interleaved.
+
+use chrono::Utc;
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::bunny::pool::PoolOptions;
+use crate::bunny::{Backend, BunnyClient, StorageBackend};
+use crate::config::{Config, StorageZoneConfig};
+use crate::error::{ProxyError, Result};
+
+use super::object_meta::ObjectMetaStore;
+
+/// Workers replicating concurrently; each owns a share of the queue.
+const REPLICATION_WORKERS: usize = 4;
+
+/// Attempts made for one key before it is written to the dead-letter log.
+const MAX_ATTEMPTS: u32 = 5;
+
+struct Job {
+    primary: Backend,
+    key: String,
+    queued_at: Instant,
+}
+
+/// A key that could not be replicated, as written to the dead-letter log.
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    timestamp: String,
+    key: &'a str,
+    root: &'a str,
+    error: String,
+}
+
+#[derive(Debug, Default)]
+struct ReplicationStats {
+    replicated: AtomicU64,
+    dead_lettered: AtomicU64,
+    lag_ms: AtomicU64,
+}
+
+pub struct Replicator {
+    shadow: Backend,
+    strict: bool,
+    queues: Vec<mpsc::Sender<Job>>,
+    dead_letter_path: Option<PathBuf>,
+    stats: Arc<ReplicationStats>,
+}
+
+impl Replicator {
+    /// Starts the replication workers if a shadow zone is configured.
+    pub fn new(config: &Config) -> Option<Arc<Self>> {
+        let zone = config.shadow_zone.clone()?;
+        // The shadow is always a Bunny zone, whatever --backend says.
+        let shadow = Backend::Bunny(BunnyClient::new(StorageZoneConfig {
+            name: zone.clone(),
+            access_key: config.shadow_key.clone().unwrap_or_default(),
+            region: config.shadow_region.unwrap_or(config.region),
+            api_key: None,
+            endpoint: None,
+            pool: PoolOptions::from(config),
+        }));
+        let mut receivers = Vec::new();
+        let queues = (0..REPLICATION_WORKERS)
+            .map(|_| {
+                let (tx, rx) =
+                    mpsc::channel((config.shadow_queue_size / REPLICATION_WORKERS).max(1));
+                receivers.push(rx);
+                tx
+            })
+            .collect();
+        let replicator = Arc::new(Self {
+            shadow,
+            strict: config.shadow_strict,
+            queues,
+            dead_letter_path: config.shadow_dead_letter_path.clone(),
+            stats: Arc::default(),
+        });
+        for rx in receivers {
+            tokio::spawn(Arc::clone(&replicator).work(rx));
+        }
+        tracing::info!(
+            "Replicating writes to shadow zone {}{}",
+            zone,
+            if config.shadow_strict {
+                " (strict)"
+            } else {
+                ""
+            }
+        );
+        Some(replicator)
+    }
+
+    pub fn shadow(&self) -> &Backend {
+        &self.shadow
+    }
+
+    /// Replicates a change to `key`, made through `primary`. Under
+    /// `--shadow-strict` this waits and fails the request if the shadow
+    /// cannot be updated; otherwise the key is queued.
+    pub async fn replicate(&self, primary: &Backend, key: &str) -> Result<()> {
+        if self.strict {
+            return match self.sync_with_retries(primary, key).await {
+                Ok(()) => {
+                    self.stats.replicated.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }
+                Err(e) => {
+                    self.dead_letter(primary, key, e.to_string()).await;
+                    Err(ProxyError::BunnyApi(format!(
+                        "Shadow zone replication failed: {}",
+                        e
+                    )))
+                }
+            };
+        }
+
+        let job = Job {
+            primary: primary.clone(),
+            key: key.to_string(),
+            queued_at: Instant::now(),
+        };
+        if let Err(e) = self.queues[shard(primary.root(), key)].try_send(job) {
+            self.dead_letter(primary, key, format!("not queued: {}", e))
+                .await;
+        }
+        Ok(())
+    }
+
+    async fn work(self: Arc<Self>, mut rx: mpsc::Receiver<Job>) {
+        while let Some(job) = rx.recv().await {
+            match self.sync_with_retries(&job.primary, &job.key).await {
+                Ok(()) => {
+                    self.stats.replicated.fetch_add(1, Ordering::Relaxed);
+                    self.stats.lag_ms.store(
+                        job.queued_at.elapsed().as_millis() as u64,
+                        Ordering::Relaxed,
+                    );
+                }
+                Err(e) => {
+                    self.dead_letter(&job.primary, &job.key, e.to_string())
+                        .await
+                }
+            }
+        }
+    }
+
+    async fn sync_with_retries(&self, primary: &Backend, key: &str) -> Result<()> {
+        let shadow = self.shadow.scoped(primary.root());
+        let mut attempt = 1;
+        loop {
+            match sync_key(primary, &shadow, key).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < MAX_ATTEMPTS => {
+                    tracing::warn!(
+                        "Replicating {} to the shadow zone failed (attempt {}): {}",
+                        key,
+                        attempt,
+                        e
+                    );
+                    tokio::time::sleep(Duration::from_millis(250 << attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn dead_letter(&self, primary: &Backend, key: &str, error: String) {
+        self.stats.dead_lettered.fetch_add(1, Ordering::Relaxed);
+        tracing::error!(
+            "Giving up replicating {} to the shadow zone: {}",
+            key,
+            error
+        );
+        let Some(path) = &self.dead_letter_path else {
+            return;
+        };
+        let record = DeadLetter {
+            timestamp: Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            key,
+            root: primary.root(),
+            error,
+        };
+        let mut line = serde_json::to_vec(&record).unwrap_or_default();
+        line.push(b'\n');
+        let written = async {
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?
+                .write_all(&line)
+                .await
+        };
+        if let Err(e) = written.await {
+            tracing::error!(
+                "Failed to write replication dead letter to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    /// Replication counters and gauges in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        for (name, help, counter) in [
+            (
+                "replicated",
+                "Keys copied to the shadow zone.",
+                &self.stats.replicated,
+            ),
+            (
+                "dead_lettered",
+                "Keys that could not be replicated to the shadow zone or queued.",
+                &self.stats.dead_lettered,
+            ),
+        ] {
+            let name = format!("bunny_s3_proxy_replication_{}_total", name);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        let depth: usize = self
+            .queues
+            .iter()
+            .map(|q| q.max_capacity() - q.capacity())
+            .sum();
+        let lag = self.stats.lag_ms.load(Ordering::Relaxed) as f64 / 1000.0;
+        for (name, help, value) in [
+            (
+                "bunny_s3_proxy_replication_queue_depth",
+                "Keys waiting to be replicated to the shadow zone.",
+                depth as f64,
+            ),
+            (
+                "bunny_s3_proxy_replication_lag_seconds",
+                "Time between queueing and replicating the most recently replicated key.",
+                lag,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+/// The worker a key is replicated by.
+fn shard(root: &str, key: &str) -> usize {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    (root, key).hash(&mut hasher);
+    hasher.finish() as usize % REPLICATION_WORKERS
+}
+
+/// Makes `key` in the shadow match the primary, re-streaming its bytes.
+async fn sync_key(primary: &Backend, shadow: &Backend, key: &str) -> Result<()> {
+    match primary.download(key).await {
+        Ok(download) => {
+            let length = download.content_length();
+            let content_type = download.content_type().map(str::to_string);
+            let stream = download.bytes_stream();
+            shadow
+                .upload_stream(key, stream, length, content_type.as_deref())
+                .await?;
+        }
+        Err(ProxyError::NotFound(_)) => shadow.delete(key).await?,
+        Err(e) => return Err(e),
+    }
+    let meta = ObjectMetaStore::get(primary, key).await?;
+    ObjectMetaStore::put(shadow, key, &meta).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shard_is_stable_per_key() {
+        assert_eq!(shard("", "a/b.txt"), shard("", "a/b.txt"));
+        let shards: std::collections::HashSet<_> =
+            (0..100).map(|i| shard("", &format!("key-{}", i))).collect();
+        assert_eq!(shards.len(), REPLICATION_WORKERS);
+        assert!(shards.iter().all(|s| *s < REPLICATION_WORKERS));
+    }
+}
@@ -0,0 +1,119 @@
+//! gzip/deflate compression of the proxy's own XML documents, negotiated via
+//! Accept-Encoding. Listings of big prefixes run to megabytes and compress
+//! well, but object bytes must reach the client untouched, and the keepalive
+//! stream of CompleteMultipartUpload must not be buffered by an encoder. Only
+//! responses marked with [`compressible`] are therefore considered.
+
+use axum::body::HttpBody;
+use axum::http;
+use axum::response::Response;
+use tower_http::compression::CompressionLayer;
+use tower_http::compression::predicate::{Predicate, SizeAbove};
+
+/// Bodies smaller than this gain too little to be worth encoding.
+const MIN_SIZE: u16 = 256;
+
+/// Response extension marking a body the proxy generated itself.
+#[derive(Debug, Clone, Copy)]
+struct Compressible;
+
+/// Marks `response` as safe to compress.
+pub fn compressible(mut response: Response) -> Response {
+    response.extensions_mut().insert(Compressible);
+    response
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MarkedOnly;
+
+impl Predicate for MarkedOnly {
+    fn should_compress<B>(&self, response: &http::Response<B>) -> bool
+    where
+        B: HttpBody,
+    {
+        response.extensions().get::<Compressible>().is_some()
+    }
+}
+
+/// Compresses marked responses of at least [`MIN_SIZE`] bytes.
+pub fn layer() -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new()
+        .gzip(true)
+        .deflate(true)
+        .compress_when(MarkedOnly.and(SizeAbove::new(MIN_SIZE)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::body::Body;
+    use axum::http::{Request, header};
+    use axum::response::IntoResponse;
+    use axum::routing::get;
+    use std::io::Read;
+    use tower::ServiceExt;
+
+    fn listing() -> String {
+        let keys: String = (0..200)
+            .map(|i| format!("<Contents><Key>photos/{}.jpg</Key></Contents>", i))
+            .collect();
+        format!("<ListBucketResult>{}</ListBucketResult>", keys)
+    }
+
+    fn app() -> Router {
+        let xml = || ([(header::CONTENT_TYPE, "application/xml")], listing());
+        Router::new()
+            .route(
+                "/list",
+                get(move || async move { compressible(xml().into_response()) }),
+            )
+            .route("/object", get(move || async move { xml().into_response() }))
+            .layer(layer())
+    }
+
+    async fn fetch(path: &str, accept: &str) -> (http::HeaderMap, Vec<u8>) {
+        let request = Request::get(path)
+            .header(header::ACCEPT_ENCODING, accept)
+            .body(Body::empty())
+            .unwrap();
+        let response = app().oneshot(request).await.unwrap();
+        let headers = response.headers().clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (headers, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_marked_responses_round_trip() {
+        let (headers, body) = fetch("/list", "gzip").await;
+        assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+        assert!(headers.get(header::CONTENT_LENGTH).is_none());
+        assert!(body.len() < listing().len() / 5);
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, listing());
+
+        let (headers, body) = fetch("/list", "deflate").await;
+        assert_eq!(headers[header::CONTENT_ENCODING], "deflate");
+        let mut decoded = String::new();
+        flate2::read::ZlibDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, listing());
+
+        let (headers, body) = fetch("/list", "identity").await;
+        assert!(headers.get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(body, listing().into_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_unmarked_responses_pass_through() {
+        let (headers, body) = fetch("/object", "gzip, deflate").await;
+        assert!(headers.get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(body, listing().into_bytes());
+    }
+}
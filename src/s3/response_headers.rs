@@ -0,0 +1,227 @@
+//! Static headers from `--response-headers-config`, added to every S3
+//! response, errors and streamed bodies included, for policies such as HSTS
+//! that would otherwise need another reverse proxy in front:
+//!
+//! ```toml
+//! [response-headers]
+//! "Strict-Transport-Security" = "max-age=63072000"
+//! "X-Frame-Options" = "DENY"
+//! "X-Served-By" = "bunny-s3-proxy/{version} {request_id}"
+//! ```
+//!
+//! `{request_id}` is replaced by the response's `x-amz-request-id` and
+//! `{version}` by the proxy's version. Headers the proxy sets to describe
+//! the body or the S3 result cannot be configured.
+
+use axum::Router;
+use axum::http::{HeaderName, HeaderValue};
+use axum::response::Response;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Headers the proxy controls, besides every `x-amz-*` header.
+const PROTECTED: &[&str] = &[
+    "accept-ranges",
+    "connection",
+    "content-encoding",
+    "content-length",
+    "content-range",
+    "content-type",
+    "etag",
+    "last-modified",
+    "transfer-encoding",
+];
+
+const REQUEST_ID: &str = "{request_id}";
+const VERSION: &str = "{version}";
+
+#[derive(Debug, Deserialize)]
+struct ResponseHeadersFile {
+    #[serde(rename = "response-headers", default)]
+    response_headers: BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+enum Template {
+    Static(HeaderValue),
+    /// Contains `{request_id}`, so is rendered per response.
+    PerRequest(String),
+}
+
+#[derive(Debug)]
+pub struct ResponseHeaders {
+    headers: Vec<(HeaderName, Template)>,
+}
+
+impl ResponseHeaders {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("--response-headers-config {}: {}", path.display(), e))?;
+        Self::parse(&text)
+            .map_err(|e| anyhow::anyhow!("--response-headers-config {}: {}", path.display(), e))
+    }
+
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let file: ResponseHeadersFile = toml::from_str(text)?;
+        let mut headers = Vec::new();
+        for (name, value) in file.response_headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| anyhow::anyhow!("invalid header name '{}'", name))?;
+            if PROTECTED.contains(&name.as_str()) || name.as_str().starts_with("x-amz-") {
+                anyhow::bail!("{} is set by the proxy and cannot be configured", name);
+            }
+            let value = value.replace(VERSION, env!("CARGO_PKG_VERSION"));
+            // Request IDs are header-safe, so the value is checked without.
+            let rest = value.replace(REQUEST_ID, "");
+            if let Some(open) = rest.find('{')
+                && rest[open..].contains('}')
+            {
+                anyhow::bail!(
+                    "{}: unknown substitution in '{}' (use {} or {})",
+                    name,
+                    value,
+                    REQUEST_ID,
+                    VERSION
+                );
+            }
+            let checked = HeaderValue::from_str(&rest)
+                .map_err(|_| anyhow::anyhow!("{}: invalid header value '{}'", name, value))?;
+            let template = match value.contains(REQUEST_ID) {
+                true => Template::PerRequest(value),
+                false => Template::Static(checked),
+            };
+            headers.push((name, template));
+        }
+        Ok(Self { headers })
+    }
+
+    /// Sets the configured headers on `response`, replacing any it has.
+    pub fn apply(&self, response: &mut Response) {
+        let request_id = response
+            .headers()
+            .get("x-amz-request-id")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        for (name, template) in &self.headers {
+            let value = match template {
+                Template::Static(value) => value.clone(),
+                Template::PerRequest(value) => {
+                    match HeaderValue::from_str(&value.replace(REQUEST_ID, &request_id)) {
+                        Ok(value) => value,
+                        Err(_) => continue,
+                    }
+                }
+            };
+            response.headers_mut().insert(name.clone(), value);
+        }
+    }
+}
+
+/// Adds the configured headers to every response `router` sends.
+pub fn layer<S>(router: Router<S>, headers: Arc<ResponseHeaders>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    router.layer(axum::middleware::map_response(
+        move |mut response: Response| {
+            let headers = Arc::clone(&headers);
+            async move {
+                headers.apply(&mut response);
+                response
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use axum::response::IntoResponse;
+    use axum::routing::get;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_config_validation() {
+        let parse = |body: &str| ResponseHeaders::parse(&format!("[response-headers]\n{}", body));
+        assert!(parse("\"X-Frame-Options\" = \"DENY\"").is_ok());
+        assert!(ResponseHeaders::parse("").unwrap().headers.is_empty());
+
+        for (body, expected) in [
+            ("\"Bad Name\" = \"x\"", "invalid header name"),
+            ("\"X-Bad\" = \"line\\nbreak\"", "invalid header value"),
+            ("\"ETag\" = \"x\"", "set by the proxy"),
+            ("\"X-Amz-Request-Id\" = \"x\"", "set by the proxy"),
+            ("\"X-Who\" = \"{user}\"", "unknown substitution"),
+        ] {
+            let err = parse(body).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: {}", body, err);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_headers_reach_errors_and_streams() {
+        let headers = ResponseHeaders::parse(
+            r#"
+            [response-headers]
+            "Strict-Transport-Security" = "max-age=63072000"
+            "X-Served-By" = "proxy/{version} {request_id}"
+            "X-Frame-Options" = "DENY"
+            "#,
+        )
+        .unwrap();
+        let app = Router::new()
+            .route(
+                "/error",
+                get(|| async {
+                    (
+                        StatusCode::NOT_FOUND,
+                        [
+                            ("x-amz-request-id", "req-1"),
+                            ("x-frame-options", "SAMEORIGIN"),
+                        ],
+                        "<Error/>",
+                    )
+                        .into_response()
+                }),
+            )
+            .route(
+                "/stream",
+                get(|| async {
+                    let chunks = futures::stream::iter([Ok::<_, std::io::Error>("a"), Ok("b")]);
+                    Body::from_stream(chunks).into_response()
+                }),
+            );
+        let app = layer(app, Arc::new(headers));
+
+        let fetch = |path: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = Request::get(path).body(Body::empty()).unwrap();
+                app.oneshot(request).await.unwrap()
+            }
+        };
+        let error = fetch("/error").await;
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            error.headers()["strict-transport-security"],
+            "max-age=63072000"
+        );
+        assert_eq!(error.headers()["x-frame-options"], "DENY");
+        assert_eq!(
+            error.headers()["x-served-by"],
+            format!("proxy/{} req-1", env!("CARGO_PKG_VERSION")).as_str()
+        );
+
+        let stream = fetch("/stream").await;
+        assert_eq!(stream.headers()["x-frame-options"], "DENY");
+        let body = axum::body::to_bytes(stream.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"ab");
+    }
+}
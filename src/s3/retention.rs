@@ -0,0 +1,140 @@
+//! Write-once retention windows from `--retention-config`. Bunny has no
+//! object lock, so the proxy refuses to overwrite or delete an object whose
+//! age, from Bunny's DateCreated, is inside the window of the rule covering
+//! its key:
+//!
+//! ```toml
+//! [[retention]]
+//! prefix = "audit/"
+//! days = 90
+//! ```
+//!
+//! As with access rules, the longest matching prefix decides and a rule
+//! naming the key's bucket beats one without at equal length; `days = 0`
+//! exempts a prefix inside a protected one.
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug, Deserialize)]
+struct RetentionFile {
+    #[serde(default)]
+    retention: Vec<RetentionRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RetentionRule {
+    pub prefix: String,
+    #[serde(default)]
+    pub bucket: Option<String>,
+    pub days: u32,
+}
+
+impl RetentionRule {
+    /// When an object created at `created` leaves the window.
+    pub fn expires(&self, created: DateTime<Utc>) -> DateTime<Utc> {
+        created + Duration::days(self.days as i64)
+    }
+}
+
+impl fmt::Display for RetentionRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.bucket {
+            Some(bucket) => write!(f, "{}/{}", bucket, self.prefix)?,
+            None => write!(f, "{}", self.prefix)?,
+        }
+        write!(f, " ({} days)", self.days)
+    }
+}
+
+#[derive(Debug)]
+pub struct RetentionRules {
+    rules: Vec<RetentionRule>,
+}
+
+impl RetentionRules {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("--retention-config {}: {}", path.display(), e))?;
+        Self::parse(&text)
+            .map_err(|e| anyhow::anyhow!("--retention-config {}: {}", path.display(), e))
+    }
+
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let file: RetentionFile = toml::from_str(text)?;
+        for (i, rule) in file.retention.iter().enumerate() {
+            if file.retention[..i]
+                .iter()
+                .any(|r| r.prefix == rule.prefix && r.bucket == rule.bucket)
+            {
+                anyhow::bail!("prefix '{}' has two rules", rule.prefix);
+            }
+        }
+        Ok(Self {
+            rules: file.retention,
+        })
+    }
+
+    /// The rule protecting `key` in `bucket`, if any. Needs no Bunny call,
+    /// so unprotected keys cost nothing.
+    pub fn rule_for(&self, bucket: &str, key: &str) -> Option<&RetentionRule> {
+        self.rules
+            .iter()
+            .filter(|r| r.bucket.as_deref().is_none_or(|b| b == bucket))
+            .filter(|r| key.starts_with(&r.prefix))
+            .max_by_key(|r| (r.prefix.len(), r.bucket.is_some()))
+            .filter(|r| r.days > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_selection() {
+        let rules = RetentionRules::parse(
+            r#"
+            [[retention]]
+            prefix = "audit/"
+            days = 90
+
+            [[retention]]
+            prefix = "audit/scratch/"
+            days = 0
+
+            [[retention]]
+            bucket = "logs"
+            prefix = "audit/"
+            days = 365
+            "#,
+        )
+        .unwrap();
+        assert_eq!(rules.rule_for("zone", "audit/2024.log").unwrap().days, 90);
+        assert_eq!(rules.rule_for("logs", "audit/2024.log").unwrap().days, 365);
+        assert!(rules.rule_for("zone", "audit/scratch/tmp").is_none());
+        assert!(rules.rule_for("zone", "auditing.txt").is_none());
+        assert_eq!(
+            rules.rule_for("logs", "audit/a").unwrap().to_string(),
+            "logs/audit/ (365 days)"
+        );
+
+        let created = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            rules.rule_for("zone", "audit/a").unwrap().expires(created),
+            created + Duration::days(90)
+        );
+
+        assert!(RetentionRules::parse("[[retention]]\nprefix = \"a/\"\ndays = -1").is_err());
+        assert!(
+            RetentionRules::parse(
+                "[[retention]]\nprefix = \"a/\"\ndays = 1\n[[retention]]\nprefix = \"a/\"\ndays = 2"
+            )
+            .is_err()
+        );
+    }
+}
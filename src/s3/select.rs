@@ -0,0 +1,1750 @@
+//! SelectObjectContent over CSV and JSON objects: a small subset of S3
+//! Select's SQL, evaluated while the object streams through, with results in
+//! the event-stream framing the SDKs decode.
+//!
+//! Supported are `SELECT *` or a list of columns (`_1`, header names with
+//! `FileHeaderInfo` `USE`, or JSON paths like `s.a.b`), `FROM S3Object` with
+//! an optional alias, a `WHERE` of comparisons joined by `AND`, `OR` and
+//! `NOT`, and `LIMIT`. Functions, aggregates, `LIKE` and the rest are refused
+//! with S3's error codes rather than evaluated approximately. CSV fields
+//! compare as numbers against numeric literals, and quoted record delimiters
+//! are always allowed.
+
+use async_compression::tokio::bufread::GzipDecoder;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Stream, StreamExt, TryStreamExt};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::Result as Io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::error::{ProxyError, Result};
+
+use super::types::{CsvInput, CsvOutput, SelectObjectContentRequest};
+
+/// Records are sent once this much output has accumulated.
+const RECORDS_EVENT_BYTES: usize = 64 * 1024;
+
+/// Words that cannot name a column or alias without quotes.
+const RESERVED: &[&str] = &[
+    "SELECT", "FROM", "WHERE", "LIMIT", "AND", "OR", "NOT", "AS", "IS", "NULL", "MISSING", "TRUE",
+    "FALSE", "LIKE", "IN", "BETWEEN", "GROUP", "ORDER", "HAVING", "JOIN", "UNION", "DISTINCT",
+    "CASE",
+];
+
+/// Longest first, so `<=` is not read as `<`.
+const SYMBOLS: &[&str] = &[
+    "<=", ">=", "<>", "!=", "||", "=", "<", ">", "*", ",", ".", "(", ")", "[", "]", "+", "-", "/",
+    "%",
+];
+
+fn error(code: &'static str, message: impl Into<String>) -> ProxyError {
+    ProxyError::InvalidSelect {
+        code,
+        message: message.into(),
+    }
+}
+
+fn unsupported(what: &str) -> ProxyError {
+    error(
+        "UnsupportedSqlOperation",
+        format!("{} is not supported", what),
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    /// A `"quoted"` identifier.
+    Quoted(String),
+    /// A `'string'` literal.
+    Str(String),
+    Num(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ident(w) | Self::Num(w) => write!(f, "{}", w),
+            Self::Quoted(w) => write!(f, "\"{}\"", w),
+            Self::Str(s) => write!(f, "'{}'", s),
+            Self::Symbol(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    let bytes = sql.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        match bytes[i] {
+            b if b.is_ascii_whitespace() => i += 1,
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                tokens.push(Token::Ident(sql[start..i].to_string()));
+            }
+            b'0'..=b'9' => {
+                while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'.') {
+                    i += 1;
+                }
+                tokens.push(Token::Num(sql[start..i].to_string()));
+            }
+            quote @ (b'\'' | b'"') => {
+                // A doubled quote stands for itself
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    let Some(end) = sql[i..].find(quote as char) else {
+                        return Err(error(
+                            "LexerInvalidLiteral",
+                            format!("Unterminated literal: {}", &sql[start..]),
+                        ));
+                    };
+                    text.push_str(&sql[i..i + end]);
+                    i += end + 1;
+                    if bytes.get(i) != Some(&quote) {
+                        break;
+                    }
+                    text.push(quote as char);
+                    i += 1;
+                }
+                tokens.push(match quote {
+                    b'\'' => Token::Str(text),
+                    _ => Token::Quoted(text),
+                });
+            }
+            _ => {
+                let symbol = SYMBOLS
+                    .iter()
+                    .find(|s| sql[i..].starts_with(**s))
+                    .ok_or_else(|| {
+                        let c = sql[i..].chars().next().unwrap_or_default();
+                        error("LexerInvalidChar", format!("Invalid character {}", c))
+                    })?;
+                i += symbol.len();
+                tokens.push(Token::Symbol(symbol));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Column {
+    /// Path into the record, without the table alias.
+    path: Vec<String>,
+    /// Name in JSON output.
+    name: String,
+}
+
+impl Column {
+    /// The 1-based position of a `_N` column.
+    fn index(&self) -> Option<usize> {
+        match self.path.as_slice() {
+            [name] => name.strip_prefix('_')?.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Column(Column),
+    Literal(Literal),
+}
+
+impl Operand {
+    fn column(&self) -> Option<&Column> {
+        match self {
+            Self::Column(c) => Some(c),
+            Self::Literal(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Self::Eq => ordering == Ordering::Equal,
+            Self::Ne => ordering != Ordering::Equal,
+            Self::Lt => ordering == Ordering::Less,
+            Self::Le => ordering != Ordering::Greater,
+            Self::Gt => ordering == Ordering::Greater,
+            Self::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Operand, Op, Operand),
+    /// `IS [NOT] NULL`, also written `MISSING`.
+    IsNull(Operand, bool),
+}
+
+impl Expr {
+    fn columns<'a>(&'a self, out: &mut Vec<&'a Column>) {
+        match self {
+            Self::And(a, b) | Self::Or(a, b) => {
+                a.columns(out);
+                b.columns(out);
+            }
+            Self::Not(e) => e.columns(out),
+            Self::Compare(a, _, b) => out.extend([a, b].into_iter().filter_map(Operand::column)),
+            Self::IsNull(o, _) => out.extend(o.column()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Query {
+    /// `None` for `SELECT *`.
+    columns: Option<Vec<Column>>,
+    /// `FROM S3Object[*]`: top-level JSON arrays hold the records.
+    flatten: bool,
+    filter: Option<Expr>,
+    limit: Option<u64>,
+}
+
+impl Query {
+    fn parse(sql: &str) -> Result<Self> {
+        let mut p = Parser {
+            tokens: tokenize(sql)?,
+            pos: 0,
+            alias: None,
+        };
+        p.expect_keyword("SELECT")?;
+        if p.is_keyword("DISTINCT") {
+            return Err(unsupported("DISTINCT"));
+        }
+        let items = match p.eat_symbol("*") {
+            true => None,
+            false => {
+                let mut items = Vec::new();
+                loop {
+                    let path = p.path()?;
+                    p.refuse_operator()?;
+                    let name = match p.eat_keyword("AS") {
+                        true => Some(p.name()?),
+                        false => None,
+                    };
+                    items.push((path, name));
+                    if !p.eat_symbol(",") {
+                        break;
+                    }
+                }
+                Some(items)
+            }
+        };
+        p.expect_keyword("FROM")?;
+        let flatten = p.from()?;
+        let columns = items.map(|items| {
+            items
+                .into_iter()
+                .map(|(path, name)| p.column(path, name))
+                .collect()
+        });
+        let filter = match p.eat_keyword("WHERE") {
+            true => Some(p.or()?),
+            false => None,
+        };
+        let limit = match p.eat_keyword("LIMIT") {
+            true => Some(p.limit()?),
+            false => None,
+        };
+        if let Some(token) = p.peek() {
+            return Err(match token {
+                Token::Ident(w)
+                    if ["GROUP", "ORDER", "HAVING", "UNION", "JOIN"]
+                        .iter()
+                        .any(|k| w.eq_ignore_ascii_case(k)) =>
+                {
+                    error(
+                        "UnsupportedSqlStructure",
+                        format!("{} is not supported", w.to_uppercase()),
+                    )
+                }
+                t => error(
+                    "ParseUnexpectedToken",
+                    format!("Unexpected {} after the end of the query", t),
+                ),
+            });
+        }
+        Ok(Self {
+            columns,
+            flatten,
+            filter,
+            limit,
+        })
+    }
+
+    fn referenced_columns(&self) -> Vec<&Column> {
+        let mut out: Vec<&Column> = self.columns.iter().flatten().collect();
+        if let Some(filter) = &self.filter {
+            filter.columns(&mut out);
+        }
+        out
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    alias: Option<String>,
+}
+
+fn unexpected(found: Option<&Token>, expected: &str) -> ProxyError {
+    let message = match found {
+        Some(t) => format!("Expected {} but found {}", expected, t),
+        None => format!("Expected {} but the query ended", expected),
+    };
+    error("ParseUnexpectedToken", message)
+}
+
+fn is_reserved(word: &str) -> bool {
+    RESERVED.iter().any(|r| word.eq_ignore_ascii_case(r))
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        match self.eat_keyword(keyword) {
+            true => Ok(()),
+            false => Err(unexpected(self.peek(), keyword)),
+        }
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol)
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let found = self.is_symbol(symbol);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<()> {
+        match self.eat_symbol(symbol) {
+            true => Ok(()),
+            false => Err(unexpected(self.peek(), symbol)),
+        }
+    }
+
+    /// Refuses arithmetic and concatenation following an operand.
+    fn refuse_operator(&self) -> Result<()> {
+        match self.peek() {
+            Some(Token::Symbol(s @ ("+" | "-" | "*" | "/" | "%" | "||"))) => {
+                Err(unsupported(&format!("The {} operator", s)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn name(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Quoted(name)) => Ok(name),
+            Some(Token::Ident(word)) if !is_reserved(&word) => {
+                if self.is_symbol("(") {
+                    return Err(unsupported(&format!(
+                        "The function {}",
+                        word.to_uppercase()
+                    )));
+                }
+                Ok(word)
+            }
+            t => Err(unexpected(t.as_ref(), "a column name")),
+        }
+    }
+
+    fn path(&mut self) -> Result<Vec<String>> {
+        let mut path = vec![self.name()?];
+        while self.eat_symbol(".") {
+            path.push(self.name()?);
+        }
+        if self.is_symbol("[") {
+            return Err(unsupported("Indexing into a column"));
+        }
+        Ok(path)
+    }
+
+    /// `S3Object [AS] alias`, returning whether it was `S3Object[*]`.
+    fn from(&mut self) -> Result<bool> {
+        match self.next() {
+            Some(Token::Ident(w)) if w.eq_ignore_ascii_case("S3Object") => {}
+            t => return Err(unexpected(t.as_ref(), "S3Object")),
+        }
+        let flatten = self.eat_symbol("[");
+        if flatten {
+            self.expect_symbol("*")?;
+            self.expect_symbol("]")?;
+        }
+        if self.is_symbol(".") {
+            return Err(unsupported("A path in the FROM clause"));
+        }
+        if self.eat_keyword("AS")
+            || matches!(self.peek(), Some(Token::Ident(w)) if !is_reserved(w))
+            || matches!(self.peek(), Some(Token::Quoted(_)))
+        {
+            self.alias = Some(self.name()?);
+        }
+        if self.is_symbol(",") || self.is_keyword("JOIN") {
+            return Err(error("UnsupportedSqlStructure", "Joins are not supported"));
+        }
+        Ok(flatten)
+    }
+
+    fn column(&self, mut path: Vec<String>, name: Option<String>) -> Column {
+        let qualified = path.len() > 1
+            && (path[0].eq_ignore_ascii_case("S3Object")
+                || self
+                    .alias
+                    .as_deref()
+                    .is_some_and(|a| a.eq_ignore_ascii_case(&path[0])));
+        if qualified {
+            path.remove(0);
+        }
+        let name = name.unwrap_or_else(|| path[path.len() - 1].clone());
+        Column { path, name }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.eat_keyword("OR") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.not()?;
+        while self.eat_keyword("AND") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        match self.eat_keyword("NOT") {
+            true => Ok(Expr::Not(Box::new(self.not()?))),
+            false => self.predicate(),
+        }
+    }
+
+    fn predicate(&mut self) -> Result<Expr> {
+        if self.eat_symbol("(") {
+            let expr = self.or()?;
+            self.expect_symbol(")")?;
+            return Ok(expr);
+        }
+        let left = self.operand()?;
+        if self.eat_keyword("IS") {
+            let negated = self.eat_keyword("NOT");
+            if !self.eat_keyword("NULL") && !self.eat_keyword("MISSING") {
+                return Err(unexpected(self.peek(), "NULL"));
+            }
+            return Ok(Expr::IsNull(left, negated));
+        }
+        for keyword in ["LIKE", "IN", "BETWEEN", "NOT"] {
+            if self.is_keyword(keyword) {
+                return Err(unsupported(keyword));
+            }
+        }
+        let op = match self.next() {
+            Some(Token::Symbol("=")) => Op::Eq,
+            Some(Token::Symbol("!=" | "<>")) => Op::Ne,
+            Some(Token::Symbol("<")) => Op::Lt,
+            Some(Token::Symbol("<=")) => Op::Le,
+            Some(Token::Symbol(">")) => Op::Gt,
+            Some(Token::Symbol(">=")) => Op::Ge,
+            t => return Err(unexpected(t.as_ref(), "a comparison")),
+        };
+        let right = self.operand()?;
+        Ok(Expr::Compare(left, op, right))
+    }
+
+    fn operand(&mut self) -> Result<Operand> {
+        let literal = match self.next() {
+            Some(Token::Str(s)) => Literal::Str(s),
+            Some(Token::Num(n)) => Literal::Num(number(&n)?),
+            Some(Token::Symbol("-")) => match self.next() {
+                Some(Token::Num(n)) => Literal::Num(-number(&n)?),
+                t => return Err(unexpected(t.as_ref(), "a number")),
+            },
+            Some(Token::Ident(w)) if w.eq_ignore_ascii_case("TRUE") => Literal::Bool(true),
+            Some(Token::Ident(w)) if w.eq_ignore_ascii_case("FALSE") => Literal::Bool(false),
+            Some(Token::Ident(w)) if w.eq_ignore_ascii_case("NULL") => Literal::Null,
+            _ => {
+                self.pos -= 1;
+                let path = self.path()?;
+                self.refuse_operator()?;
+                return Ok(Operand::Column(self.column(path, None)));
+            }
+        };
+        self.refuse_operator()?;
+        Ok(Operand::Literal(literal))
+    }
+
+    fn limit(&mut self) -> Result<u64> {
+        match self.next() {
+            Some(Token::Num(n)) => n
+                .parse()
+                .map_err(|_| error("ParseUnexpectedToken", format!("Invalid LIMIT {}", n))),
+            t => Err(unexpected(t.as_ref(), "a number")),
+        }
+    }
+}
+
+fn number(text: &str) -> Result<f64> {
+    text.parse()
+        .map_err(|_| error("ParseUnexpectedToken", format!("Invalid number {}", text)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FileHeader {
+    Use,
+    Ignore,
+    None,
+}
+
+#[derive(Debug, Clone)]
+struct CsvFormat {
+    header: FileHeader,
+    field: u8,
+    record: u8,
+    quote: u8,
+    escape: u8,
+    comment: Option<u8>,
+}
+
+#[derive(Debug, Clone)]
+enum Input {
+    Csv(CsvFormat),
+    /// `DOCUMENT` objects are parsed once fully read; `LINES` as they arrive.
+    Json {
+        document: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct CsvWriter {
+    field: String,
+    record: String,
+    quote: String,
+    escaped_quote: String,
+    always_quote: bool,
+}
+
+impl CsvWriter {
+    fn line<'a>(&self, values: impl Iterator<Item = Cow<'a, str>>) -> String {
+        let mut line = String::new();
+        for (i, value) in values.enumerate() {
+            if i > 0 {
+                line.push_str(&self.field);
+            }
+            let quoted = self.always_quote
+                || value.contains(&self.field)
+                || value.contains(&self.quote)
+                || value.contains(['\n', '\r']);
+            match quoted {
+                true => {
+                    line.push_str(&self.quote);
+                    line.push_str(&value.replace(&self.quote, &self.escaped_quote));
+                    line.push_str(&self.quote);
+                }
+                false => line.push_str(&value),
+            }
+        }
+        line.push_str(&self.record);
+        line
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Output {
+    Csv(CsvWriter),
+    Json { record_delimiter: String },
+}
+
+/// A single-character setting, or `default` when absent or blank.
+fn character(value: Option<&str>, default: u8, code: &'static str, what: &str) -> Result<u8> {
+    match value {
+        None | Some("") => Ok(default),
+        Some(s) if s.len() == 1 => Ok(s.as_bytes()[0]),
+        Some(s) => Err(error(
+            code,
+            format!("{} must be a single character: {:?}", what, s),
+        )),
+    }
+}
+
+fn csv_format(csv: &CsvInput) -> Result<CsvFormat> {
+    let header = match csv
+        .file_header_info
+        .as_deref()
+        .map(str::to_ascii_uppercase)
+        .as_deref()
+    {
+        None | Some("NONE") => FileHeader::None,
+        Some("USE") => FileHeader::Use,
+        Some("IGNORE") => FileHeader::Ignore,
+        Some(other) => {
+            return Err(error(
+                "InvalidFileHeaderInfo",
+                format!("Invalid FileHeaderInfo {}", other),
+            ));
+        }
+    };
+    // CRLF records end at the LF; the CR is dropped with the record
+    let record = match csv.record_delimiter.as_deref() {
+        Some("\r\n") => b'\n',
+        other => character(other, b'\n', "InvalidRecordDelimiter", "RecordDelimiter")?,
+    };
+    let quote = character(
+        csv.quote_character.as_deref(),
+        b'"',
+        "InvalidQuoteCharacter",
+        "QuoteCharacter",
+    )?;
+    Ok(CsvFormat {
+        header,
+        field: character(
+            csv.field_delimiter.as_deref(),
+            b',',
+            "InvalidFieldDelimiter",
+            "FieldDelimiter",
+        )?,
+        record,
+        quote,
+        escape: character(
+            csv.quote_escape_character.as_deref(),
+            quote,
+            "InvalidQuoteEscapeCharacter",
+            "QuoteEscapeCharacter",
+        )?,
+        comment: match csv.comments.as_deref() {
+            None | Some("") => None,
+            other => Some(character(other, b'#', "InvalidRequest", "Comments")?),
+        },
+    })
+}
+
+fn csv_writer(csv: &CsvOutput) -> Result<CsvWriter> {
+    let always_quote = match csv
+        .quote_fields
+        .as_deref()
+        .map(str::to_ascii_uppercase)
+        .as_deref()
+    {
+        None | Some("ASNEEDED") => false,
+        Some("ALWAYS") => true,
+        Some(other) => {
+            return Err(error(
+                "InvalidQuoteFields",
+                format!("Invalid QuoteFields {}", other),
+            ));
+        }
+    };
+    let quote = match csv.quote_character.as_deref() {
+        None | Some("") => "\"",
+        Some(q) => q,
+    };
+    let escape = match csv.quote_escape_character.as_deref() {
+        None | Some("") => quote,
+        Some(e) => e,
+    };
+    Ok(CsvWriter {
+        field: non_empty(csv.field_delimiter.as_deref(), ","),
+        record: non_empty(csv.record_delimiter.as_deref(), "\n"),
+        quote: quote.to_string(),
+        escaped_quote: format!("{}{}", escape, quote),
+        always_quote,
+    })
+}
+
+fn non_empty(value: Option<&str>, default: &str) -> String {
+    match value {
+        None | Some("") => default.to_string(),
+        Some(v) => v.to_string(),
+    }
+}
+
+/// A parsed and validated SelectObjectContent request.
+#[derive(Debug, Clone)]
+pub struct Select {
+    query: Query,
+    input: Input,
+    gzip: bool,
+    output: Output,
+}
+
+impl Select {
+    pub fn new(request: &SelectObjectContentRequest) -> Result<Self> {
+        if !request.expression_type.eq_ignore_ascii_case("SQL") {
+            return Err(error(
+                "InvalidExpressionType",
+                format!("Invalid ExpressionType {}", request.expression_type),
+            ));
+        }
+        if request.scan_range.is_some() {
+            return Err(ProxyError::NotImplemented(
+                "SelectObjectContent with ScanRange".into(),
+            ));
+        }
+        let serialization = &request.input_serialization;
+        if serialization.parquet.is_some() {
+            return Err(ProxyError::NotImplemented(
+                "SelectObjectContent on Parquet".into(),
+            ));
+        }
+        let input = match (&serialization.csv, &serialization.json) {
+            (Some(csv), None) => Input::Csv(csv_format(csv)?),
+            (None, Some(json)) => {
+                let document = match json.kind.as_deref().map(str::to_ascii_uppercase).as_deref() {
+                    Some("DOCUMENT") => true,
+                    Some("LINES") => false,
+                    other => {
+                        return Err(error(
+                            "InvalidJsonType",
+                            format!("Invalid JSON Type {}", other.unwrap_or("")),
+                        ));
+                    }
+                };
+                Input::Json { document }
+            }
+            _ => {
+                return Err(ProxyError::InvalidRequest(
+                    "InputSerialization must specify one of CSV or JSON".into(),
+                ));
+            }
+        };
+        let gzip = match serialization
+            .compression_type
+            .as_deref()
+            .map(str::to_ascii_uppercase)
+            .as_deref()
+        {
+            None | Some("NONE") => false,
+            Some("GZIP") => true,
+            Some(other) => {
+                return Err(error(
+                    "InvalidCompressionFormat",
+                    format!("CompressionType {} is not supported", other),
+                ));
+            }
+        };
+        let output = match (
+            &request.output_serialization.csv,
+            &request.output_serialization.json,
+        ) {
+            (Some(csv), None) => Output::Csv(csv_writer(csv)?),
+            (None, Some(json)) => Output::Json {
+                record_delimiter: non_empty(json.record_delimiter.as_deref(), "\n"),
+            },
+            _ => {
+                return Err(ProxyError::InvalidRequest(
+                    "OutputSerialization must specify one of CSV or JSON".into(),
+                ));
+            }
+        };
+
+        let query = Query::parse(&request.expression)?;
+        for column in query.referenced_columns() {
+            if column.index() == Some(0) {
+                return Err(error("InvalidColumnIndex", "Column indexes start at _1"));
+            }
+            if let Input::Csv(csv) = &input {
+                if column.path.len() > 1 {
+                    return Err(error(
+                        "ParseInvalidPathComponent",
+                        format!(
+                            "CSV records have no nested fields: {}",
+                            column.path.join(".")
+                        ),
+                    ));
+                }
+                if column.index().is_none() && csv.header != FileHeader::Use {
+                    return Err(error(
+                        "EvaluatorBindingDoesNotExist",
+                        format!(
+                            "Column {} needs FileHeaderInfo USE; use _1, _2, ... otherwise",
+                            column.path[0]
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(Self {
+            query,
+            input,
+            gzip,
+            output,
+        })
+    }
+
+    /// Runs the query over the stored object and returns the response body:
+    /// Records events as results accumulate, then Stats and End, or an error
+    /// event if the object cannot be read or parsed part way through.
+    pub fn run<S>(self, body: S) -> impl Stream<Item = Io<Bytes>> + Send + use<S>
+    where
+        S: Stream<Item = Io<Bytes>> + Send + 'static,
+    {
+        async_stream::stream! {
+            let scanned = Arc::new(AtomicU64::new(0));
+            let counter = Arc::clone(&scanned);
+            let body = body.inspect_ok(move |chunk| {
+                counter.fetch_add(chunk.len() as u64, AtomicOrdering::Relaxed);
+            });
+            let gzip = self.gzip;
+            let mut input: Pin<Box<dyn Stream<Item = Io<Bytes>> + Send>> = match gzip {
+                true => Box::pin(ReaderStream::new(GzipDecoder::new(StreamReader::new(body)))),
+                false => Box::pin(body),
+            };
+            let mut scan = Scan::new(self);
+            let mut processed = 0u64;
+            while !scan.done() {
+                let chunk = match input.next().await {
+                    Some(Ok(chunk)) => chunk,
+                    Some(Err(e)) => {
+                        let code = match e.kind() {
+                            std::io::ErrorKind::InvalidData if gzip => "InvalidCompressionFormat",
+                            _ => "InternalError",
+                        };
+                        yield Ok(error_event(code, &e.to_string()));
+                        return;
+                    }
+                    None => break,
+                };
+                processed += chunk.len() as u64;
+                if let Err(e) = scan.feed(&chunk) {
+                    yield Ok(error_event(e.s3_error_code(), &e.to_string()));
+                    return;
+                }
+                if scan.out.len() >= RECORDS_EVENT_BYTES {
+                    yield Ok(scan.flush());
+                }
+            }
+            if let Err(e) = scan.finish() {
+                yield Ok(error_event(e.s3_error_code(), &e.to_string()));
+                return;
+            }
+            if !scan.out.is_empty() {
+                yield Ok(scan.flush());
+            }
+            yield Ok(stats_event(
+                scanned.load(AtomicOrdering::Relaxed),
+                processed,
+                scan.returned,
+            ));
+            yield Ok(end_event());
+        }
+    }
+}
+
+/// Splits CSV input into records as it arrives.
+#[derive(Debug)]
+struct CsvReader {
+    format: CsvFormat,
+    state: CsvState,
+    field: Vec<u8>,
+    fields: Vec<String>,
+    in_comment: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CsvState {
+    Unquoted,
+    Quoted,
+    /// After the escape character inside quotes.
+    Escaped,
+    /// After a quote inside quotes, when quotes escape themselves.
+    QuoteInQuoted,
+}
+
+impl CsvReader {
+    fn new(format: CsvFormat) -> Self {
+        Self {
+            format,
+            state: CsvState::Unquoted,
+            field: Vec::new(),
+            fields: Vec::new(),
+            in_comment: false,
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8], records: &mut Vec<Vec<String>>) {
+        for &b in chunk {
+            self.byte(b, records);
+        }
+    }
+
+    fn byte(&mut self, b: u8, records: &mut Vec<Vec<String>>) {
+        let f = &self.format;
+        if self.in_comment {
+            self.in_comment = b != f.record;
+            return;
+        }
+        match self.state {
+            CsvState::Unquoted => {
+                if Some(b) == f.comment && self.field.is_empty() && self.fields.is_empty() {
+                    self.in_comment = true;
+                } else if b == f.field {
+                    self.end_field();
+                } else if b == f.record {
+                    self.end_record(records);
+                } else if b == f.quote && self.field.is_empty() {
+                    self.state = CsvState::Quoted;
+                } else {
+                    self.field.push(b);
+                }
+            }
+            CsvState::Quoted => {
+                if b == f.escape && f.escape != f.quote {
+                    self.state = CsvState::Escaped;
+                } else if b == f.quote {
+                    self.state = match f.escape == f.quote {
+                        true => CsvState::QuoteInQuoted,
+                        false => CsvState::Unquoted,
+                    };
+                } else {
+                    self.field.push(b);
+                }
+            }
+            CsvState::Escaped => {
+                self.field.push(b);
+                self.state = CsvState::Quoted;
+            }
+            CsvState::QuoteInQuoted => {
+                if b == f.quote {
+                    self.field.push(b);
+                    self.state = CsvState::Quoted;
+                } else {
+                    self.state = CsvState::Unquoted;
+                    self.byte(b, records);
+                }
+            }
+        }
+    }
+
+    fn end_field(&mut self) {
+        self.fields
+            .push(String::from_utf8_lossy(&self.field).into_owned());
+        self.field.clear();
+    }
+
+    fn end_record(&mut self, records: &mut Vec<Vec<String>>) {
+        if self.format.record == b'\n' && self.field.last() == Some(&b'\r') {
+            self.field.pop();
+        }
+        // Blank lines hold no record
+        if self.fields.is_empty() && self.field.is_empty() {
+            return;
+        }
+        self.end_field();
+        records.push(std::mem::take(&mut self.fields));
+    }
+
+    /// The last record, when the object does not end with a delimiter.
+    fn finish(&mut self, records: &mut Vec<Vec<String>>) {
+        self.state = CsvState::Unquoted;
+        if !self.in_comment {
+            self.end_record(records);
+        }
+    }
+}
+
+/// Splits JSON input into values.
+#[derive(Debug)]
+struct JsonReader {
+    buf: Vec<u8>,
+    document: bool,
+}
+
+impl JsonReader {
+    fn feed(&mut self, chunk: &[u8]) -> Result<Vec<Value>> {
+        self.buf.extend_from_slice(chunk);
+        match self.document {
+            true => Ok(Vec::new()),
+            false => self.parse(false),
+        }
+    }
+
+    fn finish(&mut self) -> Result<Vec<Value>> {
+        self.parse(true)
+    }
+
+    /// The complete values buffered so far, keeping a partial one for the
+    /// next chunk unless the input has ended.
+    fn parse(&mut self, eof: bool) -> Result<Vec<Value>> {
+        let mut values = Vec::new();
+        let mut stream = serde_json::Deserializer::from_slice(&self.buf).into_iter::<Value>();
+        let mut consumed = 0;
+        loop {
+            match stream.next() {
+                Some(Ok(value)) => {
+                    values.push(value);
+                    consumed = stream.byte_offset();
+                }
+                Some(Err(e)) if e.is_eof() && !eof => break,
+                Some(Err(e)) => return Err(error("JSONParsingError", e.to_string())),
+                None => {
+                    consumed = stream.byte_offset();
+                    break;
+                }
+            }
+        }
+        self.buf.drain(..consumed);
+        Ok(values)
+    }
+}
+
+#[derive(Debug)]
+enum Reader {
+    Csv(CsvReader),
+    Json(JsonReader),
+}
+
+#[derive(Clone, Copy)]
+enum Record<'a> {
+    Csv(&'a [String]),
+    Json(&'a Value),
+}
+
+/// A value looked up in a record.
+#[derive(Clone, Copy)]
+enum Field<'a> {
+    Missing,
+    Text(&'a str),
+    Json(&'a Value),
+}
+
+impl<'a> Field<'a> {
+    fn text(self) -> Cow<'a, str> {
+        match self {
+            Self::Missing | Self::Json(Value::Null) => Cow::Borrowed(""),
+            Self::Text(s) => Cow::Borrowed(s),
+            Self::Json(Value::String(s)) => Cow::Borrowed(s),
+            Self::Json(v) => Cow::Owned(v.to_string()),
+        }
+    }
+
+    fn json(self) -> Option<String> {
+        match self {
+            Self::Missing => None,
+            Self::Text(s) => Some(Value::from(s).to_string()),
+            Self::Json(v) => Some(v.to_string()),
+        }
+    }
+}
+
+/// A comparable value; arrays and objects have none.
+#[derive(Clone, Copy)]
+enum Scalar<'a> {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(&'a str),
+}
+
+impl<'a> Scalar<'a> {
+    fn of_field(field: Field<'a>) -> Option<Self> {
+        match field {
+            Field::Missing | Field::Json(Value::Null) => Some(Self::Null),
+            Field::Text(s) => Some(Self::Str(s)),
+            Field::Json(Value::String(s)) => Some(Self::Str(s)),
+            Field::Json(Value::Bool(b)) => Some(Self::Bool(*b)),
+            Field::Json(Value::Number(n)) => n.as_f64().map(Self::Num),
+            Field::Json(_) => None,
+        }
+    }
+
+    fn of_literal(literal: &'a Literal) -> Self {
+        match literal {
+            Literal::Null => Self::Null,
+            Literal::Bool(b) => Self::Bool(*b),
+            Literal::Num(n) => Self::Num(*n),
+            Literal::Str(s) => Self::Str(s),
+        }
+    }
+
+    /// `None` where SQL's answer is unknown: nulls, and values of different
+    /// types.
+    fn compare(self, other: Self) -> Option<Ordering> {
+        match (self, other) {
+            (Self::Num(a), Self::Num(b)) => a.partial_cmp(&b),
+            (Self::Str(a), Self::Num(b)) => a.trim().parse::<f64>().ok()?.partial_cmp(&b),
+            (Self::Num(a), Self::Str(b)) => a.partial_cmp(&b.trim().parse::<f64>().ok()?),
+            (Self::Str(a), Self::Str(b)) => Some(a.cmp(b)),
+            (Self::Bool(a), Self::Bool(b)) => Some(a.cmp(&b)),
+            _ => None,
+        }
+    }
+}
+
+/// The state of one query as the object streams through it.
+struct Scan {
+    select: Select,
+    reader: Reader,
+    header: Option<Vec<String>>,
+    /// Whether the next CSV record is the header line.
+    header_pending: bool,
+    matched: u64,
+    /// Output not yet sent.
+    out: String,
+    returned: u64,
+}
+
+impl Scan {
+    fn new(select: Select) -> Self {
+        let (reader, header_pending) = match &select.input {
+            Input::Csv(format) => (
+                Reader::Csv(CsvReader::new(format.clone())),
+                format.header != FileHeader::None,
+            ),
+            Input::Json { document } => (
+                Reader::Json(JsonReader {
+                    buf: Vec::new(),
+                    document: *document,
+                }),
+                false,
+            ),
+        };
+        Self {
+            select,
+            reader,
+            header: None,
+            header_pending,
+            matched: 0,
+            out: String::new(),
+            returned: 0,
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.select.query.limit.is_some_and(|l| self.matched >= l)
+    }
+
+    fn feed(&mut self, chunk: &[u8]) -> Result<()> {
+        match &mut self.reader {
+            Reader::Csv(reader) => {
+                let mut records = Vec::new();
+                reader.feed(chunk, &mut records);
+                self.csv_records(records)
+            }
+            Reader::Json(reader) => {
+                let values = reader.feed(chunk)?;
+                self.json_records(values)
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.done() {
+            return Ok(());
+        }
+        match &mut self.reader {
+            Reader::Csv(reader) => {
+                let mut records = Vec::new();
+                reader.finish(&mut records);
+                self.csv_records(records)
+            }
+            Reader::Json(reader) => {
+                let values = reader.finish()?;
+                self.json_records(values)
+            }
+        }
+    }
+
+    fn csv_records(&mut self, records: Vec<Vec<String>>) -> Result<()> {
+        for fields in records {
+            if self.done() {
+                break;
+            }
+            if self.header_pending {
+                self.header_pending = false;
+                if let Input::Csv(CsvFormat {
+                    header: FileHeader::Use,
+                    ..
+                }) = self.select.input
+                {
+                    self.bind_header(fields)?;
+                }
+                continue;
+            }
+            self.emit(Record::Csv(&fields));
+        }
+        Ok(())
+    }
+
+    /// Takes the header line, refusing the query if it names a column the
+    /// header lacks.
+    fn bind_header(&mut self, header: Vec<String>) -> Result<()> {
+        for column in self.select.query.referenced_columns() {
+            if column.index().is_none() && header_position(&header, &column.path[0]).is_none() {
+                return Err(error(
+                    "EvaluatorBindingDoesNotExist",
+                    format!("Column {} is not in the header", column.path[0]),
+                ));
+            }
+        }
+        self.header = Some(header);
+        Ok(())
+    }
+
+    fn json_records(&mut self, values: Vec<Value>) -> Result<()> {
+        for value in values {
+            match value {
+                Value::Array(items) if self.select.query.flatten => {
+                    for item in &items {
+                        if self.done() {
+                            break;
+                        }
+                        self.emit(Record::Json(item));
+                    }
+                }
+                value => {
+                    if self.done() {
+                        break;
+                    }
+                    self.emit(Record::Json(&value));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, record: Record<'_>) {
+        if let Some(filter) = &self.select.query.filter
+            && self.eval(filter, record) != Some(true)
+        {
+            return;
+        }
+        let line = self.render(record);
+        self.out.push_str(&line);
+        self.matched += 1;
+    }
+
+    fn flush(&mut self) -> Bytes {
+        let out = std::mem::take(&mut self.out);
+        self.returned += out.len() as u64;
+        records_event(out.as_bytes())
+    }
+
+    fn field<'a>(&'a self, record: Record<'a>, column: &Column) -> Field<'a> {
+        match record {
+            Record::Csv(fields) => {
+                let position = match column.index() {
+                    Some(n) => Some(n - 1),
+                    None => self
+                        .header
+                        .as_deref()
+                        .and_then(|h| header_position(h, &column.path[0])),
+                };
+                position
+                    .and_then(|i| fields.get(i))
+                    .map_or(Field::Missing, |s| Field::Text(s))
+            }
+            Record::Json(value) => column
+                .path
+                .iter()
+                .try_fold(value, |v, name| v.get(name))
+                .map_or(Field::Missing, Field::Json),
+        }
+    }
+
+    fn scalar<'a>(&'a self, operand: &'a Operand, record: Record<'a>) -> Option<Scalar<'a>> {
+        match operand {
+            Operand::Column(column) => Scalar::of_field(self.field(record, column)),
+            Operand::Literal(literal) => Some(Scalar::of_literal(literal)),
+        }
+    }
+
+    /// SQL's three-valued logic; `None` is unknown.
+    fn eval(&self, expr: &Expr, record: Record<'_>) -> Option<bool> {
+        match expr {
+            Expr::And(a, b) => match (self.eval(a, record), self.eval(b, record)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Expr::Or(a, b) => match (self.eval(a, record), self.eval(b, record)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+            Expr::Not(e) => self.eval(e, record).map(|b| !b),
+            Expr::IsNull(operand, negated) => {
+                let null = matches!(self.scalar(operand, record), Some(Scalar::Null));
+                Some(null != *negated)
+            }
+            Expr::Compare(a, op, b) => {
+                let ordering = self.scalar(a, record)?.compare(self.scalar(b, record)?)?;
+                Some(op.holds(ordering))
+            }
+        }
+    }
+
+    fn render(&self, record: Record<'_>) -> String {
+        let columns = self.select.query.columns.as_deref();
+        match &self.select.output {
+            Output::Csv(writer) => match (columns, record) {
+                (Some(columns), _) => {
+                    writer.line(columns.iter().map(|c| self.field(record, c).text()))
+                }
+                (None, Record::Csv(fields)) => {
+                    writer.line(fields.iter().map(|f| Cow::from(f.as_str())))
+                }
+                (None, Record::Json(Value::Object(map))) => {
+                    writer.line(map.values().map(|v| Field::Json(v).text()))
+                }
+                (None, Record::Json(value)) => {
+                    writer.line(std::iter::once(Field::Json(value).text()))
+                }
+            },
+            Output::Json { record_delimiter } => {
+                let object = match (columns, record) {
+                    (Some(columns), _) => json_object(
+                        columns
+                            .iter()
+                            .map(|c| (Cow::from(c.name.as_str()), self.field(record, c).json())),
+                    ),
+                    (None, Record::Csv(fields)) => {
+                        json_object(fields.iter().enumerate().map(|(i, f)| {
+                            let name = match self.header.as_ref().and_then(|h| h.get(i)) {
+                                Some(name) => Cow::from(name.as_str()),
+                                None => Cow::from(format!("_{}", i + 1)),
+                            };
+                            (name, Field::Text(f).json())
+                        }))
+                    }
+                    (None, Record::Json(value)) => value.to_string(),
+                };
+                object + record_delimiter
+            }
+        }
+    }
+}
+
+fn header_position(header: &[String], name: &str) -> Option<usize> {
+    header
+        .iter()
+        .position(|h| h == name)
+        .or_else(|| header.iter().position(|h| h.eq_ignore_ascii_case(name)))
+}
+
+/// A JSON object from already serialized values, in order, leaving out
+/// missing ones.
+fn json_object<'a>(fields: impl Iterator<Item = (Cow<'a, str>, Option<String>)>) -> String {
+    let members: Vec<String> = fields
+        .filter_map(|(name, value)| Some(format!("{}:{}", Value::from(name.as_ref()), value?)))
+        .collect();
+    format!("{{{}}}", members.join(","))
+}
+
+/// One event-stream message: a prelude of total and header lengths with its
+/// CRC32, string headers, the payload, then the CRC32 of everything before.
+fn message(headers: &[(&str, &str)], payload: &[u8]) -> Bytes {
+    let mut encoded = BytesMut::new();
+    for (name, value) in headers {
+        encoded.put_u8(name.len() as u8);
+        encoded.put_slice(name.as_bytes());
+        // Header value type 7: string
+        encoded.put_u8(7);
+        encoded.put_u16(value.len() as u16);
+        encoded.put_slice(value.as_bytes());
+    }
+    let total = 12 + encoded.len() + payload.len() + 4;
+    let mut message = BytesMut::with_capacity(total);
+    message.put_u32(total as u32);
+    message.put_u32(encoded.len() as u32);
+    let prelude_crc = crc32fast::hash(&message);
+    message.put_u32(prelude_crc);
+    message.put_slice(&encoded);
+    message.put_slice(payload);
+    let crc = crc32fast::hash(&message);
+    message.put_u32(crc);
+    message.freeze()
+}
+
+fn records_event(payload: &[u8]) -> Bytes {
+    message(
+        &[
+            (":message-type", "event"),
+            (":event-type", "Records"),
+            (":content-type", "application/octet-stream"),
+        ],
+        payload,
+    )
+}
+
+fn stats_event(scanned: u64, processed: u64, returned: u64) -> Bytes {
+    let stats = format!(
+        "<Stats><BytesScanned>{}</BytesScanned><BytesProcessed>{}</BytesProcessed><BytesReturned>{}</BytesReturned></Stats>",
+        scanned, processed, returned
+    );
+    message(
+        &[
+            (":message-type", "event"),
+            (":event-type", "Stats"),
+            (":content-type", "text/xml"),
+        ],
+        stats.as_bytes(),
+    )
+}
+
+fn end_event() -> Bytes {
+    message(&[(":message-type", "event"), (":event-type", "End")], &[])
+}
+
+fn error_event(code: &str, message_text: &str) -> Bytes {
+    message(
+        &[
+            (":message-type", "error"),
+            (":error-code", code),
+            (":error-message", message_text),
+        ],
+        &[],
+    )
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::s3::types::{InputSerialization, JsonInput, JsonOutput, OutputSerialization};
+
+    /// The `:event-type` (or `:error-code`) and payload of each message,
+    /// checking both CRCs.
+    pub(crate) fn decode(mut body: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut events = Vec::new();
+        while !body.is_empty() {
+            let total = u32::from_be_bytes(body[0..4].try_into().unwrap()) as usize;
+            let headers_len = u32::from_be_bytes(body[4..8].try_into().unwrap()) as usize;
+            let prelude_crc = u32::from_be_bytes(body[8..12].try_into().unwrap());
+            assert_eq!(prelude_crc, crc32fast::hash(&body[..8]));
+            let crc = u32::from_be_bytes(body[total - 4..total].try_into().unwrap());
+            assert_eq!(crc, crc32fast::hash(&body[..total - 4]));
+
+            let mut headers = &body[12..12 + headers_len];
+            let mut kind = String::new();
+            while !headers.is_empty() {
+                let name_len = headers[0] as usize;
+                let name = std::str::from_utf8(&headers[1..1 + name_len]).unwrap();
+                let rest = &headers[1 + name_len..];
+                assert_eq!(rest[0], 7);
+                let value_len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+                let value = std::str::from_utf8(&rest[3..3 + value_len]).unwrap();
+                if name == ":event-type" || name == ":error-code" {
+                    kind = value.to_string();
+                }
+                headers = &rest[3 + value_len..];
+            }
+            events.push((kind, body[12 + headers_len..total - 4].to_vec()));
+            body = &body[total..];
+        }
+        events
+    }
+
+    fn request(sql: &str, csv: Option<CsvInput>, json_output: bool) -> SelectObjectContentRequest {
+        SelectObjectContentRequest {
+            expression: sql.into(),
+            expression_type: "SQL".into(),
+            input_serialization: InputSerialization {
+                compression_type: None,
+                json: csv.is_none().then(|| JsonInput {
+                    kind: Some("LINES".into()),
+                }),
+                csv,
+                parquet: None,
+            },
+            output_serialization: OutputSerialization {
+                csv: (!json_output).then(CsvOutput::default),
+                json: json_output.then(JsonOutput::default),
+            },
+            scan_range: None,
+        }
+    }
+
+    fn header(kind: &str) -> Option<CsvInput> {
+        Some(CsvInput {
+            file_header_info: Some(kind.into()),
+            ..Default::default()
+        })
+    }
+
+    /// The concatenated Records payloads, fed to the query `chunk` bytes at
+    /// a time, or the error event's code.
+    async fn select(request: SelectObjectContentRequest, data: &str, chunk: usize) -> String {
+        let select = Select::new(&request).unwrap();
+        let chunks: Vec<Io<Bytes>> = data
+            .as_bytes()
+            .chunks(chunk)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        let body: Vec<u8> = select
+            .run(futures::stream::iter(chunks))
+            .map(|chunk| chunk.unwrap().to_vec())
+            .concat()
+            .await;
+        let events = decode(&body);
+        let mut out = String::new();
+        for (kind, payload) in &events {
+            match kind.as_str() {
+                "Records" => out.push_str(std::str::from_utf8(payload).unwrap()),
+                "Stats" | "End" => {}
+                code => return code.to_string(),
+            }
+        }
+        assert_eq!(events.last().unwrap().0, "End");
+        out
+    }
+
+    #[test]
+    fn test_parse_queries() {
+        let query = Query::parse(
+            "select s.name, s.\"Unit Price\" AS price FROM S3Object s WHERE s.qty >= 10 AND NOT (s.name = 'it''s') LIMIT 5",
+        )
+        .unwrap();
+        let columns = query.columns.as_ref().unwrap();
+        assert_eq!(columns[0].path, ["name"]);
+        assert_eq!(columns[1].path, ["Unit Price"]);
+        assert_eq!(columns[1].name, "price");
+        assert_eq!(query.limit, Some(5));
+        let Some(Expr::And(_, not)) = &query.filter else {
+            panic!("{:?}", query.filter)
+        };
+        assert!(matches!(
+            not.as_ref(),
+            Expr::Not(e) if matches!(e.as_ref(), Expr::Compare(_, Op::Eq, Operand::Literal(Literal::Str(s))) if s == "it's")
+        ));
+
+        let query = Query::parse("SELECT * FROM s3object[*] WHERE a.b IS NOT NULL").unwrap();
+        assert!(query.flatten && query.columns.is_none());
+
+        for (sql, code) in [
+            ("SELECT COUNT(*) FROM S3Object", "UnsupportedSqlOperation"),
+            (
+                "SELECT s._1 FROM S3Object s WHERE s._1 LIKE 'a%'",
+                "UnsupportedSqlOperation",
+            ),
+            ("SELECT _1 + 1 FROM S3Object", "UnsupportedSqlOperation"),
+            (
+                "SELECT * FROM S3Object WHERE CAST(_1 AS INT) > 1",
+                "UnsupportedSqlOperation",
+            ),
+            (
+                "SELECT * FROM S3Object GROUP BY _1",
+                "UnsupportedSqlStructure",
+            ),
+            (
+                "SELECT * FROM S3Object a, S3Object b",
+                "UnsupportedSqlStructure",
+            ),
+            ("SELECT * FROM S3Object WHERE", "ParseUnexpectedToken"),
+            ("SELECT * FROM table", "ParseUnexpectedToken"),
+            ("SELECT * FROM S3Object LIMIT -1", "ParseUnexpectedToken"),
+            (
+                "SELECT * FROM S3Object WHERE _1 = 'open",
+                "LexerInvalidLiteral",
+            ),
+            ("SELECT * FROM S3Object WHERE _1 = ?", "LexerInvalidChar"),
+        ] {
+            let err = Query::parse(sql).unwrap_err();
+            assert_eq!(err.s3_error_code(), code, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_request_validation() {
+        let code = |request: SelectObjectContentRequest| {
+            Select::new(&request).unwrap_err().s3_error_code()
+        };
+        assert_eq!(
+            code(request("SELECT name FROM S3Object", header("NONE"), false)),
+            "EvaluatorBindingDoesNotExist"
+        );
+        assert_eq!(
+            code(request("SELECT _0 FROM S3Object", header("NONE"), false)),
+            "InvalidColumnIndex"
+        );
+        assert_eq!(
+            code(request(
+                "SELECT s.a.b FROM S3Object s",
+                header("USE"),
+                false
+            )),
+            "ParseInvalidPathComponent"
+        );
+        let mut bzip = request("SELECT * FROM S3Object", header("NONE"), false);
+        bzip.input_serialization.compression_type = Some("BZIP2".into());
+        assert_eq!(code(bzip), "InvalidCompressionFormat");
+        let mut tab = request("SELECT * FROM S3Object", header("NONE"), false);
+        tab.input_serialization
+            .csv
+            .as_mut()
+            .unwrap()
+            .field_delimiter = Some("::".into());
+        assert_eq!(code(tab), "InvalidFieldDelimiter");
+    }
+
+    #[tokio::test]
+    async fn test_select_csv() {
+        let data = "id,name,qty\n# a comment\n1,apple,5\r\n2,\"banana, ripe\",12\n3,\"say \"\"hi\"\"\",30\n4,,\n";
+        for chunk in [1, 7, data.len()] {
+            let out = select(
+                request(
+                    "SELECT s.name, s.qty FROM S3Object s WHERE s.qty > 10",
+                    Some(CsvInput {
+                        file_header_info: Some("USE".into()),
+                        comments: Some("#".into()),
+                        ..Default::default()
+                    }),
+                    false,
+                ),
+                data,
+                chunk,
+            )
+            .await;
+            assert_eq!(
+                out, "\"banana, ripe\",12\n\"say \"\"hi\"\"\",30\n",
+                "chunk {}",
+                chunk
+            );
+        }
+
+        let out = select(
+            request(
+                "SELECT _2 FROM S3Object WHERE _3 IS NULL OR _1 = '1' LIMIT 1",
+                header("IGNORE"),
+                true,
+            ),
+            "id,name,qty\n1,apple,5\n2,pear",
+            4,
+        )
+        .await;
+        assert_eq!(out, "{\"_2\":\"apple\"}\n");
+
+        // A NULL comparison is unknown, and so is its negation
+        let out = select(
+            request(
+                "SELECT * FROM S3Object WHERE NOT _3 = 'x'",
+                header("NONE"),
+                true,
+            ),
+            "a,b\nc,d,y\n",
+            64,
+        )
+        .await;
+        assert_eq!(out, "{\"_1\":\"c\",\"_2\":\"d\",\"_3\":\"y\"}\n");
+
+        let out = select(
+            request("SELECT price FROM S3Object", header("USE"), false),
+            "id\n1\n",
+            64,
+        )
+        .await;
+        assert_eq!(out, "EvaluatorBindingDoesNotExist");
+    }
+
+    #[tokio::test]
+    async fn test_select_json() {
+        let data = "{\"user\":{\"name\":\"ann\",\"age\":31},\"tags\":[1]}\n{\"user\":{\"name\":\"bob\",\"age\":17}}\n{\"user\":{\"name\":\"cy\"}}\n";
+        for chunk in [3, data.len()] {
+            let out = select(
+                request(
+                    "SELECT s.user.name, s.tags FROM S3Object s WHERE s.user.age >= 18 OR s.user.age IS MISSING",
+                    None,
+                    true,
+                ),
+                data,
+                chunk,
+            )
+            .await;
+            assert_eq!(out, "{\"name\":\"ann\",\"tags\":[1]}\n{\"name\":\"cy\"}\n");
+        }
+
+        let out = select(
+            request("SELECT s.user.name FROM S3Object s LIMIT 2", None, false),
+            data,
+            5,
+        )
+        .await;
+        assert_eq!(out, "ann\nbob\n");
+
+        let mut document = request("SELECT * FROM S3Object[*] s WHERE s.n < 2", None, true);
+        document.input_serialization.json = Some(JsonInput {
+            kind: Some("DOCUMENT".into()),
+        });
+        let out = select(document, "[{\"n\":1},\n{\"n\":2}]", 4).await;
+        assert_eq!(out, "{\"n\":1}\n");
+
+        let out = select(request("SELECT * FROM S3Object", None, true), "{\"a\":", 64).await;
+        assert_eq!(out, "JSONParsingError");
+    }
+
+    #[test]
+    fn test_event_framing() {
+        // The End message every Select response finishes with, as S3 sends it
+        let end = end_event();
+        assert_eq!(&end[..4], &(end.len() as u32).to_be_bytes());
+        assert_eq!(decode(&end), [("End".to_string(), Vec::new())]);
+        let records = records_event(b"a,b\n");
+        assert_eq!(
+            decode(&records),
+            [("Records".to_string(), b"a,b\n".to_vec())]
+        );
+    }
+}
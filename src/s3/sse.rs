@@ -0,0 +1,236 @@
+use axum::http::{HeaderMap, HeaderValue, Method};
+use base64::Engine;
+use md5::{Digest, Md5};
+
+use crate::error::{ProxyError, Result};
+
+use super::encryption::Keyring;
+
+pub const SSE_HEADER: &str = "x-amz-server-side-encryption";
+pub const SSE_S3_ALGORITHM: &str = "AES256";
+
+pub const SSE_C_ALGORITHM_HEADER: &str = "x-amz-server-side-encryption-customer-algorithm";
+pub const SSE_C_KEY_HEADER: &str = "x-amz-server-side-encryption-customer-key";
+pub const SSE_C_KEY_MD5_HEADER: &str = "x-amz-server-side-encryption-customer-key-md5";
+
+const COPY_SOURCE_SSE_C_HEADERS: [&str; 3] = [
+    "x-amz-copy-source-server-side-encryption-customer-algorithm",
+    "x-amz-copy-source-server-side-encryption-customer-key",
+    "x-amz-copy-source-server-side-encryption-customer-key-md5",
+];
+
+const SSE_C_HEADERS: [&str; 6] = [
+    SSE_C_ALGORITHM_HEADER,
+    SSE_C_KEY_HEADER,
+    SSE_C_KEY_MD5_HEADER,
+    COPY_SOURCE_SSE_C_HEADERS[0],
+    COPY_SOURCE_SSE_C_HEADERS[1],
+    COPY_SOURCE_SSE_C_HEADERS[2],
+];
+
+/// Whether the request carries any SSE-C header, for source or destination.
+pub fn has_customer_headers(headers: &HeaderMap) -> bool {
+    SSE_C_HEADERS.iter().any(|h| headers.contains_key(*h))
+}
+
+/// An SSE-C key supplied with a request. It only lives for the request; the
+/// proxy records nothing but its MD5, as S3 does, to recognize it later.
+pub struct CustomerKey {
+    key: [u8; 32],
+    key_md5: String,
+}
+
+impl CustomerKey {
+    /// Reads the SSE-C headers, or the `x-amz-copy-source-` ones for the
+    /// source of a copy. `None` when none of them are present.
+    pub fn from_headers(headers: &HeaderMap, copy_source: bool) -> Result<Option<Self>> {
+        let [algorithm, key, key_md5] = if copy_source {
+            COPY_SOURCE_SSE_C_HEADERS
+        } else {
+            [
+                SSE_C_ALGORITHM_HEADER,
+                SSE_C_KEY_HEADER,
+                SSE_C_KEY_MD5_HEADER,
+            ]
+        };
+        let get = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let (algorithm, key, key_md5) = match (get(algorithm), get(key), get(key_md5)) {
+            (None, None, None) => return Ok(None),
+            (Some(algorithm), Some(key), Some(key_md5)) => (algorithm, key, key_md5),
+            _ => {
+                return Err(ProxyError::InvalidArgument(
+                    "Requests specifying Server Side Encryption with Customer provided keys must provide the algorithm, key and key MD5".into(),
+                ));
+            }
+        };
+        if algorithm != SSE_S3_ALGORITHM {
+            return Err(ProxyError::InvalidArgument(
+                "The encryption algorithm specified is not valid".into(),
+            ));
+        }
+        let engine = base64::engine::general_purpose::STANDARD;
+        let key: [u8; 32] = engine
+            .decode(key)
+            .ok()
+            .and_then(|k| k.try_into().ok())
+            .ok_or_else(|| {
+                ProxyError::InvalidArgument(
+                    "The secret key was invalid for the specified algorithm".into(),
+                )
+            })?;
+        let computed = engine.encode(Md5::digest(key));
+        if computed != key_md5 {
+            return Err(ProxyError::InvalidArgument(
+                "The calculated MD5 hash of the key did not match the hash that was provided"
+                    .into(),
+            ));
+        }
+        Ok(Some(Self {
+            key,
+            key_md5: computed,
+        }))
+    }
+
+    pub fn key_md5(&self) -> &str {
+        &self.key_md5
+    }
+
+    pub fn keyring(&self) -> Keyring {
+        Keyring::for_customer_key(self.key)
+    }
+
+    /// Checks that this is the key an object was stored with.
+    pub fn check(&self, stored_md5: &str) -> Result<()> {
+        if self.key_md5 == stored_md5 {
+            Ok(())
+        } else {
+            Err(ProxyError::AccessDenied)
+        }
+    }
+}
+
+/// Headers S3 returns for an object encrypted with a customer key.
+pub fn customer_response_headers(key_md5: &str) -> [(&'static str, String); 2] {
+    [
+        (SSE_C_ALGORITHM_HEADER, SSE_S3_ALGORITHM.to_string()),
+        (SSE_C_KEY_MD5_HEADER, key_md5.to_string()),
+    ]
+}
+
+/// Rejects encryption modes the proxy cannot honor instead of silently storing
+/// plaintext. SSE-C headers are only accepted with `--sse-c`.
+pub fn validate_request_headers(headers: &HeaderMap, allow_customer_keys: bool) -> Result<()> {
+    if let Some(sse) = headers.get(SSE_HEADER).and_then(|v| v.to_str().ok()) {
+        match sse {
+            SSE_S3_ALGORITHM => {}
+            "aws:kms" | "aws:kms:dsse" => {
+                return Err(ProxyError::NotImplemented(
+                    "Server-side encryption with KMS keys".to_string(),
+                ));
+            }
+            other => {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "Unsupported server-side encryption algorithm: {}",
+                    other
+                )));
+            }
+        }
+    }
+    if !has_customer_headers(headers) {
+        return Ok(());
+    }
+    if !allow_customer_keys {
+        return Err(ProxyError::NotImplemented(
+            "Server-side encryption with customer-provided keys".to_string(),
+        ));
+    }
+    if headers.contains_key(SSE_HEADER) && CustomerKey::from_headers(headers, false)?.is_some() {
+        return Err(ProxyError::InvalidArgument(
+            "Server Side Encryption with Customer provided key is incompatible with the encryption method specified".into(),
+        ));
+    }
+    CustomerKey::from_headers(headers, false)?;
+    CustomerKey::from_headers(headers, true)?;
+    Ok(())
+}
+
+/// The `x-amz-server-side-encryption` value to echo on an object response, if any.
+pub fn response_header(
+    method: &Method,
+    headers: &HeaderMap,
+    has_key: bool,
+    claimed: bool,
+) -> Option<HeaderValue> {
+    if !has_key {
+        return None;
+    }
+    let requested = headers
+        .get(SSE_HEADER)
+        .is_some_and(|v| v.as_bytes() == SSE_S3_ALGORITHM.as_bytes());
+    let echo = match *method {
+        Method::PUT | Method::POST => requested || claimed,
+        Method::GET | Method::HEAD => claimed,
+        _ => false,
+    };
+    echo.then(|| HeaderValue::from_static(SSE_S3_ALGORITHM))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn customer_headers(key: &[u8]) -> HeaderMap {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let mut headers = HeaderMap::new();
+        headers.insert(SSE_C_ALGORITHM_HEADER, "AES256".parse().unwrap());
+        headers.insert(SSE_C_KEY_HEADER, engine.encode(key).parse().unwrap());
+        headers.insert(
+            SSE_C_KEY_MD5_HEADER,
+            engine.encode(Md5::digest(key)).parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_customer_key_validation() {
+        let headers = customer_headers(&[7; 32]);
+        assert!(validate_request_headers(&headers, true).is_ok());
+        let err = validate_request_headers(&headers, false).unwrap_err();
+        assert_eq!(err.s3_error_code(), "NotImplemented");
+
+        let key = CustomerKey::from_headers(&headers, false).unwrap().unwrap();
+        assert!(key.check(key.key_md5()).is_ok());
+        let other = CustomerKey::from_headers(&customer_headers(&[8; 32]), false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            other.check(key.key_md5()).unwrap_err().s3_error_code(),
+            "AccessDenied"
+        );
+        assert!(CustomerKey::from_headers(&headers, true).unwrap().is_none());
+
+        let mut wrong_md5 = headers.clone();
+        wrong_md5.insert(
+            SSE_C_KEY_MD5_HEADER,
+            "AAAAAAAAAAAAAAAAAAAAAA==".parse().unwrap(),
+        );
+        let err = validate_request_headers(&wrong_md5, true).unwrap_err();
+        assert_eq!(err.s3_error_code(), "InvalidArgument");
+
+        let short = customer_headers(&[7; 16]);
+        assert!(validate_request_headers(&short, true).is_err());
+
+        let mut partial = HeaderMap::new();
+        partial.insert(SSE_C_ALGORITHM_HEADER, "AES256".parse().unwrap());
+        assert!(validate_request_headers(&partial, true).is_err());
+
+        let mut with_sse_s3 = headers.clone();
+        with_sse_s3.insert(SSE_HEADER, "AES256".parse().unwrap());
+        assert!(validate_request_headers(&with_sse_s3, true).is_err());
+
+        let mut copy_only = HeaderMap::new();
+        copy_only.insert(COPY_SOURCE_SSE_C_HEADERS[2], "x".parse().unwrap());
+        let err = validate_request_headers(&copy_only, false).unwrap_err();
+        assert_eq!(err.s3_error_code(), "NotImplemented");
+    }
+}
@@ -0,0 +1,192 @@
+use axum::http::{HeaderMap, Method};
+
+/// S3 subresources selected via a bare query parameter (e.g. `?acl`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subresource {
+    Accelerate,
+    Acl,
+    Analytics,
+    Attributes,
+    Cors,
+    Encryption,
+    IntelligentTiering,
+    Inventory,
+    LegalHold,
+    Lifecycle,
+    Logging,
+    Metrics,
+    Notification,
+    ObjectLock,
+    OwnershipControls,
+    Policy,
+    PolicyStatus,
+    PublicAccessBlock,
+    Replication,
+    RequestPayment,
+    Restore,
+    Retention,
+    Select,
+    Tagging,
+    Torrent,
+    Versioning,
+    Versions,
+    Website,
+}
+
+impl Subresource {
+    fn from_param(param: &str) -> Option<Self> {
+        Some(match param {
+            "accelerate" => Self::Accelerate,
+            "acl" => Self::Acl,
+            "analytics" => Self::Analytics,
+            "attributes" => Self::Attributes,
+            "cors" => Self::Cors,
+            "encryption" => Self::Encryption,
+            "intelligent-tiering" => Self::IntelligentTiering,
+            "inventory" => Self::Inventory,
+            "legal-hold" => Self::LegalHold,
+            "lifecycle" => Self::Lifecycle,
+            "logging" => Self::Logging,
+            "metrics" => Self::Metrics,
+            "notification" => Self::Notification,
+            "object-lock" => Self::ObjectLock,
+            "ownershipControls" => Self::OwnershipControls,
+            "policy" => Self::Policy,
+            "policyStatus" => Self::PolicyStatus,
+            "publicAccessBlock" => Self::PublicAccessBlock,
+            "replication" => Self::Replication,
+            "requestPayment" => Self::RequestPayment,
+            "restore" => Self::Restore,
+            "retention" => Self::Retention,
+            "select" => Self::Select,
+            "tagging" => Self::Tagging,
+            "torrent" => Self::Torrent,
+            "versioning" => Self::Versioning,
+            "versions" => Self::Versions,
+            "website" => Self::Website,
+            _ => return None,
+        })
+    }
+
+    /// Returns the first recognized subresource in a raw query string.
+    pub fn from_query(query: &str) -> Option<Self> {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split('=').next())
+            .find_map(Self::from_param)
+    }
+
+    fn operation_suffix(&self) -> &'static str {
+        match self {
+            Self::Accelerate => "AccelerateConfiguration",
+            Self::Acl => "Acl",
+            Self::Analytics => "AnalyticsConfiguration",
+            Self::Attributes => "Attributes",
+            Self::Cors => "Cors",
+            Self::Encryption => "Encryption",
+            Self::IntelligentTiering => "IntelligentTieringConfiguration",
+            Self::Inventory => "InventoryConfiguration",
+            Self::LegalHold => "LegalHold",
+            Self::Lifecycle => "LifecycleConfiguration",
+            Self::Logging => "Logging",
+            Self::Metrics => "MetricsConfiguration",
+            Self::Notification => "NotificationConfiguration",
+            Self::ObjectLock => "LockConfiguration",
+            Self::OwnershipControls => "OwnershipControls",
+            Self::Policy => "Policy",
+            Self::PolicyStatus => "PolicyStatus",
+            Self::PublicAccessBlock => "PublicAccessBlock",
+            Self::Replication => "Replication",
+            Self::RequestPayment => "RequestPayment",
+            Self::Restore => "Restore",
+            Self::Retention => "Retention",
+            Self::Select => "Select",
+            Self::Tagging => "Tagging",
+            Self::Torrent => "Torrent",
+            Self::Versioning => "Versioning",
+            Self::Versions => "Versions",
+            Self::Website => "Website",
+        }
+    }
+
+    /// The S3 API operation name a request for this subresource maps to,
+    /// e.g. `PutBucketAcl` or `GetObjectTagging`.
+    pub fn operation(&self, method: &Method, has_key: bool) -> String {
+        match self {
+            Self::Versions => return "ListObjectVersions".to_string(),
+            Self::Restore => return "RestoreObject".to_string(),
+            Self::Select => return "SelectObjectContent".to_string(),
+            Self::Attributes => return "GetObjectAttributes".to_string(),
+            Self::Torrent => return "GetObjectTorrent".to_string(),
+            _ => {}
+        }
+
+        let verb = match *method {
+            Method::GET => "Get",
+            Method::PUT => "Put",
+            Method::DELETE => "Delete",
+            Method::HEAD => "Head",
+            Method::POST => "Post",
+            _ => method.as_str(),
+        };
+        let scope = match self {
+            Self::PublicAccessBlock => "",
+            Self::ObjectLock => "Object",
+            _ if has_key => "Object",
+            _ => "Bucket",
+        };
+        format!("{}{}{}", verb, scope, self.operation_suffix())
+    }
+}
+
+/// Methods the proxy accepts for a resource, as advertised in the `Allow` header.
+pub fn allowed_methods(has_bucket: bool) -> &'static str {
+    if has_bucket {
+        "GET, HEAD, PUT, POST, DELETE"
+    } else {
+        "GET"
+    }
+}
+
+/// The S3 API operation name for a request, mirroring how `route_request`
+/// dispatches it. Used to label request spans.
+pub fn operation_name(
+    method: &Method,
+    has_bucket: bool,
+    has_key: bool,
+    query: &str,
+    headers: &HeaderMap,
+) -> String {
+    if has_bucket && let Some(subresource) = Subresource::from_query(query) {
+        return subresource.operation(method, has_key);
+    }
+
+    let is_copy = headers.contains_key("x-amz-copy-source");
+    let is_rename = headers.contains_key(super::handlers::RENAME_SOURCE);
+    let has_upload_id = query.contains("uploadId");
+    let name = match (method, has_bucket, has_key) {
+        (&Method::GET, false, _) => "ListBuckets",
+        (&Method::HEAD, true, false) => "HeadBucket",
+        (&Method::GET, true, false) if query.contains("uploads") => "ListMultipartUploads",
+        (&Method::GET, true, false) if query.contains("list-type=2") => "ListObjectsV2",
+        (&Method::GET, true, false) => "ListObjects",
+        (&Method::PUT, true, false) => "CreateBucket",
+        (&Method::DELETE, true, false) => "DeleteBucket",
+        (&Method::POST, true, false) if query.contains("delete") => "DeleteObjects",
+        (&Method::POST, true, false) => "PostObject",
+        (&Method::HEAD, true, true) => "HeadObject",
+        (&Method::GET, true, true) if has_upload_id => "ListParts",
+        (&Method::GET, true, true) => "GetObject",
+        (&Method::PUT, true, true) if has_upload_id && is_copy => "UploadPartCopy",
+        (&Method::PUT, true, true) if has_upload_id => "UploadPart",
+        (&Method::PUT, true, true) if is_rename => "RenameObject",
+        (&Method::PUT, true, true) if is_copy => "CopyObject",
+        (&Method::PUT, true, true) => "PutObject",
+        (&Method::DELETE, true, true) if has_upload_id => "AbortMultipartUpload",
+        (&Method::DELETE, true, true) => "DeleteObject",
+        (&Method::POST, true, true) if query.contains("uploads") => "CreateMultipartUpload",
+        (&Method::POST, true, true) if has_upload_id => "CompleteMultipartUpload",
+        _ => "Unknown",
+    };
+    name.to_string()
+}
@@ -0,0 +1,271 @@
+//! Soft deletes for `--trash-prefix`. DeleteObject and DeleteObjects copy an
+//! object, with its sidecar, to `<prefix><deleted at>/<key>` before deleting
+//! it, so a mistaken delete can be undone through the admin endpoint until
+//! `--trash-retention` has passed and the background purge removes it.
+//!
+//! Each bucket keeps its trash in its own client: the zone root, its folder
+//! with `--bucket-as-prefix`, or an extra zone. Clients never see the trash:
+//! it is left out of listings and its keys are refused like keys outside the
+//! served folder.
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::Serialize;
+
+use crate::bunny::{Backend, StorageBackend};
+use crate::config::Config;
+use crate::error::{ProxyError, Result};
+
+use super::handlers::{AppState, is_valid_bucket_name};
+use super::object_meta::{META_PREFIX, ObjectMetaStore};
+
+/// Request header that deletes for good even when the trash is enabled.
+pub const HARD_DELETE: &str = "x-bunny-hard-delete";
+
+/// How often trash entries past the retention window are looked for.
+const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// The deletion time as it appears in trash paths, sortable as text.
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrashEntry {
+    pub key: String,
+    pub deleted_at: DateTime<Utc>,
+    pub size: u64,
+    pub trash_key: String,
+}
+
+#[derive(Debug)]
+pub struct Trash {
+    /// The trash folder, always ending in `/`.
+    prefix: String,
+    retention: Duration,
+}
+
+impl Trash {
+    pub fn new(config: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(prefix) = &config.trash_prefix else {
+            return Ok(None);
+        };
+        let prefix = prefix.trim_matches('/');
+        if prefix.is_empty() {
+            anyhow::bail!("--trash-prefix must name a folder");
+        }
+        Ok(Some(Self {
+            prefix: format!("{}/", prefix),
+            retention: config.trash_retention,
+        }))
+    }
+
+    /// Whether `key` is the trash folder or inside it.
+    pub fn contains(&self, key: &str) -> bool {
+        key.starts_with(&self.prefix) || key == self.prefix.trim_end_matches('/')
+    }
+
+    /// Whether a request asks to skip the trash.
+    pub fn is_hard_delete(headers: &axum::http::HeaderMap) -> bool {
+        headers
+            .get(HARD_DELETE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.eq_ignore_ascii_case("true"))
+    }
+
+    fn path(&self, deleted_at: DateTime<Utc>, key: &str) -> String {
+        format!(
+            "{}{}/{}",
+            self.prefix,
+            deleted_at.format(TIMESTAMP_FORMAT),
+            key
+        )
+    }
+
+    /// The deletion time and original key of a trash path.
+    fn parse<'a>(&self, trash_key: &'a str) -> Option<(DateTime<Utc>, &'a str)> {
+        let (stamp, key) = trash_key.strip_prefix(&self.prefix)?.split_once('/')?;
+        Some((parse_timestamp(stamp)?, key))
+    }
+
+    /// Copies `key` and its sidecar into the trash as deleted at
+    /// `deleted_at`, leaving the caller to delete the original. Returns
+    /// `None` if there is no such object.
+    pub async fn move_in(
+        &self,
+        client: &Backend,
+        key: &str,
+        deleted_at: DateTime<Utc>,
+    ) -> Result<Option<String>> {
+        let trash_key = self.path(deleted_at, key);
+        match client.copy_from(client, key, &trash_key).await {
+            Ok(()) => {}
+            Err(ProxyError::NotFound(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let meta = ObjectMetaStore::get(client, key).await?;
+        if !meta.is_empty() {
+            ObjectMetaStore::put(client, &trash_key, &meta).await?;
+        }
+        tracing::info!("Moved {} to the trash as {}", key, trash_key);
+        Ok(Some(trash_key))
+    }
+
+    /// Everything in the trash of `client`, most recently deleted first.
+    pub async fn list(&self, client: &Backend) -> Result<Vec<TrashEntry>> {
+        let mut entries: Vec<TrashEntry> = client
+            .list_recursive(&self.prefix, None)
+            .await?
+            .into_iter()
+            .filter_map(|obj| {
+                let trash_key = obj.s3_key();
+                let (deleted_at, key) = self.parse(&trash_key)?;
+                Some(TrashEntry {
+                    key: key.to_string(),
+                    deleted_at,
+                    size: obj.length.max(0) as u64,
+                    trash_key: trash_key.clone(),
+                })
+            })
+            .collect();
+        entries.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at).then(a.key.cmp(&b.key)));
+        Ok(entries)
+    }
+
+    /// Puts `key` back from the trash: the copy deleted at `deleted_at`, or
+    /// the latest one. An object that has since been written to `key` is
+    /// never overwritten.
+    pub async fn restore(
+        &self,
+        client: &Backend,
+        key: &str,
+        deleted_at: Option<DateTime<Utc>>,
+    ) -> Result<TrashEntry> {
+        let entry = self
+            .list(client)
+            .await?
+            .into_iter()
+            .find(|e| e.key == key && deleted_at.is_none_or(|at| at == e.deleted_at))
+            .ok_or_else(|| ProxyError::NotFound(format!("{} in the trash", key)))?;
+        match client.describe(key).await {
+            Ok(obj) if obj.length >= 0 && !obj.is_directory => {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "{} exists; delete it before restoring",
+                    key
+                )));
+            }
+            Ok(_) | Err(ProxyError::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        client.copy_from(client, &entry.trash_key, key).await?;
+        let meta = ObjectMetaStore::get(client, &entry.trash_key).await?;
+        if !meta.is_empty() {
+            ObjectMetaStore::put(client, key, &meta).await?;
+        }
+        client.delete(&entry.trash_key).await?;
+        ObjectMetaStore::delete(client, &entry.trash_key).await?;
+        tracing::info!("Restored {} from the trash ({})", key, entry.trash_key);
+        Ok(entry)
+    }
+
+    /// Deletes the trash folders of deletions older than the retention
+    /// window, with their sidecars, and returns how many were removed.
+    pub async fn purge(&self, client: &Backend, now: DateTime<Utc>) -> Result<usize> {
+        let cutoff = now - self.retention;
+        let mut purged = 0;
+        for folder in client.list(&self.prefix).await? {
+            if !folder.is_directory
+                || parse_timestamp(&folder.object_name).is_none_or(|at| at >= cutoff)
+            {
+                continue;
+            }
+            let path = format!("{}{}/", self.prefix, folder.object_name);
+            client.delete(&format!("{}/{}", META_PREFIX, path)).await?;
+            client.delete(&path).await?;
+            purged += 1;
+        }
+        Ok(purged)
+    }
+
+    /// Purges every bucket's trash periodically until the process exits.
+    pub async fn run(state: AppState) {
+        let Some(trash) = state.trash.clone() else {
+            return;
+        };
+        let mut ticker = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let clients = match bucket_clients(&state).await {
+                Ok(clients) => clients,
+                Err(e) => {
+                    tracing::warn!("Trash purge could not list buckets: {}", e);
+                    continue;
+                }
+            };
+            for (bucket, client) in clients {
+                match trash.purge(&client, Utc::now()).await {
+                    Ok(0) => {}
+                    Ok(purged) => tracing::info!(
+                        "Trash purge: removed {} expired deletions from {}",
+                        purged,
+                        bucket
+                    ),
+                    Err(e) => tracing::warn!("Trash purge of {} failed: {}", bucket, e),
+                }
+            }
+        }
+    }
+}
+
+fn parse_timestamp(stamp: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(stamp, TIMESTAMP_FORMAT)
+        .ok()
+        .map(|t| t.and_utc())
+}
+
+/// Every bucket the proxy serves, with the client holding its objects.
+async fn bucket_clients(state: &AppState) -> Result<Vec<(String, Backend)>> {
+    let mut clients: Vec<(String, Backend)> = match state.config.bucket_as_prefix {
+        true => state
+            .bucket_root
+            .list("")
+            .await?
+            .into_iter()
+            .filter(|o| o.is_directory && is_valid_bucket_name(&o.object_name))
+            .map(|o| {
+                let client = state.bucket_root.scoped(&o.object_name);
+                (o.object_name, client)
+            })
+            .collect(),
+        false => vec![(state.config.storage_zone.clone(), state.bunny.clone())],
+    };
+    clients.extend(
+        state
+            .zones
+            .iter()
+            .map(|(name, client)| (name.clone(), client.clone())),
+    );
+    Ok(clients)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trash_paths() {
+        let trash = Trash {
+            prefix: "__trash/".into(),
+            retention: Duration::days(7),
+        };
+        let deleted_at = DateTime::parse_from_rfc3339("2024-05-01T12:30:45.678Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let path = trash.path(deleted_at, "photos/cat.jpg");
+        assert_eq!(path, "__trash/20240501T123045.678Z/photos/cat.jpg");
+        assert_eq!(trash.parse(&path), Some((deleted_at, "photos/cat.jpg")));
+        assert_eq!(trash.parse("__trash/garbage/photos/cat.jpg"), None);
+
+        assert!(trash.contains("__trash"));
+        assert!(trash.contains(&path));
+        assert!(!trash.contains("__trashcan/x"));
+    }
+}
@@ -1,4 +1,5 @@
 use chrono::{DateTime, Utc};
+use serde::de::IgnoredAny;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone)]
@@ -11,6 +12,19 @@ pub struct S3Object {
     pub owner: Option<S3Owner>,
 }
 
+/// A version or delete marker in a ListObjectVersions result.
+#[derive(Debug, Clone)]
+pub struct S3ObjectVersion {
+    pub key: String,
+    pub version_id: String,
+    pub is_latest: bool,
+    pub delete_marker: bool,
+    pub last_modified: DateTime<Utc>,
+    pub etag: String,
+    pub size: i64,
+    pub storage_class: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct S3Owner {
     pub id: String,
@@ -21,6 +35,7 @@ pub struct S3Owner {
 pub struct S3Bucket {
     pub name: String,
     pub creation_date: DateTime<Utc>,
+    pub region: String,
 }
 
 #[derive(Debug, Clone)]
@@ -28,6 +43,15 @@ pub struct S3CommonPrefix {
     pub prefix: String,
 }
 
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ListBucketsQuery {
+    pub max_buckets: Option<u32>,
+    pub continuation_token: Option<String>,
+    pub prefix: Option<String>,
+    pub bucket_region: Option<String>,
+}
+
 #[derive(Debug, Clone, Default, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct ListObjectsV2Query {
@@ -41,6 +65,25 @@ pub struct ListObjectsV2Query {
     pub start_after: Option<String>,
 }
 
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ListObjectsV1Query {
+    pub prefix: Option<String>,
+    pub delimiter: Option<String>,
+    pub max_keys: Option<u32>,
+    pub marker: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ListObjectVersionsQuery {
+    pub prefix: Option<String>,
+    pub delimiter: Option<String>,
+    pub max_keys: Option<u32>,
+    pub key_marker: Option<String>,
+    pub version_id_marker: Option<String>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct DeleteRequest {
@@ -69,6 +112,334 @@ pub struct CompleteMultipartUpload {
     pub part: Vec<Part>,
 }
 
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CreateBucketConfiguration {
+    pub location_constraint: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct VersioningConfiguration {
+    pub status: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AccessControlPolicy {
+    pub access_control_list: Option<AccessControlList>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AccessControlList {
+    #[serde(default)]
+    pub grant: Vec<Grant>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Grant {
+    pub grantee: Option<Grantee>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Grantee {
+    #[serde(rename = "URI")]
+    pub uri: Option<String>,
+}
+
+impl AccessControlPolicy {
+    /// Whether any grant targets the AllUsers or AuthenticatedUsers groups.
+    pub fn grants_public_access(&self) -> bool {
+        self.access_control_list.iter().any(|acl| {
+            acl.grant.iter().any(|g| {
+                g.grantee
+                    .as_ref()
+                    .and_then(|g| g.uri.as_deref())
+                    .is_some_and(|uri| {
+                        uri.ends_with("/AllUsers") || uri.ends_with("/AuthenticatedUsers")
+                    })
+            })
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LifecycleConfiguration {
+    #[serde(default)]
+    pub rule: Vec<LifecycleRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LifecycleRule {
+    #[serde(rename = "ID")]
+    pub id: Option<String>,
+    pub status: String,
+    pub prefix: Option<String>,
+    pub filter: Option<LifecycleFilter>,
+    pub expiration: Option<LifecycleExpiration>,
+    pub abort_incomplete_multipart_upload: Option<AbortIncompleteMultipartUpload>,
+    pub transition: Option<IgnoredAny>,
+    pub noncurrent_version_transition: Option<IgnoredAny>,
+    pub noncurrent_version_expiration: Option<NoncurrentVersionExpiration>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LifecycleFilter {
+    pub prefix: Option<String>,
+    pub tag: Option<IgnoredAny>,
+    pub and: Option<IgnoredAny>,
+    pub object_size_greater_than: Option<IgnoredAny>,
+    pub object_size_less_than: Option<IgnoredAny>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LifecycleExpiration {
+    pub days: Option<u32>,
+    pub date: Option<String>,
+    pub expired_object_delete_marker: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NoncurrentVersionExpiration {
+    pub noncurrent_days: u32,
+    pub newer_noncurrent_versions: Option<IgnoredAny>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AbortIncompleteMultipartUpload {
+    pub days_after_initiation: u32,
+}
+
+impl LifecycleRule {
+    pub fn is_enabled(&self) -> bool {
+        self.status == "Enabled"
+    }
+
+    pub fn key_prefix(&self) -> &str {
+        self.filter
+            .as_ref()
+            .and_then(|f| f.prefix.as_deref())
+            .or(self.prefix.as_deref())
+            .unwrap_or("")
+    }
+
+    pub fn expiration_days(&self) -> Option<u32> {
+        self.expiration.as_ref().and_then(|e| e.days)
+    }
+
+    pub fn noncurrent_days(&self) -> Option<u32> {
+        self.noncurrent_version_expiration
+            .as_ref()
+            .map(|e| e.noncurrent_days)
+    }
+
+    pub fn abort_days(&self) -> Option<u32> {
+        self.abort_incomplete_multipart_upload
+            .as_ref()
+            .map(|a| a.days_after_initiation)
+    }
+}
+
+impl LifecycleConfiguration {
+    /// Rejects rules the proxy cannot enforce instead of silently accepting them.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.rule.is_empty() {
+            return Err("Lifecycle configuration must contain at least one rule".into());
+        }
+        if self.rule.len() > 1000 {
+            return Err("Lifecycle configuration cannot have more than 1000 rules".into());
+        }
+        for rule in &self.rule {
+            let id = rule.id.as_deref().unwrap_or("<unnamed>");
+            if rule.id.as_ref().is_some_and(|id| id.len() > 255) {
+                return Err("Rule ID cannot be longer than 255 characters".into());
+            }
+            if rule.status != "Enabled" && rule.status != "Disabled" {
+                return Err(format!("Rule {}: invalid Status {}", id, rule.status));
+            }
+            if rule.transition.is_some() || rule.noncurrent_version_transition.is_some() {
+                return Err(format!("Rule {}: transitions are not supported", id));
+            }
+            if let Some(noncurrent) = &rule.noncurrent_version_expiration {
+                if noncurrent.newer_noncurrent_versions.is_some() {
+                    return Err(format!(
+                        "Rule {}: only NoncurrentVersionExpiration.NoncurrentDays is supported",
+                        id
+                    ));
+                }
+                if noncurrent.noncurrent_days == 0 {
+                    return Err(format!(
+                        "Rule {}: NoncurrentDays must be a positive integer",
+                        id
+                    ));
+                }
+            }
+            if let Some(filter) = &rule.filter
+                && (filter.tag.is_some()
+                    || filter.and.is_some()
+                    || filter.object_size_greater_than.is_some()
+                    || filter.object_size_less_than.is_some())
+            {
+                return Err(format!("Rule {}: only prefix filters are supported", id));
+            }
+            if let Some(expiration) = &rule.expiration {
+                if expiration.date.is_some() || expiration.expired_object_delete_marker.is_some() {
+                    return Err(format!("Rule {}: only Expiration.Days is supported", id));
+                }
+                if expiration.days.is_none_or(|d| d == 0) {
+                    return Err(format!(
+                        "Rule {}: Expiration.Days must be a positive integer",
+                        id
+                    ));
+                }
+            }
+            if rule.abort_days() == Some(0) {
+                return Err(format!(
+                    "Rule {}: DaysAfterInitiation must be a positive integer",
+                    id
+                ));
+            }
+            if rule.expiration.is_none()
+                && rule.abort_incomplete_multipart_upload.is_none()
+                && rule.noncurrent_version_expiration.is_none()
+            {
+                return Err(format!("Rule {}: at least one action is required", id));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Tagging {
+    pub tag_set: TagSet,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TagSet {
+    #[serde(default)]
+    pub tag: Vec<Tag>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Tag {
+    pub key: String,
+    #[serde(default)]
+    pub value: String,
+}
+
+impl Tagging {
+    pub const MAX_BUCKET_TAGS: usize = 50;
+
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        let tags = &self.tag_set.tag;
+        if tags.len() > Self::MAX_BUCKET_TAGS {
+            return Err(format!(
+                "Bucket tag count cannot be greater than {}",
+                Self::MAX_BUCKET_TAGS
+            ));
+        }
+        let mut seen = std::collections::HashSet::new();
+        for tag in tags {
+            let key_len = tag.key.chars().count();
+            if key_len == 0 || key_len > 128 {
+                return Err(format!(
+                    "Tag key must be between 1 and 128 characters: {}",
+                    tag.key
+                ));
+            }
+            if tag.value.chars().count() > 256 {
+                return Err(format!(
+                    "Tag value cannot be longer than 256 characters: {}",
+                    tag.key
+                ));
+            }
+            if !seen.insert(tag.key.as_str()) {
+                return Err(format!("Duplicate tag key: {}", tag.key));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The body of SelectObjectContent. `RequestProgress` is ignored, as is
+/// `AllowQuotedRecordDelimiter`: quoted record delimiters are always allowed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SelectObjectContentRequest {
+    pub expression: String,
+    pub expression_type: String,
+    pub input_serialization: InputSerialization,
+    pub output_serialization: OutputSerialization,
+    pub scan_range: Option<IgnoredAny>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct InputSerialization {
+    pub compression_type: Option<String>,
+    #[serde(rename = "CSV")]
+    pub csv: Option<CsvInput>,
+    #[serde(rename = "JSON")]
+    pub json: Option<JsonInput>,
+    pub parquet: Option<IgnoredAny>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CsvInput {
+    pub file_header_info: Option<String>,
+    pub comments: Option<String>,
+    pub quote_escape_character: Option<String>,
+    pub record_delimiter: Option<String>,
+    pub field_delimiter: Option<String>,
+    pub quote_character: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct JsonInput {
+    #[serde(rename = "Type")]
+    pub kind: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct OutputSerialization {
+    #[serde(rename = "CSV")]
+    pub csv: Option<CsvOutput>,
+    #[serde(rename = "JSON")]
+    pub json: Option<JsonOutput>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CsvOutput {
+    pub quote_fields: Option<String>,
+    pub quote_escape_character: Option<String>,
+    pub record_delimiter: Option<String>,
+    pub field_delimiter: Option<String>,
+    pub quote_character: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct JsonOutput {
+    pub record_delimiter: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct CopySource {
     pub bucket: String,
@@ -86,9 +457,12 @@ impl CopySource {
             .split_once("?versionId=")
             .map(|(k, _)| k)
             .unwrap_or(parts[1]);
+        // The header is URL-encoded like a request path.
         Some(Self {
             bucket: parts[0].to_string(),
-            key: key.to_string(),
+            key: percent_encoding::percent_decode_str(key)
+                .decode_utf8_lossy()
+                .into_owned(),
         })
     }
 }
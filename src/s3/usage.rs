@@ -0,0 +1,227 @@
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use crate::bunny::types::StorageObject;
+use crate::bunny::{Backend, StorageBackend};
+use crate::config::Config;
+use crate::error::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageSource {
+    /// Every object under the prefix was listed.
+    Walk,
+    /// Zone totals from the Bunny account API; no per-object detail.
+    Statistics,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    pub prefix: String,
+    pub source: UsageSource,
+    pub objects: u64,
+    pub total_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub largest: Option<LargestObject>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefixes: Option<Vec<PrefixUsage>>,
+    pub computed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct LargestObject {
+    pub key: String,
+    pub size: u64,
+}
+
+/// Totals for one directory directly below the requested prefix. Objects
+/// sitting at the prefix itself are not listed here.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct PrefixUsage {
+    pub prefix: String,
+    pub objects: u64,
+    pub total_bytes: u64,
+}
+
+/// Computes and caches storage usage per prefix. Only one walk runs at a
+/// time, so repeated requests cannot multiply the listing load on Bunny.
+pub struct UsageCache {
+    ttl: Duration,
+    concurrency: usize,
+    reports: DashMap<String, (Instant, Arc<UsageReport>)>,
+    walk: Mutex<()>,
+}
+
+impl UsageCache {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.usage_cache_secs),
+            concurrency: config.usage_walk_concurrency,
+            reports: DashMap::new(),
+            walk: Mutex::new(()),
+        }
+    }
+
+    pub async fn report(&self, client: &Backend, prefix: &str) -> Result<Arc<UsageReport>> {
+        if let Some(report) = self.cached(prefix) {
+            return Ok(report);
+        }
+
+        let _walk = self.walk.lock().await;
+        // Another request may have computed it while we waited.
+        if let Some(report) = self.cached(prefix) {
+            return Ok(report);
+        }
+
+        let report = Arc::new(self.compute(client, prefix).await?);
+        self.reports
+            .insert(prefix.to_string(), (Instant::now(), Arc::clone(&report)));
+        Ok(report)
+    }
+
+    fn cached(&self, prefix: &str) -> Option<Arc<UsageReport>> {
+        self.reports
+            .get(prefix)
+            .filter(|entry| entry.0.elapsed() < self.ttl)
+            .map(|entry| Arc::clone(&entry.1))
+    }
+
+    async fn compute(&self, client: &Backend, prefix: &str) -> Result<UsageReport> {
+        if prefix.is_empty() {
+            match client.zone_statistics().await {
+                Ok(Some(stats)) => {
+                    return Ok(UsageReport {
+                        prefix: String::new(),
+                        source: UsageSource::Statistics,
+                        objects: stats.files_stored,
+                        total_bytes: stats.storage_used,
+                        largest: None,
+                        prefixes: None,
+                        computed_at: Utc::now(),
+                    });
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::warn!("Zone statistics unavailable, walking instead: {}", e);
+                }
+            }
+        }
+
+        let started = Instant::now();
+        let objects = client
+            .list_recursive_concurrent(prefix, self.concurrency)
+            .await?;
+        tracing::info!(
+            "Usage walk of '{}' listed {} objects in {:?}",
+            prefix,
+            objects.len(),
+            started.elapsed()
+        );
+        Ok(summarize(prefix, &objects))
+    }
+}
+
+fn summarize(prefix: &str, objects: &[StorageObject]) -> UsageReport {
+    let mut total_bytes = 0;
+    let mut largest: Option<LargestObject> = None;
+    let mut prefixes: BTreeMap<String, PrefixUsage> = BTreeMap::new();
+
+    for obj in objects {
+        let key = obj.s3_key();
+        let size = obj.length.max(0) as u64;
+        total_bytes += size;
+        if largest.as_ref().is_none_or(|l| size > l.size) {
+            largest = Some(LargestObject {
+                key: key.clone(),
+                size,
+            });
+        }
+
+        let rest = key.strip_prefix(prefix).unwrap_or(&key);
+        if let Some((dir, _)) = rest.split_once('/') {
+            let dir = format!("{}{}/", prefix, dir);
+            let entry = prefixes.entry(dir.clone()).or_insert_with(|| PrefixUsage {
+                prefix: dir,
+                objects: 0,
+                total_bytes: 0,
+            });
+            entry.objects += 1;
+            entry.total_bytes += size;
+        }
+    }
+
+    UsageReport {
+        prefix: prefix.to_string(),
+        source: UsageSource::Walk,
+        objects: objects.len() as u64,
+        total_bytes,
+        largest,
+        prefixes: Some(prefixes.into_values().collect()),
+        computed_at: Utc::now(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(path: &str, name: &str, length: i64) -> StorageObject {
+        StorageObject {
+            guid: String::new(),
+            user_id: String::new(),
+            last_changed: Utc::now(),
+            date_created: Utc::now(),
+            storage_zone_name: "zone".into(),
+            path: path.into(),
+            object_name: name.into(),
+            length,
+            storage_zone_id: 1,
+            is_directory: false,
+            server_id: 0,
+            checksum: None,
+            replicated_zones: None,
+            content_type: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_summarize_breaks_down_by_top_level_directory() {
+        let objects = [
+            object("/zone/logs/", "root.txt", 10),
+            object("/zone/logs/2026/", "a.log", 100),
+            object("/zone/logs/2026/10/", "b.log", 250),
+            object("/zone/logs/2025/", "c.log", 5),
+        ];
+        let report = summarize("logs/", &objects);
+
+        assert_eq!(report.objects, 4);
+        assert_eq!(report.total_bytes, 365);
+        assert_eq!(
+            report.largest,
+            Some(LargestObject {
+                key: "logs/2026/10/b.log".into(),
+                size: 250,
+            })
+        );
+        assert_eq!(
+            report.prefixes.unwrap(),
+            vec![
+                PrefixUsage {
+                    prefix: "logs/2025/".into(),
+                    objects: 1,
+                    total_bytes: 5,
+                },
+                PrefixUsage {
+                    prefix: "logs/2026/".into(),
+                    objects: 2,
+                    total_bytes: 350,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! `--emulate-versioning`: S3 versioning for keys under the configured
+//! prefixes, kept by the proxy since Bunny stores one object per path.
+//!
+//! The current object stays at its key, with its version ID in the sidecar
+//! (`null` for objects written before versioning applied to them). Before it
+//! is replaced or deleted it is copied, with its sidecar, to
+//! `__versions/<key>/<version id>`, and a delete without a version ID leaves
+//! a zero-byte `__versions/<key>/<version id>.delete-marker` behind. Version
+//! IDs are the UTC time of the write, so they sort in the order written.
+
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use crate::bunny::{Backend, StorageBackend};
+use crate::error::{ProxyError, Result};
+
+use super::object_meta::{ObjectMeta, ObjectMetaStore};
+
+/// Folder holding noncurrent versions and delete markers.
+pub const VERSIONS_PREFIX: &str = "__versions";
+
+/// The version ID of objects written while versioning did not apply.
+pub const NULL_VERSION_ID: &str = "null";
+
+const DELETE_MARKER_SUFFIX: &str = ".delete-marker";
+
+const VERSION_ID_FORMAT: &str = "%Y%m%dT%H%M%S%6fZ";
+
+/// A noncurrent version or delete marker.
+#[derive(Debug, Clone)]
+pub struct VersionEntry {
+    pub key: String,
+    pub version_id: String,
+    pub delete_marker: bool,
+    /// When the version was written, from its ID where it has one.
+    pub last_modified: DateTime<Utc>,
+    /// When it stopped being current.
+    pub noncurrent_since: DateTime<Utc>,
+    pub size: i64,
+    pub etag: String,
+    /// Where it is stored.
+    pub path: String,
+}
+
+impl VersionEntry {
+    /// Orders versions by write time; the `null` version predates them all.
+    pub fn order_key(&self) -> &str {
+        match self.version_id.as_str() {
+            NULL_VERSION_ID => "",
+            id => id,
+        }
+    }
+}
+
+/// What deleting a versioned key did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deleted {
+    /// The delete marker created, or the version removed.
+    pub version_id: String,
+    pub delete_marker: bool,
+}
+
+pub struct VersionStore;
+
+impl VersionStore {
+    /// Whether a key belongs to the versions area.
+    pub fn is_internal_key(key: &str) -> bool {
+        key == VERSIONS_PREFIX
+            || key
+                .strip_prefix(VERSIONS_PREFIX)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    pub fn new_version_id(now: DateTime<Utc>) -> String {
+        now.format(VERSION_ID_FORMAT).to_string()
+    }
+
+    fn written_at(version_id: &str) -> Option<DateTime<Utc>> {
+        NaiveDateTime::parse_from_str(version_id, VERSION_ID_FORMAT)
+            .ok()
+            .map(|t| t.and_utc())
+    }
+
+    fn path(key: &str, version_id: &str) -> String {
+        format!("{}/{}/{}", VERSIONS_PREFIX, key, version_id)
+    }
+
+    /// The version ID and sidecar of the object currently at `key`, if any.
+    pub async fn current(client: &Backend, key: &str) -> Result<Option<(String, ObjectMeta)>> {
+        match client.describe(key).await {
+            Ok(obj) if obj.length >= 0 && !obj.is_directory => {}
+            Ok(_) | Err(ProxyError::NotFound(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let meta = ObjectMetaStore::get(client, key).await?;
+        let version_id = meta
+            .version_id
+            .clone()
+            .unwrap_or_else(|| NULL_VERSION_ID.to_string());
+        Ok(Some((version_id, meta)))
+    }
+
+    /// Copies the object at `key`, if there is one, into the versions area
+    /// before it is replaced or deleted, and returns its version ID.
+    pub async fn archive_current(client: &Backend, key: &str) -> Result<Option<String>> {
+        let Some((version_id, meta)) = Self::current(client, key).await? else {
+            return Ok(None);
+        };
+        let path = Self::path(key, &version_id);
+        client.copy_from(client, key, &path).await?;
+        if !meta.is_empty() {
+            ObjectMetaStore::put(client, &path, &meta).await?;
+        }
+        Ok(Some(version_id))
+    }
+
+    /// Puts the version of `key` just archived back in place after the
+    /// write that was to replace it failed, which may have left anything at
+    /// `key`. The sidecar at `key` is still the version's own.
+    pub async fn restore(client: &Backend, key: &str, version_id: &str) -> Result<()> {
+        let path = Self::path(key, version_id);
+        client.copy_from(client, &path, key).await?;
+        client.delete(&path).await?;
+        ObjectMetaStore::delete(client, &path).await
+    }
+
+    pub async fn put_delete_marker(client: &Backend, key: &str, version_id: &str) -> Result<()> {
+        let path = format!("{}{}", Self::path(key, version_id), DELETE_MARKER_SUFFIX);
+        client.upload(&path, Bytes::new(), Default::default()).await
+    }
+
+    /// The noncurrent versions and delete markers of `key`, newest first.
+    pub async fn history(client: &Backend, key: &str) -> Result<Vec<VersionEntry>> {
+        let dir = format!("{}/{}/", VERSIONS_PREFIX, key);
+        let objects = match client.list(&dir).await {
+            Ok(objects) => objects,
+            Err(ProxyError::NotFound(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut entries: Vec<VersionEntry> = objects
+            .iter()
+            .filter(|obj| !obj.is_directory)
+            .filter_map(|obj| Self::entry(key, obj))
+            .collect();
+        entries.sort_by(|a, b| b.order_key().cmp(a.order_key()));
+        Ok(entries)
+    }
+
+    /// Every noncurrent version and delete marker of keys starting with
+    /// `prefix`.
+    pub async fn list(client: &Backend, prefix: &str) -> Result<Vec<VersionEntry>> {
+        let dir = prefix.rfind('/').map(|i| &prefix[..=i]).unwrap_or("");
+        let objects = match client
+            .list_recursive(&format!("{}/{}", VERSIONS_PREFIX, dir), None)
+            .await
+        {
+            Ok(objects) => objects,
+            Err(ProxyError::NotFound(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(objects
+            .iter()
+            .filter_map(|obj| {
+                let path = obj.s3_key();
+                let (key, _) = path
+                    .strip_prefix(VERSIONS_PREFIX)?
+                    .strip_prefix('/')?
+                    .rsplit_once('/')?;
+                Self::entry(key, obj)
+            })
+            .filter(|entry| entry.key.starts_with(prefix))
+            .collect())
+    }
+
+    fn entry(key: &str, obj: &crate::bunny::types::StorageObject) -> Option<VersionEntry> {
+        let name = obj.object_name.as_str();
+        let (version_id, delete_marker) = match name.strip_suffix(DELETE_MARKER_SUFFIX) {
+            Some(id) => (id, true),
+            None => (name, false),
+        };
+        let written_at = Self::written_at(version_id);
+        if written_at.is_none() && version_id != NULL_VERSION_ID {
+            return None;
+        }
+        Some(VersionEntry {
+            key: key.to_string(),
+            version_id: version_id.to_string(),
+            delete_marker,
+            last_modified: written_at.unwrap_or(obj.last_changed),
+            noncurrent_since: obj.date_created,
+            size: obj.length.max(0),
+            etag: obj.etag(),
+            path: format!("{}/{}/{}", VERSIONS_PREFIX, key, name),
+        })
+    }
+
+    /// The noncurrent version or delete marker `version_id` of `key`.
+    pub async fn find(client: &Backend, key: &str, version_id: &str) -> Result<VersionEntry> {
+        Self::history(client, key)
+            .await?
+            .into_iter()
+            .find(|entry| entry.version_id == version_id)
+            .ok_or_else(|| ProxyError::NoSuchVersion(version_id.to_string()))
+    }
+
+    /// DeleteObject on a versioned key. Without a version ID the current
+    /// object is archived and replaced by a delete marker; with one, that
+    /// version or marker is removed for good and the newest remaining
+    /// version becomes current if nothing else is.
+    pub async fn delete(client: &Backend, key: &str, version_id: Option<&str>) -> Result<Deleted> {
+        let Some(version_id) = version_id else {
+            Self::archive_current(client, key).await?;
+            Self::delete_current(client, key).await?;
+            let marker = Self::new_version_id(Utc::now());
+            Self::put_delete_marker(client, key, &marker).await?;
+            return Ok(Deleted {
+                version_id: marker,
+                delete_marker: true,
+            });
+        };
+        let delete_marker = match Self::current(client, key).await? {
+            Some((current, _)) if current == version_id => {
+                Self::delete_current(client, key).await?;
+                false
+            }
+            _ => {
+                let entry = Self::find(client, key, version_id).await?;
+                Self::delete_entry(client, &entry).await?;
+                entry.delete_marker
+            }
+        };
+        Self::promote_latest(client, key).await?;
+        Ok(Deleted {
+            version_id: version_id.to_string(),
+            delete_marker,
+        })
+    }
+
+    async fn delete_current(client: &Backend, key: &str) -> Result<()> {
+        let (deleted, meta_deleted) =
+            tokio::join!(client.delete(key), ObjectMetaStore::delete(client, key));
+        deleted?;
+        meta_deleted
+    }
+
+    /// Deletes a noncurrent version or delete marker with its sidecar.
+    pub async fn delete_entry(client: &Backend, entry: &VersionEntry) -> Result<()> {
+        client.delete(&entry.path).await?;
+        ObjectMetaStore::delete(client, &entry.path).await
+    }
+
+    /// Once `key` has no current object, makes its newest noncurrent version
+    /// current again, unless a delete marker is newer. Used after the current
+    /// version or the latest delete marker is deleted by version ID.
+    pub async fn promote_latest(client: &Backend, key: &str) -> Result<()> {
+        if Self::current(client, key).await?.is_some() {
+            return Ok(());
+        }
+        let Some(latest) = Self::history(client, key).await?.into_iter().next() else {
+            return Ok(());
+        };
+        if latest.delete_marker {
+            return Ok(());
+        }
+        client.copy_from(client, &latest.path, key).await?;
+        let mut meta = ObjectMetaStore::get(client, &latest.path).await?;
+        meta.version_id = Some(latest.version_id.clone());
+        ObjectMetaStore::put(client, key, &meta).await?;
+        Self::delete_entry(client, &latest).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_ids_sort_by_write_time() {
+        let first = DateTime::parse_from_rfc3339("2024-05-01T09:59:59.999999Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let second = first + chrono::Duration::microseconds(1);
+        let (a, b) = (
+            VersionStore::new_version_id(first),
+            VersionStore::new_version_id(second),
+        );
+        assert_eq!(a, "20240501T095959999999Z");
+        assert!(a < b);
+        assert_eq!(VersionStore::written_at(&b), Some(second));
+        assert_eq!(VersionStore::written_at(NULL_VERSION_ID), None);
+
+        assert!(VersionStore::is_internal_key("__versions/a.txt/null"));
+        assert!(!VersionStore::is_internal_key("__versionsx/a.txt"));
+    }
+}
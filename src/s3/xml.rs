@@ -1,5 +1,11 @@
-use super::types::{S3Bucket, S3CommonPrefix, S3Object, S3Owner};
+use super::types::{S3Bucket, S3CommonPrefix, S3Object, S3ObjectVersion, S3Owner, Tag};
+use super::versions::NULL_VERSION_ID;
 use chrono::{DateTime, Utc};
+use quick_xml::events::{BytesEnd, BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use serde::de::DeserializeOwned;
+
+use crate::error::{ProxyError, Result};
 
 pub struct ListObjectsV2Params<'a> {
     pub bucket: &'a str,
@@ -15,39 +21,66 @@ pub struct ListObjectsV2Params<'a> {
     pub start_after: Option<&'a str>,
 }
 
-pub fn list_buckets_response(buckets: &[S3Bucket], owner: &S3Owner) -> String {
+pub struct ListObjectsV1Params<'a> {
+    pub bucket: &'a str,
+    pub prefix: Option<&'a str>,
+    pub delimiter: Option<&'a str>,
+    pub max_keys: u32,
+    pub objects: &'a [S3Object],
+    pub common_prefixes: &'a [S3CommonPrefix],
+    pub is_truncated: bool,
+    pub marker: Option<&'a str>,
+    pub next_marker: Option<&'a str>,
+}
+
+pub fn list_buckets_response(
+    buckets: &[S3Bucket],
+    owner: &S3Owner,
+    prefix: Option<&str>,
+    continuation_token: Option<&str>,
+) -> String {
     let buckets_xml: String = buckets
         .iter()
         .map(|b| {
             format!(
-                "<Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket>",
+                "<Bucket><Name>{}</Name><CreationDate>{}</CreationDate><BucketRegion>{}</BucketRegion></Bucket>",
                 esc(&b.name),
-                b.creation_date.format("%Y-%m-%dT%H:%M:%S%.3fZ")
+                b.creation_date.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                esc(&b.region)
             )
         })
         .collect();
+    let prefix_xml = prefix
+        .map(|p| format!("<Prefix>{}</Prefix>", esc(p)))
+        .unwrap_or_default();
+    let token_xml = continuation_token
+        .map(|t| format!("<ContinuationToken>{}</ContinuationToken>", esc(t)))
+        .unwrap_or_default();
 
     format!(
         r#"<?xml version="1.0" encoding="UTF-8"?>
 <ListAllMyBucketsResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
 <Owner><ID>{}</ID><DisplayName>{}</DisplayName></Owner>
-<Buckets>{}</Buckets>
+<Buckets>{}</Buckets>{}{}
 </ListAllMyBucketsResult>"#,
         esc(&owner.id),
         esc(&owner.display_name),
-        buckets_xml
+        buckets_xml,
+        prefix_xml,
+        token_xml
     )
 }
 
-pub fn list_objects_v2_response(params: ListObjectsV2Params<'_>) -> String {
-    let contents: String = params.objects.iter().map(|obj| {
+fn contents_xml(objects: &[S3Object]) -> String {
+    objects.iter().map(|obj| {
         let owner_xml = obj.owner.as_ref().map(|o| format!("<Owner><ID>{}</ID><DisplayName>{}</DisplayName></Owner>", esc(&o.id), esc(&o.display_name))).unwrap_or_default();
         format!(r#"<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>"{}"</ETag><Size>{}</Size><StorageClass>{}</StorageClass>{}</Contents>"#,
             esc(&obj.key), obj.last_modified.format("%Y-%m-%dT%H:%M:%S%.3fZ"), esc(&obj.etag), obj.size, obj.storage_class, owner_xml)
-    }).collect();
+    }).collect()
+}
 
-    let cp_xml: String = params
-        .common_prefixes
+fn common_prefixes_xml(common_prefixes: &[S3CommonPrefix]) -> String {
+    common_prefixes
         .iter()
         .map(|cp| {
             format!(
@@ -55,7 +88,43 @@ pub fn list_objects_v2_response(params: ListObjectsV2Params<'_>) -> String {
                 esc(&cp.prefix)
             )
         })
-        .collect();
+        .collect()
+}
+
+/// The ListObjects (V1) answer, paginated with `Marker` and `NextMarker`
+/// instead of continuation tokens.
+pub fn list_objects_v1_response(params: ListObjectsV1Params<'_>) -> String {
+    let prefix_xml = params
+        .prefix
+        .map(|p| format!("<Prefix>{}</Prefix>", esc(p)))
+        .unwrap_or_default();
+    let delim_xml = params
+        .delimiter
+        .map(|d| format!("<Delimiter>{}</Delimiter>", esc(d)))
+        .unwrap_or_default();
+    let next_xml = params
+        .next_marker
+        .map(|m| format!("<NextMarker>{}</NextMarker>", esc(m)))
+        .unwrap_or_default();
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+<Name>{}</Name>{}<Marker>{}</Marker>{}{}<MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>{}{}
+</ListBucketResult>"#,
+        esc(params.bucket),
+        prefix_xml,
+        esc(params.marker.unwrap_or("")),
+        next_xml,
+        delim_xml,
+        params.max_keys,
+        params.is_truncated,
+        contents_xml(params.objects),
+        common_prefixes_xml(params.common_prefixes)
+    )
+}
+
+pub fn list_objects_v2_response(params: ListObjectsV2Params<'_>) -> String {
     let prefix_xml = params
         .prefix
         .map(|p| format!("<Prefix>{}</Prefix>", esc(p)))
@@ -91,8 +160,8 @@ pub fn list_objects_v2_response(params: ListObjectsV2Params<'_>) -> String {
         cont_xml,
         next_xml,
         start_xml,
-        contents,
-        cp_xml
+        contents_xml(params.objects),
+        common_prefixes_xml(params.common_prefixes)
     )
 }
 
@@ -105,8 +174,19 @@ pub fn copy_object_response(etag: &str, last_modified: DateTime<Utc>) -> String
     )
 }
 
+pub fn copy_part_response(etag: &str, last_modified: DateTime<Utc>) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<CopyPartResult><ETag>"{}"</ETag><LastModified>{}</LastModified></CopyPartResult>"#,
+        esc(etag),
+        last_modified.format("%Y-%m-%dT%H:%M:%S%.3fZ")
+    )
+}
+
+/// `deleted` holds each key with the version ID asked for and, when the
+/// delete left a marker, the marker's version ID.
 pub fn delete_objects_response(
-    deleted: &[(String, Option<String>)],
+    deleted: &[(String, Option<String>, Option<String>)],
     errors: &[(String, String, String)],
     quiet: bool,
 ) -> String {
@@ -115,12 +195,21 @@ pub fn delete_objects_response(
     } else {
         deleted
             .iter()
-            .map(|(key, ver)| {
+            .map(|(key, ver, marker)| {
                 let v = ver
                     .as_ref()
                     .map(|v| format!("<VersionId>{}</VersionId>", esc(v)))
                     .unwrap_or_default();
-                format!("<Deleted><Key>{}</Key>{}</Deleted>", esc(key), v)
+                let m = marker
+                    .as_ref()
+                    .map(|m| {
+                        format!(
+                            "<DeleteMarker>true</DeleteMarker><DeleteMarkerVersionId>{}</DeleteMarkerVersionId>",
+                            esc(m)
+                        )
+                    })
+                    .unwrap_or_default();
+                format!("<Deleted><Key>{}</Key>{}{}</Deleted>", esc(key), v, m)
             })
             .collect()
     };
@@ -155,6 +244,34 @@ pub fn initiate_multipart_upload_response(bucket: &str, key: &str, upload_id: &s
     )
 }
 
+/// The result of CompleteMultipartUpload, without the XML declaration, which
+/// is sent ahead of it while the object is assembled.
+pub fn complete_multipart_upload_result(
+    location: &str,
+    bucket: &str,
+    key: &str,
+    etag: &str,
+) -> String {
+    format!(
+        r#"<CompleteMultipartUploadResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><Location>{}</Location><Bucket>{}</Bucket><Key>{}</Key><ETag>"{}"</ETag></CompleteMultipartUploadResult>"#,
+        esc(location),
+        esc(bucket),
+        esc(key),
+        esc(etag)
+    )
+}
+
+pub fn post_object_response(location: &str, bucket: &str, key: &str, etag: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<PostResponse><Location>{}</Location><Bucket>{}</Bucket><Key>{}</Key><ETag>{}</ETag></PostResponse>"#,
+        esc(location),
+        esc(bucket),
+        esc(key),
+        esc(etag)
+    )
+}
+
 pub fn list_parts_response(
     bucket: &str,
     key: &str,
@@ -220,6 +337,242 @@ pub fn list_multipart_uploads_response(
     )
 }
 
+pub struct ListObjectVersionsParams<'a> {
+    pub bucket: &'a str,
+    pub prefix: &'a str,
+    pub delimiter: Option<&'a str>,
+    pub key_marker: Option<&'a str>,
+    pub version_id_marker: Option<&'a str>,
+    pub max_keys: u32,
+    pub versions: &'a [S3ObjectVersion],
+    pub common_prefixes: &'a [S3CommonPrefix],
+    pub is_truncated: bool,
+    pub next_key_marker: Option<&'a str>,
+    pub next_version_id_marker: Option<&'a str>,
+    pub owner: &'a S3Owner,
+}
+
+pub fn list_object_versions_response(params: ListObjectVersionsParams<'_>) -> String {
+    let owner_xml = format!(
+        "<Owner><ID>{}</ID><DisplayName>{}</DisplayName></Owner>",
+        esc(&params.owner.id),
+        esc(&params.owner.display_name)
+    );
+    let versions: String = params
+        .versions
+        .iter()
+        .map(|v| match v.delete_marker {
+            true => format!(
+                r#"<DeleteMarker><Key>{}</Key><VersionId>{}</VersionId><IsLatest>{}</IsLatest><LastModified>{}</LastModified>{}</DeleteMarker>"#,
+                esc(&v.key),
+                esc(&v.version_id),
+                v.is_latest,
+                v.last_modified.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                owner_xml
+            ),
+            false => format!(
+                r#"<Version><Key>{}</Key><VersionId>{}</VersionId><IsLatest>{}</IsLatest><LastModified>{}</LastModified><ETag>"{}"</ETag><Size>{}</Size><StorageClass>{}</StorageClass>{}</Version>"#,
+                esc(&v.key),
+                esc(&v.version_id),
+                v.is_latest,
+                v.last_modified.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                esc(&v.etag),
+                v.size,
+                esc(&v.storage_class),
+                owner_xml
+            ),
+        })
+        .collect();
+    let cp_xml: String = params
+        .common_prefixes
+        .iter()
+        .map(|cp| {
+            format!(
+                "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                esc(&cp.prefix)
+            )
+        })
+        .collect();
+    let delim_xml = params
+        .delimiter
+        .map(|d| format!("<Delimiter>{}</Delimiter>", esc(d)))
+        .unwrap_or_default();
+    let next_xml = params
+        .next_key_marker
+        .map(|m| {
+            format!(
+                "<NextKeyMarker>{}</NextKeyMarker><NextVersionIdMarker>{}</NextVersionIdMarker>",
+                esc(m),
+                esc(params.next_version_id_marker.unwrap_or(NULL_VERSION_ID))
+            )
+        })
+        .unwrap_or_default();
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListVersionsResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+<Name>{}</Name><Prefix>{}</Prefix><KeyMarker>{}</KeyMarker><VersionIdMarker>{}</VersionIdMarker>{}<MaxKeys>{}</MaxKeys>{}<IsTruncated>{}</IsTruncated>
+{}{}
+</ListVersionsResult>"#,
+        esc(params.bucket),
+        esc(params.prefix),
+        esc(params.key_marker.unwrap_or("")),
+        esc(params.version_id_marker.unwrap_or("")),
+        next_xml,
+        params.max_keys,
+        delim_xml,
+        params.is_truncated,
+        versions,
+        cp_xml
+    )
+}
+
+pub fn versioning_configuration_response(status: Option<&str>) -> String {
+    let status_xml = status
+        .map(|s| format!("<Status>{}</Status>", esc(s)))
+        .unwrap_or_default();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<VersioningConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">{}</VersioningConfiguration>"#,
+        status_xml
+    )
+}
+
+pub fn access_control_policy_response(owner: &S3Owner) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<AccessControlPolicy xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+<Owner><ID>{0}</ID><DisplayName>{1}</DisplayName></Owner>
+<AccessControlList><Grant><Grantee xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:type="CanonicalUser"><ID>{0}</ID><DisplayName>{1}</DisplayName></Grantee><Permission>FULL_CONTROL</Permission></Grant></AccessControlList>
+</AccessControlPolicy>"#,
+        esc(&owner.id),
+        esc(&owner.display_name)
+    )
+}
+
+pub fn tagging_response(tags: &[Tag]) -> String {
+    let tags_xml: String = tags
+        .iter()
+        .map(|t| {
+            format!(
+                "<Tag><Key>{}</Key><Value>{}</Value></Tag>",
+                esc(&t.key),
+                esc(&t.value)
+            )
+        })
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Tagging xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><TagSet>{}</TagSet></Tagging>"#,
+        tags_xml
+    )
+}
+
+/// GetObjectAttributes: only the attributes asked for are present.
+/// `checksums` pairs each `<Checksum>` element's name with its value.
+/// The `ObjectParts` of GetObjectAttributes: up to `max_parts` of the
+/// part sizes `parts` after part number `marker`.
+pub fn object_parts(parts: &[u64], marker: usize, max_parts: usize) -> String {
+    let page: Vec<(usize, u64)> = parts
+        .iter()
+        .enumerate()
+        .map(|(i, size)| (i + 1, *size))
+        .skip(marker)
+        .take(max_parts)
+        .collect();
+    let is_truncated = marker + page.len() < parts.len();
+    let next_xml = page
+        .last()
+        .filter(|_| is_truncated)
+        .map(|(n, _)| format!("<NextPartNumberMarker>{}</NextPartNumberMarker>", n))
+        .unwrap_or_default();
+    let parts_xml: String = page
+        .iter()
+        .map(|(n, size)| {
+            format!(
+                "<Part><PartNumber>{}</PartNumber><Size>{}</Size></Part>",
+                n, size
+            )
+        })
+        .collect();
+    format!(
+        "<ObjectParts><TotalPartsCount>{}</TotalPartsCount><PartNumberMarker>{}</PartNumberMarker>{}<MaxParts>{}</MaxParts><IsTruncated>{}</IsTruncated>{}</ObjectParts>",
+        parts.len(),
+        marker,
+        next_xml,
+        max_parts,
+        is_truncated,
+        parts_xml
+    )
+}
+
+pub fn object_attributes_response(
+    etag: Option<&str>,
+    checksums: &[(&str, &str)],
+    object_parts: Option<&str>,
+    storage_class: Option<&str>,
+    object_size: Option<u64>,
+) -> String {
+    let etag_xml = etag
+        .map(|e| format!("<ETag>{}</ETag>", esc(e)))
+        .unwrap_or_default();
+    let checksum_xml = match checksums {
+        [] => String::new(),
+        checksums => format!(
+            "<Checksum>{}</Checksum>",
+            checksums
+                .iter()
+                .map(|(name, value)| format!("<{0}>{1}</{0}>", name, esc(value)))
+                .collect::<String>()
+        ),
+    };
+    let class_xml = storage_class
+        .map(|c| format!("<StorageClass>{}</StorageClass>", esc(c)))
+        .unwrap_or_default();
+    let size_xml = object_size
+        .map(|s| format!("<ObjectSize>{}</ObjectSize>", s))
+        .unwrap_or_default();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<GetObjectAttributesResponse xmlns="http://s3.amazonaws.com/doc/2006-03-01/">{}{}{}{}{}</GetObjectAttributesResponse>"#,
+        etag_xml,
+        checksum_xml,
+        object_parts.unwrap_or_default(),
+        class_xml,
+        size_xml
+    )
+}
+
+pub fn encryption_configuration_response(algorithm: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ServerSideEncryptionConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><Rule><ApplyServerSideEncryptionByDefault><SSEAlgorithm>{}</SSEAlgorithm></ApplyServerSideEncryptionByDefault><BucketKeyEnabled>false</BucketKeyEnabled></Rule></ServerSideEncryptionConfiguration>"#,
+        esc(algorithm)
+    )
+}
+
+pub fn policy_status_response(is_public: bool) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<PolicyStatus xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><IsPublic>{}</IsPublic></PolicyStatus>"#,
+        is_public
+    )
+}
+
+pub fn public_access_block_response() -> String {
+    r#"<?xml version="1.0" encoding="UTF-8"?>
+<PublicAccessBlockConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><BlockPublicAcls>true</BlockPublicAcls><IgnorePublicAcls>true</IgnorePublicAcls><BlockPublicPolicy>true</BlockPublicPolicy><RestrictPublicBuckets>true</RestrictPublicBuckets></PublicAccessBlockConfiguration>"#
+        .to_string()
+}
+
+pub fn ownership_controls_response(object_ownership: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<OwnershipControls xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><Rule><ObjectOwnership>{}</ObjectOwnership></Rule></OwnershipControls>"#,
+        esc(object_ownership)
+    )
+}
+
 fn esc(s: &str) -> String {
     s.replace('&', "&amp;")
         .replace('<', "&lt;")
@@ -227,3 +580,178 @@ fn esc(s: &str) -> String {
         .replace('"', "&quot;")
         .replace('\'', "&apos;")
 }
+
+/// Deserializes an S3 request body, tolerating a UTF-8 BOM, XML declarations,
+/// default namespaces and namespace-prefixed element names.
+pub fn parse_request_body<T: DeserializeOwned>(body: &[u8]) -> Result<T> {
+    let body = body.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(body);
+    let text = std::str::from_utf8(body).map_err(|e| ProxyError::MalformedXml(e.to_string()))?;
+    let normalized = strip_namespaces(text)?;
+    quick_xml::de::from_str(&normalized).map_err(|e| ProxyError::MalformedXml(e.to_string()))
+}
+
+fn strip_namespaces(xml: &str) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|e| ProxyError::MalformedXml(e.to_string()))?;
+        let event = match event {
+            Event::Start(e) => Event::Start(local_start(&e)?),
+            Event::Empty(e) => Event::Empty(local_start(&e)?),
+            Event::End(e) => Event::End(BytesEnd::new(
+                String::from_utf8_lossy(e.local_name().as_ref()).into_owned(),
+            )),
+            Event::Decl(_) | Event::DocType(_) | Event::PI(_) | Event::Comment(_) => continue,
+            Event::Eof => break,
+            other => other,
+        };
+        writer
+            .write_event(event)
+            .map_err(|e| ProxyError::MalformedXml(e.to_string()))?;
+    }
+
+    String::from_utf8(writer.into_inner()).map_err(|e| ProxyError::MalformedXml(e.to_string()))
+}
+
+fn local_start(e: &BytesStart<'_>) -> Result<BytesStart<'static>> {
+    let name = String::from_utf8_lossy(e.local_name().as_ref()).into_owned();
+    let mut start = BytesStart::new(name);
+    for attr in e.attributes() {
+        let attr = attr.map_err(|e| ProxyError::MalformedXml(e.to_string()))?;
+        if attr.key.as_namespace_binding().is_some() {
+            continue;
+        }
+        start.push_attribute((attr.key.local_name().as_ref(), attr.value.as_ref()));
+    }
+    Ok(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::s3::types::{
+        CompleteMultipartUpload, DeleteRequest, LifecycleConfiguration, Tagging,
+    };
+
+    fn assert_parts(req: CompleteMultipartUpload) {
+        let parts: Vec<_> = req
+            .part
+            .iter()
+            .map(|p| (p.part_number, p.etag.trim_matches('"')))
+            .collect();
+        assert_eq!(
+            parts,
+            vec![
+                (1, "5d41402abc4b2a76b9719d911017c592"),
+                (2, "7d793037a0760186574b0282f2f435e7")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_complete_multipart_java_sdk() {
+        let body = include_bytes!("../../tests/fixtures/complete_multipart_java.xml");
+        assert_parts(parse_request_body(body).unwrap());
+    }
+
+    #[test]
+    fn test_complete_multipart_go_sdk_with_checksums() {
+        let body = include_bytes!("../../tests/fixtures/complete_multipart_go.xml");
+        assert_parts(parse_request_body(body).unwrap());
+    }
+
+    #[test]
+    fn test_complete_multipart_rust_sdk() {
+        let body = include_bytes!("../../tests/fixtures/complete_multipart_rust.xml");
+        assert_parts(parse_request_body(body).unwrap());
+    }
+
+    #[test]
+    fn test_delete_objects_prefixed_namespace_with_bom() {
+        let body = include_bytes!("../../tests/fixtures/delete_objects_java.xml");
+        let req: DeleteRequest = parse_request_body(body).unwrap();
+        assert_eq!(req.quiet, Some(true));
+        let keys: Vec<_> = req.object.iter().map(|o| o.key.as_str()).collect();
+        assert_eq!(keys, vec!["logs/a.txt", "logs/b & c.txt"]);
+        assert_eq!(req.object[1].version_id.as_deref(), Some("null"));
+    }
+
+    #[test]
+    fn test_delete_objects_go_sdk() {
+        let body = include_bytes!("../../tests/fixtures/delete_objects_go.xml");
+        let req: DeleteRequest = parse_request_body(body).unwrap();
+        assert_eq!(req.quiet, Some(false));
+        assert_eq!(req.object.len(), 2);
+    }
+
+    #[test]
+    fn test_malformed_body_is_rejected() {
+        let err = parse_request_body::<DeleteRequest>(b"<Delete><Object>").unwrap_err();
+        assert_eq!(err.s3_error_code(), "MalformedXML");
+    }
+
+    #[test]
+    fn test_lifecycle_configuration_validation() {
+        let body = br#"<LifecycleConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+<Rule><ID>tmp</ID><Filter><Prefix>tmp/</Prefix></Filter><Status>Enabled</Status><Expiration><Days>7</Days></Expiration></Rule>
+<Rule><ID>mpu</ID><Filter/><Status>Enabled</Status><AbortIncompleteMultipartUpload><DaysAfterInitiation>2</DaysAfterInitiation></AbortIncompleteMultipartUpload></Rule>
+</LifecycleConfiguration>"#;
+        let config: LifecycleConfiguration = parse_request_body(body).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.rule[0].key_prefix(), "tmp/");
+        assert_eq!(config.rule[0].expiration_days(), Some(7));
+        assert_eq!(config.rule[1].key_prefix(), "");
+        assert_eq!(config.rule[1].abort_days(), Some(2));
+
+        let body = br#"<LifecycleConfiguration><Rule><Prefix>logs/</Prefix><Status>Enabled</Status><Transition><Days>30</Days><StorageClass>GLACIER</StorageClass></Transition></Rule></LifecycleConfiguration>"#;
+        let config: LifecycleConfiguration = parse_request_body(body).unwrap();
+        assert!(config.validate().unwrap_err().contains("transitions"));
+
+        let body = br#"<LifecycleConfiguration><Rule><Filter><Prefix>docs/</Prefix></Filter><Status>Enabled</Status><NoncurrentVersionExpiration><NoncurrentDays>30</NoncurrentDays></NoncurrentVersionExpiration></Rule></LifecycleConfiguration>"#;
+        let config: LifecycleConfiguration = parse_request_body(body).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.rule[0].noncurrent_days(), Some(30));
+
+        let body = br#"<LifecycleConfiguration><Rule><Status>Enabled</Status><NoncurrentVersionExpiration><NoncurrentDays>30</NoncurrentDays><NewerNoncurrentVersions>3</NewerNoncurrentVersions></NoncurrentVersionExpiration></Rule></LifecycleConfiguration>"#;
+        let config: LifecycleConfiguration = parse_request_body(body).unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_tagging_round_trip() {
+        let tags = vec![
+            Tag {
+                key: "cost-center".to_string(),
+                value: "R&D <42>".to_string(),
+            },
+            Tag {
+                key: "empty".to_string(),
+                value: String::new(),
+            },
+        ];
+        let xml = tagging_response(&tags);
+        let parsed: Tagging = parse_request_body(xml.as_bytes()).unwrap();
+        assert_eq!(parsed.tag_set.tag, tags);
+        assert!(parsed.validate().is_ok());
+    }
+
+    #[test]
+    fn test_tagging_limits() {
+        let too_many: String = (0..51)
+            .map(|i| format!("<Tag><Key>k{}</Key><Value>v</Value></Tag>", i))
+            .collect();
+        let body = format!("<Tagging><TagSet>{}</TagSet></Tagging>", too_many);
+        let parsed: Tagging = parse_request_body(body.as_bytes()).unwrap();
+        assert!(parsed.validate().is_err());
+
+        let long_key = format!(
+            "<Tagging><TagSet><Tag><Key>{}</Key><Value>v</Value></Tag></TagSet></Tagging>",
+            "k".repeat(129)
+        );
+        let parsed: Tagging = parse_request_body(long_key.as_bytes()).unwrap();
+        assert!(parsed.validate().is_err());
+    }
+}
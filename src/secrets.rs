@@ -0,0 +1,174 @@
+//! `<ENV>_FILE` variants of the secret-bearing options, for secrets mounted
+//! as files by Docker or Kubernetes: with `BUNNY_ACCESS_KEY_FILE` set, the
+//! access key is read from that file at startup and never appears in the
+//! environment or on the command line. One trailing newline is dropped.
+//! Setting an option both directly and through its file is an error.
+//!
+//! The value is handed to clap as the option's default. Clap does not count
+//! defaults towards `requires`, so the options that require a secret have
+//! that requirement lifted when it comes from a file.
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+
+/// Environment variables holding secrets, the argument each sets, and the
+/// arguments that require it.
+const SECRETS: &[(&str, &str, &[&str])] = &[
+    ("BUNNY_ACCESS_KEY", "access_key", &[]),
+    ("EXTRA_ZONES", "extra_zone", &[]),
+    ("S3_SECRET_ACCESS_KEY", "s3_secret_access_key", &[]),
+    ("S3_CREDENTIALS", "s3_credentials", &[]),
+    ("RETENTION_OVERRIDE_TOKEN", "retention_override_token", &[]),
+    ("REDIRECT_TOKEN_KEY", "redirect_token_key", &[]),
+    ("ADMIN_TOKEN", "admin_token", &["admin_addr"]),
+    ("EVENT_WEBHOOK_SECRET", "event_webhook_secret", &[]),
+    ("BUNNY_API_KEY", "bunny_api_key", &[]),
+    ("SHADOW_KEY", "shadow_key", &["shadow_zone"]),
+    ("REDIS_URL", "redis_url", &[]),
+];
+
+/// The secret file configured for `env`, if any.
+pub fn file_for(env: &str) -> Option<PathBuf> {
+    std::env::var_os(format!("{}_FILE", env)).map(PathBuf::from)
+}
+
+/// Parses the command line and environment as `Config::parse` does, taking
+/// secrets from their files where configured.
+pub fn parse_config() -> anyhow::Result<Config> {
+    let (command, from_files) = command(file_for)?;
+    config(&command.get_matches(), &from_files)
+}
+
+/// The `Config` command with each secret whose file `file_var` names read
+/// into its default, and the environment variables so set.
+fn command(
+    file_var: impl Fn(&str) -> Option<PathBuf>,
+) -> anyhow::Result<(clap::Command, Vec<&'static str>)> {
+    let mut command = Config::command();
+    let mut from_files = Vec::new();
+    for &(env, id, required_by) in SECRETS {
+        let Some(path) = file_var(env) else {
+            continue;
+        };
+        let value = read(env, &path)?;
+        command = command.mut_arg(id, |arg| arg.default_value(value).hide_default_value(true));
+        for &dependent in required_by {
+            // Each of these requires nothing but the secret.
+            command = command.mut_arg(dependent, |arg| {
+                arg.requires(clap::builder::Resettable::Reset)
+            });
+        }
+        from_files.push(env);
+    }
+    Ok((command, from_files))
+}
+
+fn config(matches: &ArgMatches, from_files: &[&str]) -> anyhow::Result<Config> {
+    for &(env, id, _) in SECRETS.iter().filter(|(env, ..)| from_files.contains(env)) {
+        if matches.value_source(id) != Some(ValueSource::DefaultValue) {
+            anyhow::bail!(
+                "{} is set both directly and through {}_FILE; use one",
+                env,
+                env
+            );
+        }
+    }
+    Config::from_arg_matches(matches).map_err(|e| anyhow::anyhow!("{}", e))
+}
+
+fn read(env: &str, path: &Path) -> anyhow::Result<String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("{}_FILE {}: {}", env, path.display(), e))?;
+    let value = text
+        .strip_suffix('\n')
+        .map(|v| v.strip_suffix('\r').unwrap_or(v))
+        .unwrap_or(&text);
+    if value.is_empty() {
+        anyhow::bail!("{}_FILE {}: file is empty", env, path.display());
+    }
+    Ok(value.to_string())
+}
+
+/// Re-reads the secret file configured for `env`, if there is one.
+pub fn reread(env: &str) -> Option<anyhow::Result<String>> {
+    file_for(env).map(|path| read(env, &path))
+}
+
+/// Enough of `value` to recognise it in logs: its first four characters.
+pub fn mask(value: &str) -> String {
+    let shown: String = value.chars().take(4).collect();
+    match shown.len() < value.len() {
+        true => format!("{}****", shown),
+        false => "****".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret_file(contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("secret-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_secrets_from_files() {
+        let key = secret_file("bunny-key\n");
+        let token = secret_file("admin-token\r\n");
+        let credentials = secret_file("APP1:secret1,APP2:secret2\n");
+        let files = |env: &str| match env {
+            "BUNNY_ACCESS_KEY" => Some(key.clone()),
+            "ADMIN_TOKEN" => Some(token.clone()),
+            "S3_CREDENTIALS" => Some(credentials.clone()),
+            _ => None,
+        };
+        let parse = |args: &[&str]| {
+            let (command, from_files) = command(files).unwrap();
+            let base = ["bunny-s3-proxy", "--storage-zone", "zone"];
+            let matches = command
+                .try_get_matches_from(base.iter().chain(args))
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            config(&matches, &from_files)
+        };
+
+        // Options that require the secret accept it from its file.
+        let config = parse(&["--admin-addr", "127.0.0.1:9000"]).unwrap();
+        assert_eq!(config.access_key, "bunny-key");
+        assert_eq!(config.admin_token.as_deref(), Some("admin-token"));
+        let keys: Vec<_> = config
+            .s3_credentials
+            .iter()
+            .map(|c| c.access_key_id.as_str())
+            .collect();
+        assert_eq!(keys, ["APP1", "APP2"]);
+
+        let err = parse(&["--access-key", "other"]).unwrap_err().to_string();
+        assert!(err.contains("BUNNY_ACCESS_KEY_FILE"), "{}", err);
+
+        let missing = std::env::temp_dir().join("no-such-secret");
+        let err = command(|env: &str| (env == "SHADOW_KEY").then(|| missing.clone()))
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("SHADOW_KEY_FILE"), "{}", err);
+        let empty = secret_file("\n");
+        let err = command(|env: &str| (env == "REDIS_URL").then(|| empty.clone()))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("empty"), "{}", err);
+
+        for path in [key, token, credentials, empty] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_mask() {
+        assert_eq!(mask("AKIAEXAMPLE"), "AKIA****");
+        assert_eq!(mask("key"), "****");
+    }
+}
@@ -0,0 +1,600 @@
+//! `bunny-s3-proxy sync`: mirrors a local directory into a folder of the
+//! zone, or a folder of the zone into a local directory, through the storage
+//! API directly rather than through the proxy.
+//!
+//! Files are compared by size and, when the sizes match and Bunny has a
+//! checksum, by SHA-256, so a run keeps no state and an interrupted one is
+//! resumed by running it again. Downloads are written to a temporary file
+//! and renamed into place, so a partial file is never taken for a copy.
+
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+
+use crate::bunny::client::escapes_root;
+use crate::bunny::{Backend, StorageBackend};
+use crate::config::Config;
+use crate::error::{ProxyError, Result};
+use crate::s3::bucket_config::BucketConfigStore;
+use crate::s3::multipart::MultipartManager;
+use crate::s3::object_meta::ObjectMetaStore;
+use crate::s3::versions::VersionStore;
+
+/// Marks the side of a sync that is a folder of the zone.
+const REMOTE_SCHEME: &str = "bunny:";
+
+/// Suffix of downloads in progress, which local walks leave out.
+const TMP_SUFFIX: &str = ".bunny-sync-tmp";
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct SyncArgs {
+    /// Copy from: a local directory, or `bunny:<prefix>` for a folder of the zone
+    pub source: String,
+
+    /// Copy to: a local directory, or `bunny:<prefix>`; exactly one side is remote
+    pub destination: String,
+
+    /// Also delete files at the destination that the source does not have
+    #[arg(long)]
+    pub delete: bool,
+
+    /// Only print what would be copied and deleted
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Compare sizes only, without hashing local files of matching size
+    #[arg(long)]
+    pub size_only: bool,
+
+    /// Files compared and transferred at once
+    #[arg(long, default_value_t = 8)]
+    pub concurrency: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LocalFile {
+    path: PathBuf,
+    size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RemoteFile {
+    size: u64,
+    checksum: Option<String>,
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Copied(u64),
+    Skipped,
+    Deleted,
+    Failed(String),
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    copied: usize,
+    skipped: usize,
+    deleted: usize,
+    failed: usize,
+    bytes: u64,
+}
+
+/// The direction, local directory and zone prefix of a sync. The prefix is
+/// empty for the zone root and otherwise ends in `/`.
+fn endpoints(args: &SyncArgs) -> anyhow::Result<(Direction, PathBuf, String)> {
+    let (direction, local, prefix) = match (
+        args.source.strip_prefix(REMOTE_SCHEME),
+        args.destination.strip_prefix(REMOTE_SCHEME),
+    ) {
+        (None, Some(prefix)) => (Direction::Upload, &args.source, prefix),
+        (Some(prefix), None) => (Direction::Download, &args.destination, prefix),
+        _ => anyhow::bail!(
+            "exactly one of the source and destination must be a {}<prefix> folder of the zone",
+            REMOTE_SCHEME
+        ),
+    };
+    if escapes_root(prefix) {
+        anyhow::bail!("{}{} is not a folder of the zone", REMOTE_SCHEME, prefix);
+    }
+    let prefix = match prefix.trim_matches('/') {
+        "" => String::new(),
+        prefix => format!("{}/", prefix),
+    };
+    Ok((direction, PathBuf::from(local), prefix))
+}
+
+pub async fn run(config: &Config, args: &SyncArgs) -> anyhow::Result<()> {
+    let (direction, dir, prefix) = endpoints(args)?;
+    if config.encryption_key_file.is_some() || config.compress.is_some() {
+        anyhow::bail!(
+            "sync moves the bytes as Bunny stores them and cannot apply --encryption-key-file or --compress"
+        );
+    }
+    if direction == Direction::Upload && !dir.is_dir() {
+        anyhow::bail!("{} is not a directory", dir.display());
+    }
+    let mut client = Backend::new(config, config.into());
+    if let Some(key_prefix) = &config.key_prefix {
+        client = client.scoped(key_prefix);
+    }
+
+    let started = Instant::now();
+    let walk_dir = dir.clone();
+    let local = tokio::task::spawn_blocking(move || walk_local(&walk_dir)).await??;
+    let (remote, with_meta) = tokio::join!(
+        list_remote(&client, &prefix, args.concurrency),
+        ObjectMetaStore::keys_with_meta(&client, &prefix, true)
+    );
+    let remote = remote?;
+    // Sidecars of objects the proxy stored encrypted or compressed.
+    let with_meta: HashSet<String> = match with_meta {
+        Ok(keys) => keys,
+        Err(ProxyError::NotFound(_)) => HashSet::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let source_keys: Vec<String> = match direction {
+        Direction::Upload => local.keys().cloned().collect(),
+        Direction::Download => remote.keys().cloned().collect(),
+    };
+    let mut summary = Summary::default();
+    let mut copies = futures::stream::iter(source_keys)
+        .map(|key| {
+            let (client, dir, prefix) = (&client, &dir, &prefix);
+            let (local, remote, with_meta) = (local.get(&key), remote.get(&key), &with_meta);
+            async move {
+                let full_key = format!("{}{}", prefix, key);
+                let outcome = match direction {
+                    Direction::Upload => {
+                        let local = local.expect("uploads start from local files");
+                        upload_if_changed(client, local, &full_key, remote, with_meta, args).await
+                    }
+                    Direction::Download => {
+                        let remote = remote.expect("downloads start from remote files");
+                        download_if_changed(
+                            client, dir, &key, &full_key, remote, local, with_meta, args,
+                        )
+                        .await
+                    }
+                };
+                (key, outcome)
+            }
+        })
+        .buffer_unordered(args.concurrency.max(1));
+    while let Some((key, outcome)) = copies.next().await {
+        report(&mut summary, &key, outcome, args.dry_run);
+    }
+    drop(copies);
+
+    if args.delete {
+        let extraneous: Vec<String> = match direction {
+            Direction::Upload => remote
+                .keys()
+                .filter(|k| !local.contains_key(*k))
+                .cloned()
+                .collect(),
+            Direction::Download => local
+                .keys()
+                .filter(|k| !remote.contains_key(*k))
+                .cloned()
+                .collect(),
+        };
+        if summary.failed > 0 && !extraneous.is_empty() {
+            eprintln!(
+                "not deleting {} extraneous files since transfers failed",
+                extraneous.len()
+            );
+        } else {
+            let mut deletes = futures::stream::iter(extraneous)
+                .map(|key| {
+                    let (client, prefix, local, with_meta) = (&client, &prefix, &local, &with_meta);
+                    async move {
+                        let outcome = if args.dry_run {
+                            Outcome::Deleted
+                        } else {
+                            let deleted = match direction {
+                                Direction::Upload => {
+                                    let full_key = format!("{}{}", prefix, key);
+                                    delete_remote(client, &full_key, with_meta).await
+                                }
+                                Direction::Download => tokio::fs::remove_file(&local[&key].path)
+                                    .await
+                                    .map_err(ProxyError::from),
+                            };
+                            match deleted {
+                                Ok(()) => Outcome::Deleted,
+                                Err(e) => Outcome::Failed(e.to_string()),
+                            }
+                        };
+                        (key, outcome)
+                    }
+                })
+                .buffer_unordered(args.concurrency.max(1));
+            while let Some((key, outcome)) = deletes.next().await {
+                report(&mut summary, &key, outcome, args.dry_run);
+            }
+        }
+    }
+
+    println!(
+        "\n{} copied, {} skipped, {} deleted, {} failed, {} bytes in {:.1}s{}",
+        summary.copied,
+        summary.skipped,
+        summary.deleted,
+        summary.failed,
+        summary.bytes,
+        started.elapsed().as_secs_f64(),
+        if args.dry_run { " (dry run)" } else { "" }
+    );
+    if summary.failed > 0 {
+        anyhow::bail!("{} transfers failed", summary.failed);
+    }
+    Ok(())
+}
+
+fn report(summary: &mut Summary, key: &str, outcome: Outcome, dry_run: bool) {
+    let would = if dry_run { "would " } else { "" };
+    match outcome {
+        Outcome::Copied(bytes) => {
+            summary.copied += 1;
+            summary.bytes += bytes;
+            println!("{}copy {} ({} bytes)", would, key, bytes);
+        }
+        Outcome::Skipped => summary.skipped += 1,
+        Outcome::Deleted => {
+            summary.deleted += 1;
+            println!("{}delete {}", would, key);
+        }
+        Outcome::Failed(error) => {
+            summary.failed += 1;
+            eprintln!("FAILED {}: {}", key, error);
+        }
+    }
+}
+
+/// Every regular file below `root`, by its path relative to `root` with `/`
+/// separators. A missing directory has no files.
+fn walk_local(root: &Path) -> std::io::Result<BTreeMap<String, LocalFile>> {
+    let mut files = BTreeMap::new();
+    if !root.exists() {
+        return Ok(files);
+    }
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let meta = std::fs::metadata(&path)?;
+            if meta.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            let relative = path.strip_prefix(root).expect("walked below the root");
+            let Some(key) = relative
+                .iter()
+                .map(|part| part.to_str())
+                .collect::<Option<Vec<_>>>()
+                .map(|parts| parts.join("/"))
+            else {
+                eprintln!("skipping {}: name is not UTF-8", path.display());
+                continue;
+            };
+            if meta.is_file() && !key.ends_with(TMP_SUFFIX) {
+                files.insert(
+                    key,
+                    LocalFile {
+                        path,
+                        size: meta.len(),
+                    },
+                );
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Every object below `prefix`, by its key relative to `prefix`, leaving out
+/// the proxy's own bookkeeping.
+async fn list_remote(
+    client: &Backend,
+    prefix: &str,
+    concurrency: usize,
+) -> Result<BTreeMap<String, RemoteFile>> {
+    let objects = match client.list_recursive_concurrent(prefix, concurrency).await {
+        Ok(objects) => objects,
+        Err(ProxyError::NotFound(_)) => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(objects
+        .iter()
+        .filter_map(|obj| {
+            let key = obj.s3_key();
+            if is_internal(&key) {
+                return None;
+            }
+            let relative = key.strip_prefix(prefix)?.to_string();
+            Some((
+                relative,
+                RemoteFile {
+                    size: obj.length.max(0) as u64,
+                    checksum: obj.checksum.clone(),
+                },
+            ))
+        })
+        .collect())
+}
+
+fn is_internal(key: &str) -> bool {
+    BucketConfigStore::is_internal_key(key)
+        || MultipartManager::is_internal_key(key)
+        || ObjectMetaStore::is_internal_key(key)
+        || VersionStore::is_internal_key(key)
+}
+
+/// Whether the local file already matches the remote one: equal sizes, and
+/// equal SHA-256 checksums unless `--size-only` or Bunny has none.
+async fn matches(local: &LocalFile, remote: &RemoteFile, size_only: bool) -> Result<bool> {
+    if local.size != remote.size {
+        return Ok(false);
+    }
+    let Some(checksum) = remote.checksum.as_deref().filter(|_| !size_only) else {
+        return Ok(true);
+    };
+    let path = local.path.clone();
+    let computed = tokio::task::spawn_blocking(move || sha256_file(&path))
+        .await
+        .map_err(std::io::Error::other)??;
+    Ok(computed.eq_ignore_ascii_case(checksum))
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 256 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hex::encode_upper(hasher.finalize()))
+}
+
+async fn upload_if_changed(
+    client: &Backend,
+    local: &LocalFile,
+    key: &str,
+    remote: Option<&RemoteFile>,
+    with_meta: &HashSet<String>,
+    args: &SyncArgs,
+) -> Outcome {
+    let result = async {
+        if let Some(remote) = remote
+            && !with_meta.contains(key)
+            && matches(local, remote, args.size_only).await?
+        {
+            return Ok(Outcome::Skipped);
+        }
+        if !args.dry_run {
+            upload(client, local, key, with_meta).await?;
+        }
+        Ok::<_, ProxyError>(Outcome::Copied(local.size))
+    };
+    result
+        .await
+        .unwrap_or_else(|e| Outcome::Failed(e.to_string()))
+}
+
+async fn upload(
+    client: &Backend,
+    local: &LocalFile,
+    key: &str,
+    with_meta: &HashSet<String>,
+) -> Result<()> {
+    let file = tokio::fs::File::open(&local.path).await?;
+    let content_type = mime_guess::from_path(key).first().map(|m| m.to_string());
+    client
+        .upload_stream(
+            key,
+            tokio_util::io::ReaderStream::new(file),
+            Some(local.size),
+            content_type.as_deref(),
+        )
+        .await?;
+    // The sidecar described the bytes just replaced.
+    if with_meta.contains(key) {
+        ObjectMetaStore::delete(client, key).await?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn download_if_changed(
+    client: &Backend,
+    dir: &Path,
+    key: &str,
+    full_key: &str,
+    remote: &RemoteFile,
+    local: Option<&LocalFile>,
+    with_meta: &HashSet<String>,
+    args: &SyncArgs,
+) -> Outcome {
+    if with_meta.contains(full_key) {
+        return Outcome::Failed(
+            "stored encrypted or compressed by the proxy; read it through the proxy".into(),
+        );
+    }
+    if escapes_root(key) {
+        return Outcome::Failed("key would leave the destination directory".into());
+    }
+    let result = async {
+        if let Some(local) = local
+            && matches(local, remote, args.size_only).await?
+        {
+            return Ok(Outcome::Skipped);
+        }
+        if args.dry_run {
+            return Ok(Outcome::Copied(remote.size));
+        }
+        let path = key
+            .split('/')
+            .fold(dir.to_path_buf(), |path, part| path.join(part));
+        download(client, full_key, &path).await.map(Outcome::Copied)
+    };
+    result
+        .await
+        .unwrap_or_else(|e| Outcome::Failed(e.to_string()))
+}
+
+async fn download(client: &Backend, key: &str, path: &Path) -> Result<u64> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(TMP_SUFFIX);
+    let tmp = PathBuf::from(tmp);
+    let result = async {
+        let mut stream = client.download(key).await?.bytes_stream();
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        let mut written = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(written)
+    };
+    let result = result.await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    result
+}
+
+async fn delete_remote(client: &Backend, key: &str, with_meta: &HashSet<String>) -> Result<()> {
+    client.delete(key).await?;
+    if with_meta.contains(key) {
+        ObjectMetaStore::delete(client, key).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Command;
+    use clap::Parser;
+
+    fn sync_args(extra: &[&str]) -> SyncArgs {
+        let base = ["bunny-s3-proxy", "-z", "zone", "-k", "key", "sync"];
+        let Some(Command::Sync(args)) = Config::parse_from(base.iter().chain(extra)).command else {
+            panic!("expected sync");
+        };
+        args
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let (direction, dir, prefix) = endpoints(&sync_args(&["./data", "bunny:backups"])).unwrap();
+        assert_eq!(direction, Direction::Upload);
+        assert_eq!(dir, PathBuf::from("./data"));
+        assert_eq!(prefix, "backups/");
+
+        let (direction, dir, prefix) = endpoints(&sync_args(&["bunny:", "out"])).unwrap();
+        assert_eq!(direction, Direction::Download);
+        assert_eq!(dir, PathBuf::from("out"));
+        assert_eq!(prefix, "");
+
+        assert!(endpoints(&sync_args(&["a", "b"])).is_err());
+        assert!(endpoints(&sync_args(&["bunny:a", "bunny:b"])).is_err());
+        assert!(endpoints(&sync_args(&["a", "bunny:../b"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync_round_trip() {
+        let root = std::env::temp_dir().join(format!("sync-{}", uuid::Uuid::new_v4()));
+        let (zone, source, restored) = (root.join("zone"), root.join("src"), root.join("out"));
+        std::fs::create_dir_all(source.join("nested")).unwrap();
+        std::fs::write(source.join("a.txt"), "alpha").unwrap();
+        std::fs::write(source.join("nested/b.txt"), "bravo").unwrap();
+        let config = Config::parse_from([
+            "bunny-s3-proxy",
+            "-z",
+            "zone",
+            "--backend",
+            "localfs",
+            "--localfs-root",
+            zone.to_str().unwrap(),
+        ]);
+        let client = Backend::new(&config, (&config).into());
+        let source_str = source.to_str().unwrap().to_string();
+        let restored_str = restored.to_str().unwrap().to_string();
+
+        run(
+            &config,
+            &sync_args(&[&source_str, "bunny:backup", "--dry-run"]),
+        )
+        .await
+        .unwrap();
+        assert!(client.describe("backup/a.txt").await.is_err());
+
+        run(&config, &sync_args(&[&source_str, "bunny:backup"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            client
+                .download("backup/nested/b.txt")
+                .await
+                .unwrap()
+                .bytes()
+                .await
+                .unwrap(),
+            "bravo"
+        );
+
+        // Changed files are copied again and extraneous ones deleted
+        std::fs::write(source.join("a.txt"), "ALPHA").unwrap();
+        std::fs::remove_file(source.join("nested/b.txt")).unwrap();
+        run(
+            &config,
+            &sync_args(&[&source_str, "bunny:backup", "--delete"]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            client
+                .download("backup/a.txt")
+                .await
+                .unwrap()
+                .bytes()
+                .await
+                .unwrap(),
+            "ALPHA"
+        );
+        assert!(client.describe("backup/nested/b.txt").await.is_err());
+
+        run(&config, &sync_args(&["bunny:backup/", &restored_str]))
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(restored.join("a.txt")).unwrap(),
+            "ALPHA"
+        );
+        let local = walk_local(&restored).unwrap();
+        assert_eq!(local.keys().collect::<Vec<_>>(), ["a.txt"]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
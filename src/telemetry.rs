@@ -0,0 +1,374 @@
+use axum::http::HeaderMap;
+use serde_json::{Map, Value};
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{EnvFilter, Layer, layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::config::{Config, LogFormat};
+
+/// Flushes spans that are still buffered for export when dropped.
+pub struct TelemetryGuard {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider.take()
+            && let Err(e) = provider.shutdown()
+        {
+            eprintln!("Failed to flush OTLP spans: {}", e);
+        }
+    }
+}
+
+/// Installs the global tracing subscriber: the fmt layer, plus an OTLP span
+/// exporter when built with the `otlp` feature and an endpoint is configured.
+pub fn init(config: &Config) -> anyhow::Result<TelemetryGuard> {
+    let registry = tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+            let mut directives = format!("bunny_s3_proxy={0},tower_http={0}", config.log_level);
+            if config.debug_http {
+                directives.push_str(",bunny_s3_proxy::debug_http=trace");
+            }
+            directives.into()
+        }))
+        .with(fmt_layer(config.log_format));
+
+    #[cfg(feature = "otlp")]
+    if let Some(provider) = otlp::provider(config)? {
+        use opentelemetry::trace::TracerProvider;
+
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        registry
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .init();
+        tracing::info!("Exporting traces via OTLP");
+        return Ok(TelemetryGuard {
+            provider: Some(provider),
+        });
+    }
+
+    registry.init();
+
+    #[cfg(not(feature = "otlp"))]
+    if config.otlp_endpoint.is_some() {
+        tracing::warn!("--otlp-endpoint is ignored: built without the `otlp` feature");
+    }
+
+    Ok(TelemetryGuard {
+        #[cfg(feature = "otlp")]
+        provider: None,
+    })
+}
+
+/// Makes `span` a child of the caller's W3C trace context when the request
+/// carries a `traceparent` header and traces are exported.
+pub fn set_parent(span: &tracing::Span, headers: &HeaderMap) {
+    #[cfg(feature = "otlp")]
+    otlp::set_parent(span, headers);
+    #[cfg(not(feature = "otlp"))]
+    let _ = (span, headers);
+}
+
+/// `traceparent`/`tracestate` headers carrying the current span's context, for
+/// requests to Bunny. Empty unless traces are exported.
+pub fn trace_headers() -> HeaderMap {
+    #[cfg(feature = "otlp")]
+    return otlp::trace_headers();
+    #[cfg(not(feature = "otlp"))]
+    HeaderMap::new()
+}
+
+/// The log output layer. Access-log events from `TraceLayer` go through the
+/// same layer, so every line shares one format.
+fn fmt_layer<S>(format: LogFormat) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let layer = tracing_subscriber::fmt::layer();
+    match format {
+        LogFormat::Pretty => layer.boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Json => layer
+            .fmt_fields(JsonFields::new())
+            .event_format(FlatJson)
+            .boxed(),
+    }
+}
+
+/// Formats each event as one JSON object with the event fields and the fields
+/// of every enclosing span (request id, operation, bucket, ...) merged in at
+/// the top level, so log pipelines can index them without unnesting.
+struct FlatJson;
+
+impl<S, N> FormatEvent<S, N> for FlatJson
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+        let mut fields = Map::new();
+        fields.insert(
+            "timestamp".into(),
+            chrono::Utc::now()
+                .to_rfc3339_opts(chrono::SecondsFormat::Micros, true)
+                .into(),
+        );
+        fields.insert("level".into(), meta.level().as_str().into());
+        fields.insert("target".into(), meta.target().into());
+
+        if let Some(scope) = ctx.event_scope() {
+            let mut spans = Vec::new();
+            for span in scope.from_root() {
+                spans.push(Value::from(span.name()));
+                let extensions = span.extensions();
+                // Span fields are recorded by `JsonFields`, so they are a JSON object.
+                if let Some(recorded) = extensions.get::<FormattedFields<N>>()
+                    && let Ok(Value::Object(span_fields)) = serde_json::from_str::<Value>(recorded)
+                {
+                    fields.extend(span_fields);
+                }
+            }
+            fields.insert("spans".into(), spans.into());
+        }
+
+        event.record(&mut JsonVisitor(&mut fields));
+        writeln!(writer, "{}", Value::Object(fields))
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{:?}", value).into());
+    }
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use axum::http::{HeaderMap, HeaderName, HeaderValue};
+    use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+    use opentelemetry::trace::TraceContextExt;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::Resource;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    use crate::config::Config;
+
+    const TRACES_PATH: &str = "/v1/traces";
+
+    struct HeaderExtractor<'a>(&'a HeaderMap);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|v| v.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(HeaderName::as_str).collect()
+        }
+    }
+
+    struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+    impl Injector for HeaderInjector<'_> {
+        fn set(&mut self, key: &str, value: String) {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(key.as_bytes()),
+                HeaderValue::from_str(&value),
+            ) {
+                self.0.insert(name, value);
+            }
+        }
+    }
+
+    pub fn set_parent(span: &tracing::Span, headers: &HeaderMap) {
+        if !headers.contains_key("traceparent") {
+            return;
+        }
+        let cx = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+        if cx.span().span_context().is_valid() {
+            // Fails only when no OpenTelemetry layer is installed.
+            let _ = span.set_parent(cx);
+        }
+    }
+
+    pub fn trace_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let cx = tracing::Span::current().context();
+        if cx.span().span_context().is_valid() {
+            TraceContextPropagator::new().inject_context(&cx, &mut HeaderInjector(&mut headers));
+        }
+        headers
+    }
+
+    /// Builds the tracer provider if `--otlp-endpoint` or one of the standard
+    /// `OTEL_EXPORTER_OTLP_*ENDPOINT` variables is set. Sampling follows
+    /// `OTEL_TRACES_SAMPLER`, which defaults to honoring the parent's decision.
+    pub fn provider(config: &Config) -> anyhow::Result<Option<SdkTracerProvider>> {
+        let from_env = [
+            "OTEL_EXPORTER_OTLP_ENDPOINT",
+            "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+        ]
+        .iter()
+        .any(|var| std::env::var_os(var).is_some());
+        if config.otlp_endpoint.is_none() && !from_env {
+            return Ok(None);
+        }
+
+        let mut exporter = SpanExporter::builder().with_http();
+        if let Some(endpoint) = &config.otlp_endpoint {
+            // Treat the flag like OTEL_EXPORTER_OTLP_ENDPOINT: a base URL.
+            let endpoint = endpoint.trim_end_matches('/');
+            exporter = exporter.with_endpoint(if endpoint.ends_with(TRACES_PATH) {
+                endpoint.to_string()
+            } else {
+                format!("{}{}", endpoint, TRACES_PATH)
+            });
+        }
+
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter.build()?)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(env!("CARGO_PKG_NAME"))
+                    .build(),
+            )
+            .build();
+        Ok(Some(provider))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_lines_flatten_span_fields() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .fmt_fields(JsonFields::new())
+                .event_format(FlatJson)
+                .with_writer(move || writer.clone()),
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "s3_request",
+                request_id = "abc",
+                operation = "GetObject",
+                bucket = "zone",
+                key = "a b.txt",
+                status = tracing::field::Empty,
+            );
+            let _entered = span.enter();
+            span.record("status", 200u16);
+            tracing::info!(bytes = 42u64, "Served object from cache");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "Served object from cache");
+        assert_eq!(line["request_id"], "abc");
+        assert_eq!(line["operation"], "GetObject");
+        assert_eq!(line["bucket"], "zone");
+        assert_eq!(line["key"], "a b.txt");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["bytes"], 42);
+        assert_eq!(line["spans"], serde_json::json!(["s3_request"]));
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn test_trace_context_joins_inbound_trace() {
+        use opentelemetry::trace::TracerProvider;
+
+        let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        let mut inbound = HeaderMap::new();
+        inbound.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+        inbound.insert("tracestate", "congo=t61rcWkgMzE".parse().unwrap());
+
+        let outbound = tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("s3_request");
+            set_parent(&span, &inbound);
+            let _entered = span.enter();
+            let _call = tracing::info_span!("bunny.get").entered();
+            trace_headers()
+        });
+
+        let traceparent = outbound["traceparent"].to_str().unwrap();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(traceparent.ends_with("-01"));
+        assert!(!traceparent.contains("00f067aa0ba902b7"));
+        assert_eq!(outbound["tracestate"], "congo=t61rcWkgMzE");
+    }
+
+    #[test]
+    fn test_no_trace_headers_without_a_trace() {
+        let span = tracing::info_span!("s3_request");
+        set_parent(&span, &HeaderMap::new());
+        let _entered = span.enter();
+        assert!(trace_headers().is_empty());
+    }
+}
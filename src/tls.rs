@@ -0,0 +1,392 @@
+//! HTTPS on the TCP listener with `--tls-cert` and `--tls-key`, and mutual TLS
+//! with `--tls-client-ca`.
+//!
+//! Client certificates are verified against the CA bundle and, with
+//! `--tls-client-crl`, its revocation lists; `--tls-require-client-cert`
+//! refuses handshakes without one. `--tls-client-cert-fingerprint` narrows the
+//! accepted certificates further to an allow-list of SHA-256 fingerprints.
+//! The verified certificate is attached to every request on the connection
+//! as a [`ClientCert`] extension; SigV4 still applies on top.
+
+use rustls::RootCertStore;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer};
+use rustls::server::{ServerConfig, WebPkiClientVerifier};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+
+use crate::config::Config;
+use crate::error::ProxyError;
+
+/// The certificate a client presented, as seen by request handlers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientCert {
+    /// The subject's distinguished name, e.g. `O=Example, CN=backup-job`.
+    pub subject: String,
+    pub common_name: Option<String>,
+    /// DNS names, URIs, email addresses and IPs from subjectAltName.
+    pub sans: Vec<String>,
+    /// Lowercase hex SHA-256 of the DER encoding.
+    pub fingerprint: String,
+}
+
+impl ClientCert {
+    fn parse(der: &[u8]) -> Option<Self> {
+        use x509_parser::extensions::GeneralName;
+
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let subject = cert.subject();
+        let common_name = subject
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_string);
+        let sans = cert
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .map(|ext| {
+                ext.value
+                    .general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        GeneralName::DNSName(s)
+                        | GeneralName::URI(s)
+                        | GeneralName::RFC822Name(s) => Some(s.to_string()),
+                        GeneralName::IPAddress(ip) => ip_string(ip),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Some(Self {
+            subject: subject.to_string(),
+            common_name,
+            sans,
+            fingerprint: hex::encode(Sha256::digest(der)),
+        })
+    }
+
+    /// The names `--map-client-cert-to-key` matches: the common name, then
+    /// each subjectAltName.
+    fn identities(&self) -> impl Iterator<Item = &str> {
+        self.common_name
+            .iter()
+            .chain(&self.sans)
+            .map(String::as_str)
+    }
+}
+
+fn ip_string(ip: &[u8]) -> Option<String> {
+    match ip.len() {
+        4 => Some(std::net::Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?).to_string()),
+        16 => Some(std::net::Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?).to_string()),
+        _ => None,
+    }
+}
+
+/// Parses a `--map-client-cert-to-key` entry, `identity=ACCESS_KEY_ID`, where
+/// the identity is a certificate's common name or one of its SANs.
+pub fn parse_cert_mapping(s: &str) -> std::result::Result<(String, String), String> {
+    match s.rsplit_once('=') {
+        Some((identity, key)) if !identity.trim().is_empty() && !key.trim().is_empty() => {
+            Ok((identity.trim().to_string(), key.trim().to_string()))
+        }
+        _ => Err(format!("expected identity=ACCESS_KEY_ID, got '{}'", s)),
+    }
+}
+
+/// Parses a `--tls-client-cert-fingerprint`: a SHA-256 in hex, with or
+/// without the colons `openssl x509 -fingerprint` prints.
+pub fn parse_fingerprint(s: &str) -> std::result::Result<String, String> {
+    let hex: String = s.chars().filter(|c| *c != ':').collect();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("'{}' is not a SHA-256 fingerprint", s));
+    }
+    Ok(hex.to_ascii_lowercase())
+}
+
+/// Enforces `--map-client-cert-to-key`: a request over a connection whose
+/// certificate maps to an access key must be signed with that key, and with
+/// mappings configured a certificate that maps to none is refused.
+pub fn check_key_mapping(
+    mappings: &[(String, String)],
+    cert: Option<&ClientCert>,
+    access_key: Option<&str>,
+) -> Result<(), ProxyError> {
+    let Some(cert) = cert.filter(|_| !mappings.is_empty()) else {
+        return Ok(());
+    };
+    let mapped = cert.identities().find_map(|identity| {
+        mappings
+            .iter()
+            .find(|(name, _)| name == identity)
+            .map(|(_, key)| key.as_str())
+    });
+    match mapped {
+        Some(key) if access_key == Some(key) => Ok(()),
+        Some(key) => {
+            tracing::warn!(
+                "Client certificate {} is mapped to {}, but the request used {}",
+                cert.subject,
+                key,
+                access_key.unwrap_or("no access key")
+            );
+            Err(ProxyError::AccessDenied)
+        }
+        None => {
+            tracing::warn!(
+                "Client certificate {} is not mapped to an access key",
+                cert.subject
+            );
+            Err(ProxyError::AccessDenied)
+        }
+    }
+}
+
+/// Accepts TLS connections on the S3 listener.
+pub struct TlsListener {
+    acceptor: TlsAcceptor,
+    fingerprints: Vec<String>,
+}
+
+/// A completed handshake.
+pub struct Accepted {
+    pub stream: TlsStream<TcpStream>,
+    /// Whether the client chose HTTP/2 through ALPN.
+    pub is_h2: bool,
+    pub client_cert: Option<ClientCert>,
+}
+
+impl TlsListener {
+    pub fn new(config: &Config) -> anyhow::Result<Option<Self>> {
+        let (Some(cert_path), Some(key_path)) = (&config.tls_cert, &config.tls_key) else {
+            return Ok(None);
+        };
+        let certs = read_certs(cert_path)?;
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .map_err(|e| anyhow::anyhow!("cannot read {}: {}", key_path.display(), e))?;
+
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()?;
+        let builder = match &config.tls_client_ca {
+            Some(ca_path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(ca_path)? {
+                    roots.add(cert)?;
+                }
+                let mut crls = Vec::new();
+                for path in &config.tls_client_crl {
+                    crls.extend(read_crls(path)?);
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .with_crls(crls);
+                let verifier = match config.tls_require_client_cert {
+                    true => verifier,
+                    false => verifier.allow_unauthenticated(),
+                };
+                builder.with_client_cert_verifier(verifier.build()?)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut server = builder.with_single_cert(certs, key)?;
+        server.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Some(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+            fingerprints: config.tls_client_cert_fingerprint.clone(),
+        }))
+    }
+
+    /// Completes the handshake on `stream`, refusing client certificates
+    /// missing from a configured fingerprint allow-list.
+    pub async fn accept(&self, stream: TcpStream) -> anyhow::Result<Accepted> {
+        let stream = self.acceptor.accept(stream).await?;
+        let connection = stream.get_ref().1;
+        let is_h2 = connection.alpn_protocol() == Some(b"h2");
+        let client_cert = connection
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| ClientCert::parse(cert));
+        if !self.fingerprints.is_empty() {
+            match &client_cert {
+                Some(cert) if self.fingerprints.contains(&cert.fingerprint) => {}
+                Some(cert) => anyhow::bail!(
+                    "client certificate {} ({}) is not in the fingerprint allow-list",
+                    cert.subject,
+                    cert.fingerprint
+                ),
+                None => anyhow::bail!("no client certificate for the fingerprint allow-list"),
+            }
+        }
+        Ok(Accepted {
+            stream,
+            is_h2,
+            client_cert,
+        })
+    }
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("cannot read {}: {}", path.display(), e))
+}
+
+/// Reads a CRL file in PEM, or in DER as `openssl ca -gencrl -outform DER`
+/// writes it.
+fn read_crls(path: &Path) -> anyhow::Result<Vec<CertificateRevocationListDer<'static>>> {
+    let data = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("cannot read {}: {}", path.display(), e))?;
+    if !data.starts_with(b"-----") {
+        return Ok(vec![CertificateRevocationListDer::from(data)]);
+    }
+    CertificateRevocationListDer::pem_slice_iter(&data)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow::anyhow!("cannot read {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use rustls::pki_types::ServerName;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn listener(extra: &[&str]) -> TlsListener {
+        let (cert, key, ca) = (
+            fixture("server.pem"),
+            fixture("server.key"),
+            fixture("ca.pem"),
+        );
+        let base = [
+            "bunny-s3-proxy",
+            "-z",
+            "zone",
+            "-k",
+            "key",
+            "--tls-cert",
+            &cert,
+            "--tls-key",
+            &key,
+            "--tls-client-ca",
+            &ca,
+        ];
+        let config = Config::parse_from(base.iter().copied().chain(extra.iter().copied()));
+        TlsListener::new(&config).unwrap().unwrap()
+    }
+
+    /// Connects with the client certificate `client`, if any, and returns
+    /// what the server side of the handshake produced.
+    async fn handshake(listener: &TlsListener, client: Option<&str>) -> anyhow::Result<Accepted> {
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(read_certs(Path::new(&fixture("ca.pem"))).unwrap());
+        let builder = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let mut config = match client {
+            Some(name) => builder
+                .with_client_auth_cert(
+                    read_certs(Path::new(&fixture(&format!("{}.pem", name)))).unwrap(),
+                    PrivateKeyDer::from_pem_file(fixture(&format!("{}.key", name))).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+
+        let tcp = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let client = async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let name = ServerName::try_from("localhost").unwrap();
+            // Keep the client open until the server has decided.
+            let stream = connector.connect(name, stream).await;
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            stream
+        };
+        let server = async { listener.accept(tcp.accept().await.unwrap().0).await };
+        tokio::join!(client, server).1
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates_are_verified() {
+        let required = listener(&["--tls-require-client-cert"]);
+        let accepted = handshake(&required, Some("client")).await.unwrap();
+        assert!(accepted.is_h2);
+        let cert = accepted.client_cert.unwrap();
+        assert_eq!(cert.subject, "O=Example, CN=backup-job");
+        assert_eq!(cert.common_name.as_deref(), Some("backup-job"));
+        assert_eq!(cert.sans, ["backup.internal", "spiffe://example/backup"]);
+        assert_eq!(
+            cert.fingerprint,
+            "e99d79a95bf3ad5364a54e6219f9382fa5f5225fd608689c0c2e001448dcc70c"
+        );
+
+        assert!(handshake(&required, None).await.is_err());
+        assert!(handshake(&required, Some("other")).await.is_err());
+
+        // Without --tls-require-client-cert a client may present none.
+        let optional = listener(&[]);
+        let accepted = handshake(&optional, None).await.unwrap();
+        assert!(accepted.client_cert.is_none());
+        assert!(handshake(&optional, Some("other")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_revoked_and_unlisted_certificates_are_refused() {
+        let crl = fixture("ca.crl");
+        let with_crl = listener(&["--tls-require-client-cert", "--tls-client-crl", &crl]);
+        assert!(handshake(&with_crl, Some("revoked")).await.is_err());
+        assert!(handshake(&with_crl, Some("client")).await.is_ok());
+        assert!(handshake(&listener(&[]), Some("revoked")).await.is_ok());
+
+        let allowed = "E9:9D:79:A9:5B:F3:AD:53:64:A5:4E:62:19:F9:38:2F:A5:F5:22:5F:D6:08:68:9C:0C:2E:00:14:48:DC:C7:0C";
+        let pinned = listener(&["--tls-client-cert-fingerprint", allowed]);
+        assert!(handshake(&pinned, Some("client")).await.is_ok());
+        assert!(handshake(&pinned, Some("revoked")).await.is_err());
+        assert!(handshake(&pinned, None).await.is_err());
+    }
+
+    #[test]
+    fn test_key_mapping() {
+        let cert = ClientCert {
+            subject: "CN=backup-job".into(),
+            common_name: Some("backup-job".into()),
+            sans: vec!["backup.internal".into()],
+            fingerprint: String::new(),
+        };
+        let mappings = [parse_cert_mapping("backup.internal=AKIDBACKUP").unwrap()];
+        assert!(check_key_mapping(&mappings, Some(&cert), Some("AKIDBACKUP")).is_ok());
+        assert!(check_key_mapping(&mappings, Some(&cert), Some("AKIDOTHER")).is_err());
+        assert!(check_key_mapping(&mappings, Some(&cert), None).is_err());
+        let unmapped = ClientCert {
+            common_name: Some("web".into()),
+            sans: Vec::new(),
+            ..cert.clone()
+        };
+        assert!(check_key_mapping(&mappings, Some(&unmapped), Some("AKIDBACKUP")).is_err());
+        // No certificate, or no mappings, leaves SigV4 alone to decide.
+        assert!(check_key_mapping(&mappings, None, Some("AKIDOTHER")).is_ok());
+        assert!(check_key_mapping(&[], Some(&cert), Some("AKIDOTHER")).is_ok());
+
+        assert!(parse_cert_mapping("no-key").is_err());
+        assert!(parse_fingerprint("abc").is_err());
+        assert_eq!(
+            parse_fingerprint(&"AB".repeat(32)).unwrap(),
+            "ab".repeat(32)
+        );
+    }
+}
@@ -0,0 +1,67 @@
+#!/bin/bash
+# Runs the e2e tests self-contained: the proxies talk to `mock-bunny` instead
+# of a real storage zone, so no credentials or Docker are needed. Memory limits
+# are not enforced in this mode; use e2e_zerofs.sh for those.
+set -e
+
+SCRIPT_DIR="$(cd "$(dirname "$0")" && pwd)"
+cd "$SCRIPT_DIR/.."
+
+export BUNNY_STORAGE_ZONE=mock-zone
+export BUNNY_ACCESS_KEY=mock-key
+export EXTRA_ZONE=mock-staging
+MOCK_ADDR=127.0.0.1:18800
+BIN=target/mock/release/bunny-s3-proxy
+
+echo "=== E2E Test against mock-bunny ==="
+
+echo "Building binary with the mock-bunny and chaos features..."
+cargo build --release --quiet --features mock-bunny,chaos --target-dir target/mock
+
+PIDS=()
+cleanup() {
+  kill "${PIDS[@]}" 2>/dev/null || true
+  wait "${PIDS[@]}" 2>/dev/null || true
+}
+trap cleanup EXIT
+
+LOG_DIR=$(mktemp -d)
+"$BIN" mock-bunny --listen "$MOCK_ADDR" >"$LOG_DIR/mock.log" 2>&1 &
+PIDS+=($!)
+
+start_proxy() {
+  local port=$1
+  shift
+  "$BIN" \
+    --bunny-endpoint "http://$MOCK_ADDR" \
+    --s3-access-key-id test \
+    --s3-secret-access-key test \
+    --listen-addr "127.0.0.1:$port" \
+    "$@" >"$LOG_DIR/proxy-$port.log" 2>&1 &
+  PIDS+=($!)
+}
+start_proxy 19000
+start_proxy 19001 --verify-downloads
+start_proxy 19002 --chaos-config tests/fixtures/chaos.toml
+start_proxy 19003 --extra-zone "$EXTRA_ZONE:$BUNNY_ACCESS_KEY"
+
+sleep 2
+for port in 19000 19001 19002 19003; do
+  if ! grep -q "Listening on" "$LOG_DIR/proxy-$port.log"; then
+    echo "FAIL: proxy on $port did not start"
+    cat "$LOG_DIR/proxy-$port.log"
+    exit 1
+  fi
+done
+
+RESULT=0
+cargo test --test e2e_zerofs -- --nocapture 2>&1 || RESULT=$?
+
+echo "Faults injected by the chaos proxy: $(grep -c '\[chaos\]' "$LOG_DIR/proxy-19002.log" || true)"
+if [ $RESULT -eq 0 ]; then
+  echo "SUCCESS: e2e tests passed against mock-bunny"
+else
+  echo "FAIL: Test failed; logs in $LOG_DIR"
+fi
+
+exit $RESULT
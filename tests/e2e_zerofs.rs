@@ -5,6 +5,14 @@
 //! Requires:
 //! - Proxy running on localhost:19000
 //! - BUNNY_STORAGE_ZONE env var
+//! - Optionally a second proxy with `--verify-downloads` on localhost:19001
+//! - Optionally a proxy built with `--features chaos` and started with
+//!   `--chaos-config tests/fixtures/chaos.toml` on localhost:19002
+//! - Optionally a proxy on localhost:19003 serving a second zone, named by the
+//!   EXTRA_ZONE env var, with `--extra-zone`
+//!
+//! `tests/e2e_zerofs.sh` starts these against a real storage zone;
+//! `tests/e2e_mock.sh` starts them against `mock-bunny` instead.
 
 use futures::future::join_all;
 use rand::Rng;
@@ -12,6 +20,9 @@ use reqwest::Client;
 use std::time::{Duration, Instant};
 
 const PROXY_URL: &str = "http://127.0.0.1:19000";
+const VERIFY_PROXY_URL: &str = "http://127.0.0.1:19001";
+const CHAOS_PROXY_URL: &str = "http://127.0.0.1:19002";
+const EXTRA_ZONE_PROXY_URL: &str = "http://127.0.0.1:19003";
 
 fn create_h2_client() -> Client {
     Client::builder()
@@ -482,3 +493,394 @@ async fn test_concurrent_range_requests() {
     assert_eq!(success, 10);
     println!("SUCCESS");
 }
+
+/// HeadBucket is used as a connectivity check and must stay cheap
+#[tokio::test]
+async fn test_head_bucket_latency() {
+    let bucket = match std::env::var("BUNNY_STORAGE_ZONE") {
+        Ok(b) => b,
+        Err(_) => {
+            eprintln!("Skipping: BUNNY_STORAGE_ZONE not set");
+            return;
+        }
+    };
+
+    println!("\n=== HeadBucket Latency Test ===");
+    let client = create_h2_client();
+    let url = format!("{}/{}", PROXY_URL, bucket);
+
+    let mut timings = Vec::new();
+    for _ in 0..10 {
+        let start = Instant::now();
+        let response = client.head(&url).send().await.expect("HEAD failed");
+        timings.push(start.elapsed());
+        assert!(
+            response.status().is_success(),
+            "HEAD returned {}",
+            response.status()
+        );
+        assert!(response.headers().contains_key("x-amz-bucket-region"));
+    }
+
+    let first = timings[0];
+    let cached = timings[1..].iter().sum::<Duration>() / (timings.len() - 1) as u32;
+    println!("First HEAD: {:?}", first);
+    println!("Cached HEAD (avg): {:?}", cached);
+
+    let missing = client
+        .head(format!("{}/{}-does-not-exist", PROXY_URL, bucket))
+        .send()
+        .await
+        .expect("HEAD failed");
+    assert_eq!(missing.status().as_u16(), 404);
+}
+
+/// Times full GETs of the same object through the plain proxy and through one
+/// started with `--verify-downloads` to measure the cost of hashing the body
+/// and the extra DESCRIBE.
+#[tokio::test]
+async fn test_verify_downloads_overhead() {
+    let bucket = match std::env::var("BUNNY_STORAGE_ZONE") {
+        Ok(b) => b,
+        Err(_) => {
+            eprintln!("Skipping: BUNNY_STORAGE_ZONE not set");
+            return;
+        }
+    };
+    let client = create_h2_client();
+    if client
+        .head(format!("{}/{}", VERIFY_PROXY_URL, bucket))
+        .send()
+        .await
+        .is_err()
+    {
+        eprintln!(
+            "Skipping: no --verify-downloads proxy on {}",
+            VERIFY_PROXY_URL
+        );
+        return;
+    }
+
+    println!("\n=== Download Verification Overhead ===");
+    let key = "zerofs-test/verify/object.bin".to_string();
+    let data = random_data(32);
+    let size = data.len();
+    put_object(&client, &bucket, &key, data)
+        .await
+        .expect("PUT failed");
+
+    const ROUNDS: usize = 5;
+    let mut timings = [Duration::ZERO; 2];
+    for _ in 0..ROUNDS {
+        for (i, base) in [PROXY_URL, VERIFY_PROXY_URL].into_iter().enumerate() {
+            let start = Instant::now();
+            let response = client
+                .get(format!("{}/{}/{}", base, bucket, key))
+                .send()
+                .await
+                .expect("GET failed");
+            assert!(response.status().is_success());
+            let body = response.bytes().await.expect("verified body failed");
+            assert_eq!(body.len(), size);
+            timings[i] += start.elapsed();
+        }
+    }
+
+    let plain = timings[0] / ROUNDS as u32;
+    let verified = timings[1] / ROUNDS as u32;
+    println!("Plain GET (avg of {}): {:?}", ROUNDS, plain);
+    println!("Verified GET (avg of {}): {:?}", ROUNDS, verified);
+    println!(
+        "Overhead: {:.1}%",
+        (verified.as_secs_f64() / plain.as_secs_f64() - 1.0) * 100.0
+    );
+
+    delete_object(&client, &bucket, &key).await.ok();
+}
+
+/// Retries `attempt` with a growing pause until it succeeds, the way S3 SDKs
+/// retry 5xx responses and dropped connections. Returns the value and the
+/// number of retries it took.
+async fn with_retries<T, F, Fut>(what: &str, mut attempt: F) -> Result<(T, usize), String>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, String>>,
+{
+    const MAX_ATTEMPTS: usize = 10;
+    let mut last_error = String::new();
+    for retry in 0..MAX_ATTEMPTS {
+        match attempt().await {
+            Ok(value) => return Ok((value, retry)),
+            Err(e) => {
+                println!("  {} attempt {} failed: {}", what, retry + 1, e);
+                last_error = e;
+                tokio::time::sleep(Duration::from_millis(200 * (retry as u64 + 1))).await;
+            }
+        }
+    }
+    Err(format!(
+        "{} failed after {} attempts: {}",
+        what, MAX_ATTEMPTS, last_error
+    ))
+}
+
+async fn chaos_put(client: &Client, url: &str, data: &[u8]) -> Result<(), String> {
+    let response = client
+        .put(url)
+        .header("content-type", "application/octet-stream")
+        .body(data.to_vec())
+        .send()
+        .await
+        .map_err(|e| format!("PUT failed: {}", e))?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("PUT failed with status: {}", response.status()))
+    }
+}
+
+async fn chaos_get(client: &Client, url: &str) -> Result<Vec<u8>, String> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| format!("GET failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("GET failed with status: {}", response.status()));
+    }
+    response
+        .bytes()
+        .await
+        .map(|b| b.to_vec())
+        .map_err(|e| format!("Failed to read body: {}", e))
+}
+
+#[tokio::test]
+async fn test_zerofs_workload_recovers_from_faults() {
+    let bucket = match std::env::var("BUNNY_STORAGE_ZONE") {
+        Ok(b) => b,
+        Err(_) => {
+            eprintln!("Skipping: BUNNY_STORAGE_ZONE not set");
+            return;
+        }
+    };
+    let client = create_h2_client();
+    if client
+        .get(format!("{}/", CHAOS_PROXY_URL))
+        .send()
+        .await
+        .is_err()
+    {
+        eprintln!("Skipping: no chaos proxy on {}", CHAOS_PROXY_URL);
+        return;
+    }
+
+    println!("\n=== ZeroFS Workload Under Injected Faults ===");
+    const BURSTS: usize = 4;
+    let mut retries = 0;
+    let mut keys = Vec::new();
+    for burst in 0..BURSTS {
+        let files: Vec<_> = (0..5)
+            .map(|i| {
+                let key = format!("zerofs-test/chaos/burst{:04}-{:02}.sst", burst, i);
+                (key, random_data(4))
+            })
+            .collect();
+        let uploads = files.iter().map(|(key, data)| {
+            let client = client.clone();
+            let url = format!("{}/{}/{}", CHAOS_PROXY_URL, bucket, key);
+            async move { with_retries("PUT", || chaos_put(&client, &url, data)).await }
+        });
+        for result in join_all(uploads).await {
+            retries += result.expect("upload did not recover").1;
+        }
+
+        for (key, data) in &files {
+            let url = format!("{}/{}/{}", CHAOS_PROXY_URL, bucket, key);
+            let (body, r) = with_retries("GET", || chaos_get(&client, &url))
+                .await
+                .expect("read did not recover");
+            retries += r;
+            assert!(body == *data, "{} came back different", key);
+            keys.push(key.clone());
+        }
+
+        let url = format!(
+            "{}/{}?list-type=2&prefix=zerofs-test/chaos/",
+            CHAOS_PROXY_URL, bucket
+        );
+        let (listing, r) = with_retries("LIST", || async {
+            let body = chaos_get(&client, &url).await?;
+            String::from_utf8(body).map_err(|e| e.to_string())
+        })
+        .await
+        .expect("listing did not recover");
+        retries += r;
+        for key in &keys {
+            assert!(
+                listing.contains(key.as_str()),
+                "{} missing from listing",
+                key
+            );
+        }
+        println!("  Burst {} verified", burst + 1);
+    }
+    println!(
+        "{} objects written and read back intact after {} retries",
+        keys.len(),
+        retries
+    );
+
+    for key in &keys {
+        delete_object(&client, &bucket, key).await.ok();
+    }
+}
+
+/// The value of the `<tag>` element of an XML response.
+fn xml_value<'a>(body: &'a str, tag: &str) -> Option<&'a str> {
+    let start = body.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = body[start..].find(&format!("</{}>", tag))? + start;
+    Some(&body[start..end])
+}
+
+#[tokio::test]
+async fn test_cross_zone_copy() {
+    let (bucket, staging) = match (
+        std::env::var("BUNNY_STORAGE_ZONE"),
+        std::env::var("EXTRA_ZONE"),
+    ) {
+        (Ok(b), Ok(s)) => (b, s),
+        _ => {
+            eprintln!("Skipping: BUNNY_STORAGE_ZONE or EXTRA_ZONE not set");
+            return;
+        }
+    };
+    let client = create_h2_client();
+    if client
+        .head(format!("{}/{}", EXTRA_ZONE_PROXY_URL, staging))
+        .send()
+        .await
+        .is_err()
+    {
+        eprintln!(
+            "Skipping: no --extra-zone proxy on {}",
+            EXTRA_ZONE_PROXY_URL
+        );
+        return;
+    }
+
+    println!("\n=== Cross-Zone Copy ===");
+    let data = random_data(6);
+    let source = "zerofs-test/cross-zone/source.bin";
+    let url = |bucket: &str, key: &str| format!("{}/{}/{}", EXTRA_ZONE_PROXY_URL, bucket, key);
+    let response = client
+        .put(url(&staging, source))
+        .body(data.clone())
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success(), "PUT {}", response.status());
+
+    // CopyObject from the extra zone into the main one
+    let copy = "zerofs-test/cross-zone/copy.bin";
+    let response = client
+        .put(url(&bucket, copy))
+        .header("x-amz-copy-source", format!("/{}/{}", staging, source))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success(), "copy {}", response.status());
+    let copied = client
+        .get(url(&bucket, copy))
+        .send()
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    assert!(copied == data, "copied object differs from the source");
+    println!("CopyObject: {} bytes across zones", copied.len());
+
+    // UploadPartCopy of two ranges of the source
+    let assembled = "zerofs-test/cross-zone/assembled.bin";
+    let body = client
+        .post(format!("{}?uploads", url(&bucket, assembled)))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let upload_id = xml_value(&body, "UploadId")
+        .expect("no UploadId")
+        .to_string();
+    let split = 5 * 1024 * 1024;
+    let mut parts = String::new();
+    for (number, range) in [
+        (1, format!("bytes=0-{}", split - 1)),
+        (2, format!("bytes={}-{}", split, data.len() - 1)),
+    ] {
+        let response = client
+            .put(format!(
+                "{}?partNumber={}&uploadId={}",
+                url(&bucket, assembled),
+                number,
+                upload_id
+            ))
+            .header("x-amz-copy-source", format!("/{}/{}", staging, source))
+            .header("x-amz-copy-source-range", range)
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success(), "part {}", response.status());
+        let body = response.text().await.unwrap();
+        let etag = xml_value(&body, "ETag")
+            .expect("no ETag")
+            .replace("&quot;", "\"");
+        parts.push_str(&format!(
+            "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+            number, etag
+        ));
+    }
+    let response = client
+        .post(format!(
+            "{}?uploadId={}",
+            url(&bucket, assembled),
+            upload_id
+        ))
+        .body(format!(
+            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+            parts
+        ))
+        .send()
+        .await
+        .unwrap();
+    let body = response.text().await.unwrap();
+    assert!(!body.contains("<Error>"), "complete failed: {}", body);
+    let assembled_data = client
+        .get(url(&bucket, assembled))
+        .send()
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    assert!(
+        assembled_data == data,
+        "assembled object differs from the source"
+    );
+    println!("UploadPartCopy: 2 ranges across zones");
+
+    let response = client
+        .put(url(&bucket, "zerofs-test/cross-zone/missing.bin"))
+        .header("x-amz-copy-source", format!("/no-such-zone/{}", source))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+    assert!(response.text().await.unwrap().contains("NoSuchBucket"));
+
+    for (bucket, key) in [(&staging, source), (&bucket, copy), (&bucket, assembled)] {
+        client.delete(url(bucket, key)).send().await.ok();
+    }
+}
@@ -24,6 +24,9 @@ echo ""
 echo "Building release binary..."
 cargo build --release --quiet
 
+echo "Building chaos binary..."
+cargo build --release --quiet --features chaos --target-dir target/chaos
+
 echo "Starting proxy container..."
 docker rm -f bunny-proxy-e2e 2>/dev/null || true
 
@@ -46,6 +49,47 @@ docker run -d \
     --s3-secret-access-key test \
     --listen-addr 0.0.0.0:9000
 
+docker rm -f bunny-proxy-e2e-verify 2>/dev/null || true
+
+docker run -d \
+  --name bunny-proxy-e2e-verify \
+  --memory="$MEMORY_LIMIT" \
+  --memory-swap="$MEMORY_LIMIT" \
+  -v "$(pwd)/target/release/bunny-s3-proxy:/bunny-s3-proxy:ro" \
+  -v "$CA_BUNDLE:/etc/ssl/certs/ca-certificates.crt:ro" \
+  -e SSL_CERT_FILE=/etc/ssl/certs/ca-certificates.crt \
+  -p 19001:9000 \
+  ubuntu:24.04 \
+  /bunny-s3-proxy \
+    --storage-zone "$BUNNY_STORAGE_ZONE" \
+    --region de \
+    --access-key "$BUNNY_ACCESS_KEY" \
+    --s3-access-key-id test \
+    --s3-secret-access-key test \
+    --listen-addr 0.0.0.0:9000 \
+    --verify-downloads
+
+docker rm -f bunny-proxy-e2e-chaos 2>/dev/null || true
+
+docker run -d \
+  --name bunny-proxy-e2e-chaos \
+  --memory="$MEMORY_LIMIT" \
+  --memory-swap="$MEMORY_LIMIT" \
+  -v "$(pwd)/target/chaos/release/bunny-s3-proxy:/bunny-s3-proxy:ro" \
+  -v "$(pwd)/tests/fixtures/chaos.toml:/chaos.toml:ro" \
+  -v "$CA_BUNDLE:/etc/ssl/certs/ca-certificates.crt:ro" \
+  -e SSL_CERT_FILE=/etc/ssl/certs/ca-certificates.crt \
+  -p 19002:9000 \
+  ubuntu:24.04 \
+  /bunny-s3-proxy \
+    --storage-zone "$BUNNY_STORAGE_ZONE" \
+    --region de \
+    --access-key "$BUNNY_ACCESS_KEY" \
+    --s3-access-key-id test \
+    --s3-secret-access-key test \
+    --listen-addr 0.0.0.0:9000 \
+    --chaos-config /chaos.toml
+
 sleep 2
 
 if ! docker ps | grep -q bunny-proxy-e2e; then
@@ -102,6 +146,11 @@ echo ""
 echo "Cleaning up..."
 docker stop bunny-proxy-e2e 2>/dev/null || true
 docker rm bunny-proxy-e2e 2>/dev/null || true
+docker stop bunny-proxy-e2e-verify 2>/dev/null || true
+docker rm bunny-proxy-e2e-verify 2>/dev/null || true
+echo "Faults injected by the chaos proxy: $(docker logs bunny-proxy-e2e-chaos 2>&1 | grep -c '\[chaos\]' || true)"
+docker stop bunny-proxy-e2e-chaos 2>/dev/null || true
+docker rm bunny-proxy-e2e-chaos 2>/dev/null || true
 
 if [ $RESULT -eq 0 ]; then
   echo ""